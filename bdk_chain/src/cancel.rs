@@ -0,0 +1,30 @@
+//! Stopping long running operations from the outside.
+//!
+//! An operation that can take a while (e.g. scanning a chain source or searching for a coin
+//! selection) takes a [`CancellationToken`] and checks it between its steps. Whoever embeds the
+//! operation keeps a clone of the token and cancels it (e.g. from another thread when shutting
+//! down) to make the operation stop at its next check.
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A flag shared by its clones that tells an operation to stop.
+///
+/// Operations document what they return once cancelled: either an error or the best result they
+/// had found so far. A token is never un-cancelled, so use a new one for the next operation.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tells the operations holding a clone of the token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
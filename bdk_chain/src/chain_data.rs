@@ -1,4 +1,4 @@
-use bitcoin::{hashes::Hash, BlockHash, OutPoint, TxOut, Txid};
+use bitcoin::{hashes::Hash, BlockHash, BlockHeader, OutPoint, TxOut, Txid};
 
 use crate::{
     sparse_chain::{self, ChainPosition},
@@ -64,6 +64,10 @@ impl crate::sparse_chain::ChainPosition for TxHeight {
     }
 }
 
+impl sparse_chain::PositionSchema for TxHeight {
+    const SCHEMA_TAG: u8 = 1;
+}
+
 impl TxHeight {
     pub fn is_confirmed(&self) -> bool {
         matches!(self, Self::Confirmed(_))
@@ -109,6 +113,17 @@ impl sparse_chain::ChainPosition for ConfirmationTime {
             TxHeight::Unconfirmed => Self::Unconfirmed,
         }
     }
+
+    fn confirmed_in_block(height: u32, header: &BlockHeader) -> Self {
+        Self::Confirmed {
+            height,
+            time: header.time as u64,
+        }
+    }
+}
+
+impl sparse_chain::PositionSchema for ConfirmationTime {
+    const SCHEMA_TAG: u8 = 2;
 }
 
 impl ConfirmationTime {
@@ -163,6 +178,11 @@ impl From<(&u32, &BlockHash)> for BlockId {
 
 /// A `TxOut` with as much data as we can retreive about it
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
 pub struct FullTxOut<I> {
     /// The location of the `TxOut`
     pub outpoint: OutPoint,
@@ -213,6 +233,28 @@ impl<I: ChainPosition> FullTxOut<I> {
 
         true
     }
+
+    /// The number of blocks the output has been confirmed for when the tip is at `tip_height` (i.e.
+    /// its number of confirmations). Outputs that are unconfirmed (or confirmed above `tip_height`)
+    /// are `0` blocks old.
+    pub fn age(&self, tip_height: u32) -> u32 {
+        match self.chain_position.height() {
+            TxHeight::Confirmed(height) if height <= tip_height => tip_height - height + 1,
+            _ => 0,
+        }
+    }
+
+    /// Estimates the coin days destroyed by spending the output when the tip is at `tip_height`.
+    ///
+    /// This is the value of the output in BTC multiplied by its [`age`] in days, assuming a block
+    /// every ten minutes.
+    ///
+    /// [`age`]: Self::age
+    pub fn coin_days_destroyed(&self, tip_height: u32) -> f64 {
+        const BLOCKS_PER_DAY: f64 = 144.0;
+        const SATS_PER_BTC: f64 = 100_000_000.0;
+        self.txout.value as f64 / SATS_PER_BTC * self.age(tip_height) as f64 / BLOCKS_PER_DAY
+    }
 }
 
 // TOOD: make test
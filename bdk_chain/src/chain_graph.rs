@@ -1,11 +1,11 @@
 use crate::{
-    collections::HashSet,
+    collections::{BTreeMap, BTreeSet, HashSet},
     sparse_chain::{self, ChainPosition, SparseChain},
-    tx_graph::{self, TxGraph},
+    tx_graph::{self, TxGraph, TxProvider},
     BlockId, ForEachTxout, FullTxOut, TxHeight,
 };
 use alloc::{string::ToString, vec::Vec};
-use bitcoin::{OutPoint, Transaction, TxOut, Txid};
+use bitcoin::{Block, OutPoint, Transaction, TxOut, Txid};
 use core::fmt::Debug;
 
 /// A convenient combination of a [`SparseChain<P>`] and a [`TxGraph`].
@@ -45,6 +45,43 @@ impl<P> ChainGraph<P> {
 }
 
 impl<P: ChainPosition> ChainGraph<P> {
+    /// Creates an update that connects `block` at `height` to the chain and confirms `txs` in it
+    /// (e.g. those [`SpkTxOutIndex::relevant_txs_in_block`] finds).
+    ///
+    /// The update includes a checkpoint for the block's parent too, so applying it replaces a tip
+    /// that has been reorged out instead of leaving it behind. Only the transactions in `txs` are
+    /// cloned.
+    ///
+    /// [`SpkTxOutIndex::relevant_txs_in_block`]: crate::SpkTxOutIndex::relevant_txs_in_block
+    pub fn from_block<'a>(
+        block: &Block,
+        height: u32,
+        txs: impl IntoIterator<Item = &'a Transaction>,
+    ) -> Self {
+        let mut update = Self::default();
+        if let Some(prev_height) = height.checked_sub(1) {
+            let _ = update
+                .insert_checkpoint(BlockId {
+                    height: prev_height,
+                    hash: block.header.prev_blockhash,
+                })
+                .expect("the update is empty");
+        }
+        let _ = update
+            .insert_checkpoint(BlockId {
+                height,
+                hash: block.block_hash(),
+            })
+            .expect("the parent is at a lower height");
+        let position = P::confirmed_in_block(height, &block.header);
+        for tx in txs {
+            let _ = update
+                .insert_tx(tx.clone(), position.clone())
+                .expect("the block is the tip of the update so its transactions can be added");
+        }
+        update
+    }
+
     pub fn checkpoint_limit(&self) -> Option<usize> {
         self.chain.checkpoint_limit()
     }
@@ -53,6 +90,34 @@ impl<P: ChainPosition> ChainGraph<P> {
         self.chain.set_checkpoint_limit(limit)
     }
 
+    pub fn checkpoint_retention(&self) -> Option<&sparse_chain::CheckpointRetention> {
+        self.chain.checkpoint_retention()
+    }
+
+    pub fn set_checkpoint_retention(
+        &mut self,
+        retention: Option<sparse_chain::CheckpointRetention>,
+    ) {
+        self.chain.set_checkpoint_retention(retention)
+    }
+
+    /// The trusted anchor of the chain. See [`SparseChain::from_anchor`].
+    ///
+    /// [`SparseChain::from_anchor`]: sparse_chain::SparseChain::from_anchor
+    pub fn anchor(&self) -> Option<BlockId> {
+        self.chain.anchor()
+    }
+
+    /// Sets the trusted anchor of the chain. See [`SparseChain::set_anchor`].
+    ///
+    /// [`SparseChain::set_anchor`]: sparse_chain::SparseChain::set_anchor
+    pub fn set_anchor(
+        &mut self,
+        anchor: Option<BlockId>,
+    ) -> Result<(), sparse_chain::InsertCheckpointError> {
+        self.chain.set_anchor(anchor)
+    }
+
     /// Determines the changes required to invalidate checkpoints `from_height` (inclusive) and
     /// above. Displaced transactions will have their positions moved to [`TxHeight::Unconfirmed`].
     pub fn invalidate_checkpoints_preview(&self, from_height: u32) -> ChangeSet<P> {
@@ -261,6 +326,55 @@ impl<P: ChainPosition> ChainGraph<P> {
         Ok(())
     }
 
+    /// The transactions `changeset` evicts from the chain of `self`, each with the transactions
+    /// `changeset` adds to the chain that spend the same outputs as it does.
+    ///
+    /// A transaction evicted without being replaced (e.g. a descendant of a replaced transaction
+    /// or one that was dropped from the mempool) has no conflicts. Call this before applying
+    /// `changeset`, afterwards the evicted transactions are no longer in the chain.
+    pub fn evictions(&self, changeset: &ChangeSet<P>) -> BTreeMap<Txid, BTreeSet<Txid>> {
+        let added = changeset
+            .chain
+            .txids
+            .iter()
+            .filter(|(_, pos)| pos.is_some())
+            .filter_map(|(&txid, _)| {
+                self.graph
+                    .get_tx(txid)
+                    .or_else(|| changeset.graph.tx.iter().find(|tx| tx.txid() == txid))
+            })
+            .collect::<Vec<_>>();
+
+        changeset
+            .chain
+            .txids
+            .iter()
+            .filter(|(&txid, pos)| pos.is_none() && self.chain.tx_position(txid).is_some())
+            .map(|(&txid, _)| {
+                let spent = self
+                    .graph
+                    .get_tx(txid)
+                    .map(|tx| {
+                        tx.input
+                            .iter()
+                            .map(|txin| txin.previous_output)
+                            .collect::<BTreeSet<_>>()
+                    })
+                    .unwrap_or_default();
+                let conflicts = added
+                    .iter()
+                    .filter(|tx| {
+                        tx.input
+                            .iter()
+                            .any(|txin| spent.contains(&txin.previous_output))
+                    })
+                    .map(|tx| tx.txid())
+                    .collect();
+                (txid, conflicts)
+            })
+            .collect()
+    }
+
     /// Applies `changeset` to `self`.
     ///
     /// **Warning** this method assumes the changeset is assumed to be correctly formed. If it isn't
@@ -307,6 +421,24 @@ impl<P: ChainPosition> ChainGraph<P> {
         }
     }
 
+    /// Like [`inflate_changeset`] but looks up the transactions the graph is missing with
+    /// `provider` (e.g. a store of raw transactions) instead of being handed them.
+    ///
+    /// [`inflate_changeset`]: Self::inflate_changeset
+    pub fn inflate_changeset_from(
+        &self,
+        changeset: sparse_chain::ChangeSet<P>,
+        provider: &impl TxProvider,
+    ) -> Result<ChangeSet<P>, InflateError<P>> {
+        let full_txs = self
+            .chain
+            .changeset_additions(&changeset)
+            .filter(|txid| self.graph.get_tx(*txid).is_none())
+            .filter_map(|txid| provider.provide_tx(txid))
+            .collect::<Vec<_>>();
+        self.inflate_changeset(changeset, full_txs)
+    }
+
     /// Applies the `update` chain graph. Note this is shorthand for calling
     /// [`Self::determine_changeset()`] and [`Self::apply_changeset()`] in sequence.
     pub fn apply_update(&mut self, update: Self) -> Result<ChangeSet<P>, UpdateError<P>> {
@@ -335,6 +467,279 @@ impl<P: ChainPosition> ChainGraph<P> {
         self.chain.spent_by(&self.graph, outpoint)
     }
 
+    /// The number of confirmations `txid` has as of the latest checkpoint.
+    ///
+    /// Returns `Some(0)` if it is unconfirmed and `None` if it is not in the chain.
+    pub fn confirmations(&self, txid: Txid) -> Option<u32> {
+        let tip_height = self
+            .chain
+            .latest_checkpoint()
+            .map_or(0, |block_id| block_id.height);
+        match self.chain.tx_position(txid)?.height() {
+            TxHeight::Confirmed(height) => Some(tip_height.saturating_sub(height) + 1),
+            TxHeight::Unconfirmed => Some(0),
+        }
+    }
+
+    /// The transactions in the graph that spend an output also spent by `txid` along with their
+    /// position in the chain (if they are in it).
+    ///
+    /// Returns nothing if the full transaction of `txid` is not in the graph.
+    pub fn conflicts_of(&self, txid: Txid) -> BTreeMap<Txid, Option<&P>> {
+        let tx = match self.graph.get_tx(txid) {
+            Some(tx) => tx,
+            None => return BTreeMap::new(),
+        };
+        self.graph
+            .conflicting_txids(tx)
+            .map(|(_, conflict)| (conflict, self.chain.tx_position(conflict)))
+            .collect()
+    }
+
+    /// The unconfirmed transactions in the chain that `txid` depends on, i.e. the ones it spends
+    /// from and (recursively) the ones those spend from.
+    ///
+    /// These must be mined before or together with `txid`.
+    pub fn unconfirmed_ancestors(&self, txid: Txid) -> BTreeSet<Txid> {
+        let mut ancestors = BTreeSet::new();
+        let mut to_visit = vec![txid];
+        while let Some(txid) = to_visit.pop() {
+            let tx = match self.graph.get_tx(txid) {
+                Some(tx) => tx,
+                None => continue,
+            };
+            for txin in &tx.input {
+                let parent = txin.previous_output.txid;
+                let is_unconfirmed = matches!(
+                    self.chain.tx_position(parent).map(|pos| pos.height()),
+                    Some(TxHeight::Unconfirmed)
+                );
+                if is_unconfirmed && ancestors.insert(parent) {
+                    to_visit.push(parent);
+                }
+            }
+        }
+        ancestors
+    }
+
+    /// The feerate (in sats per weight unit) of `txid` together with its
+    /// [unconfirmed ancestors](Self::unconfirmed_ancestors).
+    ///
+    /// This is the feerate a miner sees when deciding whether to include `txid` since its
+    /// ancestors have to be included too. Returns `None` if the fee of any of the transactions
+    /// can't be calculated.
+    pub fn ancestor_feerate(&self, txid: Txid) -> Option<f32> {
+        let (mut fee, mut weight) = (0, 0);
+        for txid in self.unconfirmed_ancestors(txid).into_iter().chain([txid]) {
+            let tx = self.graph.get_tx(txid)?;
+            fee += self.graph.calculate_fee(tx)?;
+            weight += tx.weight();
+        }
+        Some(fee as f32 / weight as f32)
+    }
+
+    /// The transactions in the chain that spend the outputs of `txid` (keyed by output index).
+    pub fn outspends_in_chain(&self, txid: Txid) -> BTreeMap<u32, (&P, Txid)> {
+        self.graph
+            .tx_outspends(txid)
+            .filter_map(|(vout, _)| Some((vout, self.spent_by(OutPoint { txid, vout })?)))
+            .collect()
+    }
+
+    /// Finds clusters of unconfirmed transactions in the chain that conflict with each other.
+    ///
+    /// Conflicting transactions are kept in order of their feerate (as the highest is the one
+    /// most likely to be mined). Each kept transaction is the `canonical` transaction of a cluster
+    /// whose evicted transactions are the ones that spend the same output as it or as one of its
+    /// unconfirmed ancestors, along with their descendants in the chain. A transaction that only
+    /// conflicts with transactions that are evicted anyway is kept.
+    pub fn unconfirmed_conflict_clusters(&self) -> Vec<ConflictCluster> {
+        let unconfirmed = self
+            .chain
+            .range_txids_by_height(TxHeight::Unconfirmed..)
+            .filter_map(|(_, txid)| Some((*txid, self.graph.get_tx(*txid)?)))
+            .collect::<BTreeMap<_, _>>();
+        let conflicts = |txid: Txid| {
+            self.graph
+                .conflicting_txids(unconfirmed[&txid])
+                .map(|(_, conflicting_txid)| conflicting_txid)
+                .filter(|conflicting_txid| unconfirmed.contains_key(conflicting_txid))
+                .collect::<Vec<_>>()
+        };
+
+        let mut candidates = unconfirmed
+            .keys()
+            .copied()
+            .filter(|&txid| !conflicts(txid).is_empty())
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| {
+            let (tx_a, tx_b) = (unconfirmed[a], unconfirmed[b]);
+            let fee_a = self.graph.calculate_fee(tx_a).unwrap_or(0) as i128;
+            let fee_b = self.graph.calculate_fee(tx_b).unwrap_or(0) as i128;
+            // compare feerates without dividing, highest first
+            (fee_b * tx_a.weight() as i128)
+                .cmp(&(fee_a * tx_b.weight() as i128))
+                .then(b.cmp(a))
+        });
+
+        let mut all_evicted = BTreeSet::new();
+        let mut clusters = vec![];
+        for canonical in candidates {
+            if all_evicted.contains(&canonical) {
+                continue;
+            }
+            let mut to_visit = self
+                .unconfirmed_ancestors(canonical)
+                .into_iter()
+                .chain([canonical])
+                .filter(|txid| unconfirmed.contains_key(txid))
+                .flat_map(conflicts)
+                .filter(|txid| !all_evicted.contains(txid))
+                .collect::<Vec<_>>();
+            let mut evicted = BTreeSet::new();
+            while let Some(txid) = to_visit.pop() {
+                if !evicted.insert(txid) {
+                    continue;
+                }
+                to_visit.extend(
+                    self.graph
+                        .tx_outspends(txid)
+                        .flat_map(|(_, spends)| spends.iter().copied())
+                        .filter(|spend| self.chain.tx_position(*spend).is_some()),
+                );
+            }
+            if !evicted.is_empty() {
+                all_evicted.extend(evicted.iter().copied());
+                clusters.push(ConflictCluster { canonical, evicted });
+            }
+        }
+        clusters
+    }
+
+    /// Finds the unconfirmed transactions in the chain that may never confirm.
+    ///
+    /// A transaction is at risk if the graph knows of another transaction that conflicts with it
+    /// (e.g. a replacement seen in the mempool), or if it pays less than `min_feerate` (in sats per
+    /// weight unit). Transactions whose fee can't be calculated because some of their prevouts are
+    /// missing from the graph are only judged by their conflicts, as are all transactions when
+    /// `min_feerate` is zero. The unconfirmed descendants of an
+    /// at risk transaction are at risk as well.
+    pub fn at_risk_txids(&self, min_feerate: f32) -> BTreeSet<Txid> {
+        let mut to_visit = self
+            .chain
+            .range_txids_by_height(TxHeight::Unconfirmed..)
+            .filter_map(|(_, txid)| Some((*txid, self.graph.get_tx(*txid)?)))
+            .filter(|(_, tx)| {
+                let has_conflict = self.graph.conflicting_txids(tx).next().is_some();
+                let below_min_feerate = min_feerate > 0.0
+                    && matches!(
+                        self.graph.calculate_fee(tx),
+                        Some(fee) if (fee as f32) < min_feerate * tx.weight() as f32
+                    );
+                has_conflict || below_min_feerate
+            })
+            .map(|(txid, _)| txid)
+            .collect::<Vec<_>>();
+
+        let mut at_risk = BTreeSet::new();
+        while let Some(txid) = to_visit.pop() {
+            if !at_risk.insert(txid) {
+                continue;
+            }
+            to_visit.extend(
+                self.outspends_in_chain(txid)
+                    .into_values()
+                    .filter(|(pos, _)| !pos.height().is_confirmed())
+                    .map(|(_, spend)| spend),
+            );
+        }
+        at_risk
+    }
+
+    /// Determines the [`ChangeSet`] that evicts the non-canonical transactions of each cluster
+    /// found by [`unconfirmed_conflict_clusters`] from the chain.
+    ///
+    /// Note that the transaction data stays in the graph since it does not allow deletions.
+    ///
+    /// [`unconfirmed_conflict_clusters`]: Self::unconfirmed_conflict_clusters
+    pub fn compact_conflicts_preview(&self) -> ChangeSet<P> {
+        let mut changeset = ChangeSet::<P>::default();
+        for cluster in self.unconfirmed_conflict_clusters() {
+            for txid in cluster.evicted {
+                if self.chain.tx_position(txid).is_some() {
+                    changeset.chain.txids.insert(txid, None);
+                }
+            }
+        }
+        changeset
+    }
+
+    /// Evicts the non-canonical transactions of each cluster of conflicting unconfirmed
+    /// transactions from the chain.
+    ///
+    /// This is shorthand for calling [`compact_conflicts_preview`] and [`apply_changeset`] in
+    /// sequence.
+    ///
+    /// [`compact_conflicts_preview`]: Self::compact_conflicts_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn compact_conflicts(&mut self) -> ChangeSet<P> {
+        let changeset = self.compact_conflicts_preview();
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Determines the [`ChangeSet`] that evicts the unconfirmed transactions `txids` from the
+    /// chain along with their descendants in the chain, e.g. to abandon transactions that will
+    /// never confirm so that the outputs they spend can be spent again.
+    ///
+    /// Transactions that aren't in the chain are ignored. Like with [`compact_conflicts_preview`],
+    /// the transaction data stays in the graph.
+    ///
+    /// [`compact_conflicts_preview`]: Self::compact_conflicts_preview
+    pub fn evict_txs_preview(
+        &self,
+        txids: impl IntoIterator<Item = Txid>,
+    ) -> Result<ChangeSet<P>, EvictTxError<P>> {
+        let mut changeset = ChangeSet::<P>::default();
+        let mut to_visit = txids.into_iter().collect::<Vec<_>>();
+        while let Some(txid) = to_visit.pop() {
+            let position = match self.chain.tx_position(txid) {
+                Some(position) => position,
+                None => continue,
+            };
+            if position.height().is_confirmed() {
+                return Err(EvictTxError {
+                    txid,
+                    position: position.clone(),
+                });
+            }
+            if changeset.chain.txids.insert(txid, None).is_some() {
+                continue;
+            }
+            to_visit.extend(
+                self.outspends_in_chain(txid)
+                    .into_values()
+                    .map(|(_, spend)| spend),
+            );
+        }
+        Ok(changeset)
+    }
+
+    /// Evicts the unconfirmed transactions `txids` and their descendants from the chain.
+    ///
+    /// This is shorthand for calling [`evict_txs_preview`] and [`apply_changeset`] in sequence.
+    ///
+    /// [`evict_txs_preview`]: Self::evict_txs_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn evict_txs(
+        &mut self,
+        txids: impl IntoIterator<Item = Txid>,
+    ) -> Result<ChangeSet<P>, EvictTxError<P>> {
+        let changeset = self.evict_txs_preview(txids)?;
+        self.apply_changeset(changeset.clone());
+        Ok(changeset)
+    }
+
     /// Whether the chain graph contains any data whatsoever.
     pub fn is_empty(&self) -> bool {
         self.chain.is_empty() && self.graph.is_empty()
@@ -374,6 +779,31 @@ impl<P> ChangeSet<P> {
         self.chain.append(other.chain);
         self.graph.append(other.graph);
     }
+
+    /// Converts the chain positions of the changeset with `f`.
+    ///
+    /// See [`sparse_chain::ChangeSet::try_map_positions`].
+    pub fn try_map_positions<Q, E>(
+        self,
+        f: impl FnMut(P) -> Result<Q, E>,
+    ) -> Result<ChangeSet<Q>, E> {
+        Ok(ChangeSet {
+            chain: self.chain.try_map_positions(f)?,
+            graph: self.graph,
+        })
+    }
+}
+
+/// A cluster of conflicting unconfirmed transactions.
+///
+/// See [`ChainGraph::unconfirmed_conflict_clusters`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConflictCluster {
+    /// The transaction to keep
+    pub canonical: Txid,
+    /// The transactions that conflict with `canonical` (or its unconfirmed ancestors) and their
+    /// descendants
+    pub evicted: BTreeSet<Txid>,
 }
 
 impl<P> Default for ChangeSet<P> {
@@ -423,6 +853,27 @@ impl<P: core::fmt::Debug> std::error::Error for InsertTxError<P> {}
 
 pub type InsertCheckpointError = sparse_chain::InsertCheckpointError;
 
+/// A transaction couldn't be evicted from the chain because it (or one of its descendants) is
+/// confirmed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvictTxError<P> {
+    pub txid: Txid,
+    pub position: P,
+}
+
+impl<P: core::fmt::Debug> core::fmt::Display for EvictTxError<P> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "transaction {} can't be evicted since it is confirmed at {:?}",
+            self.txid, self.position
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: core::fmt::Debug> std::error::Error for EvictTxError<P> {}
+
 /// Represents an update failure.
 #[derive(Clone, Debug, PartialEq)]
 pub enum UpdateError<P> {
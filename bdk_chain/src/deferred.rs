@@ -0,0 +1,155 @@
+//! Transactions to broadcast once the chain has grown far enough.
+//!
+//! A transaction spending an output with a relative timelock (like the `to_local` output of a
+//! channel close) or with a locktime in the future is refused by nodes until the block it could be
+//! mined in is next. A [`DeferredQueue`] holds such transactions so that they can be signed ahead
+//! of time and broadcast by whoever updates the chain: after applying each update ask the queue
+//! which transactions are [`due`].
+//!
+//! [`due`]: DeferredQueue::due
+use crate::{
+    collections::BTreeMap,
+    sparse_chain::{ChainPosition, SparseChain},
+};
+use alloc::vec::Vec;
+use bitcoin::{Transaction, Txid};
+
+/// When a [`DeferredTx`] can be broadcast.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub enum BroadcastAfter {
+    /// The transaction can be mined in the block at this height (e.g. its locktime)
+    Height(u32),
+    /// The transaction can be mined `confirmations` blocks after the one that confirms `txid`
+    /// (e.g. the `older` timelock of an output of `txid` it spends)
+    Confirmations {
+        /// The transaction that has to be confirmed first
+        txid: Txid,
+        /// The number of confirmations it needs
+        confirmations: u32,
+    },
+}
+
+impl BroadcastAfter {
+    /// The height of the first block the transaction can be mined in according to `chain`.
+    ///
+    /// `None` if it depends on a transaction that isn't confirmed in `chain`.
+    pub fn height<P: ChainPosition>(&self, chain: &SparseChain<P>) -> Option<u32> {
+        match self {
+            BroadcastAfter::Height(height) => Some(*height),
+            BroadcastAfter::Confirmations {
+                txid,
+                confirmations,
+            } => {
+                let confirmed_at: Option<u32> = chain.tx_position(*txid)?.height().into();
+                Some(confirmed_at? + confirmations)
+            }
+        }
+    }
+}
+
+/// A transaction waiting in a [`DeferredQueue`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct DeferredTx {
+    /// The transaction (usually already signed)
+    pub tx: Transaction,
+    /// When it can be broadcast
+    pub after: BroadcastAfter,
+}
+
+/// Transactions waiting for the chain to reach the height they can be mined at.
+///
+/// See the [module level documentation] for more.
+///
+/// [module level documentation]: crate::deferred
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct DeferredQueue {
+    txs: BTreeMap<Txid, DeferredTx>,
+}
+
+impl DeferredQueue {
+    /// Adds `tx` to the queue to be broadcast `after` the given condition is met. Returns the
+    /// transaction it replaces if one with the same txid was already queued.
+    pub fn insert(&mut self, tx: Transaction, after: BroadcastAfter) -> Option<DeferredTx> {
+        self.txs.insert(tx.txid(), DeferredTx { tx, after })
+    }
+
+    /// Removes the transaction with `txid` from the queue.
+    pub fn remove(&mut self, txid: Txid) -> Option<DeferredTx> {
+        self.txs.remove(&txid)
+    }
+
+    /// The queued transaction with `txid`.
+    pub fn get(&self, txid: Txid) -> Option<&DeferredTx> {
+        self.txs.get(&txid)
+    }
+
+    /// Iterates over the queued transactions in txid order.
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &DeferredTx> + '_ {
+        self.txs.values()
+    }
+
+    /// The number of queued transactions.
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    /// Whether the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// The queued transactions that can be mined in the block after the tip of `chain` and that
+    /// `chain` doesn't have yet.
+    ///
+    /// Nothing is due if `chain` has no checkpoints. Transactions stay in the queue until
+    /// [`remove_confirmed`] sees them confirmed so a broadcast that got lost is retried after the
+    /// next update.
+    ///
+    /// [`remove_confirmed`]: Self::remove_confirmed
+    pub fn due<'a, P: ChainPosition>(
+        &'a self,
+        chain: &'a SparseChain<P>,
+    ) -> impl Iterator<Item = &'a DeferredTx> + 'a {
+        let next_height = chain.latest_checkpoint().map(|tip| tip.height + 1);
+        self.txs.values().filter(move |deferred| {
+            matches!(
+                (next_height, deferred.after.height(chain)),
+                (Some(next_height), Some(height)) if height <= next_height
+            ) && chain.tx_position(deferred.tx.txid()).is_none()
+        })
+    }
+
+    /// Removes the transactions that are confirmed in `chain` and returns them.
+    pub fn remove_confirmed<P: ChainPosition>(
+        &mut self,
+        chain: &SparseChain<P>,
+    ) -> Vec<DeferredTx> {
+        let confirmed = self
+            .txs
+            .keys()
+            .filter(|txid| {
+                matches!(chain.tx_position(**txid), Some(position) if position.height().is_confirmed())
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        confirmed
+            .into_iter()
+            .filter_map(|txid| self.txs.remove(&txid))
+            .collect()
+    }
+}
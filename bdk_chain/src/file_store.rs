@@ -1,54 +1,382 @@
 use crate::{
-    collections::BTreeMap,
-    keychain::{KeychainChangeSet, KeychainTracker},
-    sparse_chain,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    keychain::{KeychainChangeSet, KeychainTracker, PersistBackend},
+    sparse_chain::PositionSchema,
+    tx_graph::TxProvider,
+    ConfirmationTime, TxHeight,
+};
+use alloc::{string::String, vec::Vec};
+use bitcoin::{
+    consensus::encode::{deserialize, serialize},
+    hashes::{sha256, Hash},
+    Transaction, Txid,
 };
-use core::marker::PhantomData;
 use std::{
     fs::{File, OpenOptions},
-    io::{self, Seek},
-    path::Path,
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
 };
+#[cfg(feature = "encryption")]
+use zeroize::Zeroizing;
+
+/// The bytes every store starts with. The first byte can't begin a bincode encoded changeset so
+/// stores written before the header was introduced can still be recognised.
+const MAGIC_BYTES: [u8; 4] = [0xff, b'b', b'd', b'k'];
+
+/// The version of the file format that comes after the magic bytes.
+///
+/// Stores without a header, the format they were first written in, only contain changesets. Since
+/// version 2 they contain [`StoreEntry`]s which can also be extension blobs, each one framed as a
+/// record with its length and checksums (see [`write_record`]) so damage to the file can be told
+/// apart from it having been cut short. After the version come the [`PositionSchema::SCHEMA_TAG`]
+/// and the flags of the store.
+const FORMAT_VERSION: u8 = 2;
+
+/// The flag of encrypted stores. Each of their records is encrypted and the header is followed by
+/// the salt the key is derived with and a value to check the password with.
+const ENCRYPTED_FLAG: u8 = 1;
+
+/// The flag of hash chained stores (see [`KeychainStore::set_hash_chain`]). Each of their records
+/// starts with the SHA256 of the record before it (of the header for the first one), see
+/// [`follow_link`]. The hashes are of the encrypted records so the chain can be followed without
+/// the password.
+const HASH_CHAINED_FLAG: u8 = 2;
+
+/// The length of the frame in front of each record: the length of the entry, the checksum of
+/// the length and the checksum of the entry.
+const RECORD_HEADER_LEN: u64 = 12;
+
+/// The bytes a [`RawTxStore`] starts with.
+const RAW_TX_MAGIC_BYTES: [u8; 4] = [0xff, b't', b'x', b's'];
+
+/// The version of the [`RawTxStore`] file format that comes after its magic bytes.
+const RAW_TX_FORMAT_VERSION: u8 = 1;
+
+/// The length of the salt the key of an encrypted store is derived with.
+#[cfg(feature = "encryption")]
+const SALT_LEN: usize = 16;
+
+/// The length of the nonce prepended to each encrypted entry.
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 24;
 
 /// Persists an append only list of `KeychainChangeSet<K,P>` to a single file.
 /// [`KeychainChangeSet<K,P>`] record the changes made to a [`KeychainTracker<K,P>`].
+///
+/// The file starts with a header recording the [`PositionSchema`] of `P` so a store can't be
+/// loaded with the wrong kind of chain position. Use [`migrate`] to change the position type of an
+/// existing store.
+///
+/// Alongside the changesets the store can hold named extension blobs (see [`append_extension`]).
+/// These are opaque to the store and are meant for chain source specific progress like the last
+/// scanned height or script status hashes so that syncing can resume where it left off.
+///
+/// Since entries are only ever appended the file keeps growing. [`compact`] folds everything into a
+/// single snapshot and [`set_auto_compact`] makes the store do so by itself once there are too many
+/// entries.
+///
+/// With the `encryption` feature a store can be created with [`new_encrypted`] so each entry is
+/// encrypted with XChaCha20-Poly1305 under a key derived from a password with Argon2id. Only the
+/// header (and the number and size of the entries) can be seen without the password.
+///
+/// Every entry is written with a checksum. When an entry can't be read the [`IterError`] tells a
+/// file that was cut off in the middle of its last entry ([`IterError::Truncated`]) apart from one
+/// that was damaged somewhere ([`IterError::Corrupted`]) and which entry it was. See [`recover`].
+/// Stores written before there was a header only get them once they are [`compact`]ed.
+///
+/// A store can hold several wallets. Each named wallet has its own changesets and extension blobs
+/// next to those of the default wallet (the one a store that has never been given a name uses).
+/// [`open_wallet`] picks the wallet the other methods read and write.
+///
+/// A store can be turned into a tamper-evident log with [`set_hash_chain`]. Each entry then
+/// carries the hash of the one before it so the [`head_hash`] of the store commits to all of them.
+///
+/// [`migrate`]: Self::migrate
+/// [`append_extension`]: Self::append_extension
+/// [`compact`]: Self::compact
+/// [`set_auto_compact`]: Self::set_auto_compact
+/// [`new_encrypted`]: Self::new_encrypted
+/// [`open_wallet`]: Self::open_wallet
+/// [`recover`]: Self::recover
+/// [`set_hash_chain`]: Self::set_hash_chain
+/// [`head_hash`]: Self::head_hash
 #[derive(Debug)]
 pub struct KeychainStore<K, P> {
     db_file: File,
+    /// Encrypts and decrypts the entries if the store is encrypted
+    cipher: Option<StoreCipher>,
+    /// Where the first changeset starts (after the header if there is one)
+    data_start: u64,
+    /// Whether the file has no header, as stores written before there was one don't. Their
+    /// entries are unframed changesets.
+    headerless: bool,
+    /// Whether each record carries the hash of the one before it
+    hash_chained: bool,
+    /// The number of entries in the file. Only known once they have all been read successfully.
+    entry_count: Option<usize>,
+    /// The number of entries the store was last rewritten with. They don't count towards
+    /// `auto_compact` since compacting again wouldn't make them any fewer.
+    snapshot_entries: usize,
+    /// Compact the store when an append takes the number of entries past this
+    auto_compact: Option<usize>,
+    /// The path the file was opened from. The store can only be rewritten (e.g. compacted) if it
+    /// is known since the new contents are written next to it first.
+    db_path: Option<PathBuf>,
+    /// The named wallet the store reads and writes (`None` for the default wallet)
+    wallet: Option<String>,
+    /// The hash of the record before the write position of a hash chained store. Forgotten
+    /// whenever the entries are iterated over since that moves the write position.
+    chain_head: Option<sha256::Hash>,
     chain_index: core::marker::PhantomData<(K, P)>,
 }
 
+/// An entry in a [`KeychainStore`].
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "serde_crate")]
+pub enum StoreEntry<C> {
+    /// A changeset to apply to the tracker
+    ChangeSet(C),
+    /// An extension blob. Later blobs with the same `name` replace earlier ones.
+    Extension {
+        /// The name the blob is stored under
+        name: String,
+        /// The contents of the blob
+        data: Vec<u8>,
+    },
+    /// A changeset of a named wallet (see [`KeychainStore::open_wallet`])
+    WalletChangeSet {
+        /// The name of the wallet
+        wallet: String,
+        /// The changeset
+        changeset: C,
+    },
+    /// An extension blob of a named wallet
+    WalletExtension {
+        /// The name of the wallet
+        wallet: String,
+        /// The name the blob is stored under
+        name: String,
+        /// The contents of the blob
+        data: Vec<u8>,
+    },
+}
+
+impl<C> StoreEntry<C> {
+    /// The named wallet the entry belongs to. `None` if it belongs to the default wallet.
+    pub fn wallet(&self) -> Option<&str> {
+        match self {
+            StoreEntry::ChangeSet(_) | StoreEntry::Extension { .. } => None,
+            StoreEntry::WalletChangeSet { wallet, .. }
+            | StoreEntry::WalletExtension { wallet, .. } => Some(wallet),
+        }
+    }
+
+    fn changeset(wallet: Option<&str>, changeset: C) -> Self {
+        match wallet {
+            Some(wallet) => StoreEntry::WalletChangeSet {
+                wallet: wallet.into(),
+                changeset,
+            },
+            None => StoreEntry::ChangeSet(changeset),
+        }
+    }
+
+    fn extension(wallet: Option<&str>, name: String, data: Vec<u8>) -> Self {
+        match wallet {
+            Some(wallet) => StoreEntry::WalletExtension {
+                wallet: wallet.into(),
+                name,
+                data,
+            },
+            None => StoreEntry::Extension { name, data },
+        }
+    }
+
+    /// Splits the entry into the wallet it belongs to and what it would be in the default wallet.
+    fn into_default_wallet(self) -> (Option<String>, Self) {
+        match self {
+            StoreEntry::WalletChangeSet { wallet, changeset } => {
+                (Some(wallet), StoreEntry::ChangeSet(changeset))
+            }
+            StoreEntry::WalletExtension { wallet, name, data } => {
+                (Some(wallet), StoreEntry::Extension { name, data })
+            }
+            entry => (None, entry),
+        }
+    }
+}
+
+/// The aggregate changeset and latest extension blobs of each wallet in a store.
+type WalletSnapshots<K, P> =
+    BTreeMap<Option<String>, (KeychainChangeSet<K, P>, BTreeMap<String, Vec<u8>>)>;
+
 impl<K, P> KeychainStore<K, P>
 where
     K: Ord + Clone + core::fmt::Debug,
-    P: sparse_chain::ChainPosition,
+    P: PositionSchema,
     KeychainChangeSet<K, P>: serde::Serialize + serde::de::DeserializeOwned,
 {
     /// Creates a new store from a [`File`].
     ///
-    /// The file must have been opened with read, write permissions.
+    /// The file must have been opened with read, write permissions. If it is empty the header is
+    /// written to it, otherwise the header is checked against `P`. Files without a header (written
+    /// by older versions) are assumed to contain `P`.
+    ///
+    /// Encrypted stores can't be opened this way, see [`new_encrypted`].
     ///
     /// [`File`]: std::fs::File
-    pub fn new(file: File) -> Self {
-        Self {
+    /// [`new_encrypted`]: Self::new_encrypted
+    pub fn new(file: File) -> Result<Self, FileError> {
+        Self::open(file, None)
+    }
+
+    /// Creates a new encrypted store from a [`File`] or opens an existing one with `password`.
+    ///
+    /// Like [`new`] an empty file gets a header (with a fresh random salt). An existing file must
+    /// be an encrypted store and `password` must be the one it was created with. Use
+    /// [`set_password`] to encrypt a store that isn't.
+    ///
+    /// [`File`]: std::fs::File
+    /// [`new`]: Self::new
+    /// [`set_password`]: Self::set_password
+    #[cfg(feature = "encryption")]
+    pub fn new_encrypted(file: File, password: &[u8]) -> Result<Self, FileError> {
+        Self::open(file, Some(password))
+    }
+
+    fn open(mut file: File, password: Option<&[u8]>) -> Result<Self, FileError> {
+        let is_empty = file.seek(io::SeekFrom::End(0))? == 0;
+        let (data_start, headerless, hash_chained, cipher) = if is_empty {
+            let mut cipher = password.map(StoreCipher::new);
+            let data_start = write_header(&mut file, P::SCHEMA_TAG, cipher.as_mut(), false)?;
+            (data_start, false, false, cipher)
+        } else {
+            file.rewind()?;
+            match read_header(&mut file)? {
+                Some((schema_tag, _)) if schema_tag != P::SCHEMA_TAG => {
+                    return Err(FileError::SchemaMismatch {
+                        expected: P::SCHEMA_TAG,
+                        found: schema_tag,
+                    })
+                }
+                Some((schema_tag, flags)) => {
+                    let cipher = match (flags & ENCRYPTED_FLAG != 0, password) {
+                        (true, Some(password)) => Some(StoreCipher::read(
+                            &mut file,
+                            password,
+                            &header(schema_tag, flags),
+                        )?),
+                        (true, None) => return Err(FileError::Encrypted),
+                        (false, Some(_)) => return Err(FileError::NotEncrypted),
+                        (false, None) => None,
+                    };
+                    let hash_chained = flags & HASH_CHAINED_FLAG != 0;
+                    (file.stream_position()?, false, hash_chained, cipher)
+                }
+                None if password.is_some() => return Err(FileError::NotEncrypted),
+                None => (0, true, false, None),
+            }
+        };
+        file.seek(io::SeekFrom::Start(data_start))?;
+
+        Ok(Self {
             db_file: file,
+            cipher,
+            data_start,
+            headerless,
+            hash_chained,
+            entry_count: if is_empty { Some(0) } else { None },
+            snapshot_entries: 0,
+            auto_compact: None,
+            db_path: None,
+            wallet: None,
+            chain_head: None,
             chain_index: Default::default(),
-        }
+        })
     }
 
     /// Creates or loads a a store from `db_path`. If no file exists there it will be created.
-    pub fn new_from_path(db_path: &Path) -> Result<Self, io::Error> {
-        let db_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(db_path.clone())?;
+    pub fn new_from_path(db_path: &Path) -> Result<Self, FileError> {
+        let mut store = Self::new(open_db_file(db_path)?)?;
+        store.db_path = Some(db_path.into());
+        Ok(store)
+    }
+
+    /// Creates or loads an encrypted store from `db_path` (see [`new_encrypted`]).
+    ///
+    /// [`new_encrypted`]: Self::new_encrypted
+    #[cfg(feature = "encryption")]
+    pub fn new_encrypted_from_path(db_path: &Path, password: &[u8]) -> Result<Self, FileError> {
+        let mut store = Self::new_encrypted(open_db_file(db_path)?, password)?;
+        store.db_path = Some(db_path.into());
+        Ok(store)
+    }
+
+    /// Whether the entries of the store are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Whether each entry of the store carries the hash of the one before it (see
+    /// [`set_hash_chain`]).
+    ///
+    /// [`set_hash_chain`]: Self::set_hash_chain
+    pub fn is_hash_chained(&self) -> bool {
+        self.hash_chained
+    }
+
+    /// Makes the store read and write the changesets and extension blobs of the wallet called
+    /// `name` rather than those of the wallet it had open.
+    ///
+    /// A wallet that isn't in the store yet is created by appending to it. Switch between wallets
+    /// rather than opening the same file more than once since each [`KeychainStore`] appends at
+    /// its own position.
+    pub fn open_wallet(mut self, name: &str) -> Self {
+        self.wallet = Some(name.into());
+        self
+    }
+
+    /// Makes the store read and write the default wallet again (see [`open_wallet`]).
+    ///
+    /// [`open_wallet`]: Self::open_wallet
+    pub fn open_default_wallet(mut self) -> Self {
+        self.wallet = None;
+        self
+    }
+
+    /// The name of the wallet the store has open. `None` for the default wallet.
+    pub fn wallet(&self) -> Option<&str> {
+        self.wallet.as_deref()
+    }
+
+    /// The names of the wallets in the store apart from the default one, along with the result of
+    /// reading the entries like [`aggregate_changeset`].
+    ///
+    /// [`aggregate_changeset`]: Self::aggregate_changeset
+    pub fn wallets(&mut self) -> (BTreeSet<String>, Result<(), IterError>) {
+        let mut wallets = BTreeSet::new();
+        let result = (|| {
+            for entry in self.iter_entries()? {
+                if let Some(wallet) = entry?.wallet() {
+                    if !wallets.contains(wallet) {
+                        wallets.insert(wallet.into());
+                    }
+                }
+            }
+            Ok(())
+        })();
 
-        Ok(Self::new(db_file))
+        (wallets, result)
     }
 
-    /// Iterates over the stored changeset from first to last changing the seek position at each
-    /// iteration.
+    /// Iterates over the stored entries (of every wallet) from first to last changing the seek
+    /// position at each iteration.
     ///
     /// The iterator may fail to read an entry and therefore return an error. However the first time
     /// it returns an error will be the last. After doing so the iterator will always yield `None`.
@@ -56,13 +384,54 @@ where
     /// **WARNING**: This method changes the write position in the underlying file. You should
     /// always iterate over all entries until `None` is returned if you want your next write to go
     /// at the end, otherwise you writing over existing enties.
-    pub fn iter_changesets(&mut self) -> Result<EntryIter<'_, KeychainChangeSet<K, P>>, io::Error> {
-        self.db_file.rewind()?;
+    pub fn iter_entries(
+        &mut self,
+    ) -> Result<EntryIter<'_, StoreEntry<KeychainChangeSet<K, P>>>, io::Error> {
+        self.chain_head = None;
+        let chain = match self.is_hash_chained() {
+            true => Some(chain_genesis(&mut self.db_file, self.data_start)?),
+            false => None,
+        };
+        self.db_file.seek(io::SeekFrom::Start(self.data_start))?;
+
+        if self.headerless {
+            // older stores only contain changesets
+            Ok(EntryIter::with_decoder(&mut self.db_file, |file| {
+                decode_entry::<KeychainChangeSet<K, P>>(file).map(StoreEntry::ChangeSet)
+            }))
+        } else {
+            Ok(EntryIter::framed(
+                &mut self.db_file,
+                self.cipher.as_ref(),
+                chain,
+            ))
+        }
+    }
 
-        Ok(EntryIter::new(&mut self.db_file))
+    /// Iterates over the stored changesets of the open wallet from first to last, skipping
+    /// extension blobs.
+    ///
+    /// See [`iter_entries`] for how errors are returned and the **WARNING** about the write
+    /// position.
+    ///
+    /// [`iter_entries`]: Self::iter_entries
+    pub fn iter_changesets(
+        &mut self,
+    ) -> Result<impl Iterator<Item = Result<KeychainChangeSet<K, P>, IterError>> + '_, io::Error>
+    {
+        let open_wallet = self.wallet.clone();
+        Ok(self.iter_entries()?.filter_map(move |entry| {
+            match entry.map(StoreEntry::into_default_wallet) {
+                Ok((wallet, StoreEntry::ChangeSet(changeset))) if wallet == open_wallet => {
+                    Some(Ok(changeset))
+                }
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            }
+        }))
     }
 
-    /// Loads all the changesets that have been stored as one giant changeset.
+    /// Loads all the changesets of the open wallet as one giant changeset.
     ///
     /// This function returns a tuple of the aggregate changeset and a result which indicates
     /// whether an error occurred while reading or deserializing one of the entries. If so the
@@ -76,19 +445,57 @@ where
     /// changeset will be written over the erroring entry (or the end of the file if none existed).
     pub fn aggregate_changeset(&mut self) -> (KeychainChangeSet<K, P>, Result<(), IterError>) {
         let mut changeset = KeychainChangeSet::default();
+        let mut entry_count = 0;
+        let open_wallet = self.wallet.clone();
         let result = (|| {
-            let iter_changeset = self.iter_changesets()?;
-            for next_changeset in iter_changeset {
-                changeset.append(next_changeset?);
+            for entry in self.iter_entries()? {
+                if let (wallet, StoreEntry::ChangeSet(next_changeset)) =
+                    entry?.into_default_wallet()
+                {
+                    if wallet == open_wallet {
+                        changeset.append(next_changeset);
+                    }
+                }
+                entry_count += 1;
             }
             Ok(())
         })();
+        if result.is_ok() {
+            self.entry_count = Some(entry_count);
+        }
 
         (changeset, result)
     }
 
-    /// Reads and applies all the changesets stored sequentially to tracker, stopping when it fails
-    /// to read the next one.
+    /// Loads the latest version of every extension blob of the open wallet.
+    ///
+    /// Like [`aggregate_changeset`] this returns what it was able to read along with the result of
+    /// reading the entries.
+    ///
+    /// **WARNING**: This method changes the write position of the underlying file. The next
+    /// changeset will be written over the erroring entry (or the end of the file if none existed).
+    ///
+    /// [`aggregate_changeset`]: Self::aggregate_changeset
+    pub fn aggregate_extensions(&mut self) -> (BTreeMap<String, Vec<u8>>, Result<(), IterError>) {
+        let mut extensions = BTreeMap::new();
+        let open_wallet = self.wallet.clone();
+        let result = (|| {
+            for entry in self.iter_entries()? {
+                if let (wallet, StoreEntry::Extension { name, data }) = entry?.into_default_wallet()
+                {
+                    if wallet == open_wallet {
+                        extensions.insert(name, data);
+                    }
+                }
+            }
+            Ok(())
+        })();
+
+        (extensions, result)
+    }
+
+    /// Reads and applies all the changesets of the open wallet sequentially to tracker, stopping
+    /// when it fails to read the next one.
     ///
     /// **WARNING**: This method changes the write position of the underlying file. The next
     /// changeset will be written over the erroring entry (or the end of the file if none existed).
@@ -96,33 +503,55 @@ where
         &mut self,
         tracker: &mut KeychainTracker<K, P>,
     ) -> Result<(), IterError> {
-        for changeset in self.iter_changesets()? {
-            tracker.apply_changeset(changeset?)
+        let mut entry_count = 0;
+        let open_wallet = self.wallet.clone();
+        for entry in self.iter_entries()? {
+            if let (wallet, StoreEntry::ChangeSet(changeset)) = entry?.into_default_wallet() {
+                if wallet == open_wallet {
+                    tracker.apply_changeset(changeset);
+                }
+            }
+            entry_count += 1;
         }
+        self.entry_count = Some(entry_count);
         Ok(())
     }
 
-    /// Append a new changeset to the file.
+    /// Append a new changeset of the open wallet to the file.
+    ///
+    /// Like [`append_extension`], named wallets can't be written to stores without a header.
+    ///
+    /// [`append_extension`]: Self::append_extension
     pub fn append_changeset(
         &mut self,
         changeset: &KeychainChangeSet<K, P>,
     ) -> Result<(), io::Error> {
         if !changeset.is_empty() {
-            bincode::encode_into_std_write(
-                bincode::serde::Compat(changeset),
-                &mut self.db_file,
-                bincode::config::standard(),
-            )
-            .map_err(|e| match e {
-                bincode::error::EncodeError::Io { inner, .. } => inner,
-                unexpected_err => panic!("unexpected bincode error: {}", unexpected_err),
-            })?;
+            if self.headerless {
+                if self.wallet.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "stores without a header don't support named wallets",
+                    ));
+                }
+                encode_entry(&mut self.db_file, changeset)?;
+            } else {
+                let index = self.next_entry_index()?;
+                self.find_chain_head()?;
+                write_entry(
+                    &mut self.db_file,
+                    self.cipher.as_ref().zip(index),
+                    self.chain_head.as_mut(),
+                    &StoreEntry::changeset(self.wallet.as_deref(), changeset),
+                )?;
+            }
 
             // We want to make sure that derivation indexe changes are written to disk as soon as
             // possible so you know about the write failure before you give ou the address in the application.
             if !changeset.derivation_indices.is_empty() {
                 self.db_file.sync_data()?;
             }
+            self.entry_appended()?;
         }
 
         Ok(())
@@ -138,12 +567,1354 @@ where
 
         Ok(())
     }
+
+    /// Appends an extension blob of the open wallet stored under `name`, replacing any earlier blob
+    /// with that name when the store is next loaded.
+    ///
+    /// Stores without a header predate extension blobs so they have to be rewritten with
+    /// [`migrate`] (e.g. `store.migrate(Ok)`) first, otherwise this returns an
+    /// [`io::ErrorKind::Unsupported`] error.
+    ///
+    /// [`migrate`]: Self::migrate
+    pub fn append_extension(&mut self, name: &str, data: &[u8]) -> Result<(), io::Error> {
+        if self.headerless {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "stores without a header don't support extension blobs",
+            ));
+        }
+
+        let index = self.next_entry_index()?;
+        self.find_chain_head()?;
+        write_entry(
+            &mut self.db_file,
+            self.cipher.as_ref().zip(index),
+            self.chain_head.as_mut(),
+            &StoreEntry::<()>::extension(self.wallet.as_deref(), name.into(), data.into()),
+        )?;
+        self.db_file.sync_data()?;
+        self.entry_appended()
+    }
+
+    /// Makes the store [`compact`] itself whenever an append takes the number of entries in it past
+    /// `max_entries` (or never if `None`).
+    ///
+    /// The store only knows how many entries it has once they have all been read (e.g. by
+    /// [`load_into_keychain_tracker`]) so nothing happens until then. The entries of the snapshot
+    /// the store was last compacted into don't count. Stores created from a [`File`] rather than
+    /// a path are never compacted by themselves (see [`compact`]).
+    ///
+    /// [`compact`]: Self::compact
+    /// [`load_into_keychain_tracker`]: Self::load_into_keychain_tracker
+    pub fn set_auto_compact(&mut self, max_entries: Option<usize>) {
+        self.auto_compact = max_entries;
+    }
+
+    /// Rewrites the store so it only contains a single changeset (the aggregate of all of them)
+    /// followed by the latest version of each extension blob for each of its wallets.
+    ///
+    /// Loading the compacted store gives the same result as before but it takes up less space and
+    /// is faster to load. New entries are appended after the snapshot as usual. Stores written in an
+    /// older format (e.g. one that predates extension blobs) are upgraded to the current one.
+    ///
+    /// Nothing is written if any of the entries can't be read. The snapshot is written to
+    /// `<path>.tmp` and only renamed over the store once it has been synced to disk, so the store
+    /// is left as it was if that fails. This is why stores created from a [`File`] rather than a
+    /// path (with [`new`] or [`new_encrypted`]) can't be compacted: it returns an
+    /// [`io::ErrorKind::Unsupported`] error.
+    ///
+    /// [`new`]: Self::new
+    /// [`new_encrypted`]: Self::new_encrypted
+    pub fn compact(&mut self) -> Result<(), CompactError> {
+        self.rewrite(|_| {}, self.is_hash_chained())
+    }
+
+    /// Encrypts the store with a key derived from `password`, or decrypts it if `None`.
+    ///
+    /// The store is rewritten like [`compact`] does so this also changes the password of a store
+    /// that is already encrypted (a new salt is used each time). Nothing is written if any of the
+    /// entries can't be read. The file is replaced rather than overwritten, so an interrupted
+    /// change leaves the store as it was and no entries under the old password (or unencrypted
+    /// ones) are left in the file.
+    ///
+    /// [`compact`]: Self::compact
+    #[cfg(feature = "encryption")]
+    pub fn set_password(&mut self, password: Option<&[u8]>) -> Result<(), CompactError> {
+        self.rewrite(
+            |cipher| *cipher = password.map(StoreCipher::new),
+            self.is_hash_chained(),
+        )
+    }
+
+    /// Makes each entry of the store carry the hash of the entry before it, or stops doing so if
+    /// `enabled` is false.
+    ///
+    /// The first entry carries the hash of the header, so the [`head_hash`] of the store commits
+    /// to the header and every entry in order. Back it up somewhere the wallet can't write to
+    /// each time the wallet appends something. A store whose entries were changed, removed or
+    /// reordered since then no longer leads to the backed up hash, which [`find_head`] checks.
+    /// Anyone able to rewrite the file can also recompute the hashes, so it is the copy kept
+    /// elsewhere that makes tampering evident.
+    ///
+    /// The store is rewritten like [`compact`] does, replacing the file only once the rewritten
+    /// one is on disk. Compacting starts a new chain from the snapshot, so hashes backed up before
+    /// that no longer appear in it. Nothing is written if any of the entries can't be read.
+    ///
+    /// [`head_hash`]: Self::head_hash
+    /// [`find_head`]: Self::find_head
+    /// [`compact`]: Self::compact
+    pub fn set_hash_chain(&mut self, enabled: bool) -> Result<(), CompactError> {
+        self.rewrite(|_| {}, enabled)
+    }
+
+    /// The hash of the last entry of a hash chained store (see [`set_hash_chain`]), or `None` if
+    /// the store isn't hash chained.
+    ///
+    /// Every entry is read and checked to carry the hash of the one before it. The write position
+    /// is left at the end of the store.
+    ///
+    /// [`set_hash_chain`]: Self::set_hash_chain
+    pub fn head_hash(&mut self) -> Result<Option<sha256::Hash>, IterError> {
+        if !self.is_hash_chained() {
+            return Ok(None);
+        }
+        let (head, result) = self.follow_chain(|_, _| {})?;
+        result.map(|()| Some(head))
+    }
+
+    /// The number of entries the store had when its [`head_hash`] was `head`, or `None` if the
+    /// chain of hashes never passes through it.
+    ///
+    /// A hash backed up earlier that the store no longer leads to means the entries before it
+    /// were changed since (or that the store has been compacted). Stores that aren't hash chained
+    /// never contain `head`. The write position is left at the end of the store.
+    ///
+    /// [`head_hash`]: Self::head_hash
+    pub fn find_head(&mut self, head: sha256::Hash) -> Result<Option<usize>, IterError> {
+        if !self.is_hash_chained() {
+            return Ok(None);
+        }
+        let mut found = None;
+        let (_, result) = self.follow_chain(|entries, hash| {
+            if hash == head && found.is_none() {
+                found = Some(entries);
+            }
+        })?;
+        result.map(|()| found)
+    }
+
+    /// Follows the chain of hashes from the header up to the first record that is damaged or
+    /// doesn't carry the hash of the one before it, calling `visit` with the number of entries
+    /// each hash covers.
+    ///
+    /// The write position is left at the start of that record (or the end of the store) and the
+    /// hash of the record before it becomes the head the next append carries. Returns it along
+    /// with why the chain stopped early, if it did.
+    fn follow_chain(
+        &mut self,
+        mut visit: impl FnMut(usize, sha256::Hash),
+    ) -> Result<(sha256::Hash, Result<(), IterError>), io::Error> {
+        let mut head = chain_genesis(&mut self.db_file, self.data_start)?;
+        let mut entry = 0;
+        visit(entry, head);
+        let result = loop {
+            let offset = self.db_file.stream_position()?;
+            let error = match read_record(&self.db_file)? {
+                Record::End => break Ok(()),
+                Record::Intact(record) => match follow_link(&mut head, record) {
+                    Some(_) => {
+                        entry += 1;
+                        visit(entry, head);
+                        continue;
+                    }
+                    None => IterError::BrokenChain { entry, offset },
+                },
+                Record::Truncated => IterError::Truncated { entry, offset },
+                Record::Corrupted => IterError::Corrupted { entry, offset },
+            };
+            self.db_file.seek(io::SeekFrom::Start(offset))?;
+            break Err(error);
+        };
+        self.chain_head = Some(head);
+        Ok((head, result))
+    }
+
+    /// The index the next entry of an encrypted store is sealed with (`None` if the store isn't
+    /// encrypted), counting the entries first if that hasn't been done yet.
+    ///
+    /// Like after a failed load, the next entry goes over the first one that can't be read so
+    /// only the entries before it count.
+    fn next_entry_index(&mut self) -> Result<Option<usize>, io::Error> {
+        if self.cipher.is_none() {
+            return Ok(None);
+        }
+        if let Some(entry_count) = self.entry_count {
+            return Ok(Some(entry_count));
+        }
+        let mut entry_count = 0;
+        for entry in self.iter_entries()? {
+            match entry {
+                Ok(_) => entry_count += 1,
+                Err(IterError::Io(e)) => return Err(e),
+                Err(_) => break,
+            }
+        }
+        self.entry_count = Some(entry_count);
+        Ok(Some(entry_count))
+    }
+
+    /// Works out the hash the next entry of a hash chained store has to carry, if it isn't known
+    /// already.
+    fn find_chain_head(&mut self) -> Result<(), io::Error> {
+        if self.is_hash_chained() && self.chain_head.is_none() {
+            // like after a failed load, the next entry goes over the first one that can't be read
+            let _ = self.follow_chain(|_, _| {})?;
+        }
+        Ok(())
+    }
+
+    /// Reads the aggregate changeset and the latest version of each extension blob of every wallet
+    /// and then replaces the file with one holding only them (see [`replace`]), encrypted with the
+    /// cipher `change_cipher` makes of the current one. The rewritten store is hash chained if
+    /// `chained` is true.
+    ///
+    /// [`replace`]: Self::replace
+    fn rewrite(
+        &mut self,
+        change_cipher: impl FnOnce(&mut Option<StoreCipher>),
+        chained: bool,
+    ) -> Result<(), CompactError> {
+        let snapshots = self.wallet_snapshots().map_err(CompactError::Iter)?;
+        let mut cipher = self.cipher.clone();
+        change_cipher(&mut cipher);
+
+        *self = self.replace(cipher, chained, snapshots)?;
+        Ok(())
+    }
+
+    /// Writes a store holding `snapshots` to `<path>.tmp` and renames it over the file of this
+    /// one once it has been synced to disk, returning the new store.
+    ///
+    /// The file of this store is only touched by the rename, so it is left as it was if anything
+    /// fails. Stores that weren't opened from a path can't be replaced.
+    fn replace<Q>(
+        &self,
+        cipher: Option<StoreCipher>,
+        chained: bool,
+        snapshots: WalletSnapshots<K, Q>,
+    ) -> Result<KeychainStore<K, Q>, io::Error>
+    where
+        Q: PositionSchema,
+        KeychainChangeSet<K, Q>: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let db_path = self.db_path.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "stores created from a file rather than a path can't be rewritten",
+            )
+        })?;
+        let tmp_path = tmp_path(db_path);
+        let written = (|| {
+            let db_file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp_path)?;
+            let mut store = KeychainStore::<K, Q> {
+                db_file,
+                cipher,
+                data_start: 0,
+                headerless: false,
+                hash_chained: false,
+                entry_count: Some(0),
+                snapshot_entries: 0,
+                auto_compact: self.auto_compact,
+                db_path: Some(db_path.clone()),
+                wallet: self.wallet.clone(),
+                chain_head: None,
+                chain_index: Default::default(),
+            };
+            store.write_new_header(chained)?;
+            store.write_snapshots(snapshots)?;
+            std::fs::rename(&tmp_path, db_path)?;
+            Ok(store)
+        })();
+        if written.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        written
+    }
+
+    /// Writes the header to the empty file of a new store, starting a new chain of hashes if
+    /// `chained` is true.
+    fn write_new_header(&mut self, chained: bool) -> Result<(), io::Error> {
+        self.data_start = write_header(
+            &mut self.db_file,
+            P::SCHEMA_TAG,
+            self.cipher.as_mut(),
+            chained,
+        )?;
+        self.headerless = false;
+        self.hash_chained = chained;
+        self.chain_head = match chained {
+            true => Some(chain_genesis(&mut self.db_file, self.data_start)?),
+            false => None,
+        };
+        Ok(())
+    }
+
+    /// Reads the aggregate changeset and the latest extension blobs of each wallet.
+    fn wallet_snapshots(&mut self) -> Result<WalletSnapshots<K, P>, IterError> {
+        let mut snapshots = WalletSnapshots::<K, P>::new();
+        for entry in self.iter_entries()? {
+            let (wallet, entry) = entry?.into_default_wallet();
+            let (changeset, extensions) = snapshots.entry(wallet).or_default();
+            match entry {
+                StoreEntry::ChangeSet(next_changeset) => changeset.append(next_changeset),
+                StoreEntry::Extension { name, data } => {
+                    extensions.insert(name, data);
+                }
+                _ => unreachable!("entries have been moved to the default wallet"),
+            }
+        }
+        Ok(snapshots)
+    }
+
+    /// Writes `snapshots` after the header of an empty file.
+    fn write_snapshots(&mut self, snapshots: WalletSnapshots<K, P>) -> Result<(), io::Error> {
+        let cipher = self.cipher.as_ref();
+        let mut entry_count = 0;
+        for (wallet, (changeset, extensions)) in snapshots {
+            let wallet = wallet.as_deref();
+            if !changeset.is_empty() {
+                write_entry(
+                    &mut self.db_file,
+                    cipher.map(|cipher| (cipher, entry_count)),
+                    self.chain_head.as_mut(),
+                    &StoreEntry::changeset(wallet, &changeset),
+                )?;
+                entry_count += 1;
+            }
+            for (name, data) in extensions {
+                write_entry(
+                    &mut self.db_file,
+                    cipher.map(|cipher| (cipher, entry_count)),
+                    self.chain_head.as_mut(),
+                    &StoreEntry::<()>::extension(wallet, name, data),
+                )?;
+                entry_count += 1;
+            }
+        }
+        self.db_file.sync_all()?;
+        self.entry_count = Some(entry_count);
+        self.snapshot_entries = entry_count;
+        Ok(())
+    }
+
+    /// Cuts off a damaged tail of the store, keeping all the entries before it.
+    ///
+    /// A crash while appending can leave a partially written entry at the end of the file, after
+    /// which loading fails at that entry and anything appended later is unreachable. This reads
+    /// the entries until the first one that can't be decoded, copies the whole file to
+    /// `backup_path` and then truncates it right before that entry. The write position is left at
+    /// the end of the store so it can be appended to again.
+    ///
+    /// Note that everything after the first undecodable entry is removed, even if it was only
+    /// damaged in the middle. Check the error loading the store first: an
+    /// [`IterError::Corrupted`] entry means entries after it are lost too, which the backup still
+    /// has. Nothing is changed if all the entries can be read. `backup_path` must not exist yet.
+    pub fn recover(&mut self, backup_path: &Path) -> Result<Recovery, io::Error> {
+        let mut recovered = 0;
+        let mut damaged = false;
+        for entry in self.iter_entries()? {
+            match entry {
+                Ok(_) => recovered += 1,
+                // the password was checked when opening so an entry that can't be decrypted has
+                // been damaged too
+                Err(
+                    IterError::Bincode(_)
+                    | IterError::Decrypt
+                    | IterError::Truncated { .. }
+                    | IterError::Corrupted { .. }
+                    | IterError::BrokenChain { .. },
+                ) => {
+                    damaged = true;
+                    break;
+                }
+                Err(IterError::Io(e)) => return Err(e),
+            }
+        }
+        // the iterator leaves the position at the start of the entry it couldn't read
+        let damaged_at = self.db_file.stream_position()?;
+        let len = self.db_file.seek(io::SeekFrom::End(0))?;
+
+        if damaged {
+            let mut backup = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(backup_path)?;
+            self.db_file.rewind()?;
+            io::copy(&mut self.db_file, &mut backup)?;
+            backup.sync_all()?;
+
+            self.db_file.set_len(damaged_at)?;
+            self.db_file.sync_all()?;
+            self.db_file.seek(io::SeekFrom::Start(damaged_at))?;
+        }
+        self.entry_count = Some(recovered);
+
+        Ok(Recovery {
+            recovered,
+            truncated: len - damaged_at,
+        })
+    }
+
+    /// Copies the store to `path`, which must not exist yet, and checks the copy.
+    ///
+    /// Every entry is read first (checking its checksums, and its hash if the store is hash
+    /// chained) so a damaged store isn't backed up. The copy is written to a temporary file next
+    /// to `path` and only renamed to `path` once it has been synced to disk, so there is never a
+    /// partial backup at `path`. It is then read back and compared with the store. The copy is an
+    /// exact one: it has the same password and [`head_hash`] as the store.
+    ///
+    /// Returns the number of entries backed up. The write position is left at the end of the
+    /// store.
+    ///
+    /// [`head_hash`]: Self::head_hash
+    pub fn backup(&mut self, path: &Path) -> Result<usize, BackupError> {
+        if path.exists() {
+            return Err(BackupError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            )));
+        }
+        let mut entry_count = 0;
+        for entry in self.iter_entries()? {
+            entry.map_err(BackupError::Iter)?;
+            entry_count += 1;
+        }
+        self.entry_count = Some(entry_count);
+
+        let mut contents = Vec::new();
+        self.db_file.rewind()?;
+        self.db_file.read_to_end(&mut contents)?;
+
+        let tmp_path = tmp_path(path);
+        let written = (|| {
+            let mut tmp = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&tmp_path)?;
+            tmp.write_all(&contents)?;
+            tmp.sync_all()?;
+            std::fs::rename(&tmp_path, path)
+        })();
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e.into());
+        }
+
+        if std::fs::read(path)? != contents {
+            return Err(BackupError::Mismatch);
+        }
+        Ok(entry_count)
+    }
+
+    /// Counts an entry that has just been appended and compacts the store if there are now too
+    /// many since it was last compacted.
+    fn entry_appended(&mut self) -> Result<(), io::Error> {
+        if let Some(entry_count) = &mut self.entry_count {
+            *entry_count += 1;
+            let appended = entry_count.saturating_sub(self.snapshot_entries);
+            if self.db_path.is_some() && matches!(self.auto_compact, Some(max) if appended > max) {
+                self.compact().map_err(|e| match e {
+                    CompactError::Io(e) => e,
+                    e => io::Error::new(io::ErrorKind::InvalidData, e),
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the store so that it contains positions of type `Q`, converting each position
+    /// with `convert`.
+    ///
+    /// All the changesets are read and aggregated into one before `convert` is applied. The file
+    /// is only replaced once every position has been converted and the migrated store has been
+    /// written next to it like [`compact`] does, so a failure leaves the store untouched. The
+    /// rewritten store has a header even if the original didn't and keeps the latest version of
+    /// each extension blob. Every wallet in the store is migrated. Stores created from a [`File`]
+    /// rather than a path can't be migrated.
+    ///
+    /// [`compact`]: Self::compact
+    pub fn migrate<Q, E>(
+        mut self,
+        mut convert: impl FnMut(P) -> Result<Q, E>,
+    ) -> Result<KeychainStore<K, Q>, MigrateError<E>>
+    where
+        Q: PositionSchema,
+        KeychainChangeSet<K, Q>: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let snapshots = self
+            .wallet_snapshots()
+            .map_err(MigrateError::Iter)?
+            .into_iter()
+            .map(|(wallet, (changeset, extensions))| {
+                let changeset = changeset.try_map_positions(&mut convert)?;
+                Ok((wallet, (changeset, extensions)))
+            })
+            .collect::<Result<WalletSnapshots<K, Q>, E>>()
+            .map_err(MigrateError::Convert)?;
+
+        let cipher = self.cipher.take();
+        Ok(self.replace(cipher, self.is_hash_chained(), snapshots)?)
+    }
+}
+
+impl<K, P> PersistBackend<K, P> for KeychainStore<K, P>
+where
+    K: Ord + Clone + core::fmt::Debug,
+    P: PositionSchema,
+    KeychainChangeSet<K, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    type WriteError = io::Error;
+    type LoadError = IterError;
+
+    fn append_changeset(
+        &mut self,
+        changeset: &KeychainChangeSet<K, P>,
+    ) -> Result<(), Self::WriteError> {
+        KeychainStore::append_changeset(self, changeset)
+    }
+
+    fn append_extension(&mut self, name: &str, data: &[u8]) -> Result<(), Self::WriteError> {
+        KeychainStore::append_extension(self, name, data)
+    }
+
+    fn load_into_keychain_tracker(
+        &mut self,
+        tracker: &mut KeychainTracker<K, P>,
+    ) -> Result<(), Self::LoadError> {
+        KeychainStore::load_into_keychain_tracker(self, tracker)
+    }
+
+    fn aggregate_extensions(&mut self) -> (BTreeMap<String, Vec<u8>>, Result<(), Self::LoadError>) {
+        KeychainStore::aggregate_extensions(self)
+    }
+
+    fn set_derivation_indices(
+        &mut self,
+        indices: BTreeMap<K, u32>,
+    ) -> Result<(), Self::WriteError> {
+        KeychainStore::set_derivation_indices(self, indices)
+    }
+}
+
+impl<K> KeychainStore<K, TxHeight>
+where
+    K: Ord + Clone + core::fmt::Debug,
+    KeychainChangeSet<K, TxHeight>: serde::Serialize + serde::de::DeserializeOwned,
+    KeychainChangeSet<K, ConfirmationTime>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// Upgrades a store of [`TxHeight`]s to one of [`ConfirmationTime`]s.
+    ///
+    /// `block_time` is called to get the time of the block at a height, usually by asking a chain
+    /// source for the block header. It is only called for heights that transactions are confirmed
+    /// at and at most once per height.
+    ///
+    /// See [`migrate`] for how the file is rewritten.
+    ///
+    /// [`migrate`]: Self::migrate
+    pub fn migrate_to_confirmation_time<E>(
+        self,
+        mut block_time: impl FnMut(u32) -> Result<u64, E>,
+    ) -> Result<KeychainStore<K, ConfirmationTime>, MigrateError<E>> {
+        let mut times = BTreeMap::<u32, u64>::new();
+        self.migrate(|pos| {
+            Ok(match pos {
+                TxHeight::Confirmed(height) => {
+                    let time = match times.get(&height) {
+                        Some(time) => *time,
+                        None => {
+                            let time = block_time(height)?;
+                            times.insert(height, time);
+                            time
+                        }
+                    };
+                    ConfirmationTime::Confirmed { height, time }
+                }
+                TxHeight::Unconfirmed => ConfirmationTime::Unconfirmed,
+            })
+        })
+    }
+}
+
+/// Keeps raw transactions in a file of their own, usually next to a [`KeychainStore`].
+///
+/// A [`TxGraph`] built from a txid scan doesn't have the full transactions it didn't need, yet
+/// they are what a PSBT needs as the `non_witness_utxo` of a legacy input and what has to be sent
+/// again to rebroadcast. The store keeps them on disk and only reads one when it is asked for it
+/// with [`get`] (or through its [`TxProvider`] impl, e.g. with
+/// [`ChainGraph::inflate_changeset_from`]).
+///
+/// Each transaction is written as a record with checksums like the entries of a
+/// [`KeychainStore`]. The store is a cache of transactions that can be fetched again, so when a
+/// record can't be read while opening the file it is cut off along with everything after it.
+///
+/// The transactions are kept in the order they were inserted. [`set_max_size`] bounds the size of
+/// the file by pruning the oldest ones and [`prune`] drops the ones that are no longer wanted.
+///
+/// [`TxGraph`]: crate::tx_graph::TxGraph
+/// [`ChainGraph::inflate_changeset_from`]: crate::chain_graph::ChainGraph::inflate_changeset_from
+/// [`get`]: Self::get
+/// [`set_max_size`]: Self::set_max_size
+/// [`prune`]: Self::prune
+#[derive(Debug)]
+pub struct RawTxStore {
+    file: File,
+    /// Where the record of each transaction starts
+    offsets: HashMap<Txid, u64>,
+    /// The txid and length of the record at each offset, i.e. the transactions from oldest to
+    /// newest
+    records: BTreeMap<u64, (Txid, u64)>,
+    /// Prune the oldest transactions when the file grows past this many bytes
+    max_size: Option<u64>,
+}
+
+impl RawTxStore {
+    /// Opens the store in `file`, writing the header if the file is empty.
+    ///
+    /// Fails with [`FileError::InvalidMagicBytes`] if `file` holds something else.
+    pub fn new(mut file: File) -> Result<Self, FileError> {
+        if file.metadata()?.len() == 0 {
+            file.write_all(&RAW_TX_MAGIC_BYTES)?;
+            file.write_all(&[RAW_TX_FORMAT_VERSION])?;
+            file.sync_data()?;
+        } else {
+            file.rewind()?;
+            let mut header = [0u8; RAW_TX_MAGIC_BYTES.len() + 1];
+            match file.read_exact(&mut header) {
+                Ok(()) if header[..RAW_TX_MAGIC_BYTES.len()] == RAW_TX_MAGIC_BYTES => {}
+                Ok(()) => return Err(FileError::InvalidMagicBytes),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(FileError::InvalidMagicBytes)
+                }
+                Err(e) => return Err(e.into()),
+            }
+            if header[RAW_TX_MAGIC_BYTES.len()] != RAW_TX_FORMAT_VERSION {
+                return Err(FileError::UnknownVersion(header[RAW_TX_MAGIC_BYTES.len()]));
+            }
+        }
+
+        let mut store = Self {
+            file,
+            offsets: HashMap::new(),
+            records: BTreeMap::new(),
+            max_size: None,
+        };
+        loop {
+            let offset = store.file.stream_position()?;
+            let txid = match read_record(&store.file)? {
+                Record::End => break,
+                Record::Intact(data) => deserialize::<Transaction>(&data).ok().map(|tx| tx.txid()),
+                Record::Truncated | Record::Corrupted => None,
+            };
+            match txid {
+                Some(txid) => store.index(txid, offset),
+                None => {
+                    store.file.set_len(offset)?;
+                    break;
+                }
+            }
+        }
+        Ok(store)
+    }
+
+    /// Opens the store at `path`, creating the file if it doesn't exist.
+    pub fn new_from_path(path: &Path) -> Result<Self, FileError> {
+        Self::new(open_db_file(path)?)
+    }
+
+    /// Records that the record of `txid` starts at `offset` and ends at the current position.
+    fn index(&mut self, txid: Txid, offset: u64) {
+        let end = self
+            .file
+            .stream_position()
+            .expect("the position of a file can be read");
+        self.offsets.insert(txid, offset);
+        self.records.insert(offset, (txid, end - offset));
+    }
+
+    /// Stores `tx` unless it already is. Returns whether it was added.
+    ///
+    /// The oldest transactions are pruned if this takes the file past the [`max_size`].
+    ///
+    /// [`max_size`]: Self::set_max_size
+    pub fn insert(&mut self, tx: &Transaction) -> Result<bool, io::Error> {
+        let txid = tx.txid();
+        if self.offsets.contains_key(&txid) {
+            return Ok(false);
+        }
+        let offset = self.file.seek(io::SeekFrom::End(0))?;
+        write_record(&mut self.file, &serialize(tx))?;
+        self.index(txid, offset);
+        self.prune_to_max_size()?;
+        Ok(true)
+    }
+
+    /// Reads the transaction with `txid` from the file.
+    pub fn get(&self, txid: Txid) -> Result<Option<Transaction>, io::Error> {
+        let offset = match self.offsets.get(&txid) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let mut file = &self.file;
+        file.seek(io::SeekFrom::Start(offset))?;
+        match read_record(file)? {
+            Record::Intact(data) => deserialize(&data)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the record of the transaction is damaged",
+            )),
+        }
+    }
+
+    /// Whether the transaction with `txid` is stored.
+    pub fn contains(&self, txid: Txid) -> bool {
+        self.offsets.contains_key(&txid)
+    }
+
+    /// The txids of the stored transactions from oldest to newest.
+    pub fn txids(&self) -> impl DoubleEndedIterator<Item = Txid> + '_ {
+        self.records.values().map(|(txid, _)| *txid)
+    }
+
+    /// The number of stored transactions.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Whether there are no stored transactions.
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// The size of the file in bytes.
+    pub fn size(&self) -> u64 {
+        self.records
+            .iter()
+            .next_back()
+            .map(|(offset, (_, length))| offset + length)
+            .unwrap_or(RAW_TX_MAGIC_BYTES.len() as u64 + 1)
+    }
+
+    /// Bounds the size of the file to `max_size` bytes (or removes the bound if `None`), pruning
+    /// the oldest transactions right away if it is already larger.
+    pub fn set_max_size(&mut self, max_size: Option<u64>) -> Result<(), io::Error> {
+        self.max_size = max_size;
+        self.prune_to_max_size()
+    }
+
+    /// Removes the transactions `keep` returns `false` for and returns their txids.
+    ///
+    /// The file is rewritten without them if there are any.
+    pub fn prune(&mut self, mut keep: impl FnMut(Txid) -> bool) -> Result<Vec<Txid>, io::Error> {
+        let pruned = self.txids().filter(|txid| !keep(*txid)).collect::<Vec<_>>();
+        if !pruned.is_empty() {
+            let pruned_set = pruned.iter().collect::<BTreeSet<_>>();
+            self.rewrite(|txid| !pruned_set.contains(&txid))?;
+        }
+        Ok(pruned)
+    }
+
+    /// Prunes the oldest transactions until the file is no larger than the max size.
+    fn prune_to_max_size(&mut self) -> Result<(), io::Error> {
+        let max_size = match self.max_size {
+            Some(max_size) if self.size() > max_size => max_size,
+            _ => return Ok(()),
+        };
+        let mut excess = self.size() - max_size;
+        let oldest = self
+            .records
+            .values()
+            .take_while(|(_, length)| {
+                let prune = excess > 0;
+                excess = excess.saturating_sub(*length);
+                prune
+            })
+            .map(|(txid, _)| *txid)
+            .collect::<BTreeSet<_>>();
+        self.rewrite(|txid| !oldest.contains(&txid))
+    }
+
+    /// Replaces the contents of the file with the records of the transactions to `keep`.
+    fn rewrite(&mut self, mut keep: impl FnMut(Txid) -> bool) -> Result<(), io::Error> {
+        let mut kept = Vec::new();
+        for (&offset, &(txid, length)) in &self.records {
+            if keep(txid) {
+                let mut record = vec![0u8; length as usize];
+                self.file.seek(io::SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut record)?;
+                kept.push((txid, record));
+            }
+        }
+
+        let data_start = RAW_TX_MAGIC_BYTES.len() as u64 + 1;
+        self.file.set_len(data_start)?;
+        self.file.seek(io::SeekFrom::Start(data_start))?;
+        self.offsets.clear();
+        self.records.clear();
+        for (txid, record) in kept {
+            let offset = self.file.stream_position()?;
+            // the records are copied as they are, checksums and all
+            self.file.write_all(&record)?;
+            self.index(txid, offset);
+        }
+        self.file.sync_data()
+    }
+}
+
+impl TxProvider for RawTxStore {
+    /// Transactions that can't be read from the file are treated as missing.
+    fn provide_tx(&self, txid: Txid) -> Option<Transaction> {
+        self.get(txid).ok().flatten()
+    }
+}
+
+/// The temporary file a file at `path` is written to before it is renamed to `path`.
+fn tmp_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.tmp", path.display()))
+}
+
+fn open_db_file(db_path: &Path) -> Result<File, io::Error> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(db_path)
+}
+
+/// Writes the header (followed by what an encrypted store needs to check the password) and
+/// returns where the entries start.
+fn write_header(
+    file: &mut File,
+    schema_tag: u8,
+    cipher: Option<&mut StoreCipher>,
+    chained: bool,
+) -> Result<u64, io::Error> {
+    let mut flags = 0;
+    if cipher.is_some() {
+        flags |= ENCRYPTED_FLAG;
+    }
+    if chained {
+        flags |= HASH_CHAINED_FLAG;
+    }
+    let header = header(schema_tag, flags);
+    file.write_all(&header)?;
+    if let Some(cipher) = cipher {
+        cipher.write_check(file, &header)?;
+    }
+    file.sync_data()?;
+    file.stream_position()
+}
+
+/// The header of a store: the magic bytes, the format version, the schema tag and the flags.
+fn header(schema_tag: u8, flags: u8) -> Vec<u8> {
+    let mut header = MAGIC_BYTES.to_vec();
+    header.extend([FORMAT_VERSION, schema_tag, flags]);
+    header
+}
+
+/// Reads the header at the current position returning the schema tag and flags. Returns `None` if
+/// the file doesn't start with a header. The file is left positioned after the header.
+fn read_header(file: &mut File) -> Result<Option<(u8, u8)>, FileError> {
+    let mut magic = [0u8; MAGIC_BYTES.len()];
+    match file.read_exact(&mut magic) {
+        Ok(()) if magic == MAGIC_BYTES => {}
+        Ok(()) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut version = [0u8];
+    file.read_exact(&mut version)?;
+    if version[0] != FORMAT_VERSION {
+        return Err(FileError::UnknownVersion(version[0]));
+    }
+    let mut tag_and_flags = [0u8; 2];
+    file.read_exact(&mut tag_and_flags)?;
+    let [schema_tag, flags] = tag_and_flags;
+    if flags & !(ENCRYPTED_FLAG | HASH_CHAINED_FLAG) != 0 {
+        return Err(FileError::UnknownFlags(flags));
+    }
+    Ok(Some((schema_tag, flags)))
+}
+
+fn encode_entry<V: serde::Serialize>(file: &mut File, entry: &V) -> Result<(), io::Error> {
+    bincode::encode_into_std_write(
+        bincode::serde::Compat(entry),
+        file,
+        bincode::config::standard(),
+    )
+    .map_err(|e| match e {
+        bincode::error::EncodeError::Io { inner, .. } => inner,
+        unexpected_err => panic!("unexpected bincode error: {}", unexpected_err),
+    })?;
+    Ok(())
+}
+
+fn decode_entry<V: serde::de::DeserializeOwned>(
+    file: &mut File,
+) -> Result<V, bincode::error::DecodeError> {
+    bincode::decode_from_std_read(file, bincode::config::standard())
+        .map(|bincode::serde::Compat(entry)| entry)
+}
+
+/// Writes `entry` to `file` as a record. In an encrypted store it is encrypted first with the
+/// cipher and the index of the entry in the store.
+///
+/// The record of a hash chained store starts with `chain_head`, which then moves on to the hash
+/// of the record.
+fn write_entry<V: serde::Serialize>(
+    file: &mut File,
+    cipher: Option<(&StoreCipher, usize)>,
+    chain_head: Option<&mut sha256::Hash>,
+    entry: &V,
+) -> Result<(), io::Error> {
+    let encoded =
+        bincode::encode_to_vec(bincode::serde::Compat(entry), bincode::config::standard())
+            .expect("encoding into a vec can't fail");
+    let data = match cipher {
+        Some((cipher, index)) => cipher.seal(&encoded, Some(index)),
+        None => encoded,
+    };
+    match chain_head {
+        Some(head) => {
+            let mut record = head.to_vec();
+            record.extend(data);
+            write_record(file, &record)?;
+            *head = sha256::Hash::hash(&record);
+            Ok(())
+        }
+        None => write_record(file, &data),
+    }
+}
+
+/// The hash the first record of a hash chained store carries: that of the header (and whatever
+/// follows it before `data_start`). The file is left positioned at `data_start`.
+fn chain_genesis(file: &mut File, data_start: u64) -> Result<sha256::Hash, io::Error> {
+    let mut header = vec![0u8; data_start as usize];
+    file.rewind()?;
+    file.read_exact(&mut header)?;
+    Ok(sha256::Hash::hash(&header))
+}
+
+/// Checks that a record of a hash chained store starts with `head`, the hash of the record before
+/// it. If so `head` moves on to the hash of `record` and the rest of the record is returned.
+fn follow_link(head: &mut sha256::Hash, mut record: Vec<u8>) -> Option<Vec<u8>> {
+    if !record.starts_with(&head[..]) {
+        return None;
+    }
+    *head = sha256::Hash::hash(&record);
+    Some(record.split_off(sha256::Hash::LEN))
+}
+
+/// Writes `data` as a record: its length as a little endian `u32`, the CRC-32 of the length, the
+/// CRC-32 of `data` and then `data` itself.
+///
+/// Checksumming the length separately means a damaged length is noticed before it is used to
+/// find the end of the record.
+fn write_record(file: &mut File, data: &[u8]) -> Result<(), io::Error> {
+    let length = u32::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "entry is too large"))?
+        .to_le_bytes();
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + data.len());
+    record.extend(length);
+    record.extend(crc32fast::hash(&length).to_le_bytes());
+    record.extend(crc32fast::hash(data).to_le_bytes());
+    record.extend(data);
+    // written in one go so a crash can only leave a prefix of the record behind
+    file.write_all(&record)
+}
+
+/// What [`read_record`] found at the current position of the file.
+enum Record {
+    /// The file ends here
+    End,
+    /// The file ends before the record does
+    Truncated,
+    /// One of the checksums doesn't match
+    Corrupted,
+    /// The data of an intact record
+    Intact(Vec<u8>),
+}
+
+/// Reads the record written by [`write_record`] at the current position of `file`.
+///
+/// The file is left positioned after the record if it is intact.
+fn read_record(mut file: &File) -> Result<Record, io::Error> {
+    let remaining = file.metadata()?.len() - file.stream_position()?;
+    if remaining == 0 {
+        return Ok(Record::End);
+    }
+    if remaining < RECORD_HEADER_LEN {
+        return Ok(Record::Truncated);
+    }
+
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    file.read_exact(&mut header)?;
+    let field = |i: usize| u32::from_le_bytes(header[i * 4..(i + 1) * 4].try_into().unwrap());
+    if crc32fast::hash(&header[..4]) != field(1) {
+        return Ok(Record::Corrupted);
+    }
+    let length = field(0) as u64;
+    if remaining - RECORD_HEADER_LEN < length {
+        return Ok(Record::Truncated);
+    }
+
+    let mut data = vec![0u8; length as usize];
+    file.read_exact(&mut data)?;
+    if crc32fast::hash(&data) != field(2) {
+        return Ok(Record::Corrupted);
+    }
+    Ok(Record::Intact(data))
+}
+
+fn decode_plaintext<V: serde::de::DeserializeOwned>(
+    plaintext: &[u8],
+) -> Result<V, bincode::error::DecodeError> {
+    bincode::decode_from_slice(plaintext, bincode::config::standard())
+        .map(|(bincode::serde::Compat(entry), _)| entry)
+}
+
+/// Encrypts and decrypts the entries of an encrypted [`KeychainStore`].
+///
+/// Each entry is sealed with the header of the store, the salt and the index of the entry as
+/// associated data. An entry therefore only opens at its own place in the store it was written
+/// to, so entries can't be reordered, dropped from the middle, replayed or moved between stores
+/// without it being noticed.
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+struct StoreCipher {
+    salt: [u8; SALT_LEN],
+    aead: XChaCha20Poly1305,
+    /// The header of the store followed by the salt (empty until the header is known)
+    header: Vec<u8>,
+}
+
+/// Without the `encryption` feature there are no encrypted stores to open.
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone, Debug)]
+enum StoreCipher {}
+
+#[cfg(feature = "encryption")]
+impl StoreCipher {
+    /// A cipher for a new store with a fresh salt.
+    fn new(password: &[u8]) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::derive(password, salt)
+    }
+
+    fn derive(password: &[u8], salt: [u8; SALT_LEN]) -> Self {
+        // the aead wipes its copy of the key when it is dropped
+        let mut key = Zeroizing::new([0u8; 32]);
+        argon2::Argon2::default()
+            .hash_password_into(password, &salt, key.as_mut())
+            .expect("the salt and key lengths are valid");
+        Self {
+            salt,
+            aead: XChaCha20Poly1305::new_from_slice(key.as_ref()).expect("the key is 32 bytes"),
+            header: Vec::new(),
+        }
+    }
+
+    /// Makes entries be sealed with `header`, the header of the store, as associated data.
+    fn bind(&mut self, header: &[u8]) {
+        self.header = header.to_vec();
+        self.header.extend(self.salt);
+    }
+
+    /// Reads the salt and the password check that follow `header` and derives the key.
+    fn read(file: &mut File, password: &[u8], header: &[u8]) -> Result<Self, FileError> {
+        let mut salt = [0u8; SALT_LEN];
+        file.read_exact(&mut salt)?;
+        let mut cipher = Self::derive(password, salt);
+        cipher.bind(header);
+        let check = decode_entry::<Vec<u8>>(file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // the check is an encryption of nothing which only opens with the right key
+        match cipher.open(&check, None) {
+            Ok(plaintext) if plaintext.is_empty() => Ok(cipher),
+            _ => Err(FileError::WrongPassword),
+        }
+    }
+
+    fn write_check(&mut self, file: &mut File, header: &[u8]) -> Result<(), io::Error> {
+        self.bind(header);
+        file.write_all(&self.salt)?;
+        encode_entry(file, &self.seal(&[], None))
+    }
+
+    /// The associated data of the entry at `index` (of the password check if `None`).
+    fn aad(&self, index: Option<usize>) -> Vec<u8> {
+        let mut aad = self.header.clone();
+        if let Some(index) = index {
+            aad.extend((index as u64).to_le_bytes());
+        }
+        aad
+    }
+
+    /// Encrypts `plaintext` as the entry at `index` with a random nonce which is prepended to the
+    /// result.
+    fn seal(&self, plaintext: &[u8], index: Option<usize>) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        let payload = Payload {
+            msg: plaintext,
+            aad: &self.aad(index),
+        };
+        sealed.extend(
+            self.aead
+                .encrypt(&nonce, payload)
+                .expect("the plaintext isn't too long"),
+        );
+        sealed
+    }
+
+    fn open(&self, sealed: &[u8], index: Option<usize>) -> Result<Vec<u8>, IterError> {
+        if sealed.len() < NONCE_LEN {
+            return Err(IterError::Decrypt);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &self.aad(index),
+        };
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| IterError::Decrypt)
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl StoreCipher {
+    fn new(_password: &[u8]) -> Self {
+        unreachable!("passwords are only taken with the encryption feature")
+    }
+
+    fn read(_file: &mut File, _password: &[u8], _header: &[u8]) -> Result<Self, FileError> {
+        unreachable!("passwords are only taken with the encryption feature")
+    }
+
+    fn write_check(&mut self, _file: &mut File, _header: &[u8]) -> Result<(), io::Error> {
+        match *self {}
+    }
+
+    fn seal(&self, _plaintext: &[u8], _index: Option<usize>) -> Vec<u8> {
+        match *self {}
+    }
+
+    fn open(&self, _sealed: &[u8], _index: Option<usize>) -> Result<Vec<u8>, IterError> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl core::fmt::Debug for StoreCipher {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("StoreCipher").finish_non_exhaustive()
+    }
+}
+
+/// Error opening a [`KeychainStore`] or [`RawTxStore`].
+#[derive(Debug)]
+pub enum FileError {
+    /// IO error
+    Io(io::Error),
+    /// The file was written with a different type of chain position
+    SchemaMismatch {
+        /// The [`PositionSchema::SCHEMA_TAG`] of the position type the store was opened with
+        expected: u8,
+        /// The schema tag found in the file
+        found: u8,
+    },
+    /// The file was written with a newer version of the file format
+    UnknownVersion(u8),
+    /// The header has flags for features of the file format this version doesn't know about
+    UnknownFlags(u8),
+    /// The store is encrypted so it has to be opened with a password
+    Encrypted,
+    /// A password was given but the store isn't encrypted
+    NotEncrypted,
+    /// The password isn't the one the store was encrypted with
+    WrongPassword,
+    /// The file doesn't start with the magic bytes of the kind of store it was opened as
+    InvalidMagicBytes,
+}
+
+impl core::fmt::Display for FileError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FileError::Io(e) => write!(f, "io error trying to open store: {}", e),
+            FileError::SchemaMismatch { expected, found } => write!(
+                f,
+                "store contains chain positions with schema tag {} but {} was expected",
+                found, expected
+            ),
+            FileError::UnknownVersion(version) => {
+                write!(f, "store has unknown format version {}", version)
+            }
+            FileError::UnknownFlags(flags) => {
+                write!(f, "store has unknown flags {:#04x}", flags)
+            }
+            FileError::Encrypted => write!(f, "store is encrypted and needs a password"),
+            FileError::NotEncrypted => write!(f, "store is not encrypted"),
+            FileError::WrongPassword => write!(f, "wrong password for encrypted store"),
+            FileError::InvalidMagicBytes => write!(f, "file is not a store of this kind"),
+        }
+    }
+}
+
+impl std::error::Error for FileError {}
+
+impl From<io::Error> for FileError {
+    fn from(value: io::Error) -> Self {
+        FileError::Io(value)
+    }
+}
+
+/// What [`KeychainStore::recover`] found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Recovery {
+    /// The number of entries that could be read
+    pub recovered: usize,
+    /// The number of bytes cut off the end of the file (`0` if it wasn't damaged)
+    pub truncated: u64,
+}
+
+/// Error compacting a [`KeychainStore`].
+#[derive(Debug)]
+pub enum CompactError {
+    /// Failed to read the existing entries
+    Iter(IterError),
+    /// Failed to write the compacted store
+    Io(io::Error),
+}
+
+impl core::fmt::Display for CompactError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CompactError::Iter(e) => write!(f, "failed to read store for compaction: {}", e),
+            CompactError::Io(e) => write!(f, "io error writing compacted store: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CompactError {}
+
+impl From<io::Error> for CompactError {
+    fn from(value: io::Error) -> Self {
+        CompactError::Io(value)
+    }
+}
+
+/// Error backing up a [`KeychainStore`] (see [`KeychainStore::backup`]).
+#[derive(Debug)]
+pub enum BackupError {
+    /// Failed to read the entries of the store so it wasn't backed up
+    Iter(IterError),
+    /// Failed to write the backup
+    Io(io::Error),
+    /// The backup read back from disk isn't what was written
+    Mismatch,
+}
+
+impl core::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BackupError::Iter(e) => write!(f, "failed to read store for backup: {}", e),
+            BackupError::Io(e) => write!(f, "io error writing backup: {}", e),
+            BackupError::Mismatch => write!(f, "the backup read back differs from the store"),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<io::Error> for BackupError {
+    fn from(value: io::Error) -> Self {
+        BackupError::Io(value)
+    }
+}
+
+/// Error migrating a [`KeychainStore`] to another chain position type.
+#[derive(Debug)]
+pub enum MigrateError<E> {
+    /// Failed to read the existing changesets
+    Iter(IterError),
+    /// Failed to convert a chain position
+    Convert(E),
+    /// Failed to write the migrated store
+    Io(io::Error),
+}
+
+impl<E: core::fmt::Display> core::fmt::Display for MigrateError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MigrateError::Iter(e) => write!(f, "failed to read store for migration: {}", e),
+            MigrateError::Convert(e) => write!(f, "failed to convert chain position: {}", e),
+            MigrateError::Io(e) => write!(f, "io error writing migrated store: {}", e),
+        }
+    }
+}
+
+impl<E: core::fmt::Debug + core::fmt::Display> std::error::Error for MigrateError<E> {}
+
+impl<E> From<io::Error> for MigrateError<E> {
+    fn from(value: io::Error) -> Self {
+        MigrateError::Io(value)
+    }
 }
 
 #[derive(Debug)]
 pub enum IterError {
     Io(io::Error),
     Bincode(bincode::error::DecodeError),
+    /// An entry of an encrypted store couldn't be decrypted
+    Decrypt,
+    /// The file ends in the middle of an entry, e.g. because the program crashed while appending
+    /// it. Everything before the entry is intact.
+    Truncated {
+        /// The number of entries before it in the file
+        entry: usize,
+        /// Where the entry starts in the file
+        offset: u64,
+    },
+    /// The checksums of an entry don't match what was read, so the file has been damaged there.
+    /// Unlike [`Truncated`] there may be intact entries after it.
+    ///
+    /// Stores without a header have no checksums so they can't detect this.
+    ///
+    /// [`Truncated`]: Self::Truncated
+    Corrupted {
+        /// The number of entries before it in the file
+        entry: usize,
+        /// Where the entry starts in the file
+        offset: u64,
+    },
+    /// An entry of a hash chained store doesn't carry the hash of the entry before it even
+    /// though its checksums match, so the entries up to it have been tampered with.
+    BrokenChain {
+        /// The number of entries before it in the file
+        entry: usize,
+        /// Where the entry starts in the file
+        offset: u64,
+    },
 }
 
 impl core::fmt::Display for IterError {
@@ -151,12 +1922,31 @@ impl core::fmt::Display for IterError {
         match self {
             IterError::Io(e) => write!(f, "io error trying to read entry {}", e),
             IterError::Bincode(e) => write!(f, "bincode error while reading entry {}", e),
+            IterError::Decrypt => write!(f, "failed to decrypt entry"),
+            IterError::Truncated { entry, offset } => write!(
+                f,
+                "the file ends in the middle of entry {} (at byte {})",
+                entry, offset
+            ),
+            IterError::Corrupted { entry, offset } => write!(
+                f,
+                "entry {} (at byte {}) doesn't match its checksum",
+                entry, offset
+            ),
+            IterError::BrokenChain { entry, offset } => write!(
+                f,
+                "entry {} (at byte {}) doesn't carry the hash of the entry before it",
+                entry, offset
+            ),
         }
     }
 }
 
 impl std::error::Error for IterError {}
 
+/// Decodes an entry from bytes that have already been read (and decrypted).
+type DecodePlaintext<V> = fn(&[u8]) -> Result<V, bincode::error::DecodeError>;
+
 /// Iterator over entries in a file store.
 ///
 /// Reads and returns an entry each time [`next`] is called. If an error occurs while reading the
@@ -165,52 +1955,148 @@ impl std::error::Error for IterError {}
 /// [`next`]: Self::next
 pub struct EntryIter<'a, V> {
     db_file: &'a mut File,
-    types: PhantomData<V>,
+    decode: fn(&mut File) -> Result<V, bincode::error::DecodeError>,
+    /// Decodes entries that have been read from a record (and decrypted)
+    decode_plaintext: DecodePlaintext<V>,
+    /// Decrypts the entries of an encrypted store
+    cipher: Option<&'a StoreCipher>,
+    /// Whether each entry is framed as a record (see [`write_record`]), which is the case for all
+    /// but stores without a header
+    framed: bool,
+    /// The hash the next record of a hash chained store has to start with
+    chain: Option<sha256::Hash>,
+    /// The number of entries read so far
+    entry_count: usize,
     error_exit: bool,
 }
 
 impl<'a, V> EntryIter<'a, V> {
-    pub fn new(db_file: &'a mut File) -> Self {
+    pub fn new(db_file: &'a mut File) -> Self
+    where
+        V: serde::de::DeserializeOwned,
+    {
+        Self::with_decoder(db_file, decode_entry::<V>)
+    }
+
+    fn with_decoder(
+        db_file: &'a mut File,
+        decode: fn(&mut File) -> Result<V, bincode::error::DecodeError>,
+    ) -> Self
+    where
+        V: serde::de::DeserializeOwned,
+    {
         Self {
             db_file,
-            types: PhantomData,
+            decode,
+            decode_plaintext: decode_plaintext::<V>,
+            cipher: None,
+            framed: false,
+            chain: None,
+            entry_count: 0,
             error_exit: false,
         }
     }
+
+    fn framed(
+        db_file: &'a mut File,
+        cipher: Option<&'a StoreCipher>,
+        chain: Option<sha256::Hash>,
+    ) -> Self
+    where
+        V: serde::de::DeserializeOwned,
+    {
+        Self {
+            cipher,
+            framed: true,
+            chain,
+            ..Self::new(db_file)
+        }
+    }
+
+    /// Reads the entry at `pos` (the current position) returning `None` at the end of the file.
+    fn read_entry(&mut self, pos: u64) -> Result<Option<V>, IterError> {
+        let data = if self.framed {
+            match read_record(self.db_file)? {
+                Record::End => return Ok(None),
+                Record::Truncated => {
+                    return Err(IterError::Truncated {
+                        entry: self.entry_count,
+                        offset: pos,
+                    })
+                }
+                Record::Corrupted => {
+                    return Err(IterError::Corrupted {
+                        entry: self.entry_count,
+                        offset: pos,
+                    })
+                }
+                Record::Intact(record) => match &mut self.chain {
+                    Some(head) => follow_link(head, record).ok_or(IterError::BrokenChain {
+                        entry: self.entry_count,
+                        offset: pos,
+                    })?,
+                    None => record,
+                },
+            }
+        } else {
+            return match (self.decode)(self.db_file) {
+                Ok(entry) => Ok(Some(entry)),
+                Err(e) => self.unframed_error(e, pos),
+            };
+        };
+
+        let plaintext = match self.cipher {
+            Some(cipher) => cipher.open(&data, Some(self.entry_count))?,
+            None => data,
+        };
+        (self.decode_plaintext)(&plaintext)
+            .map(Some)
+            .map_err(IterError::Bincode)
+    }
+
+    /// Handles a failure to decode the unframed entry at `pos`. Running out of bytes is the end of
+    /// the file if the entry would have started there and a truncated entry otherwise.
+    fn unframed_error(
+        &mut self,
+        error: bincode::error::DecodeError,
+        pos: u64,
+    ) -> Result<Option<V>, IterError> {
+        if let bincode::error::DecodeError::Io { inner, .. } = &error {
+            if inner.kind() == io::ErrorKind::UnexpectedEof {
+                let eof = self.db_file.seek(io::SeekFrom::End(0))?;
+                if pos == eof {
+                    return Ok(None);
+                }
+                return Err(IterError::Truncated {
+                    entry: self.entry_count,
+                    offset: pos,
+                });
+            }
+        }
+        Err(IterError::Bincode(error))
+    }
 }
 
-impl<'a, V> Iterator for EntryIter<'a, V>
-where
-    V: serde::de::DeserializeOwned,
-{
+impl<'a, V> Iterator for EntryIter<'a, V> {
     type Item = Result<V, IterError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let result = (|| {
             let pos = self.db_file.stream_position()?;
-
-            match bincode::decode_from_std_read(self.db_file, bincode::config::standard()) {
-                Ok(bincode::serde::Compat(changeset)) => Ok(Some(changeset)),
-                Err(e) => {
-                    if let bincode::error::DecodeError::Io { inner, .. } = &e {
-                        if inner.kind() == io::ErrorKind::UnexpectedEof {
-                            let eof = self.db_file.seek(io::SeekFrom::End(0))?;
-                            if pos == eof {
-                                return Ok(None);
-                            }
-                        }
-                    }
-
-                    self.db_file.seek(io::SeekFrom::Start(pos))?;
-                    Err(IterError::Bincode(e))
-                }
+            let entry = self.read_entry(pos);
+            if entry.is_err() {
+                // leave the position at the start of the entry that couldn't be read
+                self.db_file.seek(io::SeekFrom::Start(pos))?;
             }
+            entry
         })();
 
         let result = result.transpose();
 
-        if let Some(Err(_)) = &result {
-            self.error_exit = true;
+        match &result {
+            Some(Ok(_)) => self.entry_count += 1,
+            Some(Err(_)) => self.error_exit = true,
+            None => {}
         }
 
         result
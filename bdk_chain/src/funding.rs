@@ -0,0 +1,342 @@
+//! Building a transaction that two parties contribute inputs and outputs to.
+//!
+//! In a dual-funded Lightning channel both peers add coins (and their change outputs) to the
+//! funding transaction. A [`FundingTemplate`] holds the contributions of both sides while the
+//! transaction is being negotiated. Like the interactive transaction construction of BOLT 2, every
+//! input and output is added with a *serial id* and the transaction lists its inputs and outputs
+//! in ascending serial id order. Both parties end up with the same transaction no matter in which
+//! order the contributions were exchanged. The [`Role::Initiator`] uses even serial ids and the
+//! [`Role::Acceptor`] odd ones so neither side can pick an id the other might use.
+//!
+//! Each side pays the fee for the weight of the inputs and outputs it adds. The initiator also
+//! pays for the fields common to the whole transaction and for the shared outputs (e.g. the
+//! channel's funding output) which both sides put their funds into.
+use crate::collections::BTreeMap;
+use alloc::vec::Vec;
+use bitcoin::{OutPoint, PackedLockTime, Sequence, Transaction, TxIn, TxOut, VarInt};
+
+/// The weight of an input without its `scriptSig` and witness: the outpoint and the sequence.
+const TXIN_BASE_WEIGHT: u32 = (32 + 4 + 4) * 4;
+
+/// The side of the negotiation of a [`FundingTemplate`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    /// The party that started the negotiation. Uses even serial ids.
+    Initiator,
+    /// The party that accepted the negotiation. Uses odd serial ids.
+    Acceptor,
+}
+
+impl Role {
+    /// Whether `serial_id` is one this role is allowed to use.
+    pub fn owns(self, serial_id: u64) -> bool {
+        serial_id % 2 == self.parity()
+    }
+
+    /// The role of the other side.
+    pub fn counterparty(self) -> Role {
+        match self {
+            Role::Initiator => Role::Acceptor,
+            Role::Acceptor => Role::Initiator,
+        }
+    }
+
+    fn parity(self) -> u64 {
+        match self {
+            Role::Initiator => 0,
+            Role::Acceptor => 1,
+        }
+    }
+}
+
+/// An input contributed to a [`FundingTemplate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FundingInput {
+    /// The output the input spends
+    pub previous_output: OutPoint,
+    /// The value and script pubkey of the output being spent. Both parties need it to compute
+    /// the sighashes of segwit inputs.
+    pub prevout: TxOut,
+    /// The sequence of the input
+    pub sequence: Sequence,
+    /// The weight the `scriptSig` (including its length) and witness of the input will have once
+    /// it is signed
+    pub satisfaction_weight: u32,
+}
+
+impl FundingInput {
+    /// The weight the input will have once it is signed.
+    pub fn weight(&self) -> u32 {
+        TXIN_BASE_WEIGHT + self.satisfaction_weight
+    }
+}
+
+/// An output contributed to a [`FundingTemplate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FundingOutput {
+    /// The output itself
+    pub txout: TxOut,
+    /// Whether both sides put funds into the output (like a channel's funding output) rather
+    /// than it belonging to the side that added it (like change)
+    pub shared: bool,
+}
+
+/// What one side has put into a [`FundingTemplate`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Contribution {
+    /// The total value of the inputs it added
+    pub input_value: u64,
+    /// The total value of the outputs it added that aren't shared
+    pub output_value: u64,
+    /// The weight it pays the fee for
+    pub weight: u32,
+}
+
+impl Contribution {
+    /// How much this side puts into the shared outputs after paying the fee for its weight at
+    /// `feerate` (in sats per weight unit). `None` if its inputs don't cover its outputs and fee.
+    pub fn shared_value(&self, feerate: f32) -> Option<u64> {
+        let fee = (self.weight as f32 * feerate).ceil() as u64;
+        self.input_value
+            .checked_sub(self.output_value)?
+            .checked_sub(fee)
+    }
+}
+
+/// An error adding an input or output to a [`FundingTemplate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FundingError {
+    /// The serial id belongs to the other side.
+    WrongParity {
+        /// The serial id
+        serial_id: u64,
+        /// The role that tried to use it
+        role: Role,
+    },
+    /// An input or output with the serial id has already been added.
+    SerialIdTaken(u64),
+    /// The outpoint is already spent by an input of the template.
+    DuplicateInput(OutPoint),
+}
+
+impl core::fmt::Display for FundingError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FundingError::WrongParity { serial_id, role } => {
+                write!(f, "serial id {} can't be used by the {:?}", serial_id, role)
+            }
+            FundingError::SerialIdTaken(serial_id) => {
+                write!(f, "serial id {} has already been used", serial_id)
+            }
+            FundingError::DuplicateInput(outpoint) => {
+                write!(f, "{} is already spent by the transaction", outpoint)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FundingError {}
+
+/// A transaction under negotiation between an initiator and an acceptor.
+///
+/// See the [module level documentation] for the ordering and fee rules.
+///
+/// [module level documentation]: crate::funding
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FundingTemplate {
+    /// The version of the transaction
+    pub version: i32,
+    /// The locktime of the transaction
+    pub lock_time: PackedLockTime,
+    inputs: BTreeMap<u64, FundingInput>,
+    outputs: BTreeMap<u64, FundingOutput>,
+}
+
+impl FundingTemplate {
+    /// Creates an empty version 2 template with `lock_time`.
+    pub fn new(lock_time: PackedLockTime) -> Self {
+        Self {
+            version: 2,
+            lock_time,
+            inputs: Default::default(),
+            outputs: Default::default(),
+        }
+    }
+
+    /// The lowest serial id `role` can use that is higher than every serial id it has used so
+    /// far.
+    ///
+    /// Inputs and outputs added with it come after those `role` has already added.
+    pub fn next_serial_id(&self, role: Role) -> u64 {
+        self.inputs
+            .keys()
+            .chain(self.outputs.keys())
+            .filter(|serial_id| role.owns(**serial_id))
+            .max()
+            .map_or(role.parity(), |serial_id| serial_id + 2)
+    }
+
+    /// Adds an input contributed by `role`.
+    pub fn add_input(
+        &mut self,
+        role: Role,
+        serial_id: u64,
+        input: FundingInput,
+    ) -> Result<(), FundingError> {
+        self.check_serial_id(role, serial_id)?;
+        if self
+            .inputs
+            .values()
+            .any(|existing| existing.previous_output == input.previous_output)
+        {
+            return Err(FundingError::DuplicateInput(input.previous_output));
+        }
+        self.inputs.insert(serial_id, input);
+        Ok(())
+    }
+
+    /// Adds an output contributed by `role`.
+    pub fn add_output(
+        &mut self,
+        role: Role,
+        serial_id: u64,
+        output: FundingOutput,
+    ) -> Result<(), FundingError> {
+        self.check_serial_id(role, serial_id)?;
+        self.outputs.insert(serial_id, output);
+        Ok(())
+    }
+
+    /// Removes the input with `serial_id`.
+    pub fn remove_input(&mut self, serial_id: u64) -> Option<FundingInput> {
+        self.inputs.remove(&serial_id)
+    }
+
+    /// Removes the output with `serial_id`.
+    pub fn remove_output(&mut self, serial_id: u64) -> Option<FundingOutput> {
+        self.outputs.remove(&serial_id)
+    }
+
+    /// The inputs with their serial ids in the order they appear in the transaction.
+    pub fn inputs(&self) -> impl ExactSizeIterator<Item = (u64, &FundingInput)> + '_ {
+        self.inputs
+            .iter()
+            .map(|(serial_id, input)| (*serial_id, input))
+    }
+
+    /// The outputs with their serial ids in the order they appear in the transaction.
+    pub fn outputs(&self) -> impl ExactSizeIterator<Item = (u64, &FundingOutput)> + '_ {
+        self.outputs
+            .iter()
+            .map(|(serial_id, output)| (*serial_id, output))
+    }
+
+    /// The weight of the fields of the transaction that don't belong to any input or output.
+    ///
+    /// The segwit marker and flag are included since the inputs are expected to be segwit.
+    pub fn common_weight(&self) -> u32 {
+        let counts =
+            VarInt(self.inputs.len() as u64).len() + VarInt(self.outputs.len() as u64).len();
+        (4 + 4 + counts as u32) * 4 + 2
+    }
+
+    /// What `role` has put into the template.
+    pub fn contribution(&self, role: Role) -> Contribution {
+        let mut contribution = Contribution::default();
+        if role == Role::Initiator {
+            contribution.weight += self.common_weight();
+        }
+        for (_, input) in self.inputs().filter(|(serial_id, _)| role.owns(*serial_id)) {
+            contribution.input_value += input.prevout.value;
+            contribution.weight += input.weight();
+        }
+        for (serial_id, output) in self.outputs() {
+            let pays_for = if output.shared {
+                role == Role::Initiator
+            } else {
+                role.owns(serial_id)
+            };
+            if pays_for {
+                contribution.weight += output_weight(&output.txout);
+            }
+            if !output.shared && role.owns(serial_id) {
+                contribution.output_value += output.txout.value;
+            }
+        }
+        contribution
+    }
+
+    /// The total weight the transaction will have once it is signed.
+    pub fn expected_weight(&self) -> u32 {
+        self.common_weight()
+            + self.inputs.values().map(FundingInput::weight).sum::<u32>()
+            + self
+                .outputs
+                .values()
+                .map(|output| output_weight(&output.txout))
+                .sum::<u32>()
+    }
+
+    /// The fee the transaction pays. `None` if its outputs are worth more than its inputs.
+    pub fn fee(&self) -> Option<u64> {
+        let input_value = self
+            .inputs
+            .values()
+            .map(|input| input.prevout.value)
+            .sum::<u64>();
+        let output_value = self
+            .outputs
+            .values()
+            .map(|output| output.txout.value)
+            .sum::<u64>();
+        input_value.checked_sub(output_value)
+    }
+
+    /// The transaction with its inputs and outputs in serial id order but without any signatures.
+    pub fn unsigned_tx(&self) -> Transaction {
+        Transaction {
+            version: self.version,
+            lock_time: self.lock_time,
+            input: self
+                .inputs
+                .values()
+                .map(|input| TxIn {
+                    previous_output: input.previous_output,
+                    sequence: input.sequence,
+                    ..Default::default()
+                })
+                .collect(),
+            output: self
+                .outputs
+                .values()
+                .map(|output| output.txout.clone())
+                .collect(),
+        }
+    }
+
+    /// The outputs spent by the inputs of [`unsigned_tx`] in the same order.
+    ///
+    /// [`unsigned_tx`]: Self::unsigned_tx
+    pub fn prevouts(&self) -> Vec<TxOut> {
+        self.inputs
+            .values()
+            .map(|input| input.prevout.clone())
+            .collect()
+    }
+
+    fn check_serial_id(&self, role: Role, serial_id: u64) -> Result<(), FundingError> {
+        if !role.owns(serial_id) {
+            return Err(FundingError::WrongParity { serial_id, role });
+        }
+        if self.inputs.contains_key(&serial_id) || self.outputs.contains_key(&serial_id) {
+            return Err(FundingError::SerialIdTaken(serial_id));
+        }
+        Ok(())
+    }
+}
+
+fn output_weight(txout: &TxOut) -> u32 {
+    let script_len = txout.script_pubkey.len();
+    ((8 + VarInt(script_len as u64).len() + script_len) * 4) as u32
+}
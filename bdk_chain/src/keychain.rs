@@ -5,10 +5,10 @@
 //! anything that implemetns `Ord`.
 use crate::{
     chain_graph::{self, ChainGraph},
-    collections::BTreeMap,
-    sparse_chain::ChainPosition,
+    collections::{BTreeMap, BTreeSet},
+    sparse_chain::{ChainPosition, SparseChain},
     tx_graph::TxGraph,
-    ForEachTxout,
+    ForEachTxout, TxHeight,
 };
 
 #[cfg(feature = "miniscript")]
@@ -19,6 +19,10 @@ pub use keychain_tracker::*;
 mod keychain_txout_index;
 #[cfg(feature = "miniscript")]
 pub use keychain_txout_index::*;
+#[cfg(feature = "miniscript")]
+mod persist;
+#[cfg(feature = "miniscript")]
+pub use persist::*;
 
 #[derive(Clone, Debug, PartialEq)]
 /// An update that includes the last active indexes of each keychain.
@@ -92,6 +96,21 @@ impl<K, P> KeychainChangeSet<K, P> {
 
         self.chain_graph.append(other.chain_graph);
     }
+
+    /// Converts the chain positions of the changeset with `f`.
+    ///
+    /// See [`sparse_chain::ChangeSet::try_map_positions`].
+    ///
+    /// [`sparse_chain::ChangeSet::try_map_positions`]: crate::sparse_chain::ChangeSet::try_map_positions
+    pub fn try_map_positions<Q, E>(
+        self,
+        f: impl FnMut(P) -> Result<Q, E>,
+    ) -> Result<KeychainChangeSet<K, Q>, E> {
+        Ok(KeychainChangeSet {
+            derivation_indices: self.derivation_indices,
+            chain_graph: self.chain_graph.try_map_positions(f)?,
+        })
+    }
 }
 
 impl<K, P> From<chain_graph::ChangeSet<P>> for KeychainChangeSet<K, P> {
@@ -129,6 +148,9 @@ pub struct Balance {
     pub trusted_pending: u64,
     /// Unconfirmed UTXOs received from an external wallet
     pub untrusted_pending: u64,
+    /// Unconfirmed UTXOs whose transaction has a known conflict or pays too low a feerate to be
+    /// relied upon
+    pub at_risk: u64,
     /// Confirmed and immediately spendable balance
     pub confirmed: u64,
 }
@@ -144,7 +166,11 @@ impl Balance {
 
     /// Get the whole balance visible to the wallet.
     pub fn total(&self) -> u64 {
-        self.confirmed + self.trusted_pending + self.untrusted_pending + self.immature
+        self.confirmed
+            + self.trusted_pending
+            + self.untrusted_pending
+            + self.at_risk
+            + self.immature
     }
 }
 
@@ -152,12 +178,114 @@ impl core::fmt::Display for Balance {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
-            "{{ immature: {}, trusted_pending: {}, untrusted_pending: {}, confirmed: {} }}",
-            self.immature, self.trusted_pending, self.untrusted_pending, self.confirmed
+            "{{ immature: {}, trusted_pending: {}, untrusted_pending: {}, at_risk: {}, confirmed: {} }}",
+            self.immature,
+            self.trusted_pending,
+            self.untrusted_pending,
+            self.at_risk,
+            self.confirmed
         )
     }
 }
 
+/// When the outputs of untrusted keychains (e.g. deposits to an exchange's receive addresses)
+/// count as confirmed.
+///
+/// Outputs of trusted keychains count as soon as they confirm. An untrusted output that is
+/// confirmed but doesn't meet the policy yet is counted as `untrusted_pending` by
+/// [`KeychainTracker::balance_with_policy`] and shouldn't be spent. The default policy treats
+/// untrusted outputs like trusted ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct ConfirmationPolicy {
+    /// The number of confirmations an untrusted output needs (the block it is in counts as the
+    /// first)
+    pub untrusted_confirmations: u32,
+    /// Whether the block an untrusted output is confirmed in has to be a checkpoint of the chain
+    /// (or be at or below its anchor) so we know the hash of the block the output is in rather
+    /// than just its height.
+    ///
+    /// Checkpoints pruned by the checkpoint limit no longer vouch for the outputs confirmed in
+    /// them so keep enough of them around.
+    pub require_checkpoint: bool,
+}
+
+impl ConfirmationPolicy {
+    /// Whether an output at `height` in `chain` counts as confirmed. `trusted` is whether the
+    /// output's keychain is trusted.
+    pub fn is_settled<P: ChainPosition>(
+        &self,
+        chain: &SparseChain<P>,
+        height: TxHeight,
+        trusted: bool,
+    ) -> bool {
+        if trusted {
+            return true;
+        }
+        let height = match height {
+            TxHeight::Confirmed(height) => height,
+            TxHeight::Unconfirmed => {
+                return self.untrusted_confirmations == 0 && !self.require_checkpoint
+            }
+        };
+        let confirmations = match chain.latest_checkpoint() {
+            Some(tip) if tip.height >= height => tip.height - height + 1,
+            _ => 0,
+        };
+        let anchored = chain.checkpoint_at(height).is_some()
+            || matches!(chain.anchor(), Some(anchor) if anchor.height >= height);
+        confirmations >= self.untrusted_confirmations && (anchored || !self.require_checkpoint)
+    }
+}
+
+/// Where a poll for new outputs left off (see [`KeychainTracker::outputs_since`]).
+///
+/// The cursor remembers the outputs that have been reported so an output is reported once no
+/// matter when it is found: a deposit to an address that was only just scanned is reported even
+/// though it was confirmed long ago. Persist the cursor after adding the outputs that have been
+/// handled to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct OutputCursor {
+    reported: BTreeSet<bitcoin::OutPoint>,
+}
+
+impl OutputCursor {
+    /// Whether the output at `outpoint` has been reported.
+    pub fn is_reported(&self, outpoint: bitcoin::OutPoint) -> bool {
+        self.reported.contains(&outpoint)
+    }
+
+    /// The number of outputs that have been reported.
+    pub fn len(&self) -> usize {
+        self.reported.len()
+    }
+
+    /// Whether no outputs have been reported yet.
+    pub fn is_empty(&self) -> bool {
+        self.reported.is_empty()
+    }
+
+    /// Records that the output at `outpoint` has been reported.
+    pub fn insert(&mut self, outpoint: bitcoin::OutPoint) -> bool {
+        self.reported.insert(outpoint)
+    }
+}
+
+impl Extend<bitcoin::OutPoint> for OutputCursor {
+    fn extend<T: IntoIterator<Item = bitcoin::OutPoint>>(&mut self, iter: T) {
+        self.reported.extend(iter)
+    }
+}
+
 impl core::ops::Add for Balance {
     type Output = Self;
 
@@ -166,6 +294,7 @@ impl core::ops::Add for Balance {
             immature: self.immature + other.immature,
             trusted_pending: self.trusted_pending + other.trusted_pending,
             untrusted_pending: self.untrusted_pending + other.untrusted_pending,
+            at_risk: self.at_risk + other.at_risk,
             confirmed: self.confirmed + other.confirmed,
         }
     }
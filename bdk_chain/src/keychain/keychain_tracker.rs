@@ -1,4 +1,5 @@
-use bitcoin::Transaction;
+use alloc::vec::Vec;
+use bitcoin::{Block, Transaction, Txid};
 use miniscript::{Descriptor, DescriptorPublicKey};
 
 use crate::{
@@ -10,7 +11,7 @@ use crate::{
     BlockId, FullTxOut, TxHeight,
 };
 
-use super::Balance;
+use super::{Balance, ConfirmationPolicy, OutputCursor};
 
 /// A convenient combination of a `KeychainTxOutIndex<K>` and a `ChainGraph<P>`.
 ///
@@ -54,6 +55,34 @@ where
         self.chain_graph.set_checkpoint_limit(limit)
     }
 
+    pub fn checkpoint_retention(&self) -> Option<&sparse_chain::CheckpointRetention> {
+        self.chain_graph.checkpoint_retention()
+    }
+
+    pub fn set_checkpoint_retention(
+        &mut self,
+        retention: Option<sparse_chain::CheckpointRetention>,
+    ) {
+        self.chain_graph.set_checkpoint_retention(retention)
+    }
+
+    /// The trusted anchor of the chain. See [`SparseChain::from_anchor`].
+    ///
+    /// [`SparseChain::from_anchor`]: sparse_chain::SparseChain::from_anchor
+    pub fn anchor(&self) -> Option<BlockId> {
+        self.chain_graph.anchor()
+    }
+
+    /// Sets the trusted anchor of the chain. See [`SparseChain::set_anchor`].
+    ///
+    /// [`SparseChain::set_anchor`]: sparse_chain::SparseChain::set_anchor
+    pub fn set_anchor(
+        &mut self,
+        anchor: Option<BlockId>,
+    ) -> Result<(), sparse_chain::InsertCheckpointError> {
+        self.chain_graph.set_anchor(anchor)
+    }
+
     pub fn determine_changeset(
         &self,
         scan: &KeychainScan<K, P>,
@@ -99,6 +128,24 @@ where
             .filter(|(_, txout)| txout.spent_by.is_none())
     }
 
+    /// The outputs of the tracker's keychains that `cursor` hasn't reported yet.
+    ///
+    /// Only outputs of transactions in the chain are returned. They come in the order of their
+    /// position in the chain (unconfirmed ones last) so a payment processor can poll this after
+    /// each sync rather than diffing the UTXO set itself. Outputs that were spent before they
+    /// were seen are returned too. Once they have been handled, add their outpoints to `cursor`
+    /// (it implements [`Extend`]) and persist it.
+    pub fn outputs_since(&self, cursor: &OutputCursor) -> Vec<(&(K, u32), FullTxOut<P>)> {
+        let mut new_outputs = self
+            .full_txouts()
+            .filter(|(_, txout)| !cursor.is_reported(txout.outpoint))
+            .collect::<Vec<_>>();
+        new_outputs.sort_by(|(_, a), (_, b)| {
+            (&a.chain_position, a.outpoint).cmp(&(&b.chain_position, b.outpoint))
+        });
+        new_outputs
+    }
+
     pub fn chain_graph(&self) -> &ChainGraph<P> {
         &self.chain_graph
     }
@@ -137,6 +184,56 @@ where
         Ok(changeset)
     }
 
+    /// Determines the changeset that connects `block` at `height` to the chain and adds the
+    /// transactions in it that are relevant to the `txout_index`.
+    ///
+    /// The derivation indices are raised past the script pubkeys the relevant transactions pay to.
+    /// Neither the tracker nor its index are changed and transactions that aren't relevant are never
+    /// cloned so this can be called on every block a node or compact block filter client hands
+    /// over. It fails like [`determine_changeset`] when the block doesn't connect to the chain.
+    ///
+    /// [`determine_changeset`]: Self::determine_changeset
+    pub fn scan_block_preview(
+        &self,
+        block: &Block,
+        height: u32,
+    ) -> Result<KeychainChangeSet<K, P>, chain_graph::UpdateError<P>> {
+        let inner = self.txout_index.inner();
+        let mut last_active_indexes = BTreeMap::<K, u32>::new();
+        let relevant = inner.relevant_txs_in_block(block).inspect(|tx| {
+            for output in &tx.output {
+                if let Some((keychain, index)) = inner.index_of_spk(&output.script_pubkey) {
+                    let last = last_active_indexes.entry(keychain).or_insert(index);
+                    *last = index.max(*last);
+                }
+            }
+        });
+        let update = ChainGraph::from_block(block, height, relevant);
+
+        self.determine_changeset(&KeychainScan {
+            update,
+            last_active_indexes,
+        })
+    }
+
+    /// Scans `block` at `height` and applies the resulting changeset. This is equivalent to calling
+    /// [`scan_block_preview`] and [`apply_changeset`] in sequence.
+    ///
+    /// **Warning**: This function modifies the internal state of the tracker. You are responsible
+    /// for persisting these changes to disk if you need to restore them.
+    ///
+    /// [`scan_block_preview`]: Self::scan_block_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn scan_block(
+        &mut self,
+        block: &Block,
+        height: u32,
+    ) -> Result<KeychainChangeSet<K, P>, chain_graph::UpdateError<P>> {
+        let changeset = self.scan_block_preview(block, height)?;
+        self.apply_changeset(changeset.clone());
+        Ok(changeset)
+    }
+
     /// Inserts a transaction into the inner [`ChainGraph`] and optionally into the inner chain at
     /// `position`.
     ///
@@ -163,6 +260,49 @@ where
         Ok(changeset)
     }
 
+    /// Determines the changeset that evicts conflicting unconfirmed transactions from the chain,
+    /// keeping one canonical transaction per conflict cluster.
+    ///
+    /// See [`ChainGraph::compact_conflicts_preview`].
+    pub fn compact_conflicts_preview(&self) -> KeychainChangeSet<K, P> {
+        self.chain_graph.compact_conflicts_preview().into()
+    }
+
+    /// Evicts conflicting unconfirmed transactions from the chain, keeping one canonical
+    /// transaction per conflict cluster.
+    ///
+    /// **Warning**: This function modifies the internal state of the tracker. You are responsible
+    /// for persisting these changes to disk if you need to restore them.
+    pub fn compact_conflicts(&mut self) -> KeychainChangeSet<K, P> {
+        let changeset = self.compact_conflicts_preview();
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Determines the changeset that evicts the unconfirmed transactions `txids` and their
+    /// descendants from the chain.
+    ///
+    /// See [`ChainGraph::evict_txs_preview`].
+    pub fn evict_txs_preview(
+        &self,
+        txids: impl IntoIterator<Item = Txid>,
+    ) -> Result<KeychainChangeSet<K, P>, chain_graph::EvictTxError<P>> {
+        Ok(self.chain_graph.evict_txs_preview(txids)?.into())
+    }
+
+    /// Evicts the unconfirmed transactions `txids` and their descendants from the chain.
+    ///
+    /// **Warning**: This function modifies the internal state of the tracker. You are responsible
+    /// for persisting these changes to disk if you need to restore them.
+    pub fn evict_txs(
+        &mut self,
+        txids: impl IntoIterator<Item = Txid>,
+    ) -> Result<KeychainChangeSet<K, P>, chain_graph::EvictTxError<P>> {
+        let changeset = self.evict_txs_preview(txids)?;
+        self.apply_changeset(changeset.clone());
+        Ok(changeset)
+    }
+
     /// Returns the *balance* of the keychain i.e. the value of unspent transaction outputs tracked.
     /// The caller provides a `should_trust` predicate which must decide whether the value of
     /// unconfirmed outputs on this keychain are guaranteed to be realized or not. For example:
@@ -175,32 +315,75 @@ where
     ///
     /// When in doubt set `should_trust` to return false. This doesn't do anything other than change
     /// where the unconfirmed output's value is accounted for in `Balance`.
-    pub fn balance(&self, mut should_trust: impl FnMut(&K) -> bool) -> Balance {
+    ///
+    /// Unconfirmed outputs of transactions with a known conflict are counted as `at_risk` whatever
+    /// `should_trust` says. Use [`balance_with_min_feerate`] to also put the outputs of low feerate
+    /// transactions there.
+    ///
+    /// [`balance_with_min_feerate`]: Self::balance_with_min_feerate
+    pub fn balance(&self, should_trust: impl FnMut(&K) -> bool) -> Balance {
+        self.balance_with_min_feerate(should_trust, 0.0)
+    }
+
+    /// Like [`balance`] but unconfirmed outputs of transactions paying less than `min_feerate`
+    /// (in sats per weight unit) are also counted as `at_risk`.
+    ///
+    /// A merchant can use this to hold off on releasing goods until a payment has either confirmed
+    /// or is unlikely to be dropped or replaced. See [`ChainGraph::at_risk_txids`] for which
+    /// transactions are considered to be at risk.
+    ///
+    /// [`balance`]: Self::balance
+    pub fn balance_with_min_feerate(
+        &self,
+        should_trust: impl FnMut(&K) -> bool,
+        min_feerate: f32,
+    ) -> Balance {
+        self.balance_with_policy(should_trust, min_feerate, &ConfirmationPolicy::default())
+    }
+
+    /// Like [`balance_with_min_feerate`] but confirmed outputs of untrusted keychains are only
+    /// counted as `confirmed` once they meet `policy`. Until then they are `untrusted_pending`.
+    ///
+    /// [`balance_with_min_feerate`]: Self::balance_with_min_feerate
+    pub fn balance_with_policy(
+        &self,
+        mut should_trust: impl FnMut(&K) -> bool,
+        min_feerate: f32,
+        policy: &ConfirmationPolicy,
+    ) -> Balance {
         let mut immature = 0;
         let mut trusted_pending = 0;
         let mut untrusted_pending = 0;
+        let mut at_risk = 0;
         let mut confirmed = 0;
         let last_sync_height = self.chain().latest_checkpoint().map(|latest| latest.height);
+        let at_risk_txids = self.chain_graph.at_risk_txids(min_feerate);
         for ((keychain, _), utxo) in self.full_utxos() {
             let chain_position = &utxo.chain_position;
 
             match chain_position.height() {
                 TxHeight::Confirmed(_) => {
-                    if utxo.is_on_coinbase {
-                        if utxo.is_mature(
+                    if utxo.is_on_coinbase
+                        && !utxo.is_mature(
                             last_sync_height
                                 .expect("since it's confirmed we must have a checkpoint"),
-                        ) {
-                            confirmed += utxo.txout.value;
-                        } else {
-                            immature += utxo.txout.value;
-                        }
-                    } else {
+                        )
+                    {
+                        immature += utxo.txout.value;
+                    } else if policy.is_settled(
+                        self.chain(),
+                        chain_position.height(),
+                        should_trust(keychain),
+                    ) {
                         confirmed += utxo.txout.value;
+                    } else {
+                        untrusted_pending += utxo.txout.value;
                     }
                 }
                 TxHeight::Unconfirmed => {
-                    if should_trust(keychain) {
+                    if at_risk_txids.contains(&utxo.outpoint.txid) {
+                        at_risk += utxo.txout.value;
+                    } else if should_trust(keychain) {
                         trusted_pending += utxo.txout.value;
                     } else {
                         untrusted_pending += utxo.txout.value;
@@ -213,6 +396,7 @@ where
             immature,
             trusted_pending,
             untrusted_pending,
+            at_risk,
             confirmed,
         }
     }
@@ -4,7 +4,10 @@ use crate::{
     ForEachTxout, SpkTxOutIndex,
 };
 use bitcoin::{secp256k1::Secp256k1, OutPoint, Script, TxOut};
-use core::{fmt::Debug, ops::Deref};
+use core::{
+    fmt::Debug,
+    ops::{Bound, Deref, RangeInclusive},
+};
 
 /// A convenient wrapper around [`SpkTxOutIndex`] that sets the script pubkeys basaed on a miniscript
 /// [`Descriptor<DescriptorPublicKey>`][`Descriptor`]s.
@@ -156,6 +159,30 @@ impl<K: Clone + Ord + Debug> KeychainTxOutIndex<K> {
             .map(|((_, derivation_index), spk)| (*derivation_index, spk))
     }
 
+    /// Iterates over the script pubkeys stored after the index `since` has for their keychain.
+    ///
+    /// This is meant for chain sources that subscribe to script pubkeys. Keep the
+    /// [`derivation_indices`] from when you last subscribed and pass them in to find the scripts
+    /// that have been stored since then (by handing out addresses, creating change or applying
+    /// updates). All the stored scripts of keychains that aren't in `since` are returned.
+    ///
+    /// [`derivation_indices`]: Self::derivation_indices
+    pub fn stored_scripts_since<'a>(
+        &'a self,
+        since: &'a BTreeMap<K, u32>,
+    ) -> impl Iterator<Item = (&'a K, u32, &'a Script)> + 'a {
+        self.keychains.keys().flat_map(move |keychain| {
+            let start = match since.get(keychain) {
+                Some(&index) => Bound::Excluded((keychain.clone(), index)),
+                None => Bound::Included((keychain.clone(), u32::MIN)),
+            };
+            self.inner
+                .script_pubkeys()
+                .range((start, Bound::Included((keychain.clone(), u32::MAX))))
+                .map(move |((_, index), spk)| (keychain, *index, spk))
+        })
+    }
+
     /// Get the derivation index after the current one
     pub fn next_derivation_index(&self, keychain: &K) -> u32 {
         self.derivation_index(keychain)
@@ -200,17 +227,28 @@ impl<K: Clone + Ord + Debug> KeychainTxOutIndex<K> {
     /// Returns whether any new script pubkeys were derived. This will be false when they had already all been
     /// stored or wheen the `keychain` itself was never added to the index.
     pub fn store_up_to(&mut self, keychain: &K, up_to: u32) -> bool {
+        self.store_range(keychain, 0..=up_to)
+    }
+
+    /// Like [`store_up_to`] but the script pubkeys below the start of `range` are left out, e.g.
+    /// when the descriptor is known not to have been used at those indices.
+    ///
+    /// Nothing below the current derivation index is stored, so the range only skips indices of a
+    /// keychain that has nothing stored yet. Descriptors without a wildcard only have index 0.
+    ///
+    /// [`store_up_to`]: Self::store_up_to
+    pub fn store_range(&mut self, keychain: &K, range: RangeInclusive<u32>) -> bool {
         let descriptor = match self.keychains.get(&keychain) {
             Some(descriptor) => descriptor,
             None => return false,
         };
 
         let secp = Secp256k1::verification_only();
-        let end = match descriptor.has_wildcard() {
-            false => 0,
-            true => up_to,
+        let (start, end) = match descriptor.has_wildcard() {
+            false => (0, 0),
+            true => (*range.start(), *range.end()),
         };
-        let next_to_derive = self.next_derivation_index(keychain);
+        let next_to_derive = self.next_derivation_index(keychain).max(start);
         if next_to_derive > end {
             return false;
         }
@@ -342,6 +380,73 @@ impl<K: Clone + Ord + Debug> KeychainTxOutIndex<K> {
             })
             .collect()
     }
+
+    /// How the stored script pubkeys of `keychain` have been used so far.
+    ///
+    /// A keychain that hasn't been added to the index has the default (empty) [`GapStats`].
+    pub fn gap_stats(&self, keychain: &K) -> GapStats {
+        let mut max_gap = 0;
+        let mut last_active_index = None;
+        for (index, _) in self.keychain_txouts(keychain) {
+            let gap = match last_active_index {
+                Some(last) if index > last => index - last - 1,
+                Some(_) => 0,
+                None => index,
+            };
+            max_gap = max_gap.max(gap);
+            last_active_index = Some(index);
+        }
+
+        let derivation_index = self.derivation_index(keychain);
+        let current_gap = match (derivation_index, last_active_index) {
+            (Some(derived), Some(last)) => derived - last,
+            (Some(derived), None) => derived + 1,
+            (None, _) => 0,
+        };
+
+        GapStats {
+            derivation_index,
+            last_active_index,
+            current_gap,
+            max_gap,
+        }
+    }
+
+    /// The [`GapStats`] of every keychain in the index.
+    pub fn all_gap_stats(&self) -> BTreeMap<K, GapStats> {
+        self.keychains
+            .keys()
+            .map(|keychain| (keychain.clone(), self.gap_stats(keychain)))
+            .collect()
+    }
+}
+
+/// How the script pubkeys a keychain has stored are used, see [`KeychainTxOutIndex::gap_stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct GapStats {
+    /// The highest stored derivation index, see [`KeychainTxOutIndex::derivation_index`]
+    pub derivation_index: Option<u32>,
+    /// The highest derivation index with a `TxOut`, see [`KeychainTxOutIndex::last_active_index`]
+    pub last_active_index: Option<u32>,
+    /// The number of unused script pubkeys stored after the last active one (all the stored ones
+    /// if none is active yet)
+    pub current_gap: u32,
+    /// The longest run of unused derivation indices before the last active one. A scan that stops
+    /// after a gap of this many unused script pubkeys won't find the last active one.
+    pub max_gap: u32,
+}
+
+impl GapStats {
+    /// How many more unused script pubkeys have to be stored so that `lookahead` of them follow the
+    /// last active one, as [`KeychainTxOutIndex::pad_with_unused`] would store.
+    pub fn lookahead_shortfall(&self, lookahead: u32) -> u32 {
+        lookahead.saturating_sub(self.current_gap)
+    }
 }
 
 fn descriptor_into_script_iter(
@@ -0,0 +1,303 @@
+//! Persisting the changes made to a [`KeychainTracker`].
+//!
+//! [`PersistBackend`] is implemented by the places changesets can be stored so that code which
+//! only appends and loads them doesn't need to know where they end up. [`MemoryStore`] keeps them
+//! in memory which is handy for tests and wallets that don't need to outlive the process.
+//!
+//! Backends that talk to a database through an async driver implement [`AsyncPersistBackend`]
+//! instead so they don't block the executor. With `std`, [`BlockingBackend`] lets them be used by
+//! code that is written against [`PersistBackend`]. [`MemoryStore`] implements both traits.
+use crate::{
+    collections::BTreeMap,
+    keychain::{KeychainChangeSet, KeychainTracker},
+    sparse_chain::ChainPosition,
+};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{convert::Infallible, future::Future, pin::Pin};
+
+/// Somewhere the changesets of a [`KeychainTracker<K, P>`] (and extension blobs that go with them)
+/// can be stored and loaded from.
+///
+/// See [`KeychainStore`] for what the methods are expected to do.
+///
+/// [`KeychainStore`]: crate::file_store::KeychainStore
+pub trait PersistBackend<K, P> {
+    /// The error returned when writing fails
+    type WriteError: core::fmt::Debug + core::fmt::Display;
+    /// The error returned when loading fails
+    type LoadError: core::fmt::Debug + core::fmt::Display;
+
+    /// Appends a changeset.
+    fn append_changeset(
+        &mut self,
+        changeset: &KeychainChangeSet<K, P>,
+    ) -> Result<(), Self::WriteError>;
+
+    /// Appends an extension blob stored under `name` replacing any earlier blob with that name.
+    fn append_extension(&mut self, name: &str, data: &[u8]) -> Result<(), Self::WriteError>;
+
+    /// Applies the stored changesets to `tracker` in order, stopping at the first one that can't
+    /// be loaded.
+    fn load_into_keychain_tracker(
+        &mut self,
+        tracker: &mut KeychainTracker<K, P>,
+    ) -> Result<(), Self::LoadError>;
+
+    /// Loads the latest version of every extension blob along with the result of reading them.
+    fn aggregate_extensions(&mut self) -> (BTreeMap<String, Vec<u8>>, Result<(), Self::LoadError>);
+
+    /// Appends a changeset that sets the derivation indices.
+    fn set_derivation_indices(
+        &mut self,
+        indices: BTreeMap<K, u32>,
+    ) -> Result<(), Self::WriteError> {
+        self.append_changeset(&KeychainChangeSet {
+            chain_graph: Default::default(),
+            derivation_indices: indices,
+        })
+    }
+}
+
+/// The future returned by the methods of [`AsyncPersistBackend`].
+pub type PersistFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The async version of [`PersistBackend`] for backends whose IO shouldn't block the thread, e.g.
+/// an async SQL driver.
+///
+/// The methods do the same as those of [`PersistBackend`] but return boxed futures so the trait
+/// can be used as a trait object and the futures can be sent to other threads.
+pub trait AsyncPersistBackend<K, P> {
+    /// The error returned when writing fails
+    type WriteError: core::fmt::Debug + core::fmt::Display;
+    /// The error returned when loading fails
+    type LoadError: core::fmt::Debug + core::fmt::Display;
+
+    /// Appends a changeset.
+    fn append_changeset<'a>(
+        &'a mut self,
+        changeset: &'a KeychainChangeSet<K, P>,
+    ) -> PersistFuture<'a, Result<(), Self::WriteError>>;
+
+    /// Appends an extension blob stored under `name` replacing any earlier blob with that name.
+    fn append_extension<'a>(
+        &'a mut self,
+        name: &'a str,
+        data: &'a [u8],
+    ) -> PersistFuture<'a, Result<(), Self::WriteError>>;
+
+    /// Applies the stored changesets to `tracker` in order, stopping at the first one that can't
+    /// be loaded.
+    fn load_into_keychain_tracker<'a>(
+        &'a mut self,
+        tracker: &'a mut KeychainTracker<K, P>,
+    ) -> PersistFuture<'a, Result<(), Self::LoadError>>;
+
+    /// Loads the latest version of every extension blob along with the result of reading them.
+    #[allow(clippy::type_complexity)]
+    fn aggregate_extensions(
+        &mut self,
+    ) -> PersistFuture<'_, (BTreeMap<String, Vec<u8>>, Result<(), Self::LoadError>)>;
+}
+
+/// Makes an [`AsyncPersistBackend`] usable where a [`PersistBackend`] is expected by blocking the
+/// current thread until each of its futures completes.
+///
+/// The futures are polled on the calling thread so the backend must not rely on that thread to
+/// make progress. With tokio for instance call it from a blocking task (`spawn_blocking`) of a
+/// multi-threaded runtime, not from an async task.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default)]
+pub struct BlockingBackend<S>(pub S);
+
+#[cfg(feature = "std")]
+impl<K, P, S: AsyncPersistBackend<K, P>> PersistBackend<K, P> for BlockingBackend<S> {
+    type WriteError = S::WriteError;
+    type LoadError = S::LoadError;
+
+    fn append_changeset(
+        &mut self,
+        changeset: &KeychainChangeSet<K, P>,
+    ) -> Result<(), Self::WriteError> {
+        crate::blocking::block_on(self.0.append_changeset(changeset))
+    }
+
+    fn append_extension(&mut self, name: &str, data: &[u8]) -> Result<(), Self::WriteError> {
+        crate::blocking::block_on(self.0.append_extension(name, data))
+    }
+
+    fn load_into_keychain_tracker(
+        &mut self,
+        tracker: &mut KeychainTracker<K, P>,
+    ) -> Result<(), Self::LoadError> {
+        crate::blocking::block_on(self.0.load_into_keychain_tracker(tracker))
+    }
+
+    fn aggregate_extensions(&mut self) -> (BTreeMap<String, Vec<u8>>, Result<(), Self::LoadError>) {
+        crate::blocking::block_on(self.0.aggregate_extensions())
+    }
+}
+
+/// Keeps the changesets of a [`KeychainTracker<K, P>`] in memory.
+///
+/// It has the same methods as [`KeychainStore`] (apart from those dealing with files and
+/// migrations) but can't fail.
+///
+/// [`KeychainStore`]: crate::file_store::KeychainStore
+#[derive(Clone, Debug)]
+pub struct MemoryStore<K, P> {
+    changesets: Vec<KeychainChangeSet<K, P>>,
+    extensions: BTreeMap<String, Vec<u8>>,
+}
+
+impl<K, P> Default for MemoryStore<K, P> {
+    fn default() -> Self {
+        Self {
+            changesets: Default::default(),
+            extensions: Default::default(),
+        }
+    }
+}
+
+impl<K, P> MemoryStore<K, P>
+where
+    K: Ord + Clone + core::fmt::Debug,
+    P: ChainPosition,
+{
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Iterates over the stored changesets from first to last.
+    pub fn iter_changesets(&self) -> impl Iterator<Item = &KeychainChangeSet<K, P>> + '_ {
+        self.changesets.iter()
+    }
+
+    /// Loads all the changesets that have been stored as one giant changeset.
+    ///
+    /// The result is always `Ok`. It is only returned to match [`KeychainStore`].
+    ///
+    /// [`KeychainStore`]: crate::file_store::KeychainStore
+    pub fn aggregate_changeset(&self) -> (KeychainChangeSet<K, P>, Result<(), Infallible>) {
+        let mut changeset = KeychainChangeSet::default();
+        for next_changeset in &self.changesets {
+            changeset.append(next_changeset.clone());
+        }
+        (changeset, Ok(()))
+    }
+
+    /// Loads the latest version of every extension blob in the store.
+    pub fn aggregate_extensions(&self) -> (BTreeMap<String, Vec<u8>>, Result<(), Infallible>) {
+        (self.extensions.clone(), Ok(()))
+    }
+
+    /// Applies all the stored changesets to `tracker`.
+    pub fn load_into_keychain_tracker(
+        &self,
+        tracker: &mut KeychainTracker<K, P>,
+    ) -> Result<(), Infallible> {
+        for changeset in &self.changesets {
+            tracker.apply_changeset(changeset.clone());
+        }
+        Ok(())
+    }
+
+    /// Appends a new changeset (unless it is empty).
+    pub fn append_changeset(
+        &mut self,
+        changeset: &KeychainChangeSet<K, P>,
+    ) -> Result<(), Infallible> {
+        if !changeset.is_empty() {
+            self.changesets.push(changeset.clone());
+        }
+        Ok(())
+    }
+
+    /// Appends a new changeset setting the derivation indicies
+    pub fn set_derivation_indices(&mut self, indices: BTreeMap<K, u32>) -> Result<(), Infallible> {
+        self.append_changeset(&KeychainChangeSet {
+            chain_graph: Default::default(),
+            derivation_indices: indices,
+        })
+    }
+
+    /// Stores an extension blob under `name`, replacing any earlier blob with that name.
+    pub fn append_extension(&mut self, name: &str, data: &[u8]) -> Result<(), Infallible> {
+        self.extensions.insert(name.into(), data.into());
+        Ok(())
+    }
+}
+
+impl<K, P> PersistBackend<K, P> for MemoryStore<K, P>
+where
+    K: Ord + Clone + core::fmt::Debug,
+    P: ChainPosition,
+{
+    type WriteError = Infallible;
+    type LoadError = Infallible;
+
+    fn append_changeset(
+        &mut self,
+        changeset: &KeychainChangeSet<K, P>,
+    ) -> Result<(), Self::WriteError> {
+        MemoryStore::append_changeset(self, changeset)
+    }
+
+    fn append_extension(&mut self, name: &str, data: &[u8]) -> Result<(), Self::WriteError> {
+        MemoryStore::append_extension(self, name, data)
+    }
+
+    fn load_into_keychain_tracker(
+        &mut self,
+        tracker: &mut KeychainTracker<K, P>,
+    ) -> Result<(), Self::LoadError> {
+        MemoryStore::load_into_keychain_tracker(self, tracker)
+    }
+
+    fn aggregate_extensions(&mut self) -> (BTreeMap<String, Vec<u8>>, Result<(), Self::LoadError>) {
+        MemoryStore::aggregate_extensions(self)
+    }
+}
+
+impl<K, P> AsyncPersistBackend<K, P> for MemoryStore<K, P>
+where
+    K: Ord + Clone + core::fmt::Debug + Send + Sync,
+    P: ChainPosition + Send + Sync,
+{
+    type WriteError = Infallible;
+    type LoadError = Infallible;
+
+    fn append_changeset<'a>(
+        &'a mut self,
+        changeset: &'a KeychainChangeSet<K, P>,
+    ) -> PersistFuture<'a, Result<(), Self::WriteError>> {
+        Box::pin(core::future::ready(MemoryStore::append_changeset(
+            self, changeset,
+        )))
+    }
+
+    fn append_extension<'a>(
+        &'a mut self,
+        name: &'a str,
+        data: &'a [u8],
+    ) -> PersistFuture<'a, Result<(), Self::WriteError>> {
+        Box::pin(core::future::ready(MemoryStore::append_extension(
+            self, name, data,
+        )))
+    }
+
+    fn load_into_keychain_tracker<'a>(
+        &'a mut self,
+        tracker: &'a mut KeychainTracker<K, P>,
+    ) -> PersistFuture<'a, Result<(), Self::LoadError>> {
+        Box::pin(core::future::ready(
+            MemoryStore::load_into_keychain_tracker(self, tracker),
+        ))
+    }
+
+    fn aggregate_extensions(
+        &mut self,
+    ) -> PersistFuture<'_, (BTreeMap<String, Vec<u8>>, Result<(), Self::LoadError>)> {
+        Box::pin(core::future::ready(MemoryStore::aggregate_extensions(self)))
+    }
+}
@@ -1,18 +1,26 @@
 #![no_std]
 pub use bitcoin;
+pub mod cancel;
 pub mod chain_graph;
+pub mod deferred;
 mod spk_txout_index;
 pub use spk_txout_index::*;
 mod chain_data;
 pub use chain_data::*;
 mod for_each_txout;
+pub mod funding;
 pub mod keychain;
 pub mod sparse_chain;
+pub mod spend_alert;
+pub mod standardness;
+pub mod tip;
 pub mod tx_graph;
 pub use for_each_txout::*;
 
 #[cfg(feature = "miniscript")]
 pub use miniscript;
+#[cfg(feature = "std")]
+mod blocking;
 #[cfg(feature = "miniscript")]
 pub mod descriptor_ext;
 #[cfg(feature = "file_store")]
@@ -4,7 +4,8 @@ use core::{
 };
 
 use crate::{collections::*, tx_graph::TxGraph, BlockId, FullTxOut, TxHeight};
-use bitcoin::{hashes::Hash, BlockHash, OutPoint, Txid};
+use alloc::vec::Vec;
+use bitcoin::{hashes::Hash, BlockHash, BlockHeader, OutPoint, Txid};
 
 /// This is a non-monotone structure that tracks relevant [`Txid`]s that are ordered by position `P`.
 ///
@@ -22,6 +23,10 @@ pub struct SparseChain<P = TxHeight> {
     txid_to_pos: HashMap<Txid, P>,
     /// Limit number of checkpoints.
     checkpoint_limit: Option<usize>,
+    /// Which checkpoints to keep by their depth.
+    checkpoint_retention: Option<CheckpointRetention>,
+    /// A trusted block that updates may not invalidate.
+    anchor: Option<BlockId>,
 }
 
 impl<P> Default for SparseChain<P> {
@@ -31,10 +36,73 @@ impl<P> Default for SparseChain<P> {
             ordered_txids: Default::default(),
             txid_to_pos: Default::default(),
             checkpoint_limit: Default::default(),
+            checkpoint_retention: Default::default(),
+            anchor: Default::default(),
         }
     }
 }
 
+/// A policy for which checkpoints a [`SparseChain`] keeps based on how deep they are.
+///
+/// Keeping checkpoints at several depths lets us find the point of agreement with a chain source
+/// after both shallow and deep reorgs without the number of checkpoints growing with the chain.
+///
+/// Every checkpoint less than `recent` blocks below the tip is kept. Below that, each tier
+/// `(depth, interval)` applies to checkpoints at least `depth` blocks below the tip (until the next
+/// tier) and keeps the lowest checkpoint in each span of `interval` heights. Checkpoints that are
+/// neither recent nor in a tier are dropped. The tip itself is always kept.
+///
+/// For example, to keep the last 100 checkpoints, then one every 144 blocks for ~two weeks and one
+/// every 2016 blocks below that:
+///
+/// ```
+/// # use bdk_chain::sparse_chain::CheckpointRetention;
+/// let retention = CheckpointRetention {
+///     recent: 100,
+///     tiers: vec![(100, 144), (2016, 2016)],
+/// };
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckpointRetention {
+    /// The number of blocks below the tip in which all checkpoints are kept
+    pub recent: u32,
+    /// The `(depth, interval)` tiers in ascending order of depth
+    pub tiers: Vec<(u32, u32)>,
+}
+
+impl CheckpointRetention {
+    /// Selects the heights of the `checkpoints` to keep.
+    fn retained_heights(&self, checkpoints: &BTreeMap<u32, BlockHash>) -> BTreeSet<u32> {
+        let tip = match checkpoints.keys().last() {
+            Some(&tip) => tip,
+            None => return BTreeSet::new(),
+        };
+        let mut spans = BTreeSet::new();
+        checkpoints
+            .keys()
+            .copied()
+            .filter(|&height| {
+                let depth = tip - height;
+                if depth < self.recent.max(1) {
+                    return true;
+                }
+                match self
+                    .tiers
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .find(|(_, (tier_depth, _))| depth >= *tier_depth)
+                {
+                    // keeping the lowest checkpoint of each span means it stays the same as the
+                    // tip moves
+                    Some((tier, &(_, interval))) => spans.insert((tier, height / interval.max(1))),
+                    None => false,
+                }
+            })
+            .collect()
+    }
+}
+
 /// Represents a failure when trying to insert a [`Txid`] into [`SparseChain`].
 #[derive(Clone, Debug, PartialEq)]
 pub enum InsertTxError<P> {
@@ -116,6 +184,9 @@ pub enum UpdateError<P = TxHeight> {
         original_pos: P,
         update_pos: P,
     },
+    /// The update has a different block at the height of the chain's trusted anchor or would
+    /// invalidate checkpoints without connecting above it. Contains the conflicting height.
+    AnchorConflict { anchor: BlockId, height: u32 },
 }
 
 impl<P: core::fmt::Debug> core::fmt::Display for UpdateError<P> {
@@ -127,6 +198,9 @@ impl<P: core::fmt::Debug> core::fmt::Display for UpdateError<P> {
             Self::TxInconsistent { txid, original_pos, update_pos } =>
                 write!(f, "tx ({}) had position ({:?}), but is ({:?}) in the update", 
                     txid, original_pos, update_pos),
+            Self::AnchorConflict { anchor, height } =>
+                write!(f, "the update conflicts with the anchor block {} at height {} (conflicting height {})",
+                    anchor.hash, anchor.height, height),
         }
     }
 }
@@ -149,6 +223,44 @@ impl<P: ChainPosition> SparseChain<P> {
         chain
     }
 
+    /// Creates an empty chain pinned to a trusted `anchor` block.
+    ///
+    /// The chain starts with the anchor as its only checkpoint. Updates which replace the anchor,
+    /// or which invalidate checkpoints without agreeing with us on a block above the anchor, are
+    /// rejected with [`UpdateError::AnchorConflict`] so a chain source can't rewrite history we
+    /// already trust.
+    pub fn from_anchor(anchor: BlockId) -> Self {
+        let mut chain = Self::from_checkpoints([anchor]);
+        chain.anchor = Some(anchor);
+        chain
+    }
+
+    /// The trusted anchor of the chain (if any).
+    pub fn anchor(&self) -> Option<BlockId> {
+        self.anchor
+    }
+
+    /// Sets (or removes) the trusted anchor of the chain. See [`from_anchor`] for details.
+    ///
+    /// Fails if the chain already has a different block at the anchor's height.
+    ///
+    /// [`from_anchor`]: Self::from_anchor
+    pub fn set_anchor(&mut self, anchor: Option<BlockId>) -> Result<(), InsertCheckpointError> {
+        if let Some(anchor) = anchor {
+            if let Some(&original_hash) = self.checkpoints.get(&anchor.height) {
+                if original_hash != anchor.hash {
+                    return Err(InsertCheckpointError::HashNotMatching {
+                        height: anchor.height,
+                        original_hash,
+                        update_hash: anchor.hash,
+                    });
+                }
+            }
+        }
+        self.anchor = anchor;
+        Ok(())
+    }
+
     /// Get the `BlockId` for the last known tip.
     pub fn latest_checkpoint(&self) -> Option<BlockId> {
         self.checkpoints
@@ -219,6 +331,27 @@ impl<P: ChainPosition> SparseChain<P> {
         // the first checkpoint of the sparsechain to invalidate (if any)
         let invalid_from = self.checkpoints.range(invalid_lb..).next().map(|(&h, _)| h);
 
+        if let Some(anchor) = self.anchor {
+            let replaces_anchor = matches!(
+                update.checkpoints.get(&anchor.height),
+                Some(hash) if *hash != anchor.hash
+            );
+            if replaces_anchor {
+                return Err(UpdateError::AnchorConflict {
+                    anchor,
+                    height: anchor.height,
+                });
+            }
+            // an update may only invalidate checkpoints if it agrees with us on a block above the
+            // anchor
+            if let Some(first_invalid) = invalid_from.filter(|_| invalid_lb <= anchor.height) {
+                return Err(UpdateError::AnchorConflict {
+                    anchor,
+                    height: first_invalid,
+                });
+            }
+        }
+
         // the first checkpoint to invalidate (if any) should be represented in the update
         if let Some(first_invalid) = invalid_from {
             if !update.checkpoints.contains_key(&first_invalid) {
@@ -417,6 +550,9 @@ impl<P: ChainPosition> SparseChain<P> {
         match self.determine_changeset(&update) {
             Ok(changeset) => Ok(changeset),
             Err(UpdateError::NotConnected(_)) => panic!("should always connect"),
+            Err(UpdateError::AnchorConflict { .. }) => {
+                panic!("should not conflict since the update only has our latest checkpoint")
+            }
             Err(UpdateError::TxInconsistent {
                 txid: inconsistent_txid,
                 original_pos,
@@ -467,6 +603,13 @@ impl<P: ChainPosition> SparseChain<P> {
             Ok(changeset) => Ok(changeset),
             Err(UpdateError::NotConnected(_)) => panic!("error should have caught above"),
             Err(UpdateError::TxInconsistent { .. }) => panic!("should never add txs"),
+            Err(UpdateError::AnchorConflict { anchor, height }) => {
+                Err(InsertCheckpointError::HashNotMatching {
+                    height,
+                    original_hash: anchor.hash,
+                    update_hash: block_id.hash,
+                })
+            }
         }
     }
 
@@ -577,6 +720,22 @@ impl<P: ChainPosition> SparseChain<P> {
         self.prune_checkpoints();
     }
 
+    /// The policy for which checkpoints are kept based on their depth (if any).
+    pub fn checkpoint_retention(&self) -> Option<&CheckpointRetention> {
+        self.checkpoint_retention.as_ref()
+    }
+
+    /// Sets the [`CheckpointRetention`] policy and prunes the checkpoints it doesn't keep.
+    ///
+    /// This is applied before the [`checkpoint_limit`] so the two can be combined to also bound
+    /// the number of checkpoints.
+    ///
+    /// [`checkpoint_limit`]: Self::checkpoint_limit
+    pub fn set_checkpoint_retention(&mut self, retention: Option<CheckpointRetention>) {
+        self.checkpoint_retention = retention;
+        self.prune_checkpoints();
+    }
+
     /// Returns all [`Txid`]s that would be added to the sparse chain if this changeset was applied.
     pub fn changeset_additions<'a>(
         &'a self,
@@ -593,6 +752,12 @@ impl<P: ChainPosition> SparseChain<P> {
     }
 
     fn prune_checkpoints(&mut self) -> Option<BTreeMap<u32, BlockHash>> {
+        if let Some(retention) = &self.checkpoint_retention {
+            let retained = retention.retained_heights(&self.checkpoints);
+            self.checkpoints
+                .retain(|height, _| retained.contains(height));
+        }
+
         let limit = self.checkpoint_limit?;
 
         // find last height to be pruned
@@ -620,6 +785,85 @@ impl<P: ChainPosition> SparseChain<P> {
     pub fn is_empty(&self) -> bool {
         self.checkpoints.is_empty() && self.txid_to_pos.is_empty()
     }
+
+    /// Lists where `self` and `other` disagree.
+    ///
+    /// This is meant for comparing updates for the same scripts from two different chain sources
+    /// before applying either of them. Checkpoints are only compared at the heights both chains
+    /// have while every transaction that is missing from one chain or at a different position in
+    /// it is reported.
+    pub fn discrepancies(&self, other: &Self) -> Vec<Discrepancy<P>> {
+        let checkpoints = self.checkpoints.iter().filter_map(|(&height, &ours)| {
+            let theirs = *other.checkpoints.get(&height)?;
+            if ours == theirs {
+                return None;
+            }
+            Some(Discrepancy::Checkpoint {
+                height,
+                ours,
+                theirs,
+            })
+        });
+
+        let txids = self
+            .txid_to_pos
+            .keys()
+            .chain(other.txid_to_pos.keys())
+            .collect::<BTreeSet<_>>();
+        let txs = txids.into_iter().filter_map(|txid| {
+            let ours = self.tx_position(*txid);
+            let theirs = other.tx_position(*txid);
+            if ours == theirs {
+                return None;
+            }
+            Some(Discrepancy::Tx {
+                txid: *txid,
+                ours: ours.cloned(),
+                theirs: theirs.cloned(),
+            })
+        });
+
+        checkpoints.chain(txs).collect()
+    }
+}
+
+/// A disagreement between two chains found by [`SparseChain::discrepancies`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Discrepancy<P> {
+    /// The chains have different blocks at `height`
+    Checkpoint {
+        height: u32,
+        ours: BlockHash,
+        theirs: BlockHash,
+    },
+    /// A transaction is only in one of the chains (the position in the other is `None`) or is at
+    /// a different position in each
+    Tx {
+        txid: Txid,
+        ours: Option<P>,
+        theirs: Option<P>,
+    },
+}
+
+impl<P: core::fmt::Debug> core::fmt::Display for Discrepancy<P> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Discrepancy::Checkpoint {
+                height,
+                ours,
+                theirs,
+            } => write!(
+                f,
+                "block at height {} is {} but the other chain has {}",
+                height, ours, theirs
+            ),
+            Discrepancy::Tx { txid, ours, theirs } => write!(
+                f,
+                "tx {} is at {:?} but the other chain has it at {:?}",
+                txid, ours, theirs
+            ),
+        }
+    }
 }
 
 /// The return value of [`determine_changeset`].
@@ -661,6 +905,24 @@ impl<P> ChangeSet<P> {
     pub fn is_empty(&self) -> bool {
         self.checkpoints.is_empty() && self.txids.is_empty()
     }
+
+    /// Converts the positions of the changeset to another [`ChainPosition`] type with `f`.
+    ///
+    /// `f` must preserve the height of each position, otherwise the changeset will not be
+    /// consistent with its checkpoints.
+    pub fn try_map_positions<Q, E>(
+        self,
+        mut f: impl FnMut(P) -> Result<Q, E>,
+    ) -> Result<ChangeSet<Q>, E> {
+        Ok(ChangeSet {
+            checkpoints: self.checkpoints,
+            txids: self
+                .txids
+                .into_iter()
+                .map(|(txid, pos)| Ok((txid, pos.map(&mut f).transpose()?)))
+                .collect::<Result<_, E>>()?,
+        })
+    }
 }
 
 fn min_txid() -> Txid {
@@ -690,6 +952,23 @@ pub trait ChainPosition:
     fn unconfirmed() -> Self {
         Self::max_ord_of_height(TxHeight::Unconfirmed)
     }
+
+    /// Get the position of a transaction confirmed in the block with `header` at `height`.
+    ///
+    /// Positions that only record the height don't need to override this.
+    fn confirmed_in_block(height: u32, header: &BlockHeader) -> Self {
+        let _ = header;
+        Self::max_ord_of_height(TxHeight::Confirmed(height))
+    }
+}
+
+/// A [`ChainPosition`] with a serialization format that can be identified by a tag.
+///
+/// Persisted data records the tag of the positions it contains so that it can't be loaded as the
+/// wrong type of position later (and so it can be migrated from one type to another).
+pub trait PositionSchema: ChainPosition {
+    /// The tag identifying the serialization format of the position type.
+    const SCHEMA_TAG: u8;
 }
 
 #[cfg(test)]
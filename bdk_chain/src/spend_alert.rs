@@ -0,0 +1,136 @@
+//! Alerts for when watched outputs get spent.
+//!
+//! [`SpendAlerts`] holds a set of outpoints and scripts you care about (e.g. the outputs of a
+//! vault). After applying a [`ChangeSet`] to a [`ChainGraph`] you can ask it which of them were spent
+//! by the transactions the changeset introduced (or moved around in the chain).
+use crate::{
+    chain_graph::{ChainGraph, ChangeSet},
+    collections::*,
+    sparse_chain::ChainPosition,
+};
+use alloc::vec::Vec;
+use bitcoin::{OutPoint, Script, Transaction, TxOut, Txid};
+
+/// A set of outpoints and scripts to raise a [`SpendAlert`] for when they get spent.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpendAlerts {
+    outpoints: BTreeSet<OutPoint>,
+    scripts: BTreeSet<Script>,
+}
+
+/// A watched output that has been spent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpendAlert<P> {
+    /// The outpoint that was spent
+    pub outpoint: OutPoint,
+    /// The output that was spent (if it is in the graph)
+    pub txout: Option<TxOut>,
+    /// The input index of the spending transaction that spends `outpoint`
+    pub vin: u32,
+    /// The spending transaction
+    pub spending_tx: Transaction,
+    /// The position of the spending transaction in the chain. `None` if it is only in the graph.
+    pub spending_position: Option<P>,
+}
+
+impl<P> SpendAlert<P> {
+    /// The txid of the spending transaction.
+    pub fn spending_txid(&self) -> Txid {
+        self.spending_tx.txid()
+    }
+}
+
+impl SpendAlerts {
+    /// Raise an alert when `outpoint` is spent. Returns whether it wasn't already watched.
+    pub fn watch_outpoint(&mut self, outpoint: OutPoint) -> bool {
+        self.outpoints.insert(outpoint)
+    }
+
+    /// Raise an alert when any output with `script_pubkey` is spent. Returns whether it wasn't
+    /// already watched.
+    pub fn watch_script(&mut self, script_pubkey: Script) -> bool {
+        self.scripts.insert(script_pubkey)
+    }
+
+    /// Stop watching `outpoint`. Returns whether it was being watched.
+    pub fn unwatch_outpoint(&mut self, outpoint: &OutPoint) -> bool {
+        self.outpoints.remove(outpoint)
+    }
+
+    /// Stop watching `script_pubkey`. Returns whether it was being watched.
+    pub fn unwatch_script(&mut self, script_pubkey: &Script) -> bool {
+        self.scripts.remove(script_pubkey)
+    }
+
+    /// The watched outpoints.
+    pub fn outpoints(&self) -> &BTreeSet<OutPoint> {
+        &self.outpoints
+    }
+
+    /// The watched scripts.
+    pub fn scripts(&self) -> &BTreeSet<Script> {
+        &self.scripts
+    }
+
+    /// Whether nothing is being watched.
+    pub fn is_empty(&self) -> bool {
+        self.outpoints.is_empty() && self.scripts.is_empty()
+    }
+
+    /// Whether the output at `outpoint` is watched. `txout` is needed to match against the watched
+    /// scripts.
+    pub fn is_watched(&self, outpoint: &OutPoint, txout: Option<&TxOut>) -> bool {
+        self.outpoints.contains(outpoint)
+            || matches!(txout, Some(txout) if self.scripts.contains(&txout.script_pubkey))
+    }
+
+    /// Finds the watched outputs spent by the transactions in `changeset`.
+    ///
+    /// This looks at the full transactions the changeset adds to the graph and the transactions it
+    /// moves to a new position in the chain (e.g. when a spend gets confirmed). Call this **after**
+    /// `changeset` has been applied to `chain_graph` so that the spent outputs and the spending
+    /// transactions can be looked up in it.
+    pub fn check<P: ChainPosition>(
+        &self,
+        chain_graph: &ChainGraph<P>,
+        changeset: &ChangeSet<P>,
+    ) -> Vec<SpendAlert<P>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+
+        let added = changeset.graph.tx.iter().map(|tx| tx.txid());
+        let moved = changeset
+            .chain
+            .txids
+            .iter()
+            .filter(|(_, pos)| pos.is_some())
+            .map(|(txid, _)| *txid);
+        let txids = added.chain(moved).collect::<BTreeSet<_>>();
+
+        let graph = chain_graph.graph();
+        let mut alerts = Vec::new();
+
+        for txid in txids {
+            let spending_tx = match graph.get_tx(txid) {
+                Some(tx) => tx,
+                None => continue,
+            };
+            for (vin, txin) in spending_tx.input.iter().enumerate() {
+                let outpoint = txin.previous_output;
+                let txout = graph.get_txout(outpoint);
+                if self.is_watched(&outpoint, txout) {
+                    alerts.push(SpendAlert {
+                        outpoint,
+                        txout: txout.cloned(),
+                        vin: vin as u32,
+                        spending_tx: spending_tx.clone(),
+                        spending_position: chain_graph.chain().tx_position(txid).cloned(),
+                    });
+                }
+            }
+        }
+
+        alerts
+    }
+}
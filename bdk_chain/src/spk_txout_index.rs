@@ -4,7 +4,7 @@ use crate::{
     collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
     ForEachTxout,
 };
-use bitcoin::{self, OutPoint, Script, Transaction, TxOut, Txid};
+use bitcoin::{self, Block, OutPoint, Script, Transaction, TxOut, Txid};
 
 /// An index storing [`TxOut`]s that have a script pubkey that matches those in a list.
 ///
@@ -21,7 +21,38 @@ use bitcoin::{self, OutPoint, Script, Transaction, TxOut, Txid};
 /// modify txouts that have been indexed. To find out which txouts from the index are actually in the
 /// chain or unspent etc you must use other sources of information like a [`SparseChain`].
 ///
+/// The index doesn't need the `miniscript` feature. Script pubkeys can come from anywhere, e.g. the
+/// outputs of a Lightning node's channels or the addresses a block explorer has been asked about.
+/// [`KeychainTxOutIndex`] builds on it to derive the script pubkeys from descriptors.
+///
+/// # Example
+///
+/// ```rust
+/// # use bdk_chain::SpkTxOutIndex;
+/// # use bdk_chain::bitcoin::{hashes::hex::FromHex, PackedLockTime, Script, Transaction, TxOut};
+/// let channel_close = Script::from_hex("0014d9f65c5ac8dc7b0d0a05d3d0ee2d9b1d6b7a31d8").unwrap();
+/// let anchor = Script::from_hex("0020e2d4c8d0bc3a4aea4c1d4ba7b2a6f1f3a6d7b5e9a5f2c5d4b3a2f1e0d9c8b7a6").unwrap();
+///
+/// // index the scripts by whatever identifies them to the application
+/// let mut index = SpkTxOutIndex::<&str>::default();
+/// index.insert_script_pubkey("channel 1 close", channel_close.clone());
+/// index.insert_script_pubkey("channel 1 anchor", anchor);
+///
+/// let tx = Transaction {
+///     version: 2,
+///     lock_time: PackedLockTime(0),
+///     input: vec![],
+///     output: vec![TxOut { value: 50_000, script_pubkey: channel_close }],
+/// };
+/// index.scan(&tx);
+///
+/// assert!(index.is_used(&"channel 1 close"));
+/// assert_eq!(index.used(..).count(), 1);
+/// assert_eq!(index.unused(..).map(|(i, _)| *i).collect::<Vec<_>>(), vec!["channel 1 anchor"]);
+/// ```
+///
 /// [`TxOut`]: bitcoin::TxOut
+/// [`KeychainTxOutIndex`]: crate::keychain::KeychainTxOutIndex
 /// [`add_spk`]: Self::insert_script_pubkey
 /// [`Ord`]: core::cmp::Ord
 /// [`scan`]: Self::scan
@@ -149,9 +180,11 @@ impl<I: Clone + Ord> SpkTxOutIndex<I> {
         &self.script_pubkeys
     }
 
-    /// Adds a script pubkey to scan for. Returns `false` and does nothing if spk already exists in the map
+    /// Adds a script pubkey to scan for under `index`. Returns `false` and does nothing if the
+    /// script pubkey is already in the index.
     ///
-    /// the index will look for outputs spending to whenever it scans new data.
+    /// The index will look for outputs paying to it whenever it scans new data. Outputs scanned
+    /// before it was added aren't found unless they are scanned again.
     pub fn insert_script_pubkey(&mut self, index: I, spk: Script) -> bool {
         match self.spk_indexes.entry(spk.clone()) {
             Entry::Vacant(value) => {
@@ -189,6 +222,21 @@ impl<I: Clone + Ord> SpkTxOutIndex<I> {
             .map(|index| (index, self.spk_at_index(index).expect("must exist")))
     }
 
+    /// Iterates over the used script pubkeys in an index range.
+    ///
+    /// Here "used" means that the index has scanned a transaction output with the script pubkey.
+    /// See [`outputs_in_range`] for the outputs themselves.
+    ///
+    /// [`outputs_in_range`]: Self::outputs_in_range
+    pub fn used<R>(&self, range: R) -> impl DoubleEndedIterator<Item = (&I, &Script)>
+    where
+        R: RangeBounds<I>,
+    {
+        self.script_pubkeys
+            .range(range)
+            .filter(|(index, _)| !self.unused.contains(index))
+    }
+
     /// Returns whether the script pubkey at `index` has been used or not.
     ///
     /// Here "unused" means that after the script pubkey was stored in the index, the index has
@@ -255,4 +303,35 @@ impl<I: Clone + Ord> SpkTxOutIndex<I> {
             .is_some();
         input_matches || output_matches
     }
+
+    /// Iterates over the transactions in `block` that are relevant to the index without scanning
+    /// the block into it.
+    ///
+    /// Unlike calling [`is_relevant`] on each transaction, this also finds the transactions that
+    /// spend outputs created earlier in the block, so the block doesn't need to be scanned first.
+    /// Only the outputs that match are kept track of and a transaction's txid is only computed if
+    /// one of its outputs matches, so irrelevant transactions cost next to nothing.
+    ///
+    /// [`is_relevant`]: Self::is_relevant
+    pub fn relevant_txs_in_block<'a>(
+        &'a self,
+        block: &'a Block,
+    ) -> impl Iterator<Item = &'a Transaction> + 'a {
+        // outputs of earlier transactions in the block paying to our script pubkeys
+        let mut block_txouts = BTreeSet::new();
+        block.txdata.iter().filter(move |tx| {
+            let input_matches = tx.input.iter().any(|input| {
+                self.txouts.contains_key(&input.previous_output)
+                    || block_txouts.contains(&input.previous_output)
+            });
+            let mut txid = None;
+            for (vout, output) in tx.output.iter().enumerate() {
+                if self.spk_indexes.contains_key(&output.script_pubkey) {
+                    let txid = *txid.get_or_insert_with(|| tx.txid());
+                    block_txouts.insert(OutPoint::new(txid, vout as u32));
+                }
+            }
+            input_matches || txid.is_some()
+        })
+    }
 }
@@ -0,0 +1,399 @@
+//! Checking transactions against the standardness rules of bitcoind's mempool.
+//!
+//! A transaction that is valid by consensus may still be refused by nodes because it is not
+//! *standard*, in which case the backend usually answers with a terse rejection reason.
+//! [`validate_standardness`] runs the most common of these checks locally so each problem can be
+//! reported with the input or output that causes it.
+use crate::tx_graph::TxGraph;
+use alloc::vec::Vec;
+use bitcoin::{
+    blockdata::{
+        constants::WITNESS_SCALE_FACTOR,
+        opcodes::{self, all::*},
+        script::Instruction,
+    },
+    policy::{DEFAULT_BYTES_PER_SIGOP, MAX_STANDARD_TX_SIGOPS_COST, MAX_STANDARD_TX_WEIGHT},
+    Script, Transaction, TxIn, TxOut, VarInt,
+};
+
+/// The largest `scriptSig` bitcoind relays (enough for a 15-of-15 multisig P2SH input).
+pub const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+
+/// The largest P2WSH witness script bitcoind relays.
+pub const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+
+/// The largest `OP_RETURN` output script bitcoind relays.
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+
+/// The limits a transaction is checked against by [`validate_standardness`].
+///
+/// The default values are the defaults of bitcoind.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StandardnessPolicy {
+    /// The minimum feerate (in sats per weight unit) for the transaction to be relayed
+    pub min_relay_feerate: f32,
+    /// The feerate (in sats per weight unit) used to decide whether an output is dust, i.e. costs
+    /// more to spend than it is worth
+    pub dust_relay_feerate: f32,
+    /// The maximum weight of the transaction
+    pub max_weight: usize,
+    /// The maximum sigop cost of the transaction
+    pub max_sigops_cost: u32,
+}
+
+impl Default for StandardnessPolicy {
+    fn default() -> Self {
+        Self {
+            min_relay_feerate: 0.25,
+            dust_relay_feerate: 0.75,
+            max_weight: MAX_STANDARD_TX_WEIGHT as usize,
+            max_sigops_cost: MAX_STANDARD_TX_SIGOPS_COST,
+        }
+    }
+}
+
+/// A reason for a transaction not being standard.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Violation {
+    /// The transaction is heavier than [`StandardnessPolicy::max_weight`].
+    TooHeavy {
+        /// The weight of the transaction
+        weight: usize,
+        /// The maximum weight allowed
+        max: usize,
+    },
+    /// The signature operations of the transaction cost more than
+    /// [`StandardnessPolicy::max_sigops_cost`].
+    TooManySigops {
+        /// The sigop cost of the transaction
+        cost: u32,
+        /// The maximum sigop cost allowed
+        max: u32,
+    },
+    /// The `scriptSig` of an input is larger than [`MAX_STANDARD_SCRIPTSIG_SIZE`].
+    ScriptSigTooLarge {
+        /// The index of the input
+        vin: usize,
+        /// The size of the `scriptSig`
+        size: usize,
+    },
+    /// The witness script of a P2WSH input is larger than [`MAX_STANDARD_P2WSH_SCRIPT_SIZE`].
+    WitnessScriptTooLarge {
+        /// The index of the input
+        vin: usize,
+        /// The size of the witness script
+        size: usize,
+    },
+    /// An `OP_RETURN` output script is larger than [`MAX_OP_RETURN_RELAY`].
+    OpReturnTooLarge {
+        /// The index of the output
+        vout: usize,
+        /// The size of the output script
+        size: usize,
+    },
+    /// An output is worth less than it would cost to spend at
+    /// [`StandardnessPolicy::dust_relay_feerate`].
+    Dust {
+        /// The index of the output
+        vout: usize,
+        /// The value of the output
+        value: u64,
+        /// The smallest value the output could have
+        threshold: u64,
+    },
+    /// The transaction pays less than [`StandardnessPolicy::min_relay_feerate`].
+    FeerateTooLow {
+        /// The fee paid by the transaction
+        fee: i64,
+        /// The fee needed to reach the minimum relay feerate
+        min_fee: u64,
+    },
+    /// The output spent by an input is not in the graph so the checks that depend on it (sigops
+    /// of P2SH and witness inputs and the fee) could not be done.
+    UnknownPrevout {
+        /// The index of the input
+        vin: usize,
+    },
+}
+
+impl core::fmt::Display for Violation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Violation::TooHeavy { weight, max } => write!(
+                f,
+                "the transaction weighs {} wu but at most {} wu is relayed, spend fewer inputs",
+                weight, max
+            ),
+            Violation::TooManySigops { cost, max } => write!(
+                f,
+                "the transaction's signature operations cost {} but at most {} is relayed",
+                cost, max
+            ),
+            Violation::ScriptSigTooLarge { vin, size } => write!(
+                f,
+                "the scriptSig of input {} is {} bytes but at most {} bytes is relayed",
+                vin, size, MAX_STANDARD_SCRIPTSIG_SIZE
+            ),
+            Violation::WitnessScriptTooLarge { vin, size } => write!(
+                f,
+                "the witness script of input {} is {} bytes but at most {} bytes is relayed",
+                vin, size, MAX_STANDARD_P2WSH_SCRIPT_SIZE
+            ),
+            Violation::OpReturnTooLarge { vout, size } => write!(
+                f,
+                "the OP_RETURN script of output {} is {} bytes but at most {} bytes is relayed",
+                vout, size, MAX_OP_RETURN_RELAY
+            ),
+            Violation::Dust {
+                vout,
+                value,
+                threshold,
+            } => write!(
+                f,
+                "output {} is dust, it is worth {} sats but must be worth at least {} sats",
+                vout, value, threshold
+            ),
+            Violation::FeerateTooLow { fee, min_fee } => write!(
+                f,
+                "the transaction pays {} sats in fees but at least {} sats is needed to be relayed",
+                fee, min_fee
+            ),
+            Violation::UnknownPrevout { vin } => write!(
+                f,
+                "the output spent by input {} is unknown so the transaction can't be fully checked",
+                vin
+            ),
+        }
+    }
+}
+
+/// The error returned by [`validate_standardness`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct NonStandard {
+    /// Every rule the transaction breaks
+    pub violations: Vec<Violation>,
+}
+
+impl core::fmt::Display for NonStandard {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "the transaction is not standard:")?;
+        for violation in &self.violations {
+            write!(f, "\n  - {}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for NonStandard {}
+
+/// Checks whether `tx` would be relayed by nodes running with `policy`.
+///
+/// The outputs spent by `tx` are looked up in `graph`. This doesn't check the scripts themselves
+/// (e.g. whether the signatures are valid) or anything that depends on the state of the mempool.
+pub fn validate_standardness(
+    tx: &Transaction,
+    graph: &TxGraph,
+    policy: &StandardnessPolicy,
+) -> Result<(), NonStandard> {
+    let mut violations = Vec::new();
+
+    let weight = tx.weight();
+    if weight > policy.max_weight {
+        violations.push(Violation::TooHeavy {
+            weight,
+            max: policy.max_weight,
+        });
+    }
+
+    let mut prevouts_known = true;
+    for (vin, txin) in tx.input.iter().enumerate() {
+        if txin.script_sig.len() > MAX_STANDARD_SCRIPTSIG_SIZE {
+            violations.push(Violation::ScriptSigTooLarge {
+                vin,
+                size: txin.script_sig.len(),
+            });
+        }
+        match graph.get_txout(txin.previous_output) {
+            Some(prevout) => {
+                if let Some(witness_script) = p2wsh_witness_script(txin, prevout) {
+                    if witness_script.len() > MAX_STANDARD_P2WSH_SCRIPT_SIZE {
+                        violations.push(Violation::WitnessScriptTooLarge {
+                            vin,
+                            size: witness_script.len(),
+                        });
+                    }
+                }
+            }
+            None => {
+                prevouts_known = false;
+                violations.push(Violation::UnknownPrevout { vin });
+            }
+        }
+    }
+
+    for (vout, txout) in tx.output.iter().enumerate() {
+        let script = &txout.script_pubkey;
+        if script.is_op_return() {
+            if script.len() > MAX_OP_RETURN_RELAY {
+                violations.push(Violation::OpReturnTooLarge {
+                    vout,
+                    size: script.len(),
+                });
+            }
+            continue;
+        }
+        let threshold = dust_threshold(script, policy.dust_relay_feerate);
+        if txout.value < threshold {
+            violations.push(Violation::Dust {
+                vout,
+                value: txout.value,
+                threshold,
+            });
+        }
+    }
+
+    if prevouts_known {
+        let sigops_cost = sigops_cost(tx, graph);
+        if sigops_cost > policy.max_sigops_cost {
+            violations.push(Violation::TooManySigops {
+                cost: sigops_cost,
+                max: policy.max_sigops_cost,
+            });
+        }
+
+        // like bitcoind we treat transactions with a lot of sigops as if they were heavier
+        let adjusted_weight = weight.max(sigops_cost as usize * DEFAULT_BYTES_PER_SIGOP as usize);
+        let min_fee = (adjusted_weight as f32 * policy.min_relay_feerate).ceil() as u64;
+        let fee = graph
+            .calculate_fee(tx)
+            .expect("we checked that all prevouts are known");
+        if fee < min_fee as i64 {
+            violations.push(Violation::FeerateTooLow { fee, min_fee });
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(NonStandard { violations })
+    }
+}
+
+/// The smallest value an output with `script_pubkey` can have without being dust.
+///
+/// Follows bitcoind in estimating the cost of spending the output from the size of a typical input
+/// that spends it.
+pub fn dust_threshold(script_pubkey: &Script, dust_relay_feerate: f32) -> u64 {
+    if script_pubkey.is_provably_unspendable() {
+        return 0;
+    }
+    let output_size = 8 + VarInt(script_pubkey.len() as u64).len() + script_pubkey.len();
+    let spend_size = if script_pubkey.is_witness_program() {
+        32 + 4 + 1 + 107 / WITNESS_SCALE_FACTOR + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    ((output_size + spend_size) as f32 * WITNESS_SCALE_FACTOR as f32 * dust_relay_feerate) as u64
+}
+
+/// The total sigop cost of `tx` as counted by bitcoind.
+///
+/// Sigops in P2SH redeem scripts and witnesses can only be counted for inputs whose prevout is in
+/// `graph`.
+pub fn sigops_cost(tx: &Transaction, graph: &TxGraph) -> u32 {
+    let legacy = tx
+        .input
+        .iter()
+        .map(|txin| count_sigops(&txin.script_sig, false))
+        .chain(
+            tx.output
+                .iter()
+                .map(|txout| count_sigops(&txout.script_pubkey, false)),
+        )
+        .sum::<u32>();
+    let mut cost = legacy * WITNESS_SCALE_FACTOR as u32;
+    if tx.is_coin_base() {
+        return cost;
+    }
+
+    for txin in &tx.input {
+        let prevout = match graph.get_txout(txin.previous_output) {
+            Some(prevout) => prevout,
+            None => continue,
+        };
+        let mut witness_program = prevout.script_pubkey.clone();
+        if prevout.script_pubkey.is_p2sh() {
+            let redeem_script = match last_push(&txin.script_sig) {
+                Some(redeem_script) => redeem_script,
+                None => continue,
+            };
+            cost += count_sigops(&redeem_script, true) * WITNESS_SCALE_FACTOR as u32;
+            witness_program = redeem_script;
+        }
+        if witness_program.is_v0_p2wpkh() {
+            cost += 1;
+        } else if witness_program.is_v0_p2wsh() {
+            if let Some(witness_script) = txin.witness.last() {
+                cost += count_sigops(&Script::from(witness_script.to_vec()), true);
+            }
+        }
+    }
+
+    cost
+}
+
+/// Counts the signature operations in `script`.
+///
+/// When `accurate` is false every `OP_CHECKMULTISIG` counts as 20 signature operations, otherwise
+/// the number of keys pushed before it is used.
+fn count_sigops(script: &Script, accurate: bool) -> u32 {
+    let mut count = 0;
+    let mut last_opcode = None;
+    for instruction in script.instructions() {
+        let opcode = match instruction {
+            Ok(Instruction::Op(opcode)) => opcode,
+            Ok(Instruction::PushBytes(_)) => {
+                last_opcode = None;
+                continue;
+            }
+            Err(_) => break,
+        };
+        if opcode == OP_CHECKSIG || opcode == OP_CHECKSIGVERIFY {
+            count += 1;
+        } else if opcode == OP_CHECKMULTISIG || opcode == OP_CHECKMULTISIGVERIFY {
+            count += match last_opcode {
+                Some(last) if accurate && is_pushnum(last) => {
+                    (last.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as u32
+                }
+                _ => 20,
+            };
+        }
+        last_opcode = Some(opcode);
+    }
+    count
+}
+
+fn is_pushnum(opcode: opcodes::All) -> bool {
+    (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&opcode.to_u8())
+}
+
+/// The data of the last push in `script_sig` (the redeem script of a P2SH input).
+fn last_push(script_sig: &Script) -> Option<Script> {
+    match script_sig.instructions().last()? {
+        Ok(Instruction::PushBytes(bytes)) => Some(Script::from(bytes.to_vec())),
+        _ => None,
+    }
+}
+
+/// The witness script of `txin` if it spends a native or nested P2WSH `prevout`.
+fn p2wsh_witness_script<'a>(txin: &'a TxIn, prevout: &TxOut) -> Option<&'a [u8]> {
+    let is_p2wsh = prevout.script_pubkey.is_v0_p2wsh()
+        || (prevout.script_pubkey.is_p2sh()
+            && matches!(last_push(&txin.script_sig), Some(redeem_script) if redeem_script.is_v0_p2wsh()));
+    if is_p2wsh {
+        txin.witness.last()
+    } else {
+        None
+    }
+}
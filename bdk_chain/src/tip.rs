@@ -0,0 +1,220 @@
+//! Following the tip of the best chain of a chain source.
+//!
+//! A chain source that can tell us when its best chain changes implements [`TipStream`] (or
+//! [`AsyncTipStream`]) and reports each change as a [`TipEvent`]: blocks that became part of the
+//! best chain are *connected* and blocks that a reorg took out of it are *disconnected*. Whoever
+//! keeps a [`SparseChain`] up to date applies each event with [`TipEvent::changeset`] rather than
+//! working out which checkpoints to insert and invalidate itself.
+//!
+//! Sources that are only told about the latest header (like an Electrum server) can use
+//! [`RecentBlocks`] to turn each new header into events.
+use crate::{
+    collections::BTreeMap,
+    sparse_chain::{self, ChainPosition, InsertCheckpointError, SparseChain},
+    BlockId,
+};
+use alloc::{boxed::Box, vec::Vec};
+use bitcoin::BlockHash;
+use core::{future::Future, pin::Pin};
+
+/// The number of blocks [`RecentBlocks`] remembers below the tip.
+const RECENT_BLOCK_COUNT: usize = 100;
+
+/// A change to the best chain of a chain source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub enum TipEvent {
+    /// The block became part of the best chain
+    Connected(BlockId),
+    /// The block was taken out of the best chain by a reorg
+    Disconnected(BlockId),
+}
+
+impl TipEvent {
+    /// The block the event is about.
+    pub fn block(&self) -> BlockId {
+        match self {
+            TipEvent::Connected(block) | TipEvent::Disconnected(block) => *block,
+        }
+    }
+
+    /// Determines the changes that bring `chain` in line with the event.
+    ///
+    /// A connected block becomes a checkpoint. If `chain` has a different block at that height
+    /// (e.g. because the reorg happened while we weren't listening) that block and everything above
+    /// it is invalidated first. A disconnected block is invalidated along with everything above it,
+    /// which moves the transactions confirmed in those blocks back to unconfirmed. Nothing changes
+    /// when `chain` already has another block at its height.
+    ///
+    /// This fails if the event conflicts with the anchor of `chain`.
+    pub fn changeset<P: ChainPosition>(
+        &self,
+        chain: &SparseChain<P>,
+    ) -> Result<sparse_chain::ChangeSet<P>, InsertCheckpointError> {
+        match *self {
+            TipEvent::Connected(block) => match chain.checkpoint_at(block.height) {
+                Some(existing) if existing == block => Ok(Default::default()),
+                Some(_) => {
+                    if let Some(anchor) = chain.anchor().filter(|a| a.height >= block.height) {
+                        return Err(InsertCheckpointError::HashNotMatching {
+                            height: anchor.height,
+                            original_hash: anchor.hash,
+                            update_hash: block.hash,
+                        });
+                    }
+                    let mut changeset = chain.invalidate_checkpoints_preview(block.height);
+                    changeset.checkpoints.insert(block.height, Some(block.hash));
+                    Ok(changeset)
+                }
+                None => chain.insert_checkpoint_preview(block),
+            },
+            TipEvent::Disconnected(block) => match chain.checkpoint_at(block.height) {
+                Some(existing) if existing != block => Ok(Default::default()),
+                _ => {
+                    if let Some(anchor) = chain.anchor().filter(|a| a.height >= block.height) {
+                        return Err(InsertCheckpointError::HashNotMatching {
+                            height: anchor.height,
+                            original_hash: anchor.hash,
+                            update_hash: block.hash,
+                        });
+                    }
+                    Ok(chain.invalidate_checkpoints_preview(block.height))
+                }
+            },
+        }
+    }
+}
+
+/// A chain source that reports the changes to its best chain.
+pub trait TipStream {
+    /// The error returned when the source can't be reached
+    type Error: core::fmt::Debug + core::fmt::Display;
+
+    /// Returns the next change to the best chain, or `None` if nothing has changed since the last
+    /// call.
+    ///
+    /// Events come in the order they have to be applied in: when the tip is reorged out the
+    /// disconnected blocks come first (highest first) followed by the connected ones (lowest
+    /// first). The first call reports the current tip as connected.
+    fn next_event(&mut self) -> Result<Option<TipEvent>, Self::Error>;
+}
+
+/// The future returned by the methods of [`AsyncTipStream`].
+pub type TipFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The async version of [`TipStream`] for sources that are queried through an async client.
+pub trait AsyncTipStream {
+    /// The error returned when the source can't be reached
+    type Error: core::fmt::Debug + core::fmt::Display;
+
+    /// Returns the next change to the best chain, or `None` if nothing has changed since the last
+    /// call. See [`TipStream::next_event`].
+    fn next_event(&mut self) -> TipFuture<'_, Result<Option<TipEvent>, Self::Error>>;
+}
+
+/// Makes an [`AsyncTipStream`] usable where a [`TipStream`] is expected by blocking the current
+/// thread until each event has been fetched.
+///
+/// Like [`BlockingBackend`] it must be used from a thread that may block.
+///
+/// [`BlockingBackend`]: crate::keychain::BlockingBackend
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default)]
+pub struct BlockingTipStream<S>(pub S);
+
+#[cfg(feature = "std")]
+impl<S: AsyncTipStream> TipStream for BlockingTipStream<S> {
+    type Error = S::Error;
+
+    fn next_event(&mut self) -> Result<Option<TipEvent>, Self::Error> {
+        crate::blocking::block_on(self.0.next_event())
+    }
+}
+
+/// The latest blocks of a chain source's best chain as far as we have been told.
+///
+/// Each time the source announces a new tip, [`connect`] compares it with the blocks we know and
+/// returns the [`TipEvent`]s that lead from them to the new tip. The last 100 blocks are
+/// remembered so reorgs up to that depth are reported block by block.
+///
+/// [`connect`]: Self::connect
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecentBlocks {
+    blocks: BTreeMap<u32, BlockHash>,
+}
+
+impl RecentBlocks {
+    /// The latest block we know of.
+    pub fn tip(&self) -> Option<BlockId> {
+        self.blocks
+            .iter()
+            .next_back()
+            .map(|(&height, &hash)| BlockId { height, hash })
+    }
+
+    /// Moves to `tip` whose parent is `prev_blockhash` and returns the events that get there.
+    ///
+    /// If the parent isn't the block we know at its height the blocks below it are looked up with
+    /// `hash_at` (which returns the hash of the source's block at a height) until we reach a block
+    /// we know. The blocks we knew above that point are disconnected and the source's blocks are
+    /// connected in their place. Blocks below the oldest block we remember are assumed not to
+    /// have changed.
+    pub fn connect<E>(
+        &mut self,
+        tip: BlockId,
+        prev_blockhash: BlockHash,
+        mut hash_at: impl FnMut(u32) -> Result<BlockHash, E>,
+    ) -> Result<Vec<TipEvent>, E> {
+        // the source's blocks we don't know yet, from the tip down
+        let mut new_blocks = vec![tip];
+        if let Some(height) = tip.height.checked_sub(1) {
+            let mut parent = BlockId {
+                height,
+                hash: prev_blockhash,
+            };
+            while let Some(&oldest) = self.blocks.keys().next() {
+                if parent.height < oldest || self.blocks.get(&parent.height) == Some(&parent.hash) {
+                    break;
+                }
+                new_blocks.push(parent);
+                parent = match parent.height.checked_sub(1) {
+                    Some(height) => BlockId {
+                        height,
+                        hash: hash_at(height)?,
+                    },
+                    None => break,
+                };
+            }
+        }
+
+        let fork_height = new_blocks.last().expect("has the tip").height;
+        let mut events = self
+            .blocks
+            .range(fork_height..)
+            .rev()
+            .map(|(&height, &hash)| BlockId { height, hash })
+            .filter(|block| !new_blocks.contains(block))
+            .map(TipEvent::Disconnected)
+            .collect::<Vec<_>>();
+        events.extend(
+            new_blocks
+                .iter()
+                .rev()
+                .filter(|block| self.blocks.get(&block.height) != Some(&block.hash))
+                .map(|block| TipEvent::Connected(*block)),
+        );
+
+        let _ = self.blocks.split_off(&fork_height);
+        self.blocks
+            .extend(new_blocks.iter().map(|block| (block.height, block.hash)));
+        while self.blocks.len() > RECENT_BLOCK_COUNT {
+            let oldest = *self.blocks.keys().next().expect("not empty");
+            let _ = self.blocks.remove(&oldest);
+        }
+        Ok(events)
+    }
+}
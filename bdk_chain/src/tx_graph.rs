@@ -1,6 +1,6 @@
 use crate::{collections::*, ForEachTxout};
 use alloc::{borrow::Cow, vec::Vec};
-use bitcoin::{OutPoint, Transaction, TxOut, Txid};
+use bitcoin::{Block, OutPoint, Transaction, TxOut, Txid};
 
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct TxGraph {
@@ -8,6 +8,34 @@ pub struct TxGraph {
     spends: BTreeMap<OutPoint, HashSet<Txid>>,
 }
 
+/// Looks up full transactions by txid.
+///
+/// Implemented by [`TxGraph`] and by stores that keep transactions elsewhere (like
+/// `RawTxStore` with the `file_store` feature). A pair of providers tries the first one and then
+/// the second.
+pub trait TxProvider {
+    /// The full transaction with `txid` if the provider has it.
+    fn provide_tx(&self, txid: Txid) -> Option<Transaction>;
+}
+
+impl TxProvider for TxGraph {
+    fn provide_tx(&self, txid: Txid) -> Option<Transaction> {
+        self.get_tx(txid).cloned()
+    }
+}
+
+impl<T: TxProvider + ?Sized> TxProvider for &T {
+    fn provide_tx(&self, txid: Txid) -> Option<Transaction> {
+        (**self).provide_tx(txid)
+    }
+}
+
+impl<A: TxProvider, B: TxProvider> TxProvider for (A, B) {
+    fn provide_tx(&self, txid: Txid) -> Option<Transaction> {
+        self.0.provide_tx(txid).or_else(|| self.1.provide_tx(txid))
+    }
+}
+
 /// Node of a [`TxGraph`]
 #[derive(Clone, Debug, PartialEq)]
 enum TxNode {
@@ -121,6 +149,51 @@ impl TxGraph {
         additions
     }
 
+    /// Returns the resultant [`Additions`] if the transactions in `block` that are relevant to the
+    /// graph are inserted. Does not mutate `self`.
+    ///
+    /// A transaction is relevant if `is_relevant` returns true for it or if it spends an output
+    /// in the graph or one of an earlier relevant transaction in the block, so spends of coins
+    /// the graph knows about are found without scanning the block twice. Only the relevant
+    /// transactions are cloned and have their txid computed.
+    pub fn scan_block_preview(
+        &self,
+        block: &Block,
+        mut is_relevant: impl FnMut(&Transaction) -> bool,
+    ) -> Additions {
+        let mut additions = Additions::default();
+        // relevant transactions earlier in the block
+        let mut block_txids = HashSet::new();
+        for tx in &block.txdata {
+            let spends_graph = tx.input.iter().any(|txin| {
+                self.get_txout(txin.previous_output).is_some()
+                    || block_txids.contains(&txin.previous_output.txid)
+            });
+            if spends_graph || is_relevant(tx) {
+                let txid = tx.txid();
+                if self.get_tx(txid).is_none() {
+                    additions.tx.insert(tx.clone());
+                }
+                block_txids.insert(txid);
+            }
+        }
+        additions
+    }
+
+    /// Inserts the transactions in `block` that are relevant to the graph (see
+    /// [`scan_block_preview`]).
+    ///
+    /// [`scan_block_preview`]: Self::scan_block_preview
+    pub fn scan_block(
+        &mut self,
+        block: &Block,
+        is_relevant: impl FnMut(&Transaction) -> bool,
+    ) -> Additions {
+        let additions = self.scan_block_preview(block, is_relevant);
+        self.apply_additions(additions.clone());
+        additions
+    }
+
     /// Calculates the fee of a given transaction. Returns 0 if `tx` is a coinbase transaction.
     /// Returns `Some(_)` if we have all the `TxOut`s being spent by `tx` in the graph (either as
     /// the full transactions or individual txouts). If the returned value is negative then the
@@ -151,6 +224,15 @@ impl TxGraph {
         Some(inputs_sum - outputs_sum)
     }
 
+    /// Calculates the feerate of `tx` in sats per weight unit.
+    ///
+    /// Returns `None` in the same cases as [`calculate_fee`].
+    ///
+    /// [`calculate_fee`]: Self::calculate_fee
+    pub fn calculate_feerate(&self, tx: &Transaction) -> Option<f32> {
+        Some(self.calculate_fee(tx)? as f32 / tx.weight() as f32)
+    }
+
     /// Iterate over all tx outputs known by [`TxGraph`].
     pub fn all_txouts(&self) -> impl Iterator<Item = (OutPoint, &TxOut)> {
         self.txs.iter().flat_map(|(txid, tx)| match tx {
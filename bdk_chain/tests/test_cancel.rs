@@ -0,0 +1,16 @@
+use bdk_chain::cancel::CancellationToken;
+
+#[test]
+fn clones_share_the_cancellation() {
+    let token = CancellationToken::new();
+    let held_by_operation = token.clone();
+    assert!(!held_by_operation.is_cancelled());
+
+    std::thread::spawn(move || token.cancel())
+        .join()
+        .expect("thread didn't panic");
+    assert!(held_by_operation.is_cancelled());
+
+    // a separate token isn't affected
+    assert!(!CancellationToken::new().is_cancelled());
+}
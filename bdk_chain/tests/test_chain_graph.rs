@@ -2,7 +2,10 @@
 mod common;
 
 use bdk_chain::{
-    chain_graph::{ChainGraph, ChangeSet, InflateError, UnresolvableConflict, UpdateError},
+    chain_graph::{
+        ChainGraph, ChangeSet, ConflictCluster, EvictTxError, InflateError, UnresolvableConflict,
+        UpdateError,
+    },
     collections::HashSet,
     sparse_chain,
     tx_graph::{self, Additions},
@@ -143,6 +146,11 @@ fn update_evicts_conflicting_tx() {
             Ok(changeset.clone()),
             "tx should be evicted from mempool"
         );
+        assert_eq!(
+            cg1.evictions(&changeset),
+            [(tx_b.txid(), [tx_b2.txid()].into())].into(),
+            "tx_b2 replaces tx_b"
+        );
 
         cg1.apply_changeset(changeset);
     }
@@ -455,3 +463,403 @@ fn test_apply_changes_reintroduce_tx() {
         }
     );
 }
+
+#[test]
+fn compact_conflicts_keeps_highest_feerate_tx() {
+    let tx0 = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let spend_tx0 = |value| Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx0.txid(), 0),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let tx1 = spend_tx0(9_000);
+    let tx2 = spend_tx0(8_000);
+    let tx3 = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx1.txid(), 0),
+            ..Default::default()
+        }],
+        output: vec![],
+    };
+
+    // a single update can introduce conflicting unconfirmed txs into the chain
+    let mut cg = ChainGraph::default();
+    cg.apply_changeset(ChangeSet {
+        chain: changeset! {
+            checkpoints: [],
+            txids: [
+                (tx0.txid(), Some(TxHeight::Unconfirmed)),
+                (tx1.txid(), Some(TxHeight::Unconfirmed)),
+                (tx2.txid(), Some(TxHeight::Unconfirmed)),
+                (tx3.txid(), Some(TxHeight::Unconfirmed))
+            ]
+        },
+        graph: Additions {
+            tx: [tx0.clone(), tx1.clone(), tx2.clone(), tx3.clone()].into(),
+            ..Default::default()
+        },
+    });
+
+    assert_eq!(
+        cg.unconfirmed_conflict_clusters(),
+        vec![ConflictCluster {
+            canonical: tx2.txid(),
+            evicted: [tx1.txid(), tx3.txid()].into(),
+        }]
+    );
+    assert_eq!(
+        cg.compact_conflicts(),
+        ChangeSet {
+            chain: changeset! {
+                checkpoints: [],
+                txids: [(tx1.txid(), None), (tx3.txid(), None)]
+            },
+            ..Default::default()
+        }
+    );
+    assert!(cg.unconfirmed_conflict_clusters().is_empty());
+    assert!(cg.compact_conflicts_preview().is_empty());
+}
+
+#[test]
+fn compact_conflicts_keeps_txs_that_only_conflict_with_evicted_txs() {
+    let tx0 = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![
+            TxOut {
+                value: 10_000,
+                script_pubkey: Script::new(),
+            },
+            TxOut {
+                value: 10_000,
+                script_pubkey: Script::new(),
+            },
+        ],
+    };
+    let spend = |vouts: &[u32], value| Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vouts
+            .iter()
+            .map(|&vout| TxIn {
+                previous_output: OutPoint::new(tx0.txid(), vout),
+                ..Default::default()
+            })
+            .collect(),
+        output: vec![TxOut {
+            value,
+            script_pubkey: Script::new(),
+        }],
+    };
+    // a - b - c: b conflicts with a and c, which don't conflict with each other
+    let tx_a = spend(&[0], 5_000);
+    let tx_b = spend(&[0, 1], 19_000);
+    let tx_c = spend(&[1], 9_000);
+    // a child of b goes along with it
+    let tx_d = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx_b.txid(), 0),
+            ..Default::default()
+        }],
+        output: vec![],
+    };
+
+    let mut cg = ChainGraph::default();
+    cg.apply_changeset(ChangeSet {
+        chain: changeset! {
+            checkpoints: [],
+            txids: [
+                (tx0.txid(), Some(TxHeight::Unconfirmed)),
+                (tx_a.txid(), Some(TxHeight::Unconfirmed)),
+                (tx_b.txid(), Some(TxHeight::Unconfirmed)),
+                (tx_c.txid(), Some(TxHeight::Unconfirmed)),
+                (tx_d.txid(), Some(TxHeight::Unconfirmed))
+            ]
+        },
+        graph: Additions {
+            tx: [
+                tx0.clone(),
+                tx_a.clone(),
+                tx_b.clone(),
+                tx_c.clone(),
+                tx_d.clone(),
+            ]
+            .into(),
+            ..Default::default()
+        },
+    });
+
+    // a has the highest feerate so b is evicted, after which c conflicts with nothing
+    assert_eq!(
+        cg.unconfirmed_conflict_clusters(),
+        vec![ConflictCluster {
+            canonical: tx_a.txid(),
+            evicted: [tx_b.txid(), tx_d.txid()].into(),
+        }]
+    );
+    let _ = cg.compact_conflicts();
+    assert!(cg.chain().tx_position(tx_c.txid()).is_some());
+    assert!(cg.chain().tx_position(tx_b.txid()).is_none());
+    assert!(cg.unconfirmed_conflict_clusters().is_empty());
+}
+
+#[test]
+fn evict_txs_takes_descendants_along() {
+    let tx_with_input = |previous_output: Option<OutPoint>, value| Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: previous_output
+            .into_iter()
+            .map(|previous_output| TxIn {
+                previous_output,
+                ..Default::default()
+            })
+            .collect(),
+        output: vec![TxOut {
+            value,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let tx0 = tx_with_input(None, 10_000);
+    let tx1 = tx_with_input(Some(OutPoint::new(tx0.txid(), 0)), 9_000);
+    let tx2 = tx_with_input(Some(OutPoint::new(tx1.txid(), 0)), 8_000);
+
+    let mut cg = ChainGraph::default();
+    let _ = cg
+        .insert_checkpoint(BlockId {
+            height: 1,
+            hash: h!("A"),
+        })
+        .expect("should insert");
+    let _ = cg
+        .insert_tx(tx0.clone(), TxHeight::Confirmed(1))
+        .expect("should insert");
+    for tx in [&tx1, &tx2] {
+        let _ = cg
+            .insert_tx(tx.clone(), TxHeight::Unconfirmed)
+            .expect("should insert");
+    }
+
+    assert_eq!(
+        cg.evict_txs_preview([tx0.txid()]),
+        Err(EvictTxError {
+            txid: tx0.txid(),
+            position: TxHeight::Confirmed(1),
+        })
+    );
+    let changeset = cg.evict_txs_preview([tx1.txid()]).expect("should evict");
+    assert_eq!(
+        cg.evictions(&changeset),
+        [(tx1.txid(), [].into()), (tx2.txid(), [].into())].into(),
+        "nothing replaces them"
+    );
+    assert_eq!(
+        cg.evict_txs([tx1.txid()]),
+        Ok(ChangeSet {
+            chain: changeset! {
+                checkpoints: [],
+                txids: [(tx1.txid(), None), (tx2.txid(), None)]
+            },
+            ..Default::default()
+        })
+    );
+    assert_eq!(cg.spent_by(OutPoint::new(tx0.txid(), 0)), None);
+    assert!(
+        cg.graph().get_tx(tx1.txid()).is_some(),
+        "the graph keeps the tx"
+    );
+    // evicting again does nothing
+    assert!(cg
+        .evict_txs_preview([tx1.txid()])
+        .expect("nothing to evict")
+        .is_empty());
+}
+
+#[test]
+fn tx_lookup_helpers() {
+    let tx0 = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![
+            TxOut {
+                value: 10_000,
+                script_pubkey: Script::new(),
+            },
+            TxOut {
+                value: 20_000,
+                script_pubkey: Script::new(),
+            },
+        ],
+    };
+    let spend_tx0 = |vout, value| Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx0.txid(), vout),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let tx1 = spend_tx0(1, 19_000);
+    let tx2 = spend_tx0(1, 18_000);
+
+    let mut cg = ChainGraph::default();
+    let _ = cg
+        .insert_checkpoint(BlockId {
+            height: 3,
+            hash: h!("block 3"),
+        })
+        .unwrap();
+    let _ = cg.insert_tx(tx0.clone(), TxHeight::Confirmed(2)).unwrap();
+    let _ = cg.insert_tx(tx1.clone(), TxHeight::Unconfirmed).unwrap();
+    // tx2 is only known to the graph
+    cg.apply_changeset(ChangeSet {
+        graph: Additions {
+            tx: [tx2.clone()].into(),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+
+    assert_eq!(cg.confirmations(tx0.txid()), Some(2));
+    assert_eq!(cg.confirmations(tx1.txid()), Some(0));
+    assert_eq!(cg.confirmations(tx2.txid()), None);
+
+    assert_eq!(
+        cg.conflicts_of(tx1.txid()),
+        [(tx2.txid(), None)].into_iter().collect()
+    );
+    assert_eq!(
+        cg.conflicts_of(tx2.txid()),
+        [(tx1.txid(), Some(&TxHeight::Unconfirmed))]
+            .into_iter()
+            .collect()
+    );
+    assert!(cg.conflicts_of(tx0.txid()).is_empty());
+
+    assert_eq!(
+        cg.outspends_in_chain(tx0.txid()),
+        [(1, (&TxHeight::Unconfirmed, tx1.txid()))]
+            .into_iter()
+            .collect()
+    );
+}
+
+#[test]
+fn ancestor_feerate_includes_unconfirmed_parents() {
+    let spend = |previous_output, value| Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output,
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let tx0 = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut {
+            value: 100_000,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let tx1 = spend(OutPoint::new(tx0.txid(), 0), 99_000);
+    let tx2 = spend(OutPoint::new(tx1.txid(), 0), 90_000);
+    let tx3 = spend(OutPoint::new(h!("unknown"), 0), 1_000);
+
+    let mut cg = ChainGraph::default();
+    let _ = cg
+        .insert_checkpoint(BlockId {
+            height: 1,
+            hash: h!("block 1"),
+        })
+        .unwrap();
+    let _ = cg.insert_tx(tx0.clone(), TxHeight::Confirmed(1)).unwrap();
+    let _ = cg.insert_tx(tx1.clone(), TxHeight::Unconfirmed).unwrap();
+    let _ = cg.insert_tx(tx2.clone(), TxHeight::Unconfirmed).unwrap();
+    let _ = cg.insert_tx(tx3.clone(), TxHeight::Unconfirmed).unwrap();
+
+    // confirmed transactions are not ancestors that still need to be mined
+    assert!(cg.unconfirmed_ancestors(tx1.txid()).is_empty());
+    assert_eq!(
+        cg.unconfirmed_ancestors(tx2.txid()),
+        [tx1.txid()].into_iter().collect()
+    );
+
+    assert_eq!(
+        cg.graph().calculate_feerate(&tx2),
+        Some(9_000.0 / tx2.weight() as f32)
+    );
+    assert_eq!(
+        cg.ancestor_feerate(tx1.txid()),
+        cg.graph().calculate_feerate(&tx1)
+    );
+    assert_eq!(
+        cg.ancestor_feerate(tx2.txid()),
+        Some(10_000.0 / (tx1.weight() + tx2.weight()) as f32)
+    );
+    assert_eq!(cg.ancestor_feerate(tx3.txid()), None);
+}
+
+#[test]
+fn full_txout_age_and_coin_days() {
+    let tx = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut {
+            value: 100_000_000,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let op = OutPoint::new(tx.txid(), 0);
+    let mut cg = ChainGraph::default();
+    let _ = cg
+        .insert_checkpoint(BlockId {
+            height: 10,
+            hash: h!("block"),
+        })
+        .unwrap();
+    let _ = cg.insert_tx(tx.clone(), TxHeight::Unconfirmed).unwrap();
+    let unconfirmed = cg.full_txout(op).unwrap();
+    assert_eq!(unconfirmed.age(10), 0);
+    assert_eq!(unconfirmed.coin_days_destroyed(10), 0.0);
+
+    let _ = cg.insert_tx(tx, TxHeight::Confirmed(10)).unwrap();
+    let confirmed = cg.full_txout(op).unwrap();
+    assert_eq!(confirmed.age(9), 0);
+    assert_eq!(confirmed.age(10), 1);
+    // one BTC confirmed for a day's worth of blocks
+    assert_eq!(confirmed.age(153), 144);
+    assert_eq!(confirmed.coin_days_destroyed(153), 1.0);
+}
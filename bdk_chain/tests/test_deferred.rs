@@ -0,0 +1,87 @@
+#[macro_use]
+mod common;
+
+use bdk_chain::{
+    deferred::{BroadcastAfter, DeferredQueue},
+    TxHeight,
+};
+use bitcoin::{OutPoint, PackedLockTime, Transaction, TxIn, Txid};
+
+fn spending(txid: Txid) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(txid, 0),
+            ..Default::default()
+        }],
+        output: vec![],
+    }
+}
+
+fn due(queue: &DeferredQueue, chain: &bdk_chain::sparse_chain::SparseChain) -> Vec<Txid> {
+    queue
+        .due(chain)
+        .map(|deferred| deferred.tx.txid())
+        .collect()
+}
+
+#[test]
+fn due_once_next_block_can_include_it() {
+    let by_height = spending(h!("a"));
+    let by_confirmations = spending(h!("b"));
+    let mut queue = DeferredQueue::default();
+    queue.insert(by_height.clone(), BroadcastAfter::Height(3));
+    queue.insert(
+        by_confirmations.clone(),
+        BroadcastAfter::Confirmations {
+            txid: h!("b"),
+            confirmations: 2,
+        },
+    );
+
+    assert!(
+        due(&queue, &chain!()).is_empty(),
+        "nothing is due without a tip"
+    );
+
+    let chain = chain!(checkpoints: [[1, h!("A")]], txids: [(h!("b"), TxHeight::Unconfirmed)]);
+    assert!(due(&queue, &chain).is_empty());
+    assert_eq!(
+        BroadcastAfter::Confirmations {
+            txid: h!("b"),
+            confirmations: 2
+        }
+        .height(&chain),
+        None,
+        "the parent isn't confirmed"
+    );
+
+    // the parent confirms at 2 so the child can be mined at 4
+    let chain = chain!(checkpoints: [[2, h!("B")]], txids: [(h!("b"), TxHeight::Confirmed(2))]);
+    assert_eq!(due(&queue, &chain), vec![by_height.txid()]);
+
+    let chain = chain!(checkpoints: [[3, h!("C")]], txids: [(h!("b"), TxHeight::Confirmed(2))]);
+    let mut expected = vec![by_height.txid(), by_confirmations.txid()];
+    expected.sort();
+    assert_eq!(due(&queue, &chain), expected);
+}
+
+#[test]
+fn broadcast_transactions_leave_the_queue_once_confirmed() {
+    let tx = spending(h!("a"));
+    let mut queue = DeferredQueue::default();
+    queue.insert(tx.clone(), BroadcastAfter::Height(1));
+
+    // seen in the mempool: no longer due but kept in case it gets evicted
+    let chain = chain!(checkpoints: [[1, h!("A")]], txids: [(tx.txid(), TxHeight::Unconfirmed)]);
+    assert!(due(&queue, &chain).is_empty());
+    assert!(queue.remove_confirmed(&chain).is_empty());
+    assert_eq!(queue.len(), 1);
+
+    let chain = chain!(checkpoints: [[2, h!("B")]], txids: [(tx.txid(), TxHeight::Confirmed(2))]);
+    let removed = queue.remove_confirmed(&chain);
+    assert_eq!(removed.len(), 1);
+    assert_eq!(removed[0].tx, tx);
+    assert!(queue.is_empty());
+}
@@ -0,0 +1,892 @@
+#![cfg(feature = "file_store")]
+#[macro_use]
+mod common;
+
+use bdk_chain::{
+    chain_graph::ChainGraph,
+    file_store::{
+        BackupError, CompactError, FileError, IterError, KeychainStore, MigrateError, RawTxStore,
+        Recovery,
+    },
+    keychain::KeychainChangeSet,
+    tx_graph::TxProvider,
+    ConfirmationTime, TxHeight,
+};
+use bitcoin::{hashes::Hash, PackedLockTime, Transaction, TxOut, Txid};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "bdk_chain_test_file_store_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+
+    /// Where a store at the path is rewritten before it is renamed over it.
+    fn tmp(&self) -> PathBuf {
+        PathBuf::from(format!("{}.tmp", self.0.display()))
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn changeset(txids: &[(Txid, TxHeight)]) -> KeychainChangeSet<String, TxHeight> {
+    let mut changeset = KeychainChangeSet::default();
+    changeset
+        .chain_graph
+        .chain
+        .checkpoints
+        .insert(2, Some(h!("block 2")));
+    for (txid, height) in txids {
+        changeset
+            .chain_graph
+            .chain
+            .txids
+            .insert(*txid, Some(*height));
+    }
+    changeset.derivation_indices.insert("a".into(), 3);
+    changeset
+}
+
+#[test]
+fn store_rejects_other_position_schema() {
+    let path = TempPath::new("schema");
+    {
+        let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+        store
+            .append_changeset(&changeset(&[(h!("tx"), TxHeight::Confirmed(2))]))
+            .unwrap();
+    }
+
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    let (aggregate, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    assert_eq!(aggregate.derivation_indices.get("a"), Some(&3));
+
+    assert!(matches!(
+        KeychainStore::<String, ConfirmationTime>::new_from_path(&path.0),
+        Err(FileError::SchemaMismatch {
+            expected: 2,
+            found: 1
+        })
+    ));
+}
+
+#[test]
+fn store_reads_legacy_file_without_header() {
+    let path = TempPath::new("legacy");
+    let legacy_changeset = changeset(&[(h!("tx"), TxHeight::Unconfirmed)]);
+    {
+        let mut file = File::create(&path.0).unwrap();
+        bincode::encode_into_std_write(
+            bincode::serde::Compat(&legacy_changeset),
+            &mut file,
+            bincode::config::standard(),
+        )
+        .unwrap();
+        file.flush().unwrap();
+    }
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path.0)
+        .unwrap();
+    let mut store = KeychainStore::<String, TxHeight>::new(file).unwrap();
+    let (aggregate, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    assert_eq!(
+        aggregate.chain_graph.chain.txids,
+        legacy_changeset.chain_graph.chain.txids
+    );
+}
+
+#[test]
+fn store_rejects_unknown_format() {
+    let path = TempPath::new("unknown_format");
+    drop(KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap());
+    let header = std::fs::read(&path.0).unwrap();
+    let reopen = |version: u8, flags: u8| {
+        let mut changed = header.clone();
+        changed[4] = version;
+        changed[6] = flags;
+        std::fs::write(&path.0, changed).unwrap();
+        KeychainStore::<String, TxHeight>::new_from_path(&path.0)
+    };
+
+    assert!(reopen(header[4], header[6]).is_ok());
+    assert!(matches!(reopen(3, 0), Err(FileError::UnknownVersion(3))));
+    assert!(matches!(
+        reopen(header[4], 0x80),
+        Err(FileError::UnknownFlags(0x80))
+    ));
+    // the flag of encrypted stores
+    assert!(matches!(reopen(header[4], 1), Err(FileError::Encrypted)));
+}
+
+#[test]
+fn migrate_to_confirmation_time() {
+    let path = TempPath::new("migrate");
+    {
+        let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+        store
+            .append_changeset(&changeset(&[
+                (h!("tx1"), TxHeight::Confirmed(1)),
+                (h!("tx2"), TxHeight::Confirmed(2)),
+                (h!("tx3"), TxHeight::Confirmed(2)),
+                (h!("tx4"), TxHeight::Unconfirmed),
+            ]))
+            .unwrap();
+    }
+
+    let store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    let mut requested = vec![];
+    store
+        .migrate_to_confirmation_time(|height| {
+            requested.push(height);
+            Ok::<_, ()>(height as u64 * 600)
+        })
+        .unwrap();
+    // block times are only requested once per height
+    requested.sort();
+    assert_eq!(requested, vec![1, 2]);
+
+    assert!(KeychainStore::<String, TxHeight>::new_from_path(&path.0).is_err());
+    let mut store = KeychainStore::<String, ConfirmationTime>::new_from_path(&path.0).unwrap();
+    let (aggregate, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    assert_eq!(aggregate.derivation_indices.get("a"), Some(&3));
+    let txids = &aggregate.chain_graph.chain.txids;
+    assert_eq!(
+        txids.get::<Txid>(&h!("tx3")),
+        Some(&Some(ConfirmationTime::Confirmed {
+            height: 2,
+            time: 1200
+        }))
+    );
+    assert_eq!(
+        txids.get::<Txid>(&h!("tx4")),
+        Some(&Some(ConfirmationTime::Unconfirmed))
+    );
+}
+
+#[test]
+fn failed_migration_leaves_the_store_alone() {
+    let path = TempPath::new("migrate_fails");
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    store
+        .append_changeset(&changeset(&[(h!("tx"), TxHeight::Confirmed(2))]))
+        .unwrap();
+    let contents = std::fs::read(&path.0).unwrap();
+
+    std::fs::create_dir(path.tmp()).unwrap();
+    let result = store.migrate_to_confirmation_time(|height| Ok::<_, ()>(height as u64 * 600));
+    std::fs::remove_dir(path.tmp()).unwrap();
+    assert!(matches!(result, Err(MigrateError::Io(_))));
+    assert_eq!(std::fs::read(&path.0).unwrap(), contents);
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    let (aggregate, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    assert_eq!(aggregate.chain_graph.chain.txids.len(), 1);
+}
+
+#[test]
+fn extensions_are_stored_alongside_changesets() {
+    let path = TempPath::new("extensions");
+    {
+        let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+        store
+            .append_changeset(&changeset(&[(h!("tx1"), TxHeight::Confirmed(2))]))
+            .unwrap();
+        store.append_extension("cursor", &[1]).unwrap();
+        store
+            .append_changeset(&changeset(&[(h!("tx2"), TxHeight::Unconfirmed)]))
+            .unwrap();
+        store.append_extension("other", &[]).unwrap();
+        store.append_extension("cursor", &[2, 3]).unwrap();
+    }
+
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    let (aggregate, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    assert_eq!(aggregate.chain_graph.chain.txids.len(), 2);
+    let (extensions, result) = store.aggregate_extensions();
+    assert!(result.is_ok());
+    assert_eq!(extensions.get("cursor"), Some(&vec![2, 3]));
+    assert_eq!(extensions.get("other"), Some(&vec![]));
+
+    // migrating keeps the latest version of each extension
+    let mut store = store
+        .migrate_to_confirmation_time(|height| Ok::<_, ()>(height as u64 * 600))
+        .unwrap();
+    let (extensions, result) = store.aggregate_extensions();
+    assert!(result.is_ok());
+    assert_eq!(extensions.len(), 2);
+    assert_eq!(extensions.get("cursor"), Some(&vec![2, 3]));
+}
+
+#[test]
+fn legacy_store_must_be_migrated_before_adding_extensions() {
+    let path = TempPath::new("legacy_extensions");
+    {
+        let mut file = File::create(&path.0).unwrap();
+        bincode::encode_into_std_write(
+            bincode::serde::Compat(&changeset(&[(h!("tx"), TxHeight::Unconfirmed)])),
+            &mut file,
+            bincode::config::standard(),
+        )
+        .unwrap();
+    }
+
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    assert_eq!(
+        store.append_extension("cursor", &[1]).unwrap_err().kind(),
+        std::io::ErrorKind::Unsupported
+    );
+
+    let mut store = store.migrate(Ok::<_, ()>).unwrap();
+    store.append_extension("cursor", &[1]).unwrap();
+    let (aggregate, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    assert_eq!(aggregate.chain_graph.chain.txids.len(), 1);
+    assert_eq!(store.aggregate_extensions().0.get("cursor"), Some(&vec![1]));
+}
+
+#[test]
+fn compact_folds_entries_into_snapshot() {
+    let path = TempPath::new("compact");
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    for i in 0..10 {
+        let txid = Txid::hash(&[i]);
+        store
+            .append_changeset(&changeset(&[(txid, TxHeight::Confirmed(2))]))
+            .unwrap();
+        store.append_extension("cursor", &[i]).unwrap();
+    }
+    let (before, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    let len_before = std::fs::metadata(&path.0).unwrap().len();
+
+    store.compact().unwrap();
+    assert!(std::fs::metadata(&path.0).unwrap().len() < len_before);
+    // appending after compaction still works
+    store
+        .append_changeset(&changeset(&[(h!("tx10"), TxHeight::Unconfirmed)]))
+        .unwrap();
+    drop(store);
+
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    assert_eq!(store.iter_entries().unwrap().count(), 3);
+    let (after, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    assert_eq!(after.chain_graph.chain.txids.len(), 11);
+    for (txid, position) in &before.chain_graph.chain.txids {
+        assert_eq!(after.chain_graph.chain.txids.get(txid), Some(position));
+    }
+    assert_eq!(store.aggregate_extensions().0.get("cursor"), Some(&vec![9]));
+}
+
+#[test]
+fn auto_compact_past_max_entries() {
+    let path = TempPath::new("auto_compact");
+    {
+        let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+        for i in 0..5 {
+            store.append_extension("cursor", &[i]).unwrap();
+        }
+    }
+
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    store.set_auto_compact(Some(6));
+    let (_, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+
+    store.append_extension("cursor", &[5]).unwrap();
+    assert_eq!(store.iter_entries().unwrap().count(), 6);
+    store.append_extension("cursor", &[6]).unwrap();
+    assert_eq!(store.iter_entries().unwrap().count(), 1);
+    assert_eq!(store.aggregate_extensions().0.get("cursor"), Some(&vec![6]));
+}
+
+#[test]
+fn auto_compact_only_counts_entries_since_the_snapshot() {
+    let path = TempPath::new("auto_compact_snapshot");
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    store.set_auto_compact(Some(2));
+    for name in ["a", "b", "c"] {
+        store.append_extension(name, &[1]).unwrap();
+    }
+    // the third append compacted the store into a snapshot that is already past the maximum
+    assert_eq!(store.iter_entries().unwrap().count(), 3);
+    let (_, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+
+    store.append_extension("a", &[2]).unwrap();
+    assert_eq!(store.iter_entries().unwrap().count(), 4);
+    let (_, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    store.append_extension("b", &[2]).unwrap();
+    store.append_extension("c", &[2]).unwrap();
+    assert_eq!(store.iter_entries().unwrap().count(), 3);
+    assert_eq!(store.aggregate_extensions().0.get("c"), Some(&vec![2]));
+}
+
+#[test]
+fn failed_compaction_leaves_the_store_alone() {
+    let path = TempPath::new("compact_fails");
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    for i in 0..3 {
+        store.append_extension("cursor", &[i]).unwrap();
+    }
+    let contents = std::fs::read(&path.0).unwrap();
+
+    // the snapshot can't be written where it goes before it is renamed over the store
+    std::fs::create_dir(path.tmp()).unwrap();
+    let result = store.compact();
+    std::fs::remove_dir(path.tmp()).unwrap();
+    assert!(result.is_err());
+    assert_eq!(std::fs::read(&path.0).unwrap(), contents);
+
+    store.append_extension("cursor", &[3]).unwrap();
+    assert_eq!(store.iter_entries().unwrap().count(), 4);
+    store.compact().unwrap();
+    assert!(!path.tmp().exists());
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    assert_eq!(store.iter_entries().unwrap().count(), 1);
+    assert_eq!(store.aggregate_extensions().0.get("cursor"), Some(&vec![3]));
+
+    // without a path there is nowhere to write the snapshot
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path.0)
+        .unwrap();
+    let mut store = KeychainStore::<String, TxHeight>::new(file).unwrap();
+    assert!(matches!(
+        store.compact(),
+        Err(CompactError::Io(e)) if e.kind() == std::io::ErrorKind::Unsupported
+    ));
+}
+
+#[test]
+fn wallets_share_a_store() {
+    let path = TempPath::new("wallets");
+    {
+        let store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+        let mut store = store.open_wallet("savings");
+        store
+            .append_changeset(&changeset(&[(h!("savings tx"), TxHeight::Confirmed(2))]))
+            .unwrap();
+        store.append_extension("cursor", &[1]).unwrap();
+
+        let mut store = store.open_default_wallet();
+        store
+            .append_changeset(&changeset(&[(h!("tx"), TxHeight::Unconfirmed)]))
+            .unwrap();
+        store.append_extension("cursor", &[2]).unwrap();
+    }
+
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    let (wallets, result) = store.wallets();
+    assert!(result.is_ok());
+    assert_eq!(wallets.into_iter().collect::<Vec<_>>(), vec!["savings"]);
+
+    let check = |store: &mut KeychainStore<String, TxHeight>| {
+        let txids = |store: &mut KeychainStore<String, TxHeight>| {
+            let (changeset, result) = store.aggregate_changeset();
+            assert!(result.is_ok());
+            changeset
+                .chain_graph
+                .chain
+                .txids
+                .into_keys()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(store.wallet(), None);
+        assert_eq!(txids(store), vec![h!("tx")]);
+        assert_eq!(store.aggregate_extensions().0.get("cursor"), Some(&vec![2]));
+    };
+    check(&mut store);
+
+    let mut store = store.open_wallet("savings");
+    let (changeset, _) = store.aggregate_changeset();
+    assert_eq!(
+        changeset
+            .chain_graph
+            .chain
+            .txids
+            .into_keys()
+            .collect::<Vec<_>>(),
+        vec![h!("savings tx")]
+    );
+    assert_eq!(store.aggregate_extensions().0.get("cursor"), Some(&vec![1]));
+
+    // compacting keeps the entries of every wallet
+    store.compact().unwrap();
+    assert_eq!(store.iter_entries().unwrap().count(), 4);
+    let mut store = store.open_default_wallet();
+    check(&mut store);
+}
+
+#[test]
+fn recover_truncated_tail() {
+    let path = TempPath::new("recover");
+    let backup = TempPath::new("recover_backup");
+    {
+        let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+        store
+            .append_changeset(&changeset(&[(h!("tx1"), TxHeight::Confirmed(2))]))
+            .unwrap();
+        store.append_extension("cursor", &[1]).unwrap();
+        store
+            .append_changeset(&changeset(&[(h!("tx2"), TxHeight::Unconfirmed)]))
+            .unwrap();
+    }
+    // simulate a crash in the middle of writing the last entry
+    let len = std::fs::metadata(&path.0).unwrap().len();
+    OpenOptions::new()
+        .write(true)
+        .open(&path.0)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    let (_, result) = store.aggregate_changeset();
+    assert!(matches!(result, Err(IterError::Truncated { entry: 2, .. })));
+
+    let recovery = store.recover(&backup.0).unwrap();
+    let truncated = len - 3 - std::fs::metadata(&path.0).unwrap().len();
+    assert_eq!(
+        recovery,
+        Recovery {
+            recovered: 2,
+            truncated
+        }
+    );
+    assert_eq!(std::fs::metadata(&backup.0).unwrap().len(), len - 3);
+    // recovering an intact store does nothing
+    assert!(store.recover(&backup.0).is_ok());
+
+    store
+        .append_changeset(&changeset(&[(h!("tx3"), TxHeight::Unconfirmed)]))
+        .unwrap();
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    let (aggregate, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    assert_eq!(aggregate.chain_graph.chain.txids.len(), 2);
+    assert_eq!(store.aggregate_extensions().0.get("cursor"), Some(&vec![1]));
+}
+
+#[test]
+fn corrupted_entry_is_told_apart_from_truncated_tail() {
+    let path = TempPath::new("corrupted");
+    let offset = {
+        let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+        store
+            .append_changeset(&changeset(&[(h!("tx1"), TxHeight::Confirmed(2))]))
+            .unwrap();
+        let offset = std::fs::metadata(&path.0).unwrap().len();
+        store.append_extension("cursor", &[1, 2, 3, 4]).unwrap();
+        store
+            .append_changeset(&changeset(&[(h!("tx2"), TxHeight::Unconfirmed)]))
+            .unwrap();
+        offset
+    };
+    // flip a bit in the extension blob's entry, past the length and checksums in front of it
+    let mut contents = std::fs::read(&path.0).unwrap();
+    contents[offset as usize + 14] ^= 1;
+    std::fs::write(&path.0, &contents).unwrap();
+
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    let (aggregate, result) = store.aggregate_changeset();
+    assert!(matches!(
+        result,
+        Err(IterError::Corrupted { entry: 1, offset: o }) if o == offset
+    ));
+    // the entries before the damaged one are still read
+    assert_eq!(aggregate.chain_graph.chain.txids.len(), 1);
+
+    // damaging the length of an entry is noticed before it's used to find the next entry
+    contents[offset as usize] ^= 0x80;
+    std::fs::write(&path.0, &contents).unwrap();
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    assert!(matches!(
+        store.aggregate_changeset().1,
+        Err(IterError::Corrupted { entry: 1, .. })
+    ));
+}
+
+#[test]
+fn backup_is_an_exact_copy_of_an_intact_store() {
+    let path = TempPath::new("backup_source");
+    let backup = TempPath::new("backup");
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    store
+        .append_changeset(&changeset(&[(h!("tx1"), TxHeight::Confirmed(2))]))
+        .unwrap();
+    store.append_extension("cursor", &[1]).unwrap();
+
+    assert_eq!(store.backup(&backup.0).unwrap(), 2);
+    assert_eq!(
+        std::fs::read(&backup.0).unwrap(),
+        std::fs::read(&path.0).unwrap()
+    );
+    // an existing backup isn't overwritten
+    assert!(matches!(store.backup(&backup.0), Err(BackupError::Io(_))));
+
+    // the store can still be appended to after backing it up
+    store
+        .append_changeset(&changeset(&[(h!("tx2"), TxHeight::Unconfirmed)]))
+        .unwrap();
+    let mut copy = KeychainStore::<String, TxHeight>::new_from_path(&backup.0).unwrap();
+    assert_eq!(
+        copy.aggregate_changeset().0.chain_graph.chain.txids.len(),
+        1
+    );
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    assert_eq!(
+        store.aggregate_changeset().0.chain_graph.chain.txids.len(),
+        2
+    );
+
+    // a damaged store isn't backed up
+    let len = std::fs::metadata(&path.0).unwrap().len();
+    OpenOptions::new()
+        .write(true)
+        .open(&path.0)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+    std::fs::remove_file(&backup.0).unwrap();
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    assert!(matches!(
+        store.backup(&backup.0),
+        Err(BackupError::Iter(IterError::Truncated { entry: 2, .. }))
+    ));
+    assert!(!backup.0.exists());
+}
+
+#[test]
+fn hash_chained_store_is_tamper_evident() {
+    let path = TempPath::new("chained");
+    let forged = TempPath::new("chained_forged");
+    // writes the same entries to both stores except for the extension blob
+    let write = |path: &PathBuf, cursor: u8| {
+        let mut store = KeychainStore::<String, TxHeight>::new_from_path(path).unwrap();
+        store
+            .append_changeset(&changeset(&[(h!("tx1"), TxHeight::Confirmed(2))]))
+            .unwrap();
+        assert_eq!(store.head_hash().unwrap(), None);
+        store.set_hash_chain(true).unwrap();
+        assert!(store.is_hash_chained());
+        let snapshot_head = store.head_hash().unwrap().unwrap();
+        store.append_extension("cursor", &[cursor]).unwrap();
+        let cursor_end = std::fs::metadata(path).unwrap().len() as usize;
+        store
+            .append_changeset(&changeset(&[(h!("tx2"), TxHeight::Unconfirmed)]))
+            .unwrap();
+        (
+            snapshot_head,
+            store.head_hash().unwrap().unwrap(),
+            cursor_end,
+        )
+    };
+    let (snapshot_head, head, cursor_end) = write(&path.0, 1);
+
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    assert!(store.is_hash_chained());
+    let (aggregate, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    assert_eq!(aggregate.chain_graph.chain.txids.len(), 2);
+    assert_eq!(store.head_hash().unwrap(), Some(head));
+    // appending extends the chain
+    store.append_extension("cursor", &[2]).unwrap();
+    assert_ne!(store.head_hash().unwrap(), Some(head));
+    assert_eq!(store.find_head(snapshot_head).unwrap(), Some(1));
+    assert_eq!(store.find_head(head).unwrap(), Some(3));
+    assert_eq!(store.aggregate_extensions().0.get("cursor"), Some(&vec![2]));
+
+    // a store with the hashes recomputed after changing an entry doesn't lead to the head
+    let (forged_snapshot_head, forged_head, _) = write(&forged.0, 9);
+    assert_eq!(forged_snapshot_head, snapshot_head);
+    assert_ne!(forged_head, head);
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&forged.0).unwrap();
+    assert_eq!(store.find_head(snapshot_head).unwrap(), Some(1));
+    assert_eq!(store.find_head(head).unwrap(), None);
+
+    // changing an entry without recomputing the hashes after it breaks the chain
+    let mut contents = std::fs::read(&forged.0).unwrap();
+    contents.truncate(cursor_end);
+    contents.extend(&std::fs::read(&path.0).unwrap()[cursor_end..]);
+    std::fs::write(&forged.0, &contents).unwrap();
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&forged.0).unwrap();
+    assert!(matches!(
+        store.aggregate_changeset().1,
+        Err(IterError::BrokenChain { entry: 2, .. })
+    ));
+    assert!(matches!(
+        store.head_hash(),
+        Err(IterError::BrokenChain { entry: 2, .. })
+    ));
+
+    // compacting starts a new chain and turning it off leaves a plain store
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    store.compact().unwrap();
+    assert!(store.is_hash_chained());
+    assert_eq!(store.find_head(head).unwrap(), None);
+    store.set_hash_chain(false).unwrap();
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    assert!(!store.is_hash_chained());
+    assert_eq!(store.head_hash().unwrap(), None);
+    assert_eq!(
+        store.aggregate_changeset().0.chain_graph.chain.txids.len(),
+        2
+    );
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_store_needs_its_password() {
+    let path = TempPath::new("encrypted");
+    {
+        let mut store =
+            KeychainStore::<String, TxHeight>::new_encrypted_from_path(&path.0, b"hunter2")
+                .unwrap();
+        assert!(store.is_encrypted());
+        store
+            .append_changeset(&changeset(&[(h!("tx"), TxHeight::Confirmed(2))]))
+            .unwrap();
+        store.append_extension("cursor", b"secret cursor").unwrap();
+    }
+    let contents = std::fs::read(&path.0).unwrap();
+    assert!(!contents
+        .windows(b"secret cursor".len())
+        .any(|window| window == b"secret cursor"));
+
+    assert!(matches!(
+        KeychainStore::<String, TxHeight>::new_from_path(&path.0),
+        Err(FileError::Encrypted)
+    ));
+    assert!(matches!(
+        KeychainStore::<String, TxHeight>::new_encrypted_from_path(&path.0, b"hunter3"),
+        Err(FileError::WrongPassword)
+    ));
+
+    let mut store =
+        KeychainStore::<String, TxHeight>::new_encrypted_from_path(&path.0, b"hunter2").unwrap();
+    let (aggregate, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    assert_eq!(aggregate.chain_graph.chain.txids.len(), 1);
+    assert_eq!(aggregate.derivation_indices.get("a"), Some(&3));
+    assert_eq!(
+        store.aggregate_extensions().0.get("cursor"),
+        Some(&b"secret cursor".to_vec())
+    );
+
+    // the hashes of an encrypted store are those of the encrypted entries
+    store.set_hash_chain(true).unwrap();
+    let head = store.head_hash().unwrap();
+    let mut store =
+        KeychainStore::<String, TxHeight>::new_encrypted_from_path(&path.0, b"hunter2").unwrap();
+    assert!(store.is_encrypted() && store.is_hash_chained());
+    assert_eq!(store.head_hash().unwrap(), head);
+
+    // decrypting the store leaves its contents as they were
+    store.set_password(None).unwrap();
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    assert!(!store.is_encrypted());
+    let (aggregate, result) = store.aggregate_changeset();
+    assert!(result.is_ok());
+    assert_eq!(aggregate.chain_graph.chain.txids.len(), 1);
+    assert!(matches!(
+        KeychainStore::<String, TxHeight>::new_encrypted_from_path(&path.0, b"hunter2"),
+        Err(FileError::NotEncrypted)
+    ));
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_entries_only_open_in_their_place() {
+    let path = TempPath::new("encrypted_replay");
+    let header_len = {
+        let mut store =
+            KeychainStore::<String, TxHeight>::new_encrypted_from_path(&path.0, b"hunter2")
+                .unwrap();
+        let header_len = std::fs::metadata(&path.0).unwrap().len() as usize;
+        store.append_extension("cursor", b"first").unwrap();
+        header_len
+    };
+
+    // a copy of the first entry appended after it doesn't open as the second one
+    let mut contents = std::fs::read(&path.0).unwrap();
+    let first_entry = contents[header_len..].to_vec();
+    contents.extend(&first_entry);
+    std::fs::write(&path.0, &contents).unwrap();
+    let mut store =
+        KeychainStore::<String, TxHeight>::new_encrypted_from_path(&path.0, b"hunter2").unwrap();
+    let (extensions, result) = store.aggregate_extensions();
+    assert!(matches!(result, Err(IterError::Decrypt)));
+    assert_eq!(extensions.get("cursor"), Some(&b"first".to_vec()));
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypting_replaces_the_plaintext_file() {
+    let path = TempPath::new("encrypt_replace");
+    let mut store = KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    store.append_extension("cursor", b"secret cursor").unwrap();
+    let plaintext = std::fs::read(&path.0).unwrap();
+
+    // an interrupted change leaves the plaintext store as it was
+    std::fs::create_dir(path.tmp()).unwrap();
+    let result = store.set_password(Some(b"hunter2"));
+    std::fs::remove_dir(path.tmp()).unwrap();
+    assert!(result.is_err());
+    assert!(!store.is_encrypted());
+    assert_eq!(std::fs::read(&path.0).unwrap(), plaintext);
+
+    store.set_password(Some(b"hunter2")).unwrap();
+    assert!(store.is_encrypted());
+    let contents = std::fs::read(&path.0).unwrap();
+    assert!(!contents
+        .windows(b"secret cursor".len())
+        .any(|window| window == b"secret cursor"));
+    let mut store =
+        KeychainStore::<String, TxHeight>::new_encrypted_from_path(&path.0, b"hunter2").unwrap();
+    assert_eq!(
+        store.aggregate_extensions().0.get("cursor"),
+        Some(&b"secret cursor".to_vec())
+    );
+}
+
+fn raw_tx(value: u64) -> Transaction {
+    Transaction {
+        version: 1,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut {
+            value,
+            ..Default::default()
+        }],
+    }
+}
+
+#[test]
+fn raw_tx_store_keeps_transactions_across_reopening() {
+    let path = TempPath::new("raw_txs");
+    let (tx_a, tx_b) = (raw_tx(1), raw_tx(2));
+    {
+        let mut store = RawTxStore::new_from_path(&path.0).unwrap();
+        assert!(store.insert(&tx_a).unwrap());
+        assert!(store.insert(&tx_b).unwrap());
+        assert!(!store.insert(&tx_a).unwrap(), "already stored");
+    }
+
+    let store = RawTxStore::new_from_path(&path.0).unwrap();
+    assert_eq!(
+        store.txids().collect::<Vec<_>>(),
+        [tx_a.txid(), tx_b.txid()]
+    );
+    assert_eq!(store.get(tx_b.txid()).unwrap(), Some(tx_b));
+    assert_eq!(store.get(h!("missing")).unwrap(), None);
+    assert_eq!(store.provide_tx(tx_a.txid()), Some(tx_a));
+
+    {
+        let mut file = OpenOptions::new().append(true).open(&path.0).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+    }
+    let store = RawTxStore::new_from_path(&path.0).unwrap();
+    assert_eq!(store.len(), 2, "the cut off record is dropped");
+    assert_eq!(store.size(), std::fs::metadata(&path.0).unwrap().len());
+
+    let path = TempPath::new("not_raw_txs");
+    KeychainStore::<String, TxHeight>::new_from_path(&path.0).unwrap();
+    assert!(matches!(
+        RawTxStore::new_from_path(&path.0),
+        Err(FileError::InvalidMagicBytes)
+    ));
+}
+
+#[test]
+fn raw_tx_store_pruning() {
+    let path = TempPath::new("raw_txs_pruning");
+    let txs = (0..4).map(raw_tx).collect::<Vec<_>>();
+    let mut store = RawTxStore::new_from_path(&path.0).unwrap();
+    for tx in &txs {
+        store.insert(tx).unwrap();
+    }
+
+    let pruned = store.prune(|txid| txid != txs[1].txid()).unwrap();
+    assert_eq!(pruned, [txs[1].txid()]);
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.get(txs[3].txid()).unwrap(), Some(txs[3].clone()));
+
+    // each record is the same size so there is room for two of them
+    let record_size = (store.size() - 5) / 3;
+    store.set_max_size(Some(5 + 2 * record_size)).unwrap();
+    assert_eq!(
+        store.txids().collect::<Vec<_>>(),
+        [txs[2].txid(), txs[3].txid()],
+        "the oldest transaction is pruned"
+    );
+    store.insert(&txs[1]).unwrap();
+    assert_eq!(
+        store.txids().collect::<Vec<_>>(),
+        [txs[3].txid(), txs[1].txid()]
+    );
+    assert_eq!(std::fs::metadata(&path.0).unwrap().len(), store.size());
+
+    let store = RawTxStore::new_from_path(&path.0).unwrap();
+    assert_eq!(store.get(txs[1].txid()).unwrap(), Some(txs[1].clone()));
+    assert!(!store.contains(txs[2].txid()));
+}
+
+#[test]
+fn raw_tx_store_inflates_changesets() {
+    let path = TempPath::new("raw_txs_inflate");
+    let (tx_a, tx_b) = (raw_tx(1), raw_tx(2));
+    let mut store = RawTxStore::new_from_path(&path.0).unwrap();
+    store.insert(&tx_a).unwrap();
+
+    let mut graph = ChainGraph::<TxHeight>::default();
+    let _ = graph
+        .insert_tx(tx_b.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    let changeset = changeset! {
+        checkpoints: [],
+        txids: [(tx_a.txid(), Some(TxHeight::Unconfirmed))]
+    };
+
+    let inflated = graph
+        .inflate_changeset_from(changeset.clone(), &store)
+        .unwrap();
+    assert!(inflated.graph.tx.contains(&tx_a));
+
+    // the graph and the store can provide the transactions together
+    let changeset = changeset! {
+        checkpoints: [],
+        txids: [
+            (tx_a.txid(), Some(TxHeight::Unconfirmed)),
+            (tx_b.txid(), Some(TxHeight::Unconfirmed))
+        ]
+    };
+    let inflated = ChainGraph::<TxHeight>::default()
+        .inflate_changeset_from(changeset, &(graph.graph(), &store))
+        .unwrap();
+    assert_eq!(inflated.graph.tx.len(), 2);
+}
@@ -0,0 +1,154 @@
+use bdk_chain::funding::{FundingError, FundingInput, FundingOutput, FundingTemplate, Role};
+use bitcoin::{hashes::Hash, OutPoint, PackedLockTime, Script, Sequence, TxOut, WPubkeyHash};
+
+fn p2wpkh(seed: &[u8]) -> Script {
+    Script::new_v0_p2wpkh(&WPubkeyHash::hash(seed))
+}
+
+fn input(seed: &[u8], value: u64) -> FundingInput {
+    FundingInput {
+        previous_output: OutPoint::new(Hash::hash(seed), 0),
+        prevout: TxOut {
+            value,
+            script_pubkey: p2wpkh(seed),
+        },
+        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+        satisfaction_weight: 108,
+    }
+}
+
+fn output(seed: &[u8], value: u64, shared: bool) -> FundingOutput {
+    FundingOutput {
+        txout: TxOut {
+            value,
+            script_pubkey: p2wpkh(seed),
+        },
+        shared,
+    }
+}
+
+/// Adds the contributions of both sides to a template, either in the order given or with the
+/// acceptor going first.
+fn negotiate(acceptor_first: bool) -> FundingTemplate {
+    let mut template = FundingTemplate::new(PackedLockTime(100));
+    let initiator = |template: &mut FundingTemplate| {
+        let serial_id = template.next_serial_id(Role::Initiator);
+        template
+            .add_output(
+                Role::Initiator,
+                serial_id,
+                output(b"funding", 150_000, true),
+            )
+            .unwrap();
+        let serial_id = template.next_serial_id(Role::Initiator);
+        template
+            .add_input(Role::Initiator, serial_id, input(b"initiator", 100_000))
+            .unwrap();
+    };
+    let acceptor = |template: &mut FundingTemplate| {
+        let serial_id = template.next_serial_id(Role::Acceptor);
+        template
+            .add_input(Role::Acceptor, serial_id, input(b"acceptor", 80_000))
+            .unwrap();
+        let serial_id = template.next_serial_id(Role::Acceptor);
+        template
+            .add_output(Role::Acceptor, serial_id, output(b"change", 29_000, false))
+            .unwrap();
+    };
+    if acceptor_first {
+        acceptor(&mut template);
+        initiator(&mut template);
+    } else {
+        initiator(&mut template);
+        acceptor(&mut template);
+    }
+    template
+}
+
+#[test]
+fn order_doesnt_depend_on_negotiation() {
+    let template = negotiate(false);
+    assert_eq!(template, negotiate(true));
+
+    let tx = template.unsigned_tx();
+    assert_eq!(
+        tx.input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect::<Vec<_>>(),
+        // the initiator added its output first so its input got serial id 2 and the acceptor's 1
+        vec![
+            input(b"acceptor", 0).previous_output,
+            input(b"initiator", 0).previous_output,
+        ]
+    );
+    assert_eq!(tx.output[0].script_pubkey, p2wpkh(b"funding"));
+    assert_eq!(tx.output[1].script_pubkey, p2wpkh(b"change"));
+    assert_eq!(
+        template
+            .prevouts()
+            .iter()
+            .map(|o| o.value)
+            .collect::<Vec<_>>(),
+        vec![80_000, 100_000]
+    );
+    assert_eq!(template.fee(), Some(1_000));
+}
+
+#[test]
+fn serial_ids_are_checked() {
+    let mut template = FundingTemplate::new(PackedLockTime(0));
+    assert_eq!(template.next_serial_id(Role::Initiator), 0);
+    assert_eq!(template.next_serial_id(Role::Acceptor), 1);
+
+    assert_eq!(
+        template.add_input(Role::Acceptor, 2, input(b"a", 1_000)),
+        Err(FundingError::WrongParity {
+            serial_id: 2,
+            role: Role::Acceptor
+        })
+    );
+    template
+        .add_input(Role::Acceptor, 5, input(b"a", 1_000))
+        .unwrap();
+    assert_eq!(
+        template.add_output(Role::Acceptor, 5, output(b"a", 500, false)),
+        Err(FundingError::SerialIdTaken(5))
+    );
+    assert_eq!(
+        template.add_input(Role::Initiator, 0, input(b"a", 1_000)),
+        Err(FundingError::DuplicateInput(input(b"a", 0).previous_output))
+    );
+    assert_eq!(template.next_serial_id(Role::Acceptor), 7);
+    assert_eq!(template.next_serial_id(Role::Initiator), 0);
+
+    assert!(template.remove_input(5).is_some());
+    assert_eq!(template.next_serial_id(Role::Acceptor), 1);
+}
+
+#[test]
+fn each_side_pays_for_its_weight() {
+    let template = negotiate(false);
+    let initiator = template.contribution(Role::Initiator);
+    let acceptor = template.contribution(Role::Acceptor);
+
+    // p2wpkh outputs weigh 31 vbytes and the inputs 40 vbytes plus their satisfaction
+    assert_eq!(initiator.weight, template.common_weight() + 124 + 160 + 108);
+    assert_eq!(acceptor.weight, 160 + 108 + 124);
+    assert_eq!(
+        initiator.weight + acceptor.weight,
+        template.expected_weight()
+    );
+
+    assert_eq!(initiator.input_value, 100_000);
+    assert_eq!(initiator.output_value, 0);
+    assert_eq!(acceptor.output_value, 29_000);
+
+    // at 1 sat/vbyte
+    let initiator_shared = initiator.shared_value(0.25).unwrap();
+    let acceptor_shared = acceptor.shared_value(0.25).unwrap();
+    assert_eq!(acceptor_shared, 80_000 - 29_000 - 98);
+    // the template pays more than 1 sat/vbyte so together they cover the funding output
+    assert!(initiator_shared + acceptor_shared >= 150_000);
+    assert_eq!(acceptor.shared_value(1_000.0), None);
+}
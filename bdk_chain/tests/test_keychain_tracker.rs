@@ -2,14 +2,29 @@
 #[macro_use]
 mod common;
 use bdk_chain::{
-    keychain::{Balance, KeychainTracker},
+    chain_graph::ChangeSet,
+    collections::BTreeMap,
+    keychain::{
+        AsyncPersistBackend, Balance, BlockingBackend, ConfirmationPolicy, KeychainChangeSet,
+        KeychainTracker, MemoryStore, OutputCursor, PersistBackend, PersistFuture,
+    },
     miniscript::{
-        bitcoin::{secp256k1::Secp256k1, OutPoint, PackedLockTime, Transaction, TxOut},
+        bitcoin::{
+            hashes::Hash, secp256k1::Secp256k1, Block, BlockHeader, OutPoint, PackedLockTime,
+            Transaction, TxOut,
+        },
         Descriptor,
     },
+    tx_graph::TxGraph,
     BlockId, ConfirmationTime, TxHeight,
 };
 use bitcoin::TxIn;
+use core::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
 #[test]
 fn test_insert_tx() {
@@ -163,6 +178,7 @@ fn test_balance() {
             trusted_pending: 7_000,
             untrusted_pending: 0,
             immature: 11_000,
+            at_risk: 0,
             confirmed: 13_000,
         }
     );
@@ -177,6 +193,7 @@ fn test_balance() {
             trusted_pending: 0,
             untrusted_pending: 0,
             immature: 11_000,
+            at_risk: 0,
             confirmed: 20_000,
         }
     );
@@ -194,6 +211,7 @@ fn test_balance() {
             trusted_pending: 0,
             untrusted_pending: 0,
             immature: 11_000,
+            at_risk: 0,
             confirmed: 20_000,
         }
     );
@@ -211,6 +229,7 @@ fn test_balance() {
             trusted_pending: 0,
             untrusted_pending: 0,
             immature: 0,
+            at_risk: 0,
             confirmed: 31_000,
         }
     );
@@ -222,3 +241,505 @@ fn test_balance() {
     assert_eq!(tracker.balance_at(99), 31_000);
     assert_eq!(tracker.balance_at(100), 31_000);
 }
+
+#[test]
+fn test_balance_at_risk() {
+    let mut tracker = KeychainTracker::<(), TxHeight>::default();
+    let secp = Secp256k1::new();
+    let (descriptor, _) = Descriptor::parse_descriptor(&secp, "tr([73c5da0a/86'/0'/0']xprv9xgqHN7yz9MwCkxsBPN5qetuNdQSUttZNKw1dcYTV4mkaAFiBVGQziHs3NRSWMkCzvgjEe3n9xV8oYywvM8at9yRqyaZVz6TYYhX98VjsUk/0/*)").unwrap();
+    tracker.add_keychain((), descriptor);
+    let spk = tracker.txout_index.derive_new(&()).1.clone();
+
+    // adds data to the graph without putting it in the chain
+    let add_to_graph = |tracker: &mut KeychainTracker<(), TxHeight>, graph: TxGraph| {
+        let additions = tracker.graph().determine_additions(&graph);
+        tracker.apply_changeset(KeychainChangeSet {
+            chain_graph: ChangeSet {
+                chain: Default::default(),
+                graph: additions,
+            },
+            ..Default::default()
+        });
+    };
+
+    let foreign_outpoint = OutPoint::new(h!("foreign"), 0);
+    let mut graph = TxGraph::default();
+    let _ = graph.insert_txout(
+        foreign_outpoint,
+        TxOut {
+            value: 100_000,
+            script_pubkey: Default::default(),
+        },
+    );
+    add_to_graph(&mut tracker, graph);
+
+    let pays_us = |value: u64| Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: foreign_outpoint,
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: spk.clone(),
+        }],
+    };
+    let payment = pays_us(90_000);
+    let spends_payment = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(payment.txid(), 0),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: 80_000,
+            script_pubkey: spk.clone(),
+        }],
+    };
+
+    let _ = tracker
+        .insert_tx(payment.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    let _ = tracker
+        .insert_tx(spends_payment, TxHeight::Unconfirmed)
+        .unwrap();
+    assert_eq!(
+        tracker.balance(|_| true),
+        Balance {
+            trusted_pending: 80_000,
+            ..Default::default()
+        }
+    );
+
+    // the payment pays 10_000 sats in fees which isn't enough for twice its own feerate
+    let feerate = 10_000.0 / payment.weight() as f32;
+    assert_eq!(
+        tracker.balance_with_min_feerate(|_| true, feerate),
+        Balance {
+            trusted_pending: 80_000,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        tracker.balance_with_min_feerate(|_| true, feerate * 2.0),
+        Balance {
+            at_risk: 80_000,
+            ..Default::default()
+        }
+    );
+
+    // seeing a double spend of the payment puts it and its descendants at risk
+    let mut graph = TxGraph::default();
+    let _ = graph.insert_tx(pays_us(50_000));
+    add_to_graph(&mut tracker, graph);
+    assert_eq!(
+        tracker.balance(|_| true),
+        Balance {
+            at_risk: 80_000,
+            ..Default::default()
+        }
+    );
+
+    // ...until it confirms
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 1,
+            hash: h!("1"),
+        })
+        .unwrap();
+    let _ = tracker.insert_tx(payment, TxHeight::Confirmed(1)).unwrap();
+    assert_eq!(
+        tracker.balance(|_| true),
+        Balance {
+            trusted_pending: 80_000,
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn test_balance_with_confirmation_policy() {
+    #[derive(Debug, Clone, PartialEq, Eq, Ord, PartialOrd)]
+    enum Keychain {
+        Deposit,
+        Change,
+    }
+    let mut tracker = KeychainTracker::<Keychain, TxHeight>::default();
+    let secp = Secp256k1::new();
+    let (deposit, _) = Descriptor::parse_descriptor(&secp, "tr([73c5da0a/86'/0'/0']xprv9xgqHN7yz9MwCkxsBPN5qetuNdQSUttZNKw1dcYTV4mkaAFiBVGQziHs3NRSWMkCzvgjEe3n9xV8oYywvM8at9yRqyaZVz6TYYhX98VjsUk/0/*)").unwrap();
+    let (change, _) = Descriptor::parse_descriptor(&secp, "tr([73c5da0a/86'/0'/0']xprv9xgqHN7yz9MwCkxsBPN5qetuNdQSUttZNKw1dcYTV4mkaAFiBVGQziHs3NRSWMkCzvgjEe3n9xV8oYywvM8at9yRqyaZVz6TYYhX98VjsUk/1/*)").unwrap();
+    tracker.add_keychain(Keychain::Deposit, deposit);
+    tracker.add_keychain(Keychain::Change, change);
+
+    let paying_to =
+        |tracker: &mut KeychainTracker<Keychain, TxHeight>, keychain, value| Transaction {
+            version: 0x01,
+            lock_time: PackedLockTime(0),
+            input: vec![],
+            output: vec![TxOut {
+                value,
+                script_pubkey: tracker.txout_index.derive_new(&keychain).1.clone(),
+            }],
+        };
+    let deposit_tx = paying_to(&mut tracker, Keychain::Deposit, 13_000);
+    let change_tx = paying_to(&mut tracker, Keychain::Change, 7_000);
+    for (height, hash) in [(1, h!("1")), (2, h!("2"))] {
+        let _ = tracker.insert_checkpoint(BlockId { height, hash }).unwrap();
+    }
+    let _ = tracker
+        .insert_tx(deposit_tx, TxHeight::Confirmed(1))
+        .unwrap();
+    let _ = tracker
+        .insert_tx(change_tx, TxHeight::Confirmed(2))
+        .unwrap();
+
+    let should_trust = |keychain: &Keychain| *keychain == Keychain::Change;
+    let policy = ConfirmationPolicy {
+        untrusted_confirmations: 3,
+        require_checkpoint: true,
+    };
+    assert_eq!(
+        tracker.balance_with_policy(should_trust, 0.0, &policy),
+        Balance {
+            untrusted_pending: 13_000,
+            confirmed: 7_000,
+            ..Default::default()
+        },
+        "the deposit only has two confirmations"
+    );
+
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 3,
+            hash: h!("3"),
+        })
+        .unwrap();
+    assert_eq!(
+        tracker.balance_with_policy(should_trust, 0.0, &policy),
+        Balance {
+            confirmed: 20_000,
+            ..Default::default()
+        }
+    );
+
+    // only the tip is left so we don't know which block the deposit is in
+    tracker.set_checkpoint_limit(Some(1));
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 4,
+            hash: h!("4"),
+        })
+        .unwrap();
+    assert_eq!(tracker.chain().checkpoint_at(1), None);
+    assert_eq!(
+        tracker.balance_with_policy(should_trust, 0.0, &policy),
+        Balance {
+            untrusted_pending: 13_000,
+            confirmed: 7_000,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        tracker.balance_with_policy(
+            should_trust,
+            0.0,
+            &ConfirmationPolicy {
+                require_checkpoint: false,
+                ..policy
+            }
+        ),
+        Balance {
+            confirmed: 20_000,
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn test_outputs_since() {
+    let mut tracker = KeychainTracker::<(), TxHeight>::default();
+    let secp = Secp256k1::new();
+    let (descriptor, _) = Descriptor::parse_descriptor(&secp, "tr([73c5da0a/86'/0'/0']xprv9xgqHN7yz9MwCkxsBPN5qetuNdQSUttZNKw1dcYTV4mkaAFiBVGQziHs3NRSWMkCzvgjEe3n9xV8oYywvM8at9yRqyaZVz6TYYhX98VjsUk/0/*)").unwrap();
+    tracker.add_keychain((), descriptor);
+
+    let paying_to = |tracker: &mut KeychainTracker<(), TxHeight>, value| Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut {
+            value,
+            script_pubkey: tracker.txout_index.derive_new(&()).1.clone(),
+        }],
+    };
+    let unconfirmed = paying_to(&mut tracker, 1_000);
+    let confirmed = paying_to(&mut tracker, 2_000);
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 1,
+            hash: h!("1"),
+        })
+        .unwrap();
+    let _ = tracker
+        .insert_tx(unconfirmed.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    let _ = tracker
+        .insert_tx(confirmed, TxHeight::Confirmed(1))
+        .unwrap();
+
+    let mut cursor = OutputCursor::default();
+    let outputs = tracker.outputs_since(&cursor);
+    assert_eq!(
+        outputs
+            .iter()
+            .map(|(&(_, index), txout)| (index, txout.txout.value, txout.chain_position))
+            .collect::<Vec<_>>(),
+        vec![
+            (1, 2_000, TxHeight::Confirmed(1)),
+            (0, 1_000, TxHeight::Unconfirmed)
+        ],
+        "confirmed outputs come first"
+    );
+    cursor.extend(outputs.iter().map(|(_, txout)| txout.outpoint));
+    assert_eq!(cursor.len(), 2);
+    assert!(tracker.outputs_since(&cursor).is_empty());
+
+    // the unconfirmed deposit confirms and a new one arrives
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 2,
+            hash: h!("2"),
+        })
+        .unwrap();
+    let _ = tracker
+        .insert_tx(unconfirmed, TxHeight::Confirmed(2))
+        .unwrap();
+    let new_deposit = paying_to(&mut tracker, 3_000);
+    let _ = tracker
+        .insert_tx(new_deposit.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    let outputs = tracker.outputs_since(&cursor);
+    assert_eq!(
+        outputs.len(),
+        1,
+        "confirming doesn't report the output again"
+    );
+    assert_eq!(outputs[0].1.outpoint, OutPoint::new(new_deposit.txid(), 0));
+}
+
+#[test]
+fn memory_store_round_trip() {
+    let secp = Secp256k1::new();
+    let (descriptor, _) = Descriptor::parse_descriptor(&secp, "tr([73c5da0a/86'/0'/0']xprv9xgqHN7yz9MwCkxsBPN5qetuNdQSUttZNKw1dcYTV4mkaAFiBVGQziHs3NRSWMkCzvgjEe3n9xV8oYywvM8at9yRqyaZVz6TYYhX98VjsUk/0/*)").unwrap();
+    let new_tracker = || {
+        let mut tracker = KeychainTracker::<(), TxHeight>::default();
+        tracker.add_keychain((), descriptor.clone());
+        tracker
+    };
+    let tx = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut {
+            value: 100_000,
+            script_pubkey: descriptor.at_derivation_index(2).script_pubkey(),
+        }],
+    };
+
+    let mut tracker = new_tracker();
+    let mut store = MemoryStore::new();
+    assert!(tracker.txout_index.store_up_to(&(), 2));
+    store
+        .set_derivation_indices(tracker.txout_index.derivation_indices())
+        .unwrap();
+    let changeset = tracker
+        .insert_tx(tx.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    store.append_changeset(&changeset).unwrap();
+    // empty changesets aren't stored
+    store
+        .append_changeset(&KeychainChangeSet::default())
+        .unwrap();
+    assert_eq!(store.iter_changesets().count(), 2);
+    store.append_extension("cursor", &[1]).unwrap();
+    store.append_extension("cursor", &[2]).unwrap();
+
+    // load it the way code that is generic over the backend would
+    fn load<S: PersistBackend<(), TxHeight>>(
+        store: &mut S,
+        tracker: &mut KeychainTracker<(), TxHeight>,
+    ) {
+        store.load_into_keychain_tracker(tracker).unwrap();
+    }
+    let mut loaded = new_tracker();
+    load(&mut store, &mut loaded);
+    assert_eq!(
+        loaded.txout_index.derivation_indices(),
+        tracker.txout_index.derivation_indices()
+    );
+    assert_eq!(loaded.graph().get_tx(tx.txid()), Some(&tx));
+    assert_eq!(
+        store.aggregate_changeset().0.derivation_indices.get(&()),
+        Some(&2)
+    );
+    assert_eq!(store.aggregate_extensions().0.get("cursor"), Some(&vec![2]));
+}
+
+/// A future that is pending the first time it's polled, like one waiting on IO.
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Keeps the changesets in memory but yields before each operation like an async driver would.
+#[derive(Default)]
+struct AsyncStore(MemoryStore<(), TxHeight>);
+
+impl AsyncPersistBackend<(), TxHeight> for AsyncStore {
+    type WriteError = Infallible;
+    type LoadError = Infallible;
+
+    fn append_changeset<'a>(
+        &'a mut self,
+        changeset: &'a KeychainChangeSet<(), TxHeight>,
+    ) -> PersistFuture<'a, Result<(), Infallible>> {
+        Box::pin(async move {
+            YieldOnce(false).await;
+            self.0.append_changeset(changeset)
+        })
+    }
+
+    fn append_extension<'a>(
+        &'a mut self,
+        name: &'a str,
+        data: &'a [u8],
+    ) -> PersistFuture<'a, Result<(), Infallible>> {
+        Box::pin(async move {
+            YieldOnce(false).await;
+            self.0.append_extension(name, data)
+        })
+    }
+
+    fn load_into_keychain_tracker<'a>(
+        &'a mut self,
+        tracker: &'a mut KeychainTracker<(), TxHeight>,
+    ) -> PersistFuture<'a, Result<(), Infallible>> {
+        Box::pin(async move {
+            YieldOnce(false).await;
+            self.0.load_into_keychain_tracker(tracker)
+        })
+    }
+
+    fn aggregate_extensions(
+        &mut self,
+    ) -> PersistFuture<'_, (BTreeMap<String, Vec<u8>>, Result<(), Infallible>)> {
+        Box::pin(async move {
+            YieldOnce(false).await;
+            self.0.aggregate_extensions()
+        })
+    }
+}
+
+#[test]
+fn async_backend_used_as_blocking_one() {
+    let secp = Secp256k1::new();
+    let (descriptor, _) = Descriptor::parse_descriptor(&secp, "tr([73c5da0a/86'/0'/0']xprv9xgqHN7yz9MwCkxsBPN5qetuNdQSUttZNKw1dcYTV4mkaAFiBVGQziHs3NRSWMkCzvgjEe3n9xV8oYywvM8at9yRqyaZVz6TYYhX98VjsUk/0/*)").unwrap();
+    let mut tracker = KeychainTracker::<(), TxHeight>::default();
+    tracker.add_keychain((), descriptor.clone());
+    assert!(tracker.txout_index.store_up_to(&(), 4));
+
+    fn save<S: PersistBackend<(), TxHeight>>(store: &mut S, indices: BTreeMap<(), u32>) {
+        store.set_derivation_indices(indices).unwrap();
+        store.append_extension("cursor", &[7]).unwrap();
+    }
+    let mut store = BlockingBackend(AsyncStore::default());
+    save(&mut store, tracker.txout_index.derivation_indices());
+
+    let mut loaded = KeychainTracker::<(), TxHeight>::default();
+    loaded.add_keychain((), descriptor);
+    store.load_into_keychain_tracker(&mut loaded).unwrap();
+    assert_eq!(loaded.txout_index.derivation_indices().get(&()), Some(&4));
+    assert_eq!(store.aggregate_extensions().0.get("cursor"), Some(&vec![7]));
+    assert_eq!(store.0 .0.iter_changesets().count(), 1);
+}
+
+#[test]
+fn scan_block_confirms_relevant_txs() {
+    let secp = Secp256k1::new();
+    let (descriptor, _) = Descriptor::parse_descriptor(&secp, "tr([73c5da0a/86'/0'/0']xprv9xgqHN7yz9MwCkxsBPN5qetuNdQSUttZNKw1dcYTV4mkaAFiBVGQziHs3NRSWMkCzvgjEe3n9xV8oYywvM8at9yRqyaZVz6TYYhX98VjsUk/0/*)").unwrap();
+    let mut tracker = KeychainTracker::<(), ConfirmationTime>::default();
+    tracker.add_keychain((), descriptor.clone());
+    assert!(tracker.txout_index.store_up_to(&(), 5));
+
+    let paying_to = |script_pubkey| Transaction {
+        version: 0x02,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey,
+        }],
+    };
+    let ours = paying_to(descriptor.at_derivation_index(2).script_pubkey());
+    let unrelated = paying_to(descriptor.at_derivation_index(6).script_pubkey());
+    let header = |prev_blockhash, time| BlockHeader {
+        version: 1,
+        prev_blockhash,
+        merkle_root: Hash::all_zeros(),
+        time,
+        bits: 0,
+        nonce: 0,
+    };
+    let block = Block {
+        header: header(Hash::all_zeros(), 1_000),
+        txdata: vec![unrelated, ours.clone()],
+    };
+
+    let changeset = tracker.scan_block(&block, 1).unwrap();
+    assert_eq!(changeset.derivation_indices.get(&()), None);
+    assert_eq!(
+        tracker
+            .chain_graph()
+            .transactions_in_chain()
+            .collect::<Vec<_>>(),
+        vec![(
+            &ConfirmationTime::Confirmed {
+                height: 1,
+                time: 1_000
+            },
+            &ours
+        )]
+    );
+    assert_eq!(tracker.txout_index.last_active_index(&()), Some(2));
+    assert_eq!(
+        tracker.chain().latest_checkpoint(),
+        Some(BlockId {
+            height: 1,
+            hash: block.block_hash()
+        })
+    );
+
+    // a block with nothing for us still moves the tip
+    let next = Block {
+        header: header(block.block_hash(), 1_600),
+        txdata: vec![],
+    };
+    let changeset = tracker.scan_block_preview(&next, 2).unwrap();
+    assert!(changeset.chain_graph.graph.is_empty());
+    tracker.apply_changeset(changeset);
+    assert_eq!(
+        tracker.chain().latest_checkpoint().map(|cp| cp.height),
+        Some(2)
+    );
+}
@@ -1,6 +1,6 @@
 #![cfg(feature = "miniscript")]
 
-use bdk_chain::collections::BTreeMap;
+use bdk_chain::{collections::BTreeMap, keychain::GapStats};
 use bitcoin::{OutPoint, TxOut};
 
 #[derive(Clone, Debug, PartialEq, Eq, Ord, PartialOrd)]
@@ -59,3 +59,95 @@ fn test_pad_all_with_unused() {
         [(TestKeychain::External, 8), (TestKeychain::Internal, 4)].into()
     );
 }
+
+#[test]
+fn test_stored_scripts_since() {
+    let mut txout_index = init_txout_index();
+    txout_index.store_up_to(&TestKeychain::External, 2);
+    let since = txout_index.derivation_indices();
+    assert_eq!(txout_index.stored_scripts_since(&since).count(), 0);
+
+    let (_, new_spk) = txout_index.derive_new(&TestKeychain::External);
+    let new_spk = new_spk.clone();
+    txout_index.store_up_to(&TestKeychain::Internal, 1);
+
+    let stored = txout_index
+        .stored_scripts_since(&since)
+        .map(|(keychain, index, spk)| (keychain.clone(), index, spk.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(stored.len(), 3);
+    assert_eq!(stored[0], (TestKeychain::External, 3, new_spk));
+    assert_eq!(
+        stored[1..]
+            .iter()
+            .map(|(keychain, index, _)| (keychain.clone(), *index))
+            .collect::<Vec<_>>(),
+        vec![(TestKeychain::Internal, 0), (TestKeychain::Internal, 1)]
+    );
+}
+
+#[test]
+fn test_gap_stats() {
+    let mut txout_index = init_txout_index();
+    assert_eq!(
+        txout_index.gap_stats(&TestKeychain::External),
+        GapStats::default()
+    );
+
+    txout_index.store_up_to(&TestKeychain::External, 9);
+    txout_index.store_up_to(&TestKeychain::Internal, 2);
+    for (vout, index) in [(0, 1), (1, 5), (2, 5), (3, 6)] {
+        let script_pubkey = txout_index
+            .keychains()
+            .get(&TestKeychain::External)
+            .unwrap()
+            .at_derivation_index(index)
+            .script_pubkey();
+        txout_index.scan_txout(
+            OutPoint {
+                vout,
+                ..OutPoint::default()
+            },
+            &TxOut {
+                value: 420,
+                script_pubkey,
+            },
+        );
+    }
+
+    let stats = txout_index.all_gap_stats();
+    // 2, 3 and 4 are unused between 1 and 5, two outputs paying 5 don't make a gap
+    assert_eq!(
+        stats[&TestKeychain::External],
+        GapStats {
+            derivation_index: Some(9),
+            last_active_index: Some(6),
+            current_gap: 3,
+            max_gap: 3,
+        }
+    );
+    assert_eq!(
+        stats[&TestKeychain::Internal],
+        GapStats {
+            derivation_index: Some(2),
+            last_active_index: None,
+            current_gap: 3,
+            max_gap: 0,
+        }
+    );
+    assert_eq!(stats[&TestKeychain::External].lookahead_shortfall(5), 2);
+    assert_eq!(stats[&TestKeychain::Internal].lookahead_shortfall(3), 0);
+
+    // padding stores exactly the missing lookahead
+    txout_index.pad_with_unused(&TestKeychain::External, 5);
+    assert_eq!(
+        txout_index
+            .gap_stats(&TestKeychain::External)
+            .lookahead_shortfall(5),
+        0
+    );
+    assert_eq!(
+        txout_index.derivation_index(&TestKeychain::External),
+        Some(11)
+    );
+}
@@ -514,6 +514,134 @@ fn checkpoint_limit_is_respected() {
     assert_eq!(chain1.checkpoints().len(), 4);
 }
 
+#[test]
+fn checkpoint_retention_keeps_recent_and_spaced_out_checkpoints() {
+    let mut chain = SparseChain::<TxHeight>::default();
+    let update = SparseChain::from_checkpoints((0..=40).map(|height| BlockId {
+        height,
+        hash: bitcoin::BlockHash::hash(&height.to_le_bytes()),
+    }));
+    let _ = chain.apply_update(update).unwrap();
+
+    chain.set_checkpoint_retention(Some(CheckpointRetention {
+        recent: 5,
+        tiers: vec![(5, 5), (20, 10)],
+    }));
+    // 36..=40 are recent, 21..=35 are in spans of 5 and 0..=20 are in spans of 10
+    assert_eq!(
+        chain.checkpoints().keys().copied().collect::<Vec<_>>(),
+        vec![0, 10, 20, 21, 25, 30, 35, 36, 37, 38, 39, 40]
+    );
+
+    // a new tip moves the checkpoints deeper
+    let _ = chain
+        .insert_checkpoint(BlockId {
+            height: 41,
+            hash: h!("41"),
+        })
+        .unwrap();
+    assert_eq!(
+        chain.checkpoints().keys().copied().collect::<Vec<_>>(),
+        vec![0, 10, 20, 25, 30, 35, 37, 38, 39, 40, 41]
+    );
+
+    // the limit is applied on top of the retention policy
+    chain.set_checkpoint_limit(Some(3));
+    assert_eq!(
+        chain.checkpoints().keys().copied().collect::<Vec<_>>(),
+        vec![39, 40, 41]
+    );
+}
+
+#[test]
+fn anchor_cannot_be_invalidated() {
+    let anchor = BlockId {
+        height: 2,
+        hash: h!("B"),
+    };
+    let mut chain = SparseChain::<TxHeight>::from_anchor(anchor);
+    assert_eq!(chain.anchor(), Some(anchor));
+    let _ = chain
+        .apply_update(chain!([2, h!("B")], [3, h!("C")], [4, h!("D")]))
+        .unwrap();
+
+    // reorgs above the anchor are fine
+    assert!(chain
+        .determine_changeset(&chain!([3, h!("C")], [4, h!("D'")]))
+        .is_ok());
+
+    assert_eq!(
+        chain.determine_changeset(&chain!([1, h!("A")], [2, h!("B'")], [3, h!("C'")])),
+        Err(UpdateError::AnchorConflict { anchor, height: 2 })
+    );
+    // even if we have pruned the anchor checkpoint
+    chain.set_checkpoint_limit(Some(2));
+    assert_eq!(
+        chain.determine_changeset(&chain!([1, h!("A")], [3, h!("C'")])),
+        Err(UpdateError::AnchorConflict { anchor, height: 3 })
+    );
+    assert_eq!(
+        chain.determine_changeset(&chain!([0, h!("genesis")], [2, h!("B'")])),
+        Err(UpdateError::AnchorConflict { anchor, height: 2 })
+    );
+    assert!(chain
+        .insert_checkpoint(BlockId {
+            height: 2,
+            hash: h!("B'")
+        })
+        .is_err());
+    assert!(chain
+        .set_anchor(Some(BlockId {
+            height: 3,
+            hash: h!("C'")
+        }))
+        .is_err());
+}
+
+#[test]
+fn discrepancies_between_chains() {
+    let ours = chain!(
+        checkpoints: [[1, h!("A")], [2, h!("B")], [3, h!("C")]],
+        txids: [
+            (h!("tx same"), TxHeight::Confirmed(1)),
+            (h!("tx moved"), TxHeight::Confirmed(2)),
+            (h!("tx only ours"), TxHeight::Unconfirmed)
+        ]
+    );
+    let theirs = chain!(
+        checkpoints: [[2, h!("B")], [3, h!("C'")], [4, h!("D")]],
+        txids: [
+            (h!("tx same"), TxHeight::Confirmed(1)),
+            (h!("tx moved"), TxHeight::Confirmed(3)),
+            (h!("tx only theirs"), TxHeight::Confirmed(4))
+        ]
+    );
+
+    let discrepancies = ours.discrepancies(&theirs);
+    assert_eq!(discrepancies.len(), 4);
+    assert!(discrepancies.contains(&Discrepancy::Checkpoint {
+        height: 3,
+        ours: h!("C"),
+        theirs: h!("C'"),
+    }));
+    assert!(discrepancies.contains(&Discrepancy::Tx {
+        txid: h!("tx moved"),
+        ours: Some(TxHeight::Confirmed(2)),
+        theirs: Some(TxHeight::Confirmed(3)),
+    }));
+    assert!(discrepancies.contains(&Discrepancy::Tx {
+        txid: h!("tx only ours"),
+        ours: Some(TxHeight::Unconfirmed),
+        theirs: None,
+    }));
+    assert!(discrepancies.contains(&Discrepancy::Tx {
+        txid: h!("tx only theirs"),
+        ours: None,
+        theirs: Some(TxHeight::Confirmed(4)),
+    }));
+    assert!(ours.discrepancies(&ours).is_empty());
+}
+
 #[test]
 fn range_txids_by_height() {
     let mut chain = chain!(index: TestIndex, checkpoints: [[1, h!("block 1")], [2, h!("block 2")]]);
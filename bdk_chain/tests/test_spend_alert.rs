@@ -0,0 +1,114 @@
+use bdk_chain::{chain_graph::ChainGraph, spend_alert::SpendAlerts, TxHeight};
+use bitcoin::{OutPoint, PackedLockTime, Script, Transaction, TxIn, TxOut};
+
+fn spending(outpoint: OutPoint, lock_time: u32) -> Transaction {
+    Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(lock_time),
+        input: vec![TxIn {
+            previous_output: outpoint,
+            ..Default::default()
+        }],
+        output: vec![TxOut::default()],
+    }
+}
+
+#[test]
+fn alert_on_watched_outpoint_and_script() {
+    let vault_script = Script::from(vec![0x51]);
+    let tx_a = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![
+            TxOut {
+                value: 10_000,
+                script_pubkey: vault_script.clone(),
+            },
+            TxOut {
+                value: 20_000,
+                script_pubkey: Script::default(),
+            },
+            TxOut {
+                value: 30_000,
+                script_pubkey: Script::default(),
+            },
+        ],
+    };
+    let txid_a = tx_a.txid();
+    let op = |vout| OutPoint::new(txid_a, vout);
+
+    let mut alerts = SpendAlerts::default();
+    assert!(alerts.watch_script(vault_script));
+    assert!(alerts.watch_outpoint(op(1)));
+    assert!(!alerts.watch_outpoint(op(1)));
+
+    let mut cg = ChainGraph::default();
+    let changeset = cg
+        .insert_tx(tx_a, TxHeight::Unconfirmed)
+        .expect("should insert");
+    assert!(alerts.check(&cg, &changeset).is_empty());
+
+    let spends_script = spending(op(0), 0);
+    let spends_outpoint = spending(op(1), 1);
+    let spends_unwatched = spending(op(2), 2);
+
+    for (tx, expected_outpoint) in [
+        (spends_script, Some(op(0))),
+        (spends_outpoint, Some(op(1))),
+        (spends_unwatched, None),
+    ] {
+        let changeset = cg
+            .insert_tx(tx.clone(), TxHeight::Unconfirmed)
+            .expect("should insert");
+        let found = alerts.check(&cg, &changeset);
+        match expected_outpoint {
+            Some(outpoint) => {
+                assert_eq!(found.len(), 1);
+                assert_eq!(found[0].outpoint, outpoint);
+                assert_eq!(found[0].vin, 0);
+                assert_eq!(found[0].spending_txid(), tx.txid());
+                assert_eq!(found[0].spending_position, Some(TxHeight::Unconfirmed));
+                assert_eq!(
+                    found[0].txout.as_ref().map(|txout| txout.value),
+                    Some(10_000 * (outpoint.vout as u64 + 1))
+                );
+            }
+            None => assert!(found.is_empty()),
+        }
+    }
+}
+
+#[test]
+fn alert_again_when_spend_confirms() {
+    let op = OutPoint::new(bitcoin::hashes::Hash::hash(b"vault"), 0);
+    let spend = spending(op, 0);
+
+    let mut alerts = SpendAlerts::default();
+    alerts.watch_outpoint(op);
+
+    let mut cg = ChainGraph::default();
+    let changeset = cg
+        .insert_checkpoint(bdk_chain::BlockId {
+            height: 1,
+            hash: bitcoin::hashes::Hash::hash(b"block"),
+        })
+        .expect("should insert");
+    assert!(alerts.check(&cg, &changeset).is_empty());
+
+    let changeset = cg
+        .insert_tx(spend.clone(), TxHeight::Unconfirmed)
+        .expect("should insert");
+    assert_eq!(alerts.check(&cg, &changeset).len(), 1);
+
+    let changeset = cg
+        .insert_tx(spend, TxHeight::Confirmed(1))
+        .expect("should insert");
+    let found = alerts.check(&cg, &changeset);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].txout, None);
+    assert_eq!(found[0].spending_position, Some(TxHeight::Confirmed(1)));
+
+    assert!(alerts.unwatch_outpoint(&op));
+    assert!(alerts.check(&cg, &changeset).is_empty());
+}
@@ -1,5 +1,8 @@
 use bdk_chain::SpkTxOutIndex;
-use bitcoin::{hashes::hex::FromHex, OutPoint, PackedLockTime, Script, Transaction, TxIn, TxOut};
+use bitcoin::{
+    hashes::{hex::FromHex, Hash},
+    Block, BlockHeader, OutPoint, PackedLockTime, Script, Transaction, TxIn, TxOut,
+};
 
 #[test]
 fn spk_txout_sent_and_received() {
@@ -54,3 +57,96 @@ fn spk_txout_sent_and_received() {
     assert_eq!(index.sent_and_received(&tx2), (42_000, 50_000));
     assert_eq!(index.net_value(&tx2), 8_000);
 }
+
+#[test]
+fn used_and_unused_in_range() {
+    let spks = [
+        "001404f1e52ce2bab3423c6a8c63b7cd730d8f12542c",
+        "00142b57404ae14f08c3a0c903feb2af7830605eb00f",
+        "0014d9f65c5ac8dc7b0d0a05d3d0ee2d9b1d6b7a31d8",
+    ]
+    .map(|hex| Script::from_hex(hex).unwrap());
+
+    // scripts that don't come from a descriptor, indexed by (channel, output)
+    let mut index = SpkTxOutIndex::<(u32, u32)>::default();
+    index.insert_script_pubkey((1, 0), spks[0].clone());
+    index.insert_script_pubkey((1, 1), spks[1].clone());
+    index.insert_script_pubkey((2, 0), spks[2].clone());
+    assert!(!index.insert_script_pubkey((3, 0), spks[2].clone()));
+
+    let tx = Transaction {
+        version: 0x02,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey: spks[1].clone(),
+        }],
+    };
+    assert!(index.is_relevant(&tx));
+    index.scan(&tx);
+
+    assert_eq!(
+        index.used(..).collect::<Vec<_>>(),
+        vec![(&(1, 1), &spks[1])]
+    );
+    assert_eq!(
+        index
+            .unused((1, u32::MIN)..=(1, u32::MAX))
+            .collect::<Vec<_>>(),
+        vec![(&(1, 0), &spks[0])]
+    );
+    assert_eq!(index.used((2, 0)..).count(), 0);
+    assert_eq!(
+        index.outputs_in_range((1, 1)..=(1, 1)).collect::<Vec<_>>(),
+        vec![(&(1, 1), OutPoint::new(tx.txid(), 0))]
+    );
+}
+
+#[test]
+fn relevant_txs_in_block_spending_each_other() {
+    let ours = Script::from_hex("001404f1e52ce2bab3423c6a8c63b7cd730d8f12542c").unwrap();
+    let theirs = Script::from_hex("00142b57404ae14f08c3a0c903feb2af7830605eb00f").unwrap();
+
+    let mut index = SpkTxOutIndex::default();
+    index.insert_script_pubkey(0, ours.clone());
+
+    let tx = |inputs: Vec<OutPoint>, script_pubkey: &Script| Transaction {
+        version: 0x02,
+        lock_time: PackedLockTime(0),
+        input: inputs
+            .into_iter()
+            .map(|previous_output| TxIn {
+                previous_output,
+                ..Default::default()
+            })
+            .collect(),
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey: script_pubkey.clone(),
+        }],
+    };
+    let unrelated = tx(vec![], &theirs);
+    let receive = tx(vec![], &ours);
+    // only relevant because its parent is in the same block
+    let spend = tx(vec![OutPoint::new(receive.txid(), 0)], &theirs);
+    let spend_unrelated = tx(vec![OutPoint::new(unrelated.txid(), 0)], &theirs);
+
+    let block = Block {
+        header: BlockHeader {
+            version: 1,
+            prev_blockhash: Hash::all_zeros(),
+            merkle_root: Hash::all_zeros(),
+            time: 0,
+            bits: 0,
+            nonce: 0,
+        },
+        txdata: vec![unrelated, receive.clone(), spend.clone(), spend_unrelated],
+    };
+
+    assert_eq!(
+        index.relevant_txs_in_block(&block).collect::<Vec<_>>(),
+        vec![&receive, &spend]
+    );
+    assert_eq!(index.used(..).count(), 0, "the index isn't changed");
+}
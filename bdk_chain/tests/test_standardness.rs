@@ -0,0 +1,139 @@
+use bdk_chain::{
+    standardness::{
+        dust_threshold, sigops_cost, validate_standardness, StandardnessPolicy, Violation,
+    },
+    tx_graph::TxGraph,
+};
+use bitcoin::{
+    blockdata::{opcodes::all::*, script::Builder},
+    hashes::Hash,
+    OutPoint, PackedLockTime, Script, Transaction, TxIn, TxOut, WPubkeyHash, Witness,
+};
+
+fn p2wpkh(seed: &[u8]) -> Script {
+    Script::new_v0_p2wpkh(&WPubkeyHash::hash(seed))
+}
+
+/// A graph with a 100_000 sat P2WPKH output and a transaction spending it to `outputs`.
+fn spend(outputs: Vec<TxOut>) -> (TxGraph, Transaction) {
+    let prevout = OutPoint::new(Hash::hash(b"prev"), 0);
+    let mut graph = TxGraph::default();
+    let _ = graph.insert_txout(
+        prevout,
+        TxOut {
+            value: 100_000,
+            script_pubkey: p2wpkh(b"ours"),
+        },
+    );
+    let tx = Transaction {
+        version: 0x02,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: prevout,
+            // a signature and a public key
+            witness: Witness::from_vec(vec![vec![0; 72], vec![0; 33]]),
+            ..Default::default()
+        }],
+        output: outputs,
+    };
+    (graph, tx)
+}
+
+#[test]
+fn standard_spend_passes() {
+    let (graph, tx) = spend(vec![TxOut {
+        value: 99_000,
+        script_pubkey: p2wpkh(b"theirs"),
+    }]);
+    assert_eq!(
+        validate_standardness(&tx, &graph, &StandardnessPolicy::default()),
+        Ok(())
+    );
+    // a P2WPKH input costs a single sigop
+    assert_eq!(sigops_cost(&tx, &graph), 1);
+}
+
+#[test]
+fn every_violation_is_reported() {
+    let (graph, tx) = spend(vec![
+        TxOut {
+            value: 99_600,
+            script_pubkey: p2wpkh(b"theirs"),
+        },
+        TxOut {
+            value: 293,
+            script_pubkey: p2wpkh(b"change"),
+        },
+        TxOut {
+            value: 0,
+            script_pubkey: Script::new_op_return(&[0; 81]),
+        },
+    ]);
+    assert_eq!(dust_threshold(&p2wpkh(b"change"), 0.75), 294);
+
+    let violations = validate_standardness(&tx, &graph, &StandardnessPolicy::default())
+        .unwrap_err()
+        .violations;
+    assert_eq!(
+        violations,
+        vec![
+            Violation::Dust {
+                vout: 1,
+                value: 293,
+                threshold: 294
+            },
+            Violation::OpReturnTooLarge { vout: 2, size: 84 },
+            Violation::FeerateTooLow {
+                fee: 107,
+                min_fee: (tx.weight() as f32 * 0.25).ceil() as u64
+            },
+        ]
+    );
+}
+
+#[test]
+fn unknown_prevouts_are_reported() {
+    let (_, tx) = spend(vec![TxOut {
+        value: 99_000,
+        script_pubkey: p2wpkh(b"theirs"),
+    }]);
+    assert_eq!(
+        validate_standardness(&tx, &TxGraph::default(), &StandardnessPolicy::default())
+            .unwrap_err()
+            .violations,
+        vec![Violation::UnknownPrevout { vin: 0 }]
+    );
+}
+
+#[test]
+fn sigops_adjust_the_required_fee() {
+    // a bare 1-of-1 multisig output counts as 20 sigops
+    let bare_multisig = Builder::new()
+        .push_opcode(OP_PUSHNUM_1)
+        .push_slice(&[2; 33])
+        .push_opcode(OP_PUSHNUM_1)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script();
+    let (graph, tx) = spend(vec![TxOut {
+        value: 99_000,
+        script_pubkey: bare_multisig,
+    }]);
+    let cost = sigops_cost(&tx, &graph);
+    assert_eq!(cost, 20 * 4 + 1);
+
+    // the fee would be enough for the transaction's weight but not for its sigops
+    assert!(tx.weight() < 1_000);
+    let policy = StandardnessPolicy {
+        min_relay_feerate: 1.0,
+        ..Default::default()
+    };
+    assert_eq!(
+        validate_standardness(&tx, &graph, &policy)
+            .unwrap_err()
+            .violations,
+        vec![Violation::FeerateTooLow {
+            fee: 1_000,
+            min_fee: cost as u64 * 20
+        }]
+    );
+}
@@ -0,0 +1,98 @@
+#[macro_use]
+mod common;
+
+use bdk_chain::{
+    collections::BTreeMap,
+    tip::{RecentBlocks, TipEvent},
+    BlockId, TxHeight,
+};
+use bitcoin::BlockHash;
+use core::convert::Infallible;
+
+fn block(height: u32, hash: BlockHash) -> BlockId {
+    BlockId { height, hash }
+}
+
+#[test]
+fn recent_blocks_report_reorgs_block_by_block() {
+    // the source's chain after the reorg
+    let source = BTreeMap::from([(1, h!("A")), (2, h!("B'")), (3, h!("C'")), (4, h!("D'"))]);
+    let hash_at = |height: u32| Ok::<_, Infallible>(source[&height]);
+    let mut recent = RecentBlocks::default();
+
+    assert_eq!(
+        recent.connect(block(2, h!("B")), h!("A"), hash_at),
+        Ok(vec![TipEvent::Connected(block(2, h!("B")))]),
+        "the first tip is connected without looking any further"
+    );
+    assert_eq!(
+        recent.connect(block(3, h!("C")), h!("B"), hash_at),
+        Ok(vec![TipEvent::Connected(block(3, h!("C")))])
+    );
+    assert_eq!(
+        recent.connect(block(3, h!("C")), h!("B"), hash_at),
+        Ok(vec![])
+    );
+
+    assert_eq!(
+        recent.connect(block(4, h!("D'")), h!("C'"), hash_at),
+        Ok(vec![
+            TipEvent::Disconnected(block(3, h!("C"))),
+            TipEvent::Disconnected(block(2, h!("B"))),
+            TipEvent::Connected(block(2, h!("B'"))),
+            TipEvent::Connected(block(3, h!("C'"))),
+            TipEvent::Connected(block(4, h!("D'"))),
+        ]),
+        "the blocks below the new tip are looked up until we reach one we know"
+    );
+    assert_eq!(recent.tip(), Some(block(4, h!("D'"))));
+
+    // a reorg to a shorter chain
+    assert_eq!(
+        recent.connect(block(3, h!("C''")), h!("B'"), hash_at),
+        Ok(vec![
+            TipEvent::Disconnected(block(4, h!("D'"))),
+            TipEvent::Disconnected(block(3, h!("C'"))),
+            TipEvent::Connected(block(3, h!("C''"))),
+        ])
+    );
+}
+
+#[test]
+fn tip_events_update_the_chain() {
+    let chain = chain!(
+        checkpoints: [[1, h!("A")], [2, h!("B")]],
+        txids: [(h!("tx_a"), TxHeight::Confirmed(1)), (h!("tx_b"), TxHeight::Confirmed(2))]
+    );
+
+    assert_eq!(
+        TipEvent::Connected(block(3, h!("C"))).changeset(&chain),
+        Ok(changeset! {
+            checkpoints: [(3, Some(h!("C")))]
+        })
+    );
+    assert_eq!(
+        TipEvent::Connected(block(2, h!("B"))).changeset(&chain),
+        Ok(Default::default())
+    );
+    assert_eq!(
+        TipEvent::Disconnected(block(2, h!("B"))).changeset(&chain),
+        Ok(changeset! {
+            checkpoints: [(2, None)],
+            txids: [(h!("tx_b"), Some(TxHeight::Unconfirmed))]
+        })
+    );
+    assert_eq!(
+        TipEvent::Disconnected(block(2, h!("B'"))).changeset(&chain),
+        Ok(Default::default()),
+        "we never had the disconnected block"
+    );
+    assert_eq!(
+        TipEvent::Connected(block(2, h!("B'"))).changeset(&chain),
+        Ok(changeset! {
+            checkpoints: [(2, Some(h!("B'")))],
+            txids: [(h!("tx_b"), Some(TxHeight::Unconfirmed))]
+        }),
+        "a block we missed the disconnection of is replaced"
+    );
+}
@@ -4,7 +4,10 @@ use bdk_chain::{
     collections::*,
     tx_graph::{Additions, TxGraph},
 };
-use bitcoin::{hashes::Hash, OutPoint, PackedLockTime, Script, Transaction, TxIn, TxOut, Txid};
+use bitcoin::{
+    hashes::Hash, Block, BlockHeader, OutPoint, PackedLockTime, Script, Transaction, TxIn, TxOut,
+    Txid,
+};
 use core::iter;
 
 #[test]
@@ -366,3 +369,68 @@ fn test_calculate_fee_on_coinbase() {
 
     assert_eq!(graph.calculate_fee(&tx), Some(0));
 }
+
+#[test]
+fn scan_block_inserts_relevant_txs() {
+    let tx = |previous_output: OutPoint, value: u64| Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output,
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let ours = OutPoint::new(h!("ours"), 0);
+    let mut graph = TxGraph::default();
+    let _ = graph.insert_txout(
+        ours,
+        TxOut {
+            value: 50_000,
+            script_pubkey: Script::new(),
+        },
+    );
+
+    let spends_ours = tx(ours, 40_000);
+    // relevant because it spends an output of a relevant transaction earlier in the block
+    let spends_spend = tx(OutPoint::new(spends_ours.txid(), 0), 30_000);
+    let marked = tx(OutPoint::new(h!("theirs"), 0), 20_000);
+    let irrelevant = tx(OutPoint::new(h!("theirs"), 1), 10_000);
+    let block = Block {
+        header: BlockHeader {
+            version: 1,
+            prev_blockhash: h!("prev"),
+            merkle_root: Hash::all_zeros(),
+            time: 0,
+            bits: 0,
+            nonce: 0,
+        },
+        txdata: vec![
+            marked.clone(),
+            spends_ours.clone(),
+            irrelevant.clone(),
+            spends_spend.clone(),
+        ],
+    };
+
+    let additions = graph.scan_block(&block, |tx| tx.output[0].value == 20_000);
+    assert_eq!(
+        additions.tx,
+        [marked.clone(), spends_ours.clone(), spends_spend].into()
+    );
+    assert!(additions.txout.is_empty());
+    assert_eq!(
+        graph.outspends(ours).iter().collect::<Vec<_>>(),
+        [&spends_ours.txid()]
+    );
+    assert!(graph.get_tx(irrelevant.txid()).is_none());
+
+    // transactions already in the graph aren't added again
+    assert!(graph
+        .scan_block_preview(&block, |tx| tx.output[0].value == 20_000)
+        .is_empty());
+    assert_eq!(graph.get_tx(marked.txid()), Some(&marked));
+}
@@ -0,0 +1,11 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        // so building doesn't need `protoc` to be installed
+        std::env::set_var(
+            "PROTOC",
+            protoc_bin_vendored::protoc_bin_path().expect("protoc is vendored for this platform"),
+        );
+        tonic_build::compile_protos("proto/tracker.proto").expect("tracker.proto compiles");
+    }
+}
@@ -0,0 +1,98 @@
+//! The log of what the wallet signed.
+use crate::{load_extension, save_extension, Keychain};
+use anyhow::Result;
+use bdk_chain::{
+    bitcoin::{OutPoint, Txid},
+    keychain::{KeychainTracker, PersistBackend},
+    sparse_chain::ChainPosition,
+};
+use std::{
+    fmt::Debug,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The name of the extension blob the [`AuditLog`] is saved under.
+pub const AUDIT_EXTENSION: &str = "audit";
+
+/// Every time the wallet's keys signed a transaction, oldest first. Entries are only ever added.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct AuditLog {
+    pub events: Vec<SigningEvent>,
+}
+
+/// The wallet's keys signing some of the inputs of a transaction.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct SigningEvent {
+    pub txid: Txid,
+    /// When the inputs were signed (seconds since the unix epoch)
+    pub timestamp: u64,
+    pub inputs: Vec<SignedInput>,
+}
+
+/// An input spending the output of the wallet's script pubkey at `index` of `keychain`.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct SignedInput {
+    pub outpoint: OutPoint,
+    pub keychain: Keychain,
+    pub index: u32,
+}
+
+/// Appends the signing of the inputs of `tx` that spend `outpoints` to the [`AuditLog`] in `store`.
+/// Outpoints that aren't the wallet's are left out.
+pub fn audit_signing<P, S>(
+    store: &mut S,
+    tracker: &KeychainTracker<Keychain, P>,
+    txid: Txid,
+    outpoints: impl IntoIterator<Item = OutPoint>,
+) -> Result<()>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let inputs = outpoints
+        .into_iter()
+        .filter_map(|outpoint| {
+            let txout = tracker.graph().get_txout(outpoint)?;
+            let (keychain, index) = tracker.txout_index.index_of_spk(&txout.script_pubkey)?;
+            Some(SignedInput {
+                outpoint,
+                keychain,
+                index,
+            })
+        })
+        .collect::<Vec<_>>();
+    if inputs.is_empty() {
+        return Ok(());
+    }
+    let mut log = load_extension::<AuditLog, _, _>(store, AUDIT_EXTENSION)?.unwrap_or_default();
+    log.events.push(SigningEvent {
+        txid,
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        inputs,
+    });
+    save_extension(store, AUDIT_EXTENSION, &log)
+}
+
+/// Lists the [`SigningEvent`]s of the [`AuditLog`], only those of `txid` or those since `since`
+/// (seconds since the unix epoch) if given.
+pub fn run_audit_cmd<P, S>(
+    store: &mut S,
+    txid: Option<Txid>,
+    since: Option<u64>,
+) -> Result<Vec<SigningEvent>>
+where
+    S: PersistBackend<Keychain, P>,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let log = load_extension::<AuditLog, _, _>(store, AUDIT_EXTENSION)?.unwrap_or_default();
+    Ok(log
+        .events
+        .into_iter()
+        .filter(|event| {
+            !matches!(txid, Some(txid) if txid != event.txid)
+                && !matches!(since, Some(since) if event.timestamp < since)
+        })
+        .collect())
+}
@@ -0,0 +1,213 @@
+//! Keeping track of the PSBTs that are waiting for cosigners.
+use crate::{
+    broadcast_and_store, finalize_psbt, is_watch_only, load_extension, missing_auth, psbt_plans,
+    save_extension, sign_wallet_inputs, spending_assets, Broadcast, CommandOutput, CosignInput,
+    CosignOutput, CosignStatus, Keychain, Signer,
+};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{
+        consensus::encode::{deserialize, serialize},
+        util::psbt::PartiallySignedTransaction as Psbt,
+        Txid,
+    },
+    keychain::{KeychainTracker, PersistBackend},
+    miniscript::DescriptorPublicKey,
+    sparse_chain::ChainPosition,
+};
+use clap::Subcommand;
+use std::{collections::BTreeMap, fmt::Debug, path::PathBuf, str::FromStr};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CosignCmd {
+    /// Sign a PSBT made with `psbt create --cosigner` with the wallet's keys and keep it until
+    /// the cosigners have signed it too
+    Start {
+        /// The PSBT in base64
+        psbt: Psbt,
+    },
+    /// Show a pending PSBT in base64 to pass it on to the cosigners
+    Export {
+        txid: Txid,
+        /// Write the PSBT to this file (in binary) instead
+        #[clap(long)]
+        file: Option<PathBuf>,
+    },
+    /// Merge the signatures of a PSBT signed by a cosigner into the pending PSBT of the same
+    /// transaction
+    Import {
+        /// The PSBT in base64
+        #[clap(required_unless_present = "file")]
+        psbt: Option<Psbt>,
+        /// Read the PSBT from this file (in binary or base64) instead
+        #[clap(long, conflicts_with = "psbt")]
+        file: Option<PathBuf>,
+    },
+    /// List the pending PSBTs and the signatures each of their inputs still needs
+    Status,
+    /// Finalize a pending PSBT once all of its inputs have the signatures they need and
+    /// broadcast the transaction
+    Finalize {
+        txid: Txid,
+        /// Show the transaction instead of broadcasting it
+        #[clap(long)]
+        no_broadcast: bool,
+    },
+    /// Stop collecting signatures for a pending PSBT
+    Cancel { txid: Txid },
+}
+
+/// The name of the extension blob the [`PendingCosigns`] are saved under.
+pub const COSIGN_EXTENSION: &str = "cosign";
+
+/// The PSBTs started with `cosign start` that are waiting for signatures of cosigners.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct PendingCosigns {
+    /// The PSBTs in base64 by the txid of their transaction
+    pub psbts: BTreeMap<Txid, String>,
+}
+
+impl PendingCosigns {
+    pub fn get(&self, txid: Txid) -> Result<Psbt> {
+        let psbt = self
+            .psbts
+            .get(&txid)
+            .ok_or_else(|| anyhow!("there is no pending PSBT of transaction {}", txid))?;
+        Ok(Psbt::from_str(psbt)?)
+    }
+
+    pub fn insert(&mut self, psbt: &Psbt) {
+        self.psbts.insert(psbt.unsigned_tx.txid(), psbt.to_string());
+    }
+}
+
+pub fn run_cosign_cmd<P, S>(
+    cosign_cmd: CosignCmd,
+    client: &impl Broadcast,
+    tracker: &mut KeychainTracker<Keychain, P>,
+    store: &mut S,
+    signers: &[Box<dyn Signer>],
+) -> Result<CommandOutput<P>>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let assets = spending_assets(tracker, signers);
+    let mut pending =
+        load_extension::<PendingCosigns, _, _>(store, COSIGN_EXTENSION)?.unwrap_or_default();
+    let psbt = match cosign_cmd {
+        CosignCmd::Start { mut psbt } => {
+            if !is_watch_only(signers) {
+                sign_wallet_inputs(&mut psbt, tracker, store, signers, &assets)?;
+            }
+            pending.insert(&psbt);
+            save_extension(store, COSIGN_EXTENSION, &pending)?;
+            psbt
+        }
+        CosignCmd::Export { txid, file } => {
+            let psbt = pending.get(txid)?;
+            return Ok(match file {
+                Some(file) => {
+                    std::fs::write(&file, serialize(&psbt))?;
+                    CommandOutput::Cosign(CosignOutput::Exported { txid, file })
+                }
+                None => CommandOutput::Psbt(psbt),
+            });
+        }
+        CosignCmd::Import { psbt, file } => {
+            let psbt = match (psbt, file) {
+                (Some(psbt), _) => psbt,
+                (None, Some(file)) => {
+                    let bytes = std::fs::read(&file)?;
+                    match bytes.starts_with(b"psbt\xff") {
+                        true => deserialize(&bytes)?,
+                        false => Psbt::from_str(std::str::from_utf8(&bytes)?.trim())?,
+                    }
+                }
+                (None, None) => unreachable!("clap requires one of them"),
+            };
+            let mut combined = pending.get(psbt.unsigned_tx.txid())?;
+            combined.combine(psbt)?;
+            pending.insert(&combined);
+            save_extension(store, COSIGN_EXTENSION, &pending)?;
+            combined
+        }
+        CosignCmd::Status => {
+            let statuses = pending
+                .psbts
+                .keys()
+                .map(|txid| Ok(cosign_status(&pending.get(*txid)?, tracker, &assets)))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(CommandOutput::Cosign(CosignOutput::Status(statuses)));
+        }
+        CosignCmd::Finalize { txid, no_broadcast } => {
+            let mut psbt = pending.get(txid)?;
+            let plans = psbt_plans(&psbt, tracker, &assets);
+            let plans = plans.iter().map(Option::as_ref).collect::<Vec<_>>();
+            if missing_auth(&psbt, &plans)
+                .iter()
+                .flatten()
+                .any(|missing| !missing.is_empty())
+            {
+                return Err(anyhow!(
+                    "the PSBT still needs signatures:\n{}",
+                    cosign_status(&psbt, tracker, &assets)
+                ));
+            }
+            finalize_psbt(&mut psbt, &plans)?;
+            if let Some(i) = psbt.inputs.iter().position(|input| {
+                input.final_script_sig.is_none() && input.final_script_witness.is_none()
+            }) {
+                return Err(anyhow!(
+                    "input {} doesn't spend a coin of the wallet and hasn't been finalized",
+                    i
+                ));
+            }
+            let transaction = psbt.extract_tx();
+            if no_broadcast {
+                return Ok(CommandOutput::Transaction(transaction));
+            }
+            broadcast_and_store(client, tracker, store, &transaction)?;
+            pending.psbts.remove(&txid);
+            save_extension(store, COSIGN_EXTENSION, &pending)?;
+            return Ok(CommandOutput::Broadcasted(transaction.txid()));
+        }
+        CosignCmd::Cancel { txid } => {
+            if pending.psbts.remove(&txid).is_none() {
+                return Err(anyhow!("there is no pending PSBT of transaction {}", txid));
+            }
+            save_extension(store, COSIGN_EXTENSION, &pending)?;
+            return Ok(CommandOutput::Cosign(CosignOutput::Cancelled(txid)));
+        }
+    };
+    let status = cosign_status(&psbt, tracker, &assets);
+    Ok(CommandOutput::Cosign(CosignOutput::Updated {
+        psbt,
+        status,
+    }))
+}
+
+/// Which signatures (and pre-images) each input of `psbt` still needs.
+fn cosign_status<P: ChainPosition>(
+    psbt: &Psbt,
+    tracker: &KeychainTracker<Keychain, P>,
+    assets: &bdk_tmp_plan::Assets<DescriptorPublicKey>,
+) -> CosignStatus {
+    let plans = psbt_plans(psbt, tracker, assets);
+    let plans = plans.iter().map(Option::as_ref).collect::<Vec<_>>();
+    CosignStatus {
+        txid: psbt.unsigned_tx.txid(),
+        inputs: psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .zip(missing_auth(psbt, &plans))
+            .map(|(txin, missing)| CosignInput {
+                outpoint: txin.previous_output,
+                missing,
+            })
+            .collect(),
+    }
+}
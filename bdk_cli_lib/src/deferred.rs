@@ -0,0 +1,156 @@
+//! Transactions that are only broadcast later.
+use crate::{
+    load_extension, save_extension, Broadcast, DeferredOutput, DeferredStatus, Keychain, QueuedTx,
+};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{consensus::encode::deserialize, hashes::hex::FromHex, LockTime, Transaction, Txid},
+    deferred::{BroadcastAfter, DeferredQueue},
+    keychain::{KeychainTracker, PersistBackend},
+    sparse_chain::ChainPosition,
+};
+use clap::Subcommand;
+use std::{collections::BTreeSet, fmt::Debug};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DeferredCmd {
+    /// Queue a transaction. Without `--height` or `--confirmations` it is broadcast once its
+    /// locktime allows.
+    Add {
+        /// The signed transaction in hex
+        tx: String,
+        /// The height of the first block the transaction can be mined in
+        #[clap(long, conflicts_with = "confirmations")]
+        height: Option<u32>,
+        /// The number of confirmations the transaction given with `--of` needs before the
+        /// transaction can be mined
+        #[clap(long, requires = "of")]
+        confirmations: Option<u32>,
+        /// The transaction `--confirmations` refers to
+        #[clap(long, requires = "confirmations")]
+        of: Option<Txid>,
+    },
+    /// Take a transaction out of the queue
+    Remove { txid: Txid },
+    /// List the queued transactions and what they are waiting for
+    List,
+}
+
+/// The name of the extension blob the [`DeferredQueue`] is saved under.
+pub const DEFERRED_EXTENSION: &str = "deferred";
+
+pub fn run_deferred_cmd<P, S>(
+    deferred_cmd: DeferredCmd,
+    keychain_tracker: &KeychainTracker<Keychain, P>,
+    store: &mut S,
+) -> Result<DeferredOutput>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let mut queue =
+        load_extension::<DeferredQueue, _, _>(store, DEFERRED_EXTENSION)?.unwrap_or_default();
+    match deferred_cmd {
+        DeferredCmd::Add {
+            tx,
+            height,
+            confirmations,
+            of,
+        } => {
+            let tx = deserialize::<Transaction>(&Vec::<u8>::from_hex(&tx)?)?;
+            let after = match (height, confirmations.zip(of)) {
+                (Some(height), _) => BroadcastAfter::Height(height),
+                (None, Some((confirmations, txid))) => BroadcastAfter::Confirmations {
+                    txid,
+                    confirmations,
+                },
+                (None, None) => match LockTime::from(tx.lock_time) {
+                    // the locktime is the last height the transaction can't be mined at
+                    LockTime::Blocks(height) if tx.is_lock_time_enabled() => {
+                        BroadcastAfter::Height(height.to_consensus_u32() + 1)
+                    }
+                    _ => {
+                        return Err(anyhow!(
+                        "the transaction has no height locktime, use --height or --confirmations"
+                    ))
+                    }
+                },
+            };
+            let txid = tx.txid();
+            let replaced = queue.insert(tx, after).is_some();
+            save_extension(store, DEFERRED_EXTENSION, &queue)?;
+            Ok(DeferredOutput::Queued { txid, replaced })
+        }
+        DeferredCmd::Remove { txid } => {
+            if queue.remove(txid).is_none() {
+                return Err(anyhow!("{} isn't queued", txid));
+            }
+            save_extension(store, DEFERRED_EXTENSION, &queue)?;
+            Ok(DeferredOutput::Removed(txid))
+        }
+        DeferredCmd::List => {
+            let chain = keychain_tracker.chain();
+            let due = queue
+                .due(chain)
+                .map(|deferred| deferred.tx.txid())
+                .collect::<BTreeSet<_>>();
+            let queued = queue
+                .iter()
+                .map(|deferred| {
+                    let txid = deferred.tx.txid();
+                    let status = match (deferred.after.height(chain), deferred.after) {
+                        _ if due.contains(&txid) => DeferredStatus::Due,
+                        _ if chain.tx_position(txid).is_some() => DeferredStatus::Broadcast,
+                        (Some(height), _) => DeferredStatus::WaitingForHeight(height),
+                        (None, BroadcastAfter::Confirmations { txid, .. }) => {
+                            DeferredStatus::WaitingFor(txid)
+                        }
+                        (None, BroadcastAfter::Height(_)) => unreachable!("always has a height"),
+                    };
+                    QueuedTx { txid, status }
+                })
+                .collect();
+            Ok(DeferredOutput::List(queued))
+        }
+    }
+}
+
+/// Broadcasts the transactions of the [`DeferredQueue`] that are due at the tip of the tracker's
+/// chain and drops the ones that have confirmed from it. Call it after each chain update.
+///
+/// A failed broadcast is only reported since the transaction is tried again after the next
+/// update. Returns the txids that were broadcast.
+pub fn broadcast_deferred<P, S>(
+    client: &impl Broadcast,
+    keychain_tracker: &KeychainTracker<Keychain, P>,
+    store: &mut S,
+) -> Result<Vec<Txid>>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let mut queue = match load_extension::<DeferredQueue, _, _>(store, DEFERRED_EXTENSION)? {
+        Some(queue) => queue,
+        None => return Ok(vec![]),
+    };
+    let chain = keychain_tracker.chain();
+    let confirmed = queue.remove_confirmed(chain);
+
+    let mut broadcast = vec![];
+    for deferred in queue.due(chain) {
+        let txid = deferred.tx.txid();
+        match client.broadcast(&deferred.tx) {
+            Ok(()) => broadcast.push(txid),
+            Err(e) => eprintln!("failed to broadcast deferred transaction {}: {}", txid, e),
+        }
+    }
+
+    if !confirmed.is_empty() {
+        save_extension(store, DEFERRED_EXTENSION, &queue)?;
+    }
+    Ok(broadcast)
+}
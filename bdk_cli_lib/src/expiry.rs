@@ -0,0 +1,193 @@
+//! Transactions that left the mempool and abandoning the ones that never confirm.
+use crate::{load_extension, save_extension, Keychain};
+use anyhow::Result;
+use bdk_chain::{
+    bitcoin::{Transaction, Txid},
+    keychain::{KeychainChangeSet, KeychainTracker, PersistBackend},
+    sparse_chain::ChainPosition,
+    TxHeight,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The name of the extension blob the [`EvictedTxs`] ledger is saved under.
+pub const EVICTED_EXTENSION: &str = "evicted";
+
+/// The transactions that were evicted from the wallet's chain, kept so we can tell what happened
+/// to a transaction that disappeared (see `tx evicted`).
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct EvictedTxs {
+    /// The last eviction of each transaction (a transaction can come back and be evicted again)
+    pub txs: BTreeMap<Txid, EvictedTx>,
+}
+
+/// A transaction evicted from the wallet's chain.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct EvictedTx {
+    pub tx: Transaction,
+    /// Where the transaction was before it was evicted
+    pub height: TxHeight,
+    /// The transactions that replaced it by spending the same coins. Empty if it was dropped
+    /// (e.g. abandoned or no longer in the mempool) or evicted along with a transaction it spends.
+    pub conflicts: BTreeSet<Txid>,
+    /// The height of the tip of the wallet's chain at the time
+    pub tip_height: Option<u32>,
+    /// When it was evicted as a unix timestamp
+    pub evicted_at: u64,
+}
+
+/// Records the transactions `changeset` evicts from the chain in the [`EvictedTxs`] ledger,
+/// along with the ones replacing them. Call it before `changeset` is applied to
+/// `keychain_tracker`.
+pub fn record_evictions<P, S>(
+    keychain_tracker: &KeychainTracker<Keychain, P>,
+    store: &mut S,
+    changeset: &KeychainChangeSet<Keychain, P>,
+) -> Result<()>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let chain_graph = keychain_tracker.chain_graph();
+    let evictions = chain_graph.evictions(&changeset.chain_graph);
+    if evictions.is_empty() {
+        return Ok(());
+    }
+    let mut evicted =
+        load_extension::<EvictedTxs, _, _>(store, EVICTED_EXTENSION)?.unwrap_or_default();
+    let evicted_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let tip_height = keychain_tracker
+        .chain()
+        .latest_checkpoint()
+        .map(|block_id| block_id.height);
+    for (txid, conflicts) in evictions {
+        // without the full transaction there is nothing to answer questions with
+        let (position, tx) = match (
+            chain_graph.chain().tx_position(txid),
+            chain_graph.graph().get_tx(txid),
+        ) {
+            (Some(position), Some(tx)) => (position, tx),
+            _ => continue,
+        };
+        evicted.txs.insert(
+            txid,
+            EvictedTx {
+                tx: tx.clone(),
+                height: position.height(),
+                conflicts,
+                tip_height,
+                evicted_at,
+            },
+        );
+    }
+    save_extension(store, EVICTED_EXTENSION, &evicted)?;
+    Ok(())
+}
+
+/// The name of the extension blob [`SentTxs`] are saved under.
+pub const SENT_EXTENSION: &str = "sent";
+
+/// The transactions the wallet broadcast itself along with the height of the chain's tip at the
+/// time, so that the ones that never confirm can be abandoned (see [`ExpiryPolicy`]).
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct SentTxs {
+    pub txs: BTreeMap<Txid, u32>,
+}
+
+/// The name of the extension blob the [`ExpiryPolicy`] is saved under.
+pub const EXPIRY_EXTENSION: &str = "expiry";
+
+/// When the wallet gives up on its own transactions that don't confirm.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ExpiryPolicy {
+    /// Abandon a transaction that is still unconfirmed this many blocks after it was broadcast
+    /// (never if `None`)
+    pub after_blocks: Option<u32>,
+}
+
+impl ExpiryPolicy {
+    /// The transactions of `sent` that are still unconfirmed and have expired at the tip of the
+    /// tracker's chain.
+    pub fn expired<P: ChainPosition>(
+        &self,
+        sent: &SentTxs,
+        tracker: &KeychainTracker<Keychain, P>,
+    ) -> Vec<Txid> {
+        let (after_blocks, tip_height) =
+            match (self.after_blocks, tracker.chain().latest_checkpoint()) {
+                (Some(after_blocks), Some(tip)) => (after_blocks, tip.height),
+                _ => return vec![],
+            };
+        sent.txs
+            .iter()
+            .filter(|(txid, &height)| {
+                matches!(
+                    tracker.chain().tx_position(**txid),
+                    Some(position) if !position.height().is_confirmed()
+                ) && tip_height.saturating_sub(height) >= after_blocks
+            })
+            .map(|(txid, _)| *txid)
+            .collect()
+    }
+}
+
+/// Abandons the wallet's transactions that have expired under the stored [`ExpiryPolicy`]. Call
+/// it after each chain update.
+///
+/// Returns the txids of the transactions that were abandoned (see [`abandon_txs`]).
+pub fn abandon_expired<P, S>(
+    tracker: &mut KeychainTracker<Keychain, P>,
+    store: &mut S,
+) -> Result<Vec<Txid>>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let policy = load_extension::<ExpiryPolicy, _, _>(store, EXPIRY_EXTENSION)?.unwrap_or_default();
+    if policy.after_blocks.is_none() {
+        return Ok(vec![]);
+    }
+    let sent = load_extension::<SentTxs, _, _>(store, SENT_EXTENSION)?.unwrap_or_default();
+    let expired = policy.expired(&sent, tracker);
+    abandon_txs(tracker, store, &expired)?;
+    Ok(expired)
+}
+
+/// Evicts the unconfirmed transactions `txids` and their descendants from the chain and stores
+/// the eviction, so the coins they spend can be spent again.
+///
+/// They are no longer counted as sent by the wallet so they don't expire again. Note that a
+/// transaction still in the chain source's mempool comes back with the next sync unless it is
+/// replaced.
+pub fn abandon_txs<P, S>(
+    tracker: &mut KeychainTracker<Keychain, P>,
+    store: &mut S,
+    txids: &[Txid],
+) -> Result<()>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    if txids.is_empty() {
+        return Ok(());
+    }
+    let changeset = tracker.evict_txs_preview(txids.iter().copied())?;
+    store.append_changeset(&changeset)?;
+    record_evictions(tracker, store, &changeset)?;
+    tracker.apply_changeset(changeset);
+    let mut sent = load_extension::<SentTxs, _, _>(store, SENT_EXTENSION)?.unwrap_or_default();
+    for txid in txids {
+        sent.txs.remove(txid);
+    }
+    save_extension(store, SENT_EXTENSION, &sent)?;
+    Ok(())
+}
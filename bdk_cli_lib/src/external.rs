@@ -0,0 +1,280 @@
+//! Watching scripts that belong to other wallets (e.g. anchors and HTLCs).
+use crate::{
+    load_extension, save_extension, ExternalOutput, ExternalTxOut, ExternalTxOutStatus,
+    WatchedScript,
+};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::Script,
+    keychain::{KeychainTracker, PersistBackend},
+    sparse_chain::ChainPosition,
+    FullTxOut, SpkTxOutIndex,
+};
+use clap::Subcommand;
+use std::{collections::BTreeMap, fmt::Debug};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ExternalCmd {
+    /// Start watching a script pubkey
+    Add {
+        /// The script pubkey in hex
+        script_pubkey: Script,
+        /// What kind of output the script is for: `channel-close`, `anchor`, `htlc` or `other`
+        #[clap(long, default_value = "other")]
+        kind: ExternalScriptKind,
+        /// A description of the script, e.g. which channel it belongs to
+        #[clap(long, default_value = "")]
+        label: String,
+        /// The number of confirmations an output needs before we can spend it (`older`)
+        #[clap(long)]
+        csv: Option<u16>,
+        /// The height before which we can't spend the outputs (`after`)
+        #[clap(long)]
+        cltv: Option<u32>,
+        /// The number of confirmations after which someone else can spend an output too. Defaults
+        /// to 16 for anchors.
+        #[clap(long)]
+        expires_after: Option<u16>,
+    },
+    /// Stop watching a script pubkey
+    Remove {
+        /// The script pubkey in hex
+        script_pubkey: Script,
+    },
+    /// List the watched scripts and when their outputs can be spent
+    List,
+}
+
+/// The name of the extension blob [`ExternalScripts`] are saved under.
+pub const EXTERNAL_SCRIPTS_EXTENSION: &str = "external_scripts";
+
+/// The number of confirmations after which anyone can spend an anchor output (see BOLT 3).
+pub const ANCHOR_EXPIRY: u16 = 16;
+
+/// What an externally generated script pubkey is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum ExternalScriptKind {
+    /// An output of a channel's commitment (or closing) transaction paying to us
+    ChannelClose,
+    /// An anchor output of a commitment transaction that lets us bump its fee
+    Anchor,
+    /// An HTLC output of a commitment transaction
+    Htlc,
+    Other,
+}
+
+impl core::str::FromStr for ExternalScriptKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "channel-close" => ExternalScriptKind::ChannelClose,
+            "anchor" => ExternalScriptKind::Anchor,
+            "htlc" => ExternalScriptKind::Htlc,
+            "other" => ExternalScriptKind::Other,
+            unknown => return Err(anyhow!("unknown kind of script '{}'", unknown)),
+        })
+    }
+}
+
+impl core::fmt::Display for ExternalScriptKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExternalScriptKind::ChannelClose => write!(f, "channel-close"),
+            ExternalScriptKind::Anchor => write!(f, "anchor"),
+            ExternalScriptKind::Htlc => write!(f, "htlc"),
+            ExternalScriptKind::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// What we know about an externally generated script pubkey.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ScriptMetadata {
+    pub kind: ExternalScriptKind,
+    pub label: String,
+    /// The number of confirmations an output needs before we can spend it
+    pub csv: Option<u16>,
+    /// The height before which we can't spend the outputs
+    pub cltv: Option<u32>,
+    /// The number of confirmations after which someone else can spend an output too
+    pub expires_after: Option<u16>,
+}
+
+impl ScriptMetadata {
+    /// The window we can spend an output of the script in if it was confirmed at
+    /// `confirmation_height`.
+    ///
+    /// `None` if the output is unconfirmed but the window starts or ends relative to its
+    /// confirmation.
+    pub fn spend_window(&self, confirmation_height: Option<u32>) -> Option<SpendWindow> {
+        let relative = |confirmations: Option<u16>| match confirmations {
+            Some(confirmations) => {
+                confirmation_height.map(|height| Some(height + u32::from(confirmations)))
+            }
+            None => Some(None),
+        };
+        let csv_height = relative(self.csv)?;
+        let until = relative(self.expires_after)?;
+        Some(SpendWindow {
+            from: csv_height.max(self.cltv).unwrap_or(0),
+            until,
+        })
+    }
+}
+
+/// The heights of the blocks an output can be spent in by us alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpendWindow {
+    /// The first block a transaction spending the output can be in
+    pub from: u32,
+    /// The first block someone else could spend the output in (if they ever can)
+    pub until: Option<u32>,
+}
+
+/// The script pubkeys watched with the `external` command.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ExternalScripts {
+    pub scripts: BTreeMap<Script, ScriptMetadata>,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, serde::Deserialize, serde::Serialize,
+)]
+pub enum Keychain {
+    External,
+    Internal,
+    /// A descriptor added with the `import` command
+    Imported(u32),
+    /// The descriptor funds are being moved to with the `migrate` command
+    Migration,
+}
+
+/// The outputs of the watched `scripts` the tracker has found, along with their metadata.
+pub fn external_txouts<'a, P: ChainPosition>(
+    scripts: &'a ExternalScripts,
+    keychain_tracker: &'a KeychainTracker<Keychain, P>,
+) -> impl Iterator<Item = (&'a Script, &'a ScriptMetadata, FullTxOut<P>)> + 'a {
+    let mut index = SpkTxOutIndex::<Script>::default();
+    for script_pubkey in scripts.scripts.keys() {
+        index.insert_script_pubkey(script_pubkey.clone(), script_pubkey.clone());
+    }
+    index.scan(keychain_tracker);
+    let outpoints = index
+        .txouts()
+        .map(|(script_pubkey, outpoint, _)| (script_pubkey.clone(), outpoint))
+        .collect::<Vec<_>>();
+    outpoints
+        .into_iter()
+        .filter_map(move |(script_pubkey, outpoint)| {
+            let (script_pubkey, metadata) = scripts.scripts.get_key_value(&script_pubkey)?;
+            let full_txout = keychain_tracker.chain_graph().full_txout(outpoint)?;
+            Some((script_pubkey, metadata, full_txout))
+        })
+}
+
+pub fn run_external_cmd<P, S>(
+    external_cmd: ExternalCmd,
+    keychain_tracker: &KeychainTracker<Keychain, P>,
+    store: &mut S,
+) -> Result<ExternalOutput>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let mut scripts = load_extension::<ExternalScripts, _, _>(store, EXTERNAL_SCRIPTS_EXTENSION)?
+        .unwrap_or_default();
+    match external_cmd {
+        ExternalCmd::Add {
+            script_pubkey,
+            kind,
+            label,
+            csv,
+            cltv,
+            expires_after,
+        } => {
+            let expires_after = match kind {
+                ExternalScriptKind::Anchor => expires_after.or(Some(ANCHOR_EXPIRY)),
+                _ => expires_after,
+            };
+            let metadata = ScriptMetadata {
+                kind,
+                label,
+                csv,
+                cltv,
+                expires_after,
+            };
+            let updated = scripts
+                .scripts
+                .insert(script_pubkey.clone(), metadata)
+                .is_some();
+            save_extension(store, EXTERNAL_SCRIPTS_EXTENSION, &scripts)?;
+            Ok(ExternalOutput::Watching {
+                script_pubkey,
+                updated,
+            })
+        }
+        ExternalCmd::Remove { script_pubkey } => {
+            if scripts.scripts.remove(&script_pubkey).is_none() {
+                return Err(anyhow!("{} isn't being watched", script_pubkey));
+            }
+            save_extension(store, EXTERNAL_SCRIPTS_EXTENSION, &scripts)?;
+            Ok(ExternalOutput::Removed(script_pubkey))
+        }
+        ExternalCmd::List => {
+            let tip_height = keychain_tracker
+                .chain()
+                .latest_checkpoint()
+                .map(|block_id| block_id.height);
+            let mut txouts = BTreeMap::<&Script, Vec<_>>::new();
+            for (script_pubkey, _, full_txout) in external_txouts(&scripts, keychain_tracker) {
+                txouts.entry(script_pubkey).or_default().push(full_txout);
+            }
+
+            let mut watched = vec![];
+            for (script_pubkey, metadata) in &scripts.scripts {
+                let txouts = txouts
+                    .remove(script_pubkey)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|full_txout| {
+                        let status = match (
+                            full_txout.spent_by,
+                            metadata.spend_window(full_txout.chain_position.height().into()),
+                        ) {
+                            (Some((_, txid)), _) => ExternalTxOutStatus::SpentBy(txid),
+                            (None, None) => ExternalTxOutStatus::Unconfirmed,
+                            (None, Some(window)) => {
+                                // a transaction spending the output could be in the next block
+                                let next_height = tip_height.map_or(0, |height| height + 1);
+                                match window.until {
+                                    Some(until) if next_height >= until => {
+                                        ExternalTxOutStatus::Expired(until)
+                                    }
+                                    _ if next_height < window.from => {
+                                        ExternalTxOutStatus::LockedUntil(window.from)
+                                    }
+                                    Some(until) => ExternalTxOutStatus::SpendableUntil(until),
+                                    None => ExternalTxOutStatus::Spendable,
+                                }
+                            }
+                        };
+                        ExternalTxOut {
+                            outpoint: full_txout.outpoint,
+                            value: full_txout.txout.value,
+                            status,
+                        }
+                    })
+                    .collect();
+                watched.push(WatchedScript {
+                    script_pubkey: script_pubkey.clone(),
+                    metadata: metadata.clone(),
+                    txouts,
+                });
+            }
+            Ok(ExternalOutput::List(watched))
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Contributing to transactions funded together with other parties.
+use crate::{
+    check_maturity, described_utxos, is_settled, order_candidates, plan_change, sign_with_plans,
+    signer_assets, CoinSelectionAlgo, Keychain, Signer, TxBuilder,
+};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{LockTime, OutPoint, Sequence, Transaction, TxOut},
+    descriptor_ext::DescriptorExt,
+    funding::{FundingInput, FundingOutput, FundingTemplate, Role},
+    keychain::KeychainTracker,
+    miniscript::DescriptorPublicKey,
+    sparse_chain::ChainPosition,
+};
+use bdk_coin_select::{coin_select_bnb_cancellable, CoinSelector, CoinSelectorOpt, WeightedValue};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+/// The plans to sign our inputs of a [`FundingTemplate`] with keyed by the outpoint they spend.
+pub type FundingPlans = BTreeMap<OutPoint, bdk_tmp_plan::Plan<DescriptorPublicKey>>;
+
+/// Adds coins and a change output to `template` on behalf of `role` so that we put `value` into
+/// its shared outputs (e.g. the funding output of a dual-funded channel).
+///
+/// Our coins pay for the weight of everything we contribute at `feerate` (in sats per weight
+/// unit), see [`Contribution`]. They are picked with `builder.coin_select` and the change goes to
+/// the keychain of `builder.change_policy`. Coins whose plan needs a locktime the template doesn't
+/// have are left out.
+///
+/// Returns the plans to sign our inputs with once the counterparty has added theirs (see
+/// [`sign_funding_tx`]).
+///
+/// [`Contribution`]: bdk_chain::funding::Contribution
+pub fn contribute_to_funding<P: ChainPosition>(
+    template: &mut FundingTemplate,
+    role: Role,
+    value: u64,
+    feerate: f32,
+    builder: &TxBuilder,
+    keychain_tracker: &mut KeychainTracker<Keychain, P>,
+    signers: &[Box<dyn Signer>],
+) -> Result<FundingPlans> {
+    let assets = signer_assets(signers);
+    let tip_height = keychain_tracker
+        .chain()
+        .latest_checkpoint()
+        .map(|block_id| block_id.height);
+    let template_lock_time = LockTime::from(template.lock_time);
+    let already_spent = template
+        .inputs()
+        .map(|(_, input)| input.previous_output)
+        .collect::<BTreeSet<_>>();
+
+    let mut candidates = described_utxos(keychain_tracker, &assets)
+        .filter(|utxo| match &utxo.plan {
+            Some(plan) => plan.required_locktime().into_iter().all(|lock_time| {
+                lock_time.is_same_unit(template_lock_time)
+                    && lock_time.to_consensus_u32() <= template_lock_time.to_consensus_u32()
+            }),
+            None => false,
+        })
+        .filter(|utxo| !already_spent.contains(&utxo.full_txout.outpoint))
+        .filter(|utxo| check_maturity(utxo, &assets, tip_height).is_ok())
+        .filter(|utxo| is_settled(utxo, builder, keychain_tracker))
+        .collect::<Vec<_>>();
+    order_candidates(&mut candidates, &builder.coin_select, tip_height);
+
+    let wv_candidates = candidates
+        .iter()
+        .map(|utxo| {
+            let plan = utxo.plan.as_ref().expect("candidates have a plan");
+            WeightedValue::new(
+                utxo.full_txout.txout.value,
+                plan.expected_weight() as _,
+                plan.witness_version().is_some(),
+            )
+        })
+        .collect();
+
+    let change_keychain = builder
+        .change_policy
+        .change_keychain(keychain_tracker)
+        .ok_or_else(|| anyhow!("none of the keychains of the change policy exist"))?;
+    let change_descriptor = keychain_tracker
+        .txout_index
+        .keychains()
+        .get(&change_keychain)
+        .expect("must exist")
+        .clone();
+    let (change_index, change_script) = {
+        let (index, script) = keychain_tracker.txout_index.next_unused(&change_keychain);
+        (index, script.clone())
+    };
+    let change_plan = plan_change(
+        &change_descriptor.at_derivation_index(change_index),
+        &assets,
+    )
+    .ok_or_else(|| anyhow!("we can't spend the change keychain {}", change_keychain))?;
+    let change_output = TxOut {
+        value: 0,
+        script_pubkey: change_script,
+    };
+
+    // what we have contributed so far counts towards the value and its weight must be paid for
+    let contribution = template.contribution(role);
+    let cs_opts = CoinSelectorOpt {
+        target_value: Some(
+            (value + contribution.output_value).saturating_sub(contribution.input_value),
+        ),
+        target_feerate: feerate,
+        base_weight: contribution.weight,
+        min_drain_value: change_descriptor.dust_value(),
+        ..CoinSelectorOpt::fund_outputs(&[], &change_output, change_plan.expected_weight() as u32)
+    };
+    let mut coin_selector = CoinSelector::new(&wv_candidates, &cs_opts);
+    let selection = match builder.coin_select {
+        CoinSelectionAlgo::BranchAndBound => coin_select_bnb_cancellable(
+            Duration::from_secs(10),
+            coin_selector.clone(),
+            &builder.cancel,
+        )
+        .map_or_else(|| coin_selector.select_until_finished(), |cs| cs.finish()),
+        _ => coin_selector.select_until_finished(),
+    }
+    .map_err(|e| anyhow!(e))?;
+    let (_, selection_meta) = selection.best_strategy();
+
+    let mut plans = FundingPlans::new();
+    for utxo in selection.apply_selection(&candidates) {
+        let plan = utxo.plan.clone().expect("candidates have a plan");
+        let serial_id = template.next_serial_id(role);
+        template.add_input(
+            role,
+            serial_id,
+            FundingInput {
+                previous_output: utxo.full_txout.outpoint,
+                prevout: utxo.full_txout.txout.clone(),
+                sequence: plan
+                    .required_sequence()
+                    .unwrap_or(Sequence::ENABLE_RBF_NO_LOCKTIME),
+                satisfaction_weight: plan.expected_weight() as u32,
+            },
+        )?;
+        plans.insert(utxo.full_txout.outpoint, plan);
+    }
+
+    if let Some(drain_value) = selection_meta.drain_value {
+        let serial_id = template.next_serial_id(role);
+        template.add_output(
+            role,
+            serial_id,
+            FundingOutput {
+                txout: TxOut {
+                    value: drain_value,
+                    ..change_output
+                },
+                shared: false,
+            },
+        )?;
+    }
+
+    Ok(plans)
+}
+
+/// Signs our inputs of the transaction `template` describes with `signers`.
+///
+/// `plans` are the plans [`contribute_to_funding`] returned. The counterparty's inputs are left
+/// for them to sign.
+pub fn sign_funding_tx(
+    template: &FundingTemplate,
+    plans: &FundingPlans,
+    signers: &[Box<dyn Signer>],
+) -> Result<Transaction> {
+    let mut transaction = template.unsigned_tx();
+    let input_plans = transaction
+        .input
+        .iter()
+        .map(|txin| plans.get(&txin.previous_output))
+        .collect::<Vec<_>>();
+    sign_with_plans(
+        &mut transaction,
+        &input_plans,
+        &template.prevouts(),
+        signers,
+    )?;
+    Ok(transaction)
+}
@@ -0,0 +1,481 @@
+//! Serving the wallet over gRPC (see `proto/tracker.proto`).
+//!
+//! The wallet (its tracker, store, signers and chain source) stays on the thread that keeps it up
+//! to date, e.g. the one running the `watch` command of the Electrum example. [`serve`] starts the
+//! server on a thread of its own and returns the [`WalletRequests`] the wallet's thread answers
+//! with [`WalletRequests::handle_pending`] each time it gets around to it (e.g. on every poll). An
+//! RPC waits until then.
+//!
+//! Each RPC is run as one of the [`Commands`] through [`handle_commands`]. The `Events` RPC streams
+//! the [`WalletEvent`]s the wallet's thread passes to [`WalletRequests::publish`].
+// the RPCs fail with tonic's `Status`, which is what the conversions for them return too
+#![allow(clippy::result_large_err)]
+use crate::{
+    handle_commands, parse_sighash_type,
+    webhook::{EventKind, WalletEvent},
+    AddressCmd, AddressOutput, Broadcast, CoinSelectionAlgo, CommandOutput, Commands,
+    DepositPolicy, DustArgs, DustChange, EstimateFee, FeerateArg, InputSequence, InputSighashType,
+    Keychain, OrderingStrategy, Payment, Preimage, PreimageArgs, SighashArgs, Signer, SpendArgs,
+    TimelockArgs, TxOutCmd, TxOutFormat, TxOutOutput,
+};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{Network, OutPoint, Sequence, Txid},
+    keychain::{KeychainTracker, PersistBackend},
+    sparse_chain::ChainPosition,
+    TxHeight,
+};
+use std::{pin::Pin, str::FromStr, sync::mpsc};
+use tokio::sync::{broadcast, oneshot};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+/// The messages and services generated from `proto/tracker.proto`.
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("bdk.tracker.v1");
+}
+
+use proto::tracker_server::{Tracker, TrackerServer};
+
+/// How many events a client streaming them can fall behind before it is sent an error.
+pub const EVENTS_CAPACITY: usize = 1024;
+
+/// The RPCs don't need any chain specific commands.
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum NoChainCommands {}
+
+/// A command waiting for the wallet's thread to run it.
+struct PendingCommand<P> {
+    command: Commands<NoChainCommands>,
+    reply: oneshot::Sender<Result<CommandOutput<P>>>,
+}
+
+/// The implementation of the `Tracker` service, which passes the commands on to the wallet's
+/// thread.
+pub struct TrackerService<P> {
+    commands: mpsc::Sender<PendingCommand<P>>,
+    events: broadcast::Sender<WalletEvent>,
+}
+
+/// The requests the wallet's thread has to answer, and where it sends what happens to the wallet.
+pub struct WalletRequests<P> {
+    commands: mpsc::Receiver<PendingCommand<P>>,
+    events: broadcast::Sender<WalletEvent>,
+}
+
+/// Creates the service along with the end of it the wallet's thread holds on to.
+pub fn tracker_service<P>() -> (TrackerService<P>, WalletRequests<P>) {
+    let (commands_tx, commands_rx) = mpsc::channel();
+    let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+    let service = TrackerService {
+        commands: commands_tx,
+        events: events.clone(),
+    };
+    let requests = WalletRequests {
+        commands: commands_rx,
+        events,
+    };
+    (service, requests)
+}
+
+/// Serves the `Tracker` service on `listener` from a thread with its own runtime.
+///
+/// The server stops with an error (which the thread returns) if it fails. It otherwise runs until
+/// the process exits.
+pub fn serve<P>(
+    listener: std::net::TcpListener,
+) -> Result<(WalletRequests<P>, std::thread::JoinHandle<Result<()>>)>
+where
+    P: ChainPosition + Send + 'static,
+{
+    let (service, requests) = tracker_service();
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_io()
+        .build()?;
+    let server = std::thread::Builder::new()
+        .name("grpc".into())
+        .spawn(move || {
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                tonic::transport::Server::builder()
+                    .add_service(TrackerServer::new(service))
+                    .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener))
+                    .await
+                    .map_err(|e| anyhow!("the gRPC server failed: {}", e))
+            })
+        })?;
+    Ok((requests, server))
+}
+
+impl<P> WalletRequests<P>
+where
+    P: ChainPosition,
+{
+    /// Runs the commands of the RPCs that are waiting and sends back what they output.
+    ///
+    /// A command that fails is reported to the client that sent it, so the errors aren't returned
+    /// here. Nothing waits for more requests to come in.
+    pub fn handle_pending<S>(
+        &self,
+        client: &mut (impl Broadcast + EstimateFee),
+        tracker: &mut KeychainTracker<Keychain, P>,
+        store: &mut S,
+        network: Network,
+        signers: &[Box<dyn Signer>],
+    ) where
+        S: PersistBackend<Keychain, P>,
+        S::WriteError: std::error::Error + Send + Sync + 'static,
+        S::LoadError: std::error::Error + Send + Sync + 'static,
+    {
+        while let Ok(pending) = self.commands.try_recv() {
+            let output = handle_commands(
+                pending.command,
+                &mut *client,
+                tracker,
+                store,
+                network,
+                signers,
+            );
+            // the client may have given up on the RPC
+            let _ = pending.reply.send(output);
+        }
+    }
+
+    /// Streams `events` to the clients of the `Events` RPC that asked for them.
+    pub fn publish(&self, events: &[WalletEvent]) {
+        for event in events {
+            // it's fine for no one to be listening
+            let _ = self.events.send(event.clone());
+        }
+    }
+}
+
+impl<P> TrackerService<P>
+where
+    P: Send + 'static,
+{
+    /// Has the wallet's thread run `command` and waits for what it outputs.
+    async fn run(&self, command: Commands<NoChainCommands>) -> Result<CommandOutput<P>, Status> {
+        let (reply, output) = oneshot::channel();
+        self.commands
+            .send(PendingCommand { command, reply })
+            .map_err(|_| Status::unavailable("the wallet is no longer being served"))?;
+        output
+            .await
+            .map_err(|_| Status::unavailable("the wallet is no longer being served"))?
+            .map_err(|e| Status::unknown(format!("{:#}", e)))
+    }
+}
+
+fn unexpected_output() -> Status {
+    Status::internal("the command output something the RPC doesn't return")
+}
+
+fn invalid_argument(e: anyhow::Error) -> Status {
+    Status::invalid_argument(format!("{:#}", e))
+}
+
+/// Parses `s` unless it is empty, in which case it is `default`.
+fn parse_or<T: FromStr>(s: &str, default: &str) -> Result<T, Status>
+where
+    T::Err: std::fmt::Display,
+{
+    let s = if s.is_empty() { default } else { s };
+    T::from_str(s).map_err(|e| Status::invalid_argument(e.to_string()))
+}
+
+impl TryFrom<proto::OutPoint> for OutPoint {
+    type Error = Status;
+
+    fn try_from(outpoint: proto::OutPoint) -> Result<Self, Self::Error> {
+        Ok(OutPoint {
+            txid: Txid::from_str(&outpoint.txid)
+                .map_err(|e| Status::invalid_argument(format!("invalid txid: {}", e)))?,
+            vout: outpoint.vout,
+        })
+    }
+}
+
+impl From<OutPoint> for proto::OutPoint {
+    fn from(outpoint: OutPoint) -> Self {
+        proto::OutPoint {
+            txid: outpoint.txid.to_string(),
+            vout: outpoint.vout,
+        }
+    }
+}
+
+fn outpoint(outpoint: Option<proto::OutPoint>) -> Result<OutPoint, Status> {
+    outpoint
+        .ok_or_else(|| Status::invalid_argument("the outpoint is missing"))?
+        .try_into()
+}
+
+impl From<WalletEvent> for proto::Event {
+    fn from(event: WalletEvent) -> Self {
+        use proto::event::Event;
+        let event = match event {
+            WalletEvent::DepositSeen {
+                outpoint,
+                keychain,
+                index,
+                value,
+            } => Event::DepositSeen(proto::Deposit {
+                outpoint: Some(outpoint.into()),
+                keychain: keychain.to_string(),
+                index,
+                value,
+                height: None,
+            }),
+            WalletEvent::DepositConfirmed {
+                outpoint,
+                keychain,
+                index,
+                value,
+                height,
+            } => Event::DepositConfirmed(proto::Deposit {
+                outpoint: Some(outpoint.into()),
+                keychain: keychain.to_string(),
+                index,
+                value,
+                height: Some(height),
+            }),
+            WalletEvent::TxEvicted { txid } => Event::TxEvicted(txid.to_string()),
+            WalletEvent::TxAbandoned { txid } => Event::TxAbandoned(txid.to_string()),
+            WalletEvent::Reorg { height } => Event::Reorg(height),
+        };
+        proto::Event { event: Some(event) }
+    }
+}
+
+impl proto::SendRequest {
+    /// The `send-many` command the request is for.
+    fn command(self) -> Result<Commands<NoChainCommands>, Status> {
+        let payments = self
+            .payments
+            .into_iter()
+            .map(|payment| {
+                Ok(Payment {
+                    recipient: payment.recipient.parse().map_err(invalid_argument)?,
+                    value: payment.value,
+                })
+            })
+            .collect::<Result<Vec<_>, Status>>()?;
+        if payments.is_empty() {
+            return Err(Status::invalid_argument("there are no payments"));
+        }
+        let change_keychains = self
+            .change_keychains
+            .iter()
+            .map(|keychain| Keychain::from_str(keychain).map_err(invalid_argument))
+            .collect::<Result<_, _>>()?;
+        let utxos = self
+            .utxos
+            .into_iter()
+            .map(OutPoint::try_from)
+            .collect::<Result<_, _>>()?;
+        let sequences = self
+            .sequences
+            .into_iter()
+            .map(|sequence| {
+                Ok(InputSequence {
+                    outpoint: outpoint(sequence.outpoint)?,
+                    sequence: Sequence(sequence.sequence),
+                })
+            })
+            .collect::<Result<_, Status>>()?;
+        let sighash = match self.sighash.as_str() {
+            "" => None,
+            sighash => Some(parse_sighash_type(sighash).map_err(invalid_argument)?),
+        };
+        let input_sighashes = self
+            .input_sighashes
+            .into_iter()
+            .map(|input| {
+                Ok(InputSighashType {
+                    outpoint: outpoint(input.outpoint)?,
+                    sighash_type: parse_sighash_type(&input.sighash).map_err(invalid_argument)?,
+                })
+            })
+            .collect::<Result<_, Status>>()?;
+        let preimages = self
+            .preimages
+            .iter()
+            .map(|preimage| Preimage::from_str(preimage).map_err(invalid_argument))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Commands::SendMany {
+            payments,
+            coin_select: parse_or::<CoinSelectionAlgo>(&self.coin_select, "largest-first")?,
+            change_keychains,
+            subtract_fee_from: self
+                .subtract_fee_from
+                .into_iter()
+                .map(|index| index as usize)
+                .collect(),
+            spend: SpendArgs {
+                utxos,
+                feerate: self.feerate.map(FeerateArg::SatPerVb),
+                spend_unconfirmed: self.spend_unconfirmed,
+                ordering: parse_or::<OrderingStrategy>(&self.ordering, "shuffle")?,
+                ..Default::default()
+            },
+            deposit_policy: DepositPolicy {
+                deposit_confirmations: self.deposit_confirmations,
+                deposit_checkpoint: self.deposit_checkpoint,
+            },
+            timelocks: TimelockArgs {
+                locktime: self.locktime,
+                sequences,
+                no_rbf: self.no_rbf,
+                ..Default::default()
+            },
+            dust: DustArgs {
+                dust_limit: self.dust_limit,
+                dust_change: parse_or::<DustChange>(&self.dust_change, "add-to-fee")?,
+            },
+            sighash: SighashArgs {
+                sighash,
+                input_sighashes,
+            },
+            preimages: PreimageArgs {
+                preimages,
+                preimages_file: None,
+            },
+        })
+    }
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl<P> Tracker for TrackerService<P>
+where
+    P: ChainPosition + Send + 'static,
+{
+    async fn get_balance(
+        &self,
+        request: Request<proto::GetBalanceRequest>,
+    ) -> Result<Response<proto::Balance>, Status> {
+        let request = request.into_inner();
+        let command = Commands::Balance {
+            min_feerate: request.min_feerate,
+            deposit_policy: DepositPolicy {
+                deposit_confirmations: request.deposit_confirmations,
+                deposit_checkpoint: request.deposit_checkpoint,
+            },
+        };
+        match self.run(command).await? {
+            CommandOutput::Balance {
+                confirmed,
+                unconfirmed,
+                at_risk,
+            } => Ok(Response::new(proto::Balance {
+                confirmed,
+                unconfirmed,
+                at_risk,
+            })),
+            _ => Err(unexpected_output()),
+        }
+    }
+
+    async fn derive_address(
+        &self,
+        request: Request<proto::DeriveAddressRequest>,
+    ) -> Result<Response<proto::Address>, Status> {
+        let addr_cmd = match request.into_inner().new {
+            true => AddressCmd::New,
+            false => AddressCmd::Next,
+        };
+        let command = Commands::Address {
+            addr_cmd,
+            dry_run: false,
+        };
+        match self.run(command).await? {
+            CommandOutput::Address(AddressOutput::Address { index, address }) => {
+                Ok(Response::new(proto::Address {
+                    index,
+                    address: address.to_string(),
+                }))
+            }
+            _ => Err(unexpected_output()),
+        }
+    }
+
+    async fn list_utxos(
+        &self,
+        _request: Request<proto::ListUtxosRequest>,
+    ) -> Result<Response<proto::ListUtxosResponse>, Status> {
+        let command = Commands::TxOut {
+            txout_cmd: TxOutCmd::List {
+                format: TxOutFormat::Text,
+                verbose: false,
+            },
+        };
+        let txouts = match self.run(command).await? {
+            CommandOutput::TxOuts(TxOutOutput::List(txouts)) => txouts,
+            _ => return Err(unexpected_output()),
+        };
+        let utxos = txouts
+            .into_iter()
+            .filter(|listed| listed.full_txout.spent_by.is_none())
+            .map(|listed| {
+                let (keychain, index) = listed.spk_index;
+                proto::Utxo {
+                    outpoint: Some(listed.full_txout.outpoint.into()),
+                    keychain: keychain.to_string(),
+                    index,
+                    value: listed.full_txout.txout.value,
+                    address: listed.address.to_string(),
+                    confirmation_height: match listed.full_txout.chain_position.height() {
+                        TxHeight::Confirmed(height) => Some(height),
+                        TxHeight::Unconfirmed => None,
+                    },
+                    frozen: listed.frozen,
+                }
+            })
+            .collect();
+        Ok(Response::new(proto::ListUtxosResponse { utxos }))
+    }
+
+    async fn send(
+        &self,
+        request: Request<proto::SendRequest>,
+    ) -> Result<Response<proto::SendResponse>, Status> {
+        use proto::send_response::Result as SendResult;
+        let command = request.into_inner().command()?;
+        let result = match self.run(command).await? {
+            CommandOutput::Broadcasted(txid) => SendResult::Txid(txid.to_string()),
+            CommandOutput::Psbt(psbt) => SendResult::Psbt(psbt.to_string()),
+            _ => return Err(unexpected_output()),
+        };
+        Ok(Response::new(proto::SendResponse {
+            result: Some(result),
+        }))
+    }
+
+    type EventsStream = EventStream;
+
+    async fn events(
+        &self,
+        request: Request<proto::EventsRequest>,
+    ) -> Result<Response<Self::EventsStream>, Status> {
+        let kinds = request
+            .into_inner()
+            .kinds
+            .iter()
+            .map(|kind| EventKind::from_str(kind).map_err(invalid_argument))
+            .collect::<Result<Vec<_>, _>>()?;
+        let events =
+            BroadcastStream::new(self.events.subscribe()).filter_map(move |event| match event {
+                Ok(event) if kinds.is_empty() || kinds.contains(&event.kind()) => {
+                    Some(Ok(event.into()))
+                }
+                Ok(_) => None,
+                Err(e) => Some(Err(Status::data_loss(e.to_string()))),
+            });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
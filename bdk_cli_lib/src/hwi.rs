@@ -0,0 +1,224 @@
+//! Signing with hardware wallets through [HWI].
+//!
+//! The devices are driven by the `hwi` command line tool (which has to be installed) so no
+//! secret key ever has to be given to the CLI. A [`HwiSigner`] is made for each connected device
+//! that has keys in the wallet's descriptors and signs whole PSBTs, so the device asks its user to
+//! confirm each transaction once.
+//!
+//! [HWI]: https://github.com/bitcoin-core/HWI
+use crate::Signer;
+use anyhow::{anyhow, Context, Result};
+use bdk_chain::{
+    bitcoin::{
+        util::{
+            bip32::{DerivationPath, ExtendedPubKey, Fingerprint},
+            psbt::PartiallySignedTransaction as Psbt,
+        },
+        Network,
+    },
+    miniscript::{
+        descriptor::{DescriptorPublicKey, SinglePubKey},
+        Descriptor, ForEachKey,
+    },
+};
+use serde::Deserialize;
+use std::{process::Command, str::FromStr};
+
+/// A hardware wallet `hwi enumerate` found.
+#[derive(Clone, Debug, Deserialize)]
+pub struct HwiDevice {
+    /// The kind of device (e.g. `trezor` or `ledger`)
+    #[serde(rename = "type")]
+    pub device_type: String,
+    /// The model of the device
+    #[serde(default)]
+    pub model: String,
+    /// The fingerprint of the device's master key. Missing if the device is locked.
+    pub fingerprint: Option<String>,
+}
+
+/// The response of `hwi signtx`.
+#[derive(Deserialize)]
+struct SignTxResponse {
+    /// The signed PSBT in base64
+    psbt: String,
+}
+
+/// The response of `hwi getxpub`.
+#[derive(Deserialize)]
+struct GetXpubResponse {
+    xpub: String,
+}
+
+/// Runs `hwi` with `args` and parses what it prints. HWI reports failures as a JSON object with
+/// an `error` field.
+fn run_hwi<T: serde::de::DeserializeOwned>(command: &str, args: &[&str]) -> Result<T> {
+    let output = Command::new(command)
+        .args(args)
+        .output()
+        .with_context(|| format!("failed to run `{}`", command))?;
+    let value = serde_json::from_slice::<serde_json::Value>(&output.stdout)
+        .with_context(|| format!("`{}` didn't print JSON", command))?;
+    if let Some(error) = value.get("error") {
+        return Err(anyhow!("hwi failed: {}", error));
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Lists the connected hardware wallets.
+pub fn enumerate(command: &str) -> Result<Vec<HwiDevice>> {
+    run_hwi(command, &["enumerate"])
+}
+
+/// The name HWI gives `network` in its `--chain` option.
+fn hwi_chain(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "main",
+        Network::Testnet => "test",
+        Network::Signet => "signet",
+        Network::Regtest => "regtest",
+    }
+}
+
+/// Signs with the hardware wallet with a given master key fingerprint.
+#[derive(Clone, Debug)]
+pub struct HwiSigner {
+    /// The `hwi` command to run
+    pub command: String,
+    /// The fingerprint of the device's master key
+    pub fingerprint: Fingerprint,
+    /// The network the device is told the transactions are for
+    pub network: Network,
+    /// The keys of the descriptors the device has
+    pub keys: Vec<DescriptorPublicKey>,
+}
+
+impl HwiSigner {
+    /// Makes a signer for the device with `fingerprint` that signs for its keys in `descriptors`.
+    ///
+    /// A key is the device's if its origin has the device's fingerprint and the device derives
+    /// the same key at its origin's derivation path, which is checked by asking it for the xpub
+    /// there.
+    pub fn new<'a>(
+        command: &str,
+        fingerprint: Fingerprint,
+        network: Network,
+        descriptors: impl IntoIterator<Item = &'a Descriptor<DescriptorPublicKey>>,
+    ) -> Result<Self> {
+        let mut signer = Self {
+            command: command.into(),
+            fingerprint,
+            network,
+            keys: vec![],
+        };
+        let mut candidates = vec![];
+        for descriptor in descriptors {
+            descriptor.for_each_key(|key| {
+                if key.master_fingerprint() == fingerprint && !candidates.contains(key) {
+                    candidates.push(key.clone());
+                }
+                true
+            });
+        }
+        for key in candidates {
+            if signer.has_key(&key)? {
+                signer.keys.push(key);
+            }
+        }
+        Ok(signer)
+    }
+
+    /// Whether the device derives `key` at the derivation path of its origin.
+    fn has_key(&self, key: &DescriptorPublicKey) -> Result<bool> {
+        let origin_path = match key {
+            DescriptorPublicKey::Single(single) => single.origin.as_ref().map(|(_, path)| path),
+            DescriptorPublicKey::XPub(xpub) => xpub.origin.as_ref().map(|(_, path)| path),
+        };
+        let path = origin_path
+            .cloned()
+            .unwrap_or_else(|| DerivationPath::from(vec![]));
+        let response: GetXpubResponse = self.run(&["getxpub", &path.to_string()])?;
+        let device_xpub = ExtendedPubKey::from_str(&response.xpub)?;
+        Ok(match key {
+            DescriptorPublicKey::XPub(xpub) => {
+                xpub.xkey.public_key == device_xpub.public_key
+                    && xpub.xkey.chain_code == device_xpub.chain_code
+            }
+            DescriptorPublicKey::Single(single) => match single.key {
+                SinglePubKey::FullKey(pk) => pk.inner == device_xpub.public_key,
+                SinglePubKey::XOnly(x_only) => {
+                    x_only == device_xpub.public_key.x_only_public_key().0
+                }
+            },
+        })
+    }
+
+    /// Runs `hwi` against the device.
+    fn run<T: serde::de::DeserializeOwned>(&self, args: &[&str]) -> Result<T> {
+        let fingerprint = self.fingerprint.to_string();
+        let mut all_args = vec![
+            "--chain",
+            hwi_chain(self.network),
+            "--fingerprint",
+            &fingerprint,
+        ];
+        all_args.extend(args);
+        run_hwi(&self.command, &all_args)
+    }
+}
+
+impl Signer for HwiSigner {
+    fn keys(&self) -> Vec<DescriptorPublicKey> {
+        self.keys.clone()
+    }
+
+    fn sign_input(
+        &self,
+        psbt: &mut Psbt,
+        input_index: usize,
+        plan: &bdk_tmp_plan::Plan<DescriptorPublicKey>,
+    ) -> Result<()> {
+        let mut plans = vec![None; psbt.inputs.len()];
+        plans[input_index] = Some(plan);
+        self.sign_psbt(psbt, &plans)
+    }
+
+    /// Sends the whole PSBT to the device and merges the signatures it adds. The device finds
+    /// its keys through the key origins of the inputs.
+    fn sign_psbt(
+        &self,
+        psbt: &mut Psbt,
+        plans: &[Option<&bdk_tmp_plan::Plan<DescriptorPublicKey>>],
+    ) -> Result<()> {
+        if self.keys.is_empty() || plans.iter().all(Option::is_none) {
+            return Ok(());
+        }
+        let response: SignTxResponse = self.run(&["signtx", &psbt.to_string()])?;
+        psbt.combine(Psbt::from_str(&response.psbt)?)?;
+        Ok(())
+    }
+}
+
+/// Makes a [`HwiSigner`] for each connected (and unlocked) device that has keys in
+/// `descriptors`.
+pub fn connected_signers<'a>(
+    command: &str,
+    network: Network,
+    descriptors: impl IntoIterator<Item = &'a Descriptor<DescriptorPublicKey>> + Clone,
+) -> Result<Vec<HwiSigner>> {
+    let mut signers = vec![];
+    for device in enumerate(command)? {
+        let fingerprint = match &device.fingerprint {
+            Some(fingerprint) => Fingerprint::from_str(fingerprint)?,
+            None => {
+                eprintln!("skipping locked {} {}", device.device_type, device.model);
+                continue;
+            }
+        };
+        let signer = HwiSigner::new(command, fingerprint, network, descriptors.clone())?;
+        if !signer.keys.is_empty() {
+            signers.push(signer);
+        }
+    }
+    Ok(signers)
+}
@@ -0,0 +1,147 @@
+//! Importing other descriptors into the wallet.
+use crate::{load_extension, save_extension, Keychain};
+use anyhow::Result;
+use bdk_chain::{
+    bitcoin::secp256k1::Secp256k1,
+    keychain::{KeychainTracker, PersistBackend},
+    miniscript::{Descriptor, DescriptorPublicKey},
+    sparse_chain::ChainPosition,
+};
+use std::fmt::Debug;
+
+/// A descriptor to import in the format of bitcoind's `importdescriptors` RPC.
+///
+/// The `timestamp` is kept but not used to bound the rescan since the chain sources we scan with
+/// look up the history of each script rather than scanning blocks. Other fields of the RPC (e.g.
+/// `label`) are ignored.
+///
+/// The imported descriptors are saved under [`IMPORTS_EXTENSION`] without their secret keys.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ImportDescriptor {
+    pub desc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<ImportRange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<serde_json::Value>,
+}
+
+/// The range of a ranged descriptor to import. Either the end of the range or `[start, end]`.
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum ImportRange {
+    End(u32),
+    StartEnd([u32; 2]),
+}
+
+impl ImportRange {
+    /// The default range of bitcoind's `importdescriptors` for ranged descriptors.
+    pub const DEFAULT: Self = ImportRange::StartEnd([0, 999]);
+
+    /// The first derivation index of the range.
+    pub fn start(&self) -> u32 {
+        match self {
+            ImportRange::End(_) => 0,
+            ImportRange::StartEnd([start, _]) => *start,
+        }
+    }
+
+    /// The last derivation index of the range (inclusive).
+    pub fn end(&self) -> u32 {
+        match self {
+            ImportRange::End(end) | ImportRange::StartEnd([_, end]) => *end,
+        }
+    }
+}
+
+/// The name of the extension blob with the [`ImportDescriptor`]s of the `import` command.
+pub const IMPORTS_EXTENSION: &str = "imports";
+
+/// Adds `imports` to the tracker as [`Keychain::Imported`] keychains, numbered after the ones it
+/// already has, and stores the scripts in their range.
+///
+/// Returns the added keychains.
+pub(crate) fn add_imports<P>(
+    tracker: &mut KeychainTracker<Keychain, P>,
+    imports: &[ImportDescriptor],
+) -> Result<Vec<Keychain>> {
+    let secp = Secp256k1::default();
+    let first = tracker
+        .txout_index
+        .keychains()
+        .keys()
+        .filter(|keychain| matches!(keychain, Keychain::Imported(_)))
+        .count() as u32;
+    let mut added = vec![];
+
+    for (n, import) in (first..).zip(imports) {
+        let (descriptor, _) =
+            Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, &import.desc)?;
+        let keychain = Keychain::Imported(n);
+        let range = match descriptor.has_wildcard() {
+            true => import.range.unwrap_or(ImportRange::DEFAULT),
+            false => ImportRange::End(0),
+        };
+        tracker.txout_index.add_keychain(keychain, descriptor);
+        tracker
+            .txout_index
+            .store_range(&keychain, range.start()..=range.end());
+        added.push(keychain);
+    }
+
+    Ok(added)
+}
+
+/// Imports the descriptors in `descriptors_json` (see [`ImportDescriptor`]) and saves them in
+/// the store so they are loaded by [`init`](crate::init) from then on.
+///
+/// Only the public form of each descriptor is saved. Its secret keys, if it has any, have to be
+/// given again with `--signing-key` (or come from `--mnemonic`) to sign for it. The scripts in the
+/// range of each descriptor are stored but the chain is not scanned. Returns the new keychains so
+/// the caller can rescan them.
+pub fn run_import_cmd<P, S>(
+    tracker: &mut KeychainTracker<Keychain, P>,
+    store: &mut S,
+    descriptors_json: &str,
+) -> Result<Vec<Keychain>>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+{
+    let secp = Secp256k1::default();
+    let imports = serde_json::from_str::<Vec<ImportDescriptor>>(descriptors_json)?
+        .into_iter()
+        .map(|import| {
+            let (descriptor, keymap) =
+                Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, &import.desc)?;
+            if !keymap.is_empty() {
+                eprintln!(
+                    "the secret keys of {} are not saved, give them with --signing-key to sign",
+                    descriptor
+                );
+            }
+            Ok(ImportDescriptor {
+                desc: descriptor.to_string(),
+                ..import
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let added = add_imports(tracker, &imports)?;
+
+    let mut all_imports = load_extension::<Vec<ImportDescriptor>, _, _>(store, IMPORTS_EXTENSION)?
+        .unwrap_or_default();
+    all_imports.extend(imports);
+    save_extension(store, IMPORTS_EXTENSION, &all_imports)?;
+    store.set_derivation_indices(tracker.txout_index.derivation_indices())?;
+
+    for keychain in &added {
+        eprintln!(
+            "imported {} as {}",
+            tracker.txout_index.keychains()[keychain],
+            keychain
+        );
+    }
+
+    Ok(added)
+}
@@ -0,0 +1,258 @@
+//! Invoices paid to fresh addresses of the wallet.
+use crate::{
+    load_extension, save_extension, DepositPolicy, InvoiceDetails, InvoiceOutput, InvoiceSummary,
+    Keychain,
+};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{Address, Network},
+    keychain::{ConfirmationPolicy, KeychainTracker, PersistBackend},
+    sparse_chain::ChainPosition,
+    FullTxOut,
+};
+use clap::Subcommand;
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum InvoiceCmd {
+    /// Create an invoice to be paid to a new address
+    Create {
+        /// The amount to be paid in sats
+        amount: u64,
+        /// What the payment is for
+        #[clap(long, default_value = "")]
+        memo: String,
+        /// The number of seconds the invoice can be paid in
+        #[clap(long, default_value = "3600")]
+        expiry: u64,
+        /// The confirmations the payment needs before the invoice counts as paid
+        #[clap(flatten)]
+        deposit_policy: DepositPolicy,
+    },
+    /// List the invoices and their status
+    List,
+    /// Show an invoice and the payments made to it
+    Status { id: u32 },
+}
+
+/// The name of the extension blob the [`Invoices`] are saved under.
+pub const INVOICES_EXTENSION: &str = "invoices";
+
+/// A request for a payment of `amount` to the external address at `index`.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Invoice {
+    /// The derivation index of the address in the external keychain
+    pub index: u32,
+    pub address: Address,
+    /// The amount to be paid in sats
+    pub amount: u64,
+    pub memo: String,
+    /// When the invoice was created (seconds since the unix epoch)
+    pub created_at: u64,
+    /// When the invoice can no longer be paid (seconds since the unix epoch)
+    pub expires_at: u64,
+    /// The confirmations a payment needs before it counts
+    pub policy: ConfirmationPolicy,
+    /// The status as of the last [`update_invoices`]
+    pub status: InvoiceStatus,
+}
+
+/// Whether an [`Invoice`] has been paid. `received` is the amount that has been paid to it in sats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum InvoiceStatus {
+    /// Nothing has been paid yet
+    Unpaid,
+    /// Less than the amount has been paid so far
+    Underpaid { received: u64 },
+    /// The amount has been paid but the payments don't meet the invoice's policy yet
+    Pending { received: u64 },
+    /// The amount has been paid and the payments meet the invoice's policy
+    Paid { received: u64 },
+    /// The invoice expired before the amount was paid
+    Expired { received: u64 },
+}
+
+impl core::fmt::Display for InvoiceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvoiceStatus::Unpaid => write!(f, "unpaid"),
+            InvoiceStatus::Underpaid { received } => write!(f, "underpaid ({} received)", received),
+            InvoiceStatus::Pending { received } => write!(f, "pending ({} received)", received),
+            InvoiceStatus::Paid { received } => write!(f, "paid ({} received)", received),
+            InvoiceStatus::Expired { received } => write!(f, "expired ({} received)", received),
+        }
+    }
+}
+
+impl Invoice {
+    /// The outputs paying the invoice.
+    pub fn payments<'a, P: ChainPosition>(
+        &self,
+        keychain_tracker: &'a KeychainTracker<Keychain, P>,
+    ) -> impl Iterator<Item = FullTxOut<P>> + 'a {
+        let index = (Keychain::External, self.index);
+        keychain_tracker
+            .full_txouts()
+            .filter(move |(spk_index, _)| **spk_index == index)
+            .map(|(_, full_txout)| full_txout)
+    }
+
+    /// The status of the invoice at the time `now` (in seconds since the unix epoch).
+    ///
+    /// A payment in full is accepted even if it came after the invoice expired.
+    pub fn status_at<P: ChainPosition>(
+        &self,
+        keychain_tracker: &KeychainTracker<Keychain, P>,
+        now: u64,
+    ) -> InvoiceStatus {
+        let (mut received, mut settled) = (0, 0);
+        for payment in self.payments(keychain_tracker) {
+            received += payment.txout.value;
+            let height = payment.chain_position.height();
+            if self
+                .policy
+                .is_settled(keychain_tracker.chain(), height, false)
+            {
+                settled += payment.txout.value;
+            }
+        }
+        if settled >= self.amount {
+            InvoiceStatus::Paid { received }
+        } else if received >= self.amount {
+            InvoiceStatus::Pending { received }
+        } else if now >= self.expires_at {
+            InvoiceStatus::Expired { received }
+        } else if received > 0 {
+            InvoiceStatus::Underpaid { received }
+        } else {
+            InvoiceStatus::Unpaid
+        }
+    }
+}
+
+/// The invoices made with the `invoice` command.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Invoices {
+    pub invoices: BTreeMap<u32, Invoice>,
+}
+
+impl Invoices {
+    /// Adds `invoice` and returns its id.
+    pub fn insert(&mut self, invoice: Invoice) -> u32 {
+        let id = self
+            .invoices
+            .keys()
+            .next_back()
+            .map_or(0, |last_id| last_id + 1);
+        self.invoices.insert(id, invoice);
+        id
+    }
+}
+
+pub fn run_invoice_cmd<P, S>(
+    invoice_cmd: InvoiceCmd,
+    keychain_tracker: &mut KeychainTracker<Keychain, P>,
+    store: &mut S,
+    network: Network,
+) -> Result<InvoiceOutput<P>>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut invoices =
+        load_extension::<Invoices, _, _>(store, INVOICES_EXTENSION)?.unwrap_or_default();
+    match invoice_cmd {
+        InvoiceCmd::Create {
+            amount,
+            memo,
+            expiry,
+            deposit_policy,
+        } => {
+            // each invoice gets an address that has never been given out
+            let (index, spk) = keychain_tracker.txout_index.derive_new(&Keychain::External);
+            let address = Address::from_script(spk, network)
+                .expect("should always be able to derive address");
+            let id = invoices.insert(Invoice {
+                index,
+                address: address.clone(),
+                amount,
+                memo,
+                created_at: now,
+                expires_at: now.saturating_add(expiry),
+                policy: deposit_policy.into(),
+                status: InvoiceStatus::Unpaid,
+            });
+            store.set_derivation_indices(keychain_tracker.txout_index.derivation_indices())?;
+            save_extension(store, INVOICES_EXTENSION, &invoices)?;
+            Ok(InvoiceOutput::Created { id, address })
+        }
+        InvoiceCmd::List => Ok(InvoiceOutput::List(
+            invoices
+                .invoices
+                .iter()
+                .map(|(id, invoice)| InvoiceSummary {
+                    id: *id,
+                    address: invoice.address.clone(),
+                    amount: invoice.amount,
+                    status: invoice.status_at(keychain_tracker, now),
+                    memo: invoice.memo.clone(),
+                })
+                .collect(),
+        )),
+        InvoiceCmd::Status { id } => {
+            let invoice = invoices
+                .invoices
+                .get(&id)
+                .ok_or_else(|| anyhow!("there is no invoice {}", id))?;
+            Ok(InvoiceOutput::Status(InvoiceDetails {
+                address: invoice.address.clone(),
+                amount: invoice.amount,
+                memo: invoice.memo.clone(),
+                created_at: invoice.created_at,
+                expires_at: invoice.expires_at,
+                status: invoice.status_at(keychain_tracker, now),
+                payments: invoice.payments(keychain_tracker).collect(),
+            }))
+        }
+    }
+}
+
+/// Works out the current status of each invoice and stores it. Call it after each chain update.
+///
+/// Returns the ids of the invoices whose status changed along with their new status, e.g. to
+/// notify the payer when their payment went through.
+pub fn update_invoices<P, S>(
+    keychain_tracker: &KeychainTracker<Keychain, P>,
+    store: &mut S,
+) -> Result<Vec<(u32, InvoiceStatus)>>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let mut invoices = match load_extension::<Invoices, _, _>(store, INVOICES_EXTENSION)? {
+        Some(invoices) => invoices,
+        None => return Ok(vec![]),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut changed = vec![];
+    for (id, invoice) in &mut invoices.invoices {
+        let status = invoice.status_at(keychain_tracker, now);
+        if status != invoice.status {
+            invoice.status = status;
+            changed.push((*id, status));
+        }
+    }
+    if !changed.is_empty() {
+        save_extension(store, INVOICES_EXTENSION, &invoices)?;
+    }
+    Ok(changed)
+}
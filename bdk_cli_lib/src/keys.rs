@@ -0,0 +1,266 @@
+//! Generating descriptors and deriving the keys to sign with.
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{
+        secp256k1::Secp256k1,
+        util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint},
+        Network,
+    },
+    miniscript::{
+        descriptor::{DescriptorSecretKey, DescriptorXKey, KeyMap, SinglePriv},
+        Descriptor, DescriptorPublicKey, ForEachKey,
+    },
+};
+use std::{fmt::Debug, str::FromStr};
+
+/// The script types wallets can be generated for (see [`generate_descriptors`]), each with the
+/// BIP that lays out how its keys are derived.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScriptType {
+    /// P2PKH (BIP 44). The wallet can receive to it but can't plan legacy spends yet.
+    Pkh,
+    /// P2WPKH nested in P2SH (BIP 49)
+    ShWpkh,
+    /// P2WPKH (BIP 84)
+    Wpkh,
+    /// P2TR spent with the key path (BIP 86)
+    #[default]
+    Tr,
+}
+
+impl ScriptType {
+    /// The purpose, the first (hardened) step of the derivation paths of the BIP.
+    pub fn purpose(&self) -> u32 {
+        match self {
+            ScriptType::Pkh => 44,
+            ScriptType::ShWpkh => 49,
+            ScriptType::Wpkh => 84,
+            ScriptType::Tr => 86,
+        }
+    }
+
+    /// A descriptor of this type with the single key `key`.
+    fn descriptor(&self, key: &str) -> String {
+        match self {
+            ScriptType::Pkh => format!("pkh({})", key),
+            ScriptType::ShWpkh => format!("sh(wpkh({}))", key),
+            ScriptType::Wpkh => format!("wpkh({})", key),
+            ScriptType::Tr => format!("tr({})", key),
+        }
+    }
+}
+
+impl core::str::FromStr for ScriptType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "pkh" | "bip44" => ScriptType::Pkh,
+            "sh-wpkh" | "bip49" => ScriptType::ShWpkh,
+            "wpkh" | "bip84" => ScriptType::Wpkh,
+            "tr" | "bip86" => ScriptType::Tr,
+            _ => {
+                return Err(anyhow!(
+                    "unknown script type '{}', expected pkh, sh-wpkh, wpkh or tr",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+/// The descriptors of a new wallet made by [`generate_descriptors`]. They all carry their
+/// checksum.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct GeneratedDescriptors {
+    /// The master key, set if it was generated rather than given. It is the only way to get the
+    /// wallet's coins back, so it has to be kept somewhere safe.
+    pub new_xprv: Option<ExtendedPrivKey>,
+    pub fingerprint: Fingerprint,
+    /// The receive descriptor with the account's secret key
+    pub descriptor: String,
+    /// The change descriptor with the account's secret key
+    pub change_descriptor: String,
+    /// The receive descriptor with the account's public key for a watch-only wallet
+    pub public_descriptor: String,
+    /// The change descriptor with the account's public key for a watch-only wallet
+    pub public_change_descriptor: String,
+}
+
+impl core::fmt::Display for GeneratedDescriptors {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if let Some(xprv) = &self.new_xprv {
+            writeln!(f, "master key (keep it safe): {}", xprv)?;
+        }
+        writeln!(f, "master fingerprint: {}", self.fingerprint)?;
+        writeln!(f, "descriptor: {}", self.descriptor)?;
+        writeln!(f, "change descriptor: {}", self.change_descriptor)?;
+        writeln!(f, "watch-only descriptor: {}", self.public_descriptor)?;
+        writeln!(
+            f,
+            "watch-only change descriptor: {}",
+            self.public_change_descriptor
+        )
+    }
+}
+
+/// Derives the receive and change descriptors of `account` of the `script_type` BIP from `xprv`,
+/// or from a new master key made from 32 random bytes if it isn't given.
+///
+/// The keys are those of `m/purpose'/coin_type'/account'` with their origin, so a signer holding
+/// only the master key (e.g. a hardware wallet) recognizes them.
+pub fn generate_descriptors(
+    script_type: ScriptType,
+    network: Network,
+    xprv: Option<ExtendedPrivKey>,
+    account: u32,
+) -> Result<GeneratedDescriptors> {
+    let secp = Secp256k1::default();
+    let new_xprv = match xprv {
+        Some(xprv) => {
+            // testnet, signet and regtest keys are all encoded the same way
+            if (xprv.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+                return Err(anyhow!(
+                    "the master key is for {} but the wallet is for {}",
+                    xprv.network,
+                    network
+                ));
+            }
+            None
+        }
+        None => {
+            let seed = rand::random::<[u8; 32]>();
+            Some(ExtendedPrivKey::new_master(network, &seed)?)
+        }
+    };
+    let master = xprv.or(new_xprv).expect("one of them is set");
+    let coin_type = match network {
+        Network::Bitcoin => 0,
+        _ => 1,
+    };
+    let path = DerivationPath::from_str(&format!(
+        "m/{}'/{}'/{}'",
+        script_type.purpose(),
+        coin_type,
+        account
+    ))?;
+    let fingerprint = master.fingerprint(&secp);
+    let account_xprv = master.derive_priv(&secp, &path)?;
+    let account_xpub = ExtendedPubKey::from_priv(&secp, &account_xprv);
+    // the origin is written without the `m/`
+    let origin = format!("[{}{}]", fingerprint, &path.to_string()[1..]);
+
+    let descriptors = |keychain: u32| -> Result<(String, String)> {
+        let (descriptor, keymap) = Descriptor::<DescriptorPublicKey>::parse_descriptor(
+            &secp,
+            &script_type.descriptor(&format!("{}{}/{}/*", origin, account_xprv, keychain)),
+        )?;
+        let public_descriptor = Descriptor::<DescriptorPublicKey>::from_str(
+            &script_type.descriptor(&format!("{}{}/{}/*", origin, account_xpub, keychain)),
+        )?;
+        Ok((
+            descriptor.to_string_with_secret(&keymap),
+            public_descriptor.to_string(),
+        ))
+    };
+    let (descriptor, public_descriptor) = descriptors(0)?;
+    let (change_descriptor, public_change_descriptor) = descriptors(1)?;
+    Ok(GeneratedDescriptors {
+        new_xprv,
+        fingerprint,
+        descriptor,
+        change_descriptor,
+        public_descriptor,
+        public_change_descriptor,
+    })
+}
+
+/// Parses a secret key given on its own, e.g. an xprv kept apart from a watch-only descriptor,
+/// along with the public key it signs for: the key with the same origin and derivation path.
+pub fn parse_signing_key(key: &str) -> Result<(DescriptorPublicKey, DescriptorSecretKey)> {
+    let secret_key = DescriptorSecretKey::from_str(key)?;
+    let public_key = secret_key.to_public(&Secp256k1::signing_only())?;
+    Ok((public_key, secret_key))
+}
+
+/// The master key of the seed of a BIP 39 `mnemonic` extended with `passphrase` (empty if there
+/// is none).
+pub fn mnemonic_master_key(
+    mnemonic: &str,
+    passphrase: &str,
+    network: Network,
+) -> Result<ExtendedPrivKey> {
+    let mnemonic =
+        bip39::Mnemonic::parse(mnemonic).map_err(|e| anyhow!("the mnemonic isn't valid: {}", e))?;
+    Ok(ExtendedPrivKey::new_master(
+        network,
+        &mnemonic.to_seed(passphrase),
+    )?)
+}
+
+/// The secret keys `master` derives for the keys of `descriptors` whose origin is `master`, each
+/// under the public key it signs for.
+///
+/// Fails if no key has `master` as its origin, e.g. because the passphrase of a mnemonic is
+/// wrong, or if a key with its fingerprint doesn't match what it derives.
+pub fn derive_keymap<'a>(
+    master: &ExtendedPrivKey,
+    descriptors: impl IntoIterator<Item = &'a Descriptor<DescriptorPublicKey>>,
+) -> Result<KeyMap> {
+    let secp = Secp256k1::default();
+    let fingerprint = master.fingerprint(&secp);
+    let mut keys = vec![];
+    for descriptor in descriptors {
+        descriptor.for_each_key(|key| {
+            if key.master_fingerprint() == fingerprint && !keys.contains(key) {
+                keys.push(key.clone());
+            }
+            true
+        });
+    }
+    if keys.is_empty() {
+        return Err(anyhow!(
+            "no key of the descriptors comes from the master key {}",
+            fingerprint
+        ));
+    }
+
+    let mut keymap = KeyMap::new();
+    for key in keys {
+        let secret_key = match &key {
+            DescriptorPublicKey::XPub(xpub) => {
+                let origin_path = xpub
+                    .origin
+                    .as_ref()
+                    .map(|(_, path)| path.clone())
+                    .unwrap_or_else(DerivationPath::master);
+                DescriptorSecretKey::XPrv(DescriptorXKey {
+                    origin: xpub.origin.clone(),
+                    xkey: master.derive_priv(&secp, &origin_path)?,
+                    derivation_path: xpub.derivation_path.clone(),
+                    wildcard: xpub.wildcard,
+                })
+            }
+            DescriptorPublicKey::Single(single) => {
+                let origin_path = single
+                    .origin
+                    .as_ref()
+                    .map(|(_, path)| path.clone())
+                    .unwrap_or_else(DerivationPath::master);
+                DescriptorSecretKey::Single(SinglePriv {
+                    origin: single.origin.clone(),
+                    key: master.derive_priv(&secp, &origin_path)?.to_priv(),
+                })
+            }
+        };
+        // a fingerprint is only four bytes so the key could come from another master key
+        if secret_key.to_public(&secp)? != key {
+            return Err(anyhow!(
+                "{} has the fingerprint of the master key but isn't derived from it",
+                key
+            ));
+        }
+        keymap.insert(key, secret_key);
+    }
+    Ok(keymap)
+}
@@ -0,0 +1,183 @@
+//! Labelling addresses, transactions and outputs, and moving the labels around with BIP 329.
+use crate::{load_extension, save_extension, Keychain, LabelsOutput};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{Address, Network, Script, Txid},
+    keychain::PersistBackend,
+};
+use clap::Subcommand;
+use std::{
+    collections::BTreeMap,
+    fmt::{Debug, Write as _},
+    path::PathBuf,
+    str::FromStr,
+};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum LabelsCmd {
+    /// Write the labels as BIP 329 JSON lines
+    Export {
+        /// Write them to this file instead of stdout
+        #[clap(long)]
+        file: Option<PathBuf>,
+    },
+    /// Read labels from a BIP 329 file, replacing the labels of the same addresses and
+    /// transactions
+    Import { file: PathBuf },
+}
+
+/// The name of the extension blob the [`Labels`] are saved under.
+pub const LABELS_EXTENSION: &str = "labels";
+
+/// The labels given to addresses (`address label`) and transactions (`tx label`) or imported from
+/// a BIP 329 file.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Labels {
+    /// The labels of addresses by their script pubkey
+    pub addresses: BTreeMap<Script, String>,
+    pub txs: BTreeMap<Txid, String>,
+    /// Imported labels of the types the wallet doesn't use (e.g. `xpub` or `output`), kept so
+    /// they are exported again
+    #[serde(default)]
+    pub other: Vec<Bip329Label>,
+}
+
+/// A line of a BIP 329 label file.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Bip329Label {
+    /// `tx`, `addr`, `pubkey`, `input`, `output` or `xpub`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// What is labeled, e.g. the txid of a `tx` label
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// The descriptor (without its keychain and index) of the wallet the label comes from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// Whether an `output` may be spent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spendable: Option<bool>,
+}
+
+impl Labels {
+    /// Labels the address with `script_pubkey`, removing its label if `label` is empty.
+    pub fn set_address(&mut self, script_pubkey: Script, label: String) {
+        if label.is_empty() {
+            self.addresses.remove(&script_pubkey);
+        } else {
+            self.addresses.insert(script_pubkey, label);
+        }
+    }
+
+    /// Labels the transaction `txid`, removing its label if `label` is empty.
+    pub fn set_tx(&mut self, txid: Txid, label: String) {
+        if label.is_empty() {
+            self.txs.remove(&txid);
+        } else {
+            self.txs.insert(txid, label);
+        }
+    }
+
+    /// The labels as BIP 329 JSON lines. Addresses are shown for `network`; scripts that have no
+    /// address are left out.
+    pub fn to_bip329(&self, network: Network) -> Result<String> {
+        let mut lines = String::new();
+        let txs = self.txs.iter().map(|(txid, label)| Bip329Label {
+            kind: "tx".to_string(),
+            reference: txid.to_string(),
+            label: Some(label.clone()),
+            origin: None,
+            spendable: None,
+        });
+        let addresses = self.addresses.iter().filter_map(|(script_pubkey, label)| {
+            let address = Address::from_script(script_pubkey, network).ok()?;
+            Some(Bip329Label {
+                kind: "addr".to_string(),
+                reference: address.to_string(),
+                label: Some(label.clone()),
+                origin: None,
+                spendable: None,
+            })
+        });
+        for label in txs.chain(addresses).chain(self.other.iter().cloned()) {
+            writeln!(lines, "{}", serde_json::to_string(&label)?)?;
+        }
+        Ok(lines)
+    }
+
+    /// Adds the labels of the BIP 329 JSON lines `bip329`, replacing the labels of the same
+    /// addresses and transactions. Addresses have to be for `network`. Returns how many labels
+    /// were read.
+    pub fn import_bip329(&mut self, bip329: &str, network: Network) -> Result<usize> {
+        let mut count = 0;
+        for (i, line) in bip329.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let label: Bip329Label = serde_json::from_str(line)
+                .map_err(|e| anyhow!("line {} isn't a BIP 329 label: {}", i + 1, e))?;
+            match label.kind.as_str() {
+                "tx" => {
+                    let txid = Txid::from_str(&label.reference)
+                        .map_err(|e| anyhow!("line {} has an invalid txid: {}", i + 1, e))?;
+                    self.set_tx(txid, label.label.unwrap_or_default());
+                }
+                "addr" => {
+                    let address = Address::from_str(&label.reference)
+                        .map_err(|e| anyhow!("line {} has an invalid address: {}", i + 1, e))?;
+                    if !address.is_valid_for_network(network) {
+                        return Err(anyhow!(
+                            "line {} has a {} address but the wallet is on {}",
+                            i + 1,
+                            address.network,
+                            network
+                        ));
+                    }
+                    self.set_address(address.script_pubkey(), label.label.unwrap_or_default());
+                }
+                _ => {
+                    self.other.retain(|other| {
+                        other.kind != label.kind || other.reference != label.reference
+                    });
+                    self.other.push(label);
+                }
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// Exports or imports the labels of `store`.
+pub fn run_labels_cmd<P, S>(
+    labels_cmd: LabelsCmd,
+    store: &mut S,
+    network: Network,
+) -> Result<LabelsOutput>
+where
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let mut labels = load_extension::<Labels, _, _>(store, LABELS_EXTENSION)?.unwrap_or_default();
+    match labels_cmd {
+        LabelsCmd::Export { file } => {
+            let bip329 = labels.to_bip329(network)?;
+            match file {
+                Some(file) => {
+                    std::fs::write(&file, bip329)?;
+                    Ok(LabelsOutput::Exported(file))
+                }
+                None => Ok(LabelsOutput::Bip329(bip329)),
+            }
+        }
+        LabelsCmd::Import { file } => {
+            let bip329 = std::fs::read_to_string(&file)?;
+            let count = labels.import_bip329(&bip329, network)?;
+            save_extension(store, LABELS_EXTENSION, &labels)?;
+            Ok(LabelsOutput::Imported(count))
+        }
+    }
+}
@@ -2,31 +2,93 @@ pub extern crate anyhow;
 use anyhow::{anyhow, Result};
 use bdk_chain::{
     bitcoin::{
+        consensus::encode::deserialize,
+        hashes::{hex::FromHex, sha256},
+        policy::MAX_STANDARD_TX_WEIGHT,
         secp256k1::Secp256k1,
-        util::sighash::{Prevouts, SighashCache},
-        Address, LockTime, Network, Sequence, Transaction, TxIn, TxOut,
+        util::{bip32::ExtendedPrivKey, psbt::PartiallySignedTransaction as Psbt},
+        Address, Amount, Network, OutPoint, Script, Transaction, TxOut, Txid,
     },
-    descriptor_ext::DescriptorExt,
+    cancel::CancellationToken,
+    chain_graph,
     file_store::KeychainStore,
-    keychain::{KeychainChangeSet, KeychainTracker},
-    miniscript::{
-        descriptor::{DescriptorSecretKey, KeyMap},
-        Descriptor, DescriptorPublicKey,
+    keychain::{
+        AsyncPersistBackend, ConfirmationPolicy, KeychainChangeSet, KeychainTracker, PersistBackend,
     },
-    sparse_chain::{self, ChainPosition},
-    FullTxOut,
+    miniscript::{descriptor::KeyMap, Descriptor, DescriptorPublicKey, ForEachKey},
+    sparse_chain::{ChainPosition, PositionSchema},
+    standardness::{validate_standardness, StandardnessPolicy},
+    tip::{TipEvent, TipStream},
+    TxHeight,
 };
-use bdk_coin_select::{coin_select_bnb, CoinSelector, CoinSelectorOpt, WeightedValue};
+use bdk_coin_select::{sat_per_vb_to_wu, sat_per_wu_to_vb};
 pub use clap;
 use clap::{Parser, Subcommand};
-use std::{cmp::Reverse, collections::HashMap, fmt::Debug, path::PathBuf, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::Infallible,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+mod audit;
+mod cosign;
+mod deferred;
+mod expiry;
+mod external;
+mod funding;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "hwi")]
+pub mod hwi;
+mod imports;
+mod invoice;
+mod keys;
+mod labels;
+mod migrate;
+mod output;
+mod privacy;
+mod psbts;
+mod signing;
+mod spend;
+mod store;
+mod sync;
+mod tx_builder;
+mod utxos;
+mod vault;
+pub mod webhook;
+pub use audit::*;
+pub use cosign::*;
+pub use deferred::*;
+pub use expiry::*;
+pub use external::*;
+pub use funding::*;
+pub use imports::*;
+pub use invoice::*;
+pub use keys::*;
+pub use labels::*;
+pub use migrate::*;
+pub use output::*;
+pub use privacy::*;
+pub use psbts::*;
+pub use signing::*;
+pub use spend::*;
+pub use store::*;
+pub use sync::*;
+pub use tx_builder::*;
+pub use utxos::*;
+pub use vault::*;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 #[clap(propagate_version = true)]
 pub struct Args<C: clap::Subcommand> {
+    /// The wallet's descriptor. With only public keys in it the wallet is watch-only. Every
+    /// command but `generate` needs it unless there is `--wallets`.
     #[clap(env = "DESCRIPTOR")]
-    pub descriptor: String,
+    pub descriptor: Option<String>,
     #[clap(env = "CHANGE_DESCRIPTOR")]
     pub change_descriptor: Option<String>,
 
@@ -36,9 +98,81 @@ pub struct Args<C: clap::Subcommand> {
     #[clap(env = "BDK_DB_PATH", long, default_value = ".bdk_example_db")]
     pub db_path: PathBuf,
 
+    /// Encrypt the database with this password. A database that isn't encrypted yet is encrypted
+    /// when it's opened with a password. Prefer the environment variable so the password doesn't
+    /// end up in the shell's history.
+    #[clap(env = "BDK_DB_PASSWORD", long, hide_env_values = true)]
+    pub db_password: Option<String>,
+
+    /// Make each entry of the database carry the hash of the one before it so that changes to
+    /// earlier entries can be detected. The database is rewritten that way when it's opened with
+    /// this. Its head hash is printed each time it's opened, keep it somewhere safe.
+    #[clap(env = "BDK_DB_HASH_CHAIN", long)]
+    pub db_hash_chain: bool,
+
+    /// Refuse to use the database unless it still leads to this head hash, i.e. the entries it
+    /// covered haven't been changed since it was printed
+    #[clap(env = "BDK_DB_HEAD", long, requires = "db_hash_chain")]
+    pub db_head: Option<sha256::Hash>,
+
+    /// The wallet in the database to use. Each wallet has its own history and derivation indices.
+    /// Without it the database's default wallet is used. With `--wallets` it names one of the
+    /// wallets of that file instead.
+    #[clap(env = "BDK_WALLET", long)]
+    pub wallet: Option<String>,
+
+    /// A JSON file of named wallets, each with its own network, descriptors, database and chain
+    /// source servers, e.g. `{"main": {"network": "bitcoin", "descriptor": "tr(...)", "db_path":
+    /// "main.db"}, "test": {"network": "testnet", ...}}`. The descriptors, `--network` and
+    /// `--db-path` are taken from the file then.
+    #[clap(env = "BDK_WALLETS", long)]
+    pub wallets: Option<PathBuf>,
+
     #[clap(env = "BDK_CP_LIMIT", long, default_value = "20")]
     pub cp_limit: usize,
 
+    /// Give up on the command after this many seconds. Requests to the chain source (and scans
+    /// between their batches) stop with an error once it has passed.
+    #[clap(env = "BDK_TIMEOUT", long)]
+    pub timeout: Option<u64>,
+
+    /// Sign with this secret key (e.g. `[d34db33f/86'/1'/0']tprv.../0/*`), written like the public
+    /// key it stands in for in a watch-only descriptor. Can be given more than once, or as a comma
+    /// separated list in the environment variable so the keys don't end up in the shell's history.
+    #[clap(
+        env = "BDK_SIGNING_KEYS",
+        long = "signing-key",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    pub signing_keys: Vec<String>,
+
+    /// Sign with the keys of this BIP 39 mnemonic: the keys of the descriptors whose origin is
+    /// its master key (e.g. `[d34db33f/86'/1'/0']tpub...`) are derived from it. `generate` makes
+    /// its descriptors from it. Prefer the environment variable so the words don't end up in the
+    /// shell's history.
+    #[clap(env = "BDK_MNEMONIC", long, hide_env_values = true)]
+    pub mnemonic: Option<String>,
+
+    /// The BIP 39 passphrase the mnemonic is extended with
+    #[clap(
+        env = "BDK_MNEMONIC_PASSPHRASE",
+        long,
+        hide_env_values = true,
+        requires = "mnemonic"
+    )]
+    pub mnemonic_passphrase: Option<String>,
+
+    /// Sign with the connected hardware wallets whose keys are in the descriptors
+    #[cfg(feature = "hwi")]
+    #[clap(env = "BDK_HWI", long)]
+    pub hwi: bool,
+
+    /// The `hwi` command to talk to hardware wallets with
+    #[cfg(feature = "hwi")]
+    #[clap(env = "BDK_HWI_COMMAND", long, default_value = "hwi")]
+    pub hwi_command: String,
+
     #[clap(subcommand)]
     pub command: Commands<C>,
 }
@@ -51,97 +185,387 @@ pub enum Commands<C: clap::Subcommand> {
     Address {
         #[clap(subcommand)]
         addr_cmd: AddressCmd,
+        /// Show the address without marking it as revealed in the database
+        #[clap(long)]
+        dry_run: bool,
     },
     /// Get the wallet balance
-    Balance,
+    Balance {
+        /// Count unconfirmed funds from transactions paying less than this feerate (in sats per
+        /// vbyte) as at risk
+        #[clap(long, default_value = "0")]
+        min_feerate: f32,
+        #[clap(flatten)]
+        deposit_policy: DepositPolicy,
+    },
     /// TxOut related commands
     #[clap(name = "txout")]
     TxOut {
         #[clap(subcommand)]
         txout_cmd: TxOutCmd,
     },
-    /// Send coins to an address
+    /// Send coins to an address. A watch-only wallet prints the unsigned PSBT instead.
     Send {
         value: u64,
-        address: Address,
+        /// An address or `desc:<descriptor>` to pay to the next address of a counterparty's
+        /// descriptor
+        recipient: Recipient,
+        #[clap(short, default_value = "largest-first")]
+        coin_select: CoinSelectionAlgo,
+        /// A keychain to send change to. Can be given more than once to list fallbacks in order
+        /// of preference (defaults to internal then external).
+        #[clap(long = "change-keychain")]
+        change_keychains: Vec<Keychain>,
+        /// Take the fee out of the value sent rather than paying it on top
+        #[clap(long)]
+        subtract_fee: bool,
+        #[clap(flatten)]
+        spend: SpendArgs,
+        #[clap(flatten)]
+        deposit_policy: DepositPolicy,
+        #[clap(flatten)]
+        timelocks: TimelockArgs,
+        #[clap(flatten)]
+        dust: DustArgs,
+        #[clap(flatten)]
+        sighash: SighashArgs,
+        #[clap(flatten)]
+        preimages: PreimageArgs,
+    },
+    /// Pay several recipients with one transaction. A watch-only wallet prints the unsigned PSBT
+    /// instead.
+    SendMany {
+        /// The payments as `<recipient>:<value>` where the recipient is an address or
+        /// `desc:<descriptor>` (see `send`)
+        #[clap(required = true)]
+        payments: Vec<Payment>,
         #[clap(short, default_value = "largest-first")]
         coin_select: CoinSelectionAlgo,
+        /// A keychain to send change to. Can be given more than once to list fallbacks in order
+        /// of preference (defaults to internal then external).
+        #[clap(long = "change-keychain")]
+        change_keychains: Vec<Keychain>,
+        /// Take the fee out of these payments (numbered from 0 in the order given, e.g. `0,2`)
+        /// rather than paying it on top. It is split in proportion to their values.
+        #[clap(long, value_delimiter = ',')]
+        subtract_fee_from: Vec<usize>,
+        #[clap(flatten)]
+        spend: SpendArgs,
+        #[clap(flatten)]
+        deposit_policy: DepositPolicy,
+        #[clap(flatten)]
+        timelocks: TimelockArgs,
+        #[clap(flatten)]
+        dust: DustArgs,
+        #[clap(flatten)]
+        sighash: SighashArgs,
+        #[clap(flatten)]
+        preimages: PreimageArgs,
+    },
+    /// Send all the wallet's coins, less the fee, to a recipient without making change. A
+    /// watch-only wallet prints the unsigned PSBT instead.
+    Drain {
+        /// An address or `desc:<descriptor>` (see `send`)
+        recipient: Recipient,
+        #[clap(flatten)]
+        spend: SpendArgs,
+        #[clap(flatten)]
+        deposit_policy: DepositPolicy,
+        #[clap(flatten)]
+        timelocks: TimelockArgs,
+        #[clap(flatten)]
+        sighash: SighashArgs,
+        #[clap(flatten)]
+        preimages: PreimageArgs,
+    },
+    /// Create, sign and finish PSBTs so that the wallet's transactions can be signed elsewhere
+    Psbt {
+        #[clap(subcommand)]
+        psbt_cmd: PsbtCmd,
+    },
+    /// Collect the signatures of cosigners for a PSBT spending from a multisig descriptor and
+    /// broadcast it once it has enough of them
+    Cosign {
+        #[clap(subcommand)]
+        cosign_cmd: CosignCmd,
+    },
+    /// Inspect the timelocked spending paths of the wallet's coins
+    Vault {
+        #[clap(subcommand)]
+        vault_cmd: VaultCmd,
+    },
+    /// Watch script pubkeys that aren't derived from the wallet's descriptors (e.g. the outputs
+    /// of a Lightning node's channel closes) and see when their outputs can be swept
+    External {
+        #[clap(subcommand)]
+        external_cmd: ExternalCmd,
+    },
+    /// Queue signed transactions to be broadcast once the chain is high enough for them to be
+    /// mined (e.g. sweeps of outputs with a relative timelock)
+    Deferred {
+        #[clap(subcommand)]
+        deferred_cmd: DeferredCmd,
+    },
+    /// Speed up an unconfirmed transaction paying the wallet by spending its outputs back to the
+    /// wallet with a higher fee (child pays for parent)
+    Cpfp {
+        txid: Txid,
+        /// The feerate the transaction and the new one should have together in sats per vbyte,
+        /// or `auto:<blocks>` (see `send`)
+        #[clap(long)]
+        feerate: FeerateArg,
+    },
+    /// Ask the chain source for the feerate that gets a transaction confirmed in time
+    EstimateFee {
+        /// The number of blocks the transaction should be confirmed within
+        #[clap(long, default_value = "6")]
+        target_blocks: usize,
+    },
+    /// Request payments to new addresses and check whether they have been paid
+    Invoice {
+        #[clap(subcommand)]
+        invoice_cmd: InvoiceCmd,
+    },
+    /// Send wallet events (e.g. deposits) to other services while the wallet is being watched
+    Webhook {
+        #[clap(subcommand)]
+        webhook_cmd: webhook::WebhookCmd,
+    },
+    /// Look up transactions known to the wallet
+    Tx {
+        #[clap(subcommand)]
+        tx_cmd: TxCmd,
+    },
+    /// Export or import the labels of addresses and transactions in the BIP 329 format
+    Labels {
+        #[clap(subcommand)]
+        labels_cmd: LabelsCmd,
+    },
+    /// Decode a transaction and show how it relates to the wallet
+    Decode {
+        /// A raw transaction in hex or the txid of a transaction in the wallet
+        tx: String,
+    },
+    /// Report what the wallet's history gives away to someone watching the chain
+    Privacy,
+    /// Show the ways the wallet's descriptors can be spent: what each branch takes (keys,
+    /// thresholds, timelocks and hash locks), what satisfying it weighs and whether the wallet
+    /// can satisfy it
+    Inspect {
+        /// Only show this keychain
+        #[clap(long)]
+        keychain: Option<Keychain>,
+        /// The derivation index to show the descriptors at
+        #[clap(long, default_value = "0")]
+        index: u32,
+    },
+    /// List the transactions the wallet's keys have signed and the inputs they signed
+    Audit {
+        /// Only list the signing of this transaction
+        #[clap(long)]
+        txid: Option<Txid>,
+        /// Only list what was signed since this time (seconds since the unix epoch)
+        #[clap(long)]
+        since: Option<u64>,
+    },
+    /// Import descriptors to watch and rescan their range
+    Import {
+        /// A JSON array of descriptors in the format of bitcoind's `importdescriptors` e.g.
+        /// `[{"desc": "wpkh(tpub.../0/*)", "range": [0, 100], "timestamp": "now"}]`
+        descriptors_json: String,
+    },
+    /// Move all the funds of the wallet to a new descriptor, e.g. to rotate its keys. Running it
+    /// again carries on with the funds that couldn't be moved yet.
+    Migrate {
+        /// The descriptor to move the funds to. Must be the same each time the migration is
+        /// resumed.
+        new_descriptor: String,
+        /// The feerate of the sweep transactions in sats per vbyte
+        #[clap(long, default_value = "1")]
+        feerate: f32,
+        /// The maximum weight of a sweep transaction. The funds are moved with several
+        /// transactions if they don't fit in one.
+        #[clap(long, default_value_t = MAX_STANDARD_TX_WEIGHT)]
+        max_weight: u32,
+        /// The maximum fee a sweep transaction may pay in sats
+        #[clap(long)]
+        max_fee: Option<u64>,
+    },
+    /// Copy the database to a new file after checking every entry, and check that the copy loads
+    /// into the same wallet (including the descriptors added with `import`).
+    Backup {
+        /// Where to write the backup. Must not exist yet.
+        path: PathBuf,
+    },
+    /// Replace the database with a backup made by `backup` once the backup checks out. The
+    /// database it replaces is kept next to it.
+    RestoreDb {
+        /// The backup to restore
+        path: PathBuf,
+    },
+    /// Remove the entries of the database from the first one that can't be read, keeping a copy
+    /// of the damaged database next to it. A database that was cut off in the middle of its last
+    /// entry is recovered when it's loaded, anything else has to be recovered with this (or
+    /// replaced with `restore-db`).
+    RecoverDb,
+    /// Print the descriptors of a new wallet for `--network` following BIP 44, 49, 84 or 86.
+    /// Needs no descriptor or database.
+    Generate {
+        /// `pkh` (BIP 44), `sh-wpkh` (BIP 49), `wpkh` (BIP 84) or `tr` (BIP 86)
+        #[clap(long, default_value = "tr")]
+        script_type: ScriptType,
+        /// Derive the descriptors from this master key instead of a new random one (or the one of
+        /// `--mnemonic`). Prefer the environment variable so the key doesn't end up in the
+        /// shell's history.
+        #[clap(env = "BDK_XPRV", long, hide_env_values = true)]
+        xprv: Option<ExtendedPrivKey>,
+        /// The account to derive the descriptors' keys at
+        #[clap(long, default_value = "0")]
+        account: u32,
     },
 }
 
-#[derive(Clone, Debug)]
-pub enum CoinSelectionAlgo {
-    LargestFirst,
-    SmallestFirst,
-    OldestFirst,
-    NewestFirst,
-    BranchAndBound,
+#[derive(Subcommand, Debug, Clone)]
+pub enum AddressCmd {
+    /// Get the next unused address
+    Next,
+    /// Get a new address regardless if the existing ones haven't been used
+    New,
+    /// List all addresses
+    List {
+        #[clap(long)]
+        change: bool,
+    },
+    /// Show how far each keychain has been derived and used
+    Index {
+        /// How many unused addresses should follow the last used one in each keychain
+        #[clap(long, default_value = "10")]
+        lookahead: u32,
+    },
+    /// Label an address, e.g. with who it was given to. The address doesn't have to be the
+    /// wallet's. An empty label removes it.
+    Label { address: Address, label: String },
 }
 
-impl Default for CoinSelectionAlgo {
-    fn default() -> Self {
-        Self::LargestFirst
-    }
+#[derive(Subcommand, Debug, Clone)]
+pub enum TxOutCmd {
+    List {
+        /// The output format: `text`, or `core` for JSON matching bitcoind's `listunspent`
+        #[clap(long, default_value = "text")]
+        format: TxOutFormat,
+        /// Also show the age of each output and the coin days spending it would destroy
+        #[clap(long)]
+        verbose: bool,
+    },
+    /// Stop spending an output unless it is picked with `--utxo`, e.g. dust sent to link the
+    /// wallet's coins or coins that must be kept apart from the others
+    Freeze { outpoint: OutPoint },
+    /// Let a frozen output be spent again
+    Unfreeze { outpoint: OutPoint },
 }
 
-impl core::str::FromStr for CoinSelectionAlgo {
+#[derive(Clone, Debug)]
+pub enum TxOutFormat {
+    Text,
+    Core,
+}
+
+impl core::str::FromStr for TxOutFormat {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        use CoinSelectionAlgo::*;
         Ok(match s {
-            "largest-first" => LargestFirst,
-            "smallest-first" => SmallestFirst,
-            "oldest-first" => OldestFirst,
-            "newest-first" => NewestFirst,
-            "bnb" => BranchAndBound,
-            unknown => return Err(anyhow!("unknown coin selection algorithm '{}'", unknown)),
+            "text" => TxOutFormat::Text,
+            "core" => TxOutFormat::Core,
+            unknown => return Err(anyhow!("unknown txout format '{}'", unknown)),
         })
     }
 }
 
-impl core::fmt::Display for CoinSelectionAlgo {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        use CoinSelectionAlgo::*;
-        write!(
-            f,
-            "{}",
-            match self {
-                LargestFirst => "largest-first",
-                SmallestFirst => "smallest-first",
-                OldestFirst => "oldest-first",
-                NewestFirst => "newest-first",
-                BranchAndBound => "bnb",
-            }
-        )
+/// The name of the extension blob the [`FrozenUtxos`] are saved under.
+pub const FROZEN_EXTENSION: &str = "frozen";
+
+/// The outputs frozen with `txout freeze`. Transactions don't spend them unless they are picked
+/// explicitly (see [`FrozenUtxos::exclude_from`]).
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct FrozenUtxos {
+    pub outpoints: BTreeSet<OutPoint>,
+}
+
+impl FrozenUtxos {
+    /// Keeps `builder` from spending the frozen outputs, except those in
+    /// [`TxBuilder::must_spend`].
+    pub fn exclude_from(&self, builder: &mut TxBuilder) {
+        builder.exclude.extend(
+            self.outpoints
+                .iter()
+                .filter(|outpoint| !builder.must_spend.contains(outpoint)),
+        );
     }
 }
 
 #[derive(Subcommand, Debug, Clone)]
-pub enum AddressCmd {
-    /// Get the next unused address
-    Next,
-    /// Get a new address regardless if the existing ones haven't been used
-    New,
-    /// List all addresses
-    List {
+pub enum TxCmd {
+    /// List the wallet's transactions in the chain (oldest first) with what each did to the
+    /// balance, its fee and the addresses involved
+    List,
+    /// Show everything the wallet knows about a transaction
+    Get { txid: Txid },
+    /// Give up on an unconfirmed transaction of the wallet so the coins it spends can be spent
+    /// again. The transaction comes back if the chain source still has it, so pass `--replace`
+    /// unless it was dropped from the mempool.
+    Abandon {
+        txid: Txid,
+        /// Spend the coins again in a transaction paying the same recipients with a higher fee
         #[clap(long)]
-        change: bool,
+        replace: bool,
+        /// The feerate of the replacement in sats per vbyte or `auto:<blocks>` (see `send`).
+        /// Defaults to the feerate of the transaction plus the minimum relay feerate.
+        #[clap(long, requires = "replace")]
+        feerate: Option<FeerateArg>,
+    },
+    /// List the transactions that were evicted from the wallet's chain and what replaced them
+    Evicted {
+        /// Only show this transaction, along with its raw hex
+        #[clap(long)]
+        txid: Option<Txid>,
+    },
+    /// Label a transaction of the wallet, e.g. with what it paid for. An empty label removes it.
+    Label { txid: Txid, label: String },
+    /// Abandon the transactions the wallet sent once they are still unconfirmed after a number of
+    /// blocks. Shows the policy without any options.
+    Expiry {
+        /// The number of blocks after which a transaction is abandoned
+        #[clap(long, conflicts_with = "off")]
+        after_blocks: Option<u32>,
+        /// Never abandon transactions on their own
+        #[clap(long)]
+        off: bool,
     },
-    Index,
 }
 
-#[derive(Subcommand, Debug, Clone)]
-pub enum TxOutCmd {
-    List,
+impl core::str::FromStr for Keychain {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "external" => Keychain::External,
+            "internal" => Keychain::Internal,
+            "migration" => Keychain::Migration,
+            other => match other.strip_prefix("imported_").map(u32::from_str) {
+                Some(Ok(n)) => Keychain::Imported(n),
+                _ => return Err(anyhow!("unknown keychain '{}'", other)),
+            },
+        })
+    }
 }
 
-#[derive(
-    Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, serde::Deserialize, serde::Serialize,
-)]
-pub enum Keychain {
-    External,
-    Internal,
+impl Keychain {
+    /// Whether the coins on the keychain come from the wallet's own transactions (change and the
+    /// sweeps of a migration) rather than being deposits someone else could double spend.
+    pub fn is_trusted(&self) -> bool {
+        matches!(self, Keychain::Internal | Keychain::Migration)
+    }
 }
 
 impl core::fmt::Display for Keychain {
@@ -149,314 +573,736 @@ impl core::fmt::Display for Keychain {
         match self {
             Keychain::External => write!(f, "external"),
             Keychain::Internal => write!(f, "internal"),
+            Keychain::Imported(n) => write!(f, "imported_{}", n),
+            Keychain::Migration => write!(f, "migration"),
         }
     }
 }
 
-/// A structure defining output of a AddressCmd execution.
-#[derive(serde::Serialize, serde::Deserialize)]
-pub struct AddrsOutput {
-    keychain: String,
-    index: u32,
-    addrs: Address,
-    used: bool,
-}
-
-pub fn run_address_cmd<P>(
-    keychain_tracker: &mut KeychainTracker<Keychain, P>,
-    db: &mut KeychainStore<Keychain, P>,
+/// Runs `addr_cmd` without modifying the tracker.
+///
+/// Commands that reveal a new address also return the changeset that marks it as revealed. It's
+/// up to the caller to apply it to the tracker and persist it (or not, for a dry run). Likewise
+/// `address label` only changes `labels`, which the caller saves.
+pub fn run_address_cmd<P: ChainPosition>(
+    keychain_tracker: &KeychainTracker<Keychain, P>,
     addr_cmd: AddressCmd,
     network: Network,
-) -> Result<()>
-where
-    P: sparse_chain::ChainPosition,
-    KeychainChangeSet<Keychain, P>: serde::Serialize + serde::de::DeserializeOwned,
-{
-    let txout_index = &mut keychain_tracker.txout_index;
-
-    let new_address = match addr_cmd {
-        AddressCmd::Next => Some(txout_index.next_unused(&Keychain::External)),
-        AddressCmd::New => Some(txout_index.derive_new(&Keychain::External)),
-        _ => None,
-    };
-
-    if let Some((index, spk)) = new_address {
-        let spk = spk.clone();
-        // update database since we're about to give out a new address
-        db.set_derivation_indices(txout_index.derivation_indices())?;
-        let address =
-            Address::from_script(&spk, network).expect("should always be able to derive address");
-        eprintln!("This is the address at index {}", index);
-        println!("{}", address);
-    }
+    labels: &mut Labels,
+) -> Result<(AddressOutput, Option<KeychainChangeSet<Keychain, P>>)> {
+    let txout_index = &keychain_tracker.txout_index;
 
     match addr_cmd {
         AddressCmd::Next | AddressCmd::New => {
-            /* covered */
-            Ok(())
-        }
-        AddressCmd::Index => {
-            for (keychain, derivation_index) in txout_index.derivation_indices() {
-                println!("{:?}: {}", keychain, derivation_index);
-            }
-            Ok(())
+            let keychain = Keychain::External;
+            let next_to_derive = txout_index.next_derivation_index(&keychain);
+            // derive on a copy so that the tracker is only changed by applying the changeset
+            let mut txout_index = txout_index.clone();
+            let (index, spk) = match addr_cmd {
+                AddressCmd::Next => txout_index.next_unused(&keychain),
+                _ => txout_index.derive_new(&keychain),
+            };
+            let address = Address::from_script(spk, network)
+                .expect("should always be able to derive address");
+            let changeset = if index >= next_to_derive {
+                let mut changeset = KeychainChangeSet::default();
+                changeset.derivation_indices.insert(keychain, index);
+                Some(changeset)
+            } else {
+                None
+            };
+            Ok((AddressOutput::Address { index, address }, changeset))
         }
+        AddressCmd::Index { lookahead } => Ok((
+            AddressOutput::Indices {
+                lookahead,
+                keychains: txout_index.all_gap_stats().into_iter().collect(),
+            },
+            None,
+        )),
         AddressCmd::List { change } => {
             let target_keychain = match change {
                 true => Keychain::Internal,
                 false => Keychain::External,
             };
-            for (index, spk) in txout_index.stored_scripts_of_keychain(&target_keychain) {
-                let address = Address::from_script(&spk, network)
-                    .expect("should always be able to derive address");
-                println!(
-                    "{:?} {} used:{}",
+            let addrs = txout_index
+                .stored_scripts_of_keychain(&target_keychain)
+                .map(|(index, spk)| AddrsOutput {
+                    keychain: target_keychain.to_string(),
                     index,
+                    addrs: Address::from_script(spk, network)
+                        .expect("should always be able to derive address"),
+                    used: txout_index.is_used(&(target_keychain, index)),
+                    label: labels.addresses.get(spk).cloned(),
+                })
+                .collect();
+            Ok((AddressOutput::List(addrs), None))
+        }
+        AddressCmd::Label { address, label } => {
+            if !address.is_valid_for_network(network) {
+                return Err(anyhow!(
+                    "{} is a {} address but the wallet is on {}",
                     address,
-                    txout_index.is_used(&(target_keychain, index))
-                );
+                    address.network,
+                    network
+                ));
             }
-            Ok(())
+            labels.set_address(address.script_pubkey(), label.clone());
+            let label = Some(label).filter(|label| !label.is_empty());
+            Ok((AddressOutput::Labeled { address, label }, None))
         }
     }
 }
 
-pub fn run_balance_cmd<P: ChainPosition>(keychain_tracker: &KeychainTracker<Keychain, P>) {
-    let (confirmed, unconfirmed) =
-        keychain_tracker
-            .full_utxos()
-            .fold((0, 0), |(confirmed, unconfirmed), (_, utxo)| {
-                if utxo.chain_position.height().is_confirmed() {
-                    (confirmed + utxo.txout.value, unconfirmed)
-                } else {
-                    (confirmed, unconfirmed + utxo.txout.value)
-                }
-            });
-
-    println!("confirmed: {}", confirmed);
-    println!("unconfirmed: {}", unconfirmed);
+/// Returns the confirmed and unconfirmed balance.
+/// Returns the confirmed, unconfirmed and at risk balance of the wallet.
+///
+/// `min_feerate` is in sats per vbyte. Unconfirmed funds are only at risk if their transaction (or
+/// one of its unconfirmed ancestors) has a known conflict or pays less than `min_feerate`.
+/// Deposits that don't meet `policy` yet are counted as unconfirmed.
+pub fn run_balance_cmd<P: ChainPosition>(
+    keychain_tracker: &KeychainTracker<Keychain, P>,
+    min_feerate: f32,
+    policy: &ConfirmationPolicy,
+) -> (u64, u64, u64) {
+    let balance = keychain_tracker.balance_with_policy(
+        Keychain::is_trusted,
+        sat_per_vb_to_wu(min_feerate),
+        policy,
+    );
+    (
+        balance.confirmed + balance.immature,
+        balance.trusted_pending + balance.untrusted_pending,
+        balance.at_risk,
+    )
 }
 
-pub fn run_txo_cmd<K: Debug + Clone + Ord, P: ChainPosition>(
+pub fn run_txo_cmd<P, S>(
     txout_cmd: TxOutCmd,
-    keychain_tracker: &KeychainTracker<K, P>,
+    keychain_tracker: &KeychainTracker<Keychain, P>,
+    store: &mut S,
     network: Network,
-) {
-    match txout_cmd {
-        TxOutCmd::List => {
-            for (spk_index, full_txout) in keychain_tracker.full_txouts() {
-                let address =
-                    Address::from_script(&full_txout.txout.script_pubkey, network).unwrap();
-
-                println!(
-                    "{:?} {} {} {} spent:{:?}",
-                    spk_index,
-                    full_txout.txout.value,
-                    full_txout.outpoint,
-                    address,
-                    full_txout.spent_by
-                )
+    signers: &[Box<dyn Signer>],
+) -> Result<CommandOutput<P>>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let mut frozen =
+        load_extension::<FrozenUtxos, _, _>(store, FROZEN_EXTENSION)?.unwrap_or_default();
+    let output = match txout_cmd {
+        TxOutCmd::List {
+            format: TxOutFormat::Core,
+            ..
+        } => {
+            let tip_height = keychain_tracker
+                .chain()
+                .latest_checkpoint()
+                .map(|block_id| block_id.height);
+            let assets = signer_assets(signers);
+            let unspents = described_utxos(keychain_tracker, &assets)
+                .map(|described| {
+                    let utxo = &described.full_txout;
+                    let confirmations = match (utxo.chain_position.height(), tip_height) {
+                        (TxHeight::Confirmed(height), Some(tip)) if tip >= height => {
+                            tip - height + 1
+                        }
+                        _ => 0,
+                    };
+                    CoreUnspent {
+                        txid: utxo.outpoint.txid.to_string(),
+                        vout: utxo.outpoint.vout,
+                        address: Address::from_script(&utxo.txout.script_pubkey, network)
+                            .expect("should always be able to derive address"),
+                        script_pubkey: format!("{:x}", utxo.txout.script_pubkey),
+                        amount: Amount::from_sat(utxo.txout.value).to_btc(),
+                        confirmations,
+                        // the signers have the keys of the descriptor before it was derived
+                        spendable: keychain_tracker.txout_index.keychains()[&described.keychain]
+                            .for_any_key(|pk| assets.keys.contains(pk)),
+                        solvable: true,
+                        desc: described.descriptor.to_string(),
+                    }
+                })
+                .collect::<Vec<_>>();
+            TxOutOutput::Core(unspents)
+        }
+        TxOutCmd::List {
+            format: TxOutFormat::Text,
+            verbose,
+        } => {
+            let tip_height = keychain_tracker
+                .chain()
+                .latest_checkpoint()
+                .map(|block_id| block_id.height)
+                .unwrap_or_default();
+            TxOutOutput::List(
+                keychain_tracker
+                    .full_txouts()
+                    .map(|(spk_index, full_txout)| {
+                        let chain_graph = keychain_tracker.chain_graph();
+                        let txid = full_txout.outpoint.txid;
+                        // a confirmed transaction's feerate no longer matters
+                        let (feerate, ancestor_feerate) =
+                            if full_txout.chain_position.height().is_confirmed() {
+                                (None, None)
+                            } else {
+                                (
+                                    chain_graph
+                                        .graph()
+                                        .get_tx(txid)
+                                        .and_then(|tx| chain_graph.graph().calculate_feerate(tx)),
+                                    chain_graph.ancestor_feerate(txid),
+                                )
+                            };
+                        ListedTxOut {
+                            spk_index: *spk_index,
+                            address: Address::from_script(&full_txout.txout.script_pubkey, network)
+                                .expect("should always be able to derive address"),
+                            // sats per weight unit to sats per vbyte
+                            feerate: feerate.map(sat_per_wu_to_vb),
+                            ancestor_feerate: ancestor_feerate.map(sat_per_wu_to_vb),
+                            age: verbose.then(|| full_txout.age(tip_height)),
+                            coin_days_destroyed: verbose
+                                .then(|| full_txout.coin_days_destroyed(tip_height)),
+                            frozen: frozen.outpoints.contains(&full_txout.outpoint),
+                            full_txout,
+                        }
+                    })
+                    .collect(),
+            )
+        }
+        TxOutCmd::Freeze { outpoint } => {
+            match keychain_tracker.chain_graph().full_txout(outpoint) {
+                Some(full_txout) if full_txout.spent_by.is_some() => {
+                    return Err(anyhow!("{} is already spent", outpoint))
+                }
+                Some(_) => {}
+                None => return Err(anyhow!("{} isn't an output of the wallet", outpoint)),
+            }
+            if !frozen.outpoints.insert(outpoint) {
+                return Err(anyhow!("{} is already frozen", outpoint));
             }
+            save_extension(store, FROZEN_EXTENSION, &frozen)?;
+            TxOutOutput::Froze(outpoint)
         }
-    }
+        TxOutCmd::Unfreeze { outpoint } => {
+            if !frozen.outpoints.remove(&outpoint) {
+                return Err(anyhow!("{} isn't frozen", outpoint));
+            }
+            save_extension(store, FROZEN_EXTENSION, &frozen)?;
+            TxOutOutput::Unfroze(outpoint)
+        }
+    };
+    Ok(CommandOutput::TxOuts(output))
 }
 
-pub fn create_tx<P: ChainPosition>(
-    value: u64,
-    address: Address,
-    coin_select: CoinSelectionAlgo,
+pub fn run_tx_cmd<P, S>(
+    tx_cmd: TxCmd,
+    client: &mut (impl Broadcast + EstimateFee),
     keychain_tracker: &mut KeychainTracker<Keychain, P>,
-    keymap: &HashMap<DescriptorPublicKey, DescriptorSecretKey>,
-) -> Result<Transaction> {
-    let assets = bdk_tmp_plan::Assets {
-        keys: keymap.iter().map(|(pk, _)| pk.clone()).collect(),
-        ..Default::default()
-    };
-
-    // TODO use planning module
-    let mut candidates = planned_utxos(keychain_tracker, &assets).collect::<Vec<_>>();
-
-    // apply coin selection algorithm
-    match coin_select {
-        CoinSelectionAlgo::LargestFirst => {
-            candidates.sort_by_key(|(_, utxo)| Reverse(utxo.txout.value))
+    store: &mut S,
+    network: Network,
+    signers: &[Box<dyn Signer>],
+) -> Result<CommandOutput<P>>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    match tx_cmd {
+        TxCmd::Abandon {
+            txid,
+            replace,
+            feerate,
+        } => {
+            let original = keychain_tracker
+                .graph()
+                .get_tx(txid)
+                .cloned()
+                .ok_or_else(|| anyhow!("transaction {} is not in the wallet", txid))?;
+            if keychain_tracker.chain().tx_position(txid).is_none() {
+                return Err(anyhow!("{} isn't in the wallet's chain", txid));
+            }
+            let feerate = match feerate {
+                Some(feerate) => Some(sat_per_vb_to_wu(feerate.resolve(client)?)),
+                None => None,
+            };
+            abandon_txs(keychain_tracker, store, &[txid])?;
+            if !replace {
+                return Ok(CommandOutput::Tx(TxOutput::Abandoned(txid)));
+            }
+            let frozen =
+                load_extension::<FrozenUtxos, _, _>(store, FROZEN_EXTENSION)?.unwrap_or_default();
+            let psbt =
+                create_replacement_tx(&original, feerate, &frozen, keychain_tracker, signers)?;
+            send_psbt(psbt, None, client, keychain_tracker, store, signers)
         }
-        CoinSelectionAlgo::SmallestFirst => candidates.sort_by_key(|(_, utxo)| utxo.txout.value),
-        CoinSelectionAlgo::OldestFirst => {
-            candidates.sort_by_key(|(_, utxo)| utxo.chain_position.clone())
+        TxCmd::Evicted { txid } => {
+            let evicted =
+                load_extension::<EvictedTxs, _, _>(store, EVICTED_EXTENSION)?.unwrap_or_default();
+            if let Some(txid) = txid {
+                if !evicted.txs.contains_key(&txid) {
+                    return Err(anyhow!("{} has never been evicted", txid));
+                }
+            }
+            let chain = keychain_tracker.chain();
+            let reports = evicted
+                .txs
+                .iter()
+                .filter(|(evicted_txid, _)| txid.is_none() || txid == Some(**evicted_txid))
+                .map(|(&evicted_txid, evicted_tx)| EvictionReport {
+                    txid: evicted_txid,
+                    height: evicted_tx.height,
+                    evicted_at: evicted_tx.evicted_at,
+                    tip_height: evicted_tx.tip_height,
+                    replaced_by: evicted_tx
+                        .conflicts
+                        .iter()
+                        .map(|&conflict| Conflict {
+                            txid: conflict,
+                            position: chain.tx_position(conflict).cloned(),
+                        })
+                        .collect(),
+                    position: chain.tx_position(evicted_txid).cloned(),
+                    tx: txid.map(|_| evicted_tx.tx.clone()),
+                })
+                .collect();
+            Ok(CommandOutput::Tx(TxOutput::Evicted(reports)))
         }
-        CoinSelectionAlgo::NewestFirst => {
-            candidates.sort_by_key(|(_, utxo)| Reverse(utxo.chain_position.clone()))
+        TxCmd::List => {
+            let chain_graph = keychain_tracker.chain_graph();
+            let txout_index = &keychain_tracker.txout_index;
+            let labels =
+                load_extension::<Labels, _, _>(store, LABELS_EXTENSION)?.unwrap_or_default();
+            let endpoint = |txout: &TxOut| TxEndpoint {
+                address: describe_script(&txout.script_pubkey, network),
+                index: txout_index.index_of_spk(&txout.script_pubkey),
+                value: txout.value,
+            };
+            let summaries = chain_graph
+                .transactions_in_chain()
+                .filter(|(_, tx)| txout_index.is_relevant(tx))
+                .map(|(position, tx)| TxSummary {
+                    txid: tx.txid(),
+                    position: position.clone(),
+                    net: txout_index.net_value(tx),
+                    fee: chain_graph.graph().calculate_fee(tx),
+                    label: labels.txs.get(&tx.txid()).cloned(),
+                    from: tx
+                        .input
+                        .iter()
+                        .filter_map(|txin| txout_index.txout(txin.previous_output))
+                        .map(|(_, txout)| endpoint(txout))
+                        .collect(),
+                    to: tx.output.iter().map(endpoint).collect(),
+                })
+                .collect();
+            Ok(CommandOutput::Tx(TxOutput::List(summaries)))
         }
-        CoinSelectionAlgo::BranchAndBound => {}
-    }
+        TxCmd::Label { txid, label } => {
+            if keychain_tracker.graph().get_tx(txid).is_none() {
+                return Err(anyhow!("transaction {} is not in the wallet", txid));
+            }
+            let mut labels =
+                load_extension::<Labels, _, _>(store, LABELS_EXTENSION)?.unwrap_or_default();
+            labels.set_tx(txid, label.clone());
+            save_extension(store, LABELS_EXTENSION, &labels)?;
+            Ok(CommandOutput::Tx(TxOutput::Labeled {
+                txid,
+                label: Some(label).filter(|label| !label.is_empty()),
+            }))
+        }
+        TxCmd::Expiry { after_blocks, off } => {
+            let mut policy =
+                load_extension::<ExpiryPolicy, _, _>(store, EXPIRY_EXTENSION)?.unwrap_or_default();
+            if after_blocks.is_some() || off {
+                policy.after_blocks = after_blocks;
+                save_extension(store, EXPIRY_EXTENSION, &policy)?;
+            }
+            Ok(CommandOutput::Tx(TxOutput::Expiry {
+                after_blocks: policy.after_blocks,
+            }))
+        }
+        TxCmd::Get { txid } => {
+            let chain_graph = keychain_tracker.chain_graph();
+            let txout_index = &keychain_tracker.txout_index;
+            let tx = chain_graph
+                .graph()
+                .get_tx(txid)
+                .ok_or_else(|| anyhow!("transaction {} is not in the wallet", txid))?;
 
-    // turn the txos we chose into a weight and value
-    let wv_candidates = candidates
-        .iter()
-        .map(|(plan, utxo)| {
-            WeightedValue::new(
-                utxo.txout.value,
-                plan.expected_weight() as _,
-                plan.witness_version().is_some(),
-            )
-        })
-        .collect();
+            let evicted =
+                load_extension::<EvictedTxs, _, _>(store, EVICTED_EXTENSION)?.unwrap_or_default();
+            let labels =
+                load_extension::<Labels, _, _>(store, LABELS_EXTENSION)?.unwrap_or_default();
+            let inputs = tx
+                .input
+                .iter()
+                .enumerate()
+                .filter_map(|(vin, txin)| {
+                    let (index, txout) = txout_index.txout(txin.previous_output)?;
+                    Some(OwnedInput {
+                        vin,
+                        outpoint: txin.previous_output,
+                        value: txout.value,
+                        index: *index,
+                    })
+                })
+                .collect();
+            let outputs = tx
+                .output
+                .iter()
+                .enumerate()
+                .filter_map(|(vout, txout)| {
+                    let index = txout_index.index_of_spk(&txout.script_pubkey)?;
+                    Some(OwnedOutput {
+                        vout,
+                        value: txout.value,
+                        address: describe_script(&txout.script_pubkey, network),
+                        index,
+                    })
+                })
+                .collect();
 
-    let mut outputs = vec![TxOut {
-        value,
-        script_pubkey: address.script_pubkey(),
-    }];
-
-    let internal_keychain = if keychain_tracker
-        .txout_index
-        .keychains()
-        .get(&Keychain::Internal)
-        .is_some()
-    {
-        Keychain::Internal
-    } else {
-        Keychain::External
-    };
+            Ok(CommandOutput::Tx(TxOutput::Details(TxDetails {
+                txid,
+                label: labels.txs.get(&txid).cloned(),
+                position: chain_graph.chain().tx_position(txid).cloned(),
+                confirmations: chain_graph.confirmations(txid).unwrap_or(0),
+                evicted: evicted.txs.contains_key(&txid),
+                net: txout_index.net_value(tx),
+                fee: chain_graph.graph().calculate_fee(tx),
+                tx: tx.clone(),
+                inputs,
+                outputs,
+                spent_by: chain_graph
+                    .outspends_in_chain(txid)
+                    .into_iter()
+                    .map(|(vout, (position, spending_txid))| Outspend {
+                        vout,
+                        txid: spending_txid,
+                        position: position.clone(),
+                    })
+                    .collect(),
+                conflicts: chain_graph
+                    .conflicts_of(txid)
+                    .into_iter()
+                    .map(|(conflict, position)| Conflict {
+                        txid: conflict,
+                        position: position.cloned(),
+                    })
+                    .collect(),
+            })))
+        }
+    }
+}
 
-    let (change_index, change_script) = {
-        let (index, script) = keychain_tracker.txout_index.next_unused(&internal_keychain);
-        (index, script.clone())
+/// Describes the transaction `tx` (raw hex or a txid known to the tracker) along with what
+/// the tracker knows about it: the outputs it spends, its fee and which outputs are ours.
+pub fn run_decode_cmd<K: Debug + Clone + Ord, P: ChainPosition>(
+    tx: &str,
+    keychain_tracker: &KeychainTracker<K, P>,
+    network: Network,
+) -> Result<TxDescription<K, P>> {
+    let tx = match Txid::from_str(tx) {
+        Ok(txid) => keychain_tracker
+            .graph()
+            .get_tx(txid)
+            .cloned()
+            .ok_or_else(|| anyhow!("transaction {} is not in the wallet", txid))?,
+        Err(_) => deserialize::<Transaction>(&Vec::<u8>::from_hex(tx)?)?,
     };
-    let change_plan = bdk_tmp_plan::plan_satisfaction(
-        &keychain_tracker
-            .txout_index
-            .keychains()
-            .get(&internal_keychain)
-            .expect("must exist")
-            .at_derivation_index(change_index),
-        &assets,
-    )
-    .expect("failed to obtain change plan");
+    Ok(describe_tx(&tx, keychain_tracker, network))
+}
 
-    let mut change_output = TxOut {
-        value: 0,
-        script_pubkey: change_script,
-    };
+/// The address of `script_pubkey` on `network`, or the script itself if it has no address.
+pub(crate) fn describe_script(script_pubkey: &Script, network: Network) -> String {
+    Address::from_script(script_pubkey, network)
+        .map(|address| address.to_string())
+        .unwrap_or_else(|_| format!("script {:x}", script_pubkey))
+}
 
-    let cs_opts = CoinSelectorOpt {
-        target_feerate: 0.5,
-        min_drain_value: keychain_tracker
-            .txout_index
-            .keychains()
-            .get(&internal_keychain)
-            .expect("must exist")
-            .dust_value(),
-        ..CoinSelectorOpt::fund_outputs(
-            &outputs,
-            &change_output,
-            change_plan.expected_weight() as u32,
-        )
+/// What [`run_decode_cmd`] reports about `tx`.
+pub fn describe_tx<K: Debug + Clone + Ord, P: ChainPosition>(
+    tx: &Transaction,
+    keychain_tracker: &KeychainTracker<K, P>,
+    network: Network,
+) -> TxDescription<K, P> {
+    let txid = tx.txid();
+    let txout_index = &keychain_tracker.txout_index;
+    let endpoint = |txout: &TxOut| TxEndpoint {
+        address: describe_script(&txout.script_pubkey, network),
+        index: txout_index.index_of_spk(&txout.script_pubkey),
+        value: txout.value,
     };
+    let inputs = tx
+        .input
+        .iter()
+        .map(|txin| {
+            let value = txin.sequence.to_consensus_u32() & 0xffff;
+            DescribedInput {
+                outpoint: txin.previous_output,
+                prevout: keychain_tracker
+                    .graph()
+                    .get_txout(txin.previous_output)
+                    .map(endpoint),
+                relative_lock: match txin.sequence.is_relative_lock_time() {
+                    false => None,
+                    true if txin.sequence.is_height_locked() => Some(RelativeLock::Blocks(value)),
+                    true => Some(RelativeLock::Seconds(value * 512)),
+                },
+            }
+        })
+        .collect();
 
-    // TODO: How can we make it easy to shuffle in order of inputs and outputs here?
-    // apply coin selection by saying we need to fund these outputs
-    let mut coin_selector = CoinSelector::new(&wv_candidates, &cs_opts);
-
-    // just select coins in the order provided until we have enough
-    // only use first result (least waste)
-    let selection = match coin_select {
-        CoinSelectionAlgo::BranchAndBound => {
-            coin_select_bnb(Duration::from_secs(10), coin_selector.clone())
-                .map_or_else(|| coin_selector.select_until_finished(), |cs| cs.finish())?
-        }
-        _ => coin_selector.select_until_finished()?,
-    };
-    let (_, selection_meta) = selection.best_strategy();
-
-    // get the selected utxos
-    let selected_txos = selection.apply_selection(&candidates).collect::<Vec<_>>();
-
-    if let Some(drain_value) = selection_meta.drain_value {
-        change_output.value = drain_value;
-        // if the selection tells us to use change and the change value is sufficient we add it as an output
-        outputs.push(change_output)
-    }
-
-    let mut transaction = Transaction {
-        version: 0x02,
-        lock_time: keychain_tracker
-            .chain()
-            .latest_checkpoint()
-            .and_then(|block_id| LockTime::from_height(block_id.height).ok())
-            .unwrap_or(LockTime::ZERO)
-            .into(),
-        input: selected_txos
+    TxDescription {
+        txid,
+        position: keychain_tracker.chain().tx_position(txid).cloned(),
+        version: tx.version,
+        size: tx.size(),
+        vsize: tx.vsize(),
+        weight: tx.weight(),
+        lock_time: tx.lock_time.into(),
+        lock_time_enabled: tx
+            .input
             .iter()
-            .map(|(_, utxo)| TxIn {
-                previous_output: utxo.outpoint,
-                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
-                ..Default::default()
-            })
-            .collect(),
-        output: outputs,
-    };
-
-    let prevouts = selected_txos
-        .iter()
-        .map(|(_, utxo)| utxo.txout.clone())
-        .collect::<Vec<_>>();
-    let sighash_prevouts = Prevouts::All(&prevouts);
+            .any(|txin| txin.sequence.enables_absolute_lock_time()),
+        signals_rbf: tx.is_explicitly_rbf(),
+        inputs,
+        outputs: tx.output.iter().map(endpoint).collect(),
+    }
+}
 
-    // first set tx values for plan so that we don't change them while signing
-    for (i, (plan, _)) in selected_txos.iter().enumerate() {
-        if let Some(sequence) = plan.required_sequence() {
-            transaction.input[i].sequence = sequence
+/// Keeps the tracker's chain at the tip of `stream` until an error occurs.
+///
+/// Every `poll_interval` the events the stream has for us are applied to the tracker and stored.
+/// `on_poll` is then called with the events that were applied (which may be none) so the caller
+/// can do its own syncing or broadcast what became due.
+pub fn follow_tip<T, P, S>(
+    stream: &mut T,
+    keychain_tracker: &mut KeychainTracker<Keychain, P>,
+    store: &mut S,
+    poll_interval: Duration,
+    mut on_poll: impl FnMut(
+        &mut T,
+        &mut KeychainTracker<Keychain, P>,
+        &mut S,
+        &[TipEvent],
+    ) -> Result<()>,
+) -> Result<()>
+where
+    T: TipStream,
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+{
+    loop {
+        let mut events = vec![];
+        while let Some(event) = stream
+            .next_event()
+            .map_err(|e| anyhow!("failed to follow the tip: {}", e))?
+        {
+            let changeset = KeychainChangeSet {
+                chain_graph: chain_graph::ChangeSet {
+                    chain: event.changeset(keychain_tracker.chain())?,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            store.append_changeset(&changeset)?;
+            keychain_tracker.apply_changeset(changeset);
+            events.push(event);
         }
+        on_poll(stream, keychain_tracker, store, &events)?;
+        std::thread::sleep(poll_interval);
     }
+}
 
-    // create a short lived transaction
-    let _sighash_tx = transaction.clone();
-    let mut sighash_cache = SighashCache::new(&_sighash_tx);
-
-    for (i, (plan, _)) in selected_txos.iter().enumerate() {
-        let requirements = plan.requirements();
-        let mut auth_data = bdk_tmp_plan::SatisfactionMaterial::default();
-        assert!(
-            !requirements.requires_hash_preimages(),
-            "can't have hash pre-images since we didn't provide any"
-        );
-        assert!(
-            requirements.signatures.sign_with_keymap(
-                i,
-                &keymap,
-                &sighash_prevouts,
-                None,
-                None,
-                &mut sighash_cache,
-                &mut auth_data,
-                &Secp256k1::default(),
-            )?,
-            "we should have signed with this input"
-        );
+/// The coin selection, change keychains and confirmation policy of a transaction made with `send`,
+/// `send-many` or `psbt create`, which excludes the `frozen` coins.
+pub(crate) fn send_builder(
+    coin_select: CoinSelectionAlgo,
+    change_keychains: Vec<Keychain>,
+    deposit_policy: DepositPolicy,
+    frozen: &FrozenUtxos,
+) -> TxBuilder {
+    let mut builder = TxBuilder {
+        coin_select,
+        confirmation_policy: deposit_policy.into(),
+        ..Default::default()
+    };
+    if !change_keychains.is_empty() {
+        builder.change_policy.keychains = change_keychains;
+    }
+    frozen.exclude_from(&mut builder);
+    builder
+}
 
-        match plan.try_complete(&auth_data) {
-            bdk_tmp_plan::PlanState::Complete {
-                final_script_sig,
-                final_script_witness,
-            } => {
-                if let Some(witness) = final_script_witness {
-                    transaction.input[i].witness = witness;
-                }
+/// The outputs making `payments`. Paying a counterparty's descriptor also returns the
+/// [`Counterparties`] to save once the payment has been made. Paying the same descriptor twice
+/// pays two of its addresses.
+pub(crate) fn resolve_payments<P, S>(
+    payments: Vec<Payment>,
+    store: &mut S,
+    network: Network,
+    allow_network_mismatch: bool,
+) -> Result<(Vec<TxOut>, Option<Counterparties>)>
+where
+    S: PersistBackend<Keychain, P>,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let mut outputs = vec![];
+    let mut counterparties = None;
+    for Payment { recipient, value } in payments {
+        let address = resolve_recipient(
+            recipient,
+            &mut counterparties,
+            store,
+            network,
+            allow_network_mismatch,
+        )?;
+        outputs.push(TxOut {
+            value,
+            script_pubkey: address.script_pubkey(),
+        });
+    }
+    Ok((outputs, counterparties))
+}
 
-                if let Some(script_sig) = final_script_sig {
-                    transaction.input[i].script_sig = script_sig;
-                }
-            }
-            bdk_tmp_plan::PlanState::Incomplete(_) => {
+/// The address to pay `recipient` at. A counterparty's descriptor gives its next address, which
+/// is recorded in `counterparties` (loaded from `store` the first time one is needed).
+///
+/// An address has to be for `network` unless `allow_network_mismatch` is set.
+fn resolve_recipient<P, S>(
+    recipient: Recipient,
+    counterparties: &mut Option<Counterparties>,
+    store: &mut S,
+    network: Network,
+    allow_network_mismatch: bool,
+) -> Result<Address>
+where
+    S: PersistBackend<Keychain, P>,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    match recipient {
+        Recipient::Address(address) => {
+            if !allow_network_mismatch && !address.is_valid_for_network(network) {
                 return Err(anyhow!(
-                    "we weren't able to complete the plan with our keys"
+                    "{} is a {} address but the wallet is on {} (pass --allow-network-mismatch to pay it anyway)",
+                    address,
+                    address.network,
+                    network
                 ));
             }
+            Ok(address)
+        }
+        Recipient::Descriptor(descriptor) => {
+            let counterparties = match counterparties {
+                Some(counterparties) => counterparties,
+                None => counterparties.insert(
+                    load_extension::<Counterparties, _, _>(store, COUNTERPARTIES_EXTENSION)?
+                        .unwrap_or_default(),
+                ),
+            };
+            counterparties.next_address(&descriptor, network)
         }
     }
+}
+
+/// Broadcasts one of our transactions and stores it along with the derivation indices it used.
+pub(crate) fn broadcast_and_store<P, S>(
+    client: &impl Broadcast,
+    tracker: &mut KeychainTracker<Keychain, P>,
+    store: &mut S,
+    transaction: &Transaction,
+) -> Result<()>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    // the backend would reject a non-standard transaction without telling us much
+    validate_standardness(transaction, tracker.graph(), &StandardnessPolicy::default())?;
+    let changeset = tracker.insert_tx(transaction.clone(), P::unconfirmed())?;
+    client.broadcast(transaction)?;
+    // We only want to store the changeset if we actually successfully broadcasted because
+    // it will increase the derivation index of the internal keychain.
+    store.set_derivation_indices(tracker.txout_index.derivation_indices())?;
+    store.append_changeset(&changeset)?;
+    let mut sent = load_extension::<SentTxs, _, _>(store, SENT_EXTENSION)?.unwrap_or_default();
+    let tip_height = tracker
+        .chain()
+        .latest_checkpoint()
+        .map_or(0, |block_id| block_id.height);
+    sent.txs.insert(transaction.txid(), tip_height);
+    save_extension(store, SENT_EXTENSION, &sent)?;
+    Ok(())
+}
+
+/// Describes the transaction of a PSBT made by [`create_tx`] instead of broadcasting it. Nothing is
+/// stored, not even the derivation index of the change address.
+fn dry_run_report<P: ChainPosition>(
+    psbt: Psbt,
+    tracker: &KeychainTracker<Keychain, P>,
+    network: Network,
+    signers: &[Box<dyn Signer>],
+) -> Result<CommandOutput<P>> {
+    eprintln!("Dry run: the transaction has not been broadcast");
+    if is_watch_only(signers) {
+        return Ok(CommandOutput::DryRun {
+            tx: describe_tx(&psbt.unsigned_tx, tracker, network),
+            unsigned_psbt: Some(psbt),
+        });
+    }
+    Ok(CommandOutput::DryRun {
+        tx: describe_tx(&psbt.extract_tx(), tracker, network),
+        unsigned_psbt: None,
+    })
+}
 
-    Ok(transaction)
+/// Broadcasts the transaction of a PSBT made by [`create_tx`] and stores it (saving
+/// `counterparties` once it has paid them). A watch-only wallet outputs the unsigned PSBT instead.
+fn send_psbt<P, S>(
+    psbt: Psbt,
+    counterparties: Option<Counterparties>,
+    client: &impl Broadcast,
+    tracker: &mut KeychainTracker<Keychain, P>,
+    store: &mut S,
+    signers: &[Box<dyn Signer>],
+) -> Result<CommandOutput<P>>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    if is_watch_only(signers) {
+        eprintln!("Watch-only wallet: sign the PSBT elsewhere and broadcast it with `psbt extract --broadcast`");
+        // like `psbt create` the addresses go out with the PSBT
+        store.set_derivation_indices(tracker.txout_index.derivation_indices())?;
+        if let Some(counterparties) = counterparties {
+            save_extension(store, COUNTERPARTIES_EXTENSION, &counterparties)?;
+        }
+        return Ok(CommandOutput::Psbt(psbt));
+    }
+    let transaction = psbt.extract_tx();
+    audit_signing(
+        store,
+        tracker,
+        transaction.txid(),
+        transaction.input.iter().map(|txin| txin.previous_output),
+    )?;
+    broadcast_and_store(client, tracker, store, &transaction)?;
+    // likewise the counterparty's address is only used once we've paid to it
+    if let Some(counterparties) = counterparties {
+        save_extension(store, COUNTERPARTIES_EXTENSION, &counterparties)?;
+    }
+    Ok(CommandOutput::Broadcasted(transaction.txid()))
 }
 
 pub trait Broadcast {
@@ -464,117 +1310,648 @@ pub trait Broadcast {
     fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error>;
 }
 
-pub fn handle_commands<C: clap::Subcommand, P>(
+/// A chain source that can tell what feerate gets a transaction confirmed in time.
+pub trait EstimateFee {
+    type Error: std::error::Error + Send + Sync + 'static;
+    /// The feerate (in sats per vbyte) for a transaction to be confirmed within `target_blocks`
+    /// blocks.
+    fn estimate_fee(&mut self, target_blocks: usize) -> Result<f32, Self::Error>;
+}
+
+impl<T: Broadcast> Broadcast for &mut T {
+    type Error = T::Error;
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        (**self).broadcast(tx)
+    }
+}
+
+impl<T: EstimateFee> EstimateFee for &mut T {
+    type Error = T::Error;
+    fn estimate_fee(&mut self, target_blocks: usize) -> Result<f32, Self::Error> {
+        (**self).estimate_fee(target_blocks)
+    }
+}
+
+/// Runs a command that isn't chain specific.
+///
+/// Nothing is printed, the caller gets what the command produced so that it can display it (e.g.
+/// with the [`Display`] implementation of [`CommandOutput`]) or use it in some other way.
+///
+/// `store` is a blocking [`PersistBackend`]. An application that persists with an
+/// [`AsyncPersistBackend`] uses [`handle_commands_async`] instead.
+///
+/// `import` only adds the descriptors, scanning them is up to the caller. The commands working on
+/// the database file (`backup`, `restore-db` and `recover-db`) fail since `store` may not be one,
+/// the caller runs them with [`run_backup_cmd`], [`run_restore_db_cmd`] and
+/// [`run_recover_db_cmd`] instead.
+///
+/// [`Display`]: core::fmt::Display
+pub fn handle_commands<C: clap::Subcommand, P, S>(
     command: Commands<C>,
-    client: impl Broadcast,
+    mut client: impl Broadcast + EstimateFee,
     tracker: &mut KeychainTracker<Keychain, P>,
-    store: &mut KeychainStore<Keychain, P>,
+    store: &mut S,
     network: Network,
-    keymap: &HashMap<DescriptorPublicKey, DescriptorSecretKey>,
-) -> Result<()>
+    signers: &[Box<dyn Signer>],
+) -> Result<CommandOutput<P>>
 where
     P: ChainPosition,
-    KeychainChangeSet<Keychain, P>: serde::Serialize + serde::de::DeserializeOwned,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
 {
-    match command {
-        // TODO: Make these functions return stuffs
-        Commands::Address { addr_cmd } => {
-            run_address_cmd(tracker, store, addr_cmd, network)?;
-        }
-        Commands::Balance => {
-            run_balance_cmd(&tracker);
+    Ok(match command {
+        Commands::Address { addr_cmd, dry_run } => {
+            let mut labels =
+                load_extension::<Labels, _, _>(store, LABELS_EXTENSION)?.unwrap_or_default();
+            let labels_before = labels.clone();
+            let (output, changeset) = run_address_cmd(tracker, addr_cmd, network, &mut labels)?;
+            if labels != labels_before {
+                if dry_run {
+                    eprintln!("Dry run: the label has not been saved");
+                } else {
+                    save_extension(store, LABELS_EXTENSION, &labels)?;
+                }
+            }
+            if let Some(changeset) = changeset {
+                if dry_run {
+                    eprintln!("Dry run: the address has not been marked as revealed");
+                } else {
+                    // update database since we're about to give out a new address
+                    store.append_changeset(&changeset)?;
+                    tracker.apply_changeset(changeset);
+                }
+            }
+            CommandOutput::Address(output)
         }
-        Commands::TxOut { txout_cmd } => {
-            run_txo_cmd(txout_cmd, tracker, network);
+        Commands::Balance {
+            min_feerate,
+            deposit_policy,
+        } => {
+            let (confirmed, unconfirmed, at_risk) =
+                run_balance_cmd(tracker, min_feerate, &deposit_policy.into());
+            CommandOutput::Balance {
+                confirmed,
+                unconfirmed,
+                at_risk,
+            }
         }
+        Commands::TxOut { txout_cmd } => run_txo_cmd(txout_cmd, tracker, store, network, signers)?,
         Commands::Send {
             value,
-            address,
+            recipient,
             coin_select,
+            change_keychains,
+            subtract_fee,
+            spend,
+            deposit_policy,
+            dust,
+            timelocks,
+            sighash,
+            preimages,
         } => {
-            let transaction = create_tx(value, address, coin_select, tracker, &keymap)?;
-            let changeset = tracker.insert_tx(transaction.clone(), P::unconfirmed())?;
-            client.broadcast(&transaction)?;
-            // We only want to store the changeset if we actually successfully broadcasted because
-            // it will increase the derivation index of the internal keychain.
-            store.set_derivation_indices(tracker.txout_index.derivation_indices())?;
-            store.append_changeset(&changeset)?;
-            println!("Broadcasted Tx : {}", transaction.txid());
+            let payments = vec![Payment { recipient, value }];
+            let (mut outputs, counterparties) =
+                resolve_payments(payments, store, network, spend.allow_network_mismatch)?;
+            outputs.extend(spend.data.as_ref().map(OpReturnData::txout));
+            let frozen =
+                load_extension::<FrozenUtxos, _, _>(store, FROZEN_EXTENSION)?.unwrap_or_default();
+            let mut builder = TxBuilder {
+                subtract_fee_from: if subtract_fee { vec![0] } else { vec![] },
+                ..send_builder(coin_select, change_keychains, deposit_policy, &frozen)
+            };
+            spend.apply_to(&mut builder, &mut client)?;
+            timelocks.apply_to(&mut builder);
+            dust.apply_to(&mut builder);
+            sighash.apply_to(&mut builder);
+            preimages.apply_to(&mut builder)?;
+            let psbt = create_tx(&outputs, &builder, tracker, signers)?;
+            if spend.dry_run {
+                dry_run_report(psbt, tracker, network, signers)?
+            } else {
+                send_psbt(psbt, counterparties, &client, tracker, store, signers)?
+            }
+        }
+        Commands::SendMany {
+            payments,
+            coin_select,
+            change_keychains,
+            subtract_fee_from,
+            spend,
+            dust,
+            deposit_policy,
+            timelocks,
+            sighash,
+            preimages,
+        } => {
+            let (mut outputs, counterparties) =
+                resolve_payments(payments, store, network, spend.allow_network_mismatch)?;
+            outputs.extend(spend.data.as_ref().map(OpReturnData::txout));
+            let frozen =
+                load_extension::<FrozenUtxos, _, _>(store, FROZEN_EXTENSION)?.unwrap_or_default();
+            let mut builder = TxBuilder {
+                subtract_fee_from,
+                ..send_builder(coin_select, change_keychains, deposit_policy, &frozen)
+            };
+            spend.apply_to(&mut builder, &mut client)?;
+            timelocks.apply_to(&mut builder);
+            dust.apply_to(&mut builder);
+            sighash.apply_to(&mut builder);
+            preimages.apply_to(&mut builder)?;
+            let psbt = create_tx(&outputs, &builder, tracker, signers)?;
+            if spend.dry_run {
+                dry_run_report(psbt, tracker, network, signers)?
+            } else {
+                send_psbt(psbt, counterparties, &client, tracker, store, signers)?
+            }
+        }
+        Commands::Drain {
+            recipient,
+            spend,
+            deposit_policy,
+            timelocks,
+            sighash,
+            preimages,
+        } => {
+            let mut counterparties = None;
+            let address = resolve_recipient(
+                recipient,
+                &mut counterparties,
+                store,
+                network,
+                spend.allow_network_mismatch,
+            )?;
+            let mut builder = TxBuilder {
+                confirmation_policy: deposit_policy.into(),
+                drain_to: Some(address.script_pubkey()),
+                ..Default::default()
+            };
+            let frozen =
+                load_extension::<FrozenUtxos, _, _>(store, FROZEN_EXTENSION)?.unwrap_or_default();
+            frozen.exclude_from(&mut builder);
+            spend.apply_to(&mut builder, &mut client)?;
+            timelocks.apply_to(&mut builder);
+            sighash.apply_to(&mut builder);
+            preimages.apply_to(&mut builder)?;
+            let outputs = spend
+                .data
+                .as_ref()
+                .map(OpReturnData::txout)
+                .into_iter()
+                .collect::<Vec<_>>();
+            let psbt = create_tx(&outputs, &builder, tracker, signers)?;
+            if spend.dry_run {
+                dry_run_report(psbt, tracker, network, signers)?
+            } else {
+                send_psbt(psbt, counterparties, &client, tracker, store, signers)?
+            }
+        }
+        Commands::EstimateFee { target_blocks } => CommandOutput::FeeEstimate {
+            target_blocks,
+            feerate: client.estimate_fee(target_blocks)?,
+        },
+        Commands::Cpfp { txid, feerate } => {
+            let feerate = feerate.resolve(&mut client)?;
+            let frozen =
+                load_extension::<FrozenUtxos, _, _>(store, FROZEN_EXTENSION)?.unwrap_or_default();
+            let psbt = create_cpfp_tx(txid, sat_per_vb_to_wu(feerate), &frozen, tracker, signers)?;
+            send_psbt(psbt, None, &client, tracker, store, signers)?
+        }
+        Commands::Psbt { psbt_cmd } => {
+            run_psbt_cmd(psbt_cmd, &mut client, tracker, store, network, signers)?
+        }
+        Commands::Cosign { cosign_cmd } => {
+            run_cosign_cmd(cosign_cmd, &client, tracker, store, signers)?
+        }
+        Commands::Vault { vault_cmd } => CommandOutput::Vault(run_vault_cmd(vault_cmd, tracker)?),
+        Commands::External { external_cmd } => {
+            CommandOutput::External(run_external_cmd(external_cmd, tracker, store)?)
+        }
+        Commands::Deferred { deferred_cmd } => {
+            CommandOutput::Deferred(run_deferred_cmd(deferred_cmd, tracker, store)?)
+        }
+        Commands::Invoice { invoice_cmd } => {
+            CommandOutput::Invoice(run_invoice_cmd(invoice_cmd, tracker, store, network)?)
+        }
+        Commands::Webhook { webhook_cmd } => {
+            CommandOutput::Webhook(webhook::run_webhook_cmd(webhook_cmd, store)?)
+        }
+        Commands::Tx { tx_cmd } => {
+            run_tx_cmd(tx_cmd, &mut client, tracker, store, network, signers)?
+        }
+        Commands::Labels { labels_cmd } => {
+            CommandOutput::Labels(run_labels_cmd(labels_cmd, store, network)?)
+        }
+        Commands::Decode { tx } => CommandOutput::Decode(run_decode_cmd(&tx, tracker, network)?),
+        Commands::Privacy => CommandOutput::Privacy(run_privacy_cmd(tracker, network)),
+        Commands::Inspect { keychain, index } => {
+            CommandOutput::Inspect(run_inspect_cmd(keychain, index, tracker, signers)?)
+        }
+        Commands::Audit { txid, since } => CommandOutput::Audit(run_audit_cmd(store, txid, since)?),
+        Commands::Migrate {
+            new_descriptor,
+            feerate,
+            max_weight,
+            max_fee,
+        } => {
+            let limits = SweepLimits {
+                feerate: sat_per_vb_to_wu(feerate),
+                max_weight,
+                max_fee,
+            };
+            CommandOutput::Migrate(run_migrate_cmd(
+                &new_descriptor,
+                &limits,
+                &client,
+                tracker,
+                store,
+                signers,
+            )?)
+        }
+        Commands::Import { descriptors_json } => {
+            CommandOutput::Imported(run_import_cmd(tracker, store, &descriptors_json)?)
+        }
+        Commands::Generate {
+            script_type,
+            xprv,
+            account,
+        } => CommandOutput::Generated(generate_descriptors(script_type, network, xprv, account)?),
+        Commands::Backup { .. } | Commands::RestoreDb { .. } | Commands::RecoverDb => {
+            return Err(anyhow!(
+                "`backup`, `restore-db` and `recover-db` need the path of the database file, run \
+                 them with `run_backup_cmd`, `run_restore_db_cmd` or `run_recover_db_cmd`"
+            ))
         }
         Commands::ChainSpecific(_) => {
             todo!("example code is meant to handle this!")
         }
-    }
-
-    Ok(())
+    })
 }
 
-pub fn init<C: clap::Subcommand, P>() -> anyhow::Result<(
-    Args<C>,
-    KeyMap,
-    KeychainTracker<Keychain, P>,
-    KeychainStore<Keychain, P>,
-)>
+/// Runs a command that isn't chain specific like [`handle_commands`] but persists with an
+/// [`AsyncPersistBackend`].
+///
+/// The command sees the extension blobs `store` has when it starts. What it writes is kept in
+/// memory while it runs and appended to `store` afterwards in the same order, also when it fails
+/// part way (e.g. after broadcasting) so nothing it did is lost. Only `store` is awaited, `client`
+/// is still called in a blocking way.
+pub async fn handle_commands_async<C: clap::Subcommand, P, S>(
+    command: Commands<C>,
+    client: impl Broadcast + EstimateFee,
+    tracker: &mut KeychainTracker<Keychain, P>,
+    store: &mut S,
+    network: Network,
+    signers: &[Box<dyn Signer>],
+) -> Result<CommandOutput<P>>
 where
-    P: sparse_chain::ChainPosition,
-    KeychainChangeSet<Keychain, P>: serde::Serialize + serde::de::DeserializeOwned,
+    P: ChainPosition,
+    S: AsyncPersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
 {
-    let args = Args::<C>::parse();
+    let (extensions, result) = store.aggregate_extensions().await;
+    result?;
+    let mut buffer = WriteBuffer {
+        extensions,
+        writes: vec![],
+    };
+    let output = handle_commands(command, client, tracker, &mut buffer, network, signers);
+    for write in buffer.writes {
+        match write {
+            Write::Changeset(changeset) => store.append_changeset(&changeset).await?,
+            Write::Extension(name, data) => store.append_extension(&name, &data).await?,
+        }
+    }
+    output
+}
+
+/// A write [`handle_commands_async`] has yet to make.
+enum Write<P> {
+    Changeset(KeychainChangeSet<Keychain, P>),
+    Extension(String, Vec<u8>),
+}
+
+/// Keeps the writes of a command until they can be made to an [`AsyncPersistBackend`].
+struct WriteBuffer<P> {
+    /// The extension blobs of the backend with those written since
+    extensions: BTreeMap<String, Vec<u8>>,
+    writes: Vec<Write<P>>,
+}
+
+impl<P: ChainPosition> PersistBackend<Keychain, P> for WriteBuffer<P> {
+    type WriteError = Infallible;
+    type LoadError = Infallible;
+
+    fn append_changeset(
+        &mut self,
+        changeset: &KeychainChangeSet<Keychain, P>,
+    ) -> Result<(), Self::WriteError> {
+        if !changeset.is_empty() {
+            self.writes.push(Write::Changeset(changeset.clone()));
+        }
+        Ok(())
+    }
+
+    fn append_extension(&mut self, name: &str, data: &[u8]) -> Result<(), Self::WriteError> {
+        self.extensions.insert(name.into(), data.into());
+        self.writes.push(Write::Extension(name.into(), data.into()));
+        Ok(())
+    }
+
+    /// The tracker has already been loaded from the backend so there is nothing to do.
+    fn load_into_keychain_tracker(
+        &mut self,
+        _tracker: &mut KeychainTracker<Keychain, P>,
+    ) -> Result<(), Self::LoadError> {
+        Ok(())
+    }
+
+    fn aggregate_extensions(&mut self) -> (BTreeMap<String, Vec<u8>>, Result<(), Self::LoadError>) {
+        (self.extensions.clone(), Ok(()))
+    }
+}
+
+/// Parses the wallet's descriptor and optional change descriptor into the keychains of a tracker.
+pub fn parse_descriptors(
+    descriptor: &str,
+    change_descriptor: Option<&str>,
+) -> Result<(BTreeMap<Keychain, Descriptor<DescriptorPublicKey>>, KeyMap)> {
     let secp = Secp256k1::default();
+    let mut keychains = BTreeMap::new();
     let (descriptor, mut keymap) =
-        Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, &args.descriptor)?;
+        Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, descriptor)?;
+    keychains.insert(Keychain::External, descriptor);
 
-    let mut tracker = KeychainTracker::default();
-    tracker.set_checkpoint_limit(Some(args.cp_limit));
+    if let Some(change_descriptor) = change_descriptor {
+        let (internal_descriptor, internal_keymap) =
+            Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, change_descriptor)?;
+        keymap.extend(internal_keymap);
+        keychains.insert(Keychain::Internal, internal_descriptor);
+    }
 
+    Ok((keychains, keymap))
+}
+
+/// Creates an empty tracker for `keychains`.
+pub fn build_tracker<K: Clone + Ord + Debug, P: ChainPosition>(
+    keychains: BTreeMap<K, Descriptor<DescriptorPublicKey>>,
+    checkpoint_limit: Option<usize>,
+) -> KeychainTracker<K, P> {
+    let mut tracker = KeychainTracker::default();
+    tracker.set_checkpoint_limit(checkpoint_limit);
+    for (keychain, descriptor) in keychains {
+        tracker.txout_index.add_keychain(keychain, descriptor);
+    }
     tracker
-        .txout_index
-        .add_keychain(Keychain::External, descriptor);
-
-    let internal = args
-        .change_descriptor
-        .clone()
-        .map(|descriptor| Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, &descriptor))
-        .transpose()?;
-    if let Some((internal_descriptor, internal_keymap)) = internal {
-        keymap.extend(internal_keymap);
-        tracker
-            .txout_index
-            .add_keychain(Keychain::Internal, internal_descriptor);
-    };
+}
 
-    let mut db = KeychainStore::<Keychain, P>::new_from_path(args.db_path.as_path())?;
+/// Loads the JSON encoded extension blob saved under `name` (e.g. a [`SyncCursor`]).
+pub fn load_extension<T: serde::de::DeserializeOwned, P, S>(
+    store: &mut S,
+    name: &str,
+) -> Result<Option<T>>
+where
+    S: PersistBackend<Keychain, P>,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let (mut extensions, result) = store.aggregate_extensions();
+    result?;
+    extensions
+        .remove(name)
+        .map(|data| Ok(serde_json::from_slice(&data)?))
+        .transpose()
+}
 
-    if let Err(e) = db.load_into_keychain_tracker(&mut tracker) {
-        match tracker.chain().latest_checkpoint()  {
-            Some(checkpoint) => eprintln!("Failed to load all changesets from {}. Last checkpoint was at height {}. Error: {}", args.db_path.display(), checkpoint.height, e),
-            None => eprintln!("Failed to load any checkpoints from {}: {}", args.db_path.display(), e),
+/// Saves `value` as a JSON encoded extension blob under `name` replacing the previous one.
+pub fn save_extension<T: serde::Serialize, P, S>(store: &mut S, name: &str, value: &T) -> Result<()>
+where
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+{
+    store.append_extension(name, &serde_json::to_vec(value)?)?;
+    Ok(())
+}
 
+/// A wallet of the `--wallets` file, which maps names to these.
+///
+/// Wallets that share a database have to be on the same network. They are told apart by
+/// `db_wallet`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WalletConfig {
+    pub network: Network,
+    pub descriptor: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_descriptor: Option<String>,
+    pub db_path: PathBuf,
+    /// The wallet in the database (see [`KeychainStore::open_wallet`]), its default wallet if not
+    /// set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub db_wallet: Option<String>,
+    /// The servers of the chain source to use for this wallet instead of the ones given on the
+    /// command line (or the default ones of its network)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub servers: Vec<String>,
+}
+
+/// Reads the wallets of a `--wallets` file and checks that the ones sharing a database are on
+/// the same network.
+pub fn load_wallet_configs(path: &Path) -> Result<BTreeMap<String, WalletConfig>> {
+    let configs: BTreeMap<String, WalletConfig> = serde_json::from_slice(&std::fs::read(path)?)
+        .map_err(|e| anyhow!("invalid wallets file {}: {}", path.display(), e))?;
+    let mut db_networks = HashMap::<&Path, (&str, Network)>::new();
+    for (name, config) in &configs {
+        let (other, network) = *db_networks
+            .entry(&config.db_path)
+            .or_insert((name, config.network));
+        if network != config.network {
+            return Err(anyhow!(
+                "wallets {} and {} share the database {} but are on different networks",
+                other,
+                name,
+                config.db_path.display()
+            ));
+        }
+    }
+    Ok(configs)
+}
+
+impl<C: clap::Subcommand> Args<C> {
+    /// A token that is cancelled once `--timeout` has passed (and never without it).
+    pub fn cancellation_token(&self) -> CancellationToken {
+        let cancel = CancellationToken::new();
+        if let Some(timeout) = self.timeout {
+            let timer = cancel.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_secs(timeout));
+                timer.cancel();
+            });
+        }
+        cancel
+    }
+
+    /// The wallets the arguments describe by name: those of the `--wallets` file, or else the one
+    /// wallet the descriptors, `--network` and `--db-path` describe, named after `--wallet` (or
+    /// `default`).
+    pub fn wallet_configs(&self) -> Result<BTreeMap<String, WalletConfig>> {
+        if let Some(path) = &self.wallets {
+            return load_wallet_configs(path);
         }
-        eprintln!("⚠ Consider running a rescan of chain data.");
+        let descriptor = self
+            .descriptor
+            .clone()
+            .ok_or_else(|| anyhow!("a descriptor or --wallets is required"))?;
+        let config = WalletConfig {
+            network: self.network,
+            descriptor,
+            change_descriptor: self.change_descriptor.clone(),
+            db_path: self.db_path.clone(),
+            db_wallet: self.wallet.clone(),
+            servers: vec![],
+        };
+        let name = self.wallet.clone().unwrap_or_else(|| "default".to_string());
+        Ok(BTreeMap::from([(name, config)]))
     }
 
-    Ok((args, keymap, tracker, db))
+    /// The wallet commands apply to: the one `--wallet` names in the `--wallets` file (which can
+    /// be left out if the file only has one), or the one the arguments describe without it.
+    pub fn selected_wallet(&self) -> Result<(String, WalletConfig)> {
+        let mut configs = self.wallet_configs()?;
+        if configs.len() == 1 {
+            return Ok(configs.into_iter().next().expect("there is one"));
+        }
+        if configs.is_empty() {
+            return Err(anyhow!("there are no wallets in the wallets file"));
+        }
+        let names = configs.keys().cloned().collect::<Vec<_>>().join(", ");
+        let name = self
+            .wallet
+            .as_ref()
+            .ok_or_else(|| anyhow!("pick one of the wallets with --wallet: {}", names))?;
+        let config = configs
+            .remove(name)
+            .ok_or_else(|| anyhow!("no wallet named {}, the wallets are {}", name, names))?;
+        Ok((name.clone(), config))
+    }
 }
 
-pub fn planned_utxos<'a, AK: bdk_tmp_plan::CanDerive + Clone, P: ChainPosition>(
-    tracker: &'a KeychainTracker<Keychain, P>,
-    assets: &'a bdk_tmp_plan::Assets<AK>,
-) -> impl Iterator<Item = (bdk_tmp_plan::Plan<AK>, FullTxOut<P>)> + 'a {
-    tracker
-        .full_utxos()
-        .filter_map(|((keychain, derivation_index), full_txout)| {
-            Some((
-                bdk_tmp_plan::plan_satisfaction(
-                    &tracker
-                        .txout_index
-                        .keychains()
-                        .get(keychain)
-                        .expect("must exist since we have a utxo for it")
-                        .at_derivation_index(*derivation_index),
-                    assets,
-                )?,
-                full_txout,
-            ))
-        })
+/// A wallet set up from a [`WalletConfig`]: its tracker and store along with a [`Signer`] for the
+/// secret keys in its descriptors and the `--signing-key`s.
+pub struct Wallet<P> {
+    pub name: String,
+    pub config: WalletConfig,
+    pub signers: Vec<Box<dyn Signer>>,
+    pub tracker: KeychainTracker<Keychain, P>,
+    pub store: KeychainStore<Keychain, P>,
+}
+
+/// Parses the command line arguments and sets up the wallet they select (see
+/// [`Args::selected_wallet`]). Commands that need no wallet are run right away and `None` is
+/// returned (see [`run_walletless_cmd`]).
+pub fn init<C: clap::Subcommand, P>() -> anyhow::Result<Option<(Args<C>, Wallet<P>)>>
+where
+    P: PositionSchema,
+    KeychainChangeSet<Keychain, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    let args = Args::<C>::parse();
+    if run_walletless_cmd(&args)? {
+        return Ok(None);
+    }
+    let (name, config) = args.selected_wallet()?;
+    let wallet = open_wallet(&args, name, config)?;
+    Ok(Some((args, wallet)))
+}
+
+/// Runs `args.command` and prints what it outputs if it doesn't need a wallet (`generate`).
+/// Returns whether it was run.
+pub fn run_walletless_cmd<C: clap::Subcommand>(args: &Args<C>) -> anyhow::Result<bool> {
+    match &args.command {
+        Commands::Generate {
+            script_type,
+            xprv,
+            account,
+        } => {
+            let xprv = match (xprv, &args.mnemonic) {
+                (None, Some(mnemonic)) => Some(mnemonic_master_key(
+                    mnemonic,
+                    args.mnemonic_passphrase.as_deref().unwrap_or(""),
+                    args.network,
+                )?),
+                (xprv, _) => *xprv,
+            };
+            let generated = generate_descriptors(*script_type, args.network, xprv, *account)?;
+            print!("{}", generated);
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Sets up the wallet `config` describes. The arguments that aren't specific to a wallet, like
+/// the database password or `--signing-key`, apply to every wallet.
+pub fn open_wallet<C: clap::Subcommand, P>(
+    args: &Args<C>,
+    name: String,
+    config: WalletConfig,
+) -> anyhow::Result<Wallet<P>>
+where
+    P: PositionSchema,
+    KeychainChangeSet<Keychain, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    let (keychains, mut keymap) =
+        parse_descriptors(&config.descriptor, config.change_descriptor.as_deref())?;
+
+    let mut db = open_store(
+        &config.db_path,
+        args.db_password.as_deref(),
+        args.db_hash_chain,
+    )?;
+    if let Some(wallet) = &config.db_wallet {
+        db = db.open_wallet(wallet);
+    }
+    let mut tracker = wallet_tracker(keychains, Some(args.cp_limit), &mut db)?;
+    if let Commands::RecoverDb = args.command {
+        // the command recovers the store itself
+        load_or_warn(&mut db, &mut tracker, &config.db_path);
+    } else {
+        load_or_recover(&mut db, &mut tracker, &config.db_path)?;
+    }
+    if args.db_hash_chain {
+        check_head_hash(&mut db, &config.db_path, args.db_head)?;
+    }
+
+    for key in &args.signing_keys {
+        let (public_key, secret_key) = parse_signing_key(key)?;
+        keymap.insert(public_key, secret_key);
+    }
+    if let Some(mnemonic) = &args.mnemonic {
+        let master = mnemonic_master_key(
+            mnemonic,
+            args.mnemonic_passphrase.as_deref().unwrap_or(""),
+            config.network,
+        )?;
+        keymap.extend(derive_keymap(
+            &master,
+            tracker.txout_index.keychains().values(),
+        )?);
+    }
+    let signers: Vec<Box<dyn Signer>> = vec![Box::new(keymap)];
+    #[cfg(feature = "hwi")]
+    let signers = {
+        let mut signers = signers;
+        if args.hwi {
+            let descriptors = tracker.txout_index.keychains().values();
+            for signer in hwi::connected_signers(&args.hwi_command, config.network, descriptors)? {
+                eprintln!("signing with the hardware wallet {}", signer.fingerprint);
+                signers.push(Box::new(signer));
+            }
+        }
+        signers
+    };
+    Ok(Wallet {
+        name,
+        config,
+        signers,
+        tracker,
+        store: db,
+    })
 }
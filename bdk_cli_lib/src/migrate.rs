@@ -0,0 +1,332 @@
+//! Sweeping the wallet's coins to a new descriptor.
+use crate::{
+    audit_signing, broadcast_and_store, check_maturity, described_utxos, load_extension,
+    save_extension, sign_with_plans, signer_assets, AntiFeeSniping, Broadcast, DescribedUtxo,
+    Keychain, MigrationReport, Signer,
+};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{
+        consensus::encode::serialize, secp256k1::Secp256k1, OutPoint, Script, Sequence,
+        Transaction, TxIn, TxOut, Txid, VarInt,
+    },
+    keychain::{KeychainTracker, PersistBackend},
+    miniscript::{Descriptor, DescriptorPublicKey},
+    sparse_chain::ChainPosition,
+    standardness::{dust_threshold, StandardnessPolicy},
+};
+use bdk_coin_select::TXIN_BASE_WEIGHT;
+use std::{cmp::Reverse, fmt::Debug};
+
+/// The name of the extension blob the progress of a [`Migration`] is saved under.
+pub const MIGRATION_EXTENSION: &str = "migration";
+
+/// Moving the wallet's funds to a new descriptor with the `migrate` command.
+///
+/// The new descriptor is tracked as [`Keychain::Migration`] until the wallet is started with it as
+/// its own descriptor.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Migration {
+    /// The descriptor the funds are moved to (without any secret keys)
+    pub descriptor: String,
+    /// The sweep transactions broadcast so far
+    pub sweeps: Vec<Txid>,
+}
+
+/// The limits each sweep transaction of a migration has to stay within.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SweepLimits {
+    /// The feerate of the sweep transactions in sats per weight unit
+    pub feerate: f32,
+    /// The maximum weight of a sweep transaction
+    pub max_weight: u32,
+    /// The maximum fee a sweep transaction may pay
+    pub max_fee: Option<u64>,
+}
+
+/// The UTXOs moved by one sweep transaction and the fee it pays.
+#[derive(Clone, Debug)]
+pub struct SweepBatch<P> {
+    pub utxos: Vec<DescribedUtxo<Keychain, DescriptorPublicKey, P>>,
+    pub fee: u64,
+}
+
+/// A UTXO that can't be swept and why.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct LeftBehind {
+    pub outpoint: OutPoint,
+    pub value: u64,
+    pub reason: String,
+}
+
+impl LeftBehind {
+    fn new<P>(utxo: &DescribedUtxo<Keychain, DescriptorPublicKey, P>, reason: &str) -> Self {
+        Self {
+            outpoint: utxo.full_txout.outpoint,
+            value: utxo.full_txout.txout.value,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl core::fmt::Display for LeftBehind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} ({} sats) {}", self.outpoint, self.value, self.reason)
+    }
+}
+
+/// The weight of a sweep transaction with `inputs` inputs weighing `input_weight` in total and a
+/// single output to `destination`.
+fn sweep_weight(inputs: usize, input_weight: u32, destination: &Script) -> u32 {
+    let output = TxOut {
+        value: 0,
+        script_pubkey: destination.clone(),
+    };
+    // version, locktime, input count, output count (and the segwit marker and flag to be safe)
+    let base_size = 4 + 4 + VarInt(inputs as u64).len() + 1 + serialize(&output).len();
+    base_size as u32 * 4 + 2 + input_weight
+}
+
+/// Splits `utxos` into batches that can each be swept to `destination` by a transaction within
+/// `limits`.
+///
+/// UTXOs we can't sign for, that cost more to spend than they are worth at the feerate or that
+/// would make a transaction exceed the limits on their own are left behind. So are the UTXOs of a
+/// batch that wouldn't leave more than dust after paying the fee.
+pub fn plan_sweeps<P: ChainPosition>(
+    mut utxos: Vec<DescribedUtxo<Keychain, DescriptorPublicKey, P>>,
+    destination: &Script,
+    limits: &SweepLimits,
+) -> (Vec<SweepBatch<P>>, Vec<LeftBehind>) {
+    let mut left_behind = vec![];
+    let fee_of = |weight: u32| (weight as f32 * limits.feerate).ceil() as u64;
+    let fits = |inputs: usize, input_weight: u32| {
+        let weight = sweep_weight(inputs, input_weight, destination);
+        weight <= limits.max_weight && limits.max_fee.is_none_or(|max| fee_of(weight) <= max)
+    };
+
+    // sweep the largest UTXOs first so the value moved by each transaction is as large as possible
+    utxos.sort_by_key(|utxo| Reverse(utxo.full_txout.txout.value));
+
+    let mut batches: Vec<(Vec<_>, u32)> = vec![];
+    let mut current: (Vec<_>, u32) = (vec![], 0);
+    for utxo in utxos {
+        let weight = match utxo.plan_weight() {
+            Some(plan_weight) => TXIN_BASE_WEIGHT + plan_weight as u32,
+            None => {
+                left_behind.push(LeftBehind::new(&utxo, "can't be spent with our keys"));
+                continue;
+            }
+        };
+        if utxo.full_txout.txout.value <= fee_of(weight) {
+            left_behind.push(LeftBehind::new(
+                &utxo,
+                "costs more to spend than it is worth at this feerate",
+            ));
+            continue;
+        }
+        if !fits(1, weight) {
+            left_behind.push(LeftBehind::new(
+                &utxo,
+                "can't be swept within the weight and fee limits",
+            ));
+            continue;
+        }
+        if !fits(current.0.len() + 1, current.1 + weight) {
+            batches.push(core::mem::take(&mut current));
+        }
+        current.0.push(utxo);
+        current.1 += weight;
+    }
+    if !current.0.is_empty() {
+        batches.push(current);
+    }
+
+    let dust = dust_threshold(
+        destination,
+        StandardnessPolicy::default().dust_relay_feerate,
+    );
+    let mut sweeps = vec![];
+    for (utxos, input_weight) in batches {
+        let fee = fee_of(sweep_weight(utxos.len(), input_weight, destination));
+        let value = utxos
+            .iter()
+            .map(|utxo| utxo.full_txout.txout.value)
+            .sum::<u64>();
+        if value < fee + dust {
+            for utxo in &utxos {
+                left_behind.push(LeftBehind::new(
+                    utxo,
+                    "wouldn't leave more than dust after paying the fee",
+                ));
+            }
+            continue;
+        }
+        sweeps.push(SweepBatch { utxos, fee });
+    }
+
+    (sweeps, left_behind)
+}
+
+/// Creates a transaction moving all the UTXOs of `batch` to `destination`, paying the batch's fee.
+pub fn create_sweep_tx<P: ChainPosition>(
+    batch: &SweepBatch<P>,
+    destination: Script,
+    anti_fee_sniping: &AntiFeeSniping,
+    tip_height: Option<u32>,
+    signers: &[Box<dyn Signer>],
+) -> Result<Transaction> {
+    let plans = batch
+        .utxos
+        .iter()
+        .map(|utxo| Some(utxo.plan.as_ref().expect("swept utxos have a plan")))
+        .collect::<Vec<_>>();
+    let prevouts = batch
+        .utxos
+        .iter()
+        .map(|utxo| utxo.full_txout.txout.clone())
+        .collect::<Vec<_>>();
+    let value = prevouts.iter().map(|txout| txout.value).sum::<u64>() - batch.fee;
+
+    let lock_time = anti_fee_sniping.locktime(
+        tip_height,
+        plans
+            .iter()
+            .flatten()
+            .filter_map(|plan| plan.required_locktime()),
+        &mut rand::thread_rng(),
+    )?;
+    let mut transaction = Transaction {
+        version: 0x02,
+        lock_time: lock_time.into(),
+        input: batch
+            .utxos
+            .iter()
+            .map(|utxo| TxIn {
+                previous_output: utxo.full_txout.outpoint,
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            })
+            .collect(),
+        output: vec![TxOut {
+            value,
+            script_pubkey: destination,
+        }],
+    };
+    sign_with_plans(&mut transaction, &plans, &prevouts, signers)?;
+
+    Ok(transaction)
+}
+
+/// Sweeps the funds of every keychain but [`Keychain::Migration`] to `descriptor` with as many
+/// transactions as `limits` require.
+///
+/// The descriptor is added to the tracker and recorded in the [`Migration`] extension which is
+/// updated as each sweep is broadcast, so running it again (with the same descriptor) picks up
+/// what was left behind, e.g. outputs that were immature or arrived later.
+pub fn run_migrate_cmd<P, S>(
+    descriptor: &str,
+    limits: &SweepLimits,
+    client: &impl Broadcast,
+    tracker: &mut KeychainTracker<Keychain, P>,
+    store: &mut S,
+    signers: &[Box<dyn Signer>],
+) -> Result<MigrationReport>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let (descriptor, _) =
+        Descriptor::<DescriptorPublicKey>::parse_descriptor(&Secp256k1::default(), descriptor)?;
+    let mut migration = match load_extension::<Migration, _, _>(store, MIGRATION_EXTENSION)? {
+        Some(migration) if migration.descriptor != descriptor.to_string() => {
+            return Err(anyhow!(
+                "a migration to {} is already in progress",
+                migration.descriptor
+            ))
+        }
+        Some(migration) => migration,
+        None => Migration {
+            descriptor: descriptor.to_string(),
+            sweeps: vec![],
+        },
+    };
+    tracker
+        .txout_index
+        .add_keychain(Keychain::Migration, descriptor);
+    save_extension(store, MIGRATION_EXTENSION, &migration)?;
+
+    let assets = signer_assets(signers);
+    let tip_height = tracker
+        .chain()
+        .latest_checkpoint()
+        .map(|block_id| block_id.height);
+
+    let mut utxos = vec![];
+    let mut left_behind = vec![];
+    for utxo in described_utxos(tracker, &assets) {
+        if utxo.keychain == Keychain::Migration {
+            continue;
+        }
+        match check_maturity(&utxo, &assets, tip_height) {
+            Ok(()) => utxos.push(utxo),
+            Err(immature) => left_behind.push(LeftBehind {
+                outpoint: immature.outpoint,
+                value: immature.value,
+                reason: "hasn't matured yet".into(),
+            }),
+        }
+    }
+    let destination = tracker
+        .txout_index
+        .keychains()
+        .get(&Keychain::Migration)
+        .expect("just added")
+        .at_derivation_index(0)
+        .script_pubkey();
+    let (batches, not_swept) = plan_sweeps(utxos, &destination, limits);
+    left_behind.extend(not_swept);
+
+    let mut broadcast = vec![];
+    for batch in &batches {
+        // a fresh address for each sweep since the last one is used once it's inserted
+        let (_, destination) = tracker.txout_index.next_unused(&Keychain::Migration);
+        let transaction = create_sweep_tx(
+            batch,
+            destination.clone(),
+            &AntiFeeSniping::default(),
+            tip_height,
+            signers,
+        )?;
+        audit_signing(
+            store,
+            tracker,
+            transaction.txid(),
+            transaction.input.iter().map(|txin| txin.previous_output),
+        )?;
+        broadcast_and_store(client, tracker, store, &transaction)?;
+        migration.sweeps.push(transaction.txid());
+        save_extension(store, MIGRATION_EXTENSION, &migration)?;
+        broadcast.push(transaction.txid());
+    }
+
+    let confirmed = migration
+        .sweeps
+        .iter()
+        .filter(|txid| {
+            tracker
+                .chain()
+                .tx_position(**txid)
+                .is_some_and(|position| position.height().is_confirmed())
+        })
+        .count();
+    Ok(MigrationReport {
+        descriptor: migration.descriptor,
+        broadcast,
+        confirmed,
+        sweeps: migration.sweeps.len(),
+        left_behind,
+    })
+}
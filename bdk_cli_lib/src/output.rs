@@ -0,0 +1,1381 @@
+//! What running the [`Commands`](crate::Commands) produces.
+//!
+//! Every command returns a [`CommandOutput`] instead of printing. Its [`Display`] is the human
+//! readable report the examples print while the types themselves can be serialized (e.g. to
+//! JSON) for other programs to consume.
+//!
+//! [`Display`]: core::fmt::Display
+use crate::{
+    webhook::WebhookOutput, GeneratedDescriptors, InvoiceStatus, Keychain, LeftBehind, MissingAuth,
+    ScriptMetadata, SigningEvent,
+};
+use bdk_chain::{
+    bitcoin::{
+        consensus::encode::serialize_hex, util::psbt::PartiallySignedTransaction as Psbt, Address,
+        LockTime, OutPoint, Script, Transaction, Txid,
+    },
+    keychain::GapStats,
+    sparse_chain::ChainPosition,
+    FullTxOut, TxHeight,
+};
+use core::fmt::{self, Debug, Display, Formatter};
+use std::path::PathBuf;
+
+/// What running one of the [`Commands`](crate::Commands) produced.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum CommandOutput<P> {
+    Address(AddressOutput),
+    Balance {
+        confirmed: u64,
+        unconfirmed: u64,
+        /// Unconfirmed funds that may never confirm (see [`bdk_chain::keychain::Balance::at_risk`])
+        at_risk: u64,
+    },
+    TxOuts(TxOutOutput<Keychain, P>),
+    Tx(TxOutput<P>),
+    /// The txid of a transaction that has been broadcast
+    Broadcasted(Txid),
+    /// A PSBT to pass on, e.g. to be signed elsewhere
+    Psbt(Psbt),
+    /// A transaction that is ready but wasn't broadcast
+    Transaction(Transaction),
+    /// The transaction `send` or `drain` would have broadcast, along with the PSBT if it still has
+    /// to be signed
+    DryRun {
+        tx: TxDescription<Keychain, P>,
+        unsigned_psbt: Option<Psbt>,
+    },
+    Cosign(CosignOutput),
+    FeeEstimate {
+        target_blocks: usize,
+        /// In sats per vbyte
+        feerate: f32,
+    },
+    Vault(Vec<VaultStatus>),
+    External(ExternalOutput),
+    Deferred(DeferredOutput),
+    Invoice(InvoiceOutput<P>),
+    Webhook(WebhookOutput),
+    Labels(LabelsOutput),
+    Decode(TxDescription<Keychain, P>),
+    Privacy(PrivacyReport<Keychain>),
+    Inspect(Vec<KeychainPolicy>),
+    Audit(Vec<SigningEvent>),
+    Migrate(MigrationReport),
+    Backup(BackupReport),
+    Restore(RestoreReport),
+    Recover(RecoveryReport),
+    /// The keychains of the descriptors that were imported, which still have to be scanned for
+    /// their history
+    Imported(Vec<Keychain>),
+    Generated(GeneratedDescriptors),
+}
+
+impl<P: ChainPosition> Display for CommandOutput<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandOutput::Address(output) => write!(f, "{}", output),
+            CommandOutput::Balance {
+                confirmed,
+                unconfirmed,
+                at_risk,
+            } => {
+                writeln!(f, "confirmed: {}", confirmed)?;
+                writeln!(f, "unconfirmed: {}", unconfirmed)?;
+                writeln!(f, "at risk: {}", at_risk)
+            }
+            CommandOutput::TxOuts(output) => write!(f, "{}", output),
+            CommandOutput::Tx(output) => write!(f, "{}", output),
+            CommandOutput::Broadcasted(txid) => writeln!(f, "Broadcasted Tx : {}", txid),
+            CommandOutput::Psbt(psbt) => writeln!(f, "{}", psbt),
+            CommandOutput::Transaction(tx) => writeln!(f, "{}", serialize_hex(tx)),
+            CommandOutput::DryRun { tx, unsigned_psbt } => {
+                write!(f, "{}", tx)?;
+                if let Some(psbt) = unsigned_psbt {
+                    writeln!(
+                        f,
+                        "the transaction isn't signed: the signatures will change its txid and lower its feerate"
+                    )?;
+                    writeln!(f, "{}", psbt)?;
+                }
+                Ok(())
+            }
+            CommandOutput::Cosign(output) => write!(f, "{}", output),
+            CommandOutput::FeeEstimate { feerate, .. } => writeln!(f, "{:.2} sats/vbyte", feerate),
+            CommandOutput::Vault(statuses) => {
+                for status in statuses {
+                    writeln!(
+                        f,
+                        "{} {} {}:{} after:{:?} older:{:?} {}",
+                        status.outpoint,
+                        status.value,
+                        status.keychain,
+                        status.index,
+                        status.after,
+                        status.older,
+                        status.status
+                    )?;
+                }
+                Ok(())
+            }
+            CommandOutput::External(output) => write!(f, "{}", output),
+            CommandOutput::Deferred(output) => write!(f, "{}", output),
+            CommandOutput::Invoice(output) => write!(f, "{}", output),
+            CommandOutput::Webhook(output) => write!(f, "{}", output),
+            CommandOutput::Labels(output) => write!(f, "{}", output),
+            CommandOutput::Decode(tx) => write!(f, "{}", tx),
+            CommandOutput::Privacy(report) => write!(f, "{}", report),
+            CommandOutput::Inspect(policies) => {
+                for policy in policies {
+                    write!(f, "{}", policy)?;
+                }
+                Ok(())
+            }
+            CommandOutput::Audit(events) => {
+                for event in events {
+                    writeln!(f, "{} signed {}", event.timestamp, event.txid)?;
+                    for input in &event.inputs {
+                        writeln!(f, "  {} {}:{}", input.outpoint, input.keychain, input.index)?;
+                    }
+                }
+                Ok(())
+            }
+            CommandOutput::Migrate(report) => write!(f, "{}", report),
+            CommandOutput::Backup(report) => write!(f, "{}", report),
+            CommandOutput::Restore(report) => write!(f, "{}", report),
+            CommandOutput::Recover(report) => write!(f, "{}", report),
+            CommandOutput::Imported(keychains) => {
+                for keychain in keychains {
+                    writeln!(f, "imported {}", keychain)?;
+                }
+                Ok(())
+            }
+            CommandOutput::Generated(generated) => write!(f, "{}", generated),
+        }
+    }
+}
+
+/// A structure defining output of a AddressCmd execution.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct AddrsOutput {
+    pub keychain: String,
+    pub index: u32,
+    pub addrs: Address,
+    pub used: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// The output of an [`AddressCmd`](crate::AddressCmd).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum AddressOutput {
+    /// An address to give out
+    Address { index: u32, address: Address },
+    /// How far each keychain has been derived and used
+    Indices {
+        /// The lookahead the gaps were checked against
+        lookahead: u32,
+        keychains: Vec<(Keychain, GapStats)>,
+    },
+    /// The addresses of a keychain
+    List(Vec<AddrsOutput>),
+    /// The address that was labeled, without a label if it was removed
+    Labeled {
+        address: Address,
+        label: Option<String>,
+    },
+}
+
+impl Display for AddressOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressOutput::Address { address, .. } => writeln!(f, "{}", address),
+            AddressOutput::Indices {
+                lookahead,
+                keychains,
+            } => {
+                let show = |index: Option<u32>| match index {
+                    Some(index) => index.to_string(),
+                    None => "none".to_string(),
+                };
+                for (keychain, stats) in keychains {
+                    write!(
+                        f,
+                        "{:?}: derivation index {} last used {} gap {} max gap {}",
+                        keychain,
+                        show(stats.derivation_index),
+                        show(stats.last_active_index),
+                        stats.current_gap,
+                        stats.max_gap
+                    )?;
+                    match stats.lookahead_shortfall(*lookahead) {
+                        0 => writeln!(f, " lookahead:ok")?,
+                        missing => writeln!(f, " lookahead:short by {}", missing)?,
+                    }
+                }
+                Ok(())
+            }
+            AddressOutput::List(addrs) => {
+                for addr in addrs {
+                    write!(f, "{:?} {} used:{}", addr.index, addr.addrs, addr.used)?;
+                    match &addr.label {
+                        Some(label) => writeln!(f, " label:{:?}", label)?,
+                        None => writeln!(f)?,
+                    }
+                }
+                Ok(())
+            }
+            AddressOutput::Labeled { address, label } => match label {
+                Some(label) => writeln!(f, "Labeled {} {:?}", address, label),
+                None => writeln!(f, "Removed the label of {}", address),
+            },
+        }
+    }
+}
+
+/// An unspent output in the format of bitcoind's `listunspent` RPC.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CoreUnspent {
+    pub txid: String,
+    pub vout: u32,
+    pub address: Address,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pubkey: String,
+    pub amount: f64,
+    pub confirmations: u32,
+    pub spendable: bool,
+    pub solvable: bool,
+    pub desc: String,
+}
+
+/// A transaction output of the wallet listed by `txout list`.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListedTxOut<K, P> {
+    pub spk_index: (K, u32),
+    pub address: Address,
+    pub full_txout: FullTxOut<P>,
+    /// The feerate (in sats per vbyte) of the unconfirmed transaction that created the output, if
+    /// its prevouts are known
+    pub feerate: Option<f32>,
+    /// The feerate (in sats per vbyte) of the unconfirmed transaction that created the output
+    /// together with its unconfirmed ancestors
+    pub ancestor_feerate: Option<f32>,
+    /// The age of the output in blocks (only listed with `--verbose`)
+    pub age: Option<u32>,
+    /// The coin days spending the output would destroy (only listed with `--verbose`)
+    pub coin_days_destroyed: Option<f64>,
+    /// Whether the output was frozen with `txout freeze`
+    pub frozen: bool,
+}
+
+/// The output of a [`TxOutCmd`](crate::TxOutCmd).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum TxOutOutput<K, P> {
+    List(Vec<ListedTxOut<K, P>>),
+    /// The wallet's UTXOs in the format of bitcoind's `listunspent`
+    Core(Vec<CoreUnspent>),
+    Froze(OutPoint),
+    Unfroze(OutPoint),
+}
+
+impl<K: Debug, P: Debug> Display for TxOutOutput<K, P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TxOutOutput::List(txouts) => {
+                for txout in txouts {
+                    write!(
+                        f,
+                        "{:?} {} {} {} spent:{:?}",
+                        txout.spk_index,
+                        txout.full_txout.txout.value,
+                        txout.full_txout.outpoint,
+                        txout.address,
+                        txout.full_txout.spent_by
+                    )?;
+                    if let Some(feerate) = txout.feerate {
+                        write!(f, " feerate:{:.1}", feerate)?;
+                    }
+                    if let Some(ancestor_feerate) = txout.ancestor_feerate {
+                        write!(f, " ancestor_feerate:{:.1}", ancestor_feerate)?;
+                    }
+                    if let Some(age) = txout.age {
+                        write!(f, " age:{}", age)?;
+                    }
+                    if let Some(coin_days_destroyed) = txout.coin_days_destroyed {
+                        write!(f, " coin_days:{:.4}", coin_days_destroyed)?;
+                    }
+                    if txout.frozen {
+                        write!(f, " frozen")?;
+                    }
+                    writeln!(f)?;
+                }
+                Ok(())
+            }
+            TxOutOutput::Core(unspents) => writeln!(
+                f,
+                "{}",
+                serde_json::to_string_pretty(unspents).map_err(|_| fmt::Error)?
+            ),
+            TxOutOutput::Froze(outpoint) => writeln!(f, "Froze {}", outpoint),
+            TxOutOutput::Unfroze(outpoint) => writeln!(f, "Unfroze {}", outpoint),
+        }
+    }
+}
+
+/// The output of a [`TxCmd`](crate::TxCmd).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum TxOutput<P> {
+    Abandoned(Txid),
+    Evicted(Vec<EvictionReport<P>>),
+    List(Vec<TxSummary<P>>),
+    /// The transaction that was labeled, without a label if it was removed
+    Labeled {
+        txid: Txid,
+        label: Option<String>,
+    },
+    /// The expiry policy, see [`ExpiryPolicy`](crate::ExpiryPolicy)
+    Expiry {
+        after_blocks: Option<u32>,
+    },
+    Details(TxDetails<P>),
+}
+
+impl<P: ChainPosition> Display for TxOutput<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TxOutput::Abandoned(txid) => writeln!(f, "Abandoned {}", txid),
+            TxOutput::Evicted(reports) => {
+                for report in reports {
+                    write!(f, "{}", report)?;
+                }
+                Ok(())
+            }
+            TxOutput::List(summaries) => {
+                for summary in summaries {
+                    write!(f, "{}", summary)?;
+                }
+                Ok(())
+            }
+            TxOutput::Labeled { txid, label } => match label {
+                Some(label) => writeln!(f, "Labeled {} {:?}", txid, label),
+                None => writeln!(f, "Removed the label of {}", txid),
+            },
+            TxOutput::Expiry { after_blocks } => match after_blocks {
+                Some(after_blocks) => writeln!(
+                    f,
+                    "Transactions are abandoned when still unconfirmed after {} blocks",
+                    after_blocks
+                ),
+                None => writeln!(f, "Transactions are never abandoned on their own"),
+            },
+            TxOutput::Details(details) => write!(f, "{}", details),
+        }
+    }
+}
+
+/// A transaction that conflicts with another one, and where the other one is in the chain.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Conflict<P> {
+    pub txid: Txid,
+    /// `None` if it isn't in the chain
+    pub position: Option<P>,
+}
+
+/// A transaction of the [`EvictedTxs`](crate::EvictedTxs) ledger.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct EvictionReport<P> {
+    pub txid: Txid,
+    /// Where the transaction was before it was evicted
+    pub height: TxHeight,
+    /// When it was evicted as a unix timestamp
+    pub evicted_at: u64,
+    /// The height of the tip of the wallet's chain at the time
+    pub tip_height: Option<u32>,
+    /// The transactions that replaced it (it was dropped if there are none)
+    pub replaced_by: Vec<Conflict<P>>,
+    /// Where it is in the chain if it made it back in
+    pub position: Option<P>,
+    /// The transaction itself, only given when asking about a single eviction
+    pub tx: Option<Transaction>,
+}
+
+impl<P: Debug> Display for EvictionReport<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} was {:?} and evicted at {} (tip {}): ",
+            self.txid,
+            self.height,
+            self.evicted_at,
+            self.tip_height
+                .map_or("unknown".to_string(), |height| height.to_string())
+        )?;
+        if self.replaced_by.is_empty() {
+            write!(f, "dropped")?;
+        } else {
+            write!(f, "replaced by")?;
+            for conflict in &self.replaced_by {
+                match &conflict.position {
+                    Some(position) => write!(f, " {} ({:?})", conflict.txid, position)?,
+                    None => write!(f, " {} (not in chain)", conflict.txid)?,
+                }
+            }
+        }
+        if let Some(position) = &self.position {
+            write!(f, ", back in the chain at {:?}", position)?;
+        }
+        writeln!(f)?;
+        if let Some(tx) = &self.tx {
+            writeln!(f, "hex: {}", serialize_hex(tx))?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the coins of a transaction came from or went to.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TxEndpoint<K> {
+    /// The address of the script pubkey (or the script itself if it has none)
+    pub address: String,
+    /// The index of the script pubkey if it's the wallet's
+    pub index: Option<(K, u32)>,
+    pub value: u64,
+}
+
+/// A transaction of the wallet listed by `tx list`.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TxSummary<P> {
+    pub txid: Txid,
+    pub position: P,
+    /// What the transaction added to (or took from) the wallet's balance
+    pub net: i64,
+    pub fee: Option<i64>,
+    pub label: Option<String>,
+    /// The wallet's outputs the transaction spends
+    pub from: Vec<TxEndpoint<Keychain>>,
+    /// Every output of the transaction
+    pub to: Vec<TxEndpoint<Keychain>>,
+}
+
+impl<P: ChainPosition> Display for TxSummary<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let describe = |endpoint: &TxEndpoint<Keychain>| match &endpoint.index {
+            Some((keychain, index)) => format!("{} [{:?} {}]", endpoint.address, keychain, index),
+            None => endpoint.address.clone(),
+        };
+        write!(
+            f,
+            "{} {} net:{:+}",
+            self.txid,
+            self.position.height(),
+            self.net
+        )?;
+        match self.fee {
+            Some(fee) => write!(f, " fee:{}", fee)?,
+            None => write!(f, " fee:unknown")?,
+        }
+        match &self.label {
+            Some(label) => writeln!(f, " label:{:?}", label)?,
+            None => writeln!(f)?,
+        }
+        for from in &self.from {
+            writeln!(f, "  from {} {}", describe(from), from.value)?;
+        }
+        for to in &self.to {
+            writeln!(f, "  to {} {}", describe(to), to.value)?;
+        }
+        Ok(())
+    }
+}
+
+/// An input of a transaction spending the wallet's output.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct OwnedInput {
+    pub vin: usize,
+    pub outpoint: OutPoint,
+    pub value: u64,
+    pub index: (Keychain, u32),
+}
+
+/// An output of a transaction paying the wallet.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct OwnedOutput {
+    pub vout: usize,
+    pub value: u64,
+    pub address: String,
+    pub index: (Keychain, u32),
+}
+
+/// A transaction in the chain spending an output.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Outspend<P> {
+    pub vout: u32,
+    pub txid: Txid,
+    pub position: P,
+}
+
+/// Everything the wallet knows about one of its transactions, see `tx get`.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TxDetails<P> {
+    pub txid: Txid,
+    pub label: Option<String>,
+    /// `None` if it isn't in the chain
+    pub position: Option<P>,
+    pub confirmations: u32,
+    /// Whether it's in the [`EvictedTxs`](crate::EvictedTxs) ledger
+    pub evicted: bool,
+    pub net: i64,
+    pub fee: Option<i64>,
+    pub tx: Transaction,
+    pub inputs: Vec<OwnedInput>,
+    pub outputs: Vec<OwnedOutput>,
+    pub spent_by: Vec<Outspend<P>>,
+    pub conflicts: Vec<Conflict<P>>,
+}
+
+impl<P: Debug> Display for TxDetails<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "txid: {}", self.txid)?;
+        if let Some(label) = &self.label {
+            writeln!(f, "label: {:?}", label)?;
+        }
+        match &self.position {
+            Some(position) => writeln!(
+                f,
+                "position: {:?} confirmations: {}",
+                position, self.confirmations
+            )?,
+            None if self.evicted => writeln!(
+                f,
+                "position: evicted from the chain (see `tx evicted --txid {}`)",
+                self.txid
+            )?,
+            None => writeln!(f, "position: not in chain")?,
+        }
+        writeln!(f, "net value: {:+}", self.net)?;
+        if let Some(fee) = self.fee {
+            writeln!(f, "fee: {}", fee)?;
+        }
+        writeln!(f, "hex: {}", serialize_hex(&self.tx))?;
+
+        writeln!(f, "our inputs:")?;
+        for input in &self.inputs {
+            writeln!(
+                f,
+                "  {}: {} {} {:?}",
+                input.vin, input.outpoint, input.value, input.index
+            )?;
+        }
+        writeln!(f, "our outputs:")?;
+        for output in &self.outputs {
+            writeln!(
+                f,
+                "  {}: {} {} {:?}",
+                output.vout, output.value, output.address, output.index
+            )?;
+        }
+        writeln!(f, "spent by:")?;
+        for outspend in &self.spent_by {
+            writeln!(
+                f,
+                "  {}: {} at {:?}",
+                outspend.vout, outspend.txid, outspend.position
+            )?;
+        }
+        writeln!(f, "conflicts:")?;
+        for conflict in &self.conflicts {
+            match &conflict.position {
+                Some(position) => writeln!(f, "  {} at {:?}", conflict.txid, position)?,
+                None => writeln!(f, "  {} (not in chain)", conflict.txid)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The relative timelock of an input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum RelativeLock {
+    Blocks(u32),
+    Seconds(u32),
+}
+
+/// An input described by [`describe_tx`](crate::describe_tx).
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DescribedInput<K> {
+    pub outpoint: OutPoint,
+    /// The output it spends, `None` if the tracker doesn't have it
+    pub prevout: Option<TxEndpoint<K>>,
+    pub relative_lock: Option<RelativeLock>,
+}
+
+/// A transaction described by [`describe_tx`](crate::describe_tx).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TxDescription<K, P> {
+    pub txid: Txid,
+    /// `None` if it isn't in the chain
+    pub position: Option<P>,
+    pub version: i32,
+    pub size: usize,
+    pub vsize: usize,
+    pub weight: usize,
+    pub lock_time: LockTime,
+    /// Whether the locktime is enforced, which it isn't if every input has a final sequence
+    pub lock_time_enabled: bool,
+    pub signals_rbf: bool,
+    pub inputs: Vec<DescribedInput<K>>,
+    pub outputs: Vec<TxEndpoint<K>>,
+}
+
+impl<K, P> TxDescription<K, P> {
+    /// The value of the outputs spent by the transaction, `None` if some of them are unknown.
+    pub fn input_value(&self) -> Option<u64> {
+        self.inputs
+            .iter()
+            .map(|input| input.prevout.as_ref().map(|prevout| prevout.value))
+            .sum()
+    }
+
+    pub fn output_value(&self) -> u64 {
+        self.outputs.iter().map(|output| output.value).sum()
+    }
+
+    /// The fee paid by the transaction, `None` if some of its prevouts are unknown or the outputs
+    /// are worth more than them.
+    pub fn fee(&self) -> Option<u64> {
+        self.input_value()?.checked_sub(self.output_value())
+    }
+}
+
+impl<K: Debug, P: Debug> Display for TxDescription<K, P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let describe = |endpoint: &TxEndpoint<K>| match &endpoint.index {
+            Some((keychain, index)) => {
+                format!("{} [ours: {:?} {}]", endpoint.address, keychain, index)
+            }
+            None => endpoint.address.clone(),
+        };
+
+        writeln!(f, "txid: {}", self.txid)?;
+        match &self.position {
+            Some(position) => writeln!(f, "position: {:?}", position)?,
+            None => writeln!(f, "position: not in chain")?,
+        }
+        writeln!(
+            f,
+            "version: {} size: {} vsize: {} weight: {}",
+            self.version, self.size, self.vsize, self.weight
+        )?;
+        match self.lock_time {
+            locktime if locktime.to_consensus_u32() == 0 => writeln!(f, "locktime: none")?,
+            locktime if !self.lock_time_enabled => writeln!(
+                f,
+                "locktime: {} (disabled since every input has a final sequence)",
+                locktime
+            )?,
+            LockTime::Blocks(height) => writeln!(
+                f,
+                "locktime: can be mined in blocks after height {}",
+                height
+            )?,
+            LockTime::Seconds(time) => writeln!(
+                f,
+                "locktime: can be mined once the median time past is after {}",
+                time
+            )?,
+        }
+        writeln!(f, "signals rbf: {}", self.signals_rbf)?;
+
+        writeln!(f, "inputs:")?;
+        for (vin, input) in self.inputs.iter().enumerate() {
+            write!(f, "  {}: {}", vin, input.outpoint)?;
+            match &input.prevout {
+                Some(prevout) => write!(f, " {} {}", prevout.value, describe(prevout))?,
+                None => write!(f, " (unknown prevout)")?,
+            }
+            match input.relative_lock {
+                Some(RelativeLock::Blocks(blocks)) => {
+                    write!(f, " relative lock: {} blocks", blocks)?
+                }
+                Some(RelativeLock::Seconds(seconds)) => {
+                    write!(f, " relative lock: {} seconds", seconds)?
+                }
+                None => {}
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f, "outputs:")?;
+        for (vout, output) in self.outputs.iter().enumerate() {
+            writeln!(f, "  {}: {} {}", vout, output.value, describe(output))?;
+        }
+
+        match self.input_value() {
+            Some(input_value) if input_value >= self.output_value() => {
+                let fee = input_value - self.output_value();
+                writeln!(
+                    f,
+                    "fee: {} ({:.2} sat/vB)",
+                    fee,
+                    fee as f64 / self.vsize as f64
+                )
+            }
+            Some(_) => writeln!(f, "fee: invalid (outputs are worth more than inputs)"),
+            None => writeln!(f, "fee: unknown (not all prevouts are known)"),
+        }
+    }
+}
+
+/// When a timelocked branch of a UTXO's descriptor can be used.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct VaultStatus {
+    pub outpoint: OutPoint,
+    pub value: u64,
+    pub keychain: Keychain,
+    pub index: u32,
+    /// The `after` timelock of the branch (in consensus encoding)
+    pub after: Option<u32>,
+    /// The `older` timelock of the branch (in consensus encoding)
+    pub older: Option<u32>,
+    pub status: String,
+}
+
+/// The output of a [`CosignCmd`](crate::CosignCmd).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum CosignOutput {
+    /// The PSBT after signatures were collected and what it still needs
+    Updated {
+        psbt: Psbt,
+        status: CosignStatus,
+    },
+    /// The PSBT of the transaction was written to `file`
+    Exported {
+        txid: Txid,
+        file: PathBuf,
+    },
+    Status(Vec<CosignStatus>),
+    /// Signatures are no longer collected for the transaction
+    Cancelled(Txid),
+}
+
+impl Display for CosignOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CosignOutput::Updated { psbt, status } => write!(f, "{}\n{}", psbt, status),
+            CosignOutput::Exported { txid, file } => {
+                writeln!(f, "Wrote the PSBT of {} to {}", txid, file.display())
+            }
+            CosignOutput::Status(statuses) => {
+                for status in statuses {
+                    write!(f, "{}", status)?;
+                }
+                Ok(())
+            }
+            CosignOutput::Cancelled(txid) => {
+                writeln!(f, "Stopped collecting signatures for {}", txid)
+            }
+        }
+    }
+}
+
+/// Which signatures (and pre-images) each input of a PSBT still needs.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CosignStatus {
+    pub txid: Txid,
+    pub inputs: Vec<CosignInput>,
+}
+
+/// An input of a [`CosignStatus`].
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CosignInput {
+    pub outpoint: OutPoint,
+    /// `None` if the input doesn't spend a coin of the wallet
+    pub missing: Option<MissingAuth>,
+}
+
+impl CosignStatus {
+    /// Whether every input of the wallet has all it needs to be finalized.
+    pub fn is_ready(&self) -> bool {
+        self.inputs
+            .iter()
+            .filter_map(|input| input.missing.as_ref())
+            .all(MissingAuth::is_empty)
+    }
+}
+
+impl Display for CosignStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_ready() {
+            return writeln!(f, "{} ready to finalize", self.txid);
+        }
+        writeln!(f, "{} waiting for signatures", self.txid)?;
+        for (i, input) in self.inputs.iter().enumerate() {
+            match &input.missing {
+                None => writeln!(
+                    f,
+                    "  input {} {}: not a coin of the wallet",
+                    i, input.outpoint
+                )?,
+                Some(missing) if missing.is_empty() => {
+                    writeln!(f, "  input {} {}: signed", i, input.outpoint)?
+                }
+                Some(missing) => {
+                    let keys = missing
+                        .signatures
+                        .iter()
+                        .map(|(fingerprint, path)| {
+                            // in the form of a descriptor key origin
+                            format!(
+                                "[{}{}]",
+                                fingerprint,
+                                path.to_string().trim_start_matches('m')
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    write!(
+                        f,
+                        "  input {} {}: needs {} more signature(s) from {}",
+                        i,
+                        input.outpoint,
+                        keys.len(),
+                        keys.join(" ")
+                    )?;
+                    match missing.preimages {
+                        0 => writeln!(f)?,
+                        preimages => writeln!(f, " and {} pre-image(s)", preimages)?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The output of an [`ExternalCmd`](crate::ExternalCmd).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum ExternalOutput {
+    /// The script is watched, `updated` if it was already and only its metadata changed
+    Watching {
+        script_pubkey: Script,
+        updated: bool,
+    },
+    Removed(Script),
+    List(Vec<WatchedScript>),
+}
+
+impl Display for ExternalOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ExternalOutput::Watching { updated: true, .. } => {
+                writeln!(
+                    f,
+                    "Updated the metadata of the script, sync to find its outputs"
+                )
+            }
+            ExternalOutput::Watching { updated: false, .. } => {
+                writeln!(f, "Watching the script, sync to find its outputs")
+            }
+            ExternalOutput::Removed(_) => writeln!(f, "Stopped watching the script"),
+            ExternalOutput::List(scripts) => {
+                for script in scripts {
+                    let metadata = &script.metadata;
+                    writeln!(
+                        f,
+                        "{} {} {} csv:{:?} cltv:{:?} expires_after:{:?}",
+                        metadata.kind,
+                        script.script_pubkey,
+                        metadata.label,
+                        metadata.csv,
+                        metadata.cltv,
+                        metadata.expires_after
+                    )?;
+                    for txout in &script.txouts {
+                        writeln!(f, "  {} {} {}", txout.outpoint, txout.value, txout.status)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A script watched with `external add` along with the outputs found on it.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct WatchedScript {
+    pub script_pubkey: Script,
+    pub metadata: ScriptMetadata,
+    pub txouts: Vec<ExternalTxOut>,
+}
+
+/// An output of a [`WatchedScript`].
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ExternalTxOut {
+    pub outpoint: OutPoint,
+    pub value: u64,
+    pub status: ExternalTxOutStatus,
+}
+
+/// Whether we can spend an [`ExternalTxOut`] in the next block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ExternalTxOutStatus {
+    SpentBy(Txid),
+    /// Its spend window starts once it confirms
+    Unconfirmed,
+    /// Someone else can spend it too since the height
+    Expired(u32),
+    /// It can't be spent before the height
+    LockedUntil(u32),
+    /// Only we can spend it until the height
+    SpendableUntil(u32),
+    Spendable,
+}
+
+impl Display for ExternalTxOutStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ExternalTxOutStatus::SpentBy(txid) => write!(f, "spent by {}", txid),
+            ExternalTxOutStatus::Unconfirmed => write!(f, "waiting for a confirmation"),
+            ExternalTxOutStatus::Expired(height) => {
+                write!(f, "anyone can spend it since height {}", height)
+            }
+            ExternalTxOutStatus::LockedUntil(height) => write!(f, "locked until height {}", height),
+            ExternalTxOutStatus::SpendableUntil(height) => {
+                write!(f, "spendable until height {}", height)
+            }
+            ExternalTxOutStatus::Spendable => write!(f, "spendable"),
+        }
+    }
+}
+
+/// The output of a [`DeferredCmd`](crate::DeferredCmd).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum DeferredOutput {
+    /// The transaction was queued, `replaced` if it already was
+    Queued {
+        txid: Txid,
+        replaced: bool,
+    },
+    Removed(Txid),
+    List(Vec<QueuedTx>),
+}
+
+impl Display for DeferredOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DeferredOutput::Queued {
+                txid,
+                replaced: true,
+            } => writeln!(f, "Replaced queued transaction {}", txid),
+            DeferredOutput::Queued {
+                txid,
+                replaced: false,
+            } => writeln!(f, "Queued transaction {}", txid),
+            DeferredOutput::Removed(txid) => writeln!(f, "Removed {} from the queue", txid),
+            DeferredOutput::List(queued) => {
+                for queued in queued {
+                    writeln!(f, "{} {}", queued.txid, queued.status)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A transaction of the [`DeferredQueue`](bdk_chain::deferred::DeferredQueue).
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct QueuedTx {
+    pub txid: Txid,
+    pub status: DeferredStatus,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum DeferredStatus {
+    /// It will be broadcast after the next update
+    Due,
+    /// It has been broadcast and is in the chain
+    Broadcast,
+    WaitingForHeight(u32),
+    /// Waiting for the transaction to confirm
+    WaitingFor(Txid),
+}
+
+impl Display for DeferredStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DeferredStatus::Due => write!(f, "due"),
+            DeferredStatus::Broadcast => write!(f, "broadcast"),
+            DeferredStatus::WaitingForHeight(height) => {
+                write!(f, "waiting until height {}", height)
+            }
+            DeferredStatus::WaitingFor(txid) => write!(f, "waiting for {} to confirm", txid),
+        }
+    }
+}
+
+/// The output of an [`InvoiceCmd`](crate::InvoiceCmd).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum InvoiceOutput<P> {
+    Created { id: u32, address: Address },
+    List(Vec<InvoiceSummary>),
+    Status(InvoiceDetails<P>),
+}
+
+impl<P: Debug> Display for InvoiceOutput<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InvoiceOutput::Created { id, address } => writeln!(f, "{} {}", id, address),
+            InvoiceOutput::List(invoices) => {
+                for invoice in invoices {
+                    writeln!(
+                        f,
+                        "{} {} {} {} {}",
+                        invoice.id, invoice.address, invoice.amount, invoice.status, invoice.memo
+                    )?;
+                }
+                Ok(())
+            }
+            InvoiceOutput::Status(invoice) => {
+                writeln!(f, "address: {}", invoice.address)?;
+                writeln!(f, "amount: {}", invoice.amount)?;
+                writeln!(f, "memo: {}", invoice.memo)?;
+                writeln!(f, "created at: {}", invoice.created_at)?;
+                writeln!(f, "expires at: {}", invoice.expires_at)?;
+                writeln!(f, "status: {}", invoice.status)?;
+                for payment in &invoice.payments {
+                    writeln!(
+                        f,
+                        "  {} {} {:?}",
+                        payment.outpoint, payment.txout.value, payment.chain_position
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// An [`Invoice`](crate::Invoice) listed by `invoice list`.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct InvoiceSummary {
+    pub id: u32,
+    pub address: Address,
+    pub amount: u64,
+    pub status: InvoiceStatus,
+    pub memo: String,
+}
+
+/// An [`Invoice`](crate::Invoice) along with the outputs paying it.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct InvoiceDetails<P> {
+    pub address: Address,
+    pub amount: u64,
+    pub memo: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub status: InvoiceStatus,
+    pub payments: Vec<FullTxOut<P>>,
+}
+
+/// The output of a [`LabelsCmd`](crate::LabelsCmd).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum LabelsOutput {
+    /// The labels were written to the file
+    Exported(PathBuf),
+    /// The labels in the BIP 329 format, one JSON record per line
+    Bip329(String),
+    /// The number of labels that were imported
+    Imported(usize),
+}
+
+impl Display for LabelsOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LabelsOutput::Exported(file) => {
+                writeln!(f, "Exported the labels to {}", file.display())
+            }
+            LabelsOutput::Bip329(bip329) => write!(f, "{}", bip329),
+            LabelsOutput::Imported(count) => writeln!(f, "Imported {} labels", count),
+        }
+    }
+}
+
+/// The privacy leaks in the history of the wallet found by
+/// [`run_privacy_cmd`](crate::run_privacy_cmd).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PrivacyReport<K> {
+    pub reused_addresses: Vec<ReusedAddress<K>>,
+    pub round_payments: Vec<RoundPayment>,
+    pub linked_utxos: Vec<LinkedUtxos>,
+}
+
+/// An address that received funds in more than one transaction.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ReusedAddress<K> {
+    pub index: (K, u32),
+    pub address: String,
+    /// The number of transactions that paid to it
+    pub txs: usize,
+}
+
+/// A transaction paying round amounts, which gives away which of its outputs is the change.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RoundPayment {
+    pub txid: Txid,
+    /// The values of the outputs that aren't the wallet's
+    pub paid: Vec<u64>,
+    /// The values of the wallet's outputs
+    pub change: Vec<u64>,
+}
+
+/// UTXOs that can be linked to each other since the addresses they are on were spent from
+/// together.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct LinkedUtxos {
+    /// The number of addresses in the cluster
+    pub addresses: usize,
+    pub utxos: Vec<(OutPoint, u64)>,
+}
+
+impl<K: Debug> Display for PrivacyReport<K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "address reuse: {}", self.reused_addresses.len())?;
+        for reused in &self.reused_addresses {
+            writeln!(
+                f,
+                "  {} [{:?} {}] received in {} transactions",
+                reused.address, reused.index.0, reused.index.1, reused.txs
+            )?;
+        }
+
+        writeln!(
+            f,
+            "change given away by round payments: {}",
+            self.round_payments.len()
+        )?;
+        let values = |values: &[u64]| {
+            values
+                .iter()
+                .map(|value| value.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        for payment in &self.round_payments {
+            writeln!(
+                f,
+                "  {} paid {} leaving change of {}",
+                payment.txid,
+                values(&payment.paid),
+                values(&payment.change)
+            )?;
+        }
+
+        writeln!(f, "linked UTXO clusters: {}", self.linked_utxos.len())?;
+        for linked in &self.linked_utxos {
+            writeln!(
+                f,
+                "  {} UTXOs worth {} sats across {} addresses:",
+                linked.utxos.len(),
+                linked.utxos.iter().map(|(_, value)| value).sum::<u64>(),
+                linked.addresses
+            )?;
+            for (outpoint, value) in &linked.utxos {
+                writeln!(f, "    {} ({} sats)", outpoint, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The spending policy of a keychain's descriptor at a derivation index, see
+/// [`run_inspect_cmd`](crate::run_inspect_cmd).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct KeychainPolicy {
+    pub keychain: Keychain,
+    pub index: u32,
+    pub branches: Vec<PolicyBranchOutput>,
+}
+
+/// A [`PolicyBranch`](bdk_tmp_plan::PolicyBranch) of a [`KeychainPolicy`].
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PolicyBranchOutput {
+    /// Where the branch is in the descriptor
+    pub kind: String,
+    /// Whether the wallet can satisfy the branch once its timelocks have passed
+    pub satisfiable: bool,
+    /// The weight of satisfying it with every key and pre-image
+    pub weight: Option<usize>,
+    /// The weight of satisfying it with what the wallet has
+    pub assets_weight: Option<usize>,
+    /// The conditions of the branch as an indented tree
+    pub policy: String,
+}
+
+impl Display for KeychainPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} keychain at index {}:", self.keychain, self.index)?;
+        let weight = |weight: Option<usize>| match weight {
+            Some(weight) => format!("{} wu", weight),
+            None => "unknown".to_string(),
+        };
+        for branch in &self.branches {
+            if branch.satisfiable {
+                write!(
+                    f,
+                    "  {}: satisfiable, weight {}",
+                    branch.kind,
+                    weight(branch.assets_weight)
+                )?;
+                if branch.weight != branch.assets_weight {
+                    write!(f, " (at best {})", weight(branch.weight))?;
+                }
+                writeln!(f)?;
+            } else {
+                writeln!(
+                    f,
+                    "  {}: not satisfiable, weight {}",
+                    branch.kind,
+                    weight(branch.weight)
+                )?;
+            }
+            for line in branch.policy.lines() {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How far a migration started with [`run_migrate_cmd`](crate::run_migrate_cmd) has come.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MigrationReport {
+    /// The descriptor the funds are swept to
+    pub descriptor: String,
+    /// The sweeps broadcast by this run
+    pub broadcast: Vec<Txid>,
+    /// The number of sweeps (of every run) that have confirmed
+    pub confirmed: usize,
+    /// The number of sweeps of every run
+    pub sweeps: usize,
+    pub left_behind: Vec<LeftBehind>,
+}
+
+impl MigrationReport {
+    /// Whether every sweep confirmed and nothing was left behind.
+    pub fn is_complete(&self) -> bool {
+        self.left_behind.is_empty() && self.confirmed == self.sweeps
+    }
+}
+
+impl Display for MigrationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "migrating to {}", self.descriptor)?;
+        for txid in &self.broadcast {
+            writeln!(f, "broadcast sweep {}", txid)?;
+        }
+        writeln!(
+            f,
+            "{} of {} sweep transaction(s) confirmed",
+            self.confirmed, self.sweeps
+        )?;
+        if !self.left_behind.is_empty() {
+            writeln!(f, "left behind (run `migrate` again to retry):")?;
+            for utxo in &self.left_behind {
+                writeln!(f, "  {}", utxo)?;
+            }
+        }
+        if self.is_complete() {
+            writeln!(
+                f,
+                "the migration is complete, the new descriptor can now be used as the wallet's descriptor"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A backup made by [`run_backup_cmd`](crate::run_backup_cmd).
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BackupReport {
+    /// The number of entries that were backed up
+    pub entries: usize,
+    pub path: PathBuf,
+    /// The hash chaining the entries of the store, see
+    /// [`KeychainStore::head_hash`](bdk_chain::file_store::KeychainStore::head_hash)
+    pub head_hash: Option<String>,
+}
+
+impl Display for BackupReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "backed up {} entries to {}",
+            self.entries,
+            self.path.display()
+        )?;
+        if let Some(head) = &self.head_hash {
+            writeln!(f, "head hash: {}", head)?;
+        }
+        Ok(())
+    }
+}
+
+/// A database restored by [`run_restore_db_cmd`](crate::run_restore_db_cmd).
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RestoreReport {
+    pub db_path: PathBuf,
+    pub backup_path: PathBuf,
+    /// Where the database that was replaced was kept
+    pub kept_path: PathBuf,
+    /// The height of the last checkpoint of the restored database
+    pub last_checkpoint: Option<u32>,
+}
+
+impl Display for RestoreReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "restored {} from {}, the previous database was kept at {}",
+            self.db_path.display(),
+            self.backup_path.display(),
+            self.kept_path.display()
+        )?;
+        if let Some(height) = self.last_checkpoint {
+            writeln!(f, "last checkpoint at height {}", height)?;
+        }
+        Ok(())
+    }
+}
+
+/// A damaged database cut short by [`run_recover_db_cmd`](crate::run_recover_db_cmd).
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RecoveryReport {
+    pub db_path: PathBuf,
+    /// Where the damaged database was copied to, `None` if it wasn't damaged
+    pub backup_path: Option<PathBuf>,
+    /// The number of entries that were kept
+    pub recovered: usize,
+    /// The number of bytes cut off the end of the database
+    pub truncated: u64,
+}
+
+impl Display for RecoveryReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.backup_path {
+            Some(backup_path) => writeln!(
+                f,
+                "kept {} entries of {} and removed the last {} bytes, the damaged database was \
+                 copied to {}",
+                self.recovered,
+                self.db_path.display(),
+                self.truncated,
+                backup_path.display()
+            ),
+            None => writeln!(
+                f,
+                "all {} entries of {} can be read, nothing was removed",
+                self.recovered,
+                self.db_path.display()
+            ),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Reporting what a transaction gives away about the wallet.
+use crate::{describe_script, LinkedUtxos, PrivacyReport, ReusedAddress, RoundPayment};
+use bdk_chain::{
+    bitcoin::{Network, Txid},
+    keychain::KeychainTracker,
+    sparse_chain::ChainPosition,
+    FullTxOut,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+};
+
+/// Payments that are a multiple of this many sats are considered round amounts by
+/// [`run_privacy_cmd`].
+const ROUND_AMOUNT: u64 = 10_000;
+
+/// Reports the privacy leaks in the history of the wallet.
+///
+/// Only transactions in the chain are considered. The report lists:
+///
+/// - addresses that received funds in more than one transaction
+/// - transactions where paying round amounts makes the change output easy to spot
+/// - clusters of UTXOs that can be linked to each other because the addresses they are on were
+///   spent from in the same transaction (the common input ownership heuristic)
+pub fn run_privacy_cmd<K: Debug + Clone + Ord, P: ChainPosition>(
+    keychain_tracker: &KeychainTracker<K, P>,
+    network: Network,
+) -> PrivacyReport<K> {
+    let txout_index = &keychain_tracker.txout_index;
+    let txs = keychain_tracker
+        .chain_graph()
+        .transactions_in_chain()
+        .map(|(_, tx)| tx)
+        .collect::<Vec<_>>();
+
+    let mut receiving_txids = BTreeMap::<(K, u32), BTreeSet<Txid>>::new();
+    for tx in &txs {
+        let txid = tx.txid();
+        for (index, _, _) in txout_index.txouts_in_tx(txid) {
+            receiving_txids
+                .entry(index.clone())
+                .or_default()
+                .insert(txid);
+        }
+    }
+    let reused_addresses = receiving_txids
+        .into_iter()
+        .filter(|(_, txids)| txids.len() > 1)
+        .map(|(index, txids)| ReusedAddress {
+            address: txout_index
+                .spk_at_index(&index)
+                .map(|spk| describe_script(spk, network))
+                .unwrap_or_default(),
+            index,
+            txs: txids.len(),
+        })
+        .collect();
+
+    let mut round_payments = vec![];
+    for tx in &txs {
+        if txout_index.sent_and_received(tx).0 == 0 {
+            continue;
+        }
+        let (ours, theirs): (Vec<_>, Vec<_>) = tx
+            .output
+            .iter()
+            .partition(|txout| txout_index.index_of_spk(&txout.script_pubkey).is_some());
+        let is_round = |value: u64| value.is_multiple_of(ROUND_AMOUNT);
+        if !theirs.is_empty()
+            && theirs.iter().all(|txout| is_round(txout.value))
+            && ours.iter().any(|txout| !is_round(txout.value))
+        {
+            round_payments.push(RoundPayment {
+                txid: tx.txid(),
+                paid: theirs.iter().map(|txout| txout.value).collect(),
+                change: ours.iter().map(|txout| txout.value).collect(),
+            });
+        }
+    }
+
+    // the addresses we spent from in the same transaction are assumed to have the same owner
+    let mut clusters = Vec::<BTreeSet<(K, u32)>>::new();
+    for tx in &txs {
+        let mut cluster = tx
+            .input
+            .iter()
+            .filter_map(|txin| txout_index.txout(txin.previous_output))
+            .map(|(index, _)| index.clone())
+            .collect::<BTreeSet<_>>();
+        if cluster.is_empty() {
+            continue;
+        }
+        let (linked, rest) = clusters
+            .into_iter()
+            .partition::<Vec<_>, _>(|other| !other.is_disjoint(&cluster));
+        clusters = rest;
+        for other in linked {
+            cluster.extend(other);
+        }
+        clusters.push(cluster);
+    }
+
+    let mut linked_utxos = BTreeMap::<usize, Vec<FullTxOut<P>>>::new();
+    for (index, utxo) in keychain_tracker.full_utxos() {
+        let cluster = match clusters.iter().position(|cluster| cluster.contains(index)) {
+            Some(cluster) => cluster,
+            // UTXOs on the same address are linked even if it was never spent from
+            None => {
+                clusters.push(core::iter::once(index.clone()).collect());
+                clusters.len() - 1
+            }
+        };
+        linked_utxos.entry(cluster).or_default().push(utxo);
+    }
+    let linked_utxos = linked_utxos
+        .into_iter()
+        .filter(|(_, utxos)| utxos.len() > 1)
+        .map(|(cluster, utxos)| LinkedUtxos {
+            addresses: clusters[cluster].len(),
+            utxos: utxos
+                .iter()
+                .map(|utxo| (utxo.outpoint, utxo.txout.value))
+                .collect(),
+        })
+        .collect();
+
+    PrivacyReport {
+        reused_addresses,
+        round_payments,
+        linked_utxos,
+    }
+}
@@ -0,0 +1,367 @@
+//! Creating, signing and combining PSBTs with other wallets.
+use crate::{
+    audit_signing, broadcast_and_store, create_psbt, described_utxos, finalize_psbt, is_watch_only,
+    load_extension, resolve_payments, save_extension, send_builder, sign_psbt, spending_assets,
+    Broadcast, CoinSelectionAlgo, CommandOutput, DepositPolicy, DustArgs, EstimateFee, FeerateArg,
+    FrozenUtxos, Keychain, OpReturnData, OrderingStrategy, Payment, PreimageArgs, Preimages,
+    Recipient, SighashArgs, Signer, TimelockArgs, TxBuilder, COUNTERPARTIES_EXTENSION,
+    FROZEN_EXTENSION,
+};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{
+        util::{
+            bip32::Fingerprint,
+            psbt::{self, PartiallySignedTransaction as Psbt},
+        },
+        Network, OutPoint,
+    },
+    keychain::{KeychainTracker, PersistBackend},
+    miniscript::{descriptor::DefiniteDescriptorKey, DescriptorPublicKey, ForEachKey},
+    sparse_chain::ChainPosition,
+};
+use bdk_coin_select::sat_per_vb_to_wu;
+use clap::Subcommand;
+use std::{collections::HashMap, fmt::Debug};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum PsbtCmd {
+    /// Create an unsigned PSBT sending coins (see `send`)
+    Create {
+        value: u64,
+        /// An address or `desc:<descriptor>` to pay to the next address of a counterparty's
+        /// descriptor
+        recipient: Recipient,
+        #[clap(short, default_value = "largest-first")]
+        coin_select: CoinSelectionAlgo,
+        /// A keychain to send change to. Can be given more than once to list fallbacks in order
+        /// of preference (defaults to internal then external).
+        #[clap(long = "change-keychain")]
+        change_keychains: Vec<Keychain>,
+        /// Spend this coin. Can be given more than once. No other coins are spent if it is.
+        #[clap(long = "utxo")]
+        utxos: Vec<OutPoint>,
+        /// Don't spend this coin. Can be given more than once.
+        #[clap(long = "exclude-utxo")]
+        exclude_utxos: Vec<OutPoint>,
+        /// The feerate in sats per vbyte (defaults to 2), or `auto:<blocks>` to ask the chain
+        /// source for the feerate that gets the transaction confirmed within that many blocks
+        #[clap(long)]
+        feerate: Option<FeerateArg>,
+        /// Also spend unconfirmed coins, paying enough fee for their unconfirmed ancestors to
+        /// reach the feerate too
+        #[clap(long)]
+        spend_unconfirmed: bool,
+        /// Take the fee out of the value sent rather than paying it on top
+        #[clap(long)]
+        subtract_fee: bool,
+        /// How to order the inputs and outputs: `shuffle`, `bip69` or `untouched`
+        #[clap(long, default_value = "shuffle")]
+        ordering: OrderingStrategy,
+        /// Hex data to embed in the transaction with an `OP_RETURN` output
+        #[clap(long)]
+        data: Option<OpReturnData>,
+        /// Pay addresses of another network than the wallet's
+        #[clap(long)]
+        allow_network_mismatch: bool,
+        #[clap(flatten)]
+        deposit_policy: DepositPolicy,
+        #[clap(flatten)]
+        timelocks: TimelockArgs,
+        #[clap(flatten)]
+        dust: DustArgs,
+        #[clap(flatten)]
+        sighash: SighashArgs,
+        #[clap(flatten)]
+        preimages: PreimageArgs,
+        /// The master fingerprint of a cosigner whose key of the wallet's descriptors signs too,
+        /// e.g. to spend from a multisig. Can be given more than once.
+        #[clap(long = "cosigner")]
+        cosigners: Vec<Fingerprint>,
+    },
+    /// Sign the inputs spending the wallet's coins with its keys
+    Sign {
+        /// The PSBT in base64
+        psbt: Psbt,
+    },
+    /// Merge the signatures and other fields of several PSBTs of the same transaction
+    Combine {
+        /// The PSBTs in base64
+        #[clap(required = true, num_args = 2..)]
+        psbts: Vec<Psbt>,
+    },
+    /// Complete the inputs spending the wallet's coins with the signatures the PSBT has
+    Finalize {
+        /// The PSBT in base64
+        psbt: Psbt,
+    },
+    /// Extract the transaction of a finalized PSBT
+    Extract {
+        /// The PSBT in base64
+        psbt: Psbt,
+        /// Broadcast the transaction instead of showing it
+        #[clap(long)]
+        broadcast: bool,
+    },
+}
+
+/// The plans of the inputs of `psbt` in order. Inputs that don't spend one of the wallet's coins
+/// or that `assets` (along with the hash pre-images in the PSBT) can't satisfy have none.
+///
+/// An input whose key origins (BIP 371) list keys of the wallet's descriptors is planned with
+/// those keys instead of the ones in `assets`. [`create_psbt`] lists the keys it planned with, so
+/// every cosigner of a multisig plans the spend the PSBT was made for.
+pub fn psbt_plans<P: ChainPosition>(
+    psbt: &Psbt,
+    tracker: &KeychainTracker<Keychain, P>,
+    assets: &bdk_tmp_plan::Assets<DescriptorPublicKey>,
+) -> Vec<Option<bdk_tmp_plan::Plan<DescriptorPublicKey>>> {
+    let mut assets = assets.clone();
+    for input in &psbt.inputs {
+        Preimages::of_input(input).extend_assets(&mut assets);
+    }
+    let mut plans = described_utxos(tracker, &assets)
+        .filter_map(|utxo| Some((utxo.full_txout.outpoint, utxo.plan?)))
+        .collect::<HashMap<_, _>>();
+    psbt.unsigned_tx
+        .input
+        .iter()
+        .zip(&psbt.inputs)
+        .map(|(txin, input)| {
+            let keys = keys_of_origins(tracker, input);
+            if keys.is_empty() {
+                return plans.remove(&txin.previous_output);
+            }
+            let assets = bdk_tmp_plan::Assets {
+                keys,
+                ..assets.clone()
+            };
+            let plan = described_utxos(tracker, &assets)
+                .find(|utxo| utxo.full_txout.outpoint == txin.previous_output)
+                .and_then(|utxo| utxo.plan);
+            plan
+        })
+        .collect()
+}
+
+/// The keys of the tracker's descriptors that the key origins of `input` were derived from.
+fn keys_of_origins<P>(
+    tracker: &KeychainTracker<Keychain, P>,
+    input: &psbt::Input,
+) -> Vec<DescriptorPublicKey> {
+    let mut keys = vec![];
+    for descriptor in tracker.txout_index.keychains().values() {
+        descriptor.for_each_key(|key| {
+            let mut origins = input
+                .tap_key_origins
+                .values()
+                .map(|(_, origin)| origin)
+                .chain(input.bip32_derivation.values());
+            let derives_origin = origins.any(|(fingerprint, path)| {
+                *fingerprint == key.master_fingerprint()
+                    && path
+                        .as_ref()
+                        .starts_with(key.full_derivation_path().as_ref())
+            });
+            if derives_origin && !keys.contains(key) {
+                keys.push(key.clone());
+            }
+            true
+        });
+    }
+    keys
+}
+
+/// The keys `plan` needs signatures from.
+pub(crate) fn plan_keys(
+    plan: &bdk_tmp_plan::Plan<DescriptorPublicKey>,
+) -> Vec<DefiniteDescriptorKey> {
+    match plan.requirements().signatures {
+        bdk_tmp_plan::RequiredSignatures::TapKey { plan_key, .. } => vec![plan_key.descriptor_key],
+        bdk_tmp_plan::RequiredSignatures::TapScript { plan_keys, .. }
+        | bdk_tmp_plan::RequiredSignatures::Segwitv0 {
+            keys: plan_keys, ..
+        } => plan_keys
+            .into_iter()
+            .map(|plan_key| plan_key.descriptor_key)
+            .collect(),
+        bdk_tmp_plan::RequiredSignatures::Legacy { .. } => vec![],
+    }
+}
+
+/// Adds the keys of the tracker's descriptors whose master fingerprint is one of `cosigners` to
+/// `assets`, so that spends are planned with the signatures of the cosigners as well.
+pub fn add_cosigners<P>(
+    assets: &mut bdk_tmp_plan::Assets<DescriptorPublicKey>,
+    tracker: &KeychainTracker<Keychain, P>,
+    cosigners: &[Fingerprint],
+) -> Result<()> {
+    for fingerprint in cosigners {
+        let mut found = false;
+        for descriptor in tracker.txout_index.keychains().values() {
+            descriptor.for_each_key(|key| {
+                if key.master_fingerprint() == *fingerprint {
+                    found = true;
+                    if !assets.keys.contains(key) {
+                        assets.keys.push(key.clone());
+                    }
+                }
+                true
+            });
+        }
+        if !found {
+            return Err(anyhow!(
+                "none of the wallet's descriptors has a key with fingerprint {}",
+                fingerprint
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn run_psbt_cmd<P, S>(
+    psbt_cmd: PsbtCmd,
+    client: &mut (impl Broadcast + EstimateFee),
+    tracker: &mut KeychainTracker<Keychain, P>,
+    store: &mut S,
+    network: Network,
+    signers: &[Box<dyn Signer>],
+) -> Result<CommandOutput<P>>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let assets = spending_assets(tracker, signers);
+    let psbt = match psbt_cmd {
+        PsbtCmd::Create {
+            value,
+            recipient,
+            coin_select,
+            change_keychains,
+            utxos,
+            exclude_utxos,
+            feerate,
+            spend_unconfirmed,
+            subtract_fee,
+            ordering,
+            data,
+            allow_network_mismatch,
+            deposit_policy,
+            timelocks,
+            dust,
+            sighash,
+            preimages,
+            cosigners,
+        } => {
+            let mut assets = assets;
+            add_cosigners(&mut assets, tracker, &cosigners)?;
+            let feerate = feerate.map(|feerate| feerate.resolve(client)).transpose()?;
+            let payments = vec![Payment { recipient, value }];
+            let (mut outputs, counterparties) =
+                resolve_payments(payments, store, network, allow_network_mismatch)?;
+            outputs.extend(data.map(|data| data.txout()));
+            let frozen =
+                load_extension::<FrozenUtxos, _, _>(store, FROZEN_EXTENSION)?.unwrap_or_default();
+            let mut builder = TxBuilder {
+                feerate: feerate.map(sat_per_vb_to_wu),
+                only_must_spend: !utxos.is_empty(),
+                must_spend: utxos,
+                spend_unconfirmed,
+                ordering,
+                subtract_fee_from: if subtract_fee { vec![0] } else { vec![] },
+                ..send_builder(coin_select, change_keychains, deposit_policy, &frozen)
+            };
+            builder.exclude.extend(exclude_utxos);
+            timelocks.apply_to(&mut builder);
+            dust.apply_to(&mut builder);
+            sighash.apply_to(&mut builder);
+            preimages.apply_to(&mut builder)?;
+            let (psbt, _) = create_psbt(&outputs, &builder, tracker, &assets)?;
+            // the change address and the counterparty's address are given out with the PSBT so
+            // they mustn't be handed out again
+            store.set_derivation_indices(tracker.txout_index.derivation_indices())?;
+            if let Some(counterparties) = counterparties {
+                save_extension(store, COUNTERPARTIES_EXTENSION, &counterparties)?;
+            }
+            psbt
+        }
+        PsbtCmd::Sign { mut psbt } => {
+            if is_watch_only(signers) {
+                return Err(anyhow!(
+                    "the wallet is watch-only so it has no keys to sign with"
+                ));
+            }
+            sign_wallet_inputs(&mut psbt, tracker, store, signers, &assets)?;
+            psbt
+        }
+        PsbtCmd::Combine { psbts } => {
+            let mut psbts = psbts.into_iter();
+            let mut combined = psbts.next().expect("clap requires at least two");
+            for psbt in psbts {
+                combined.combine(psbt)?;
+            }
+            combined
+        }
+        PsbtCmd::Finalize { mut psbt } => {
+            let plans = psbt_plans(&psbt, tracker, &assets);
+            let plans = plans.iter().map(Option::as_ref).collect::<Vec<_>>();
+            finalize_psbt(&mut psbt, &plans)?;
+            psbt
+        }
+        PsbtCmd::Extract { psbt, broadcast } => {
+            if let Some(i) = psbt.inputs.iter().position(|input| {
+                input.final_script_sig.is_none() && input.final_script_witness.is_none()
+            }) {
+                return Err(anyhow!("input {} hasn't been finalized", i));
+            }
+            let transaction = psbt.extract_tx();
+            if !broadcast {
+                return Ok(CommandOutput::Transaction(transaction));
+            }
+            broadcast_and_store(client, tracker, store, &transaction)?;
+            return Ok(CommandOutput::Broadcasted(transaction.txid()));
+        }
+    };
+    Ok(CommandOutput::Psbt(psbt))
+}
+
+/// Signs the inputs of `psbt` that spend the wallet's coins with `signers` and records the
+/// signing in the audit log.
+pub(crate) fn sign_wallet_inputs<P, S>(
+    psbt: &mut Psbt,
+    tracker: &KeychainTracker<Keychain, P>,
+    store: &mut S,
+    signers: &[Box<dyn Signer>],
+    assets: &bdk_tmp_plan::Assets<DescriptorPublicKey>,
+) -> Result<()>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    // a PSBT made by someone else may only have the full transactions of our outputs
+    for (input, txin) in psbt.inputs.iter_mut().zip(&psbt.unsigned_tx.input) {
+        if input.witness_utxo.is_none() {
+            input.witness_utxo = tracker.graph().get_txout(txin.previous_output).cloned();
+        }
+    }
+    let plans = psbt_plans(psbt, tracker, assets);
+    if plans.iter().all(Option::is_none) {
+        return Err(anyhow!(
+            "none of the inputs spend coins the wallet can sign for"
+        ));
+    }
+    let plans = plans.iter().map(Option::as_ref).collect::<Vec<_>>();
+    sign_psbt(psbt, &plans, signers)?;
+    let signed = psbt
+        .unsigned_tx
+        .input
+        .iter()
+        .zip(&plans)
+        .filter(|(_, plan)| plan.is_some())
+        .map(|(txin, _)| txin.previous_output);
+    audit_signing(store, tracker, psbt.unsigned_tx.txid(), signed)
+}
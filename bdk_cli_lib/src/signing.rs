@@ -0,0 +1,373 @@
+//! Signing and finalizing PSBTs.
+use crate::{Keychain, Preimages};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{
+        secp256k1::Secp256k1,
+        util::{
+            address::WitnessVersion,
+            bip32::KeySource,
+            psbt::{self, PartiallySignedTransaction as Psbt},
+            sighash::{Prevouts, SighashCache},
+        },
+        Transaction, TxOut,
+    },
+    keychain::KeychainTracker,
+    miniscript::{descriptor::KeyMap, DescriptorPublicKey, ForEachKey, ToPublicKey},
+};
+use std::fmt::Debug;
+
+/// Something that can sign the inputs of a PSBT: the keys in a [`KeyMap`], a hardware wallet, a
+/// remote signer or a cosigner.
+///
+/// The commands are given a list of signers. Coins are planned to be spent with the keys the
+/// signers advertise and then each of them is asked to sign every input it may have a key for.
+pub trait Signer {
+    /// The keys (with the origin and derivation path they are used at in our descriptors) the
+    /// signer can sign for.
+    fn keys(&self) -> Vec<DescriptorPublicKey>;
+
+    /// Adds the signatures `plan` needs from the signer to input `input_index` of `psbt`.
+    ///
+    /// Signatures for keys the signer doesn't have are left for other signers. The signatures go
+    /// into the taproot signature fields of the input so [`finalize_psbt`] can use them.
+    fn sign_input(
+        &self,
+        psbt: &mut Psbt,
+        input_index: usize,
+        plan: &bdk_tmp_plan::Plan<DescriptorPublicKey>,
+    ) -> Result<()>;
+
+    /// Signs the inputs of `psbt` that have a plan in `plans` (which are in input order).
+    ///
+    /// By default each input is signed with [`sign_input`]. Signers that sign the whole
+    /// transaction at once, like a hardware wallet that asks its user to confirm it, override this.
+    ///
+    /// [`sign_input`]: Self::sign_input
+    fn sign_psbt(
+        &self,
+        psbt: &mut Psbt,
+        plans: &[Option<&bdk_tmp_plan::Plan<DescriptorPublicKey>>],
+    ) -> Result<()> {
+        for (i, plan) in plans.iter().enumerate() {
+            if let Some(plan) = plan {
+                self.sign_input(psbt, i, plan)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Signer for KeyMap {
+    fn keys(&self) -> Vec<DescriptorPublicKey> {
+        self.keys().cloned().collect()
+    }
+
+    fn sign_input(
+        &self,
+        psbt: &mut Psbt,
+        input_index: usize,
+        plan: &bdk_tmp_plan::Plan<DescriptorPublicKey>,
+    ) -> Result<()> {
+        let prevouts = psbt_prevouts(psbt)?;
+        // the pre-images the plan may need as well are added when the PSBT is finalized
+        let requirements = plan.requirements();
+        // the sighash type the PSBT asks for, `SIGHASH_DEFAULT` (or `SIGHASH_ALL` before taproot)
+        // if it doesn't
+        let input = &psbt.inputs[input_index];
+        let (schnorr_sighash_type, ecdsa_sighash_type) = match plan.witness_version() {
+            Some(WitnessVersion::V1) => (Some(input.schnorr_hash_ty()?), None),
+            _ => (None, Some(input.ecdsa_hash_ty()?)),
+        };
+        let mut auth_data = bdk_tmp_plan::SatisfactionMaterial::default();
+        requirements.signatures.sign_with_keymap(
+            input_index,
+            self,
+            &Prevouts::All(&prevouts),
+            schnorr_sighash_type,
+            ecdsa_sighash_type,
+            &mut SighashCache::new(&psbt.unsigned_tx),
+            &mut auth_data,
+            &Secp256k1::default(),
+        )?;
+        add_signatures(
+            &mut psbt.inputs[input_index],
+            &requirements.signatures,
+            &auth_data,
+        );
+        Ok(())
+    }
+}
+
+/// The previous outputs of the inputs of `psbt` in order (which every taproot sighash commits to).
+pub fn psbt_prevouts(psbt: &Psbt) -> Result<Vec<TxOut>> {
+    psbt.inputs
+        .iter()
+        .enumerate()
+        .map(|(i, input)| {
+            input
+                .witness_utxo
+                .clone()
+                .ok_or_else(|| anyhow!("input {} doesn't have the output it spends", i))
+        })
+        .collect()
+}
+
+/// Puts the signatures in `auth_data` that `signatures` asks for into the fields of `input` they
+/// belong in.
+fn add_signatures(
+    input: &mut psbt::Input,
+    signatures: &bdk_tmp_plan::RequiredSignatures<DescriptorPublicKey>,
+    auth_data: &bdk_tmp_plan::SatisfactionMaterial,
+) {
+    match signatures {
+        bdk_tmp_plan::RequiredSignatures::TapKey { plan_key, .. } => {
+            if let Some(sig) = auth_data.schnorr_sigs.get(&plan_key.descriptor_key) {
+                input.tap_key_sig = Some(*sig);
+            }
+        }
+        bdk_tmp_plan::RequiredSignatures::TapScript {
+            leaf_hash,
+            plan_keys,
+        } => {
+            for plan_key in plan_keys {
+                if let Some(sig) = auth_data.schnorr_sigs.get(&plan_key.descriptor_key) {
+                    let key = plan_key.descriptor_key.to_x_only_pubkey();
+                    input.tap_script_sigs.insert((key, *leaf_hash), *sig);
+                }
+            }
+        }
+        bdk_tmp_plan::RequiredSignatures::Segwitv0 { keys, .. } => {
+            for plan_key in keys {
+                if let Some(sig) = auth_data.ecdsa_sigs.get(&plan_key.descriptor_key) {
+                    let key = plan_key.descriptor_key.to_public_key();
+                    input.partial_sigs.insert(key, *sig);
+                }
+            }
+        }
+        // legacy spends aren't planned for
+        bdk_tmp_plan::RequiredSignatures::Legacy { .. } => {}
+    }
+}
+
+/// The assets to plan spends with when the keys of `signers` are the ones we can sign with.
+pub fn signer_assets(signers: &[Box<dyn Signer>]) -> bdk_tmp_plan::Assets<DescriptorPublicKey> {
+    bdk_tmp_plan::Assets {
+        keys: signers.iter().flat_map(|signer| signer.keys()).collect(),
+        ..Default::default()
+    }
+}
+
+/// Whether none of `signers` have any keys, i.e. the wallet only watches its descriptors and its
+/// transactions have to be signed somewhere else.
+pub fn is_watch_only(signers: &[Box<dyn Signer>]) -> bool {
+    signers.iter().all(|signer| signer.keys().is_empty())
+}
+
+/// The assets to plan the wallet's spends with.
+///
+/// These are the keys of `signers`, unless the wallet [is watch-only](is_watch_only). Then we
+/// assume whoever signs the PSBTs we make has every key of the tracker's descriptors.
+pub fn spending_assets<P>(
+    tracker: &KeychainTracker<Keychain, P>,
+    signers: &[Box<dyn Signer>],
+) -> bdk_tmp_plan::Assets<DescriptorPublicKey> {
+    if !is_watch_only(signers) {
+        return signer_assets(signers);
+    }
+    let mut keys = vec![];
+    for descriptor in tracker.txout_index.keychains().values() {
+        descriptor.for_each_key(|key| {
+            if !keys.contains(key) {
+                keys.push(key.clone());
+            }
+            true
+        });
+    }
+    bdk_tmp_plan::Assets {
+        keys,
+        ..Default::default()
+    }
+}
+
+/// Signs the inputs of `psbt` with `signers`.
+///
+/// `plans` are the plans of the inputs in order. Inputs without a plan are left for someone else
+/// to sign. The PSBT can then be passed on to other signers or to [`finalize_psbt`].
+pub fn sign_psbt(
+    psbt: &mut Psbt,
+    plans: &[Option<&bdk_tmp_plan::Plan<DescriptorPublicKey>>],
+    signers: &[Box<dyn Signer>],
+) -> Result<()> {
+    for signer in signers {
+        signer.sign_psbt(psbt, plans)?;
+    }
+    Ok(())
+}
+
+/// Completes the inputs of `psbt` that have a plan with the signatures and hash pre-images it has
+/// collected.
+///
+/// The final `scriptSig` and witness of each of those inputs are set and the fields that were only
+/// needed to sign them are cleared. Fails if an input with a plan is still missing a signature or
+/// pre-image.
+pub fn finalize_psbt(
+    psbt: &mut Psbt,
+    plans: &[Option<&bdk_tmp_plan::Plan<DescriptorPublicKey>>],
+) -> Result<()> {
+    for (i, plan) in plans.iter().enumerate() {
+        let plan = match plan {
+            Some(plan) => plan,
+            None => continue,
+        };
+        let input = &mut psbt.inputs[i];
+        match plan.try_complete(&input_satisfaction_material(input, plan)) {
+            bdk_tmp_plan::PlanState::Complete {
+                final_script_sig,
+                final_script_witness,
+            } => {
+                // only the spent output and the final fields are kept (see BIP 174)
+                *input = psbt::Input {
+                    non_witness_utxo: input.non_witness_utxo.take(),
+                    witness_utxo: input.witness_utxo.take(),
+                    final_script_sig,
+                    final_script_witness,
+                    unknown: core::mem::take(&mut input.unknown),
+                    proprietary: core::mem::take(&mut input.proprietary),
+                    ..Default::default()
+                };
+            }
+            bdk_tmp_plan::PlanState::Incomplete(_) => {
+                return Err(anyhow!(
+                    "we weren't able to complete the plan of input {} with the signatures we have",
+                    i
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The signatures and hash pre-images `input` has collected for `plan`.
+fn input_satisfaction_material(
+    input: &psbt::Input,
+    plan: &bdk_tmp_plan::Plan<DescriptorPublicKey>,
+) -> bdk_tmp_plan::SatisfactionMaterial {
+    let mut auth_data = Preimages::of_input(input).to_satisfaction_material();
+    match plan.requirements().signatures {
+        bdk_tmp_plan::RequiredSignatures::TapKey { plan_key, .. } => {
+            if let Some(sig) = input.tap_key_sig {
+                auth_data.schnorr_sigs.insert(plan_key.descriptor_key, sig);
+            }
+        }
+        bdk_tmp_plan::RequiredSignatures::TapScript {
+            leaf_hash,
+            plan_keys,
+        } => {
+            for plan_key in plan_keys {
+                let key = plan_key.descriptor_key.to_x_only_pubkey();
+                if let Some(sig) = input.tap_script_sigs.get(&(key, leaf_hash)) {
+                    auth_data.schnorr_sigs.insert(plan_key.descriptor_key, *sig);
+                }
+            }
+        }
+        bdk_tmp_plan::RequiredSignatures::Segwitv0 { keys, .. } => {
+            for plan_key in keys {
+                let key = plan_key.descriptor_key.to_public_key();
+                if let Some(sig) = input.partial_sigs.get(&key) {
+                    auth_data.ecdsa_sigs.insert(plan_key.descriptor_key, *sig);
+                }
+            }
+        }
+        bdk_tmp_plan::RequiredSignatures::Legacy { .. } => {}
+    }
+    auth_data
+}
+
+/// What an input of a PSBT still needs before [`finalize_psbt`] can complete it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct MissingAuth {
+    /// The keys that still have to sign (by master fingerprint and derivation path)
+    pub signatures: Vec<KeySource>,
+    /// The number of hash pre-images that are still missing
+    pub preimages: usize,
+}
+
+impl MissingAuth {
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty() && self.preimages == 0
+    }
+}
+
+/// What each input of `psbt` that has a plan in `plans` still needs, in input order.
+pub fn missing_auth(
+    psbt: &Psbt,
+    plans: &[Option<&bdk_tmp_plan::Plan<DescriptorPublicKey>>],
+) -> Vec<Option<MissingAuth>> {
+    psbt.inputs
+        .iter()
+        .zip(plans)
+        .map(|(input, plan)| {
+            let plan = (*plan)?;
+            let requirements = match plan.try_complete(&input_satisfaction_material(input, plan)) {
+                bdk_tmp_plan::PlanState::Complete { .. } => return Some(MissingAuth::default()),
+                bdk_tmp_plan::PlanState::Incomplete(requirements) => requirements,
+            };
+            let plan_keys = match requirements.signatures {
+                bdk_tmp_plan::RequiredSignatures::TapKey { plan_key, .. } => vec![plan_key],
+                bdk_tmp_plan::RequiredSignatures::TapScript { plan_keys, .. } => plan_keys,
+                bdk_tmp_plan::RequiredSignatures::Segwitv0 { keys, .. } => keys,
+                bdk_tmp_plan::RequiredSignatures::Legacy { .. } => vec![],
+            };
+            Some(MissingAuth {
+                signatures: plan_keys
+                    .into_iter()
+                    .map(|plan_key| {
+                        let key = plan_key.descriptor_key;
+                        (key.master_fingerprint(), key.full_derivation_path())
+                    })
+                    .collect(),
+                preimages: requirements.sha256_images.len()
+                    + requirements.hash256_images.len()
+                    + requirements.ripemd160_images.len()
+                    + requirements.hash160_images.len(),
+            })
+        })
+        .collect()
+}
+
+/// Signs and finalizes the inputs of `transaction` with `signers`.
+///
+/// `plans` and `prevouts` are the plans and previous outputs of the inputs in order. Inputs
+/// without a plan are left for someone else to sign. The sequence of an input is set first if its
+/// plan requires one.
+pub(crate) fn sign_with_plans(
+    transaction: &mut Transaction,
+    plans: &[Option<&bdk_tmp_plan::Plan<DescriptorPublicKey>>],
+    prevouts: &[TxOut],
+    signers: &[Box<dyn Signer>],
+) -> Result<()> {
+    // first set tx values for plan so that we don't change them while signing
+    for (txin, plan) in transaction.input.iter_mut().zip(plans) {
+        if let Some(sequence) = plan.and_then(|plan| plan.required_sequence()) {
+            txin.sequence = sequence;
+        }
+    }
+
+    let mut psbt = Psbt::from_unsigned_tx(transaction.clone())?;
+    for (input, prevout) in psbt.inputs.iter_mut().zip(prevouts) {
+        input.witness_utxo = Some(prevout.clone());
+    }
+    sign_psbt(&mut psbt, plans, signers)?;
+    finalize_psbt(&mut psbt, plans)?;
+
+    for ((txin, input), plan) in transaction.input.iter_mut().zip(psbt.inputs).zip(plans) {
+        if plan.is_some() {
+            txin.script_sig = input.final_script_sig.unwrap_or_default();
+            txin.witness = input.final_script_witness.unwrap_or_default();
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,798 @@
+//! Selecting coins and building the transactions the wallet sends.
+use crate::{
+    check_maturity, described_utxos, finalize_psbt, is_watch_only, plan_keys, sign_psbt,
+    spending_assets, CoinSelectionAlgo, DescribedUtxo, DustChange, FrozenUtxos, Keychain, Signer,
+    TxBuilder, DEFAULT_FEERATE,
+};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{
+        locktime::LOCK_TIME_THRESHOLD,
+        util::{address::WitnessVersion, psbt::PartiallySignedTransaction as Psbt},
+        LockTime, OutPoint, SchnorrSighashType, Sequence, Transaction, TxIn, TxOut, Txid,
+    },
+    chain_graph::ChainGraph,
+    descriptor_ext::DescriptorExt,
+    keychain::KeychainTracker,
+    miniscript::{
+        descriptor::DefiniteDescriptorKey,
+        psbt::{PsbtInputExt, PsbtOutputExt},
+        Descriptor, DescriptorPublicKey, ToPublicKey,
+    },
+    sparse_chain::ChainPosition,
+    standardness::{dust_threshold, StandardnessPolicy},
+    TxHeight,
+};
+use bdk_coin_select::{
+    coin_select_bnb_cancellable, sat_per_wu_to_vb, CoinSelector, CoinSelectorOpt,
+    ExcessStrategyKind, WeightedValue,
+};
+use rand::{rngs::StdRng, SeedableRng};
+use std::{cmp::Reverse, collections::BTreeSet, time::Duration};
+
+/// Creates a transaction funding `outputs` and signs it with `signers`.
+///
+/// This is [`create_psbt`] followed by [`sign_psbt`] and [`finalize_psbt`]. Call them one by one
+/// to have someone else sign the transaction. The returned PSBT is finalized so the transaction can
+/// be extracted from it, unless the wallet [is watch-only](is_watch_only): then there is nothing to
+/// sign with and the PSBT is returned unsigned.
+pub fn create_tx<P: ChainPosition>(
+    outputs: &[TxOut],
+    builder: &TxBuilder,
+    keychain_tracker: &mut KeychainTracker<Keychain, P>,
+    signers: &[Box<dyn Signer>],
+) -> Result<Psbt> {
+    let assets = spending_assets(keychain_tracker, signers);
+    let (mut psbt, plans) = create_psbt(outputs, builder, keychain_tracker, &assets)?;
+    if is_watch_only(signers) {
+        return Ok(psbt);
+    }
+    let plans = plans.iter().map(Some).collect::<Vec<_>>();
+    sign_psbt(&mut psbt, &plans, signers)?;
+    finalize_psbt(&mut psbt, &plans)?;
+    Ok(psbt)
+}
+
+/// Creates a transaction spending the wallet's outputs of the unconfirmed transaction `txid` back
+/// to the wallet, paying enough fee for the two (and the unconfirmed ancestors of `txid`) to reach
+/// `feerate` in sats per weight unit together (child pays for parent).
+///
+/// The transaction is made and signed like [`create_tx`]. More coins (other than the `frozen`
+/// ones) are added if the outputs of `txid` can't pay the fee on their own.
+pub fn create_cpfp_tx<P: ChainPosition>(
+    txid: Txid,
+    feerate: f32,
+    frozen: &FrozenUtxos,
+    keychain_tracker: &mut KeychainTracker<Keychain, P>,
+    signers: &[Box<dyn Signer>],
+) -> Result<Psbt> {
+    match keychain_tracker.chain().tx_position(txid) {
+        Some(position) if position.height() == TxHeight::Unconfirmed => {}
+        Some(_) => return Err(anyhow!("{} is already confirmed", txid)),
+        None => return Err(anyhow!("{} isn't in the wallet's chain", txid)),
+    }
+    let must_spend = keychain_tracker
+        .full_utxos()
+        .map(|(_, utxo)| utxo.outpoint)
+        .filter(|outpoint| outpoint.txid == txid)
+        .collect::<Vec<_>>();
+    if must_spend.is_empty() {
+        return Err(anyhow!("{} has no unspent outputs of the wallet", txid));
+    }
+    let mut builder = TxBuilder {
+        feerate: Some(feerate),
+        must_spend,
+        ..Default::default()
+    };
+    frozen.exclude_from(&mut builder);
+    create_tx(&[], &builder, keychain_tracker, signers)
+}
+
+/// Creates a transaction replacing `original`, one of the wallet's transactions that has been
+/// abandoned (see [`abandon_txs`]). It spends the wallet's coins `original` spent again, so the two
+/// conflict, and pays the outputs of `original` that aren't the wallet's. The rest goes to change.
+///
+/// `feerate` is in sats per weight unit and defaults to the feerate of `original` plus the minimum
+/// relay feerate, which usually pays the extra fee a replacement needs. The transaction is made
+/// and signed like [`create_tx`], adding more coins (other than the `frozen` ones) if needed.
+///
+/// [`abandon_txs`]: crate::abandon_txs
+pub fn create_replacement_tx<P: ChainPosition>(
+    original: &Transaction,
+    feerate: Option<f32>,
+    frozen: &FrozenUtxos,
+    keychain_tracker: &mut KeychainTracker<Keychain, P>,
+    signers: &[Box<dyn Signer>],
+) -> Result<Psbt> {
+    let txid = original.txid();
+    if keychain_tracker.chain().tx_position(txid).is_some() {
+        return Err(anyhow!(
+            "{} has to be abandoned before it is replaced",
+            txid
+        ));
+    }
+    let txout_index = &keychain_tracker.txout_index;
+    let must_spend = original
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .filter(|&outpoint| txout_index.txout(outpoint).is_some())
+        .collect::<Vec<_>>();
+    if must_spend.is_empty() {
+        return Err(anyhow!("{} doesn't spend any of the wallet's coins", txid));
+    }
+    let outputs = original
+        .output
+        .iter()
+        .filter(|txout| txout_index.index_of_spk(&txout.script_pubkey).is_none())
+        .cloned()
+        .collect::<Vec<_>>();
+    let feerate = match feerate {
+        Some(feerate) => feerate,
+        None => {
+            keychain_tracker
+                .graph()
+                .calculate_feerate(original)
+                .ok_or_else(|| anyhow!("the fee of {} is unknown, pass a feerate", txid))?
+                + StandardnessPolicy::default().min_relay_feerate
+        }
+    };
+    let mut builder = TxBuilder {
+        feerate: Some(feerate),
+        must_spend,
+        ..Default::default()
+    };
+    frozen.exclude_from(&mut builder);
+    create_tx(&outputs, &builder, keychain_tracker, signers)
+}
+
+/// How a change output paying to `descriptor` will be spent, which is only needed for the weight of
+/// spending it. If only timelocked branches can spend it, it is planned as if their timelocks had
+/// passed (first height based ones, then time based ones).
+pub(crate) fn plan_change(
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+    assets: &bdk_tmp_plan::Assets<DescriptorPublicKey>,
+) -> Option<bdk_tmp_plan::Plan<DescriptorPublicKey>> {
+    let matured = |max_locktime: LockTime, txo_age: Sequence| bdk_tmp_plan::Assets {
+        max_locktime: Some(max_locktime),
+        txo_age: Some(txo_age),
+        ..assets.clone()
+    };
+    bdk_tmp_plan::plan_satisfaction(descriptor, assets)
+        .or_else(|| {
+            let assets = matured(
+                LockTime::from_consensus(LOCK_TIME_THRESHOLD - 1),
+                Sequence::from_height(u16::MAX),
+            );
+            bdk_tmp_plan::plan_satisfaction(descriptor, &assets)
+        })
+        .or_else(|| {
+            let assets = matured(
+                LockTime::from_consensus(u32::MAX),
+                Sequence::from_512_second_intervals(u16::MAX),
+            );
+            bdk_tmp_plan::plan_satisfaction(descriptor, &assets)
+        })
+}
+
+/// Takes `fee` from the outputs at the indices `from`, in proportion to their values. The
+/// rounding remainder is taken from the first of them.
+///
+/// It is an error for an output to be left with less than the dust threshold.
+pub(crate) fn subtract_fee(outputs: &mut [TxOut], from: &[usize], fee: u64) -> Result<()> {
+    let from = from.iter().copied().collect::<BTreeSet<_>>();
+    let total = from.iter().map(|&index| outputs[index].value).sum::<u64>();
+    if fee > total {
+        return Err(anyhow!(
+            "the outputs the fee is subtracted from have {} sats but the fee is {} sats",
+            total,
+            fee
+        ));
+    }
+    let shares = from
+        .iter()
+        .map(|&index| (fee as u128 * outputs[index].value as u128 / total as u128) as u64)
+        .collect::<Vec<_>>();
+    let remainder = fee - shares.iter().sum::<u64>();
+    for (i, (&index, share)) in from.iter().zip(shares).enumerate() {
+        let share = if i == 0 { share + remainder } else { share };
+        let output = &mut outputs[index];
+        let dust = dust_threshold(
+            &output.script_pubkey,
+            StandardnessPolicy::default().dust_relay_feerate,
+        );
+        match output.value.checked_sub(share) {
+            Some(value) if value >= dust => output.value = value,
+            _ => {
+                return Err(anyhow!(
+                    "output {} of {} sats would be dust after paying {} sats of the fee",
+                    index,
+                    output.value,
+                    share
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the input spending `outpoint` with `plan` can have `sequence`.
+///
+/// A relative timelock is only satisfied by a sequence of the same unit that is at least as long,
+/// and an absolute timelock needs a sequence that doesn't disable the locktime.
+fn check_sequence(
+    outpoint: OutPoint,
+    sequence: Sequence,
+    plan: &bdk_tmp_plan::Plan<DescriptorPublicKey>,
+    disable_rbf: bool,
+) -> Result<()> {
+    if let Some(required) = plan.required_sequence() {
+        if !sequence.is_relative_lock_time()
+            || sequence.is_height_locked() != required.is_height_locked()
+            || sequence.to_consensus_u32() & 0xffff < required.to_consensus_u32() & 0xffff
+        {
+            return Err(anyhow!(
+                "the sequence {:#x} of the input spending {} doesn't satisfy the relative \
+                 timelock of {:#x} its spending path requires",
+                sequence,
+                outpoint,
+                required
+            ));
+        }
+    }
+    if plan.required_locktime().is_some() && !sequence.enables_absolute_lock_time() {
+        return Err(anyhow!(
+            "the sequence {:#x} of the input spending {} disables the locktime its spending path \
+             requires",
+            sequence,
+            outpoint
+        ));
+    }
+    if disable_rbf && sequence.is_rbf() {
+        return Err(anyhow!(
+            "the sequence {:#x} of the input spending {} signals replaceability but RBF is \
+             disabled",
+            sequence,
+            outpoint
+        ));
+    }
+    Ok(())
+}
+
+/// Creates an unsigned PSBT funding `outputs` from the coins we can spend with `assets`.
+///
+/// Without any `outputs` everything but the fee goes to the change output, e.g. to spend the
+/// coins of [`TxBuilder::must_spend`] back to the wallet. With [`TxBuilder::drain_to`] there is no
+/// change and what is left goes to that script instead.
+///
+/// The inputs have the outputs they spend (and the transactions of those outputs when we have
+/// them) along with the key origins of the keys that sign for them. The change output has the key
+/// origins of its descriptor so a signer can tell it apart from the payment. The sequence of each
+/// input is already set to what its plan requires (or to [`TxBuilder::sequences`]).
+///
+/// Returns the plans of the inputs in order, which [`sign_psbt`] and [`finalize_psbt`] need.
+pub fn create_psbt<P: ChainPosition>(
+    outputs: &[TxOut],
+    builder: &TxBuilder,
+    keychain_tracker: &mut KeychainTracker<Keychain, P>,
+    assets: &bdk_tmp_plan::Assets<DescriptorPublicKey>,
+) -> Result<(Psbt, Vec<bdk_tmp_plan::Plan<DescriptorPublicKey>>)> {
+    if matches!(builder.txo_age, Some(age) if !age.is_time_locked()) {
+        return Err(anyhow!(
+            "the age of the coins is only for timelocks in units of 512 seconds, height based ones \
+             are checked against the confirmations of each coin"
+        ));
+    }
+    let mut assets = bdk_tmp_plan::Assets {
+        max_locktime: builder.max_locktime.or(assets.max_locktime),
+        txo_age: builder.txo_age.or(assets.txo_age),
+        ..assets.clone()
+    };
+    builder.preimages.extend_assets(&mut assets);
+    let assets = &assets;
+    let tip_height = keychain_tracker
+        .chain()
+        .latest_checkpoint()
+        .map(|block_id| block_id.height);
+
+    let target_feerate = builder.feerate.unwrap_or(DEFAULT_FEERATE);
+    let min_relay_feerate = StandardnessPolicy::default().min_relay_feerate;
+    // also refuses NaN
+    if !(target_feerate.is_finite() && target_feerate >= min_relay_feerate) {
+        return Err(anyhow!(
+            "a feerate of {} sats/vbyte is below the minimum relay feerate of {} sats/vbyte",
+            sat_per_wu_to_vb(target_feerate),
+            sat_per_wu_to_vb(min_relay_feerate)
+        ));
+    }
+
+    if let Some(index) = builder
+        .subtract_fee_from
+        .iter()
+        .find(|&&index| index >= outputs.len())
+    {
+        return Err(anyhow!(
+            "can't subtract the fee from output {} since there are only {} outputs",
+            index,
+            outputs.len()
+        ));
+    }
+    if builder.drain_to.is_some() && !builder.subtract_fee_from.is_empty() {
+        return Err(anyhow!(
+            "the fee can't be subtracted from the outputs when draining the wallet"
+        ));
+    }
+    // the outputs pay the fee rather than the coins so the coins are selected as if there was no
+    // fee, and the fee is taken from the outputs once the weight of the transaction is known
+    let selection_feerate = if builder.subtract_fee_from.is_empty() {
+        target_feerate
+    } else {
+        0.0
+    };
+
+    // TODO use planning module
+    let mut candidates = vec![];
+    let mut immature = vec![];
+    let mut unsettled = 0;
+    let mut unconfirmed = 0;
+    if let Some(outpoint) = builder
+        .must_spend
+        .iter()
+        .find(|outpoint| builder.exclude.contains(outpoint))
+    {
+        return Err(anyhow!(
+            "{} is both excluded and required to be spent",
+            outpoint
+        ));
+    }
+    if let Some(outpoint) = builder
+        .sequences
+        .keys()
+        .find(|outpoint| !builder.must_spend.contains(outpoint))
+    {
+        return Err(anyhow!(
+            "a sequence is given for {} but it isn't one of the coins that have to be spent",
+            outpoint
+        ));
+    }
+    if let Some(outpoint) = builder
+        .input_sighash_types
+        .keys()
+        .find(|outpoint| !builder.must_spend.contains(outpoint))
+    {
+        return Err(anyhow!(
+            "a sighash type is given for {} but it isn't one of the coins that have to be spent",
+            outpoint
+        ));
+    }
+    for utxo in described_utxos(keychain_tracker, assets) {
+        let outpoint = utxo.full_txout.outpoint;
+        if builder.exclude.contains(&outpoint)
+            || (builder.only_must_spend && !builder.must_spend.contains(&outpoint))
+        {
+            continue;
+        }
+        match check_maturity(&utxo, assets, tip_height) {
+            Ok(()) if utxo.plan.is_none() => {}
+            Ok(()) if !is_settled(&utxo, builder, keychain_tracker) => unsettled += 1,
+            Ok(())
+                if !builder.spend_unconfirmed
+                    && !utxo.full_txout.chain_position.height().is_confirmed()
+                    && !builder.must_spend.contains(&outpoint) =>
+            {
+                unconfirmed += 1
+            }
+            Ok(()) => candidates.push(utxo),
+            Err(immature_utxo) => immature.push(immature_utxo),
+        }
+    }
+
+    // apply coin selection algorithm
+    order_candidates(&mut candidates, &builder.coin_select, tip_height);
+
+    // turn the txos we chose into a weight and value. An unconfirmed coin also carries the weight
+    // of its ancestors that their fees don't pay for, unless a coin that has to be spent comes
+    // from the same transaction and the base weight pays for them already.
+    let wv_candidates = candidates
+        .iter()
+        .map(|utxo| {
+            let plan = utxo.plan.as_ref().expect("candidates have a plan");
+            let txid = utxo.full_txout.outpoint.txid;
+            let ancestor_weight = if builder
+                .must_spend
+                .iter()
+                .any(|outpoint| outpoint.txid == txid)
+            {
+                0
+            } else {
+                unpaid_ancestor_weight(keychain_tracker.chain_graph(), [txid], target_feerate)
+            };
+            let sighash_weight =
+                sighash_flag_weight(builder.sighash_type_of(utxo.full_txout.outpoint), plan);
+            WeightedValue::new(
+                utxo.full_txout.txout.value,
+                plan.expected_weight() as u32 + sighash_weight + ancestor_weight,
+                plan.witness_version().is_some(),
+            )
+        })
+        .collect();
+
+    let mut outputs = outputs.to_vec();
+
+    // the output getting what is left over: change, or the script everything is drained to
+    let (mut change_output, change_satisfaction_weight, min_drain_value, change_descriptor) =
+        match &builder.drain_to {
+            Some(script) => (
+                TxOut {
+                    value: 0,
+                    script_pubkey: script.clone(),
+                },
+                0,
+                dust_threshold(script, StandardnessPolicy::default().dust_relay_feerate),
+                None,
+            ),
+            None => {
+                let internal_keychain = builder
+                    .change_policy
+                    .change_keychain(keychain_tracker)
+                    .ok_or_else(|| anyhow!("none of the keychains of the change policy exist"))?;
+                let (change_index, change_script) = {
+                    let (index, script) =
+                        keychain_tracker.txout_index.next_unused(&internal_keychain);
+                    (index, script.clone())
+                };
+                let keychain_descriptor = keychain_tracker
+                    .txout_index
+                    .keychains()
+                    .get(&internal_keychain)
+                    .expect("must exist");
+                let change_descriptor = keychain_descriptor.at_derivation_index(change_index);
+                let change_plan = plan_change(&change_descriptor, assets).ok_or_else(|| {
+                    anyhow!("we can't spend the change keychain {}", internal_keychain)
+                })?;
+                (
+                    TxOut {
+                        value: 0,
+                        script_pubkey: change_script,
+                    },
+                    change_plan.expected_weight() as u32,
+                    match builder.dust_change {
+                        // any change at all is better than giving it to the miners
+                        DustChange::Include => 1,
+                        _ => builder
+                            .dust_limit
+                            .unwrap_or_else(|| keychain_descriptor.dust_value()),
+                    },
+                    Some(change_descriptor),
+                )
+            }
+        };
+
+    let mut cs_opts = CoinSelectorOpt {
+        target_feerate: selection_feerate,
+        min_drain_value,
+        ..CoinSelectorOpt::fund_outputs(&outputs, &change_output, change_satisfaction_weight)
+    };
+    // paying for the weight of the parents their fees don't cover brings the package up to the
+    // feerate
+    cs_opts.base_weight += unpaid_ancestor_weight(
+        keychain_tracker.chain_graph(),
+        builder.must_spend.iter().map(|outpoint| outpoint.txid),
+        target_feerate,
+    );
+
+    // apply coin selection by saying we need to fund these outputs
+    let mut coin_selector = CoinSelector::new(&wv_candidates, &cs_opts);
+    for outpoint in &builder.must_spend {
+        let index = candidates
+            .iter()
+            .position(|utxo| utxo.full_txout.outpoint == *outpoint)
+            .ok_or_else(|| anyhow!("{} isn't one of the coins we can spend", outpoint))?;
+        coin_selector.select(index);
+    }
+
+    // just select coins in the order provided until we have enough
+    // only use first result (least waste)
+    let selection = match builder.coin_select {
+        _ if builder.drain_to.is_some() => {
+            if builder.must_spend.is_empty() {
+                coin_selector.select_all();
+            }
+            coin_selector.finish()
+        }
+        CoinSelectionAlgo::BranchAndBound => coin_select_bnb_cancellable(
+            Duration::from_secs(10),
+            coin_selector.clone(),
+            &builder.cancel,
+        )
+        .map_or_else(|| coin_selector.select_until_finished(), |cs| cs.finish()),
+        _ => coin_selector.select_until_finished(),
+    }
+    .map_err(|e| {
+        let mut error = anyhow!(e);
+        if unsettled > 0 {
+            error = error.context(format!(
+                "{} received output(s) don't have enough confirmations to be spent yet",
+                unsettled
+            ));
+        }
+        if unconfirmed > 0 {
+            error = error.context(format!(
+                "{} unconfirmed output(s) weren't spent since unconfirmed coins aren't spent by \
+                 default",
+                unconfirmed
+            ));
+        }
+        if immature.is_empty() {
+            return error;
+        }
+        let mut explanation = format!(
+            "{} output(s) couldn't be used because they haven't matured yet:",
+            immature.len()
+        );
+        for immature_utxo in &immature {
+            explanation += &format!("\n  {}", immature_utxo);
+        }
+        error.context(explanation)
+    })?;
+    let to_drain = selection
+        .excess_strategies
+        .get(&ExcessStrategyKind::ToDrain);
+    let selection_meta = match (&builder.drain_to, builder.dust_change, to_drain) {
+        (Some(_), _, to_drain) => to_drain
+            .ok_or_else(|| anyhow!("nothing would be left to drain after paying the fee"))?,
+        (None, DustChange::AddToFee, _) => selection.best_strategy().1,
+        (None, _, Some(to_drain)) => to_drain,
+        (None, DustChange::Fail, None) if selection.excess > 0 => {
+            return Err(anyhow!(
+                "the {} sats left over would be dust as change (the dust limit is {} sats)",
+                selection.excess,
+                min_drain_value
+            ))
+        }
+        (None, _, None) => selection.best_strategy().1,
+    };
+
+    // get the selected utxos
+    let selected_txos = selection.apply_selection(&candidates).collect::<Vec<_>>();
+    let plans = selected_txos
+        .iter()
+        .map(|utxo| utxo.plan.clone().expect("candidates have a plan"))
+        .collect::<Vec<_>>();
+
+    if !builder.subtract_fee_from.is_empty() {
+        // what is left over without change already goes to the fee
+        let fee = (selection_meta.weight as f32 * target_feerate).ceil() as u64;
+        subtract_fee(
+            &mut outputs,
+            &builder.subtract_fee_from,
+            fee.saturating_sub(selection_meta.fee),
+        )?;
+    }
+
+    let mut change_index = None;
+    if let Some(drain_value) = selection_meta.drain_value {
+        change_output.value = drain_value;
+        // if the selection tells us to use change and the change value is sufficient we add it as an output
+        change_index = Some(outputs.len());
+        outputs.push(change_output)
+    }
+
+    let mut rng = match builder.rng_seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    // the signatures commit to the order so it has to be settled before the PSBT is made
+    let mut inputs = selected_txos.into_iter().zip(plans).collect::<Vec<_>>();
+    builder
+        .ordering
+        .sort_inputs(&mut inputs, |(utxo, _)| utxo.full_txout.outpoint, &mut rng);
+    let (selected_txos, plans): (Vec<_>, Vec<_>) = inputs.into_iter().unzip();
+    let mut outputs = outputs.into_iter().enumerate().collect::<Vec<_>>();
+    builder
+        .ordering
+        .sort_outputs(&mut outputs, |(_, txout)| txout, &mut rng);
+    let change_index = change_index
+        .and_then(|change_index| outputs.iter().position(|&(index, _)| index == change_index));
+    let outputs = outputs
+        .into_iter()
+        .map(|(_, txout)| txout)
+        .collect::<Vec<_>>();
+
+    // the locktime must satisfy the `after` timelocks of the spending paths we chose
+    let lock_time = match builder.lock_time {
+        Some(lock_time) => {
+            if let Some(required) = plans
+                .iter()
+                .filter_map(|plan| plan.required_locktime())
+                .find(|required| {
+                    !required.is_same_unit(lock_time)
+                        || required.to_consensus_u32() > lock_time.to_consensus_u32()
+                })
+            {
+                return Err(anyhow!(
+                    "a locktime of {:#} doesn't satisfy the locktime of {:#} a spent coin requires",
+                    lock_time,
+                    required
+                ));
+            }
+            lock_time
+        }
+        None => builder.anti_fee_sniping.locktime(
+            tip_height,
+            plans.iter().filter_map(|plan| plan.required_locktime()),
+            &mut rng,
+        )?,
+    };
+
+    let default_sequence = if builder.disable_rbf {
+        Sequence::ENABLE_LOCKTIME_NO_RBF
+    } else {
+        Sequence::ENABLE_RBF_NO_LOCKTIME
+    };
+    let mut input = vec![];
+    for (utxo, plan) in selected_txos.iter().zip(&plans) {
+        let outpoint = utxo.full_txout.outpoint;
+        let sequence = match builder.sequences.get(&outpoint) {
+            Some(&sequence) => sequence,
+            None => plan.required_sequence().unwrap_or(default_sequence),
+        };
+        check_sequence(outpoint, sequence, plan, builder.disable_rbf)?;
+        input.push(TxIn {
+            previous_output: outpoint,
+            sequence,
+            ..Default::default()
+        });
+    }
+    if lock_time != LockTime::ZERO
+        && !input
+            .iter()
+            .any(|txin| txin.sequence.enables_absolute_lock_time())
+    {
+        return Err(anyhow!(
+            "the locktime of {:#} would be disabled since every input has a final sequence",
+            lock_time
+        ));
+    }
+
+    let transaction = Transaction {
+        version: 0x02,
+        lock_time: lock_time.into(),
+        input,
+        output: outputs,
+    };
+
+    let mut psbt = Psbt::from_unsigned_tx(transaction)?;
+    for ((input, utxo), plan) in psbt.inputs.iter_mut().zip(&selected_txos).zip(&plans) {
+        let outpoint = utxo.full_txout.outpoint;
+        input.witness_utxo = Some(utxo.full_txout.txout.clone());
+        // some signers want the whole transaction even for segwit inputs
+        input.non_witness_utxo = keychain_tracker.graph().get_tx(outpoint.txid).cloned();
+        input
+            .update_with_descriptor_unchecked(&utxo.descriptor)
+            .map_err(|e| anyhow!("can't describe the input spending {}: {:?}", outpoint, e))?;
+        input.sighash_type = match (builder.sighash_type_of(outpoint), plan.witness_version()) {
+            // ECDSA signatures have no default sighash type, `SIGHASH_ALL` is used instead
+            (Some(SchnorrSighashType::Default), Some(WitnessVersion::V0)) => None,
+            (sighash_type, _) => sighash_type.map(Into::into),
+        };
+        builder.preimages.add_to_input(input, plan);
+        // only the keys that sign are listed so that cosigners plan the same spend (see
+        // `psbt_plans`)
+        let signing_keys = plan_keys(plan);
+        let x_only_keys = signing_keys
+            .iter()
+            .map(|key| key.to_x_only_pubkey())
+            .collect::<BTreeSet<_>>();
+        input
+            .tap_key_origins
+            .retain(|key, _| x_only_keys.contains(key));
+        let keys = signing_keys
+            .iter()
+            .map(|key| key.to_public_key().inner)
+            .collect::<BTreeSet<_>>();
+        input.bip32_derivation.retain(|key, _| keys.contains(key));
+    }
+    if let (Some(change_descriptor), Some(change_index)) = (&change_descriptor, change_index) {
+        let change = &mut psbt.outputs[change_index];
+        change
+            .update_with_descriptor_unchecked(change_descriptor)
+            .map_err(|e| anyhow!("can't describe the change output: {:?}", e))?;
+    }
+
+    Ok((psbt, plans))
+}
+
+/// The weight the sighash flags of the signatures `plan` needs add to its
+/// [`expected_weight`](bdk_tmp_plan::Plan::expected_weight): a byte each for taproot signatures
+/// unless they are signed with `SIGHASH_DEFAULT`. ECDSA signatures always have one, which the
+/// plan counts already.
+fn sighash_flag_weight(
+    sighash_type: Option<SchnorrSighashType>,
+    plan: &bdk_tmp_plan::Plan<DescriptorPublicKey>,
+) -> u32 {
+    match (sighash_type, plan.witness_version()) {
+        (Some(SchnorrSighashType::Default) | None, _) => 0,
+        (Some(_), Some(WitnessVersion::V1)) => plan.signature_count() as u32,
+        (Some(_), _) => 0,
+    }
+}
+
+/// The weight of `parents` and their unconfirmed ancestors that their fees don't pay for at
+/// `feerate` (in sats per weight unit).
+///
+/// A transaction spending from `parents` has to pay `feerate` for this much weight on top of its
+/// own for the whole package to reach `feerate`. The fee of a transaction we don't have the spent
+/// outputs of can't be calculated so it is taken to be zero, which may overpay but never underpays.
+/// Confirmed parents need nothing.
+pub fn unpaid_ancestor_weight<P: ChainPosition>(
+    chain_graph: &ChainGraph<P>,
+    parents: impl IntoIterator<Item = Txid>,
+    feerate: f32,
+) -> u32 {
+    let mut package = BTreeSet::new();
+    for txid in parents {
+        let position = chain_graph.chain().tx_position(txid);
+        if position.map(ChainPosition::height) == Some(TxHeight::Unconfirmed) {
+            package.insert(txid);
+            package.extend(chain_graph.unconfirmed_ancestors(txid));
+        }
+    }
+    let (mut fee, mut weight) = (0, 0);
+    for tx in package
+        .into_iter()
+        .filter_map(|txid| chain_graph.graph().get_tx(txid))
+    {
+        fee += chain_graph.graph().calculate_fee(tx).unwrap_or(0).max(0);
+        weight += tx.weight();
+    }
+    (weight as f32 - fee as f32 / feerate).max(0.0).ceil() as u32
+}
+
+/// Whether `utxo` meets the confirmation policy of `builder` so it can be spent.
+pub(crate) fn is_settled<AK, P: ChainPosition>(
+    utxo: &DescribedUtxo<Keychain, AK, P>,
+    builder: &TxBuilder,
+    keychain_tracker: &KeychainTracker<Keychain, P>,
+) -> bool {
+    builder.confirmation_policy.is_settled(
+        keychain_tracker.chain(),
+        utxo.full_txout.chain_position.height(),
+        utxo.keychain.is_trusted(),
+    )
+}
+
+/// Sorts `candidates` in the order `coin_select` picks them in.
+///
+/// Branch and bound searches through the candidates itself so their order is left alone.
+pub(crate) fn order_candidates<P: ChainPosition>(
+    candidates: &mut [DescribedUtxo<Keychain, DescriptorPublicKey, P>],
+    coin_select: &CoinSelectionAlgo,
+    tip_height: Option<u32>,
+) {
+    match coin_select {
+        CoinSelectionAlgo::LargestFirst => {
+            candidates.sort_by_key(|utxo| Reverse(utxo.full_txout.txout.value))
+        }
+        CoinSelectionAlgo::SmallestFirst => {
+            candidates.sort_by_key(|utxo| utxo.full_txout.txout.value)
+        }
+        CoinSelectionAlgo::OldestFirst => {
+            candidates.sort_by_key(|utxo| utxo.full_txout.chain_position.clone())
+        }
+        CoinSelectionAlgo::NewestFirst => {
+            candidates.sort_by_key(|utxo| Reverse(utxo.full_txout.chain_position.clone()))
+        }
+        CoinSelectionAlgo::MostCoinDaysFirst | CoinSelectionAlgo::FewestCoinDaysFirst => {
+            let coin_days = |utxo: &DescribedUtxo<_, _, _>| {
+                utxo.full_txout
+                    .coin_days_destroyed(tip_height.unwrap_or_default())
+            };
+            candidates.sort_by(|a, b| coin_days(a).total_cmp(&coin_days(b)));
+            if matches!(coin_select, CoinSelectionAlgo::MostCoinDaysFirst) {
+                candidates.reverse();
+            }
+        }
+        CoinSelectionAlgo::BranchAndBound => {}
+    }
+}
@@ -0,0 +1,423 @@
+//! Opening, recovering, backing up and restoring the wallet database.
+use crate::{
+    add_imports, build_tracker, BackupReport, ImportDescriptor, Keychain, Migration,
+    RecoveryReport, RestoreReport, IMPORTS_EXTENSION, MIGRATION_EXTENSION,
+};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::hashes::sha256,
+    file_store::{FileError, IterError, KeychainStore},
+    keychain::{KeychainChangeSet, KeychainTracker, PersistBackend},
+    miniscript::{Descriptor, DescriptorPublicKey},
+    sparse_chain::{ChainPosition, PositionSchema},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The number of entries the store can build up before it is compacted.
+const AUTO_COMPACT_ENTRIES: usize = 1000;
+
+/// Opens (or creates) the store at `db_path`, encrypted with `password` if there is one and hash
+/// chained if `hash_chain` is set.
+///
+/// An existing store that isn't encrypted is rewritten encrypted when a password is given, and
+/// one that isn't hash chained is rewritten as such when `hash_chain` is set. Either way the file
+/// is only replaced once the rewritten one is on disk. The store compacts itself once it has more
+/// than [`AUTO_COMPACT_ENTRIES`] entries.
+pub fn open_store<K, P>(
+    db_path: &Path,
+    password: Option<&str>,
+    hash_chain: bool,
+) -> Result<KeychainStore<K, P>>
+where
+    K: Clone + Ord + Debug,
+    P: PositionSchema,
+    KeychainChangeSet<K, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    let mut store = match password {
+        Some(password) => {
+            match KeychainStore::new_encrypted_from_path(db_path, password.as_bytes()) {
+                Err(FileError::NotEncrypted) => {
+                    eprintln!("Encrypting {}", db_path.display());
+                    let mut store = KeychainStore::new_from_path(db_path)?;
+                    store.set_password(Some(password.as_bytes()))?;
+                    store
+                }
+                result => result?,
+            }
+        }
+        None => KeychainStore::new_from_path(db_path)?,
+    };
+    if hash_chain && !store.is_hash_chained() {
+        eprintln!("Hash chaining {}", db_path.display());
+        store.set_hash_chain(true)?;
+    }
+    store.set_auto_compact(Some(AUTO_COMPACT_ENTRIES));
+    Ok(store)
+}
+
+/// Loads the changesets in `store` into `tracker`.
+///
+/// If some of them can't be read the ones before it are still loaded and a warning is printed
+/// suggesting a rescan.
+pub fn load_or_warn<K, P>(
+    store: &mut impl PersistBackend<K, P>,
+    tracker: &mut KeychainTracker<K, P>,
+    db_path: &Path,
+) where
+    K: Clone + Ord + Debug,
+    P: ChainPosition,
+{
+    if let Err(e) = store.load_into_keychain_tracker(tracker) {
+        warn_load_failure(tracker, db_path, e);
+    }
+}
+
+/// Loads the changesets in the store at `db_path` into `tracker`, cutting off a damaged tail if
+/// the last write to it didn't complete.
+///
+/// The entries before the cut off one are kept and a copy of the original file is saved next to
+/// it (see [`KeychainStore::recover`]). An entry that is damaged in any other way may have intact
+/// entries after it, so rather than dropping those this fails and points to `recover-db` (see
+/// [`run_recover_db_cmd`]). Other failures are reported like [`load_or_warn`] does.
+pub fn load_or_recover<K, P>(
+    store: &mut KeychainStore<K, P>,
+    tracker: &mut KeychainTracker<K, P>,
+    db_path: &Path,
+) -> Result<()>
+where
+    K: Clone + Ord + Debug,
+    P: PositionSchema,
+    KeychainChangeSet<K, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    let error = match store.load_into_keychain_tracker(tracker) {
+        Ok(()) => return Ok(()),
+        Err(error @ IterError::Truncated { .. }) => error,
+        Err(error @ (IterError::Bincode(_) | IterError::Decrypt | IterError::Corrupted { .. })) => {
+            return Err(anyhow!(
+                "{} is damaged ({}). Run `recover-db` to remove the entries from the damaged one \
+                 on (a copy of the database is kept), or `restore-db` to replace it with a backup",
+                db_path.display(),
+                error
+            ))
+        }
+        Err(e) => {
+            warn_load_failure(tracker, db_path, e);
+            return Ok(());
+        }
+    };
+
+    let backup_path = recovery_backup_path(db_path);
+    match store.recover(&backup_path) {
+        // the changesets before the damaged one have already been applied to the tracker
+        Ok(recovery) => eprintln!(
+            "The end of {} was damaged ({}). Recovered {} entries and removed the last {} bytes, \
+             the original was saved to {}.",
+            db_path.display(),
+            error,
+            recovery.recovered,
+            recovery.truncated,
+            backup_path.display()
+        ),
+        Err(e) => {
+            eprintln!("Failed to recover {}: {}", db_path.display(), e);
+            warn_load_failure(tracker, db_path, error);
+        }
+    }
+    Ok(())
+}
+
+/// Cuts off the store at the first entry that can't be read (see [`KeychainStore::recover`]),
+/// copying it to `<db_path>.<unix time>.bak` first.
+///
+/// Unlike the tail [`load_or_recover`] cuts off, the entries after a damaged one may well be
+/// intact so they are only removed when asked to. They are still in the copy.
+pub fn run_recover_db_cmd<K, P>(
+    store: &mut KeychainStore<K, P>,
+    db_path: &Path,
+) -> Result<RecoveryReport>
+where
+    K: Clone + Ord + Debug,
+    P: PositionSchema,
+    KeychainChangeSet<K, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    let backup_path = recovery_backup_path(db_path);
+    let recovery = store.recover(&backup_path)?;
+    Ok(RecoveryReport {
+        db_path: db_path.to_path_buf(),
+        backup_path: (recovery.truncated > 0).then_some(backup_path),
+        recovered: recovery.recovered,
+        truncated: recovery.truncated,
+    })
+}
+
+fn recovery_backup_path(db_path: &Path) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    PathBuf::from(format!("{}.{}.bak", db_path.display(), now))
+}
+
+/// Checks that the entries of a hash chained store still lead to `expected` (a head hash printed
+/// earlier) and prints the current head hash to keep in its place.
+///
+/// The store is left positioned at its end, where the next changeset goes.
+pub fn check_head_hash<K, P>(
+    store: &mut KeychainStore<K, P>,
+    db_path: &Path,
+    expected: Option<sha256::Hash>,
+) -> Result<()>
+where
+    K: Clone + Ord + Debug,
+    P: PositionSchema,
+    KeychainChangeSet<K, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    if let Some(expected) = expected {
+        match store.find_head(expected)? {
+            Some(entries) => {
+                eprintln!(
+                    "The first {} entries of the database are unchanged",
+                    entries
+                )
+            }
+            None => {
+                return Err(anyhow!(
+                    "{} doesn't lead to the head hash {}: entries were changed since (or the \
+                     database was compacted)",
+                    db_path.display(),
+                    expected
+                ))
+            }
+        }
+    }
+    if let Some(head) = store.head_hash()? {
+        eprintln!("database head hash: {}", head);
+    }
+    Ok(())
+}
+
+fn warn_load_failure<K, P>(
+    tracker: &KeychainTracker<K, P>,
+    db_path: &Path,
+    error: impl core::fmt::Display,
+) where
+    K: Clone + Ord + Debug,
+    P: ChainPosition,
+{
+    match tracker.chain().latest_checkpoint() {
+        Some(checkpoint) => eprintln!(
+            "Failed to load all changesets from {}. Last checkpoint was at height {}. Error: {}",
+            db_path.display(),
+            checkpoint.height,
+            error
+        ),
+        None => eprintln!(
+            "Failed to load any checkpoints from {}: {}",
+            db_path.display(),
+            error
+        ),
+    }
+    eprintln!("⚠ Consider running a rescan of chain data.");
+}
+
+/// Builds the tracker of the wallet open in `store` without loading anything into it: its
+/// `keychains`, the descriptors it imported and the keychain its funds are being migrated to, if
+/// any.
+pub(crate) fn wallet_tracker<P>(
+    keychains: BTreeMap<Keychain, Descriptor<DescriptorPublicKey>>,
+    checkpoint_limit: Option<usize>,
+    store: &mut KeychainStore<Keychain, P>,
+) -> Result<KeychainTracker<Keychain, P>>
+where
+    P: PositionSchema,
+    KeychainChangeSet<Keychain, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    let mut tracker = build_tracker(keychains, checkpoint_limit);
+
+    // these keychains have to be there before the indices are loaded
+    let (mut extensions, _) = store.aggregate_extensions();
+    if let Some(data) = extensions.remove(IMPORTS_EXTENSION) {
+        add_imports(
+            &mut tracker,
+            &serde_json::from_slice::<Vec<ImportDescriptor>>(&data)?,
+        )?;
+    }
+    if let Some(data) = extensions.remove(MIGRATION_EXTENSION) {
+        let migration = serde_json::from_slice::<Migration>(&data)?;
+        let descriptor = Descriptor::<DescriptorPublicKey>::from_str(&migration.descriptor)?;
+        tracker
+            .txout_index
+            .add_keychain(Keychain::Migration, descriptor);
+    }
+    Ok(tracker)
+}
+
+/// Loads `wallet` (or the default wallet) of the store at `path` into a new tracker with the same
+/// keychains as `tracker`, failing if any entry can't be read.
+fn replay_store<P>(
+    tracker: &KeychainTracker<Keychain, P>,
+    path: &Path,
+    password: Option<&str>,
+    wallet: Option<&str>,
+) -> Result<KeychainTracker<Keychain, P>>
+where
+    P: PositionSchema,
+    KeychainChangeSet<Keychain, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    // opening the store creates it if it doesn't exist
+    if !path.exists() {
+        return Err(anyhow!("{} doesn't exist", path.display()));
+    }
+    let mut store = match password {
+        Some(password) => KeychainStore::new_encrypted_from_path(path, password.as_bytes())?,
+        None => KeychainStore::new_from_path(path)?,
+    };
+    if let Some(wallet) = wallet {
+        store = store.open_wallet(wallet);
+    }
+    let keychains = tracker
+        .txout_index
+        .keychains()
+        .iter()
+        .filter(|(keychain, _)| matches!(keychain, Keychain::External | Keychain::Internal))
+        .map(|(keychain, descriptor)| (*keychain, descriptor.clone()))
+        .collect();
+    let mut replayed = wallet_tracker(keychains, tracker.checkpoint_limit(), &mut store)?;
+    store
+        .load_into_keychain_tracker(&mut replayed)
+        .map_err(|e| anyhow!("failed to load {}: {}", path.display(), e))?;
+    Ok(replayed)
+}
+
+/// What differs between the states of two trackers of the same wallet, if anything.
+fn state_difference<P: ChainPosition>(
+    tracker: &KeychainTracker<Keychain, P>,
+    other: &KeychainTracker<Keychain, P>,
+) -> Option<&'static str> {
+    if tracker.chain().checkpoints() != other.chain().checkpoints() {
+        Some("checkpoints")
+    } else if tracker.chain_graph() != other.chain_graph() {
+        Some("transactions")
+    } else if tracker.txout_index.derivation_indices() != other.txout_index.derivation_indices() {
+        Some("derivation indices")
+    } else {
+        None
+    }
+}
+
+/// Backs up the store to `backup_path` (see [`KeychainStore::backup`]) and checks that the
+/// backup loads into the same state as `tracker`, which has to hold everything in the store.
+pub fn run_backup_cmd<P>(
+    tracker: &KeychainTracker<Keychain, P>,
+    store: &mut KeychainStore<Keychain, P>,
+    backup_path: &Path,
+    password: Option<&str>,
+) -> Result<BackupReport>
+where
+    P: PositionSchema,
+    KeychainChangeSet<Keychain, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    let entries = store.backup(backup_path)?;
+    let replayed = replay_store(tracker, backup_path, password, store.wallet())?;
+    if let Some(difference) = state_difference(tracker, &replayed) {
+        return Err(anyhow!(
+            "the {} loaded from {} don't match the wallet's",
+            difference,
+            backup_path.display()
+        ));
+    }
+
+    Ok(BackupReport {
+        entries,
+        path: backup_path.to_path_buf(),
+        head_hash: store.head_hash()?.map(|head| head.to_string()),
+    })
+}
+
+/// Replaces the store at `db_path` with the backup at `backup_path` once every entry of the
+/// backup has been read and loaded. The store is kept at `<db_path>.<unix time>.bak` (which must
+/// not exist yet, e.g. because `recover-db` was run in the same second) and the backup is copied
+/// next to it before being renamed over it, so `db_path` is either the store or the backup if the
+/// copy is cut short.
+///
+/// Afterwards the store at `db_path` is loaded again to check it holds the same state as the
+/// backup. `tracker` is only used for the wallet's keychains.
+pub fn run_restore_db_cmd<P>(
+    tracker: &KeychainTracker<Keychain, P>,
+    db_path: &Path,
+    backup_path: &Path,
+    password: Option<&str>,
+    wallet: Option<&str>,
+) -> Result<RestoreReport>
+where
+    P: PositionSchema,
+    KeychainChangeSet<Keychain, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    let backup = replay_store(tracker, backup_path, password, wallet)?;
+    let contents = std::fs::read(backup_path)?;
+
+    let kept_path = recovery_backup_path(db_path);
+    let mut kept = match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&kept_path)
+    {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(anyhow!(
+                "{} already exists, try again in a second",
+                kept_path.display()
+            ))
+        }
+        result => result?,
+    };
+    let copied = (|| {
+        std::io::copy(&mut std::fs::File::open(db_path)?, &mut kept)?;
+        kept.sync_all()
+    })();
+    if let Err(e) = copied {
+        let _ = std::fs::remove_file(&kept_path);
+        return Err(e.into());
+    }
+
+    // a temporary file left behind by a restore that was cut short is written over
+    let tmp_path = PathBuf::from(format!("{}.restore", db_path.display()));
+    let replaced = (|| {
+        let mut tmp = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        std::io::Write::write_all(&mut tmp, &contents)?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, db_path)
+    })();
+    if let Err(e) = replaced {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+
+    let restored = replay_store(tracker, db_path, password, wallet)?;
+    if let Some(difference) = state_difference(&backup, &restored) {
+        return Err(anyhow!(
+            "the {} of the restored database don't match the backup's, the previous database was \
+             kept at {}",
+            difference,
+            kept_path.display()
+        ));
+    }
+    Ok(RestoreReport {
+        db_path: db_path.to_path_buf(),
+        backup_path: backup_path.to_path_buf(),
+        kept_path,
+        last_checkpoint: restored
+            .chain()
+            .latest_checkpoint()
+            .map(|checkpoint| checkpoint.height),
+    })
+}
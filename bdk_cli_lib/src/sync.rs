@@ -0,0 +1,142 @@
+//! Deciding which scripts to sync first.
+use crate::Keychain;
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::Script,
+    keychain::KeychainTracker,
+    sparse_chain::{ChainPosition, SparseChain},
+    BlockId, TxHeight,
+};
+use std::{collections::BTreeMap, fmt::Debug};
+
+/// How far a chain source got the last time the wallet was synced with it.
+///
+/// This is saved as an extension blob in the store (under the chain source's name) so that the
+/// next run knows where the previous one left off.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct SyncCursor {
+    /// The tip of the chain source at the time of the sync
+    pub tip: Option<BlockId>,
+    /// The last script index that was scanned in each keychain
+    pub scanned: Vec<(Keychain, u32)>,
+}
+
+impl SyncCursor {
+    /// The index the scan of each keychain can resume from, i.e. the one after the last index
+    /// the cursor has seen used. Scripts before it are left to `sync`.
+    ///
+    /// Nothing can be skipped (so the map is empty) if the tip the cursor was made at is no longer
+    /// in `chain`, since the transactions found under it may have been reorganized away.
+    pub fn resume_from<P: ChainPosition>(&self, chain: &SparseChain<P>) -> BTreeMap<Keychain, u32> {
+        match self.tip {
+            Some(tip) if chain.checkpoint_at(tip.height) == Some(tip) => self
+                .scanned
+                .iter()
+                .map(|(keychain, index)| (*keychain, index + 1))
+                .collect(),
+            _ => BTreeMap::new(),
+        }
+    }
+
+    /// Moves the cursor to `tip` after a scan that found `last_active` to be the last used index
+    /// of each keychain. A keychain the scan found nothing new in keeps its index.
+    pub fn advance(&mut self, tip: Option<BlockId>, last_active: &BTreeMap<Keychain, u32>) {
+        self.tip = tip;
+        let mut scanned = self.scanned.iter().copied().collect::<BTreeMap<_, _>>();
+        for (keychain, index) in last_active {
+            let scanned = scanned.entry(*keychain).or_insert(*index);
+            *scanned = (*scanned).max(*index);
+        }
+        self.scanned = scanned.into_iter().collect();
+    }
+}
+
+/// What the wallet knows about the use of a script, which a [`ScriptRanking`] orders scripts by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScriptActivity {
+    /// The height of the latest transaction paying the script, `None` if none has
+    pub last_used: Option<TxHeight>,
+    /// The value of the script's unspent outputs
+    pub unspent_value: u64,
+}
+
+/// The [`ScriptActivity`] of each script of the tracker's keychains that has received coins.
+pub fn script_activity<P: ChainPosition>(
+    tracker: &KeychainTracker<Keychain, P>,
+) -> BTreeMap<Script, ScriptActivity> {
+    let mut activity = BTreeMap::<Script, ScriptActivity>::new();
+    for (_, full_txout) in tracker.full_txouts() {
+        let script_activity = activity
+            .entry(full_txout.txout.script_pubkey.clone())
+            .or_default();
+        script_activity.last_used = script_activity
+            .last_used
+            .max(Some(full_txout.chain_position.height()));
+        if full_txout.spent_by.is_none() {
+            script_activity.unspent_value += full_txout.txout.value;
+        }
+    }
+    activity
+}
+
+/// Decides which scripts a sync asks the chain source about first, so that in a long sync the
+/// updates that matter most arrive early. See [`ScriptOrder`] for the built in rankings.
+pub trait ScriptRanking {
+    /// How the script with activity `a` ranks against the one with `b`. Scripts ranking
+    /// [`Less`](core::cmp::Ordering::Less) are synced first and equal ones keep their order.
+    fn compare(&self, a: &ScriptActivity, b: &ScriptActivity) -> core::cmp::Ordering;
+}
+
+/// The orders `sync` can request the wallet's scripts in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScriptOrder {
+    /// By keychain and index (the order they are gathered in)
+    #[default]
+    Untouched,
+    /// The scripts with the latest transactions first (unconfirmed ones before confirmed ones)
+    RecentlyUsed,
+    /// The scripts holding the most value first
+    HighestValue,
+}
+
+impl ScriptRanking for ScriptOrder {
+    fn compare(&self, a: &ScriptActivity, b: &ScriptActivity) -> core::cmp::Ordering {
+        match self {
+            ScriptOrder::Untouched => core::cmp::Ordering::Equal,
+            ScriptOrder::RecentlyUsed => b.last_used.cmp(&a.last_used),
+            ScriptOrder::HighestValue => b
+                .unspent_value
+                .cmp(&a.unspent_value)
+                .then(b.last_used.cmp(&a.last_used)),
+        }
+    }
+}
+
+impl core::str::FromStr for ScriptOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "untouched" => ScriptOrder::Untouched,
+            "recent" => ScriptOrder::RecentlyUsed,
+            "value" => ScriptOrder::HighestValue,
+            unknown => return Err(anyhow!("unknown script order '{}'", unknown)),
+        })
+    }
+}
+
+/// Sorts `scripts` (each along with some data of the caller's) by their [`ScriptActivity`] in
+/// `activity` according to `ranking`. Scripts without activity have never been used.
+pub fn rank_scripts<T>(
+    scripts: &mut [(Script, T)],
+    activity: &BTreeMap<Script, ScriptActivity>,
+    ranking: &dyn ScriptRanking,
+) {
+    let unused = ScriptActivity::default();
+    scripts.sort_by(|(a, _), (b, _)| {
+        ranking.compare(
+            activity.get(a).unwrap_or(&unused),
+            activity.get(b).unwrap_or(&unused),
+        )
+    });
+}
@@ -0,0 +1,970 @@
+//! The options transactions are built with.
+use crate::{EstimateFee, Keychain};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{
+        hashes::{hash160, hex::FromHex, ripemd160, sha256, sha256d, Hash},
+        util::psbt,
+        Address, LockTime, Network, OutPoint, SchnorrSighashType, Script, Sequence, TxOut,
+    },
+    cancel::CancellationToken,
+    keychain::{ConfirmationPolicy, KeychainTracker},
+    miniscript::{hash256, Descriptor, DescriptorPublicKey},
+    standardness::MAX_OP_RETURN_RELAY,
+};
+use bdk_coin_select::sat_per_vb_to_wu;
+use rand::seq::SliceRandom;
+use std::{collections::BTreeMap, fmt::Debug, path::PathBuf, str::FromStr};
+
+/// Who a transaction pays to.
+#[derive(Clone, Debug)]
+pub enum Recipient {
+    Address(Address),
+    /// The next unused address of a counterparty's descriptor (see [`Counterparties`])
+    Descriptor(Box<Descriptor<DescriptorPublicKey>>),
+}
+
+impl core::str::FromStr for Recipient {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.strip_prefix("desc:") {
+            Some(descriptor) => Recipient::Descriptor(Box::new(Descriptor::from_str(descriptor)?)),
+            None => Recipient::Address(Address::from_str(s).map_err(|e| {
+                anyhow!(
+                    "'{}' isn't a base58 (P2PKH or P2SH) or bech32/bech32m (segwit) address: {}",
+                    s,
+                    e
+                )
+            })?),
+        })
+    }
+}
+
+/// A payment of `value` sats to `recipient`, written as `<recipient>:<value>`.
+#[derive(Clone, Debug)]
+pub struct Payment {
+    pub recipient: Recipient,
+    pub value: u64,
+}
+
+impl core::str::FromStr for Payment {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a descriptor may contain colons itself so the value comes after the last one
+        let (recipient, value) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("expected <recipient>:<value> but got '{}'", s))?;
+        Ok(Payment {
+            recipient: recipient.parse()?,
+            value: value.parse()?,
+        })
+    }
+}
+
+/// Data embedded in a transaction with an `OP_RETURN` output, written in hex.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpReturnData(pub Vec<u8>);
+
+impl OpReturnData {
+    /// The output carrying the data. It is worth nothing since it can never be spent, which also
+    /// exempts it from the dust limit.
+    pub fn txout(&self) -> TxOut {
+        TxOut {
+            value: 0,
+            script_pubkey: Script::new_op_return(&self.0),
+        }
+    }
+}
+
+impl core::str::FromStr for OpReturnData {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let data = OpReturnData(Vec::<u8>::from_hex(s)?);
+        let size = data.txout().script_pubkey.len();
+        if size > MAX_OP_RETURN_RELAY {
+            return Err(anyhow!(
+                "{} bytes of data make a {} byte OP_RETURN script but at most {} bytes is relayed",
+                data.0.len(),
+                size,
+                MAX_OP_RETURN_RELAY
+            ));
+        }
+        Ok(data)
+    }
+}
+
+/// A feerate given on the command line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeerateArg {
+    /// A feerate in sats per vbyte
+    SatPerVb(f32),
+    /// The feerate the chain source estimates gets a transaction confirmed within this many
+    /// blocks (written `auto:<blocks>`)
+    Auto(usize),
+}
+
+impl FeerateArg {
+    /// The feerate in sats per vbyte, estimated by `client` if it is [`FeerateArg::Auto`].
+    pub fn resolve(self, client: &mut impl EstimateFee) -> Result<f32> {
+        match self {
+            FeerateArg::SatPerVb(feerate) => Ok(feerate),
+            FeerateArg::Auto(target_blocks) => {
+                let feerate = client.estimate_fee(target_blocks)?;
+                eprintln!(
+                    "Using an estimated feerate of {:.2} sats/vbyte to confirm within {} blocks",
+                    feerate, target_blocks
+                );
+                Ok(feerate)
+            }
+        }
+    }
+}
+
+impl core::str::FromStr for FeerateArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("auto:") {
+            Some(target_blocks) => match target_blocks.parse()? {
+                0 => Err(anyhow!("the target has to be at least one block")),
+                target_blocks => Ok(FeerateArg::Auto(target_blocks)),
+            },
+            None => Ok(FeerateArg::SatPerVb(s.parse()?)),
+        }
+    }
+}
+
+/// The name of the extension blob [`Counterparties`] are saved under.
+pub const COUNTERPARTIES_EXTENSION: &str = "counterparties";
+
+/// The descriptors we have paid to and the next derivation index to pay each of them at.
+///
+/// This lets us pay a counterparty over and over again without asking them for a new address
+/// each time.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Counterparties {
+    /// The next derivation index of each descriptor (keyed by its string form)
+    pub next_indices: BTreeMap<String, u32>,
+}
+
+impl Counterparties {
+    /// The next address of `descriptor` which is then considered used.
+    ///
+    /// Descriptors without a wildcard always give the same address.
+    pub fn next_address(
+        &mut self,
+        descriptor: &Descriptor<DescriptorPublicKey>,
+        network: Network,
+    ) -> Result<Address> {
+        let next_index = self.next_indices.entry(descriptor.to_string()).or_default();
+        let address = descriptor
+            .at_derivation_index(*next_index)
+            .address(network)?;
+        if descriptor.has_wildcard() {
+            *next_index += 1;
+        }
+        Ok(address)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum CoinSelectionAlgo {
+    LargestFirst,
+    SmallestFirst,
+    OldestFirst,
+    NewestFirst,
+    /// Spend the coins that would destroy the most coin days first (gets rid of old coins)
+    MostCoinDaysFirst,
+    /// Spend the coins that would destroy the fewest coin days first (keeps old coins)
+    FewestCoinDaysFirst,
+    BranchAndBound,
+}
+
+impl Default for CoinSelectionAlgo {
+    fn default() -> Self {
+        Self::LargestFirst
+    }
+}
+
+impl core::str::FromStr for CoinSelectionAlgo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use CoinSelectionAlgo::*;
+        Ok(match s {
+            "largest-first" => LargestFirst,
+            "smallest-first" => SmallestFirst,
+            "oldest-first" => OldestFirst,
+            "newest-first" => NewestFirst,
+            "most-coin-days-first" => MostCoinDaysFirst,
+            "fewest-coin-days-first" => FewestCoinDaysFirst,
+            "bnb" => BranchAndBound,
+            unknown => return Err(anyhow!("unknown coin selection algorithm '{}'", unknown)),
+        })
+    }
+}
+
+impl core::fmt::Display for CoinSelectionAlgo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use CoinSelectionAlgo::*;
+        write!(
+            f,
+            "{}",
+            match self {
+                LargestFirst => "largest-first",
+                SmallestFirst => "smallest-first",
+                OldestFirst => "oldest-first",
+                NewestFirst => "newest-first",
+                MostCoinDaysFirst => "most-coin-days-first",
+                FewestCoinDaysFirst => "fewest-coin-days-first",
+                BranchAndBound => "bnb",
+            }
+        )
+    }
+}
+
+/// How [`create_psbt`](crate::create_psbt) orders the inputs and outputs of a transaction.
+///
+/// Without any ordering the change output would always come last, giving away which output is
+/// the payment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OrderingStrategy {
+    /// Put the inputs and the outputs in a random order
+    #[default]
+    Shuffle,
+    /// Sort them as described in BIP 69: inputs by the txid (as it is displayed) and vout of the
+    /// coin they spend, outputs by value and then script pubkey
+    Bip69,
+    /// Keep the inputs in the order they were selected and the outputs in the order they were
+    /// given, followed by the change
+    Untouched,
+}
+
+impl OrderingStrategy {
+    /// Orders `inputs` by the coins they spend, shuffling them with `rng`.
+    pub fn sort_inputs<T>(
+        &self,
+        inputs: &mut [T],
+        outpoint: impl Fn(&T) -> OutPoint,
+        rng: &mut impl rand::Rng,
+    ) {
+        match self {
+            OrderingStrategy::Shuffle => inputs.shuffle(rng),
+            OrderingStrategy::Bip69 => inputs.sort_by_cached_key(|input| {
+                let outpoint = outpoint(input);
+                // a txid is displayed in reverse byte order
+                let mut txid = outpoint.txid.into_inner();
+                txid.reverse();
+                (txid, outpoint.vout)
+            }),
+            OrderingStrategy::Untouched => {}
+        }
+    }
+
+    /// Orders `outputs`, shuffling them with `rng`.
+    pub fn sort_outputs<T>(
+        &self,
+        outputs: &mut [T],
+        txout: impl Fn(&T) -> &TxOut,
+        rng: &mut impl rand::Rng,
+    ) {
+        match self {
+            OrderingStrategy::Shuffle => outputs.shuffle(rng),
+            OrderingStrategy::Bip69 => outputs.sort_by(|a, b| {
+                let (a, b) = (txout(a), txout(b));
+                (a.value, a.script_pubkey.as_bytes()).cmp(&(b.value, b.script_pubkey.as_bytes()))
+            }),
+            OrderingStrategy::Untouched => {}
+        }
+    }
+}
+
+impl core::str::FromStr for OrderingStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "shuffle" => OrderingStrategy::Shuffle,
+            "bip69" => OrderingStrategy::Bip69,
+            "untouched" => OrderingStrategy::Untouched,
+            unknown => return Err(anyhow!("unknown ordering strategy '{}'", unknown)),
+        })
+    }
+}
+
+impl core::fmt::Display for OrderingStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrderingStrategy::Shuffle => write!(f, "shuffle"),
+            OrderingStrategy::Bip69 => write!(f, "bip69"),
+            OrderingStrategy::Untouched => write!(f, "untouched"),
+        }
+    }
+}
+
+/// Which keychain receives the change of a transaction.
+///
+/// The first of `keychains` that the tracker has is used so later entries act as fallbacks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangePolicy {
+    /// The keychains to send change to in order of preference
+    pub keychains: Vec<Keychain>,
+}
+
+impl Default for ChangePolicy {
+    fn default() -> Self {
+        Self {
+            keychains: vec![Keychain::Internal, Keychain::External],
+        }
+    }
+}
+
+impl ChangePolicy {
+    /// The keychain change should go to. `None` if the tracker has none of the keychains.
+    pub fn change_keychain<P>(&self, tracker: &KeychainTracker<Keychain, P>) -> Option<Keychain> {
+        let keychains = tracker.txout_index.keychains();
+        self.keychains
+            .iter()
+            .find(|keychain| keychains.contains_key(keychain))
+            .copied()
+    }
+}
+
+/// How [`create_tx`](crate::create_tx) sets the locktime to discourage fee sniping.
+///
+/// Setting the locktime to the current height means a miner reorging the tip can't include the
+/// transaction in the replacement block. Like bitcoind, the locktime is occasionally moved back a
+/// random number of blocks so transactions that are slow to propagate don't stand out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AntiFeeSniping {
+    /// Whether to set the locktime to the tip at all. When disabled the locktime only satisfies
+    /// the `after` timelocks of the spent outputs.
+    pub enabled: bool,
+    /// The locktime is moved back with a chance of one in `back_off_chance` (never if it is zero)
+    pub back_off_chance: u32,
+    /// The maximum number of blocks the locktime is moved back by
+    pub max_back_off: u32,
+}
+
+impl Default for AntiFeeSniping {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            back_off_chance: 10,
+            max_back_off: 100,
+        }
+    }
+}
+
+impl AntiFeeSniping {
+    /// Picks the locktime of a transaction created on top of `tip_height` whose inputs require
+    /// the `required` locktimes.
+    ///
+    /// A required locktime that is later than the anti fee sniping height (or is time based and
+    /// so can't be combined with it) takes precedence. A transaction can only have one unit of
+    /// locktime so it's an error for `required` to mix heights and times.
+    pub fn locktime(
+        &self,
+        tip_height: Option<u32>,
+        required: impl IntoIterator<Item = LockTime>,
+        rng: &mut impl rand::Rng,
+    ) -> Result<LockTime, MixedLocktimes> {
+        let mut height = match tip_height {
+            Some(height) if self.enabled => height,
+            _ => 0,
+        };
+        if height > 0 && self.back_off_chance > 0 && rng.gen_range(0..self.back_off_chance) == 0 {
+            height = height.saturating_sub(rng.gen_range(0..self.max_back_off.max(1)));
+        }
+        let anti_fee_sniping = LockTime::from_height(height).unwrap_or(LockTime::ZERO);
+
+        let mut latest_required: Option<LockTime> = None;
+        for required in required {
+            latest_required = Some(match latest_required {
+                Some(other) if !other.is_same_unit(required) => {
+                    let (height, time) = if required.is_block_height() {
+                        (required, other)
+                    } else {
+                        (other, required)
+                    };
+                    return Err(MixedLocktimes { height, time });
+                }
+                Some(other) if other.to_consensus_u32() >= required.to_consensus_u32() => other,
+                _ => required,
+            });
+        }
+        Ok(match latest_required {
+            Some(required)
+                if !required.is_same_unit(anti_fee_sniping)
+                    || required.to_consensus_u32() > anti_fee_sniping.to_consensus_u32() =>
+            {
+                required
+            }
+            _ => anti_fee_sniping,
+        })
+    }
+}
+
+/// The coins being spent require both a height based and a time based locktime, which no
+/// transaction can satisfy at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MixedLocktimes {
+    pub height: LockTime,
+    pub time: LockTime,
+}
+
+impl core::fmt::Display for MixedLocktimes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "the spent coins require a locktime of both {:#} and {:#} but a transaction can only have one",
+            self.height, self.time
+        )
+    }
+}
+
+impl std::error::Error for MixedLocktimes {}
+
+/// The feerate (in sats per weight unit) transactions pay when [`TxBuilder::feerate`] isn't set.
+pub const DEFAULT_FEERATE: f32 = 0.5;
+
+/// The options [`create_tx`](crate::create_tx) builds a transaction with.
+#[derive(Clone, Debug, Default)]
+pub struct TxBuilder {
+    pub coin_select: CoinSelectionAlgo,
+    pub change_policy: ChangePolicy,
+    pub anti_fee_sniping: AntiFeeSniping,
+    /// Coins received on untrusted keychains are only spent once they meet this policy (see
+    /// [`Keychain::is_trusted`])
+    pub confirmation_policy: ConfirmationPolicy,
+    /// The feerate in sats per weight unit ([`DEFAULT_FEERATE`] if `None`). It has to be at
+    /// least the minimum relay feerate of [`StandardnessPolicy`].
+    ///
+    /// [`StandardnessPolicy`]: bdk_chain::standardness::StandardnessPolicy
+    pub feerate: Option<f32>,
+    /// Coins that have to be spent. The fee also makes up for the unconfirmed transactions they
+    /// come from so the package of those and the new transaction reaches the feerate (see
+    /// [`unpaid_ancestor_weight`](crate::unpaid_ancestor_weight)).
+    pub must_spend: Vec<OutPoint>,
+    /// Spend no coins besides [`must_spend`](Self::must_spend)
+    pub only_must_spend: bool,
+    /// Coins that must not be spent
+    pub exclude: Vec<OutPoint>,
+    /// Also spend unconfirmed coins that meet the [`confirmation_policy`], e.g. our own change.
+    /// Coins in [`must_spend`] are spent whether they are confirmed or not.
+    ///
+    /// Like with [`must_spend`], the fee makes up for the unconfirmed ancestors of the coins that
+    /// are picked. Two picked coins from the same transaction both pay for its ancestors, which
+    /// overpays but never underpays.
+    ///
+    /// [`confirmation_policy`]: Self::confirmation_policy
+    /// [`must_spend`]: Self::must_spend
+    pub spend_unconfirmed: bool,
+    /// Spend every coin we can (or only [`must_spend`] if it isn't empty) and send what is left
+    /// after paying the outputs and the fee to this script rather than to a change output
+    ///
+    /// [`must_spend`]: Self::must_spend
+    pub drain_to: Option<Script>,
+    /// The locktime of the transaction instead of the one [`anti_fee_sniping`] picks. It has to
+    /// satisfy the `after` timelocks of the spending paths of the coins.
+    ///
+    /// [`anti_fee_sniping`]: Self::anti_fee_sniping
+    pub lock_time: Option<LockTime>,
+    /// The sequence of the inputs spending these coins, which have to be in
+    /// [`must_spend`](Self::must_spend). The sequence of an input must satisfy the `older`
+    /// timelock of its spending path, and must not be final if the path has an `after` timelock
+    /// since that would disable the locktime.
+    pub sequences: BTreeMap<OutPoint, Sequence>,
+    /// The indices of the outputs the fee is taken from (like bitcoind's
+    /// `subtractfeefromamount`). The coins then only have to pay for the outputs, and the fee is
+    /// split between these outputs in proportion to their values. Can't be used with
+    /// [`drain_to`](Self::drain_to), which already pays the fee out of what it gets.
+    pub subtract_fee_from: Vec<usize>,
+    /// Don't signal that the transaction can be replaced (BIP 125). Inputs get a sequence that
+    /// leaves the locktime enabled without signalling, and it is an error for a sequence (given
+    /// or required by a relative timelock) to signal.
+    pub disable_rbf: bool,
+    /// The height or time `after` timelocks are satisfied up to instead of the latest checkpoint,
+    /// e.g. to spend a time locked branch (which the chain's heights can't unlock) or when the
+    /// wallet's chain is behind
+    pub max_locktime: Option<LockTime>,
+    /// How long the confirmed coins are taken to have been confirmed for, which the `older`
+    /// timelocks in units of 512 seconds are checked against since they need the median time past
+    /// of the blocks. It has to be time based (see [`Sequence::from_seconds_floor`]), height based
+    /// `older` timelocks are always checked against the confirmations of each coin.
+    pub txo_age: Option<Sequence>,
+    /// How the inputs and outputs are ordered once the coins have been selected
+    pub ordering: OrderingStrategy,
+    /// The value in sats below which change is dust instead of the dust value of the change
+    /// descriptor. It doesn't apply to [`drain_to`](Self::drain_to), which only has to be above
+    /// the dust threshold of its script.
+    pub dust_limit: Option<u64>,
+    /// What to do when the change would be dust
+    pub dust_change: DustChange,
+    /// The sighash type the inputs are signed with, recorded in the PSBT (`SIGHASH_DEFAULT` if
+    /// `None`). Anything but `SIGHASH_ALL` or `SIGHASH_DEFAULT` leaves parts of the transaction
+    /// uncommitted, e.g. `SIGHASH_ALL|SIGHASH_ANYONECANPAY` lets others add inputs to bump the
+    /// fee. `SIGHASH_SINGLE` commits to the output at the index of the input, so it needs
+    /// [`OrderingStrategy::Untouched`].
+    pub sighash_type: Option<SchnorrSighashType>,
+    /// The sighash type of the inputs spending these coins instead of
+    /// [`sighash_type`](Self::sighash_type). They have to be in [`must_spend`](Self::must_spend).
+    pub input_sighash_types: BTreeMap<OutPoint, SchnorrSighashType>,
+    /// Pre-images unlocking the hash locks (e.g. `sha256(H)`) in the descriptors of the coins.
+    /// Coins can be spent with the branches they unlock, and the inputs that need them carry
+    /// them in the PSBT for [`finalize_psbt`](crate::finalize_psbt).
+    pub preimages: Preimages,
+    /// Seeds the randomness of [`OrderingStrategy::Shuffle`] and [`AntiFeeSniping`] so the same
+    /// coins and options always make the same transaction, e.g. for test vectors. Signatures are
+    /// deterministic already. Left `None`, fresh entropy is used every time, which is what keeps
+    /// the order and locktime from fingerprinting the wallet.
+    pub rng_seed: Option<u64>,
+    /// Stops the branch and bound coin selection early once cancelled, which then goes with the
+    /// best selection it has found (if any)
+    pub cancel: CancellationToken,
+}
+
+impl TxBuilder {
+    /// The sighash type the input spending `outpoint` is to be signed with, if one is given.
+    pub fn sighash_type_of(&self, outpoint: OutPoint) -> Option<SchnorrSighashType> {
+        self.input_sighash_types
+            .get(&outpoint)
+            .or(self.sighash_type.as_ref())
+            .copied()
+    }
+}
+
+/// What [`create_psbt`] does when the change of a transaction would be below the dust limit (see
+/// [`TxBuilder::dust_limit`]).
+///
+/// [`create_psbt`]: crate::create_psbt
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DustChange {
+    /// Leave the change out and pay it to the fee. Change above the dust limit may be paid to
+    /// the fee too when it costs more to create and spend than it is worth.
+    #[default]
+    AddToFee,
+    /// Always add change when anything is left over, however little. Nodes don't relay a
+    /// transaction with change below the dust threshold of its script though.
+    Include,
+    /// Fail instead of paying what is left over to the fee. Change above the dust limit is
+    /// always added.
+    Fail,
+}
+
+impl core::str::FromStr for DustChange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "add-to-fee" => DustChange::AddToFee,
+            "include" => DustChange::Include,
+            "fail" => DustChange::Fail,
+            unknown => return Err(anyhow!("unknown way to handle dust change '{}'", unknown)),
+        })
+    }
+}
+
+impl core::fmt::Display for DustChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DustChange::AddToFee => write!(f, "add-to-fee"),
+            DustChange::Include => write!(f, "include"),
+            DustChange::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// The sequence of the input spending a coin, written as `<txid>:<vout>=<sequence>` where the
+/// sequence is a number or hex prefixed with `0x`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputSequence {
+    pub outpoint: OutPoint,
+    pub sequence: Sequence,
+}
+
+impl core::str::FromStr for InputSequence {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (outpoint, sequence) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected <txid>:<vout>=<sequence> but got '{}'", s))?;
+        Ok(InputSequence {
+            outpoint: outpoint.parse()?,
+            sequence: parse_sequence(sequence)?,
+        })
+    }
+}
+
+/// Parses a sequence written as a number or in hex prefixed with `0x`.
+pub fn parse_sequence(s: &str) -> Result<Sequence> {
+    let sequence = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16)?,
+        None => s.parse()?,
+    };
+    Ok(Sequence::from_consensus(sequence))
+}
+
+/// Parses an age in seconds into the time based relative timelock it satisfies.
+pub fn parse_txo_age(s: &str) -> Result<Sequence> {
+    let seconds = s.parse::<u32>()?;
+    Sequence::from_seconds_floor(seconds).map_err(|_| {
+        anyhow!(
+            "{} seconds is longer than a relative timelock can be",
+            seconds
+        )
+    })
+}
+
+/// The locktime and sequences of a transaction, which otherwise discourage fee sniping and
+/// signal replaceability, and the timelocks its coins can be spent with.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct TimelockArgs {
+    /// The locktime of the transaction, a height or (from 500000000 on) a unix time. Defaults to
+    /// about the current height.
+    #[clap(long)]
+    pub locktime: Option<u32>,
+    /// The sequence of the input spending a coin as `<txid>:<vout>=<sequence>`. The coin has to
+    /// be picked with `--utxo` too. Can be given more than once.
+    #[clap(long = "sequence")]
+    pub sequences: Vec<InputSequence>,
+    /// Don't signal that the transaction can be replaced by one paying a higher fee (BIP 125)
+    #[clap(long)]
+    pub no_rbf: bool,
+    /// Spend coins whose `after` timelocks are up to this height or (from 500000000 on) unix
+    /// time. Defaults to the latest checkpoint, which can't unlock time based timelocks.
+    #[clap(long)]
+    pub max_locktime: Option<u32>,
+    /// How many seconds (of median time past) the confirmed coins have been confirmed for, to
+    /// spend branches with `older` timelocks in units of 512 seconds. Height based ones are
+    /// checked against the confirmations of each coin.
+    #[clap(long, value_parser = parse_txo_age)]
+    pub txo_age: Option<Sequence>,
+}
+
+impl TimelockArgs {
+    /// Sets the options of `builder` that were given.
+    pub fn apply_to(self, builder: &mut TxBuilder) {
+        if let Some(lock_time) = self.locktime {
+            builder.lock_time = Some(LockTime::from_consensus(lock_time));
+        }
+        builder.sequences.extend(
+            self.sequences
+                .into_iter()
+                .map(|InputSequence { outpoint, sequence }| (outpoint, sequence)),
+        );
+        builder.disable_rbf |= self.no_rbf;
+        if let Some(max_locktime) = self.max_locktime {
+            builder.max_locktime = Some(LockTime::from_consensus(max_locktime));
+        }
+        builder.txo_age = self.txo_age.or(builder.txo_age);
+    }
+}
+
+/// How small change can get and what happens when it would be smaller.
+#[derive(clap::Args, Clone, Copy, Debug, Default)]
+pub struct DustArgs {
+    /// The value in sats below which change is dust (defaults to the dust value of the change
+    /// keychain's descriptor)
+    #[clap(long)]
+    pub dust_limit: Option<u64>,
+    /// What to do when the change would be dust: `add-to-fee`, `include` it anyway or `fail`
+    #[clap(long, default_value = "add-to-fee")]
+    pub dust_change: DustChange,
+}
+
+impl DustArgs {
+    /// Sets the dust options of `builder`.
+    pub fn apply_to(self, builder: &mut TxBuilder) {
+        builder.dust_limit = self.dust_limit.or(builder.dust_limit);
+        builder.dust_change = self.dust_change;
+    }
+}
+
+/// The coins, feerate and extra output of a transaction that is sent right away (`send`,
+/// `send-many` and `drain`), and whether it is only shown instead.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct SpendArgs {
+    /// Spend this coin. Can be given more than once. No other coins are spent if it is.
+    #[clap(long = "utxo")]
+    pub utxos: Vec<OutPoint>,
+    /// Don't spend this coin. Can be given more than once.
+    #[clap(long = "exclude-utxo")]
+    pub exclude_utxos: Vec<OutPoint>,
+    /// The feerate in sats per vbyte (defaults to 2), or `auto:<blocks>` to ask the chain
+    /// source for the feerate that gets the transaction confirmed within that many blocks
+    #[clap(long)]
+    pub feerate: Option<FeerateArg>,
+    /// Also spend unconfirmed coins, paying enough fee for their unconfirmed ancestors to
+    /// reach the feerate too
+    #[clap(long)]
+    pub spend_unconfirmed: bool,
+    /// How to order the inputs and outputs: `shuffle`, `bip69` or `untouched`
+    #[clap(long, default_value = "shuffle")]
+    pub ordering: OrderingStrategy,
+    /// Hex data to embed in the transaction with an `OP_RETURN` output
+    #[clap(long)]
+    pub data: Option<OpReturnData>,
+    /// Pay addresses of another network than the wallet's
+    #[clap(long)]
+    pub allow_network_mismatch: bool,
+    /// Show the transaction without broadcasting it or storing anything
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+impl SpendArgs {
+    /// Sets the coins, feerate and ordering of `builder`, asking `client` for the feerate if it
+    /// is [`FeerateArg::Auto`]. The coins are added to those `builder` already has, e.g. the
+    /// frozen ones it excludes.
+    pub fn apply_to(&self, builder: &mut TxBuilder, client: &mut impl EstimateFee) -> Result<()> {
+        if let Some(feerate) = self.feerate {
+            builder.feerate = Some(sat_per_vb_to_wu(feerate.resolve(client)?));
+        }
+        builder.only_must_spend |= !self.utxos.is_empty();
+        builder.must_spend.extend(&self.utxos);
+        builder.exclude.extend(&self.exclude_utxos);
+        builder.spend_unconfirmed |= self.spend_unconfirmed;
+        builder.ordering = self.ordering;
+        Ok(())
+    }
+}
+
+/// The sighash type of an input, written as `<txid>:<vout>=<sighash type>` (see
+/// [`parse_sighash_type`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputSighashType {
+    pub outpoint: OutPoint,
+    pub sighash_type: SchnorrSighashType,
+}
+
+impl core::str::FromStr for InputSighashType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (outpoint, sighash_type) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected <txid>:<vout>=<sighash type> but got '{}'", s))?;
+        Ok(InputSighashType {
+            outpoint: outpoint.parse()?,
+            sighash_type: parse_sighash_type(sighash_type)?,
+        })
+    }
+}
+
+/// Parses a taproot sighash type like `SIGHASH_ALL|SIGHASH_ANYONECANPAY`. The `SIGHASH_` prefix
+/// can be left out and case doesn't matter, e.g. `all|anyonecanpay`.
+pub fn parse_sighash_type(s: &str) -> Result<SchnorrSighashType> {
+    let name = s
+        .split('|')
+        .map(|flag| {
+            let flag = flag.trim().to_uppercase();
+            match flag.starts_with("SIGHASH_") {
+                true => flag,
+                false => format!("SIGHASH_{}", flag),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("|");
+    SchnorrSighashType::from_str(&name).map_err(|_| anyhow!("unknown sighash type '{}'", s))
+}
+
+/// The sighash types the inputs of a transaction are signed with.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct SighashArgs {
+    /// Sign every input with this sighash type, e.g. `all|anyonecanpay` so others can add inputs
+    /// to bump the fee. `single` needs `--ordering untouched`.
+    #[clap(long, value_parser = parse_sighash_type)]
+    pub sighash: Option<SchnorrSighashType>,
+    /// The sighash type of the input spending a coin as `<txid>:<vout>=<sighash type>`. The coin
+    /// has to be picked with `--utxo` too. Can be given more than once.
+    #[clap(long = "input-sighash")]
+    pub input_sighashes: Vec<InputSighashType>,
+}
+
+impl SighashArgs {
+    /// Sets the sighash types of `builder` that were given.
+    pub fn apply_to(self, builder: &mut TxBuilder) {
+        builder.sighash_type = self.sighash.or(builder.sighash_type);
+        builder
+            .input_sighash_types
+            .extend(self.input_sighashes.into_iter().map(
+                |InputSighashType {
+                     outpoint,
+                     sighash_type,
+                 }| (outpoint, sighash_type),
+            ));
+    }
+}
+
+/// Hash pre-images under their hashes, with one map per hash function a descriptor can lock coins
+/// with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Preimages {
+    pub sha256: BTreeMap<sha256::Hash, Vec<u8>>,
+    pub hash256: BTreeMap<hash256::Hash, Vec<u8>>,
+    pub ripemd160: BTreeMap<ripemd160::Hash, Vec<u8>>,
+    pub hash160: BTreeMap<hash160::Hash, Vec<u8>>,
+}
+
+impl Preimages {
+    /// Adds `preimage` under each of its hashes, so that it unlocks any hash lock it is the
+    /// pre-image of.
+    pub fn insert(&mut self, preimage: Vec<u8>) {
+        self.sha256
+            .insert(sha256::Hash::hash(&preimage), preimage.clone());
+        self.hash256
+            .insert(hash256::Hash::hash(&preimage), preimage.clone());
+        self.ripemd160
+            .insert(ripemd160::Hash::hash(&preimage), preimage.clone());
+        self.hash160
+            .insert(hash160::Hash::hash(&preimage), preimage);
+    }
+
+    /// The pre-images in the BIP 174 fields of `input`.
+    pub fn of_input(input: &psbt::Input) -> Self {
+        Preimages {
+            sha256: input.sha256_preimages.clone(),
+            hash256: input
+                .hash256_preimages
+                .iter()
+                .map(|(hash, preimage)| {
+                    (
+                        hash256::Hash::from_inner(hash.into_inner()),
+                        preimage.clone(),
+                    )
+                })
+                .collect(),
+            ripemd160: input.ripemd160_preimages.clone(),
+            hash160: input.hash160_preimages.clone(),
+        }
+    }
+
+    /// Lets `assets` plan with the hash locks the pre-images unlock.
+    pub fn extend_assets(&self, assets: &mut bdk_tmp_plan::Assets<DescriptorPublicKey>) {
+        assets.sha256.extend(self.sha256.keys());
+        assets.hash256.extend(self.hash256.keys());
+        assets.ripemd160.extend(self.ripemd160.keys());
+        assets.hash160.extend(self.hash160.keys());
+    }
+
+    /// Puts the pre-images `plan` needs into the BIP 174 fields of `input`.
+    pub(crate) fn add_to_input(
+        &self,
+        input: &mut psbt::Input,
+        plan: &bdk_tmp_plan::Plan<DescriptorPublicKey>,
+    ) {
+        let requirements = plan.requirements();
+        for image in requirements.sha256_images {
+            if let Some(preimage) = self.sha256.get(&image) {
+                input.sha256_preimages.insert(image, preimage.clone());
+            }
+        }
+        for image in requirements.hash256_images {
+            if let Some(preimage) = self.hash256.get(&image) {
+                let image = sha256d::Hash::from_inner(image.into_inner());
+                input.hash256_preimages.insert(image, preimage.clone());
+            }
+        }
+        for image in requirements.ripemd160_images {
+            if let Some(preimage) = self.ripemd160.get(&image) {
+                input.ripemd160_preimages.insert(image, preimage.clone());
+            }
+        }
+        for image in requirements.hash160_images {
+            if let Some(preimage) = self.hash160.get(&image) {
+                input.hash160_preimages.insert(image, preimage.clone());
+            }
+        }
+    }
+
+    /// The pre-images as the material to complete a plan with.
+    pub(crate) fn to_satisfaction_material(&self) -> bdk_tmp_plan::SatisfactionMaterial {
+        bdk_tmp_plan::SatisfactionMaterial {
+            sha256_preimages: self.sha256.clone(),
+            hash256_preimages: self.hash256.clone(),
+            ripemd160_preimages: self.ripemd160.clone(),
+            hash160_preimages: self.hash160.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A hash pre-image written in hex. The hash locks of miniscript only accept 32 byte pre-images.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preimage(pub Vec<u8>);
+
+impl core::str::FromStr for Preimage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let preimage = Vec::<u8>::from_hex(s.trim())?;
+        if preimage.len() != 32 {
+            return Err(anyhow!(
+                "a pre-image has to be 32 bytes but this one is {} bytes",
+                preimage.len()
+            ));
+        }
+        Ok(Preimage(preimage))
+    }
+}
+
+/// The pre-images of the hash locks a transaction's coins are spent with.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct PreimageArgs {
+    /// A 32 byte pre-image in hex unlocking the `sha256`, `hash256`, `ripemd160` or `hash160`
+    /// locks of the coins' descriptors it is the pre-image of. Can be given more than once.
+    #[clap(long = "preimage")]
+    pub preimages: Vec<Preimage>,
+    /// A file with a pre-image in hex on each line, so they don't end up in the shell's history
+    #[clap(long)]
+    pub preimages_file: Option<PathBuf>,
+}
+
+impl PreimageArgs {
+    /// Adds the pre-images that were given (and those in the file) to `builder`.
+    pub fn apply_to(self, builder: &mut TxBuilder) -> Result<()> {
+        let mut preimages = self.preimages;
+        if let Some(path) = &self.preimages_file {
+            let contents = std::fs::read_to_string(path)?;
+            for (n, line) in contents.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let preimage = line
+                    .parse()
+                    .map_err(|e| anyhow!("line {} of {}: {}", n + 1, path.display(), e))?;
+                preimages.push(preimage);
+            }
+        }
+        for Preimage(preimage) in preimages {
+            builder.preimages.insert(preimage);
+        }
+        Ok(())
+    }
+}
+
+/// When coins received on the external and imported keychains can be counted and spent.
+#[derive(clap::Args, Clone, Copy, Debug, Default)]
+pub struct DepositPolicy {
+    /// The number of confirmations received coins need before they count as confirmed (and can be
+    /// spent)
+    #[clap(long, default_value = "0")]
+    pub deposit_confirmations: u32,
+    /// Received coins also need the block they are in to be one of the wallet's checkpoints
+    #[clap(long)]
+    pub deposit_checkpoint: bool,
+}
+
+impl From<DepositPolicy> for ConfirmationPolicy {
+    fn from(deposit_policy: DepositPolicy) -> Self {
+        ConfirmationPolicy {
+            untrusted_confirmations: deposit_policy.deposit_confirmations,
+            require_checkpoint: deposit_policy.deposit_checkpoint,
+        }
+    }
+}
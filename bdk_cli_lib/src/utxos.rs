@@ -0,0 +1,204 @@
+//! The coins of the wallet and whether they can be spent yet.
+use anyhow::Result;
+use bdk_chain::{
+    bitcoin::{LockTime, OutPoint, Sequence},
+    keychain::KeychainTracker,
+    miniscript::{
+        descriptor::{DefiniteDescriptorKey, DescriptorType},
+        Descriptor,
+    },
+    sparse_chain::ChainPosition,
+    FullTxOut,
+};
+use std::fmt::Debug;
+
+/// A UTXO of the wallet along with the details of how it was derived and how it can be spent.
+#[derive(Clone, Debug)]
+pub struct DescribedUtxo<K, AK, P> {
+    pub keychain: K,
+    pub index: u32,
+    /// The descriptor of `keychain` derived at `index`
+    pub descriptor: Descriptor<DefiniteDescriptorKey>,
+    pub spk_type: DescriptorType,
+    /// How we would spend the UTXO with the assets we have. `None` if we can't (or if planning
+    /// isn't supported for this type of descriptor yet).
+    pub plan: Option<bdk_tmp_plan::Plan<AK>>,
+    pub full_txout: FullTxOut<P>,
+}
+
+impl<K, AK: Clone, P> DescribedUtxo<K, AK, P> {
+    /// The expected weight of the satisfaction of the plan.
+    pub fn plan_weight(&self) -> Option<usize> {
+        self.plan.as_ref().map(|plan| plan.expected_weight())
+    }
+}
+
+/// A UTXO that can't be spent yet because it doesn't have enough confirmations for the relative
+/// (`older`) timelock of the branch it would be spent with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImmatureUtxo {
+    pub outpoint: OutPoint,
+    pub value: u64,
+    /// The confirmations the UTXO has at the tip (`None` if it is unconfirmed)
+    pub confirmations: Option<u32>,
+    /// The relative timelock the UTXO must satisfy
+    pub older: Sequence,
+}
+
+impl core::fmt::Display for ImmatureUtxo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} ({} sats) ", self.outpoint, self.value)?;
+        let value = self.older.to_consensus_u32() & 0xffff;
+        if self.older.is_time_locked() {
+            return write!(
+                f,
+                "must have been confirmed for {} seconds, which needs the age of the coins \
+                 (`--txo-age`) to check",
+                value * 512
+            );
+        }
+        match self.confirmations {
+            Some(confirmations) => write!(
+                f,
+                "needs {} confirmations but only has {}",
+                value, confirmations
+            ),
+            None => write!(f, "needs {} confirmations but is unconfirmed", value),
+        }
+    }
+}
+
+/// Checks that `utxo` has enough confirmations at `tip_height` for the relative timelock of the
+/// branch it would be spent with.
+///
+/// A UTXO without a plan is checked against the branch it could be spent with once it is old
+/// enough so that we can explain why it isn't spendable yet.
+pub fn check_maturity<K, AK, P>(
+    utxo: &DescribedUtxo<K, AK, P>,
+    assets: &bdk_tmp_plan::Assets<AK>,
+    tip_height: Option<u32>,
+) -> Result<(), ImmatureUtxo>
+where
+    AK: bdk_tmp_plan::CanDerive + Clone,
+    P: ChainPosition,
+{
+    let confirmation_height: Option<u32> = utxo.full_txout.chain_position.height().into();
+    let older = match &utxo.plan {
+        Some(plan) => plan.required_sequence(),
+        None => {
+            let matured = |txo_age: Sequence| bdk_tmp_plan::Assets {
+                max_locktime: tip_height.and_then(|height| LockTime::from_height(height).ok()),
+                txo_age: Some(txo_age),
+                ..assets.clone()
+            };
+            bdk_tmp_plan::plan_satisfaction(
+                &utxo.descriptor,
+                &matured(Sequence::from_height(u16::MAX)),
+            )
+            .or_else(|| {
+                bdk_tmp_plan::plan_satisfaction(
+                    &utxo.descriptor,
+                    &matured(Sequence::from_512_second_intervals(u16::MAX)),
+                )
+            })
+            .and_then(|plan| plan.required_sequence())
+        }
+    };
+    let older = match older {
+        Some(older) if older.is_relative_lock_time() => older,
+        _ => return Ok(()),
+    };
+
+    let confirmations = match (confirmation_height, tip_height) {
+        (Some(height), Some(tip_height)) if height <= tip_height => Some(tip_height - height + 1),
+        (Some(_), _) => Some(0),
+        (None, _) => None,
+    };
+    let required = older.to_consensus_u32() & 0xffff;
+    let matured = match older.is_height_locked() {
+        true => confirmations.unwrap_or(0) >= required,
+        // we don't know the median time past of the blocks so the age has to be given
+        false => {
+            confirmation_height.is_some()
+                && matches!(assets.txo_age, Some(age) if age.is_time_locked()
+                    && age.to_consensus_u32() & 0xffff >= required)
+        }
+    };
+    if matured {
+        return Ok(());
+    }
+
+    Err(ImmatureUtxo {
+        outpoint: utxo.full_txout.outpoint,
+        value: utxo.full_txout.txout.value,
+        confirmations,
+        older,
+    })
+}
+
+/// The UTXOs of the tracker described with the descriptor they were derived from and how they
+/// can be spent with `assets`.
+pub fn described_utxos<'a, K, AK, P>(
+    tracker: &'a KeychainTracker<K, P>,
+    assets: &'a bdk_tmp_plan::Assets<AK>,
+) -> impl Iterator<Item = DescribedUtxo<K, AK, P>> + 'a
+where
+    K: Clone + Ord + Debug,
+    AK: bdk_tmp_plan::CanDerive + Clone,
+    P: ChainPosition,
+{
+    let tip_height = tracker
+        .chain()
+        .latest_checkpoint()
+        .map(|block_id| block_id.height);
+    tracker
+        .full_utxos()
+        .map(move |((keychain, index), full_txout)| {
+            let descriptor = tracker
+                .txout_index
+                .keychains()
+                .get(keychain)
+                .expect("must exist since we have a utxo for it")
+                .at_derivation_index(*index);
+            let confirmation_height: Option<u32> = full_txout.chain_position.height().into();
+            // give the planner the timelock assets of this particular utxo so that it can pick a
+            // timelocked branch once it becomes spendable. A max locktime that was given takes
+            // precedence, while the age that was given only applies to time based `older`
+            // timelocks (so the coin's confirmations still count for height based ones).
+            let plan = match tip_height {
+                Some(tip_height) => {
+                    let timelocked = assets
+                        .clone()
+                        .with_timelocks(tip_height, confirmation_height);
+                    let timelocked = bdk_tmp_plan::Assets {
+                        max_locktime: assets.max_locktime.or(timelocked.max_locktime),
+                        ..timelocked
+                    };
+                    let by_height = bdk_tmp_plan::plan_satisfaction(&descriptor, &timelocked);
+                    let by_time = assets
+                        .txo_age
+                        .filter(|age| age.is_time_locked() && confirmation_height.is_some())
+                        .and_then(|txo_age| {
+                            let assets = bdk_tmp_plan::Assets {
+                                txo_age: Some(txo_age),
+                                ..timelocked.clone()
+                            };
+                            bdk_tmp_plan::plan_satisfaction(&descriptor, &assets)
+                        });
+                    by_height
+                        .into_iter()
+                        .chain(by_time)
+                        .min_by_key(|plan| plan.expected_weight())
+                }
+                None => bdk_tmp_plan::plan_satisfaction(&descriptor, assets),
+            };
+            DescribedUtxo {
+                keychain: keychain.clone(),
+                index: *index,
+                spk_type: descriptor.desc_type(),
+                descriptor,
+                plan,
+                full_txout,
+            }
+        })
+}
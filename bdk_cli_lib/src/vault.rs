@@ -0,0 +1,125 @@
+//! Inspecting descriptors and spending from vaults.
+use crate::{spending_assets, Keychain, KeychainPolicy, PolicyBranchOutput, Signer, VaultStatus};
+use anyhow::{anyhow, Result};
+use bdk_chain::{keychain::KeychainTracker, sparse_chain::ChainPosition};
+use clap::Subcommand;
+use std::{
+    fmt::Debug,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum VaultCmd {
+    /// Show when the timelocked branches of each UTXO become spendable
+    Status {
+        /// The median time past of the chain tip (defaults to the current system time)
+        #[clap(long)]
+        mtp: Option<u32>,
+    },
+}
+
+/// Describes the spending policy of the descriptors of the wallet's keychains (or just
+/// `keychain`) at `index` (see [`bdk_tmp_plan::spending_policy`]). The conditions the wallet
+/// can't satisfy are marked as missing.
+pub fn run_inspect_cmd<P>(
+    keychain: Option<Keychain>,
+    index: u32,
+    tracker: &KeychainTracker<Keychain, P>,
+    signers: &[Box<dyn Signer>],
+) -> Result<Vec<KeychainPolicy>> {
+    let keychains = tracker.txout_index.keychains();
+    if let Some(keychain) = &keychain {
+        if !keychains.contains_key(keychain) {
+            return Err(anyhow!("the wallet has no {} keychain", keychain));
+        }
+    }
+    let assets = spending_assets(tracker, signers);
+    Ok(keychains
+        .iter()
+        .filter(|(k, _)| keychain.is_none() || keychain == Some(**k))
+        .map(|(keychain, descriptor)| KeychainPolicy {
+            keychain: *keychain,
+            index,
+            branches: bdk_tmp_plan::spending_policy(
+                &descriptor.at_derivation_index(index),
+                &assets,
+            )
+            .into_iter()
+            .map(|branch| PolicyBranchOutput {
+                kind: branch.kind.to_string(),
+                satisfiable: branch.is_satisfiable(),
+                weight: branch.weight,
+                assets_weight: branch.assets_weight,
+                policy: branch.policy.to_string(),
+            })
+            .collect(),
+        })
+        .collect())
+}
+
+pub fn run_vault_cmd<P: ChainPosition>(
+    vault_cmd: VaultCmd,
+    keychain_tracker: &KeychainTracker<Keychain, P>,
+) -> Result<Vec<VaultStatus>> {
+    match vault_cmd {
+        VaultCmd::Status { mtp } => {
+            let tip_height = keychain_tracker
+                .chain()
+                .latest_checkpoint()
+                .map(|block_id| block_id.height)
+                .ok_or_else(|| anyhow!("there are no checkpoints yet, sync first"))?;
+            let tip_mtp = match mtp {
+                Some(mtp) => mtp,
+                None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32,
+            };
+
+            let mut statuses = vec![];
+            for ((keychain, index), utxo) in keychain_tracker.full_utxos() {
+                let descriptor = keychain_tracker
+                    .txout_index
+                    .keychains()
+                    .get(keychain)
+                    .expect("must exist since we have a utxo for it")
+                    .at_derivation_index(*index);
+                let confirmation_height: Option<u32> = utxo.chain_position.height().into();
+
+                for branch in bdk_tmp_plan::branch_timelocks(&descriptor)
+                    .into_iter()
+                    .filter(|branch| branch.is_timelocked())
+                {
+                    // we don't know the median time past of the confirmation block here
+                    let status = match branch.unlock(confirmation_height, None) {
+                        Some(unlock) if unlock.is_reached(tip_height, tip_mtp) => {
+                            "spendable".to_string()
+                        }
+                        Some(unlock) => {
+                            let mut status = "locked until".to_string();
+                            if let Some(height) = unlock.height {
+                                status += &format!(" height:{}", height);
+                            }
+                            if let Some(time) = unlock.time {
+                                status += &format!(" mtp:{}", time);
+                            }
+                            status
+                        }
+                        None if confirmation_height.is_none() => {
+                            "locked until confirmed".to_string()
+                        }
+                        None => "unknown".to_string(),
+                    };
+
+                    statuses.push(VaultStatus {
+                        outpoint: utxo.outpoint,
+                        value: utxo.txout.value,
+                        keychain: *keychain,
+                        index: *index,
+                        after: branch.after.map(|locktime| locktime.to_consensus_u32()),
+                        older: branch.older.map(|sequence| sequence.to_consensus_u32()),
+                        status,
+                    });
+                }
+            }
+            Ok(statuses)
+        }
+    }
+}
@@ -0,0 +1,463 @@
+//! Telling other services what happens to the wallet through webhooks.
+//!
+//! While the wallet is kept up to date (e.g. by the `watch` command of the Electrum example) each
+//! changeset that is applied is turned into [`WalletEvent`]s with [`changeset_events`]. A
+//! [`WebhookDispatcher`] POSTs each event as JSON to every registered [`Webhook`] that wants it and
+//! retries the deliveries that fail.
+//!
+//! The requests are made through a [`WebhookTransport`] so no HTTP client has to be built into
+//! the wallet. The Electrum example implements it by running `curl`.
+use crate::{load_extension, save_extension, Keychain};
+use anyhow::{anyhow, Result};
+use bdk_chain::{
+    bitcoin::{
+        hashes::{
+            hmac::{Hmac, HmacEngine},
+            sha256, Hash, HashEngine,
+        },
+        OutPoint, Txid,
+    },
+    keychain::{KeychainChangeSet, KeychainTracker, PersistBackend},
+    sparse_chain::ChainPosition,
+    tip::TipEvent,
+    TxHeight,
+};
+use clap::Subcommand;
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The name of the extension blob the [`Webhooks`] are saved under.
+pub const WEBHOOKS_EXTENSION: &str = "webhooks";
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum WebhookCmd {
+    /// Register a URL to POST wallet events to (replaces the webhook if the URL is registered)
+    Add {
+        url: String,
+        /// Only send events of this kind: `deposit-seen`, `deposit-confirmed`, `tx-evicted`,
+        /// `tx-abandoned` or `reorg`. Can be given more than once, all events are sent without it.
+        #[clap(long = "event")]
+        events: Vec<EventKind>,
+        /// Sign the payloads with this secret (see [`Webhook::secret`])
+        #[clap(long)]
+        secret: Option<String>,
+    },
+    /// Stop sending events to a URL
+    Remove { url: String },
+    /// List the registered webhooks
+    List,
+}
+
+/// The kinds of [`WalletEvent`] a webhook can ask for.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, serde::Serialize,
+)]
+pub enum EventKind {
+    DepositSeen,
+    DepositConfirmed,
+    TxEvicted,
+    TxAbandoned,
+    Reorg,
+}
+
+impl core::str::FromStr for EventKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "deposit-seen" => EventKind::DepositSeen,
+            "deposit-confirmed" => EventKind::DepositConfirmed,
+            "tx-evicted" => EventKind::TxEvicted,
+            "tx-abandoned" => EventKind::TxAbandoned,
+            "reorg" => EventKind::Reorg,
+            unknown => return Err(anyhow!("unknown kind of event '{}'", unknown)),
+        })
+    }
+}
+
+impl core::fmt::Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventKind::DepositSeen => write!(f, "deposit-seen"),
+            EventKind::DepositConfirmed => write!(f, "deposit-confirmed"),
+            EventKind::TxEvicted => write!(f, "tx-evicted"),
+            EventKind::TxAbandoned => write!(f, "tx-abandoned"),
+            EventKind::Reorg => write!(f, "reorg"),
+        }
+    }
+}
+
+/// Something that happened to the wallet. This is what a webhook is sent (as JSON with the kind of
+/// event in the `event` field).
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WalletEvent {
+    /// A transaction paying to an untrusted keychain (see [`Keychain::is_trusted`]) was seen
+    /// unconfirmed
+    DepositSeen {
+        outpoint: OutPoint,
+        keychain: Keychain,
+        index: u32,
+        value: u64,
+    },
+    /// A transaction paying to an untrusted keychain was confirmed
+    DepositConfirmed {
+        outpoint: OutPoint,
+        keychain: Keychain,
+        index: u32,
+        value: u64,
+        height: u32,
+    },
+    /// A transaction of the wallet was dropped from the chain, e.g. because it was replaced
+    TxEvicted { txid: Txid },
+    /// A transaction the wallet sent was abandoned because it didn't confirm in time (see
+    /// [`ExpiryPolicy`](crate::ExpiryPolicy))
+    TxAbandoned { txid: Txid },
+    /// The blocks from `height` up were reorged out
+    Reorg { height: u32 },
+}
+
+impl WalletEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            WalletEvent::DepositSeen { .. } => EventKind::DepositSeen,
+            WalletEvent::DepositConfirmed { .. } => EventKind::DepositConfirmed,
+            WalletEvent::TxEvicted { .. } => EventKind::TxEvicted,
+            WalletEvent::TxAbandoned { .. } => EventKind::TxAbandoned,
+            WalletEvent::Reorg { .. } => EventKind::Reorg,
+        }
+    }
+}
+
+/// The events of `changeset` once it has been applied to `keychain_tracker`.
+///
+/// A transaction that moves back to unconfirmed in a reorg is seen again, so its deposits are
+/// reported as seen once more.
+pub fn changeset_events<P: ChainPosition>(
+    keychain_tracker: &KeychainTracker<Keychain, P>,
+    changeset: &KeychainChangeSet<Keychain, P>,
+) -> Vec<WalletEvent> {
+    let chain_changeset = &changeset.chain_graph.chain;
+    let mut events = vec![];
+    if let Some((&height, _)) = chain_changeset
+        .checkpoints
+        .iter()
+        .find(|(_, hash)| hash.is_none())
+    {
+        events.push(WalletEvent::Reorg { height });
+    }
+    for (&txid, position) in &chain_changeset.txids {
+        let position = match position {
+            Some(position) => position,
+            None => {
+                events.push(WalletEvent::TxEvicted { txid });
+                continue;
+            }
+        };
+        let deposits = keychain_tracker
+            .txout_index
+            .inner()
+            .txouts_in_tx(txid)
+            .filter(|((keychain, _), _, _)| !keychain.is_trusted());
+        for (&(keychain, index), outpoint, txout) in deposits {
+            let value = txout.value;
+            events.push(match position.height() {
+                TxHeight::Confirmed(height) => WalletEvent::DepositConfirmed {
+                    outpoint,
+                    keychain,
+                    index,
+                    value,
+                    height,
+                },
+                TxHeight::Unconfirmed => WalletEvent::DepositSeen {
+                    outpoint,
+                    keychain,
+                    index,
+                    value,
+                },
+            });
+        }
+    }
+    events
+}
+
+/// The reorg `tip_events` went through (if any) as a [`WalletEvent`], for changesets applied by
+/// [`follow_tip`](crate::follow_tip) rather than by the caller.
+pub fn reorg_event(tip_events: &[TipEvent]) -> Option<WalletEvent> {
+    tip_events
+        .iter()
+        .filter_map(|event| match event {
+            TipEvent::Disconnected(block) => Some(block.height),
+            TipEvent::Connected(_) => None,
+        })
+        .min()
+        .map(|height| WalletEvent::Reorg { height })
+}
+
+/// Where to send events.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Webhook {
+    /// The kinds of events to send (all of them if empty)
+    pub events: BTreeSet<EventKind>,
+    /// The key the payloads are signed with.
+    ///
+    /// Each request has the unix time it was sent at in the `X-Webhook-Timestamp` header. The
+    /// hex encoded HMAC-SHA256 of the timestamp, a `.` and the payload is sent in the
+    /// `X-Webhook-Signature` header as `sha256=<hmac>` (see [`sign_payload`]) so the receiver can
+    /// check that the payload came from us. Since the timestamp is signed too, the receiver can
+    /// also reject requests that are too old to be anything but a replay.
+    pub secret: Option<String>,
+}
+
+impl Webhook {
+    pub fn wants(&self, event: &WalletEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event.kind())
+    }
+}
+
+/// The webhooks registered with the `webhook` command, keyed by URL.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Webhooks {
+    pub webhooks: BTreeMap<String, Webhook>,
+}
+
+/// The output of a [`WebhookCmd`].
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub enum WebhookOutput {
+    /// The webhook was registered, `replaced` if the URL already had one
+    Saved {
+        url: String,
+        replaced: bool,
+    },
+    Removed(String),
+    List(Vec<ListedWebhook>),
+}
+
+/// A registered [`Webhook`] without its secret.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ListedWebhook {
+    pub url: String,
+    pub events: BTreeSet<EventKind>,
+    /// Whether the payloads are signed
+    pub signed: bool,
+}
+
+impl core::fmt::Display for WebhookOutput {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            WebhookOutput::Saved { replaced: true, .. } => writeln!(
+                f,
+                "Updated the webhook, restart the watcher for it to take effect"
+            ),
+            WebhookOutput::Saved {
+                replaced: false, ..
+            } => writeln!(
+                f,
+                "Added the webhook, restart the watcher for it to take effect"
+            ),
+            WebhookOutput::Removed(_) => writeln!(f, "Removed the webhook"),
+            WebhookOutput::List(webhooks) => {
+                for webhook in webhooks {
+                    let events = match webhook.events.is_empty() {
+                        true => "all".to_string(),
+                        false => webhook
+                            .events
+                            .iter()
+                            .map(EventKind::to_string)
+                            .collect::<Vec<_>>()
+                            .join(","),
+                    };
+                    writeln!(
+                        f,
+                        "{} events:{} signed:{}",
+                        webhook.url, events, webhook.signed
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn run_webhook_cmd<P, S>(webhook_cmd: WebhookCmd, store: &mut S) -> Result<WebhookOutput>
+where
+    P: ChainPosition,
+    S: PersistBackend<Keychain, P>,
+    S::WriteError: std::error::Error + Send + Sync + 'static,
+    S::LoadError: std::error::Error + Send + Sync + 'static,
+{
+    let mut webhooks =
+        load_extension::<Webhooks, _, _>(store, WEBHOOKS_EXTENSION)?.unwrap_or_default();
+    match webhook_cmd {
+        WebhookCmd::Add {
+            url,
+            events,
+            secret,
+        } => {
+            let webhook = Webhook {
+                events: events.into_iter().collect(),
+                secret,
+            };
+            let replaced = webhooks.webhooks.insert(url.clone(), webhook).is_some();
+            save_extension(store, WEBHOOKS_EXTENSION, &webhooks)?;
+            Ok(WebhookOutput::Saved { url, replaced })
+        }
+        WebhookCmd::Remove { url } => {
+            if webhooks.webhooks.remove(&url).is_none() {
+                return Err(anyhow!("there is no webhook for {}", url));
+            }
+            save_extension(store, WEBHOOKS_EXTENSION, &webhooks)?;
+            Ok(WebhookOutput::Removed(url))
+        }
+        WebhookCmd::List => Ok(WebhookOutput::List(
+            webhooks
+                .webhooks
+                .into_iter()
+                .map(|(url, webhook)| ListedWebhook {
+                    url,
+                    events: webhook.events,
+                    signed: webhook.secret.is_some(),
+                })
+                .collect(),
+        )),
+    }
+}
+
+/// The signature of a request to a webhook with `secret` (see [`Webhook::secret`]): the hex
+/// encoded HMAC-SHA256 of `<timestamp>.<payload>`.
+pub fn sign_payload(secret: &str, timestamp: u64, payload: &str) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(timestamp.to_string().as_bytes());
+    engine.input(b".");
+    engine.input(payload.as_bytes());
+    Hmac::<sha256::Hash>::from_engine(engine).to_string()
+}
+
+/// Makes the requests of a [`WebhookDispatcher`].
+pub trait WebhookTransport {
+    /// POSTs the JSON `payload` to `url` with `headers` (names and values) on top of the
+    /// `Content-Type`. Only returns `Ok` if the webhook accepted it.
+    fn post(&mut self, url: &str, headers: &[(&str, String)], payload: &str) -> Result<()>;
+}
+
+/// An event waiting to be sent to a webhook.
+#[derive(Clone, Debug)]
+struct Delivery {
+    url: String,
+    payload: String,
+    /// The secret of the webhook, the payload is signed when it is sent
+    secret: Option<String>,
+    /// The number of failed attempts so far
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Sends [`WalletEvent`]s to the webhooks that want them through `transport` and retries the
+/// deliveries that fail.
+///
+/// A failed delivery is retried after `retry_interval`, which doubles after each attempt up to
+/// `max_retry_interval`, until it has been tried `max_attempts` times. Deliveries that are still
+/// waiting are lost when the dispatcher is dropped.
+#[derive(Clone, Debug)]
+pub struct WebhookDispatcher<T> {
+    pub transport: T,
+    pub webhooks: Webhooks,
+    pub max_attempts: u32,
+    pub retry_interval: Duration,
+    pub max_retry_interval: Duration,
+    queue: VecDeque<Delivery>,
+}
+
+impl<T: WebhookTransport> WebhookDispatcher<T> {
+    pub fn new(transport: T, webhooks: Webhooks) -> Self {
+        Self {
+            transport,
+            webhooks,
+            max_attempts: 5,
+            retry_interval: Duration::from_secs(30),
+            max_retry_interval: Duration::from_secs(60 * 60),
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// The number of deliveries that haven't succeeded yet.
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Queues each of `events` for the webhooks that want it.
+    pub fn queue(&mut self, events: &[WalletEvent]) -> Result<()> {
+        for event in events {
+            let payload = serde_json::to_string(event)?;
+            for (url, webhook) in &self.webhooks.webhooks {
+                if !webhook.wants(event) {
+                    continue;
+                }
+                self.queue.push_back(Delivery {
+                    url: url.clone(),
+                    payload: payload.clone(),
+                    secret: webhook.secret.clone(),
+                    attempts: 0,
+                    next_attempt: Instant::now(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the deliveries that are due and returns how many succeeded.
+    ///
+    /// Failures are only reported since the delivery is tried again later.
+    pub fn dispatch(&mut self) -> usize {
+        let now = Instant::now();
+        let mut delivered = 0;
+        for mut delivery in core::mem::take(&mut self.queue) {
+            if delivery.next_attempt > now {
+                self.queue.push_back(delivery);
+                continue;
+            }
+            match self.post(&delivery) {
+                Ok(()) => delivered += 1,
+                Err(e) => {
+                    delivery.attempts += 1;
+                    if delivery.attempts >= self.max_attempts {
+                        eprintln!(
+                            "giving up on sending {} to {}: {:#}",
+                            delivery.payload, delivery.url, e
+                        );
+                        continue;
+                    }
+                    eprintln!("failed to send an event to {}: {:#}", delivery.url, e);
+                    delivery.next_attempt = now + self.retry_delay(delivery.attempts);
+                    self.queue.push_back(delivery);
+                }
+            }
+        }
+        delivered
+    }
+
+    /// How long to wait after the `attempts`th failed attempt.
+    fn retry_delay(&self, attempts: u32) -> Duration {
+        let backoff = 2u32.saturating_pow(attempts - 1);
+        self.retry_interval
+            .saturating_mul(backoff)
+            .min(self.max_retry_interval)
+    }
+
+    /// Signs the payload of `delivery` (if its webhook has a secret) and POSTs it.
+    fn post(&mut self, delivery: &Delivery) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut headers = vec![("X-Webhook-Timestamp", timestamp.to_string())];
+        if let Some(secret) = &delivery.secret {
+            let signature = sign_payload(secret, timestamp, &delivery.payload);
+            headers.push(("X-Webhook-Signature", format!("sha256={}", signature)));
+        }
+        self.transport
+            .post(&delivery.url, &headers, &delivery.payload)
+    }
+}
@@ -0,0 +1,108 @@
+use bdk_chain::{
+    bitcoin::{
+        hashes::Hash, util::bip32::ExtendedPrivKey, BlockHash, Network, OutPoint, PackedLockTime,
+        Script, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    },
+    keychain::KeychainTracker,
+    BlockId, TxHeight,
+};
+use bdk_cli::{
+    build_tracker,
+    clap::{Parser, Subcommand},
+    parse_descriptors, Broadcast, Commands, EstimateFee, Keychain, Signer,
+};
+use std::cell::RefCell;
+
+/// A chain source that fails to do anything.
+#[allow(dead_code)]
+pub struct NoChain;
+
+impl Broadcast for NoChain {
+    type Error = std::io::Error;
+    fn broadcast(&self, _tx: &Transaction) -> Result<(), Self::Error> {
+        Err(std::io::Error::other("no chain"))
+    }
+}
+
+impl EstimateFee for NoChain {
+    type Error = std::io::Error;
+    fn estimate_fee(&mut self, _target_blocks: usize) -> Result<f32, Self::Error> {
+        Err(std::io::Error::other("no chain"))
+    }
+}
+
+/// Keeps the transactions it is asked to broadcast.
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct Recorder(pub RefCell<Vec<Transaction>>);
+
+impl Broadcast for Recorder {
+    type Error = std::io::Error;
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        self.0.borrow_mut().push(tx.clone());
+        Ok(())
+    }
+}
+
+impl EstimateFee for Recorder {
+    type Error = std::io::Error;
+    fn estimate_fee(&mut self, _target_blocks: usize) -> Result<f32, Self::Error> {
+        Err(std::io::Error::other("no estimates"))
+    }
+}
+
+/// The commands of a chain source that has none of its own.
+#[derive(Subcommand, Debug, Clone)]
+pub enum NoChainCommands {}
+
+/// Parses the commands like the examples do.
+#[allow(dead_code)]
+#[derive(Parser)]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Commands<NoChainCommands>,
+}
+
+#[allow(dead_code)]
+pub fn xprv(seed: u8) -> ExtendedPrivKey {
+    ExtendedPrivKey::new_master(Network::Testnet, &[seed; 32]).expect("valid seed")
+}
+
+/// A wallet of `xprv(1)` (with a change keychain) with a confirmed coin of each of `values`.
+#[allow(dead_code)]
+pub fn wallet(values: &[u64]) -> (KeychainTracker<Keychain, TxHeight>, Vec<Box<dyn Signer>>) {
+    let (keychains, keymap) = parse_descriptors(
+        &format!("wpkh({}/0/*)", xprv(1)),
+        Some(&format!("wpkh({}/1/*)", xprv(1))),
+    )
+    .expect("valid descriptor");
+    let mut tracker = build_tracker::<_, TxHeight>(keychains, None);
+    let (_, script_pubkey) = tracker.txout_index.derive_new(&Keychain::External);
+    let funding = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::hash(b"coinbase"), 0),
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: values
+            .iter()
+            .map(|&value| TxOut {
+                value,
+                script_pubkey: script_pubkey.clone(),
+            })
+            .collect(),
+    };
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 100,
+            hash: BlockHash::hash(b"tip"),
+        })
+        .expect("valid checkpoint");
+    let _ = tracker
+        .insert_tx(funding, TxHeight::Confirmed(10))
+        .expect("valid tx");
+    (tracker, vec![Box::new(keymap)])
+}
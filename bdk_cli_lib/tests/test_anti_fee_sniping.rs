@@ -0,0 +1,64 @@
+use bdk_chain::bitcoin::LockTime;
+use bdk_cli::{AntiFeeSniping, MixedLocktimes};
+use rand::{rngs::StdRng, SeedableRng};
+
+fn height(height: u32) -> LockTime {
+    LockTime::from_height(height).unwrap()
+}
+
+fn time(time: u32) -> LockTime {
+    LockTime::from_time(time).unwrap()
+}
+
+/// Never backs off so the locktime is the tip.
+fn at_tip() -> AntiFeeSniping {
+    AntiFeeSniping {
+        back_off_chance: 0,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn later_required_locktimes_win() {
+    let mut rng = StdRng::seed_from_u64(0);
+    assert_eq!(at_tip().locktime(Some(100), [], &mut rng), Ok(height(100)));
+    assert_eq!(
+        at_tip().locktime(Some(100), [height(50), height(120), height(110)], &mut rng),
+        Ok(height(120))
+    );
+    assert_eq!(
+        at_tip().locktime(Some(100), [height(50)], &mut rng),
+        Ok(height(100))
+    );
+    // a time can't be combined with the tip height
+    assert_eq!(
+        at_tip().locktime(Some(100), [time(600_000_000), time(500_000_000)], &mut rng),
+        Ok(time(600_000_000))
+    );
+}
+
+#[test]
+fn mixed_required_locktimes_are_an_error() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mixed = MixedLocktimes {
+        height: height(50),
+        time: time(500_000_000),
+    };
+    assert_eq!(
+        at_tip().locktime(Some(100), [height(50), time(500_000_000)], &mut rng),
+        Err(mixed)
+    );
+    assert_eq!(
+        at_tip().locktime(Some(100), [time(500_000_000), height(50)], &mut rng),
+        Err(mixed)
+    );
+    // even when anti fee sniping is off
+    let disabled = AntiFeeSniping {
+        enabled: false,
+        ..Default::default()
+    };
+    assert_eq!(
+        disabled.locktime(None, [time(500_000_000), height(50)], &mut rng),
+        Err(mixed)
+    );
+}
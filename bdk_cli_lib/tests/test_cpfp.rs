@@ -0,0 +1,171 @@
+mod common;
+use bdk_chain::{
+    bitcoin::{
+        hashes::Hash, secp256k1::Secp256k1, Address, Network, OutPoint, PublicKey, Transaction,
+        TxOut, Txid,
+    },
+    keychain::{KeychainTracker, MemoryStore},
+    TxHeight,
+};
+use bdk_cli::{
+    clap::Parser, create_cpfp_tx, create_tx, handle_commands, CommandOutput, FrozenUtxos, Keychain,
+    Signer, TxBuilder,
+};
+use common::{wallet, xprv, Cli, Recorder};
+
+fn payment(value: u64) -> TxOut {
+    let public_key = PublicKey::new(xprv(7).private_key.public_key(&Secp256k1::new()));
+    TxOut {
+        value,
+        script_pubkey: Address::p2wpkh(&public_key, Network::Testnet)
+            .unwrap()
+            .script_pubkey(),
+    }
+}
+
+/// Pays 30000 sats at the minimum relay feerate and adds the (unconfirmed) transaction to the
+/// wallet. Returns it along with the fee it paid.
+fn send_slowly(
+    tracker: &mut KeychainTracker<Keychain, TxHeight>,
+    signers: &[Box<dyn Signer>],
+) -> (Transaction, u64) {
+    let builder = TxBuilder {
+        feerate: Some(0.25),
+        ..Default::default()
+    };
+    let parent = create_tx(&[payment(30_000)], &builder, tracker, signers)
+        .unwrap()
+        .extract_tx();
+    let fee = 100_000 - parent.output.iter().map(|txout| txout.value).sum::<u64>();
+    let _ = tracker
+        .insert_tx(parent.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    (parent, fee)
+}
+
+#[test]
+fn the_child_brings_the_package_to_the_feerate() {
+    let (mut tracker, signers) = wallet(&[100_000]);
+    let (parent, parent_fee) = send_slowly(&mut tracker, &signers);
+    let change = parent
+        .output
+        .iter()
+        .position(|txout| *txout != payment(30_000))
+        .unwrap();
+
+    let child = create_cpfp_tx(
+        parent.txid(),
+        5.0,
+        &FrozenUtxos::default(),
+        &mut tracker,
+        &signers,
+    )
+    .unwrap()
+    .extract_tx();
+
+    assert_eq!(
+        child.input[0].previous_output,
+        OutPoint::new(parent.txid(), change as u32)
+    );
+    assert_eq!(child.input.len(), 1);
+    let child_fee = parent.output[change].value - child.output[0].value;
+    let package_feerate =
+        (parent_fee + child_fee) as f32 / (parent.weight() + child.weight()) as f32;
+    assert!(
+        package_feerate >= 5.0,
+        "package feerate {}",
+        package_feerate
+    );
+    assert!(child_fee as f32 / child.weight() as f32 > 5.0);
+}
+
+#[test]
+fn only_unconfirmed_transactions_paying_the_wallet_are_bumped() {
+    let (mut tracker, signers) = wallet(&[100_000]);
+    let frozen = FrozenUtxos::default();
+
+    let unknown = Txid::hash(b"unknown");
+    let error = create_cpfp_tx(unknown, 5.0, &frozen, &mut tracker, &signers).unwrap_err();
+    assert!(
+        error.to_string().contains("isn't in the wallet's chain"),
+        "{}",
+        error
+    );
+
+    let funding = tracker.full_utxos().next().unwrap().1.outpoint.txid;
+    let error = create_cpfp_tx(funding, 5.0, &frozen, &mut tracker, &signers).unwrap_err();
+    assert!(error.to_string().contains("already confirmed"), "{}", error);
+
+    // everything goes to someone else
+    let builder = TxBuilder {
+        drain_to: Some(payment(0).script_pubkey),
+        ..Default::default()
+    };
+    let parent = create_tx(&[], &builder, &mut tracker, &signers)
+        .unwrap()
+        .extract_tx();
+    let _ = tracker
+        .insert_tx(parent.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    let error = create_cpfp_tx(parent.txid(), 5.0, &frozen, &mut tracker, &signers).unwrap_err();
+    assert!(
+        error.to_string().contains("no unspent outputs"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn frozen_outputs_of_the_parent_are_still_spent() {
+    let (mut tracker, signers) = wallet(&[100_000]);
+    let (parent, _) = send_slowly(&mut tracker, &signers);
+    let change = tracker
+        .full_utxos()
+        .map(|(_, utxo)| utxo.outpoint)
+        .find(|outpoint| outpoint.txid == parent.txid())
+        .unwrap();
+    let frozen = FrozenUtxos {
+        outpoints: [change].into(),
+    };
+    // freezing only keeps other coins from being added to the child
+    let child = create_cpfp_tx(parent.txid(), 5.0, &frozen, &mut tracker, &signers)
+        .unwrap()
+        .extract_tx();
+    assert_eq!(child.input[0].previous_output, change);
+}
+
+#[test]
+fn the_cpfp_command_broadcasts_the_child() {
+    let (mut tracker, signers) = wallet(&[100_000]);
+    let (parent, _) = send_slowly(&mut tracker, &signers);
+    let mut client = Recorder::default();
+    let command = Cli::try_parse_from([
+        "bdk_cli".to_string(),
+        "cpfp".to_string(),
+        parent.txid().to_string(),
+        "--feerate".to_string(),
+        "20".to_string(),
+    ])
+    .unwrap()
+    .command;
+
+    let output = handle_commands(
+        command,
+        &mut client,
+        &mut tracker,
+        &mut MemoryStore::new(),
+        Network::Testnet,
+        &signers,
+    )
+    .unwrap();
+    let broadcast = client.0.borrow();
+    match output {
+        CommandOutput::Broadcasted(txid) => assert_eq!(txid, broadcast[0].txid()),
+        output => panic!("unexpected output {:?}", output),
+    }
+    assert_eq!(broadcast[0].input[0].previous_output.txid, parent.txid());
+    assert_eq!(
+        tracker.chain().tx_position(broadcast[0].txid()),
+        Some(&TxHeight::Unconfirmed)
+    );
+}
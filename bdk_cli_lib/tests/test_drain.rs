@@ -0,0 +1,167 @@
+mod common;
+use bdk_chain::{
+    bitcoin::{secp256k1::Secp256k1, Address, Network, OutPoint, PublicKey, TxOut},
+    keychain::{KeychainTracker, MemoryStore},
+    TxHeight,
+};
+use bdk_cli::{
+    clap::Parser, create_psbt, described_utxos, handle_commands, signer_assets, CommandOutput,
+    Keychain, Signer, TxBuilder,
+};
+use common::{wallet, xprv, Cli, Recorder};
+
+/// Someone else's address.
+fn recipient() -> Address {
+    let public_key = PublicKey::new(xprv(7).private_key.public_key(&Secp256k1::new()));
+    Address::p2wpkh(&public_key, Network::Testnet).unwrap()
+}
+
+fn drain_to(builder: TxBuilder) -> TxBuilder {
+    TxBuilder {
+        feerate: Some(1.0),
+        drain_to: Some(recipient().script_pubkey()),
+        ..builder
+    }
+}
+
+fn coins(
+    tracker: &KeychainTracker<Keychain, TxHeight>,
+    signers: &[Box<dyn Signer>],
+) -> Vec<OutPoint> {
+    described_utxos(tracker, &signer_assets(signers))
+        .map(|utxo| utxo.full_txout.outpoint)
+        .collect()
+}
+
+#[test]
+fn everything_but_the_fee_goes_to_the_script() {
+    let (mut tracker, signers) = wallet(&[60_000, 40_000]);
+    let (psbt, _) = create_psbt(
+        &[],
+        &drain_to(TxBuilder::default()),
+        &mut tracker,
+        &signer_assets(&signers),
+    )
+    .unwrap();
+
+    let tx = &psbt.unsigned_tx;
+    assert_eq!(tx.input.len(), 2);
+    assert_eq!(tx.output.len(), 1);
+    assert_eq!(tx.output[0].script_pubkey, recipient().script_pubkey());
+    let fee = 100_000 - tx.output[0].value;
+    assert!(fee > 0 && fee < 1_000, "fee of {} sats", fee);
+    // no change address was handed out
+    assert_eq!(
+        tracker
+            .txout_index
+            .derivation_indices()
+            .get(&Keychain::Internal),
+        None
+    );
+}
+
+#[test]
+fn only_the_given_coins_are_drained_along_with_the_payments() {
+    let (mut tracker, signers) = wallet(&[60_000, 40_000]);
+    let must_spend = coins(&tracker, &signers)
+        .into_iter()
+        .find(|outpoint| outpoint.vout == 1)
+        .unwrap();
+    let payment = TxOut {
+        value: 10_000,
+        script_pubkey: Address::p2wpkh(
+            &PublicKey::new(xprv(8).private_key.public_key(&Secp256k1::new())),
+            Network::Testnet,
+        )
+        .unwrap()
+        .script_pubkey(),
+    };
+    let builder = drain_to(TxBuilder {
+        must_spend: vec![must_spend],
+        ..Default::default()
+    });
+    let (psbt, _) = create_psbt(
+        std::slice::from_ref(&payment),
+        &builder,
+        &mut tracker,
+        &signer_assets(&signers),
+    )
+    .unwrap();
+
+    let tx = &psbt.unsigned_tx;
+    assert_eq!(
+        tx.input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect::<Vec<_>>(),
+        [must_spend]
+    );
+    assert!(tx.output.contains(&payment));
+    let drained = tx
+        .output
+        .iter()
+        .find(|txout| txout.script_pubkey == recipient().script_pubkey())
+        .unwrap();
+    assert!(drained.value < 30_000 && drained.value > 29_000);
+}
+
+#[test]
+fn dust_is_not_drained() {
+    let (mut tracker, signers) = wallet(&[500]);
+    let error = create_psbt(
+        &[],
+        &drain_to(TxBuilder::default()),
+        &mut tracker,
+        &signer_assets(&signers),
+    )
+    .unwrap_err();
+    // the coin can't pay for a drain output above the dust limit
+    assert!(
+        format!("{:#}", error).contains("MinDrainValue"),
+        "{:#}",
+        error
+    );
+}
+
+#[test]
+fn the_drain_command_broadcasts_the_sweep() {
+    let (mut tracker, signers) = wallet(&[60_000, 40_000]);
+    let excluded = coins(&tracker, &signers)
+        .into_iter()
+        .find(|outpoint| outpoint.vout == 0)
+        .unwrap();
+    let mut store = MemoryStore::new();
+    let mut client = Recorder::default();
+    let command = Cli::try_parse_from([
+        "bdk_cli".to_string(),
+        "drain".to_string(),
+        recipient().to_string(),
+        "--feerate".to_string(),
+        "2".to_string(),
+        "--exclude-utxo".to_string(),
+        excluded.to_string(),
+    ])
+    .unwrap()
+    .command;
+
+    let output = handle_commands(
+        command,
+        &mut client,
+        &mut tracker,
+        &mut store,
+        Network::Testnet,
+        &signers,
+    )
+    .unwrap();
+    let broadcast = client.0.borrow();
+    assert_eq!(broadcast.len(), 1);
+    match output {
+        CommandOutput::Broadcasted(txid) => assert_eq!(txid, broadcast[0].txid()),
+        output => panic!("unexpected output {:?}", output),
+    }
+    assert_eq!(broadcast[0].input.len(), 1);
+    assert_ne!(broadcast[0].input[0].previous_output, excluded);
+    assert_eq!(broadcast[0].output.len(), 1);
+    // the wallet knows it only has the excluded coin left
+    assert_eq!(coins(&tracker, &signers), [excluded]);
+}
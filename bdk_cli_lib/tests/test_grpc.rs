@@ -0,0 +1,131 @@
+#![cfg(feature = "grpc")]
+mod common;
+use bdk_chain::{
+    bitcoin::{hashes::Hash, util::bip32::ExtendedPrivKey, Network, Txid},
+    keychain::{KeychainTracker, MemoryStore},
+    TxHeight,
+};
+use bdk_cli::{
+    build_tracker,
+    grpc::{
+        proto::{self, event::Event, tracker_client::TrackerClient},
+        serve,
+    },
+    parse_descriptors,
+    webhook::WalletEvent,
+    Keychain,
+};
+use common::NoChain;
+use std::{net::TcpListener, sync::mpsc, time::Duration};
+
+fn tracker() -> KeychainTracker<Keychain, TxHeight> {
+    let xprv = ExtendedPrivKey::new_master(Network::Testnet, &[1; 32]).expect("valid seed");
+    let (keychains, _) =
+        parse_descriptors(&format!("wpkh({}/0/*)", xprv), None).expect("valid descriptor");
+    build_tracker(keychains, None)
+}
+
+#[test]
+fn rpcs_are_answered_by_the_wallet_thread() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (requests, _server) = serve::<TxHeight>(listener).unwrap();
+    let (subscribed_tx, subscribed_rx) = mpsc::channel();
+
+    let client = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let mut client = TrackerClient::connect(format!("http://{}", addr))
+                .await
+                .unwrap();
+            let mut events = client
+                .events(proto::EventsRequest {
+                    kinds: vec!["reorg".into()],
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            subscribed_tx.send(()).unwrap();
+
+            let balance = client
+                .get_balance(proto::GetBalanceRequest::default())
+                .await
+                .unwrap()
+                .into_inner();
+            let next = client
+                .derive_address(proto::DeriveAddressRequest { new: false })
+                .await
+                .unwrap()
+                .into_inner();
+            let new = client
+                .derive_address(proto::DeriveAddressRequest { new: true })
+                .await
+                .unwrap()
+                .into_inner();
+            let utxos = client
+                .list_utxos(proto::ListUtxosRequest {})
+                .await
+                .unwrap()
+                .into_inner();
+            let unfunded = client
+                .send(proto::SendRequest {
+                    payments: vec![proto::Payment {
+                        recipient: new.address.clone(),
+                        value: 10_000,
+                    }],
+                    ..Default::default()
+                })
+                .await
+                .unwrap_err();
+            let no_payments = client
+                .send(proto::SendRequest::default())
+                .await
+                .unwrap_err();
+            let event = events.message().await.unwrap();
+            (balance, next, new, utxos, unfunded, no_payments, event)
+        })
+    });
+
+    let mut tracker = tracker();
+    let mut store = MemoryStore::new();
+    while !client.is_finished() {
+        requests.handle_pending(
+            &mut NoChain,
+            &mut tracker,
+            &mut store,
+            Network::Testnet,
+            &[],
+        );
+        if subscribed_rx.try_recv().is_ok() {
+            requests.publish(&[
+                WalletEvent::TxEvicted {
+                    txid: Txid::all_zeros(),
+                },
+                WalletEvent::Reorg { height: 7 },
+            ]);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let (balance, next, new, utxos, unfunded, no_payments, event) = client.join().unwrap();
+
+    assert_eq!(balance, proto::Balance::default());
+    assert_eq!(next.index, 0);
+    assert_eq!(new.index, 1);
+    assert_eq!(
+        tracker
+            .txout_index
+            .derivation_indices()
+            .get(&Keychain::External),
+        Some(&1)
+    );
+    assert!(utxos.utxos.is_empty());
+    assert_eq!(unfunded.code(), tonic::Code::Unknown);
+    assert_eq!(no_payments.code(), tonic::Code::InvalidArgument);
+    // the evicted transaction isn't one of the kinds that were asked for
+    assert_eq!(
+        event,
+        Some(proto::Event {
+            event: Some(Event::Reorg(7))
+        })
+    );
+}
@@ -0,0 +1,122 @@
+mod common;
+use bdk_chain::{
+    bitcoin::Network,
+    keychain::{KeychainTracker, MemoryStore},
+    TxHeight,
+};
+use bdk_cli::{
+    build_tracker, handle_commands, handle_commands_async, parse_descriptors, CommandOutput,
+    Commands, Keychain, ScriptType, IMPORTS_EXTENSION,
+};
+use common::{xprv, NoChain, NoChainCommands};
+use std::{
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
+fn tracker() -> KeychainTracker<Keychain, TxHeight> {
+    let (keychains, _) =
+        parse_descriptors(&format!("wpkh({}/0/*)", xprv(1)), None).expect("valid descriptor");
+    build_tracker(keychains, None)
+}
+
+fn run(
+    command: Commands<NoChainCommands>,
+    tracker: &mut KeychainTracker<Keychain, TxHeight>,
+    store: &mut MemoryStore<Keychain, TxHeight>,
+) -> anyhow::Result<CommandOutput<TxHeight>> {
+    handle_commands(command, NoChain, tracker, store, Network::Testnet, &[])
+}
+
+/// Polls `future` until it is ready. The stores of these tests never have to wait.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+#[test]
+fn import_adds_the_keychains() {
+    let mut tracker = tracker();
+    let mut store = MemoryStore::new();
+    let command = Commands::Import {
+        descriptors_json: format!(r#"[{{"desc":"wpkh({}/0/*)","range":2}}]"#, xprv(2)),
+    };
+    match run(command, &mut tracker, &mut store).unwrap() {
+        CommandOutput::Imported(keychains) => assert_eq!(keychains, [Keychain::Imported(0)]),
+        output => panic!("unexpected output {:?}", output),
+    }
+    assert!(tracker
+        .txout_index
+        .keychains()
+        .contains_key(&Keychain::Imported(0)));
+}
+
+#[test]
+fn generate_uses_the_network() {
+    let command = Commands::Generate {
+        script_type: ScriptType::Wpkh,
+        xprv: Some(xprv(3)),
+        account: 0,
+    };
+    match run(command, &mut tracker(), &mut MemoryStore::new()).unwrap() {
+        CommandOutput::Generated(generated) => {
+            assert_eq!(generated.new_xprv, None);
+            assert!(generated.descriptor.starts_with("wpkh("));
+            assert!(generated.public_descriptor.contains("tpub"));
+        }
+        output => panic!("unexpected output {:?}", output),
+    }
+}
+
+#[test]
+fn database_file_commands_fail() {
+    for command in [
+        Commands::Backup {
+            path: "backup".into(),
+        },
+        Commands::RestoreDb {
+            path: "backup".into(),
+        },
+        Commands::RecoverDb,
+    ] {
+        let error = run(command, &mut tracker(), &mut MemoryStore::new()).unwrap_err();
+        assert!(
+            error.to_string().contains("path of the database"),
+            "{}",
+            error
+        );
+    }
+}
+
+#[test]
+fn async_store_gets_the_writes_of_the_command() {
+    let mut tracker = tracker();
+    let mut store = MemoryStore::new();
+    let command = Commands::<NoChainCommands>::Import {
+        descriptors_json: format!(r#"[{{"desc":"wpkh({}/0/*)","range":2}}]"#, xprv(2)),
+    };
+    let output = block_on(handle_commands_async(
+        command,
+        NoChain,
+        &mut tracker,
+        &mut store,
+        Network::Testnet,
+        &[],
+    ))
+    .unwrap();
+    assert!(matches!(output, CommandOutput::Imported(_)));
+
+    let (extensions, _) = store.aggregate_extensions();
+    assert!(extensions.contains_key(IMPORTS_EXTENSION));
+    let (changeset, _) = store.aggregate_changeset();
+    assert_eq!(
+        changeset.derivation_indices.get(&Keychain::Imported(0)),
+        Some(&2)
+    );
+}
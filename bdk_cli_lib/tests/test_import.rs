@@ -0,0 +1,75 @@
+mod common;
+use bdk_chain::TxHeight;
+use bdk_cli::{
+    build_tracker, load_extension, open_store, parse_descriptors, run_backup_cmd, run_import_cmd,
+    ImportDescriptor, Keychain, IMPORTS_EXTENSION,
+};
+use common::xprv;
+use std::path::PathBuf;
+
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "bdk_cli_test_import_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn import_saves_public_descriptors_in_the_store() {
+    let db = TempPath::new("db");
+    let backup = TempPath::new("backup");
+    let (keychains, _) =
+        parse_descriptors(&format!("wpkh({}/0/*)", xprv(1)), None).expect("valid descriptor");
+    let mut tracker = build_tracker::<_, TxHeight>(keychains, None);
+    let mut store = open_store::<Keychain, TxHeight>(&db.0, None, false).unwrap();
+
+    let imports = format!(
+        r#"[{{"desc":"wpkh({}/84'/1'/0'/0/*)","range":[100,104]}},{{"desc":"wpkh({}/0/*)","range":3}}]"#,
+        xprv(2),
+        xprv(3)
+    );
+    let added = run_import_cmd(&mut tracker, &mut store, &imports).unwrap();
+    assert_eq!(added, [Keychain::Imported(0), Keychain::Imported(1)]);
+
+    // only the range of the import is stored
+    let indices = |keychain| {
+        tracker
+            .txout_index
+            .stored_scripts_of_keychain(&keychain)
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(indices(Keychain::Imported(0)), [100, 101, 102, 103, 104]);
+    assert_eq!(indices(Keychain::Imported(1)), [0, 1, 2, 3]);
+
+    let saved = load_extension::<Vec<ImportDescriptor>, _, _>(&mut store, IMPORTS_EXTENSION)
+        .unwrap()
+        .expect("the imports are saved");
+    assert_eq!(saved.len(), 2);
+    for import in &saved {
+        assert!(import.desc.contains("tpub"), "{}", import.desc);
+        assert!(!import.desc.contains("tprv"), "{}", import.desc);
+    }
+
+    // the backup is checked to load with the same keychains and indices
+    run_backup_cmd(&tracker, &mut store, &backup.0, None).unwrap();
+    let mut backup_store = open_store::<Keychain, TxHeight>(&backup.0, None, false).unwrap();
+    let backed_up =
+        load_extension::<Vec<ImportDescriptor>, _, _>(&mut backup_store, IMPORTS_EXTENSION)
+            .unwrap()
+            .expect("the imports are backed up");
+    assert_eq!(backed_up.len(), 2);
+}
@@ -0,0 +1,209 @@
+use bdk_chain::{
+    bitcoin::{
+        hashes::Hash, util::bip32::ExtendedPrivKey, Address, BlockHash, Network, OutPoint,
+        PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    },
+    keychain::{ConfirmationPolicy, KeychainTracker, MemoryStore},
+    BlockId, TxHeight,
+};
+use bdk_cli::{
+    build_tracker, load_extension, parse_descriptors, run_invoice_cmd, update_invoices,
+    DepositPolicy, Invoice, InvoiceCmd, InvoiceOutput, InvoiceStatus, Invoices, Keychain,
+    INVOICES_EXTENSION,
+};
+
+fn tracker() -> KeychainTracker<Keychain, TxHeight> {
+    let xprv = ExtendedPrivKey::new_master(Network::Testnet, &[1; 32]).expect("valid seed");
+    let (keychains, _) =
+        parse_descriptors(&format!("wpkh({}/0/*)", xprv), None).expect("valid descriptor");
+    let mut tracker = build_tracker::<_, TxHeight>(keychains, None);
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 100,
+            hash: BlockHash::hash(b"tip"),
+        })
+        .expect("valid checkpoint");
+    tracker
+}
+
+/// Pays `value` to `address` in a transaction at `position`.
+fn pay(
+    tracker: &mut KeychainTracker<Keychain, TxHeight>,
+    address: &Address,
+    value: u64,
+    position: TxHeight,
+) {
+    let tx = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::hash(&value.to_le_bytes()), 0),
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: address.script_pubkey(),
+        }],
+    };
+    let _ = tracker.insert_tx(tx, position).unwrap();
+}
+
+fn create(
+    tracker: &mut KeychainTracker<Keychain, TxHeight>,
+    store: &mut MemoryStore<Keychain, TxHeight>,
+    amount: u64,
+) -> (u32, Address) {
+    let create = InvoiceCmd::Create {
+        amount,
+        memo: "coffee".into(),
+        expiry: 3600,
+        deposit_policy: DepositPolicy::default(),
+    };
+    match run_invoice_cmd(create, tracker, store, Network::Testnet).unwrap() {
+        InvoiceOutput::Created { id, address } => (id, address),
+        output => panic!("unexpected output {:?}", output),
+    }
+}
+
+#[test]
+fn each_invoice_gets_a_fresh_address() {
+    let mut tracker = tracker();
+    let mut store = MemoryStore::new();
+    let (first, first_address) = create(&mut tracker, &mut store, 50_000);
+    let (second, second_address) = create(&mut tracker, &mut store, 20_000);
+    assert_eq!((first, second), (0, 1));
+    assert_ne!(first_address, second_address);
+    assert_eq!(
+        tracker
+            .txout_index
+            .derivation_indices()
+            .get(&Keychain::External),
+        Some(&1)
+    );
+
+    let invoices = load_extension::<Invoices, _, _>(&mut store, INVOICES_EXTENSION)
+        .unwrap()
+        .unwrap();
+    assert_eq!(invoices.invoices[&1].address, second_address);
+    assert_eq!(invoices.invoices[&1].memo, "coffee");
+    match run_invoice_cmd(InvoiceCmd::List, &mut tracker, &mut store, Network::Testnet).unwrap() {
+        InvoiceOutput::List(invoices) => {
+            assert_eq!(invoices.len(), 2);
+            assert!(invoices
+                .iter()
+                .all(|invoice| invoice.status == InvoiceStatus::Unpaid));
+        }
+        output => panic!("unexpected output {:?}", output),
+    }
+
+    let error = run_invoice_cmd(
+        InvoiceCmd::Status { id: 2 },
+        &mut tracker,
+        &mut store,
+        Network::Testnet,
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("no invoice 2"), "{}", error);
+}
+
+#[test]
+fn payments_move_an_invoice_to_paid() {
+    let mut tracker = tracker();
+    let address = tracker
+        .txout_index
+        .derive_new(&Keychain::External)
+        .1
+        .clone();
+    let invoice = Invoice {
+        index: 0,
+        address: Address::from_script(&address, Network::Testnet).unwrap(),
+        amount: 50_000,
+        memo: String::new(),
+        created_at: 1_000,
+        expires_at: 2_000,
+        policy: ConfirmationPolicy {
+            untrusted_confirmations: 2,
+            require_checkpoint: false,
+        },
+        status: InvoiceStatus::Unpaid,
+    };
+    assert_eq!(invoice.status_at(&tracker, 1_500), InvoiceStatus::Unpaid);
+
+    pay(
+        &mut tracker,
+        &invoice.address,
+        20_000,
+        TxHeight::Confirmed(99),
+    );
+    assert_eq!(
+        invoice.status_at(&tracker, 1_500),
+        InvoiceStatus::Underpaid { received: 20_000 }
+    );
+    assert_eq!(
+        invoice.status_at(&tracker, 2_000),
+        InvoiceStatus::Expired { received: 20_000 }
+    );
+
+    // the rest is paid but with a single confirmation
+    pay(
+        &mut tracker,
+        &invoice.address,
+        30_000,
+        TxHeight::Confirmed(100),
+    );
+    assert_eq!(
+        invoice.status_at(&tracker, 1_500),
+        InvoiceStatus::Pending { received: 50_000 }
+    );
+    // a payment in full counts even after the invoice expired
+    assert_eq!(
+        invoice.status_at(&tracker, 3_000),
+        InvoiceStatus::Pending { received: 50_000 }
+    );
+
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 101,
+            hash: BlockHash::hash(b"next"),
+        })
+        .unwrap();
+    assert_eq!(
+        invoice.status_at(&tracker, 3_000),
+        InvoiceStatus::Paid { received: 50_000 }
+    );
+}
+
+#[test]
+fn updating_reports_the_invoices_that_changed() {
+    let mut tracker = tracker();
+    let mut store = MemoryStore::new();
+    assert!(update_invoices(&tracker, &mut store).unwrap().is_empty());
+
+    let (paid, address) = create(&mut tracker, &mut store, 50_000);
+    let _ = create(&mut tracker, &mut store, 20_000);
+    assert!(update_invoices(&tracker, &mut store).unwrap().is_empty());
+
+    pay(&mut tracker, &address, 50_000, TxHeight::Unconfirmed);
+    assert_eq!(
+        update_invoices(&tracker, &mut store).unwrap(),
+        [(paid, InvoiceStatus::Paid { received: 50_000 })]
+    );
+    // the new status was stored
+    assert!(update_invoices(&tracker, &mut store).unwrap().is_empty());
+    match run_invoice_cmd(
+        InvoiceCmd::Status { id: paid },
+        &mut tracker,
+        &mut store,
+        Network::Testnet,
+    )
+    .unwrap()
+    {
+        InvoiceOutput::Status(details) => {
+            assert_eq!(details.status, InvoiceStatus::Paid { received: 50_000 });
+            assert_eq!(details.payments.len(), 1);
+        }
+        output => panic!("unexpected output {:?}", output),
+    }
+}
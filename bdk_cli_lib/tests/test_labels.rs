@@ -0,0 +1,63 @@
+use bdk_chain::bitcoin::{hashes::Hash, Address, Network, Txid};
+use bdk_cli::Labels;
+use std::str::FromStr;
+
+const ADDRESS: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+#[test]
+fn bip329_round_trip() {
+    let address = Address::from_str(ADDRESS).unwrap();
+    let txid = Txid::hash(b"tx");
+    let mut labels = Labels::default();
+    labels.set_address(address.script_pubkey(), "from alice".to_string());
+    labels.set_tx(txid, "rent".to_string());
+
+    let bip329 = labels.to_bip329(Network::Testnet).unwrap();
+    assert_eq!(
+        bip329,
+        format!(
+            "{{\"type\":\"tx\",\"ref\":\"{}\",\"label\":\"rent\"}}\n\
+             {{\"type\":\"addr\",\"ref\":\"{}\",\"label\":\"from alice\"}}\n",
+            txid, ADDRESS
+        )
+    );
+
+    let mut imported = Labels::default();
+    assert_eq!(
+        imported.import_bip329(&bip329, Network::Testnet).unwrap(),
+        2
+    );
+    assert_eq!(imported, labels);
+}
+
+#[test]
+fn bip329_import_keeps_unknown_types_and_replaces_labels() {
+    let mut labels = Labels::default();
+    let txid = Txid::hash(b"tx");
+    labels.set_tx(txid, "old".to_string());
+    let xpub = r#"{"type":"xpub","ref":"tpubD6NzVbkrYhZ4WaWSyoBvQwbpLkojyoTZPRsgXELWz3Popb3qkjcJyJUGLnL4qHHoQvao8ESaAstxYSnhyswJ76uZPStJRJCTKvosUCJZL5B","label":"cold"}"#;
+    let bip329 = format!(
+        "{}\n\n{{\"type\":\"tx\",\"ref\":\"{}\",\"label\":\"new\"}}\n",
+        xpub, txid
+    );
+    assert_eq!(labels.import_bip329(&bip329, Network::Testnet).unwrap(), 2);
+    assert_eq!(labels.txs[&txid], "new");
+    assert_eq!(labels.other.len(), 1);
+    assert!(labels.to_bip329(Network::Testnet).unwrap().contains(xpub));
+
+    // an empty label removes the old one
+    labels.set_tx(txid, String::new());
+    assert!(labels.txs.is_empty());
+}
+
+#[test]
+fn bip329_import_rejects_other_networks() {
+    let mainnet =
+        r#"{"type":"addr","ref":"bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4","label":"x"}"#;
+    assert!(Labels::default()
+        .import_bip329(mainnet, Network::Testnet)
+        .is_err());
+    assert!(Labels::default()
+        .import_bip329("not json", Network::Testnet)
+        .is_err());
+}
@@ -0,0 +1,245 @@
+mod common;
+use bdk_chain::{
+    bitcoin::{secp256k1::Secp256k1, util::bip32::ExtendedPubKey, Script, TxOut},
+    keychain::{KeychainTracker, MemoryStore},
+    miniscript::DescriptorPublicKey,
+    TxHeight,
+};
+use bdk_cli::{
+    create_sweep_tx, described_utxos, load_extension, parse_descriptors, plan_sweeps,
+    run_migrate_cmd, signer_assets, AntiFeeSniping, DescribedUtxo, Keychain, Migration, Signer,
+    SweepLimits, MIGRATION_EXTENSION,
+};
+use bdk_coin_select::TXIN_BASE_WEIGHT;
+use common::{wallet, xprv, Recorder};
+
+/// The descriptor the funds are migrated to (we don't need its secret keys).
+fn new_descriptor() -> String {
+    format!(
+        "wpkh({}/0/*)",
+        ExtendedPubKey::from_priv(&Secp256k1::new(), &xprv(2))
+    )
+}
+
+fn utxos(
+    tracker: &KeychainTracker<Keychain, TxHeight>,
+    signers: &[Box<dyn Signer>],
+) -> Vec<DescribedUtxo<Keychain, DescriptorPublicKey, TxHeight>> {
+    described_utxos(tracker, &signer_assets(signers)).collect()
+}
+
+/// The weight a coin of the wallet adds to a transaction spending it.
+fn input_weight(tracker: &KeychainTracker<Keychain, TxHeight>, signers: &[Box<dyn Signer>]) -> u32 {
+    TXIN_BASE_WEIGHT + utxos(tracker, signers)[0].plan_weight().unwrap() as u32
+}
+
+fn destination() -> Script {
+    let (keychains, _) = parse_descriptors(&new_descriptor(), None).unwrap();
+    keychains[&Keychain::External]
+        .at_derivation_index(0)
+        .script_pubkey()
+}
+
+fn reasons(left_behind: &[bdk_cli::LeftBehind]) -> Vec<(u64, &str)> {
+    let mut reasons = left_behind
+        .iter()
+        .map(|left| (left.value, left.reason.as_str()))
+        .collect::<Vec<_>>();
+    reasons.sort();
+    reasons
+}
+
+#[test]
+fn sweeps_are_batched_within_the_weight_limit() {
+    let (tracker, signers) = wallet(&[10_000, 50_000, 20_000, 40_000, 30_000]);
+    let weight = input_weight(&tracker, &signers);
+    let limits = SweepLimits {
+        feerate: 1.0,
+        // room for two inputs but not for three
+        max_weight: 200 + 2 * weight,
+        max_fee: None,
+    };
+    let (batches, left_behind) = plan_sweeps(utxos(&tracker, &signers), &destination(), &limits);
+    assert!(left_behind.is_empty());
+
+    let values = batches
+        .iter()
+        .map(|batch| {
+            batch
+                .utxos
+                .iter()
+                .map(|utxo| utxo.full_txout.txout.value)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    // the largest coins are swept first
+    assert_eq!(
+        values,
+        [vec![50_000, 40_000], vec![30_000, 20_000], vec![10_000]]
+    );
+    // the fee is the same for batches with the same number of inputs and grows with them
+    assert_eq!(batches[0].fee, batches[1].fee);
+    assert!(batches[0].fee > batches[2].fee);
+    assert!(batches[0].fee as u32 - batches[2].fee as u32 >= weight);
+}
+
+#[test]
+fn coins_that_arent_worth_sweeping_are_left_behind() {
+    let (tracker, signers) = wallet(&[100_000, 500, 200]);
+    let weight = input_weight(&tracker, &signers);
+    assert!(200 < weight as u64 && 500 > weight as u64);
+    let limits = SweepLimits {
+        feerate: 1.0,
+        // a single input per transaction
+        max_weight: 200 + weight,
+        max_fee: None,
+    };
+    let (batches, left_behind) = plan_sweeps(utxos(&tracker, &signers), &destination(), &limits);
+    assert_eq!(batches.len(), 1);
+    assert_eq!(batches[0].utxos[0].full_txout.txout.value, 100_000);
+    assert_eq!(
+        reasons(&left_behind),
+        [
+            (200, "costs more to spend than it is worth at this feerate"),
+            (500, "wouldn't leave more than dust after paying the fee"),
+        ]
+    );
+}
+
+#[test]
+fn coins_beyond_the_limits_or_our_keys_are_left_behind() {
+    let (tracker, signers) = wallet(&[100_000, 60_000]);
+    let weight = input_weight(&tracker, &signers);
+
+    let limits = SweepLimits {
+        feerate: 1.0,
+        max_weight: 400_000,
+        // less than a transaction with a single input pays
+        max_fee: Some(weight as u64),
+    };
+    let (batches, left_behind) = plan_sweeps(utxos(&tracker, &signers), &destination(), &limits);
+    assert!(batches.is_empty());
+    assert_eq!(
+        reasons(&left_behind),
+        [
+            (60_000, "can't be swept within the weight and fee limits"),
+            (100_000, "can't be swept within the weight and fee limits"),
+        ]
+    );
+
+    let limits = SweepLimits {
+        max_weight: weight,
+        max_fee: None,
+        ..limits
+    };
+    let (batches, left_behind) = plan_sweeps(utxos(&tracker, &signers), &destination(), &limits);
+    assert!(batches.is_empty());
+    assert_eq!(left_behind.len(), 2);
+
+    // without the keys there is no plan to spend the coins with
+    let (batches, left_behind) = plan_sweeps(utxos(&tracker, &[]), &destination(), &limits);
+    assert!(batches.is_empty());
+    assert_eq!(
+        reasons(&left_behind),
+        [
+            (60_000, "can't be spent with our keys"),
+            (100_000, "can't be spent with our keys"),
+        ]
+    );
+}
+
+#[test]
+fn sweep_txs_pay_the_batch_fee_to_the_destination() {
+    let (tracker, signers) = wallet(&[100_000, 60_000]);
+    let limits = SweepLimits {
+        feerate: 2.0,
+        max_weight: 400_000,
+        max_fee: None,
+    };
+    let (batches, _) = plan_sweeps(utxos(&tracker, &signers), &destination(), &limits);
+    let batch = &batches[0];
+    let tx = create_sweep_tx(
+        batch,
+        destination(),
+        &AntiFeeSniping::default(),
+        Some(100),
+        &signers,
+    )
+    .unwrap();
+
+    assert_eq!(tx.input.len(), 2);
+    assert!(tx.input.iter().all(|txin| !txin.witness.is_empty()));
+    assert_eq!(
+        tx.output,
+        [TxOut {
+            value: 160_000 - batch.fee,
+            script_pubkey: destination(),
+        }]
+    );
+    // the fee was planned for (at least) the weight the signed transaction ended up with
+    assert!(batch.fee as f32 >= tx.weight() as f32 * limits.feerate);
+}
+
+#[test]
+fn migrations_are_resumed_with_the_same_descriptor() {
+    let (mut tracker, signers) = wallet(&[100_000, 60_000]);
+    let mut store = MemoryStore::new();
+    let client = Recorder::default();
+    let limits = SweepLimits {
+        feerate: 1.0,
+        max_weight: 400_000,
+        max_fee: None,
+    };
+
+    let report = run_migrate_cmd(
+        &new_descriptor(),
+        &limits,
+        &client,
+        &mut tracker,
+        &mut store,
+        &signers,
+    )
+    .unwrap();
+    let broadcast = client.0.borrow().clone();
+    assert_eq!(broadcast.len(), 1);
+    assert_eq!(report.broadcast, [broadcast[0].txid()]);
+    assert_eq!((report.sweeps, report.confirmed), (1, 0));
+    assert!(report.left_behind.is_empty());
+    assert!(!report.is_complete());
+    let migration = load_extension::<Migration, _, _>(&mut store, MIGRATION_EXTENSION)
+        .unwrap()
+        .unwrap();
+    assert_eq!(migration.sweeps, report.broadcast);
+    // the sweep pays to the new descriptor which isn't swept itself
+    let keychains = utxos(&tracker, &signers)
+        .into_iter()
+        .map(|utxo| utxo.keychain)
+        .collect::<Vec<_>>();
+    assert_eq!(keychains, [Keychain::Migration]);
+
+    // nothing is left to sweep when resuming
+    let report = run_migrate_cmd(
+        &new_descriptor(),
+        &limits,
+        &client,
+        &mut tracker,
+        &mut store,
+        &signers,
+    )
+    .unwrap();
+    assert!(report.broadcast.is_empty());
+    assert_eq!(report.sweeps, 1);
+    assert_eq!(client.0.borrow().len(), 1);
+
+    let other = format!(
+        "wpkh({}/0/*)",
+        ExtendedPubKey::from_priv(&Secp256k1::new(), &xprv(3))
+    );
+    let error =
+        run_migrate_cmd(&other, &limits, &client, &mut tracker, &mut store, &signers).unwrap_err();
+    assert!(
+        error.to_string().contains("already in progress"),
+        "{}",
+        error
+    );
+}
@@ -0,0 +1,119 @@
+use bdk_chain::{
+    bitcoin::{
+        hashes::Hash, util::bip32::ExtendedPrivKey, BlockHash, Network, OutPoint, PackedLockTime,
+        Script, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    },
+    keychain::KeychainTracker,
+    BlockId, TxHeight,
+};
+use bdk_cli::{
+    build_tracker, parse_descriptors, run_address_cmd, run_decode_cmd, run_privacy_cmd, AddressCmd,
+    CommandOutput, Keychain, Labels,
+};
+
+fn payment(seed: &[u8], script_pubkey: &Script, value: u64) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::hash(seed), 0),
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: script_pubkey.clone(),
+        }],
+    }
+}
+
+/// A wallet that received two payments on its first address.
+fn wallet() -> (KeychainTracker<Keychain, TxHeight>, [Txid; 2]) {
+    let xprv = ExtendedPrivKey::new_master(Network::Regtest, &[7; 32]).expect("valid seed");
+    let (keychains, _) =
+        parse_descriptors(&format!("wpkh({}/0/*)", xprv), None).expect("valid descriptor");
+    let mut tracker = build_tracker::<_, TxHeight>(keychains, None);
+    let (_, script_pubkey) = tracker.txout_index.derive_new(&Keychain::External);
+    let script_pubkey = script_pubkey.clone();
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 10,
+            hash: BlockHash::hash(b"tip"),
+        })
+        .expect("valid checkpoint");
+    let first = payment(b"first", &script_pubkey, 50_000);
+    let second = payment(b"second", &script_pubkey, 20_000);
+    let txids = [first.txid(), second.txid()];
+    let _ = tracker
+        .insert_tx(first, TxHeight::Confirmed(5))
+        .expect("valid tx");
+    let _ = tracker
+        .insert_tx(second, TxHeight::Unconfirmed)
+        .expect("valid tx");
+    (tracker, txids)
+}
+
+/// Serializes `output` to JSON and back, checking nothing is lost along the way.
+fn roundtrip(output: CommandOutput<TxHeight>) -> serde_json::Value {
+    let json = serde_json::to_value(&output).expect("serializable");
+    let decoded: CommandOutput<TxHeight> =
+        serde_json::from_value(json.clone()).expect("deserializable");
+    assert_eq!(decoded.to_string(), output.to_string());
+    json
+}
+
+#[test]
+fn decoded_tx_is_serializable() {
+    let (tracker, [txid, _]) = wallet();
+    let description =
+        run_decode_cmd(&txid.to_string(), &tracker, Network::Regtest).expect("known tx");
+    assert_eq!(description.outputs[0].value, 50_000);
+    assert_eq!(description.outputs[0].index, Some((Keychain::External, 0)));
+    // the coin it spends isn't the wallet's
+    assert_eq!(description.fee(), None);
+    let report = CommandOutput::Decode(description).to_string();
+    assert!(report.contains("fee: unknown (not all prevouts are known)"));
+
+    let json = roundtrip(CommandOutput::Decode(
+        run_decode_cmd(&txid.to_string(), &tracker, Network::Regtest).expect("known tx"),
+    ));
+    assert_eq!(json["Decode"]["txid"], txid.to_string());
+    assert_eq!(json["Decode"]["position"]["Confirmed"], 5);
+}
+
+#[test]
+fn privacy_report_is_serializable() {
+    let (tracker, _) = wallet();
+    let report = run_privacy_cmd(&tracker, Network::Regtest);
+    assert_eq!(report.reused_addresses.len(), 1);
+    assert_eq!(report.reused_addresses[0].index, (Keychain::External, 0));
+    assert_eq!(report.reused_addresses[0].txs, 2);
+    // both UTXOs are on the same address
+    assert_eq!(report.linked_utxos.len(), 1);
+    assert_eq!(report.linked_utxos[0].utxos.len(), 2);
+
+    let json = roundtrip(CommandOutput::Privacy(report));
+    assert_eq!(json["Privacy"]["reused_addresses"][0]["txs"], 2);
+}
+
+#[test]
+fn gap_stats_of_imported_keychains_are_serializable() {
+    let (mut tracker, _) = wallet();
+    let xprv = ExtendedPrivKey::new_master(Network::Regtest, &[8; 32]).expect("valid seed");
+    let (keychains, _) =
+        parse_descriptors(&format!("wpkh({}/0/*)", xprv), None).expect("valid descriptor");
+    tracker.txout_index.add_keychain(
+        Keychain::Imported(0),
+        keychains[&Keychain::External].clone(),
+    );
+    let (output, _) = run_address_cmd(
+        &tracker,
+        AddressCmd::Index { lookahead: 20 },
+        Network::Regtest,
+        &mut Labels::default(),
+    )
+    .expect("index");
+    let json = roundtrip(CommandOutput::Address(output));
+    assert_eq!(json["Address"]["Indices"]["keychains"][1][0]["Imported"], 0);
+}
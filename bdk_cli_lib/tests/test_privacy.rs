@@ -0,0 +1,230 @@
+mod common;
+use bdk_chain::{
+    bitcoin::{
+        hashes::Hash, secp256k1::Secp256k1, Address, BlockHash, Network, OutPoint, PackedLockTime,
+        PublicKey, Script, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    },
+    keychain::{KeychainTracker, MemoryStore},
+    BlockId, TxHeight,
+};
+use bdk_cli::{
+    build_tracker, handle_commands, parse_descriptors, run_privacy_cmd, CommandOutput, Commands,
+    Keychain, RoundPayment,
+};
+use common::{xprv, NoChain, NoChainCommands};
+
+fn descriptor() -> String {
+    format!("wpkh({}/0/*)", xprv(1))
+}
+
+fn tracker() -> KeychainTracker<Keychain, TxHeight> {
+    let (keychains, _) = parse_descriptors(&descriptor(), None).expect("valid descriptor");
+    let mut tracker = build_tracker::<_, TxHeight>(keychains, None);
+    tracker.txout_index.store_up_to(&Keychain::External, 5);
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 100,
+            hash: BlockHash::hash(b"tip"),
+        })
+        .expect("valid checkpoint");
+    tracker
+}
+
+/// An output to the wallet's address at `index`.
+fn ours(index: u32, value: u64) -> TxOut {
+    let (keychains, _) = parse_descriptors(&descriptor(), None).unwrap();
+    TxOut {
+        value,
+        script_pubkey: keychains[&Keychain::External]
+            .at_derivation_index(index)
+            .script_pubkey(),
+    }
+}
+
+fn theirs(value: u64) -> TxOut {
+    let public_key = PublicKey::new(xprv(7).private_key.public_key(&Secp256k1::new()));
+    TxOut {
+        value,
+        script_pubkey: Address::p2wpkh(&public_key, Network::Testnet)
+            .unwrap()
+            .script_pubkey(),
+    }
+}
+
+/// Adds a confirmed transaction spending `inputs` to `outputs` and returns its txid.
+fn confirm(
+    tracker: &mut KeychainTracker<Keychain, TxHeight>,
+    inputs: &[OutPoint],
+    outputs: Vec<TxOut>,
+) -> Txid {
+    let tx = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: inputs
+            .iter()
+            .map(|&previous_output| TxIn {
+                previous_output,
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: outputs,
+    };
+    let txid = tx.txid();
+    let _ = tracker.insert_tx(tx, TxHeight::Confirmed(10)).unwrap();
+    txid
+}
+
+/// A coin from outside the wallet, different for each `tag`.
+fn outside(tag: &[u8]) -> OutPoint {
+    OutPoint::new(Txid::hash(tag), 0)
+}
+
+#[test]
+fn addresses_receiving_more_than_once_are_reused() {
+    let mut tracker = tracker();
+    let first = vec![ours(0, 10_000)];
+    confirm(&mut tracker, &[outside(b"a")], first.clone());
+    confirm(&mut tracker, &[outside(b"b")], first);
+    // two outputs of one transaction don't make the address reused
+    let twice = vec![ours(1, 10_000), ours(1, 20_000)];
+    confirm(&mut tracker, &[outside(b"c")], twice);
+
+    let report = run_privacy_cmd(&tracker, Network::Testnet);
+    assert_eq!(report.reused_addresses.len(), 1);
+    let reused = &report.reused_addresses[0];
+    assert_eq!(reused.index, (Keychain::External, 0));
+    assert_eq!(reused.txs, 2);
+    assert_eq!(
+        reused.address,
+        Address::from_script(&ours(0, 0).script_pubkey, Network::Testnet)
+            .unwrap()
+            .to_string()
+    );
+}
+
+#[test]
+fn round_payments_give_the_change_away() {
+    let mut tracker = tracker();
+    let funding = confirm(
+        &mut tracker,
+        &[outside(b"a")],
+        vec![ours(0, 100_000), ours(1, 100_000)],
+    );
+    let round = confirm(
+        &mut tracker,
+        &[OutPoint::new(funding, 0)],
+        vec![theirs(30_000), ours(2, 69_800)],
+    );
+    // the change is as round as the payment
+    confirm(
+        &mut tracker,
+        &[OutPoint::new(funding, 1)],
+        vec![theirs(30_000), ours(3, 60_000)],
+    );
+    // paying the wallet isn't a payment of ours
+    confirm(
+        &mut tracker,
+        &[outside(b"b")],
+        vec![theirs(30_000), ours(4, 12_345)],
+    );
+
+    let report = run_privacy_cmd(&tracker, Network::Testnet);
+    assert_eq!(
+        report.round_payments,
+        [RoundPayment {
+            txid: round,
+            paid: vec![30_000],
+            change: vec![69_800],
+        }]
+    );
+}
+
+#[test]
+fn utxos_on_addresses_spent_together_are_linked() {
+    let mut tracker = tracker();
+    let funding = confirm(
+        &mut tracker,
+        &[outside(b"a")],
+        vec![ours(0, 50_000), ours(1, 50_000)],
+    );
+    // spending both coins together links the two addresses
+    confirm(
+        &mut tracker,
+        &[OutPoint::new(funding, 0), OutPoint::new(funding, 1)],
+        vec![theirs(99_000)],
+    );
+    let linked = confirm(
+        &mut tracker,
+        &[outside(b"b")],
+        vec![
+            ours(0, 10_000),
+            ours(1, 20_000),
+            // never spent from together with the others
+            ours(2, 30_000),
+        ],
+    );
+    // coins on the same address are linked too
+    let same_address = confirm(
+        &mut tracker,
+        &[outside(b"c")],
+        vec![ours(3, 40_000), ours(3, 50_000)],
+    );
+
+    let report = run_privacy_cmd(&tracker, Network::Testnet);
+    let mut clusters = report
+        .linked_utxos
+        .iter()
+        .map(|linked| {
+            let mut utxos = linked.utxos.clone();
+            utxos.sort();
+            (linked.addresses, utxos)
+        })
+        .collect::<Vec<_>>();
+    clusters.sort_by_key(|(_, utxos)| utxos[0].1);
+    assert_eq!(
+        clusters,
+        [
+            (
+                2,
+                vec![
+                    (OutPoint::new(linked, 0), 10_000),
+                    (OutPoint::new(linked, 1), 20_000)
+                ]
+            ),
+            (
+                1,
+                vec![
+                    (OutPoint::new(same_address, 0), 40_000),
+                    (OutPoint::new(same_address, 1), 50_000)
+                ]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn the_privacy_command_reports_the_leaks() {
+    let mut tracker = tracker();
+    let first = vec![ours(0, 10_000)];
+    confirm(&mut tracker, &[outside(b"a")], first.clone());
+    confirm(&mut tracker, &[outside(b"b")], first);
+
+    let output = handle_commands(
+        Commands::<NoChainCommands>::Privacy,
+        NoChain,
+        &mut tracker,
+        &mut MemoryStore::new(),
+        Network::Testnet,
+        &[],
+    )
+    .unwrap();
+    match output {
+        CommandOutput::Privacy(report) => {
+            assert_eq!(report.reused_addresses.len(), 1);
+            assert_eq!(report.linked_utxos.len(), 1);
+        }
+        output => panic!("unexpected output {:?}", output),
+    }
+}
@@ -0,0 +1,180 @@
+use bdk_chain::{
+    bitcoin::{util::bip32::ExtendedPrivKey, Network},
+    keychain::{KeychainChangeSet, KeychainTracker},
+    TxHeight,
+};
+use bdk_cli::{
+    build_tracker, load_or_recover, open_store, parse_descriptors, run_recover_db_cmd,
+    run_restore_db_cmd, Keychain,
+};
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "bdk_cli_test_recover_db_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+        for backup in backups(&self.0) {
+            let _ = std::fs::remove_file(backup);
+        }
+    }
+}
+
+/// The copies of the database at `db_path` that recovering it left behind.
+fn backups(db_path: &Path) -> Vec<PathBuf> {
+    let name = db_path.file_name().unwrap().to_str().unwrap().to_owned();
+    std::fs::read_dir(db_path.parent().unwrap())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            file_name.starts_with(&format!("{}.", name)) && file_name.ends_with(".bak")
+        })
+        .collect()
+}
+
+fn tracker() -> KeychainTracker<Keychain, TxHeight> {
+    let xprv = ExtendedPrivKey::new_master(Network::Testnet, &[1; 32]).expect("valid seed");
+    let (keychains, _) =
+        parse_descriptors(&format!("wpkh({}/0/*)", xprv), None).expect("valid descriptor");
+    build_tracker(keychains, None)
+}
+
+/// Writes a store with three entries and returns the length of the file after each of them.
+fn write_store(db_path: &Path) -> Vec<u64> {
+    let mut store = open_store::<Keychain, TxHeight>(db_path, None, false).unwrap();
+    (1..=3)
+        .map(|index| {
+            let changeset = KeychainChangeSet {
+                derivation_indices: [(Keychain::External, index)].into(),
+                ..Default::default()
+            };
+            store.append_changeset(&changeset).unwrap();
+            std::fs::metadata(db_path).unwrap().len()
+        })
+        .collect()
+}
+
+fn flip_byte(path: &Path, offset: u64) {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let mut byte = [0u8];
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.read_exact(&mut byte).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&[byte[0] ^ 0xff]).unwrap();
+}
+
+fn last_index(tracker: &KeychainTracker<Keychain, TxHeight>) -> Option<u32> {
+    tracker
+        .txout_index
+        .derivation_indices()
+        .get(&Keychain::External)
+        .copied()
+}
+
+#[test]
+fn a_cut_off_tail_is_recovered_when_loading() {
+    let db = TempPath::new("tail");
+    let lens = write_store(&db.0);
+    let file = std::fs::OpenOptions::new().write(true).open(&db.0).unwrap();
+    file.set_len(lens[2] - 3).unwrap();
+
+    let mut store = open_store::<Keychain, TxHeight>(&db.0, None, false).unwrap();
+    let mut tracker = tracker();
+    load_or_recover(&mut store, &mut tracker, &db.0).unwrap();
+    assert_eq!(last_index(&tracker), Some(2));
+    assert_eq!(std::fs::metadata(&db.0).unwrap().len(), lens[1]);
+    assert_eq!(backups(&db.0).len(), 1);
+}
+
+#[test]
+fn damage_before_the_tail_needs_recover_db() {
+    let db = TempPath::new("middle");
+    let lens = write_store(&db.0);
+    // the last byte of the first entry
+    flip_byte(&db.0, lens[0] - 1);
+
+    let mut store = open_store::<Keychain, TxHeight>(&db.0, None, false).unwrap();
+    let error = load_or_recover(&mut store, &mut tracker(), &db.0).unwrap_err();
+    assert!(error.to_string().contains("recover-db"), "{}", error);
+    // the intact entries after the damaged one are still there
+    assert_eq!(std::fs::metadata(&db.0).unwrap().len(), lens[2]);
+    assert!(backups(&db.0).is_empty());
+
+    let report = run_recover_db_cmd(&mut store, &db.0).unwrap();
+    assert_eq!(report.recovered, 0);
+    assert_eq!(
+        std::fs::metadata(&db.0).unwrap().len(),
+        lens[2] - report.truncated
+    );
+    assert!(report.truncated > lens[2] - lens[0]);
+    let backup_path = report.backup_path.expect("the damaged database is copied");
+    assert_eq!(std::fs::metadata(&backup_path).unwrap().len(), lens[2]);
+
+    // the recovered store loads without the damaged entry
+    let mut store = open_store::<Keychain, TxHeight>(&db.0, None, false).unwrap();
+    let mut tracker = tracker();
+    load_or_recover(&mut store, &mut tracker, &db.0).unwrap();
+    assert_eq!(last_index(&tracker), None);
+
+    // recovering an intact store changes nothing
+    let report = run_recover_db_cmd(&mut store, &db.0).unwrap();
+    assert_eq!(report.backup_path, None);
+    assert_eq!(report.truncated, 0);
+}
+
+#[test]
+fn restoring_never_overwrites_a_kept_database() {
+    let db = TempPath::new("restore");
+    let backup = TempPath::new("restore_backup");
+    write_store(&db.0);
+    write_store(&backup.0);
+    let contents = std::fs::read(&db.0).unwrap();
+
+    // a copy recover-db just made (one for the next second too in case it ticks over)
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let recovered = (now..now + 2)
+        .map(|secs| PathBuf::from(format!("{}.{}.bak", db.0.display(), secs)))
+        .collect::<Vec<_>>();
+    for path in &recovered {
+        std::fs::write(path, b"recovered").unwrap();
+    }
+    let error = run_restore_db_cmd(&tracker(), &db.0, &backup.0, None, None).unwrap_err();
+    assert!(error.to_string().contains("already exists"), "{}", error);
+    for path in &recovered {
+        assert_eq!(std::fs::read(path).unwrap(), b"recovered");
+        std::fs::remove_file(path).unwrap();
+    }
+    assert_eq!(std::fs::read(&db.0).unwrap(), contents);
+
+    // nor is it stopped by what a restore that was cut short left behind
+    let tmp_path = PathBuf::from(format!("{}.restore", db.0.display()));
+    std::fs::write(&tmp_path, b"cut short").unwrap();
+    let report = run_restore_db_cmd(&tracker(), &db.0, &backup.0, None, None).unwrap();
+    assert!(!tmp_path.exists());
+    assert_eq!(std::fs::read(&report.kept_path).unwrap(), contents);
+    assert_eq!(report.last_checkpoint, None);
+}
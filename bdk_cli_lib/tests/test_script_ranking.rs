@@ -0,0 +1,51 @@
+use bdk_chain::{
+    bitcoin::{hashes::Hash, Script, WPubkeyHash},
+    collections::BTreeMap,
+    TxHeight,
+};
+use bdk_cli::{rank_scripts, ScriptActivity, ScriptOrder};
+
+fn script(name: &str) -> Script {
+    Script::new_v0_p2wpkh(&WPubkeyHash::hash(name.as_bytes()))
+}
+
+fn ranked(order: ScriptOrder) -> Vec<&'static str> {
+    let mut activity = BTreeMap::new();
+    let mut used = |name: &str, last_used: TxHeight, unspent_value: u64| {
+        activity.insert(
+            script(name),
+            ScriptActivity {
+                last_used: Some(last_used),
+                unspent_value,
+            },
+        );
+    };
+    used("old", TxHeight::Confirmed(100), 50_000);
+    used("recent", TxHeight::Confirmed(200), 1_000);
+    used("mempool", TxHeight::Unconfirmed, 0);
+    used("rich", TxHeight::Confirmed(150), 50_000);
+
+    let mut scripts = ["unused", "old", "recent", "mempool", "rich"]
+        .into_iter()
+        .map(|name| (script(name), name))
+        .collect::<Vec<_>>();
+    rank_scripts(&mut scripts, &activity, &order);
+    scripts.into_iter().map(|(_, name)| name).collect()
+}
+
+#[test]
+fn ranks_scripts_by_the_order() {
+    assert_eq!(
+        ranked(ScriptOrder::Untouched),
+        ["unused", "old", "recent", "mempool", "rich"]
+    );
+    assert_eq!(
+        ranked(ScriptOrder::RecentlyUsed),
+        ["mempool", "recent", "rich", "old", "unused"]
+    );
+    // equal values are ranked by how recently they were used
+    assert_eq!(
+        ranked(ScriptOrder::HighestValue),
+        ["rich", "old", "recent", "mempool", "unused"]
+    );
+}
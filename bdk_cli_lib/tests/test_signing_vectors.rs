@@ -0,0 +1,180 @@
+mod common;
+use bdk_chain::{
+    bitcoin::{
+        consensus::encode::serialize_hex,
+        hashes::Hash,
+        secp256k1::Secp256k1,
+        util::bip32::{DerivationPath, ExtendedPubKey},
+        BlockHash, OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut, Txid,
+        WPubkeyHash, Witness,
+    },
+    keychain::KeychainTracker,
+    miniscript::descriptor::KeyMap,
+    BlockId, TxHeight,
+};
+use bdk_cli::{
+    build_tracker, create_tx, parse_descriptors, parse_signing_key, Keychain, Signer, TxBuilder,
+};
+use common::xprv;
+use std::str::FromStr;
+
+fn xpub(seed: u8) -> ExtendedPubKey {
+    ExtendedPubKey::from_priv(&Secp256k1::new(), &xprv(seed))
+}
+
+/// A wallet of `descriptor` (with `*` standing in for the keychain) which received 100,000 sats
+/// at its first external address in block 100.
+fn funded_wallet(descriptor: &str) -> (KeychainTracker<Keychain, TxHeight>, KeyMap) {
+    let (keychains, keymap) = parse_descriptors(
+        &descriptor.replace('*', "0/*"),
+        Some(&descriptor.replace('*', "1/*")),
+    )
+    .expect("valid descriptors");
+    let mut tracker = build_tracker::<_, TxHeight>(keychains, None);
+    let (_, script_pubkey) = tracker.txout_index.derive_new(&Keychain::External);
+    let funding = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::hash(b"coinbase"), 0),
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: 100_000,
+            script_pubkey: script_pubkey.clone(),
+        }],
+    };
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 100,
+            hash: BlockHash::hash(b"100"),
+        })
+        .expect("valid checkpoint");
+    let _ = tracker
+        .insert_tx(funding, TxHeight::Confirmed(100))
+        .expect("valid tx");
+    (tracker, keymap)
+}
+
+/// Spends the coin of [`funded_wallet`] paying 50,000 sats to a fixed script, with the order of
+/// the inputs and outputs and the anti fee sniping locktime seeded.
+fn signed_spend(descriptor: &str) -> Transaction {
+    let (tracker, keymap) = funded_wallet(descriptor);
+    spend(tracker, keymap)
+}
+
+fn spend(mut tracker: KeychainTracker<Keychain, TxHeight>, keymap: KeyMap) -> Transaction {
+    let outputs = [TxOut {
+        value: 50_000,
+        script_pubkey: Script::new_v0_p2wpkh(&WPubkeyHash::hash(b"recipient")),
+    }];
+    let builder = TxBuilder {
+        rng_seed: Some(42),
+        ..Default::default()
+    };
+    let signers: Vec<Box<dyn Signer>> = vec![Box::new(keymap)];
+    let psbt = create_tx(&outputs, &builder, &mut tracker, &signers).expect("spendable");
+    psbt.extract_tx()
+}
+
+/// Checks the spend of `descriptor` is exactly `expected_hex` and weighs `expected_weight`, and
+/// that the fee pays the default feerate for that weight. The fee is paid for the weight the plan
+/// expected, which may only overshoot by a few weight units (ECDSA signatures are at most 72 bytes
+/// but often 71).
+fn check_vector(descriptor: &str, expected_hex: &str, expected_weight: usize) {
+    let tx = signed_spend(descriptor);
+    assert_eq!(serialize_hex(&tx), expected_hex, "{}", descriptor);
+    assert_eq!(tx.weight(), expected_weight, "{}", descriptor);
+    let fee = 100_000 - tx.output.iter().map(|txout| txout.value).sum::<u64>();
+    let min_fee = (tx.weight() as f32 * bdk_cli::DEFAULT_FEERATE).ceil() as u64;
+    assert!(
+        fee >= min_fee,
+        "{}: the expected weight is too low",
+        descriptor
+    );
+    assert!(
+        fee <= min_fee + 2,
+        "{}: the expected weight is too high",
+        descriptor
+    );
+}
+
+#[test]
+fn wpkh_vector() {
+    check_vector(
+        &format!("wpkh({}/84'/1'/0'/*)", xprv(1)),
+        "0200000000010150d818b53a32f9ef7e324bfce00e524f3e438ae2963c8e5bb77da274322700660000000000fdffffff0237c2000000000000160014fa46a699a599dcc42d6a5c4bd5c18daff861d8ec50c30000000000001600149a6e6676f98ea0e05489079f5226a518b5a72ae10247304402207856a58356184a7d4003962dcbcbd2927a8a18b2e031cdc8786e54df55c578b302204b34dd2034324b7569c1ba6f37ee4d0693fe77dd56d94e76f50e8887f1771b2b0121038b7479652d8f0cda05450f45d849785a442f8ab68298baf2180fb1da80fcd57964000000",
+        561,
+    );
+}
+
+#[test]
+fn sh_wpkh_vector() {
+    check_vector(
+        &format!("sh(wpkh({}/49'/1'/0'/*))", xprv(2)),
+        "0200000000010150173febb2769ff51ff5d0c91ac8be1060bc7ed883cc627bddcbdcec22cd75120000000017160014122e4b8206d703470a37cf8a49ea2a2070fd07cafdffffff0207c200000000000017a9148cae63e04d3268e6c1a6c6fb562b0a31085a8fe08750c30000000000001600149a6e6676f98ea0e05489079f5226a518b5a72ae10247304402205e7e3af61578d3e3bf89f30dbbc8d7688cbf851f3ffcb2481a21ceef07597377022021a7ee47f5e6ec37d4fcb2ca19bd3dbb98e25fd47ca19429a24afa58b8fba3eb012102a6bd484252e88b828878622a2858d925401c0c723060aced182ce312d546840b64000000",
+        657,
+    );
+}
+
+#[test]
+fn wsh_multi_vector() {
+    // two of the three keys are ours, the third only signs with the others
+    check_vector(
+        &format!(
+            "wsh(multi(2,{}/48'/1'/0'/2'/*,{}/48'/1'/0'/2'/*,{}/*))",
+            xprv(3),
+            xprv(4),
+            xpub(5)
+        ),
+        "02000000000101716bf2910df88e9025007ef078b42fc6b02bef721f4f5476771f1d43c3addd200000000000fdffffff02d6c100000000000022002033b70e8e3bd60d0cc92b9662fc6b60503bb61051d4c94cf2d83b74cbce49f4c050c30000000000001600149a6e6676f98ea0e05489079f5226a518b5a72ae10400473044022078beee2d522ffd31faaaa618a4f937f29137bc7d96b5f601a168c953f0a9d68e0220287fe0ff1b2610a3fbe6423af859de55148a343df56d9c350dc56789413366560147304402204223f53799baa3fe3633370bbfe11992eddc199c493d08e10584e3d842e593da02207d92ee7f2a0ec08c0423453896da0bb2185b2c65f009d5568243da9a672109a4016952210253c16a596be8c9bd7be3abecd7f72d02121e5dbf0096a5a9cd9064267fc6944a2102b8e1a817a280e72928ac5639772b4a49c3d350c7689884f10b12e4d269ca81e721031937ba69cce8c07643b3801000742ff55f356555ac459536e84b340254c254c253ae64000000",
+        754,
+    );
+}
+
+#[test]
+fn tr_key_path_vector() {
+    check_vector(
+        &format!("tr({}/86'/1'/0'/*)", xprv(6)),
+        "02000000000101478e76386430a6b1cbfc8ff74b13bb35f0082c743c7b4dfc4694dfc65622773e0000000000fdffffff0234c20000000000002251205061e63f7aa11ade8629762ccd0a96a29253041e9061911dbb0c241d67e57f6e50c30000000000001600149a6e6676f98ea0e05489079f5226a518b5a72ae101401888b9e685715c171bd61626db8ea5e73d960895835c399ec7eee7a4656b2134d5e3dc509274ae840db8658e41aa4191a13a6942ce79fa9439752a99259a51e664000000",
+        568,
+    );
+}
+
+#[test]
+fn tr_script_path_vector() {
+    // the internal key is unspendable so the leaf has to be used
+    check_vector(
+        &format!(
+            "tr(50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0,pk({}/*))",
+            xprv(7)
+        ),
+        "02000000000101e19991b5c82d6d2c8a17c3d2b14e7889313e93b75836c637f718eaf5ba1e6a760000000000fdffffff0211c2000000000000225120cce2fe467b6b580286973e7195a36a7abb4c09a1750ce9792fc646acef74205e50c30000000000001600149a6e6676f98ea0e05489079f5226a518b5a72ae10340652f43878b91cb4401c38ba4ba3ff7561980e9440ea54d6ea77f6a523aa611f7836bc87edd152bd77b3f578a38197e460ca71eaf8b985149851dcd5d1d573f1f22201183aef3b0049ac001fb154a19c9b274a83830bded64d78409f83c76a31c7195ac21c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac064000000",
+        637,
+    );
+}
+
+#[test]
+fn signing_key_signs_for_a_watch_only_descriptor() {
+    let secp = Secp256k1::new();
+    let path = DerivationPath::from_str("m/84'/1'/0'").expect("valid path");
+    let account = xprv(1).derive_priv(&secp, &path).expect("derivable");
+    let origin = format!("[{}/84'/1'/0']", xprv(1).fingerprint(&secp));
+    let (tracker, keymap) = funded_wallet(&format!(
+        "wpkh({}{}/*)",
+        origin,
+        ExtendedPubKey::from_priv(&secp, &account)
+    ));
+    assert!(keymap.is_empty());
+
+    let (public_key, secret_key) =
+        parse_signing_key(&format!("{}{}/0/*", origin, account)).expect("valid key");
+    let keymap = KeyMap::from([(public_key, secret_key)]);
+    // the same spend as the wallet of the secret descriptor makes
+    assert_eq!(
+        spend(tracker, keymap),
+        signed_spend(&format!("wpkh({}/84'/1'/0'/*)", xprv(1)))
+    );
+}
@@ -0,0 +1,123 @@
+mod common;
+use bdk_chain::bitcoin::{
+    secp256k1::Secp256k1, util::psbt::PartiallySignedTransaction as Psbt, Address, Network,
+    PublicKey, TxOut,
+};
+use bdk_cli::{create_psbt, signer_assets, OrderingStrategy, TxBuilder};
+use common::{wallet, xprv};
+
+/// A payment of `value` sats to someone else.
+fn payment(seed: u8, value: u64) -> TxOut {
+    let public_key = PublicKey::new(xprv(seed).private_key.public_key(&Secp256k1::new()));
+    TxOut {
+        value,
+        script_pubkey: Address::p2wpkh(&public_key, Network::Testnet)
+            .unwrap()
+            .script_pubkey(),
+    }
+}
+
+fn builder(subtract_fee_from: Vec<usize>) -> TxBuilder {
+    TxBuilder {
+        feerate: Some(1.0),
+        ordering: OrderingStrategy::Untouched,
+        subtract_fee_from,
+        ..Default::default()
+    }
+}
+
+fn fee(psbt: &Psbt, input_value: u64) -> u64 {
+    input_value
+        - psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .map(|txout| txout.value)
+            .sum::<u64>()
+}
+
+#[test]
+fn the_fee_is_split_between_the_outputs_by_value() {
+    let (mut tracker, signers) = wallet(&[100_000]);
+    let outputs = [payment(7, 60_000), payment(8, 30_000)];
+    let (psbt, _) = create_psbt(
+        &outputs,
+        &builder(vec![0, 1]),
+        &mut tracker,
+        &signer_assets(&signers),
+    )
+    .unwrap();
+
+    let tx = &psbt.unsigned_tx;
+    // the coin only has to pay for the outputs so all that is left goes to change
+    assert_eq!(tx.output.len(), 3);
+    assert_eq!(tx.output[2].value, 10_000);
+    let fee = fee(&psbt, 100_000);
+    assert!(fee > 0);
+    let shares = [60_000 - tx.output[0].value, 30_000 - tx.output[1].value];
+    assert_eq!(shares[0] + shares[1], fee);
+    // twice the share of the other output, give or take the rounding (the remainder of which the
+    // first output also takes)
+    assert!(shares[0] >= 2 * shares[1] && shares[0] <= 2 * shares[1] + 2);
+}
+
+#[test]
+fn the_fee_can_come_out_of_one_of_the_outputs() {
+    let (mut tracker, signers) = wallet(&[100_000]);
+    let outputs = [payment(7, 40_000), payment(8, 60_000)];
+    let (psbt, _) = create_psbt(
+        &outputs,
+        &builder(vec![1]),
+        &mut tracker,
+        &signer_assets(&signers),
+    )
+    .unwrap();
+
+    // the whole coin is spent without change
+    let tx = &psbt.unsigned_tx;
+    assert_eq!(tx.output.len(), 2);
+    assert_eq!(tx.output[0].value, 40_000);
+    assert_eq!(tx.output[1].value, 60_000 - fee(&psbt, 100_000));
+}
+
+#[test]
+fn the_outputs_have_to_be_able_to_pay_the_fee() {
+    let (mut tracker, signers) = wallet(&[100_000]);
+    let assets = signer_assets(&signers);
+
+    let error = create_psbt(
+        &[payment(7, 50_000)],
+        &builder(vec![1]),
+        &mut tracker,
+        &assets,
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("only 1 outputs"), "{}", error);
+
+    let drain = TxBuilder {
+        drain_to: Some(payment(8, 0).script_pubkey),
+        ..builder(vec![0])
+    };
+    let error = create_psbt(&[payment(7, 50_000)], &drain, &mut tracker, &assets).unwrap_err();
+    assert!(
+        error.to_string().contains("draining the wallet"),
+        "{}",
+        error
+    );
+
+    // more than the output is worth
+    let error =
+        create_psbt(&[payment(7, 100)], &builder(vec![0]), &mut tracker, &assets).unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("the outputs the fee is subtracted from have 100 sats"),
+        "{}",
+        error
+    );
+
+    // worth more than the fee but not more than dust afterwards
+    let error =
+        create_psbt(&[payment(7, 600)], &builder(vec![0]), &mut tracker, &assets).unwrap_err();
+    assert!(error.to_string().contains("would be dust"), "{}", error);
+}
@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+
+use bdk_chain::{
+    bitcoin::{hashes::Hash, BlockHash},
+    sparse_chain::SparseChain,
+    BlockId, TxHeight,
+};
+use bdk_cli::{Keychain, SyncCursor};
+
+fn block(height: u32, seed: &[u8]) -> BlockId {
+    BlockId {
+        height,
+        hash: BlockHash::hash(seed),
+    }
+}
+
+fn chain_with(tip: BlockId) -> SparseChain<TxHeight> {
+    let mut chain = SparseChain::default();
+    let _ = chain.insert_checkpoint(tip).expect("valid checkpoint");
+    chain
+}
+
+#[test]
+fn resumes_after_the_last_scanned_index() {
+    let tip = block(100, b"100");
+    let cursor = SyncCursor {
+        tip: Some(tip),
+        scanned: vec![(Keychain::External, 4), (Keychain::Internal, 0)],
+    };
+    let resume_from = cursor.resume_from(&chain_with(tip));
+    assert_eq!(
+        resume_from,
+        [(Keychain::External, 5), (Keychain::Internal, 1)].into()
+    );
+}
+
+#[test]
+fn starts_over_when_the_tip_is_gone() {
+    let cursor = SyncCursor {
+        tip: Some(block(100, b"100")),
+        scanned: vec![(Keychain::External, 4)],
+    };
+    // the block the cursor was made at has been reorganized away
+    assert!(cursor
+        .resume_from(&chain_with(block(100, b"other 100")))
+        .is_empty());
+    // or was never seen by this chain at all
+    assert!(cursor
+        .resume_from(&SparseChain::<TxHeight>::default())
+        .is_empty());
+    // a cursor that never got a tip can't be resumed from either
+    assert!(SyncCursor::default()
+        .resume_from(&chain_with(block(100, b"100")))
+        .is_empty());
+}
+
+#[test]
+fn advancing_keeps_the_furthest_index() {
+    let mut cursor = SyncCursor {
+        tip: Some(block(100, b"100")),
+        scanned: vec![(Keychain::External, 4), (Keychain::Internal, 2)],
+    };
+    let new_tip = block(110, b"110");
+    let last_active = BTreeMap::from([(Keychain::External, 7), (Keychain::Internal, 1)]);
+    cursor.advance(Some(new_tip), &last_active);
+    assert_eq!(cursor.tip, Some(new_tip));
+    assert_eq!(
+        cursor.scanned,
+        vec![(Keychain::External, 7), (Keychain::Internal, 2)]
+    );
+
+    // a scan that found nothing in a keychain doesn't forget it
+    cursor.advance(Some(block(111, b"111")), &BTreeMap::new());
+    assert_eq!(
+        cursor.scanned,
+        vec![(Keychain::External, 7), (Keychain::Internal, 2)]
+    );
+}
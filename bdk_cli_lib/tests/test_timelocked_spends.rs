@@ -0,0 +1,200 @@
+mod common;
+use bdk_chain::{
+    bitcoin::{
+        hashes::Hash, secp256k1::Secp256k1, util::bip32::ExtendedPubKey, Address, BlockHash,
+        Network, OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut, Txid,
+        Witness,
+    },
+    keychain::KeychainTracker,
+    miniscript::DescriptorPublicKey,
+    BlockId, TxHeight,
+};
+use bdk_cli::{
+    build_tracker, check_maturity, create_psbt, described_utxos, parse_descriptors, parse_txo_age,
+    signer_assets, Keychain, Signer, TxBuilder,
+};
+use common::xprv;
+
+/// An internal key the wallet can't sign for, so coins are spent through the script paths.
+fn unowned() -> ExtendedPubKey {
+    ExtendedPubKey::from_priv(&Secp256k1::new(), &xprv(9))
+}
+
+/// A wallet spending its coins with the script path `leaf` (of the key `xprv(1)`).
+fn tr_with(leaf: &str) -> String {
+    format!(
+        "tr({}/0/*,and_v(v:pk({}/0/*),{}))",
+        unowned(),
+        xprv(1),
+        leaf
+    )
+}
+
+/// The wallet with its tip at `tip_height` and a coin of 100000 sats at `position`.
+fn wallet(
+    descriptor: &str,
+    change_descriptor: Option<&str>,
+    position: TxHeight,
+    tip_height: u32,
+) -> (KeychainTracker<Keychain, TxHeight>, Vec<Box<dyn Signer>>) {
+    let (keychains, keymap) =
+        parse_descriptors(descriptor, change_descriptor).expect("valid descriptor");
+    let mut tracker = build_tracker::<_, TxHeight>(keychains, None);
+    let (_, script_pubkey) = tracker.txout_index.derive_new(&Keychain::External);
+    let funding = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::hash(b"coinbase"), 0),
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: 100_000,
+            script_pubkey: script_pubkey.clone(),
+        }],
+    };
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: tip_height,
+            hash: BlockHash::hash(&tip_height.to_le_bytes()),
+        })
+        .expect("valid checkpoint");
+    let _ = tracker.insert_tx(funding, position).expect("valid tx");
+    (tracker, vec![Box::new(keymap)])
+}
+
+/// Whether the wallet's coin can be spent given `txo_age` (in seconds).
+fn spendable(
+    tracker: &KeychainTracker<Keychain, TxHeight>,
+    signers: &[Box<dyn Signer>],
+    txo_age: Option<&str>,
+) -> bool {
+    let assets = bdk_tmp_plan::Assets::<DescriptorPublicKey> {
+        txo_age: txo_age.map(|age| parse_txo_age(age).unwrap()),
+        ..signer_assets(signers)
+    };
+    let tip_height = tracker
+        .chain()
+        .latest_checkpoint()
+        .map(|block| block.height);
+    let utxo = described_utxos(tracker, &assets)
+        .next()
+        .expect("the wallet has a coin");
+    check_maturity(&utxo, &assets, tip_height).is_ok() && utxo.plan.is_some()
+}
+
+#[test]
+fn an_age_doesnt_unlock_height_based_timelocks() {
+    let descriptor = tr_with("older(10)");
+    // 6 confirmations
+    let (tracker, signers) = wallet(&descriptor, None, TxHeight::Confirmed(95), 100);
+    assert!(!spendable(&tracker, &signers, None));
+    assert!(!spendable(&tracker, &signers, Some("30000000")));
+    // the tenth confirmation
+    let (tracker, signers) = wallet(&descriptor, None, TxHeight::Confirmed(95), 104);
+    assert!(spendable(&tracker, &signers, None));
+    assert!(spendable(&tracker, &signers, Some("0")));
+}
+
+#[test]
+fn time_based_timelocks_need_the_age() {
+    // 16 times 512 seconds
+    let descriptor = tr_with("older(4194320)");
+    let (tracker, signers) = wallet(&descriptor, None, TxHeight::Confirmed(95), 100);
+    let utxo_error = {
+        let assets = signer_assets(&signers);
+        let utxo = described_utxos(&tracker, &assets).next().unwrap();
+        check_maturity(&utxo, &assets, Some(100)).unwrap_err()
+    };
+    assert!(utxo_error.older.is_time_locked());
+    assert!(!spendable(&tracker, &signers, None));
+    assert!(!spendable(&tracker, &signers, Some("8000")));
+    assert!(spendable(&tracker, &signers, Some("8192")));
+
+    // an unconfirmed coin has no age
+    let (tracker, signers) = wallet(&descriptor, None, TxHeight::Unconfirmed, 100);
+    assert!(!spendable(&tracker, &signers, Some("8192")));
+}
+
+#[test]
+fn height_based_ages_are_refused() {
+    let (mut tracker, signers) = wallet(&tr_with("older(10)"), None, TxHeight::Confirmed(95), 104);
+    let builder = TxBuilder {
+        txo_age: Some(Sequence::from_height(10)),
+        ..Default::default()
+    };
+    let error = create_psbt(&[], &builder, &mut tracker, &signer_assets(&signers)).unwrap_err();
+    assert!(error.to_string().contains("512 seconds"), "{}", error);
+    assert!(parse_txo_age(&(512 * 65536).to_string()).is_err());
+}
+
+#[test]
+fn change_only_timelocked_branches_can_spend_is_planned() {
+    let descriptor = format!("tr({}/0/*)", xprv(1));
+    let payment = TxOut {
+        value: 50_000,
+        script_pubkey: Address::p2wpkh(
+            &bdk_chain::bitcoin::PublicKey::new(xprv(7).private_key.public_key(&Secp256k1::new())),
+            Network::Testnet,
+        )
+        .unwrap()
+        .script_pubkey(),
+    };
+    for change_leaf in [
+        "older(10)",
+        "older(4194320)",
+        "after(1000000)",
+        "after(500000001)",
+    ] {
+        let change_descriptor = format!(
+            "tr({}/1/*,and_v(v:pk({}/1/*),{}))",
+            unowned(),
+            xprv(1),
+            change_leaf
+        );
+        let (mut tracker, signers) = wallet(
+            &descriptor,
+            Some(&change_descriptor),
+            TxHeight::Confirmed(95),
+            100,
+        );
+        let (psbt, _) = create_psbt(
+            std::slice::from_ref(&payment),
+            &TxBuilder::default(),
+            &mut tracker,
+            &signer_assets(&signers),
+        )
+        .unwrap_or_else(|e| panic!("change with {}: {}", change_leaf, e));
+        assert_eq!(
+            psbt.unsigned_tx.output.len(),
+            2,
+            "change with {}",
+            change_leaf
+        );
+    }
+
+    // change we can't spend at all
+    let change_descriptor = format!("tr({}/1/*)", unowned());
+    let (mut tracker, signers) = wallet(
+        &descriptor,
+        Some(&change_descriptor),
+        TxHeight::Confirmed(95),
+        100,
+    );
+    let error = create_psbt(
+        &[payment],
+        &TxBuilder::default(),
+        &mut tracker,
+        &signer_assets(&signers),
+    )
+    .unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("can't spend the change keychain"),
+        "{}",
+        error
+    );
+}
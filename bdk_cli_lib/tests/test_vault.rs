@@ -0,0 +1,87 @@
+mod common;
+use bdk_chain::{
+    bitcoin::{
+        hashes::Hash, secp256k1::Secp256k1, util::bip32::ExtendedPubKey, BlockHash, OutPoint,
+        PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    },
+    keychain::KeychainTracker,
+    BlockId, TxHeight,
+};
+use bdk_cli::{build_tracker, parse_descriptors, run_vault_cmd, Keychain, VaultCmd};
+use common::xprv;
+
+/// A wallet of `descriptor` with its tip at `tip_height` and a coin confirmed at `height`.
+fn vault(descriptor: &str, height: u32, tip_height: u32) -> KeychainTracker<Keychain, TxHeight> {
+    let (keychains, _) = parse_descriptors(descriptor, None).expect("valid descriptor");
+    let mut tracker = build_tracker::<_, TxHeight>(keychains, None);
+    let (_, script_pubkey) = tracker.txout_index.derive_new(&Keychain::External);
+    let funding = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::hash(b"coinbase"), 0),
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: 100_000,
+            script_pubkey: script_pubkey.clone(),
+        }],
+    };
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: tip_height,
+            hash: BlockHash::hash(&tip_height.to_le_bytes()),
+        })
+        .expect("valid checkpoint");
+    let _ = tracker
+        .insert_tx(funding, TxHeight::Confirmed(height))
+        .expect("valid tx");
+    tracker
+}
+
+fn statuses(descriptor: &str, height: u32, tip_height: u32) -> Vec<(Option<u32>, String)> {
+    let tracker = vault(descriptor, height, tip_height);
+    run_vault_cmd(VaultCmd::Status { mtp: Some(0) }, &tracker)
+        .unwrap()
+        .into_iter()
+        .map(|status| (status.older.or(status.after), status.status))
+        .collect()
+}
+
+#[test]
+fn wsh_recovery_branch_unlocks_after_older() {
+    let descriptor = format!(
+        "wsh(or_d(pk({}/0/*),and_v(v:pk({}/0/*),older(10))))",
+        xprv(1),
+        xprv(2)
+    );
+    // the tenth confirmation of a coin confirmed at 95 is at 104
+    assert_eq!(
+        statuses(&descriptor, 95, 100),
+        vec![(Some(10), "locked until height:104".to_string())]
+    );
+    assert_eq!(
+        statuses(&descriptor, 95, 104),
+        vec![(Some(10), "spendable".to_string())]
+    );
+}
+
+#[test]
+fn tr_leaves_with_after_and_older() {
+    let internal = ExtendedPubKey::from_priv(&Secp256k1::new(), &xprv(3));
+    let descriptor = format!(
+        "tr({}/0/*,{{and_v(v:pk({}/0/*),after(200)),and_v(v:pk({}/0/*),older(5))}})",
+        internal,
+        xprv(1),
+        xprv(2)
+    );
+    assert_eq!(
+        statuses(&descriptor, 150, 180),
+        vec![
+            (Some(200), "locked until height:200".to_string()),
+            (Some(5), "spendable".to_string()),
+        ]
+    );
+}
@@ -0,0 +1,113 @@
+use bdk_chain::bitcoin::{hashes::Hash, Txid};
+use bdk_cli::{
+    anyhow::{anyhow, Result},
+    webhook::{sign_payload, WalletEvent, Webhook, WebhookDispatcher, WebhookTransport, Webhooks},
+};
+use std::time::Duration;
+
+/// A request a webhook was sent: its URL, headers and payload.
+type Post = (String, Vec<(String, String)>, String);
+
+/// Records the requests it is asked to make and fails them if `fail` is set.
+#[derive(Default)]
+struct Recorder {
+    fail: bool,
+    posts: Vec<Post>,
+}
+
+impl WebhookTransport for Recorder {
+    fn post(&mut self, url: &str, headers: &[(&str, String)], payload: &str) -> Result<()> {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        self.posts.push((url.into(), headers, payload.into()));
+        match self.fail {
+            true => Err(anyhow!("unreachable")),
+            false => Ok(()),
+        }
+    }
+}
+
+fn webhooks(hooks: &[(&str, Option<&str>)]) -> Webhooks {
+    Webhooks {
+        webhooks: hooks
+            .iter()
+            .map(|(url, secret)| {
+                let webhook = Webhook {
+                    secret: secret.map(String::from),
+                    ..Default::default()
+                };
+                (url.to_string(), webhook)
+            })
+            .collect(),
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header == name)
+        .map(|(_, value)| value.as_str())
+}
+
+#[test]
+fn signatures_cover_the_timestamp() {
+    let hooks = webhooks(&[("http://a", Some("secret")), ("http://b", None)]);
+    let mut dispatcher = WebhookDispatcher::new(Recorder::default(), hooks);
+    dispatcher
+        .queue(&[WalletEvent::Reorg { height: 100 }])
+        .unwrap();
+    assert_eq!(dispatcher.dispatch(), 2);
+
+    let posts = &dispatcher.transport.posts;
+    let (url, headers, payload) = &posts[0];
+    assert_eq!(url, "http://a");
+    assert_eq!(payload, r#"{"event":"reorg","height":100}"#);
+    let timestamp = header(headers, "X-Webhook-Timestamp").expect("the time is sent");
+    let signature = header(headers, "X-Webhook-Signature").expect("the payload is signed");
+    assert_eq!(
+        signature,
+        format!(
+            "sha256={}",
+            sign_payload("secret", timestamp.parse().unwrap(), payload)
+        )
+    );
+    // replaying the payload at another time needs another signature
+    assert_ne!(
+        signature,
+        format!(
+            "sha256={}",
+            sign_payload("secret", timestamp.parse::<u64>().unwrap() + 1, payload)
+        )
+    );
+
+    let (url, headers, _) = &posts[1];
+    assert_eq!(url, "http://b");
+    assert!(header(headers, "X-Webhook-Timestamp").is_some());
+    assert_eq!(header(headers, "X-Webhook-Signature"), None);
+}
+
+#[test]
+fn retries_more_than_32_times() {
+    let transport = Recorder {
+        fail: true,
+        ..Default::default()
+    };
+    let mut dispatcher = WebhookDispatcher::new(transport, webhooks(&[("http://a", None)]));
+    dispatcher.max_attempts = 40;
+    dispatcher.retry_interval = Duration::ZERO;
+    dispatcher
+        .queue(&[WalletEvent::TxEvicted {
+            txid: Txid::all_zeros(),
+        }])
+        .unwrap();
+
+    for _ in 1..40 {
+        assert_eq!(dispatcher.dispatch(), 0);
+        assert_eq!(dispatcher.pending(), 1);
+    }
+    assert_eq!(dispatcher.dispatch(), 0);
+    assert_eq!(dispatcher.pending(), 0);
+    assert_eq!(dispatcher.transport.posts.len(), 40);
+}
@@ -1,4 +1,5 @@
 use super::*;
+use bdk_chain::cancel::CancellationToken;
 
 /// Strategy in which we should branch.
 pub enum BranchStrategy {
@@ -204,6 +205,19 @@ impl From<core::time::Duration> for BnbLimit {
 /// TODO: Another optimization we could do is figure out candidate with smallest waste, and
 /// if we find a result with waste equal to this, we can just break.
 pub fn coin_select_bnb<L>(limit: L, selector: CoinSelector) -> Option<CoinSelector>
+where
+    L: Into<BnbLimit>,
+{
+    coin_select_bnb_cancellable(limit, selector, &CancellationToken::default())
+}
+
+/// [`coin_select_bnb`] that also stops searching once `cancel` is cancelled. Like reaching the
+/// `limit`, this returns the best selection found so far (if any).
+pub fn coin_select_bnb_cancellable<'a, L>(
+    limit: L,
+    selector: CoinSelector<'a>,
+    cancel: &CancellationToken,
+) -> Option<CoinSelector<'a>>
 where
     L: Into<BnbLimit>,
 {
@@ -255,12 +269,21 @@ where
             return (BranchStrategy::SkipBoth, None);
         }
 
-        // solution?
-        if selected_abs >= target_abs && selected_eff >= target_eff {
+        // solution? the selection also has to meet what the bounds above don't account for, e.g.
+        // the minimum drain value when there is no target value
+        if selected_abs >= target_abs
+            && selected_eff >= target_eff
+            && bnb.selection.finish().is_ok()
+        {
             let waste = selected_waste + bnb.selection.current_excess();
             return (BranchStrategy::SkipBoth, Some(waste));
         }
 
+        // nothing left to select
+        if bnb.pool_pos == bnb.pool.len() {
+            return (BranchStrategy::SkipBoth, None);
+        }
+
         // early bailout optimization:
         // If the candidate at the previous position is NOT selected and has the same weight and
         // value as the current candidate, we can skip selecting the current candidate.
@@ -296,16 +319,19 @@ where
     }
 
     match limit.into() {
-        BnbLimit::Rounds(rounds) => {
-            bnb.into_iter(&strategy)
-                .take(rounds)
-                .reduce(|b, c| if c.is_some() { c } else { b })
-        }
+        BnbLimit::Rounds(rounds) => bnb
+            .into_iter(&strategy)
+            .take(rounds)
+            .take_while(|_| !cancel.is_cancelled())
+            .reduce(|b, c| if c.is_some() { c } else { b }),
         #[cfg(feature = "std")]
         BnbLimit::Duration(duration) => {
             let start = std::time::SystemTime::now();
             bnb.into_iter(&strategy)
-                .take_while(|_| start.elapsed().expect("failed to get system time") <= duration)
+                .take_while(|_| {
+                    start.elapsed().expect("failed to get system time") <= duration
+                        && !cancel.is_cancelled()
+                })
                 .reduce(|b, c| if c.is_some() { c } else { b })
         }
     }?
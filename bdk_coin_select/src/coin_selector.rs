@@ -291,6 +291,55 @@ impl<'a> CoinSelector<'a> {
         selection
     }
 
+    /// Checks that every excess strategy of `selection` (which must have been finished from the
+    /// coins `self` has selected) is a transaction we would be happy to make: it spends exactly
+    /// the selected value, pays the target and at least the target feerate and minimum fee, has
+    /// the weight of the selected inputs plus the template (and the drain output if it has one)
+    /// and never makes a drain output below the minimum drain value.
+    pub fn check_selection(&self, selection: &Selection) -> Result<(), InvariantViolation> {
+        if selection.selected != self.selected {
+            return Err(InvariantViolation::SelectionMismatch);
+        }
+        let selected = self.selected_absolute_value();
+        for (&kind, strategy) in &selection.excess_strategies {
+            let recipient_value = strategy.recipient_value.unwrap_or(0);
+            let drain_value = strategy.drain_value.unwrap_or(0);
+            let violation = |violation| Err(InvariantViolation::Strategy { kind, violation });
+
+            if recipient_value + drain_value + strategy.fee != selected {
+                return violation(StrategyViolation::ValueNotConserved { selected });
+            }
+            if self.opts.target_value.is_some() != strategy.recipient_value.is_some()
+                || recipient_value < self.opts.target_value.unwrap_or(0)
+            {
+                return violation(StrategyViolation::TargetNotPaid);
+            }
+            let expected_weight = self.current_weight()
+                + strategy
+                    .drain_value
+                    .map(|_| self.opts.drain_weight)
+                    .unwrap_or(0);
+            if strategy.weight != expected_weight {
+                return violation(StrategyViolation::WeightMismatch { expected_weight });
+            }
+            let required_fee = ((strategy.weight as f32 * self.opts.target_feerate).ceil() as u64)
+                .max(self.opts.min_absolute_fee);
+            if strategy.fee < required_fee {
+                return violation(StrategyViolation::FeeTooLow { required_fee });
+            }
+            match strategy.drain_value {
+                Some(drain_value) if drain_value < self.opts.min_drain_value => {
+                    return violation(StrategyViolation::DrainBelowMinimum)
+                }
+                None if self.opts.target_value.is_none() => {
+                    return violation(StrategyViolation::NoOutputs)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     pub fn finish(&self) -> Result<Selection, SelectionError> {
         let weight_without_drain = self.current_weight();
         let weight_with_drain = weight_without_drain + self.opts.drain_weight;
@@ -324,7 +373,8 @@ impl<'a> CoinSelector<'a> {
                     // the selected amount can satisfy requirements for a drain output (so we at
                     // least have one txout)
                     if self.opts.target_value.is_none() {
-                        (fee_with_drain + self.opts.min_drain_value).saturating_sub(selected)
+                        (fee_with_drain.max(self.opts.min_absolute_fee) + self.opts.min_drain_value)
+                            .saturating_sub(selected)
                     } else {
                         0
                     },
@@ -467,6 +517,69 @@ impl core::fmt::Display for SelectionConstraint {
     }
 }
 
+/// A way in which a [`Selection`] breaks what [`CoinSelector::check_selection`] expects of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvariantViolation {
+    /// The selection isn't of the coins the selector has selected
+    SelectionMismatch,
+    /// One of the excess strategies of the selection is wrong
+    Strategy {
+        kind: ExcessStrategyKind,
+        violation: StrategyViolation,
+    },
+}
+
+/// How an [`ExcessStrategy`] is wrong.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StrategyViolation {
+    /// The outputs and the fee don't add up to the selected value
+    ValueNotConserved { selected: u64 },
+    /// The recipients get less than the target value (or there are recipients without a target)
+    TargetNotPaid,
+    /// The weight isn't that of the template and selected inputs (and drain output)
+    WeightMismatch { expected_weight: u32 },
+    /// The fee is below the target feerate or the minimum absolute fee
+    FeeTooLow { required_fee: u64 },
+    /// The drain output is worth less than the minimum drain value
+    DrainBelowMinimum,
+    /// Without a target value the transaction would have no outputs
+    NoOutputs,
+}
+
+impl core::fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InvariantViolation::SelectionMismatch => {
+                write!(f, "the selection isn't of the selected coins")
+            }
+            InvariantViolation::Strategy { kind, violation } => {
+                write!(f, "excess strategy {}: ", kind)?;
+                match violation {
+                    StrategyViolation::ValueNotConserved { selected } => write!(
+                        f,
+                        "the outputs and the fee don't add up to the {} sats selected",
+                        selected
+                    ),
+                    StrategyViolation::TargetNotPaid => write!(f, "the target isn't paid"),
+                    StrategyViolation::WeightMismatch { expected_weight } => {
+                        write!(f, "the weight should be {}", expected_weight)
+                    }
+                    StrategyViolation::FeeTooLow { required_fee } => {
+                        write!(f, "the fee should be at least {}", required_fee)
+                    }
+                    StrategyViolation::DrainBelowMinimum => {
+                        write!(f, "the drain output is below the minimum drain value")
+                    }
+                    StrategyViolation::NoOutputs => write!(f, "there are no outputs"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvariantViolation {}
+
 #[derive(Clone, Debug)]
 pub struct Selection {
     pub selected: BTreeSet<usize>,
@@ -528,7 +641,11 @@ impl ExcessStrategy {
 mod test {
     use crate::{ExcessStrategyKind, SelectionConstraint};
 
-    use super::{CoinSelector, CoinSelectorOpt, WeightedValue};
+    use super::{
+        CoinSelector, CoinSelectorOpt, InvariantViolation, StrategyViolation, WeightedValue,
+    };
+    use crate::coin_select_bnb;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     /// Ensure `target_value` is respected. Can't have no disrespect.
     #[test]
@@ -609,9 +726,106 @@ mod test {
         assert!(strategy.drain_value.is_some());
     }
 
-    /// TODO: Tests to add:
-    /// * `finish` should ensure at least `target_value` is selected.
-    /// * actual feerate should be equal or higher than `target_feerate`.
-    /// * actual drain value should be equal or higher than `min_drain_value` (or else no drain).
-    fn _todo() {}
+    fn random_candidates(rng: &mut StdRng) -> super::Vec<WeightedValue> {
+        (0..rng.gen_range(0..20))
+            .map(|_| {
+                WeightedValue::new(
+                    rng.gen_range(0..100_000),
+                    rng.gen_range(0..600),
+                    rng.gen_bool(0.5),
+                )
+            })
+            .collect()
+    }
+
+    fn random_opts(rng: &mut StdRng) -> CoinSelectorOpt {
+        CoinSelectorOpt {
+            target_value: rng.gen_bool(0.8).then(|| rng.gen_range(0..200_000)),
+            max_extra_target: if rng.gen_bool(0.5) {
+                0
+            } else {
+                rng.gen_range(0..1_000)
+            },
+            target_feerate: rng.gen_range(0.0..10.0),
+            long_term_feerate: rng.gen_bool(0.5).then(|| rng.gen_range(0.0..10.0)),
+            min_absolute_fee: rng.gen_range(0..2_000),
+            base_weight: rng.gen_range(40..1_000),
+            drain_weight: rng.gen_range(0..200),
+            spend_drain_weight: rng.gen_range(0..300),
+            min_drain_value: rng.gen_range(0..1_000),
+        }
+    }
+
+    /// Whatever the candidates and options, a selection that succeeds spends exactly what it
+    /// selects, pays the target, reaches the feerate and minimum fee and keeps the drain above its
+    /// minimum. One that fails has run out of candidates.
+    #[test]
+    fn random_selections_uphold_invariants() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for round in 0..5_000 {
+            let candidates = random_candidates(&mut rng);
+            let opts = random_opts(&mut rng);
+            let mut selector = CoinSelector::new(&candidates, &opts);
+            // some coins are picked by hand before the rest are selected
+            for index in 0..candidates.len() {
+                if rng.gen_bool(0.2) {
+                    selector.select(index);
+                }
+            }
+
+            if let Some(bnb) = coin_select_bnb(1_000, selector.clone()) {
+                let selection = bnb.finish().expect("bnb only returns finished selections");
+                if let Err(violation) = bnb.check_selection(&selection) {
+                    panic!("round {}: bnb: {}\n{:?}\n{:?}", round, violation, opts, bnb);
+                }
+            }
+
+            match selector.select_until_finished() {
+                Ok(selection) => {
+                    if let Err(violation) = selector.check_selection(&selection) {
+                        panic!("round {}: {}\n{:?}\n{:?}", round, violation, opts, selector);
+                    }
+                }
+                Err(_) => assert!(selector.all_selected(), "round {}", round),
+            }
+        }
+    }
+
+    #[test]
+    fn check_selection_catches_bad_strategies() {
+        let candidates = super::Vec::from([WeightedValue::new(10_000, 200, true)]);
+        let opts = CoinSelectorOpt {
+            target_value: Some(5_000),
+            max_extra_target: 0,
+            target_feerate: 1.0,
+            long_term_feerate: None,
+            min_absolute_fee: 0,
+            base_weight: 200,
+            drain_weight: 100,
+            spend_drain_weight: 100,
+            min_drain_value: 500,
+        };
+        let mut selector = CoinSelector::new(&candidates, &opts);
+        let mut selection = selector.select_until_finished().expect("enough value");
+        assert_eq!(selector.check_selection(&selection), Ok(()));
+
+        let drain = selection
+            .excess_strategies
+            .get_mut(&ExcessStrategyKind::ToDrain)
+            .expect("there is enough left for change");
+        drain.fee -= 1;
+        assert_eq!(
+            selector.check_selection(&selection),
+            Err(InvariantViolation::Strategy {
+                kind: ExcessStrategyKind::ToDrain,
+                violation: StrategyViolation::ValueNotConserved { selected: 10_000 },
+            })
+        );
+
+        selector.deselect(0);
+        assert_eq!(
+            selector.check_selection(&selection),
+            Err(InvariantViolation::SelectionMismatch)
+        );
+    }
 }
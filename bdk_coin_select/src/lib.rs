@@ -6,6 +6,8 @@ extern crate std;
 #[macro_use]
 extern crate alloc;
 extern crate bdk_chain;
+#[cfg(test)]
+extern crate rand;
 
 use alloc::vec::Vec;
 use bdk_chain::{
@@ -21,10 +23,24 @@ pub use coin_selector::*;
 mod bnb;
 pub use bnb::*;
 
+mod spend_cost;
+pub use spend_cost::*;
+
 /// Txin "base" fields include `outpoint` (32+4) and `nSequence` (4). This does not include
 /// `scriptSigLen` or `scriptSig`.
 pub const TXIN_BASE_WEIGHT: u32 = (32 + 4 + 4) * 4;
 
+/// Converts a feerate in sats per vbyte to sats per weight unit, the unit of
+/// [`CoinSelectorOpt::target_feerate`].
+pub fn sat_per_vb_to_wu(sat_per_vb: f32) -> f32 {
+    sat_per_vb / bitcoin::blockdata::constants::WITNESS_SCALE_FACTOR as f32
+}
+
+/// Converts a feerate in sats per weight unit to sats per vbyte.
+pub fn sat_per_wu_to_vb(sat_per_wu: f32) -> f32 {
+    sat_per_wu * bitcoin::blockdata::constants::WITNESS_SCALE_FACTOR as f32
+}
+
 /// Helper to calculate varint size. `v` is the value the varint represents.
 // Shamelessly copied from
 // https://github.com/rust-bitcoin/rust-miniscript/blob/d5615acda1a7fdc4041a11c1736af139b8c7ebe8/src/util.rs#L8
@@ -0,0 +1,98 @@
+//! Whether an output is worth spending at a given feerate.
+//!
+//! An output is uneconomical when the fee for the input spending it is at least as large as its
+//! value. Wallets can use [`min_economical_value`] (or one of its variants) to warn about incoming
+//! payments that would cost more to spend than they are worth. This is different from the dust
+//! limit bitcoind enforces when relaying which doesn't depend on how the output is spent (see
+//! `bdk_chain::standardness::dust_threshold`).
+use super::*;
+use bdk_chain::miniscript::{Descriptor, MiniscriptKey};
+use bitcoin::Script;
+
+/// The satisfaction weight of spending a P2PKH output: a scriptSig with a 72 byte signature and a
+/// 33 byte public key (plus the pushes and the scriptSig length).
+pub const P2PKH_SATISFACTION_WEIGHT: u32 = (1 + 1 + 72 + 1 + 33) * 4;
+
+/// The satisfaction weight of spending a P2WPKH output: an empty scriptSig and a witness with a 72
+/// byte signature and a 33 byte public key.
+pub const P2WPKH_SATISFACTION_WEIGHT: u32 = 4 + 1 + 1 + 72 + 1 + 33;
+
+/// The satisfaction weight of a P2TR key path spend: an empty scriptSig and a witness with a 64
+/// byte schnorr signature (using the default sighash).
+pub const P2TR_KEY_SPEND_SATISFACTION_WEIGHT: u32 = 4 + 1 + 1 + 64;
+
+/// The smallest value an input with `satisfaction_weight` (see [`WeightedValue::new`]) must have
+/// to be worth spending at `feerate` (in sats per weight unit), i.e. to have a positive effective
+/// value.
+pub fn min_economical_value(satisfaction_weight: u32, feerate: f32) -> u64 {
+    let candidate = WeightedValue::new(0, satisfaction_weight, false);
+    candidate.effective_value(feerate).unsigned_abs() + 1
+}
+
+/// Like [`min_economical_value`] for an output of `descriptor`.
+///
+/// Uses the descriptor's maximum satisfaction weight so the result is an upper bound. Returns
+/// `None` if the descriptor can't be satisfied.
+pub fn min_economical_value_of_descriptor<Pk: MiniscriptKey>(
+    descriptor: &Descriptor<Pk>,
+    feerate: f32,
+) -> Option<u64> {
+    let satisfaction_weight = descriptor.max_satisfaction_weight().ok()?;
+    Some(min_economical_value(satisfaction_weight as u32, feerate))
+}
+
+/// Like [`min_economical_value`] for an output with `script_pubkey` when we don't know its
+/// descriptor.
+///
+/// Only single key script types (P2PKH, P2WPKH and P2TR spent with the key path) can be told
+/// apart by their script pubkey, `None` is returned for anything else.
+pub fn min_economical_value_of_spk(script_pubkey: &Script, feerate: f32) -> Option<u64> {
+    let satisfaction_weight = if script_pubkey.is_p2pkh() {
+        P2PKH_SATISFACTION_WEIGHT
+    } else if script_pubkey.is_v0_p2wpkh() {
+        P2WPKH_SATISFACTION_WEIGHT
+    } else if script_pubkey.is_v1_p2tr() {
+        P2TR_KEY_SPEND_SATISFACTION_WEIGHT
+    } else {
+        return None;
+    };
+    Some(min_economical_value(satisfaction_weight, feerate))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{
+        hashes::Hash,
+        util::address::{Address, Payload},
+        PubkeyHash, WPubkeyHash,
+    };
+
+    #[test]
+    fn economical_value_covers_spend_fee() {
+        // at 1 sat/vb a P2WPKH input (272 wu) costs 68 sats
+        let value = min_economical_value(P2WPKH_SATISFACTION_WEIGHT, 0.25);
+        assert_eq!(value, 69);
+        let candidate = WeightedValue::new(value, P2WPKH_SATISFACTION_WEIGHT, true);
+        assert!(candidate.effective_value(0.25) > 0);
+        let candidate = WeightedValue::new(value - 1, P2WPKH_SATISFACTION_WEIGHT, true);
+        assert_eq!(candidate.effective_value(0.25), 0);
+
+        assert_eq!(min_economical_value(P2WPKH_SATISFACTION_WEIGHT, 0.0), 1);
+    }
+
+    #[test]
+    fn economical_value_of_spk_types() {
+        let p2pkh = Address {
+            payload: Payload::PubkeyHash(PubkeyHash::all_zeros()),
+            network: bitcoin::Network::Bitcoin,
+        }
+        .script_pubkey();
+        let p2wpkh = Script::new_v0_p2wpkh(&WPubkeyHash::all_zeros());
+
+        let legacy = min_economical_value_of_spk(&p2pkh, 2.5).unwrap();
+        let segwit = min_economical_value_of_spk(&p2wpkh, 2.5).unwrap();
+        assert!(legacy > segwit);
+        assert_eq!(min_economical_value_of_spk(&Script::new(), 2.5), None);
+    }
+}
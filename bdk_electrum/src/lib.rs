@@ -0,0 +1,1282 @@
+//! An [Electrum] chain source for `bdk_chain`.
+//!
+//! [`ElectrumClient::wallet_scan`] scans the script pubkeys of each keychain and returns a
+//! [`KeychainScan`] which can be turned into a changeset with
+//! [`KeychainTracker::determine_changeset`]. To avoid downloading transactions the wallet already
+//! has you can instead do a [`wallet_txid_scan`], determine the changeset of the resulting
+//! [`SparseChain`] and fetch only the transactions it adds before inflating it with
+//! [`ChainGraph::inflate_changeset`]. [`inflate_txid_update`] does both and tells a reorg that
+//! happened in between apart from other failures.
+//!
+//! [`ElectrumClient`] is also a [`TipStream`]: it subscribes to the server's headers and reports
+//! each new tip (and the blocks a reorg replaced) as [`TipEvent`]s.
+//!
+//! [`ElectrumClient::estimate_feerate`] asks the server what feerate gets a transaction confirmed
+//! in time.
+//!
+//! [`ElectrumClient::server_info`] describes the server: its software, the protocol version it
+//! speaks, its tip and its relay fee. The protocol version also decides which requests a scan or
+//! fee estimate makes and how many scripts a scan asks about at once.
+//!
+//! [Electrum]: https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html
+//! [`KeychainTracker::determine_changeset`]: bdk_chain::keychain::KeychainTracker::determine_changeset
+//! [`wallet_txid_scan`]: ElectrumClient::wallet_txid_scan
+//! [`ChainGraph::inflate_changeset`]: bdk_chain::chain_graph::ChainGraph::inflate_changeset
+use std::{
+    collections::{BTreeMap, VecDeque},
+    mem,
+    ops::Deref,
+};
+
+use bdk_chain::{
+    bitcoin::{
+        blockdata::constants::{MAX_BLOCK_WEIGHT, WITNESS_SCALE_FACTOR},
+        hashes::{sha256d, Hash, HashEngine},
+        util::uint::Uint256,
+        BlockHash, BlockHeader, Network, Script, Transaction, TxMerkleNode, Txid,
+    },
+    cancel::CancellationToken,
+    chain_graph::{self, ChainGraph},
+    keychain::KeychainScan,
+    sparse_chain::{self, SparseChain},
+    tip::{RecentBlocks, TipEvent, TipStream},
+    tx_graph::TxGraph,
+    BlockId, TxHeight,
+};
+pub use electrum_client;
+use electrum_client::{
+    Client, Config, ElectrumApi, GetHistoryRes, GetMerkleRes, ServerFeaturesRes,
+};
+
+/// The electrum status of a script: a hash that changes whenever the script's history does.
+pub type StatusHash = [u8; 32];
+
+/// A version of the Electrum protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl ProtocolVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parses a version as servers report it, e.g. `1.4` or `1.4.2`.
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.trim().split('.').map(|part| part.parse::<u32>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next()?.ok()?;
+        let patch = match parts.next() {
+            Some(patch) => patch.ok()?,
+            None => 0,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self::new(major, minor, patch))
+    }
+
+    /// Whether the server answers `mempool.get_fee_histogram` (added in 1.2).
+    pub fn has_fee_histogram(&self) -> bool {
+        *self >= ProtocolVersion::new(1, 2, 0)
+    }
+
+    /// The most scripts whose histories are requested in one batch. Servers older than 1.4 are
+    /// mostly old ElectrumX versions whose per session resource limits big batches run into.
+    pub fn max_batch_size(&self) -> usize {
+        if *self >= ProtocolVersion::new(1, 4, 0) {
+            usize::MAX
+        } else {
+            LEGACY_BATCH_SIZE
+        }
+    }
+}
+
+impl core::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.patch != 0 {
+            write!(f, ".{}", self.patch)?;
+        }
+        Ok(())
+    }
+}
+
+/// The newest protocol version the client speaks.
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 4, 0);
+
+/// The batch size of servers older than protocol 1.4, see [`ProtocolVersion::max_batch_size`].
+pub const LEGACY_BATCH_SIZE: usize = 10;
+
+/// What an Electrum server told us about itself, see [`ElectrumClient::server_info`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerInfo {
+    /// The url of the server if we know it
+    pub url: Option<String>,
+    /// The name and version of the server software, e.g. `ElectrumX 1.16.0`
+    pub software: String,
+    /// The newest protocol version both the server and the client speak
+    pub protocol_version: ProtocolVersion,
+    /// The oldest and newest protocol versions the server speaks
+    pub protocol_range: (ProtocolVersion, ProtocolVersion),
+    pub genesis_hash: BlockHash,
+    /// The height below which the server's node has pruned blocks (if it has)
+    pub pruning: Option<i64>,
+    /// The message of the day the operator of the server set
+    pub banner: String,
+    pub tip: BlockId,
+    /// The lowest feerate (in sats per vbyte) of transactions the server's node relays
+    pub relay_feerate: f32,
+}
+
+impl core::fmt::Display for ServerInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if let Some(url) = &self.url {
+            writeln!(f, "server: {}", url)?;
+        }
+        writeln!(f, "software: {}", self.software)?;
+        writeln!(
+            f,
+            "protocol: {} (the server speaks {} to {})",
+            self.protocol_version, self.protocol_range.0, self.protocol_range.1
+        )?;
+        writeln!(f, "genesis: {}", self.genesis_hash)?;
+        if let Some(pruning) = self.pruning {
+            writeln!(f, "pruned below: {}", pruning)?;
+        }
+        writeln!(f, "tip: {} at height {}", self.tip.hash, self.tip.height)?;
+        writeln!(f, "relay feerate: {} sat/vB", self.relay_feerate)?;
+        writeln!(
+            f,
+            "fee histogram: {}",
+            if self.protocol_version.has_fee_histogram() {
+                "yes"
+            } else {
+                "no"
+            }
+        )?;
+        let max_batch_size = self.protocol_version.max_batch_size();
+        if max_batch_size != usize::MAX {
+            writeln!(f, "max batch size: {}", max_batch_size)?;
+        }
+        let banner = self.banner.trim();
+        if !banner.is_empty() {
+            writeln!(f, "banner:")?;
+            for line in banner.lines() {
+                writeln!(f, "  {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An error that occurred while creating an update.
+#[derive(Debug)]
+pub enum ElectrumError {
+    Client(electrum_client::Error),
+    Reorg,
+    /// The transactions the server returned don't fit into the update
+    InsertTx(chain_graph::InsertTxError<TxHeight>),
+    /// The server didn't return a transaction it told us about
+    MissingTx(Txid),
+    /// No servers were given to connect to
+    NoServers,
+    /// The server couldn't prove that the transaction is in the block at the height it claimed
+    InvalidProof {
+        txid: Txid,
+        height: u32,
+    },
+    /// The update doesn't connect to (or is inconsistent with) the chain it is for
+    Update(sparse_chain::UpdateError<TxHeight>),
+    /// The fetched transactions couldn't be turned into a changeset
+    Inflate(chain_graph::InflateError<TxHeight>),
+    /// The block a scan started from was reorged out before the scan finished, on each of
+    /// [`ElectrumClient::scan_attempts`] attempts
+    TipMoved {
+        attempts: usize,
+    },
+    /// [`ElectrumClient::cancel`] was cancelled
+    Cancelled,
+}
+
+impl ElectrumError {
+    /// Whether scanning again may succeed because the error came from the server's chain (or the
+    /// wallet's) changing during the scan rather than from a bad or unreachable server.
+    pub fn is_retriable(&self) -> bool {
+        matches!(
+            self,
+            ElectrumError::Reorg
+                | ElectrumError::TipMoved { .. }
+                | ElectrumError::MissingTx(_)
+                | ElectrumError::Update(
+                    sparse_chain::UpdateError::NotConnected(_)
+                        | sparse_chain::UpdateError::TxInconsistent { .. }
+                )
+                | ElectrumError::Inflate(chain_graph::InflateError::Missing(_))
+        )
+    }
+}
+
+impl core::fmt::Display for ElectrumError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ElectrumError::Client(e) => write!(f, "{}", e),
+            ElectrumError::Reorg => write!(
+                f,
+                "Reorg detected at sync time. Please run the sync call again"
+            ),
+            ElectrumError::InsertTx(e) => write!(f, "{}", e),
+            ElectrumError::NoServers => write!(f, "no electrum servers to connect to"),
+            ElectrumError::MissingTx(txid) => {
+                write!(f, "the server didn't return transaction {}", txid)
+            }
+            ElectrumError::InvalidProof { txid, height } => write!(
+                f,
+                "the server claimed {} is confirmed at height {} but couldn't prove it",
+                txid, height
+            ),
+            ElectrumError::Update(e) => write!(f, "{}", e),
+            ElectrumError::Inflate(e) => write!(f, "{}", e),
+            ElectrumError::TipMoved { attempts } => write!(
+                f,
+                "the chain reorganized during each of the {} attempts to scan it",
+                attempts
+            ),
+            ElectrumError::Cancelled => write!(f, "the request to the server was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for ElectrumError {}
+
+impl From<electrum_client::Error> for ElectrumError {
+    fn from(e: electrum_client::Error) -> Self {
+        Self::Client(e)
+    }
+}
+
+/// How many blocks pass between adjustments of the proof of work target.
+pub const RETARGET_INTERVAL: u32 = 2016;
+
+/// What the header of a block is checked against before a merkle proof into it is accepted.
+///
+/// The header's own target says nothing about how much work went into it, so the target must be
+/// within the proof of work limit of `network`. On mainnet it must also be reachable from the
+/// target of the `trusted` header since the target can get at most 4 times easier (or harder) each
+/// [`RETARGET_INTERVAL`] blocks. The test networks allow minimum difficulty blocks so only the
+/// limit applies to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderCheck {
+    pub network: Network,
+    /// The height and header of a block known to be in the best chain
+    pub trusted: Option<(u32, BlockHeader)>,
+}
+
+impl HeaderCheck {
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            trusted: None,
+        }
+    }
+
+    /// The easiest target a header at `height` is allowed to have.
+    pub fn max_target(&self, height: u32) -> Uint256 {
+        let limit = BlockHeader::u256_from_compact_target(match self.network {
+            Network::Bitcoin | Network::Testnet => 0x1d00ffff,
+            Network::Signet => 0x1e0377ae,
+            Network::Regtest => 0x207fffff,
+        });
+        let (trusted_height, trusted_target) = match (self.network, self.trusted) {
+            (Network::Bitcoin, Some((trusted_height, trusted))) => {
+                (trusted_height, trusted.target())
+            }
+            _ => return limit,
+        };
+        let retargets = (height / RETARGET_INTERVAL).abs_diff(trusted_height / RETARGET_INTERVAL);
+        let shift = 2 * retargets as usize;
+        if trusted_target.bits() + shift > 256 {
+            return limit;
+        }
+        limit.min(trusted_target << shift)
+    }
+
+    /// Checks the proof of work of `header`, the header of the block at `height`, returning the
+    /// hash of the block if it's valid.
+    pub fn validate(&self, height: u32, header: &BlockHeader) -> Option<BlockHash> {
+        if matches!(self.trusted, Some((trusted_height, trusted)) if trusted_height == height && trusted != *header)
+        {
+            return None;
+        }
+        let target = header.target();
+        if target > self.max_target(height) {
+            return None;
+        }
+        header.validate_pow(&target).ok()
+    }
+}
+
+/// An Electrum client that can scan for the transactions of a wallet.
+///
+/// The client can be given other servers to fail over to (see [`connect_any`]). When a request
+/// made during a scan fails it is retried with the next server that can be reached so the scan
+/// continues where it was rather than starting over.
+///
+/// [`connect_any`]: Self::connect_any
+pub struct ElectrumClient {
+    inner: Client,
+    /// The url of `inner` if we know it
+    url: Option<String>,
+    /// The servers to fail over to in the order they will be tried
+    fallbacks: VecDeque<String>,
+    config: Config,
+    /// If set, the merkle proof of every confirmed transaction is checked before its height is
+    /// accepted, against a block header that passes the check
+    pub verify_proofs: Option<HeaderCheck>,
+    /// How many script history requests to make at the same time (each over its own connection)
+    /// when scanning
+    pub parallel_requests: usize,
+    /// How many times a scan is started over when the block it started from is reorged out
+    /// before it finishes
+    pub scan_attempts: usize,
+    /// Once cancelled, requests (and scans between their batches) fail with
+    /// [`ElectrumError::Cancelled`] instead of going to the server
+    pub cancel: CancellationToken,
+    /// The blocks reported by the headers subscription
+    recent_blocks: RecentBlocks,
+    /// Tip events that have been determined but not returned yet
+    tip_events: VecDeque<TipEvent>,
+    /// The url of the server we subscribed to headers on (if we did)
+    tip_subscription: Option<Option<String>>,
+    /// The protocol version of the server at the url (if we asked it)
+    protocol_version: Option<(Option<String>, ProtocolVersion)>,
+}
+
+impl ElectrumClient {
+    pub fn new(client: Client) -> Result<Self, ElectrumError> {
+        Ok(Self {
+            inner: client,
+            url: None,
+            fallbacks: VecDeque::new(),
+            config: Config::default(),
+            verify_proofs: None,
+            parallel_requests: 1,
+            scan_attempts: 3,
+            cancel: CancellationToken::default(),
+            recent_blocks: RecentBlocks::default(),
+            tip_events: VecDeque::new(),
+            tip_subscription: None,
+            protocol_version: None,
+        })
+    }
+
+    /// Connects to the first of `urls` that responds to a ping. The rest become fallbacks.
+    pub fn connect_any(urls: &[String], config: Config) -> Result<Self, ElectrumError> {
+        let mut last_error = None;
+        for (i, url) in urls.iter().enumerate() {
+            match connect_healthy(url, &config) {
+                Ok(client) => {
+                    let fallbacks = urls[i + 1..].iter().chain(&urls[..i]).cloned().collect();
+                    return Ok(Self {
+                        inner: client,
+                        url: Some(url.clone()),
+                        fallbacks,
+                        config,
+                        verify_proofs: None,
+                        parallel_requests: 1,
+                        scan_attempts: 3,
+                        cancel: CancellationToken::default(),
+                        recent_blocks: RecentBlocks::default(),
+                        tip_events: VecDeque::new(),
+                        tip_subscription: None,
+                        protocol_version: None,
+                    });
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.map_or(ElectrumError::NoServers, ElectrumError::Client))
+    }
+
+    /// The url of the server requests are currently sent to (if it is known).
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    /// Makes a request to the current server, failing over to the next healthy server and
+    /// retrying if it fails. Every fallback is tried at most once.
+    fn call<T>(
+        &mut self,
+        mut request: impl FnMut(&Client) -> Result<T, electrum_client::Error>,
+    ) -> Result<T, ElectrumError> {
+        let mut servers_left = self.fallbacks.len();
+        loop {
+            if self.cancel.is_cancelled() {
+                return Err(ElectrumError::Cancelled);
+            }
+            let error = match request(&self.inner) {
+                Ok(response) => return Ok(response),
+                Err(e) => e,
+            };
+
+            loop {
+                if servers_left == 0 {
+                    return Err(error.into());
+                }
+                servers_left -= 1;
+                let url = self
+                    .fallbacks
+                    .pop_front()
+                    .expect("we only try as many servers as there are");
+                match connect_healthy(&url, &self.config) {
+                    Ok(client) => {
+                        self.inner = client;
+                        if let Some(failed) = self.url.replace(url) {
+                            self.fallbacks.push_back(failed);
+                        }
+                        break;
+                    }
+                    Err(_) => self.fallbacks.push_back(url),
+                }
+            }
+        }
+    }
+}
+
+/// How far the scan of a keychain has got.
+struct KeychainProgress<I> {
+    scripts: I,
+    last_active_index: u32,
+    unused_script_count: usize,
+    /// Whether the stop gap has been reached or there are no scripts left
+    done: bool,
+}
+
+impl<I> KeychainProgress<I> {
+    fn new(scripts: I) -> Self {
+        Self {
+            scripts,
+            last_active_index: 0,
+            unused_script_count: 0,
+            done: false,
+        }
+    }
+}
+
+/// The scripts of a keychain, kept as they are taken so a scan can go over them again.
+struct Replay<I: Iterator> {
+    inner: I,
+    taken: Vec<I::Item>,
+    position: usize,
+}
+
+impl<I: Iterator> Replay<I> {
+    fn new(inner: I) -> Self {
+        Self {
+            inner,
+            taken: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Starts over from the first script.
+    fn rewind(&mut self) {
+        self.position = 0;
+    }
+}
+
+impl<I: Iterator> Iterator for Replay<I>
+where
+    I::Item: Clone,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.taken.get(self.position) {
+            self.position += 1;
+            return Some(item.clone());
+        }
+        let item = self.inner.next()?;
+        self.taken.push(item.clone());
+        self.position += 1;
+        Some(item)
+    }
+}
+
+/// The feerate (in sats per vbyte) that gets a transaction into the next `target_blocks` blocks if
+/// they are filled with the transactions of `histogram` paying the most.
+///
+/// `histogram` is the mempool fee histogram of an Electrum server: pairs of a feerate and the
+/// total vsize of the transactions paying it (or a little more). When the mempool wouldn't fill
+/// `target_blocks` blocks any feerate would do so `min_feerate` (the minimum relay feerate) is
+/// returned.
+pub fn histogram_feerate(histogram: &[(f32, u64)], target_blocks: usize, min_feerate: f32) -> f32 {
+    let block_vsize = (MAX_BLOCK_WEIGHT / WITNESS_SCALE_FACTOR as u32) as u64;
+    let space = block_vsize * target_blocks as u64;
+    let mut histogram = histogram.to_vec();
+    histogram.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    let mut vsize = 0;
+    for (feerate, feerate_vsize) in histogram {
+        vsize += feerate_vsize;
+        if vsize > space {
+            return feerate.max(min_feerate);
+        }
+    }
+    min_feerate
+}
+
+/// The oldest and newest protocol versions of the server's `features`.
+fn protocol_range(
+    features: &ServerFeaturesRes,
+) -> Result<(ProtocolVersion, ProtocolVersion), ElectrumError> {
+    let parse = |version: &str| {
+        ProtocolVersion::parse(version).ok_or_else(|| {
+            ElectrumError::Client(electrum_client::Error::Message(format!(
+                "the server reported an invalid protocol version {}",
+                version
+            )))
+        })
+    };
+    Ok((
+        parse(&features.protocol_min)?,
+        parse(&features.protocol_max)?,
+    ))
+}
+
+/// Connects to `url` and checks that the server responds.
+fn connect_healthy(url: &str, config: &Config) -> Result<Client, electrum_client::Error> {
+    let client = Client::from_config(url, config.clone())?;
+    client.ping()?;
+    Ok(client)
+}
+
+impl Deref for ElectrumClient {
+    type Target = Client;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl TipStream for ElectrumClient {
+    type Error = ElectrumError;
+
+    /// Subscribes to the server's headers on the first call (and again after failing over to
+    /// another server) and otherwise pops the headers the server has announced since.
+    fn next_event(&mut self) -> Result<Option<TipEvent>, ElectrumError> {
+        loop {
+            if let Some(event) = self.tip_events.pop_front() {
+                return Ok(Some(event));
+            }
+
+            let notification = if self.tip_subscription.as_ref() != Some(&self.url) {
+                let notification = self.call(|client| client.block_headers_subscribe())?;
+                self.tip_subscription = Some(self.url.clone());
+                notification
+            } else {
+                // reading the server's response lets the client queue up any notifications
+                match self.call(|client| {
+                    client.ping()?;
+                    client.block_headers_pop()
+                })? {
+                    Some(notification) => notification,
+                    None => return Ok(None),
+                }
+            };
+
+            let tip = BlockId {
+                height: notification.height as u32,
+                hash: notification.header.block_hash(),
+            };
+            let mut recent_blocks = mem::take(&mut self.recent_blocks);
+            let events = recent_blocks.connect(tip, notification.header.prev_blockhash, |height| {
+                self.call(|client| client.block_header(height as usize))
+                    .map(|header| header.block_hash())
+            });
+            self.recent_blocks = recent_blocks;
+            self.tip_events.extend(events?);
+        }
+    }
+}
+
+impl ElectrumClient {
+    /// Fetch latest block height.
+    pub fn get_tip(&mut self) -> Result<(u32, BlockHash), ElectrumError> {
+        // TODO: unsubscribe when added to the client, or is there a better call to use here?
+        self.call(|client| {
+            client
+                .block_headers_subscribe()
+                .map(|data| (data.height as u32, data.header.block_hash()))
+        })
+    }
+
+    /// The newest protocol version both the current server and the client speak. The server is
+    /// only asked once (and again after failing over to another server).
+    pub fn protocol_version(&mut self) -> Result<ProtocolVersion, ElectrumError> {
+        if let Some((url, version)) = &self.protocol_version {
+            if *url == self.url {
+                return Ok(*version);
+            }
+        }
+        let features = self.call(|client| client.server_features())?;
+        let (_, max) = protocol_range(&features)?;
+        let version = max.min(PROTOCOL_VERSION);
+        self.protocol_version = Some((self.url.clone(), version));
+        Ok(version)
+    }
+
+    /// Asks the server about itself: its software and protocol versions, its banner, its tip and
+    /// its relay fee.
+    pub fn server_info(&mut self) -> Result<ServerInfo, ElectrumError> {
+        let features = self.call(|client| client.server_features())?;
+        let protocol_range = protocol_range(&features)?;
+        let protocol_version = protocol_range.1.min(PROTOCOL_VERSION);
+        self.protocol_version = Some((self.url.clone(), protocol_version));
+        let banner = self.call(|client| client.raw_call("server.banner", []))?;
+        let banner = banner
+            .as_str()
+            .ok_or_else(|| electrum_client::Error::InvalidResponse(banner.clone()))?
+            .to_string();
+        let (height, hash) = self.get_tip()?;
+        // in BTC per kvB
+        let relay_fee = self.call(|client| client.relay_fee())?;
+        Ok(ServerInfo {
+            url: self.url.clone(),
+            software: features.server_version,
+            protocol_version,
+            protocol_range,
+            genesis_hash: BlockHash::from_inner(features.genesis_hash),
+            pruning: features.pruning,
+            banner,
+            tip: BlockId { height, hash },
+            relay_feerate: (relay_fee * 100_000.0) as f32,
+        })
+    }
+
+    /// `batch_size` capped to what the server handles, see [`ProtocolVersion::max_batch_size`].
+    fn server_batch_size(&mut self, batch_size: usize) -> Result<usize, ElectrumError> {
+        Ok(batch_size.min(self.protocol_version()?.max_batch_size()))
+    }
+
+    /// The feerate (in sats per vbyte) a transaction needs to be confirmed within `target_blocks`
+    /// blocks.
+    ///
+    /// This is the estimate of the server's node (`blockchain.estimatefee`). When the node has no
+    /// estimate yet (e.g. it was just started) the feerate is read off the server's mempool fee
+    /// histogram with [`histogram_feerate`] instead, or is the relay fee if the server is too old
+    /// to have a histogram.
+    pub fn estimate_feerate(&mut self, target_blocks: usize) -> Result<f32, ElectrumError> {
+        // in BTC per kvB, negative if there is no estimate
+        let estimate = self.call(|client| client.estimate_fee(target_blocks))?;
+        if estimate > 0.0 {
+            return Ok((estimate * 100_000.0) as f32);
+        }
+        if !self.protocol_version()?.has_fee_histogram() {
+            let relay_fee = self.call(|client| client.relay_fee())?;
+            return Ok((relay_fee * 100_000.0) as f32);
+        }
+        let response = self.call(|client| client.raw_call("mempool.get_fee_histogram", []))?;
+        let histogram = response
+            .as_array()
+            .and_then(|entries| {
+                entries
+                    .iter()
+                    .map(|entry| match entry.as_array()?.as_slice() {
+                        [feerate, vsize] => Some((feerate.as_f64()? as f32, vsize.as_u64()?)),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| electrum_client::Error::InvalidResponse(response.clone()))?;
+        let relay_fee = self.call(|client| client.relay_fee())?;
+        Ok(histogram_feerate(
+            &histogram,
+            target_blocks,
+            (relay_fee * 100_000.0) as f32,
+        ))
+    }
+
+    /// Checks that `txid` is in the block at `height` with a merkle proof from the server.
+    ///
+    /// The header of the block must pass `check` and match the block at `height` in `chain` (if
+    /// there is one). Headers are fetched once and kept in `headers`.
+    pub fn verify_tx_height(
+        &mut self,
+        txid: Txid,
+        height: u32,
+        chain: &SparseChain,
+        check: &HeaderCheck,
+        headers: &mut BTreeMap<u32, BlockHeader>,
+    ) -> Result<(), ElectrumError> {
+        let header = match headers.get(&height) {
+            Some(header) => *header,
+            None => {
+                let header = self.call(|client| client.block_header(height as usize))?;
+                headers.insert(height, header);
+                header
+            }
+        };
+
+        let invalid = || ElectrumError::InvalidProof { txid, height };
+        let block_hash = check.validate(height, &header).ok_or_else(invalid)?;
+        if matches!(chain.checkpoint_at(height), Some(cp) if cp.hash != block_hash) {
+            return Err(invalid());
+        }
+
+        let proof = self.call(|client| client.transaction_get_merkle(&txid, height as usize))?;
+        if proof.block_height != height as usize
+            || !validate_merkle_proof(&txid, &header.merkle_root, &proof)
+        {
+            return Err(invalid());
+        }
+
+        Ok(())
+    }
+
+    /// Scan for a given list of scripts, and create an initial [`bdk_chain::sparse_chain::SparseChain`] update candidate.
+    /// This will only contain [`Txid`]s in SparseChain, and no actual transaction data.
+    ///
+    /// User needs to fetch the required transaction data and create the final [`bdk_chain::keychain::KeychainChangeSet`] before applying it.
+    pub fn spk_txid_scan(
+        &mut self,
+        spks: impl Iterator<Item = Script>,
+        local_chain: &BTreeMap<u32, BlockHash>,
+        batch_size: usize,
+    ) -> Result<SparseChain, ElectrumError> {
+        let mut dummy_keychains = BTreeMap::new();
+        dummy_keychains.insert((), spks.enumerate().map(|(i, spk)| (i as u32, spk)));
+
+        Ok(self
+            .wallet_txid_scan(dummy_keychains, None, local_chain, batch_size)?
+            .0)
+    }
+
+    /// Like [`spk_txid_scan`] but only gets the history of the scripts whose electrum status
+    /// differs from the one in `known`.
+    ///
+    /// The status of a script is a hash of its history that the server sends when subscribing to
+    /// it, so checking it is much cheaper than downloading the history. Returns the update along
+    /// with the new status of each script that changed (`None` if it no longer has any history).
+    /// Merge these into `known` once the update has been applied so the next sync can skip them.
+    ///
+    /// Scripts whose status is unchanged contribute nothing to the update so this relies on the
+    /// transactions of those scripts already being in the chain the update is applied to. The
+    /// histories of the changed scripts are fetched in the order of `spks`. A script whose status
+    /// the server doesn't give is treated as changed and its status is forgotten (`None`).
+    ///
+    /// [`spk_txid_scan`]: Self::spk_txid_scan
+    pub fn spk_txid_sync(
+        &mut self,
+        spks: impl Iterator<Item = Script>,
+        local_chain: &BTreeMap<u32, BlockHash>,
+        known: &BTreeMap<Script, StatusHash>,
+        batch_size: usize,
+    ) -> Result<(SparseChain, BTreeMap<Script, Option<StatusHash>>), ElectrumError> {
+        let batch_size = self.server_batch_size(batch_size)?;
+        let spks = spks.collect::<Vec<_>>();
+        let statuses = self.script_statuses(&spks, batch_size)?;
+
+        let mut changed = BTreeMap::new();
+        let mut changed_in_order = Vec::new();
+        for (spk, status) in spks.into_iter().zip(statuses) {
+            let unchanged = matches!(status, Some(status) if known.get(&spk) == status.as_ref());
+            if !unchanged && !changed.contains_key(&spk) {
+                changed.insert(spk.clone(), status.flatten());
+                changed_in_order.push(spk);
+            }
+        }
+
+        let sparse_chain =
+            self.spk_txid_scan(changed_in_order.into_iter(), local_chain, batch_size)?;
+        Ok((sparse_chain, changed))
+    }
+
+    /// Scan for a keychain tracker, and create an initial [`bdk_chain::sparse_chain::SparseChain`] update candidate.
+    /// This will only contain [`Txid`]s in SparseChain, and no actual transaction data.
+    ///
+    /// User needs to fetch the required transaction data and create the final [`bdk_chain::keychain::KeychainChangeSet`] before applying it.
+    ///
+    /// The keychains are scanned side by side. With [`parallel_requests`] above one, that many
+    /// batches of scripts are requested at once over separate connections. This may request a few
+    /// batches past the stop gap of a keychain; any history found in them is kept.
+    ///
+    /// The tip of the server's chain is recorded before the histories are fetched and checked to
+    /// still be in the chain afterwards. If it was reorged out the scan starts over, up to
+    /// [`scan_attempts`] times before returning [`ElectrumError::TipMoved`].
+    ///
+    /// Batches are no bigger than the server's protocol version allows (see
+    /// [`ProtocolVersion::max_batch_size`]) whatever `batch_size` is.
+    ///
+    /// [`parallel_requests`]: Self::parallel_requests
+    /// [`scan_attempts`]: Self::scan_attempts
+    pub fn wallet_txid_scan<K: Ord + Clone>(
+        &mut self,
+        scripts: BTreeMap<K, impl Iterator<Item = (u32, Script)>>,
+        stop_gap: Option<usize>,
+        local_chain: &BTreeMap<u32, BlockHash>,
+        batch_size: usize,
+    ) -> Result<(SparseChain, BTreeMap<K, u32>), ElectrumError> {
+        let batch_size = self.server_batch_size(batch_size)?;
+        let mut scripts = scripts
+            .into_iter()
+            .map(|(keychain, scripts)| (keychain, Replay::new(scripts)))
+            .collect::<Vec<_>>();
+        let attempts = self.scan_attempts.max(1);
+        for _ in 0..attempts {
+            match self.txid_scan_attempt(&mut scripts, stop_gap, local_chain, batch_size) {
+                Err(ElectrumError::Reorg) => continue,
+                result => return result,
+            }
+        }
+        Err(ElectrumError::TipMoved { attempts })
+    }
+
+    /// Scans `scripts` from the start, failing with [`ElectrumError::Reorg`] if the tip the scan
+    /// started from is reorged out before it finishes.
+    fn txid_scan_attempt<K: Ord + Clone, I: Iterator<Item = (u32, Script)>>(
+        &mut self,
+        scripts: &mut [(K, Replay<I>)],
+        stop_gap: Option<usize>,
+        local_chain: &BTreeMap<u32, BlockHash>,
+        batch_size: usize,
+    ) -> Result<(SparseChain, BTreeMap<K, u32>), ElectrumError> {
+        let mut sparse_chain = SparseChain::default();
+
+        // Find local chain block that is still there so our update can connect to the local chain.
+        for (&existing_height, &existing_hash) in local_chain.iter().rev() {
+            let current_hash = self
+                .call(|client| client.block_header(existing_height as usize))?
+                .block_hash();
+            let changeset = sparse_chain
+                .insert_checkpoint_preview(BlockId {
+                    height: existing_height,
+                    hash: current_hash,
+                })
+                .expect("This never errors because we are working with a fresh chain");
+            sparse_chain.apply_changeset(changeset);
+
+            if current_hash == existing_hash {
+                break;
+            }
+        }
+
+        // Insert the new tip so new transactions will be accepted into the sparse chain.
+        let tip = {
+            let (height, hash) = self.get_tip()?;
+            BlockId { height, hash }
+        };
+        if let Err(failure) = sparse_chain.insert_checkpoint(tip) {
+            match failure {
+                sparse_chain::InsertCheckpointError::HashNotMatching { .. } => {
+                    // There has been a re-org before we even begin scanning addresses.
+                    return Err(ElectrumError::Reorg);
+                }
+            }
+        }
+
+        let mut headers = BTreeMap::new();
+        let workers = self.connect_workers();
+        let requests_per_round = workers.len().max(1);
+        let mut keychains = scripts
+            .iter_mut()
+            .map(|(keychain, scripts)| {
+                scripts.rewind();
+                (keychain.clone(), KeychainProgress::new(scripts))
+            })
+            .collect::<Vec<_>>();
+
+        loop {
+            // take batches from the keychains in turn so they are scanned side by side
+            let mut jobs = Vec::<(usize, Vec<u32>, Vec<Script>)>::new();
+            'fill: loop {
+                let jobs_before = jobs.len();
+                for (position, (_, progress)) in keychains.iter_mut().enumerate() {
+                    if jobs.len() == requests_per_round {
+                        break 'fill;
+                    }
+                    if progress.done {
+                        continue;
+                    }
+                    let (indexes, scripts): (Vec<_>, Vec<_>) = (0..batch_size)
+                        .filter_map(|_| progress.scripts.next())
+                        .unzip();
+                    if scripts.is_empty() {
+                        progress.done = true;
+                        continue;
+                    }
+                    jobs.push((position, indexes, scripts));
+                }
+                if jobs.len() == jobs_before {
+                    break;
+                }
+            }
+            if jobs.is_empty() {
+                break;
+            }
+            // the workers' requests don't go through `call`
+            if self.cancel.is_cancelled() {
+                return Err(ElectrumError::Cancelled);
+            }
+
+            let histories = self.batch_histories(&workers, &jobs)?;
+            for ((position, indexes, _), histories) in jobs.into_iter().zip(histories) {
+                for (history, index) in histories.into_iter().zip(indexes) {
+                    let progress = &mut keychains[position].1;
+                    if history.is_empty() {
+                        progress.unused_script_count += 1;
+                    } else {
+                        progress.last_active_index = progress.last_active_index.max(index);
+                        progress.unused_script_count = 0;
+                    }
+
+                    for history_result in history {
+                        let txid = history_result.tx_hash;
+                        let pos = if history_result.height > 0
+                            && (history_result.height as u32) <= tip.height
+                        {
+                            TxHeight::Confirmed(history_result.height as u32)
+                        } else {
+                            TxHeight::Unconfirmed
+                        };
+                        if let (Some(check), TxHeight::Confirmed(height)) =
+                            (self.verify_proofs, pos)
+                        {
+                            self.verify_tx_height(
+                                txid,
+                                height,
+                                &sparse_chain,
+                                &check,
+                                &mut headers,
+                            )?;
+                        }
+                        if let Err(failure) = sparse_chain.insert_tx(txid, pos) {
+                            match failure {
+                                sparse_chain::InsertTxError::TxTooHigh { .. } => {
+                                    unreachable!("We should not encounter this error as we ensured tx_height <= tip.height");
+                                }
+                                sparse_chain::InsertTxError::TxMovedUnexpectedly { .. } => {
+                                    /* This means there is a reorg, we will handle this situation below */
+                                }
+                            }
+                        }
+                    }
+                }
+
+                let progress = &mut keychains[position].1;
+                if progress.unused_script_count >= stop_gap.unwrap_or(usize::MAX) {
+                    progress.done = true;
+                }
+            }
+        }
+
+        let keychain_index_update = keychains
+            .into_iter()
+            .map(|(keychain, progress)| (keychain, progress.last_active_index))
+            .collect::<BTreeMap<_, _>>();
+
+        // Check for Reorg during the above sync process
+        let our_latest = sparse_chain.latest_checkpoint().expect("must exist");
+        if !self.is_in_chain(our_latest)? {
+            return Err(ElectrumError::Reorg);
+        }
+
+        Ok((sparse_chain, keychain_index_update))
+    }
+
+    /// Whether `block` is still in the server's chain.
+    fn is_in_chain(&mut self, block: BlockId) -> Result<bool, ElectrumError> {
+        let current_hash = self
+            .call(|client| client.block_header(block.height as usize))?
+            .block_hash();
+        Ok(current_hash == block.hash)
+    }
+
+    /// Gets the electrum status of each of `spks`, `None` for the ones the server didn't give.
+    ///
+    /// Learning the status of a script means subscribing to it. That is done over a connection of
+    /// its own (if the url of the server is known) so it neither clashes with subscriptions made on
+    /// the main connection, e.g. to watch the scripts, nor has to unsubscribe again. The requests
+    /// of each batch are sent together. Failures don't fail over to another server since the
+    /// status is only an optimization.
+    fn script_statuses(
+        &self,
+        spks: &[Script],
+        batch_size: usize,
+    ) -> Result<Vec<Option<Option<StatusHash>>>, ElectrumError> {
+        let own_connection = self
+            .url
+            .as_ref()
+            .and_then(|url| Client::from_config(url, self.config.clone()).ok());
+        let client = own_connection.as_ref().unwrap_or(&self.inner);
+        let shared = own_connection.is_none();
+
+        let mut statuses = Vec::with_capacity(spks.len());
+        for batch in spks.chunks(batch_size.max(1)) {
+            if self.cancel.is_cancelled() {
+                return Err(ElectrumError::Cancelled);
+            }
+            std::thread::scope(|scope| {
+                let handles = batch
+                    .iter()
+                    .map(|spk| {
+                        scope.spawn(move || {
+                            // fails with `AlreadySubscribed` if the script is watched on `client`
+                            let status = client.script_subscribe(spk).ok()?;
+                            if shared {
+                                let _ = client.script_unsubscribe(spk);
+                            }
+                            Some(status.map(|status| *status))
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                statuses.extend(
+                    handles
+                        .into_iter()
+                        .map(|handle| handle.join().ok().flatten()),
+                );
+            });
+        }
+        Ok(statuses)
+    }
+
+    /// Opens the extra connections to the current server used to make requests in parallel.
+    ///
+    /// Returns no connections when [`parallel_requests`] is at most one, when the url of the
+    /// server isn't known or when connecting fails (in which case requests are made one by one).
+    ///
+    /// [`parallel_requests`]: Self::parallel_requests
+    fn connect_workers(&self) -> Vec<Client> {
+        let url = match &self.url {
+            Some(url) if self.parallel_requests > 1 => url,
+            _ => return Vec::new(),
+        };
+        (0..self.parallel_requests)
+            .map(|_| Client::from_config(url, self.config.clone()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_default()
+    }
+
+    /// Gets the history of the scripts of each job, spreading the jobs over `workers`.
+    fn batch_histories(
+        &mut self,
+        workers: &[Client],
+        jobs: &[(usize, Vec<u32>, Vec<Script>)],
+    ) -> Result<Vec<Vec<Vec<GetHistoryRes>>>, ElectrumError> {
+        let results = if workers.is_empty() {
+            jobs.iter().map(|_| None).collect::<Vec<_>>()
+        } else {
+            std::thread::scope(|scope| {
+                let handles = jobs
+                    .iter()
+                    .zip(workers)
+                    .map(|((_, _, scripts), worker)| {
+                        scope.spawn(move || worker.batch_script_get_history(scripts.iter()).ok())
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().ok().flatten())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        // whatever the workers couldn't get is requested through the main connection which can
+        // fail over to other servers
+        results
+            .into_iter()
+            .zip(jobs)
+            .map(|(result, (_, _, scripts))| match result {
+                Some(histories) => Ok(histories),
+                None => self.call(|client| client.batch_script_get_history(scripts.iter())),
+            })
+            .collect()
+    }
+
+    /// Scans the scripts of each keychain like [`wallet_txid_scan`] and fetches the transactions
+    /// to create a full update.
+    ///
+    /// Transactions that are already in `graph` (usually the graph of the tracker the update is
+    /// for) are taken from it rather than being downloaded again. The tip is checked once more
+    /// after the transactions are fetched so the update never mixes transactions from before and
+    /// after a reorg.
+    ///
+    /// [`wallet_txid_scan`]: Self::wallet_txid_scan
+    pub fn wallet_scan<K: Ord + Clone>(
+        &mut self,
+        scripts: BTreeMap<K, impl Iterator<Item = (u32, Script)>>,
+        stop_gap: Option<usize>,
+        local_chain: &BTreeMap<u32, BlockHash>,
+        graph: &TxGraph,
+        batch_size: usize,
+    ) -> Result<KeychainScan<K, TxHeight>, ElectrumError> {
+        let batch_size = self.server_batch_size(batch_size)?;
+        let mut scripts = scripts
+            .into_iter()
+            .map(|(keychain, scripts)| (keychain, Replay::new(scripts)))
+            .collect::<Vec<_>>();
+        let attempts = self.scan_attempts.max(1);
+        for _ in 0..attempts {
+            match self.scan_attempt(&mut scripts, stop_gap, local_chain, graph, batch_size) {
+                Err(ElectrumError::Reorg) => continue,
+                result => return result,
+            }
+        }
+        Err(ElectrumError::TipMoved { attempts })
+    }
+
+    /// One attempt of [`wallet_scan`](Self::wallet_scan), failing with [`ElectrumError::Reorg`]
+    /// if the chain reorganizes before the transactions have been fetched.
+    fn scan_attempt<K: Ord + Clone, I: Iterator<Item = (u32, Script)>>(
+        &mut self,
+        scripts: &mut [(K, Replay<I>)],
+        stop_gap: Option<usize>,
+        local_chain: &BTreeMap<u32, BlockHash>,
+        graph: &TxGraph,
+        batch_size: usize,
+    ) -> Result<KeychainScan<K, TxHeight>, ElectrumError> {
+        let (sparse_chain, last_active_indexes) =
+            self.txid_scan_attempt(scripts, stop_gap, local_chain, batch_size)?;
+
+        let missing = sparse_chain
+            .txids()
+            .map(|(_, txid)| *txid)
+            .filter(|txid| graph.get_tx(*txid).is_none())
+            .collect::<Vec<_>>();
+        let mut fetched = self
+            .call(|client| client.batch_transaction_get(&missing))?
+            .into_iter()
+            .map(|tx| (tx.txid(), tx))
+            .collect::<BTreeMap<Txid, Transaction>>();
+        let tip = sparse_chain.latest_checkpoint().expect("must exist");
+        if !self.is_in_chain(tip)? {
+            return Err(ElectrumError::Reorg);
+        }
+
+        let mut update = ChainGraph::default();
+        for (&height, &hash) in sparse_chain.checkpoints() {
+            let _ = update
+                .insert_checkpoint(BlockId { height, hash })
+                .expect("the checkpoints come from a valid chain");
+        }
+        for (pos, txid) in sparse_chain.txids() {
+            let tx = match fetched.remove(txid) {
+                Some(tx) => tx,
+                None => graph
+                    .get_tx(*txid)
+                    .cloned()
+                    .ok_or(ElectrumError::MissingTx(*txid))?,
+            };
+            let _ = update
+                .insert_tx(tx, *pos)
+                .map_err(ElectrumError::InsertTx)?;
+        }
+
+        Ok(KeychainScan {
+            update,
+            last_active_indexes,
+        })
+    }
+}
+
+/// The requests [`inflate_txid_update`] makes to get the transactions of an update.
+///
+/// [`ElectrumClient`] makes them to its server. Implementing it otherwise lets the answers be
+/// controlled, e.g. to have the chain reorganize between scanning and fetching.
+pub trait TxidUpdateSource {
+    /// Fetches the transactions with `txids`.
+    fn fetch_txs(&mut self, txids: &[Txid]) -> Result<Vec<Transaction>, ElectrumError>;
+    /// The hash of the block at `height` in the source's chain as it is now.
+    fn block_hash(&mut self, height: u32) -> Result<BlockHash, ElectrumError>;
+}
+
+impl TxidUpdateSource for ElectrumClient {
+    fn fetch_txs(&mut self, txids: &[Txid]) -> Result<Vec<Transaction>, ElectrumError> {
+        self.call(|client| client.batch_transaction_get(txids))
+    }
+
+    fn block_hash(&mut self, height: u32) -> Result<BlockHash, ElectrumError> {
+        Ok(self
+            .call(|client| client.block_header(height as usize))?
+            .block_hash())
+    }
+}
+
+/// Turns an `update` from [`ElectrumClient::wallet_txid_scan`] (or [`spk_txid_scan`]) into a
+/// changeset for `chain_graph`, fetching the transactions it adds from `source`.
+///
+/// The source's chain may have reorganized since the scan so the transactions the update refers
+/// to can be gone by the time they are fetched. When fetching or inflating fails and the tip of
+/// `update` is no longer in the source's chain, [`ElectrumError::Reorg`] is returned so the scan
+/// can be run again. If the transactions could still be fetched the changeset is consistent with
+/// `chain_graph` and can be applied: the next scan replaces the blocks that were reorged out.
+///
+/// [`spk_txid_scan`]: ElectrumClient::spk_txid_scan
+pub fn inflate_txid_update(
+    source: &mut impl TxidUpdateSource,
+    chain_graph: &ChainGraph<TxHeight>,
+    update: &SparseChain,
+) -> Result<chain_graph::ChangeSet<TxHeight>, ElectrumError> {
+    let changeset = chain_graph
+        .chain()
+        .determine_changeset(update)
+        .map_err(ElectrumError::Update)?;
+    let missing = chain_graph
+        .chain()
+        .changeset_additions(&changeset)
+        .filter(|txid| chain_graph.graph().get_tx(*txid).is_none())
+        .collect::<Vec<_>>();
+
+    let fetched = if missing.is_empty() {
+        Ok(Vec::new())
+    } else {
+        source.fetch_txs(&missing)
+    };
+    let error = match fetched.and_then(|txs| {
+        chain_graph
+            .inflate_changeset(changeset, txs)
+            .map_err(ElectrumError::Inflate)
+    }) {
+        Ok(changeset) => return Ok(changeset),
+        Err(error) => error,
+    };
+
+    if let Some(tip) = update.latest_checkpoint() {
+        if source.block_hash(tip.height)? != tip.hash {
+            return Err(ElectrumError::Reorg);
+        }
+    }
+    Err(error)
+}
+
+/// Checks that the merkle branch in `proof` connects `txid` to `merkle_root`.
+///
+/// The server sends the branch hashes in display (reversed) byte order.
+pub fn validate_merkle_proof(
+    txid: &Txid,
+    merkle_root: &TxMerkleNode,
+    proof: &GetMerkleRes,
+) -> bool {
+    let mut index = proof.pos;
+    let mut current = txid.as_hash();
+    for branch in &proof.merkle {
+        let mut branch = *branch;
+        branch.reverse();
+        let mut engine = sha256d::Hash::engine();
+        if index.is_multiple_of(2) {
+            engine.input(&current[..]);
+            engine.input(&branch);
+        } else {
+            engine.input(&branch);
+            engine.input(&current[..]);
+        }
+        current = sha256d::Hash::from_engine(engine);
+        index /= 2;
+    }
+    current == merkle_root.as_hash()
+}
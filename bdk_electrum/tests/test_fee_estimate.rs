@@ -0,0 +1,22 @@
+use bdk_electrum::histogram_feerate;
+
+#[test]
+fn histogram_feerate_fills_target_blocks() {
+    // out of order to check the highest feerates are taken first
+    let histogram = [
+        (5.0, 600_000),
+        (20.0, 500_000),
+        (10.0, 700_000),
+        (2.0, 2_000_000),
+    ];
+
+    // 20 and 10 sats/vbyte fill the next block
+    assert_eq!(histogram_feerate(&histogram, 1, 1.0), 10.0);
+    // 1.8M vbytes pay at least 5 sats/vbyte, the rest of the second block is filled at 2
+    assert_eq!(histogram_feerate(&histogram, 2, 1.0), 2.0);
+    // the mempool doesn't fill four blocks
+    assert_eq!(histogram_feerate(&histogram, 4, 1.0), 1.0);
+    assert_eq!(histogram_feerate(&[], 1, 1.5), 1.5);
+    // never less than the minimum
+    assert_eq!(histogram_feerate(&histogram, 2, 3.0), 3.0);
+}
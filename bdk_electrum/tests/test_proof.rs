@@ -0,0 +1,166 @@
+use bdk_chain::bitcoin::{
+    consensus::encode::deserialize,
+    hashes::{
+        hex::{FromHex, ToHex},
+        sha256d, Hash,
+    },
+    BlockHeader, Network, Txid,
+};
+use bdk_electrum::{
+    electrum_client::GetMerkleRes, validate_merkle_proof, HeaderCheck, RETARGET_INTERVAL,
+};
+
+/// The header of mainnet block 170, the first block with a transaction that isn't a coinbase.
+const HEADER_170: &str = "0100000055bd840a78798ad0da853f68974f3d183e2bd1db6a842c1feecf222a00000000ff104ccb05421ab93e63f8c3ce5c2c2e9dbb37de2764b3a3175c8166562cac7d51b96a49ffff001d283e9e70";
+const COINBASE_170: &str = "b1fea52486ce0c62bb442b530a3f0132b826c74e473d1f2c220bfa78111c5082";
+const SPEND_170: &str = "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16";
+
+/// The header of mainnet block 100000 and its four transactions.
+const HEADER_100000: &str = "0100000050120119172a610421a6c3011dd330d9df07b63616c2cc1f1cd00200000000006657a9252aacd5c0b2940996ecff952228c3067cc38d4885efb5a4ac4247e9f337221b4d4c86041b0f2b5710";
+const TXS_100000: [&str; 4] = [
+    "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+    "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+    "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+    "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+];
+
+fn header(hex: &str) -> BlockHeader {
+    deserialize(&Vec::<u8>::from_hex(hex).unwrap()).unwrap()
+}
+
+fn txid(hex: &str) -> Txid {
+    hex.parse().unwrap()
+}
+
+/// A proof the way the server sends it: with the branch hashes as displayed.
+fn proof(block_height: usize, pos: usize, branch: &[&str]) -> GetMerkleRes {
+    GetMerkleRes {
+        block_height,
+        pos,
+        merkle: branch
+            .iter()
+            .map(|hash| <[u8; 32]>::from_hex(hash).unwrap())
+            .collect(),
+    }
+}
+
+#[test]
+fn proofs_of_block_170() {
+    let root = header(HEADER_170).merkle_root;
+    assert!(validate_merkle_proof(
+        &txid(SPEND_170),
+        &root,
+        &proof(170, 1, &[COINBASE_170])
+    ));
+    assert!(validate_merkle_proof(
+        &txid(COINBASE_170),
+        &root,
+        &proof(170, 0, &[SPEND_170])
+    ));
+    // the branch hash is on the wrong side
+    assert!(!validate_merkle_proof(
+        &txid(SPEND_170),
+        &root,
+        &proof(170, 0, &[COINBASE_170])
+    ));
+    // the proof is for another block
+    assert!(!validate_merkle_proof(
+        &txid(SPEND_170),
+        &header(HEADER_100000).merkle_root,
+        &proof(170, 1, &[COINBASE_170])
+    ));
+}
+
+#[test]
+fn proofs_of_block_100000() {
+    let root = header(HEADER_100000).merkle_root;
+    let txids = TXS_100000.map(txid);
+    let branches = [
+        [TXS_100000[1], TXS_100000[2], TXS_100000[3]],
+        [TXS_100000[0], TXS_100000[2], TXS_100000[3]],
+        [TXS_100000[3], TXS_100000[0], TXS_100000[1]],
+        [TXS_100000[2], TXS_100000[0], TXS_100000[1]],
+    ];
+    for (pos, [sibling, left, right]) in branches.into_iter().enumerate() {
+        // the second hash of the branch is the hash of the other pair
+        let (left, right) = (txid(left), txid(right));
+        let other_pair = sha256d::Hash::hash(&[left.as_ref(), right.as_ref()].concat());
+        let branch = [sibling.to_string(), other_pair.to_hex()];
+        let branch = branch.iter().map(String::as_str).collect::<Vec<_>>();
+        assert!(validate_merkle_proof(
+            &txids[pos],
+            &root,
+            &proof(100_000, pos, &branch)
+        ));
+        assert!(!validate_merkle_proof(
+            &txids[pos],
+            &root,
+            &proof(100_000, pos ^ 1, &branch)
+        ));
+    }
+}
+
+#[test]
+fn headers_must_meet_the_network_difficulty() {
+    let check = HeaderCheck::new(Network::Bitcoin);
+    let block_170 = header(HEADER_170);
+    assert_eq!(
+        check.validate(170, &block_170),
+        Some(block_170.block_hash())
+    );
+    assert_eq!(
+        check.validate(100_000, &header(HEADER_100000)),
+        Some(header(HEADER_100000).block_hash())
+    );
+
+    // a header that is easy to mine because it claims an easy target
+    let mut easy = block_170;
+    easy.bits = 0x207fffff;
+    let easy = (0..)
+        .map(|nonce| BlockHeader { nonce, ..easy })
+        .find(|header| header.validate_pow(&header.target()).is_ok())
+        .unwrap();
+    assert_eq!(check.validate(170, &easy), None);
+    // only regtest allows it
+    assert_eq!(
+        HeaderCheck::new(Network::Regtest).validate(170, &easy),
+        Some(easy.block_hash())
+    );
+
+    // a header that doesn't meet its own target
+    let mut tampered = block_170;
+    tampered.nonce += 1;
+    assert_eq!(check.validate(170, &tampered), None);
+}
+
+#[test]
+fn trusted_headers_bound_the_difficulty() {
+    let block_100000 = header(HEADER_100000);
+    let check = HeaderCheck {
+        network: Network::Bitcoin,
+        trusted: Some((100_000, block_100000)),
+    };
+    assert_eq!(
+        check.validate(100_000, &block_100000),
+        Some(block_100000.block_hash())
+    );
+    // only one header can be at the trusted height
+    assert_eq!(check.validate(100_000, &header(HEADER_170)), None);
+    // block 170 has the minimum difficulty which is far too low to be this close to block 100000
+    assert_eq!(check.validate(99_000, &header(HEADER_170)), None);
+    assert!(check.max_target(100_000) < check.max_target(100_000 + RETARGET_INTERVAL));
+    // but many retargets before it the difficulty could have been that low
+    assert_eq!(
+        check.validate(170, &header(HEADER_170)),
+        Some(header(HEADER_170).block_hash())
+    );
+    // test networks have blocks with the minimum difficulty at any height
+    let testnet = HeaderCheck {
+        network: Network::Testnet,
+        ..check
+    };
+    assert_eq!(
+        testnet.validate(99_000, &header(HEADER_170)),
+        Some(header(HEADER_170).block_hash())
+    );
+}
@@ -0,0 +1,34 @@
+use bdk_electrum::{ProtocolVersion, LEGACY_BATCH_SIZE, PROTOCOL_VERSION};
+
+#[test]
+fn parses_versions_servers_report() {
+    assert_eq!(
+        ProtocolVersion::parse("1.4"),
+        Some(ProtocolVersion::new(1, 4, 0))
+    );
+    assert_eq!(
+        ProtocolVersion::parse("1.4.2"),
+        Some(ProtocolVersion::new(1, 4, 2))
+    );
+    assert_eq!(ProtocolVersion::parse("1"), None);
+    assert_eq!(ProtocolVersion::parse("1.4.2.1"), None);
+    assert_eq!(ProtocolVersion::parse("1.x"), None);
+    assert_eq!(ProtocolVersion::new(1, 4, 2).to_string(), "1.4.2");
+    assert_eq!(ProtocolVersion::new(1, 4, 0).to_string(), "1.4");
+}
+
+#[test]
+fn old_servers_get_small_batches_and_no_histogram() {
+    let old = ProtocolVersion::new(1, 1, 0);
+    assert!(!old.has_fee_histogram());
+    assert_eq!(old.max_batch_size(), LEGACY_BATCH_SIZE);
+
+    let histogram = ProtocolVersion::new(1, 2, 0);
+    assert!(histogram.has_fee_histogram());
+    assert_eq!(histogram.max_batch_size(), LEGACY_BATCH_SIZE);
+
+    // a newer server than the client is talked to with the client's version
+    let newer = ProtocolVersion::new(1, 5, 0).min(PROTOCOL_VERSION);
+    assert_eq!(newer, PROTOCOL_VERSION);
+    assert_eq!(newer.max_batch_size(), usize::MAX);
+}
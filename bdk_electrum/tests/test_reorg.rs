@@ -0,0 +1,251 @@
+use bdk_chain::{
+    bitcoin::{
+        hashes::Hash, BlockHash, OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn,
+        TxOut, Txid, Witness,
+    },
+    chain_graph::{ChainGraph, InflateError},
+    collections::{BTreeMap, HashMap},
+    sparse_chain::SparseChain,
+    BlockId, TxHeight,
+};
+use bdk_electrum::{electrum_client, inflate_txid_update, ElectrumError, TxidUpdateSource};
+
+fn hash(name: &str) -> BlockHash {
+    BlockHash::hash(name.as_bytes())
+}
+
+fn tx(input: Option<OutPoint>, value: u64) -> Transaction {
+    Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: input
+            .into_iter()
+            .map(|previous_output| TxIn {
+                previous_output,
+                script_sig: Script::new(),
+                sequence: Sequence::default(),
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: vec![TxOut {
+            value,
+            script_pubkey: Script::new(),
+        }],
+    }
+}
+
+/// What the server knows at some point.
+#[derive(Clone)]
+struct ServerState {
+    chain: BTreeMap<u32, BlockHash>,
+    txs: HashMap<Txid, Transaction>,
+}
+
+/// A server whose chain changes to `after_scan` once the wallet starts fetching transactions.
+struct Server {
+    state: ServerState,
+    after_scan: Option<ServerState>,
+}
+
+impl TxidUpdateSource for Server {
+    fn fetch_txs(&mut self, txids: &[Txid]) -> Result<Vec<Transaction>, ElectrumError> {
+        if let Some(state) = self.after_scan.take() {
+            self.state = state;
+        }
+        txids
+            .iter()
+            .map(|txid| {
+                self.state.txs.get(txid).cloned().ok_or_else(|| {
+                    ElectrumError::Client(electrum_client::Error::Message(format!(
+                        "no such transaction {}",
+                        txid
+                    )))
+                })
+            })
+            .collect()
+    }
+
+    fn block_hash(&mut self, height: u32) -> Result<BlockHash, ElectrumError> {
+        Ok(self.state.chain[&height])
+    }
+}
+
+/// The update a txid scan of `state` returns for a wallet whose transactions are `txs`.
+fn scan(state: &ServerState, txs: &[(Txid, TxHeight)]) -> SparseChain {
+    let mut update = SparseChain::from_checkpoints(
+        state
+            .chain
+            .iter()
+            .map(|(&height, &hash)| BlockId { height, hash }),
+    );
+    for &(txid, height) in txs {
+        let _ = update.insert_tx(txid, height).expect("valid update");
+    }
+    update
+}
+
+fn wallet(tx_a: &Transaction) -> ChainGraph<TxHeight> {
+    let mut wallet = ChainGraph::default();
+    for (height, name) in [(1, "A"), (2, "B")] {
+        let _ = wallet
+            .insert_checkpoint(BlockId {
+                height,
+                hash: hash(name),
+            })
+            .expect("valid checkpoint");
+    }
+    let _ = wallet
+        .insert_tx(tx_a.clone(), TxHeight::Confirmed(2))
+        .expect("valid tx");
+    wallet
+}
+
+#[test]
+fn reorg_between_scan_and_fetch_is_retriable() {
+    let tx_a = tx(None, 10_000);
+    let tx_b = tx(Some(OutPoint::new(tx_a.txid(), 0)), 9_000);
+    // double spends tx_b in the block that replaces the one tx_b was in
+    let tx_b2 = tx(Some(OutPoint::new(tx_a.txid(), 0)), 8_000);
+    let mut wallet = wallet(&tx_a);
+
+    let before = ServerState {
+        chain: BTreeMap::from([(1, hash("A")), (2, hash("B")), (3, hash("C"))]),
+        txs: [&tx_a, &tx_b].map(|tx| (tx.txid(), tx.clone())).into(),
+    };
+    let after = ServerState {
+        chain: BTreeMap::from([(1, hash("A")), (2, hash("B")), (3, hash("C'"))]),
+        txs: [&tx_a, &tx_b2].map(|tx| (tx.txid(), tx.clone())).into(),
+    };
+    let update = scan(
+        &before,
+        &[
+            (tx_a.txid(), TxHeight::Confirmed(2)),
+            (tx_b.txid(), TxHeight::Confirmed(3)),
+        ],
+    );
+
+    let mut server = Server {
+        state: before.clone(),
+        after_scan: Some(after.clone()),
+    };
+    let error = inflate_txid_update(&mut server, &wallet, &update).expect_err("tx_b is gone");
+    assert!(matches!(error, ElectrumError::Reorg), "got {:?}", error);
+    assert!(error.is_retriable());
+
+    // without the reorg, a server leaving out a transaction is reported as such
+    struct Forgetful(ServerState);
+    impl TxidUpdateSource for Forgetful {
+        fn fetch_txs(&mut self, _: &[Txid]) -> Result<Vec<Transaction>, ElectrumError> {
+            Ok(Vec::new())
+        }
+        fn block_hash(&mut self, height: u32) -> Result<BlockHash, ElectrumError> {
+            Ok(self.0.chain[&height])
+        }
+    }
+    let error = inflate_txid_update(&mut Forgetful(before), &wallet, &update).expect_err("no txs");
+    assert!(
+        matches!(error, ElectrumError::Inflate(InflateError::Missing(_))),
+        "got {:?}",
+        error
+    );
+    assert!(error.is_retriable());
+
+    // scanning again picks up the new chain
+    let mut server = Server {
+        state: after.clone(),
+        after_scan: None,
+    };
+    let update = scan(
+        &after,
+        &[
+            (tx_a.txid(), TxHeight::Confirmed(2)),
+            (tx_b2.txid(), TxHeight::Confirmed(3)),
+        ],
+    );
+    let changeset = inflate_txid_update(&mut server, &wallet, &update).expect("consistent");
+    wallet.apply_changeset(changeset);
+
+    assert_eq!(
+        wallet.chain().checkpoint_at(3),
+        Some(BlockId {
+            height: 3,
+            hash: hash("C'")
+        })
+    );
+    assert_eq!(
+        wallet
+            .transactions_in_chain()
+            .map(|(height, tx)| (*height, tx.txid()))
+            .collect::<Vec<_>>(),
+        vec![
+            (TxHeight::Confirmed(2), tx_a.txid()),
+            (TxHeight::Confirmed(3), tx_b2.txid()),
+        ]
+    );
+}
+
+#[test]
+fn fetch_that_outlives_a_reorg_is_applied_consistently() {
+    let tx_a = tx(None, 10_000);
+    let tx_b = tx(Some(OutPoint::new(tx_a.txid(), 0)), 9_000);
+    let mut wallet = wallet(&tx_a);
+
+    let before = ServerState {
+        chain: BTreeMap::from([(1, hash("A")), (2, hash("B")), (3, hash("C"))]),
+        txs: [&tx_a, &tx_b].map(|tx| (tx.txid(), tx.clone())).into(),
+    };
+    // tx_b went back to the mempool so the server still has it
+    let after = ServerState {
+        chain: BTreeMap::from([(1, hash("A")), (2, hash("B")), (3, hash("C'"))]),
+        ..before.clone()
+    };
+    let update = scan(
+        &before,
+        &[
+            (tx_a.txid(), TxHeight::Confirmed(2)),
+            (tx_b.txid(), TxHeight::Confirmed(3)),
+        ],
+    );
+
+    let mut server = Server {
+        state: before,
+        after_scan: Some(after.clone()),
+    };
+    let changeset =
+        inflate_txid_update(&mut server, &wallet, &update).expect("tx_b is still there");
+    wallet.apply_changeset(changeset);
+    assert_eq!(
+        wallet.chain().tx_position(tx_b.txid()),
+        Some(&TxHeight::Confirmed(3))
+    );
+
+    // the next scan replaces the block that was reorged out
+    let update = scan(
+        &after,
+        &[
+            (tx_a.txid(), TxHeight::Confirmed(2)),
+            (tx_b.txid(), TxHeight::Unconfirmed),
+        ],
+    );
+    let changeset = inflate_txid_update(&mut server, &wallet, &update).expect("consistent");
+    assert!(changeset.graph.tx.is_empty(), "nothing new to fetch");
+    wallet.apply_changeset(changeset);
+
+    assert_eq!(
+        wallet.chain().checkpoint_at(3),
+        Some(BlockId {
+            height: 3,
+            hash: hash("C'")
+        })
+    );
+    assert_eq!(
+        wallet
+            .transactions_in_chain()
+            .map(|(height, tx)| (*height, tx.txid()))
+            .collect::<Vec<_>>(),
+        vec![
+            (TxHeight::Confirmed(2), tx_a.txid()),
+            (TxHeight::Unconfirmed, tx_b.txid()),
+        ]
+    );
+}
@@ -0,0 +1,55 @@
+use bdk_cli::{
+    anyhow::{self, anyhow, Context},
+    webhook::WebhookTransport,
+};
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// Makes the requests to webhooks by running `curl` so the example doesn't need an HTTP client.
+#[derive(Clone, Debug)]
+pub struct Curl {
+    /// The `curl` command to run
+    pub command: String,
+}
+
+impl WebhookTransport for Curl {
+    fn post(&mut self, url: &str, headers: &[(&str, String)], payload: &str) -> anyhow::Result<()> {
+        let mut command = Command::new(&self.command);
+        command.args([
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--max-time",
+            "10",
+            "--request",
+            "POST",
+            "--header",
+            "Content-Type: application/json",
+        ]);
+        for (name, value) in headers {
+            command.args(["--header", &format!("{}: {}", name, value)]);
+        }
+        let mut child = command
+            .args(["--data-binary", "@-", url])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run `{}`", self.command))?;
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(payload.as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "{}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
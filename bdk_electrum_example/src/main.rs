@@ -1,13 +1,33 @@
+mod curl;
 mod electrum;
-use bdk_chain::{bitcoin::Network, keychain::KeychainChangeSet};
+use bdk_chain::{
+    bitcoin::{consensus::encode::deserialize, hashes::hex::FromHex, Network, Script},
+    file_store::KeychainStore,
+    keychain::{KeychainChangeSet, KeychainTracker},
+    sparse_chain::{ChainPosition, SparseChain},
+    tip::TipEvent,
+    TxHeight,
+};
 use bdk_cli::{
     anyhow::{self, Context},
     clap::{self, Parser, Subcommand},
+    webhook::{self, WebhookDispatcher, WEBHOOKS_EXTENSION},
+    Keychain,
 };
+use curl::Curl;
 use electrum::ElectrumClient;
-use std::{fmt::Debug, io, io::Write};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    io,
+    io::Write,
+    time::Duration,
+};
 
-use electrum_client::{Client, ConfigBuilder, ElectrumApi};
+use bdk_electrum::{
+    electrum_client::{Config, ConfigBuilder, ElectrumApi},
+    ElectrumError, HeaderCheck, StatusHash,
+};
 
 #[derive(Subcommand, Debug, Clone)]
 enum ElectrumCommands {
@@ -16,9 +36,39 @@ enum ElectrumCommands {
         /// When a gap this large has been found for a keychain it will stop.
         #[clap(long, default_value = "5")]
         stop_gap: usize,
+        /// Scan every keychain from its first script instead of resuming after the last used
+        /// script the previous scan found
+        #[clap(long)]
+        from_start: bool,
         #[clap(flatten)]
         scan_option: ScanOption,
     },
+    /// Subscribes to the wallet's addresses and keeps syncing them as the server notifies us of
+    /// changes (until interrupted)
+    Watch {
+        /// How many unused addresses to keep watching ahead of the last used one in each keychain
+        #[clap(long, default_value = "10")]
+        lookahead: u32,
+        /// How many seconds to wait between checking for notifications
+        #[clap(long, default_value = "5")]
+        poll_secs: u64,
+        /// Set batch size for each script_history call to electrum client
+        #[clap(long, default_value = "25")]
+        batch_size: usize,
+        /// The `curl` command used to send events to the registered webhooks
+        #[clap(long, default_value = "curl")]
+        webhook_command: String,
+        /// Watch all the wallets of `--wallets` at the same time, each over its own connection to
+        /// a server of its network
+        #[clap(long)]
+        all_wallets: bool,
+        /// Also serve the wallet over gRPC on this address (see `bdk_cli_lib/proto/tracker.proto`)
+        #[cfg(feature = "grpc")]
+        #[clap(long, conflicts_with = "all_wallets")]
+        grpc: Option<std::net::SocketAddr>,
+        #[clap(flatten)]
+        server: ServerOption,
+    },
     /// Scans particular addresses using esplora API
     Sync {
         /// Scan all the unused addresses
@@ -30,9 +80,21 @@ enum ElectrumCommands {
         /// Scan every address that you have derived
         #[clap(long)]
         all: bool,
+        /// Only fetch the history of the addresses whose status has changed since the last sync
+        #[clap(long)]
+        incremental: bool,
+        /// The order to sync the wallet's addresses in: `untouched` (by keychain and index),
+        /// `recent` (the most recently used first) or `value` (the most unspent value first)
+        #[clap(long, default_value = "untouched")]
+        order: bdk_cli::ScriptOrder,
         #[clap(flatten)]
         scan_option: ScanOption,
     },
+    /// Show the software, protocol version, banner, tip and relay fee of the electrum server
+    Status {
+        #[clap(flatten)]
+        server: ServerOption,
+    },
 }
 
 #[derive(Parser, Debug, Clone, PartialEq)]
@@ -40,88 +102,300 @@ pub struct ScanOption {
     /// Set batch size for each script_history call to electrum client
     #[clap(long, default_value = "25")]
     pub batch_size: usize,
+    /// Run the scan against this second electrum server as well and refuse to apply the result
+    /// if the two servers disagree
+    #[clap(long)]
+    pub cross_check: Option<String>,
+    /// Check the merkle proof of every confirmed transaction the server returns
+    #[clap(long)]
+    pub verify_proofs: bool,
+    /// A mainnet block header known to be in the best chain, as `<height>:<header hex>`. The
+    /// difficulty of the headers merkle proofs are checked against must be reachable from it.
+    #[clap(long, requires = "verify_proofs")]
+    pub trusted_header: Option<String>,
+    /// How many batches of scripts to request from the server at the same time
+    #[clap(long, default_value = "1")]
+    pub parallel_requests: usize,
+    /// How many times to start the scan over when the chain reorganizes during it
+    #[clap(long, default_value = "3")]
+    pub scan_attempts: usize,
+    #[clap(flatten)]
+    pub server: ServerOption,
 }
 
-fn main() -> anyhow::Result<()> {
-    let (args, keymap, mut tracker, mut db) = bdk_cli::init::<ElectrumCommands, _>()?;
+#[derive(Parser, Debug, Clone, PartialEq)]
+pub struct ServerOption {
+    /// The electrum server to connect to, as `ssl://host:port` or `tcp://host:port`. When given
+    /// more than once (or as a comma separated list) the next server is used if one fails.
+    /// Defaults to a public server of the network.
+    #[clap(long = "server", env = "ELECTRUM_URL", value_delimiter = ',')]
+    pub servers: Vec<String>,
+    /// Whether to check that the certificate of `ssl://` servers is valid for their domain.
+    /// Defaults to only checking on mainnet.
+    #[clap(long, env = "ELECTRUM_VALIDATE_DOMAIN")]
+    pub validate_domain: Option<bool>,
+}
 
-    let electrum_url = match args.network {
+impl ScanOption {
+    /// What the headers of merkle proofs are checked against, if proofs are verified at all.
+    fn header_check(&self, network: Network) -> anyhow::Result<Option<HeaderCheck>> {
+        if !self.verify_proofs {
+            return Ok(None);
+        }
+        let trusted = match &self.trusted_header {
+            Some(trusted) => {
+                let (height, header) = trusted
+                    .split_once(':')
+                    .context("the trusted header must be given as <height>:<header hex>")?;
+                let header = Vec::<u8>::from_hex(header).context("the trusted header isn't hex")?;
+                Some((
+                    height
+                        .parse()
+                        .context("invalid height of the trusted header")?,
+                    deserialize(&header).context("invalid trusted header")?,
+                ))
+            }
+            None => None,
+        };
+        Ok(Some(HeaderCheck { network, trusted }))
+    }
+}
+
+impl ServerOption {
+    /// The servers to try in order, falling back to the default server of `network`.
+    fn urls(&self, network: Network) -> anyhow::Result<Vec<String>> {
+        if self.servers.is_empty() {
+            return Ok(vec![default_server(network).to_string()]);
+        }
+        for url in &self.servers {
+            check_scheme(url)?;
+        }
+        Ok(self.servers.clone())
+    }
+
+    fn config(&self, network: Network) -> anyhow::Result<Config> {
+        Ok(ConfigBuilder::new()
+            .validate_domain(
+                self.validate_domain
+                    .unwrap_or(matches!(network, Network::Bitcoin)),
+            )
+            .timeout(Some(TIMEOUT))?
+            .build())
+    }
+
+    /// Connects to the first healthy server keeping the others to fail over to.
+    fn connect(&self, network: Network) -> anyhow::Result<ElectrumClient> {
+        let client =
+            bdk_electrum::ElectrumClient::connect_any(&self.urls(network)?, self.config(network)?)?;
+        if let Some(url) = client.url() {
+            eprintln!("connected to {}", url);
+        }
+        Ok(ElectrumClient(client))
+    }
+}
+
+/// The name of the extension blob the [`bdk_cli::SyncCursor`] is saved under.
+const CURSOR_NAME: &str = "electrum_cursor";
+/// The name the electrum status of each synced script is stored under.
+const STATUSES_NAME: &str = "electrum_statuses";
+
+/// How long to wait for a server before giving up on it (in seconds).
+const TIMEOUT: u8 = 30;
+
+fn default_server(network: Network) -> &'static str {
+    match network {
         Network::Bitcoin => "ssl://electrum.blockstream.info:50002",
         Network::Testnet => "ssl://electrum.blockstream.info:60002",
         Network::Regtest => "ssl://localhost:60401",
         Network::Signet => "tcp://signet-electrumx.wakiyamap.dev:50001",
-    };
-    let config = ConfigBuilder::new()
-        .validate_domain(match args.network {
-            Network::Bitcoin => true,
-            _ => false,
-        })
-        .build();
+    }
+}
 
-    let client = ElectrumClient::new(Client::from_config(electrum_url, config)?)?;
+/// The electrum client treats urls without a scheme as `tcp://` which would silently turn off TLS
+/// for anyone who forgot to write `ssl://`.
+fn check_scheme(url: &str) -> anyhow::Result<()> {
+    match url.split_once("://") {
+        Some(("ssl", _)) | Some(("tcp", _)) => Ok(()),
+        Some((scheme, _)) => anyhow::bail!(
+            "electrum server {} has an unsupported scheme `{}`, use ssl:// or tcp://",
+            url,
+            scheme
+        ),
+        None => anyhow::bail!(
+            "electrum server {} is missing its scheme, use ssl://{} or tcp://{}",
+            url,
+            url,
+            url
+        ),
+    }
+}
 
-    let electrum_cmd = match args.command {
-        bdk_cli::Commands::ChainSpecific(electrum_cmd) => electrum_cmd,
-        general_command => {
-            return bdk_cli::handle_commands(
-                general_command,
-                client,
-                &mut tracker,
-                &mut db,
-                args.network,
-                &keymap,
-            )
+fn connect(url: &str, config: Config) -> anyhow::Result<ElectrumClient> {
+    check_scheme(url)?;
+    Ok(ElectrumClient(bdk_electrum::ElectrumClient::connect_any(
+        &[url.to_string()],
+        config,
+    )?))
+}
+
+/// Fails if the `update` we got from one server disagrees with the `other` we got from the
+/// cross-check server.
+fn cross_check<P: ChainPosition>(
+    update: &SparseChain<P>,
+    other: &SparseChain<P>,
+) -> anyhow::Result<()> {
+    let discrepancies = update.discrepancies(other);
+    if discrepancies.is_empty() {
+        return Ok(());
+    }
+    for discrepancy in &discrepancies {
+        eprintln!("{}", discrepancy);
+    }
+    anyhow::bail!(
+        "the servers disagree in {} places, not applying the update",
+        discrepancies.len()
+    )
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = bdk_cli::Args::<ElectrumCommands>::parse();
+    if bdk_cli::run_walletless_cmd(&args)? {
+        return Ok(());
+    }
+    if let bdk_cli::Commands::ChainSpecific(ElectrumCommands::Watch {
+        lookahead,
+        poll_secs,
+        batch_size,
+        webhook_command,
+        all_wallets: true,
+        server,
+        ..
+    }) = &args.command
+    {
+        return watch_all(
+            &args,
+            *lookahead,
+            Duration::from_secs(*poll_secs),
+            *batch_size,
+            webhook_command,
+            server,
+        );
+    }
+    let (name, config) = args.selected_wallet()?;
+    let bdk_cli::Wallet {
+        config,
+        signers,
+        mut tracker,
+        store: mut db,
+        ..
+    } = bdk_cli::open_wallet(&args, name, config)?;
+
+    let mut server = match &args.command {
+        bdk_cli::Commands::ChainSpecific(ElectrumCommands::Scan { scan_option, .. })
+        | bdk_cli::Commands::ChainSpecific(ElectrumCommands::Sync { scan_option, .. }) => {
+            scan_option.server.clone()
         }
+        bdk_cli::Commands::ChainSpecific(ElectrumCommands::Watch { server, .. })
+        | bdk_cli::Commands::ChainSpecific(ElectrumCommands::Status { server }) => server.clone(),
+        // the general commands don't take electrum options so only the environment applies
+        _ => ServerOption::parse_from([env!("CARGO_PKG_NAME")]),
     };
+    if !config.servers.is_empty() {
+        server.servers = config.servers.clone();
+    }
+    let mut client = server.connect(config.network)?;
+    client.cancel = args.cancellation_token();
 
     let mut keychain_changeset = KeychainChangeSet::default();
+    let mut cursor = None;
+    let mut statuses = None;
 
-    let chain_update = match electrum_cmd {
-        ElectrumCommands::Scan {
+    let chain_update = match args.command {
+        bdk_cli::Commands::ChainSpecific(ElectrumCommands::Scan {
             stop_gap,
+            from_start,
             scan_option,
-        } => {
-            let scripts = tracker
-                .txout_index
-                .scripts_of_all_keychains()
-                .into_iter()
-                .map(|(keychain, iter)| {
-                    let mut first = true;
-                    (
-                        keychain,
-                        iter.inspect(move |(i, _)| {
-                            if first {
-                                eprint!("\nscanning {}: ", keychain);
-                                first = false;
-                            }
-
-                            eprint!("{} ", i);
-                            let _ = io::stdout().flush();
-                        }),
-                    )
-                })
-                .collect();
+        }) => {
+            // a cursor that can't be resumed from is started over
+            let mut previous =
+                bdk_cli::load_extension::<bdk_cli::SyncCursor, _, _>(&mut db, CURSOR_NAME)?
+                    .unwrap_or_default();
+            let resume_from = match from_start {
+                true => BTreeMap::new(),
+                false => previous.resume_from(tracker.chain()),
+            };
+            if resume_from.is_empty() {
+                previous = bdk_cli::SyncCursor::default();
+            } else if let Some(tip) = previous.tip {
+                eprintln!(
+                    "resuming the last scan, which was up to block {} at height {}",
+                    tip.hash, tip.height
+                );
+            }
 
-            let (new_sparsechain, keychain_index_update) = client.wallet_txid_scan(
-                scripts,
-                Some(stop_gap),
-                tracker.chain().checkpoints(),
-                scan_option.batch_size,
-            )?;
+            let scan = |client: &mut ElectrumClient| {
+                let scripts = tracker
+                    .txout_index
+                    .scripts_of_all_keychains()
+                    .into_iter()
+                    .map(|(keychain, iter)| {
+                        let mut first = true;
+                        let start = resume_from.get(&keychain).copied().unwrap_or(0);
+                        (
+                            keychain,
+                            iter.skip(start as usize).inspect(move |(i, _)| {
+                                if first {
+                                    eprint!("\nscanning {}: ", keychain);
+                                    first = false;
+                                }
+
+                                eprint!("{} ", i);
+                                let _ = io::stdout().flush();
+                            }),
+                        )
+                    })
+                    .collect();
+
+                let result = client.wallet_txid_scan(
+                    scripts,
+                    Some(stop_gap),
+                    tracker.chain().checkpoints(),
+                    scan_option.batch_size,
+                );
+                eprintln!();
+                result
+            };
 
-            eprintln!();
+            client.verify_proofs = scan_option.header_check(config.network)?;
+            client.parallel_requests = scan_option.parallel_requests;
+            client.scan_attempts = scan_option.scan_attempts;
+            let (new_sparsechain, keychain_index_update) = scan(&mut client)?;
 
+            if let Some(url) = &scan_option.cross_check {
+                eprintln!("cross-checking against {}", url);
+                let mut other_client = connect(url, server.config(config.network)?)?;
+                other_client.verify_proofs = scan_option.header_check(config.network)?;
+                other_client.parallel_requests = scan_option.parallel_requests;
+                other_client.scan_attempts = scan_option.scan_attempts;
+                other_client.cancel = client.cancel.clone();
+                let (other, _) = scan(&mut other_client)?;
+                cross_check(&new_sparsechain, &other)?;
+            }
+
+            previous.advance(new_sparsechain.latest_checkpoint(), &keychain_index_update);
+            cursor = Some(previous);
             keychain_changeset.derivation_indices = keychain_index_update;
 
             new_sparsechain
         }
-        ElectrumCommands::Sync {
+        bdk_cli::Commands::ChainSpecific(ElectrumCommands::Sync {
             mut unused,
             mut unspent,
             all,
+            incremental,
+            order,
             scan_option,
-        } => {
-            let txout_index = &tracker.txout_index;
+        }) => {
             if !(all || unused || unspent) {
                 unused = true;
                 unspent = true;
@@ -129,60 +403,518 @@ fn main() -> anyhow::Result<()> {
                 unused = false;
                 unspent = false
             }
-            let mut spks: Box<dyn Iterator<Item = bdk_chain::bitcoin::Script>> =
-                Box::new(core::iter::empty());
-            if unused {
-                spks = Box::new(spks.chain(txout_index.inner().unused(..).map(
-                    |(index, script)| {
-                        eprintln!("Checking if address at {:?} has been used", index);
-                        script.clone()
-                    },
-                )));
-            }
+            let known_statuses = if incremental {
+                bdk_cli::load_extension::<BTreeMap<Script, StatusHash>, _, _>(
+                    &mut db,
+                    STATUSES_NAME,
+                )?
+                .unwrap_or_default()
+            } else {
+                BTreeMap::new()
+            };
+            let external_scripts = bdk_cli::load_extension::<bdk_cli::ExternalScripts, _, _>(
+                &mut db,
+                bdk_cli::EXTERNAL_SCRIPTS_EXTENSION,
+            )?
+            .unwrap_or_default();
+
+            let scan = |client: &mut ElectrumClient| {
+                let txout_index = &tracker.txout_index;
+                // the wallet's scripts along with what syncing each of them checks
+                let mut wallet_spks = Vec::<(Script, String)>::new();
+                if unused {
+                    wallet_spks.extend(txout_index.inner().unused(..).map(|(index, script)| {
+                        (
+                            script.clone(),
+                            format!("Checking if address at {:?} has been used", index),
+                        )
+                    }));
+                }
 
-            if all {
-                spks = Box::new(spks.chain(txout_index.script_pubkeys().iter().map(
-                    |(index, script)| {
-                        eprintln!("scanning {:?}", index);
+                if all {
+                    wallet_spks.extend(
+                        txout_index.script_pubkeys().iter().map(|(index, script)| {
+                            (script.clone(), format!("scanning {:?}", index))
+                        }),
+                    );
+                }
+
+                if unspent {
+                    wallet_spks.extend(tracker.full_utxos().map(|(_index, ftxout)| {
+                        (
+                            ftxout.txout.script_pubkey,
+                            format!("checking if {} has been spent", ftxout.outpoint),
+                        )
+                    }));
+                }
+                bdk_cli::rank_scripts(
+                    &mut wallet_spks,
+                    &bdk_cli::script_activity(&tracker),
+                    &order,
+                );
+
+                let mut spks: Box<dyn Iterator<Item = Script>> =
+                    Box::new(wallet_spks.into_iter().map(|(script, checking)| {
+                        eprintln!("{}", checking);
+                        script
+                    }));
+                // the outputs of external scripts can only be found by syncing them
+                spks = Box::new(spks.chain(external_scripts.scripts.iter().map(
+                    |(script, metadata)| {
+                        eprintln!("checking {} script {}", metadata.kind, script);
                         script.clone()
                     },
                 )));
+
+                if incremental {
+                    client
+                        .spk_txid_sync(
+                            spks,
+                            tracker.chain().checkpoints(),
+                            &known_statuses,
+                            scan_option.batch_size,
+                        )
+                        .map(|(chain, changed)| (chain, Some(changed)))
+                        .context("syncing the blockchain")
+                } else {
+                    client
+                        .spk_txid_scan(spks, tracker.chain().checkpoints(), scan_option.batch_size)
+                        .map(|chain| (chain, None))
+                        .context("scanning the blockchain")
+                }
+            };
+
+            client.verify_proofs = scan_option.header_check(config.network)?;
+            client.parallel_requests = scan_option.parallel_requests;
+            client.scan_attempts = scan_option.scan_attempts;
+            let (new_sparsechain, changed_statuses) = scan(&mut client)?;
+
+            if let Some(url) = &scan_option.cross_check {
+                eprintln!("cross-checking against {}", url);
+                let mut other_client = connect(url, server.config(config.network)?)?;
+                other_client.verify_proofs = scan_option.header_check(config.network)?;
+                other_client.parallel_requests = scan_option.parallel_requests;
+                other_client.scan_attempts = scan_option.scan_attempts;
+                other_client.cancel = client.cancel.clone();
+                let (other, _) = scan(&mut other_client)?;
+                cross_check(&new_sparsechain, &other)?;
             }
 
-            if unspent {
-                spks = Box::new(spks.chain(tracker.full_utxos().map(|(_index, ftxout)| {
-                    eprintln!("checking if {} has been spent", ftxout.outpoint);
-                    ftxout.txout.script_pubkey
-                })));
+            if let Some(changed_statuses) = changed_statuses {
+                let mut merged = known_statuses;
+                for (spk, status) in changed_statuses {
+                    match status {
+                        Some(status) => merged.insert(spk, status),
+                        None => merged.remove(&spk),
+                    };
+                }
+                statuses = Some(merged);
             }
 
-            let new_sparsechain = client
-                .spk_txid_scan(spks, tracker.chain().checkpoints(), scan_option.batch_size)
-                .context("scanning the blockchain")?;
+            new_sparsechain
+        }
+        bdk_cli::Commands::ChainSpecific(ElectrumCommands::Watch {
+            lookahead,
+            poll_secs,
+            batch_size,
+            webhook_command,
+            #[cfg(feature = "grpc")]
+            grpc,
+            ..
+        }) => {
+            let webhooks = bdk_cli::load_extension(&mut db, WEBHOOKS_EXTENSION)?;
+            let curl = Curl {
+                command: webhook_command,
+            };
+            let mut outlets = Outlets {
+                dispatcher: WebhookDispatcher::new(curl, webhooks.unwrap_or_default()),
+                #[cfg(feature = "grpc")]
+                grpc: match grpc {
+                    Some(addr) => {
+                        let listener = std::net::TcpListener::bind(addr)
+                            .with_context(|| format!("failed to listen on {}", addr))?;
+                        let (requests, _server) = bdk_cli::grpc::serve(listener)?;
+                        eprintln!("serving gRPC on {}", addr);
+                        Some(GrpcOutlet {
+                            requests,
+                            network: config.network,
+                            signers,
+                        })
+                    }
+                    None => None,
+                },
+            };
+            return watch(
+                &mut client,
+                &mut tracker,
+                &mut db,
+                lookahead,
+                Duration::from_secs(poll_secs),
+                batch_size,
+                &mut outlets,
+            );
+        }
+        bdk_cli::Commands::ChainSpecific(ElectrumCommands::Status { .. }) => {
+            print!("{}", client.server_info()?);
+            return Ok(());
+        }
+        bdk_cli::Commands::Import { descriptors_json } => {
+            let imported = bdk_cli::run_import_cmd(&mut tracker, &mut db, &descriptors_json)?;
+
+            // only rescan the range that was imported
+            let scripts = imported
+                .iter()
+                .map(|keychain| {
+                    eprintln!("rescanning {}", keychain);
+                    (
+                        *keychain,
+                        tracker
+                            .txout_index
+                            .stored_scripts_of_keychain(keychain)
+                            .map(|(i, spk)| (i, spk.clone())),
+                    )
+                })
+                .collect();
+
+            let (new_sparsechain, keychain_index_update) =
+                client.wallet_txid_scan(scripts, None, tracker.chain().checkpoints(), 25)?;
+
+            keychain_changeset.derivation_indices = keychain_index_update;
 
             new_sparsechain
         }
+        bdk_cli::Commands::Backup { path } => {
+            let report =
+                bdk_cli::run_backup_cmd(&tracker, &mut db, &path, args.db_password.as_deref())?;
+            print!("{}", report);
+            return Ok(());
+        }
+        bdk_cli::Commands::RestoreDb { path } => {
+            let report = bdk_cli::run_restore_db_cmd(
+                &tracker,
+                &config.db_path,
+                &path,
+                args.db_password.as_deref(),
+                config.db_wallet.as_deref(),
+            )?;
+            print!("{}", report);
+            return Ok(());
+        }
+        bdk_cli::Commands::RecoverDb => {
+            let report = bdk_cli::run_recover_db_cmd(&mut db, &config.db_path)?;
+            print!("{}", report);
+            return Ok(());
+        }
+        general_command => {
+            let output = bdk_cli::handle_commands(
+                general_command,
+                client,
+                &mut tracker,
+                &mut db,
+                config.network,
+                &signers,
+            )?;
+            print!("{}", output);
+            return Ok(());
+        }
     };
 
-    let sparsechain_changeset = tracker.chain().determine_changeset(&chain_update)?;
+    let _ = apply_chain_update(
+        &mut client,
+        &mut tracker,
+        &mut db,
+        &chain_update,
+        keychain_changeset,
+    )?;
+    for txid in bdk_cli::broadcast_deferred(&client, &tracker, &mut db)? {
+        eprintln!("broadcast deferred transaction {}", txid);
+    }
+    for (id, status) in bdk_cli::update_invoices(&tracker, &mut db)? {
+        eprintln!("invoice {} is now {}", id, status);
+    }
+    for txid in bdk_cli::abandon_expired(&mut tracker, &mut db)? {
+        eprintln!("abandoned tx {} as it didn't confirm in time", txid);
+    }
+    // only save the cursor once what it points to has been persisted
+    if let Some(cursor) = cursor {
+        if let Err(e) = bdk_cli::save_extension(&mut db, CURSOR_NAME, &cursor) {
+            eprintln!("failed to save the scan progress: {}", e);
+        }
+    }
+    if let Some(statuses) = statuses {
+        if let Err(e) = bdk_cli::save_extension(&mut db, STATUSES_NAME, &statuses) {
+            eprintln!("failed to save the script statuses: {}", e);
+        }
+    }
+    Ok(())
+}
 
-    let new_txids = tracker
-        .chain()
-        .changeset_additions(&sparsechain_changeset)
-        .collect::<Vec<_>>();
+/// Fetches the transactions `chain_update` adds to the tracker and applies it (along with
+/// `keychain_changeset`) to the tracker and store.
+fn apply_chain_update(
+    client: &mut ElectrumClient,
+    tracker: &mut KeychainTracker<Keychain, TxHeight>,
+    db: &mut KeychainStore<Keychain, TxHeight>,
+    chain_update: &SparseChain,
+    mut keychain_changeset: KeychainChangeSet<Keychain, TxHeight>,
+) -> anyhow::Result<KeychainChangeSet<Keychain, TxHeight>> {
+    keychain_changeset.chain_graph =
+        bdk_electrum::inflate_txid_update(&mut client.0, tracker.chain_graph(), chain_update)?;
 
-    let new_txs = client
-        .batch_transaction_get(new_txids.iter())
-        .context("fetching full transactions")?;
+    db.append_changeset(&keychain_changeset)?;
+    bdk_cli::record_evictions(tracker, db, &keychain_changeset)?;
+    tracker.apply_changeset(keychain_changeset.clone());
+    Ok(keychain_changeset)
+}
 
-    let chaingraph_changeset = tracker
-        .chain_graph()
-        .inflate_changeset(sparsechain_changeset, new_txs)
-        .context("inflating changeset")?;
+/// Watches all the wallets `args` describe at once (see [`watch`]), each from its own thread with
+/// its own connection and webhooks. Only returns once every one of them has stopped.
+fn watch_all(
+    args: &bdk_cli::Args<ElectrumCommands>,
+    lookahead: u32,
+    poll_interval: Duration,
+    batch_size: usize,
+    webhook_command: &str,
+    server: &ServerOption,
+) -> anyhow::Result<()> {
+    let configs = args.wallet_configs()?;
+    // a store can only be appended to from one place at a time
+    let mut db_paths = BTreeSet::new();
+    for config in configs.values() {
+        if !db_paths.insert(&config.db_path) {
+            anyhow::bail!(
+                "several wallets are kept in {}, they can't be watched at the same time",
+                config.db_path.display()
+            );
+        }
+    }
 
-    keychain_changeset.chain_graph = chaingraph_changeset;
+    let cancel = args.cancellation_token();
+    let mut watchers = Vec::new();
+    for (name, config) in configs {
+        let bdk_cli::Wallet {
+            name,
+            config,
+            mut tracker,
+            store: mut db,
+            ..
+        } = bdk_cli::open_wallet::<_, TxHeight>(args, name, config)?;
+        let mut server = server.clone();
+        if !config.servers.is_empty() {
+            server.servers = config.servers.clone();
+        }
+        let webhook_command = webhook_command.to_string();
+        let cancel = cancel.clone();
+        let watcher = std::thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                let mut client = server.connect(config.network)?;
+                client.cancel = cancel;
+                let webhooks = bdk_cli::load_extension(&mut db, WEBHOOKS_EXTENSION)?;
+                let curl = Curl {
+                    command: webhook_command,
+                };
+                let mut outlets = Outlets {
+                    dispatcher: WebhookDispatcher::new(curl, webhooks.unwrap_or_default()),
+                    #[cfg(feature = "grpc")]
+                    grpc: None,
+                };
+                watch(
+                    &mut client,
+                    &mut tracker,
+                    &mut db,
+                    lookahead,
+                    poll_interval,
+                    batch_size,
+                    &mut outlets,
+                )
+            })?;
+        eprintln!("watching {} on {}", name, config.network);
+        watchers.push((name, watcher));
+    }
 
-    db.append_changeset(&keychain_changeset)?;
-    tracker.apply_changeset(keychain_changeset);
+    let mut stopped = 0;
+    for (name, watcher) in watchers {
+        let error = match watcher.join() {
+            Ok(Ok(())) => continue,
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "the thread panicked".to_string(),
+        };
+        eprintln!("stopped watching {}: {}", name, error);
+        stopped += 1;
+    }
+    if stopped > 0 {
+        anyhow::bail!("{} of the wallets stopped with an error", stopped);
+    }
     Ok(())
 }
+
+/// Keeps the wallet up to date by subscribing to its scripts and syncing the ones the server
+/// notifies us about.
+///
+/// `lookahead` unused scripts are kept stored (and subscribed to) for each keychain. Scripts that
+/// get stored while watching, e.g. when one of the lookahead scripts is used, are subscribed to on
+/// the next poll.
+///
+/// What happens to the wallet is sent to the webhooks (and gRPC clients) of `outlets`. A sync the
+/// server reorging interrupts is tried again on the next poll.
+fn watch(
+    client: &mut ElectrumClient,
+    tracker: &mut KeychainTracker<Keychain, TxHeight>,
+    db: &mut KeychainStore<Keychain, TxHeight>,
+    lookahead: u32,
+    poll_interval: Duration,
+    batch_size: usize,
+    outlets: &mut Outlets,
+) -> anyhow::Result<()> {
+    let mut subscribed_up_to = BTreeMap::new();
+    let mut subscribed = Vec::new();
+    // scripts whose sync was interrupted by the server's chain changing
+    let mut resync = Vec::new();
+
+    bdk_cli::follow_tip(
+        client,
+        tracker,
+        db,
+        poll_interval,
+        |client, tracker, db, events| {
+            outlets.answer_requests(client, tracker, db);
+            for event in events {
+                match event {
+                    TipEvent::Connected(block) => {
+                        eprintln!("connected block {} at {}", block.hash, block.height)
+                    }
+                    TipEvent::Disconnected(block) => {
+                        eprintln!("disconnected block {} at {}", block.hash, block.height)
+                    }
+                }
+            }
+            let reorg = webhook::reorg_event(events);
+            outlets.queue(reorg.as_slice())?;
+
+            tracker.txout_index.pad_all_with_unused(lookahead);
+
+            // scripts we haven't subscribed to yet need a sync since we don't know their history
+            let mut to_sync = tracker
+                .txout_index
+                .stored_scripts_since(&subscribed_up_to)
+                .map(|(_, _, spk)| spk.clone())
+                .collect::<Vec<_>>();
+            for spk in &to_sync {
+                client.script_subscribe(spk)?;
+            }
+            if !to_sync.is_empty() {
+                eprintln!("subscribed to {} new scripts", to_sync.len());
+            }
+            subscribed.extend(to_sync.iter().cloned());
+            subscribed_up_to = tracker.txout_index.derivation_indices();
+
+            // reading the server's response lets the client queue up any notifications
+            client.ping()?;
+            for spk in &subscribed {
+                if client.script_pop(spk)?.is_some() && !to_sync.contains(spk) {
+                    to_sync.push(spk.clone());
+                }
+            }
+
+            for spk in resync.drain(..) {
+                if !to_sync.contains(&spk) {
+                    to_sync.push(spk);
+                }
+            }
+            if to_sync.is_empty() && events.is_empty() {
+                outlets.dispatcher.dispatch();
+                return Ok(());
+            }
+            if !to_sync.is_empty() {
+                let synced = client
+                    .spk_txid_scan(
+                        to_sync.iter().cloned(),
+                        tracker.chain().checkpoints(),
+                        batch_size,
+                    )
+                    .map_err(anyhow::Error::from)
+                    .and_then(|chain_update| {
+                        apply_chain_update(client, tracker, db, &chain_update, Default::default())
+                    });
+                let changeset = match synced {
+                    Ok(changeset) => changeset,
+                    Err(e) if matches!(e.downcast_ref::<ElectrumError>(), Some(e) if e.is_retriable()) =>
+                    {
+                        eprintln!("sync interrupted ({}), retrying on the next poll", e);
+                        resync = to_sync;
+                        outlets.dispatcher.dispatch();
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
+                for (txid, position) in &changeset.chain_graph.chain.txids {
+                    match position {
+                        Some(position) => eprintln!("tx {} is now at {:?}", txid, position),
+                        None => eprintln!("tx {} was evicted", txid),
+                    }
+                }
+                let wallet_events = webhook::changeset_events(tracker, &changeset);
+                outlets.queue(&wallet_events)?;
+            }
+            for txid in bdk_cli::broadcast_deferred(client, tracker, db)? {
+                eprintln!("broadcast deferred transaction {}", txid);
+            }
+            for (id, status) in bdk_cli::update_invoices(tracker, db)? {
+                eprintln!("invoice {} is now {}", id, status);
+            }
+            let abandoned = bdk_cli::abandon_expired(tracker, db)?;
+            for &txid in &abandoned {
+                eprintln!("abandoned tx {} as it didn't confirm in time", txid);
+            }
+            outlets.queue(
+                &abandoned
+                    .into_iter()
+                    .map(|txid| webhook::WalletEvent::TxAbandoned { txid })
+                    .collect::<Vec<_>>(),
+            )?;
+            outlets.dispatcher.dispatch();
+            Ok(())
+        },
+    )
+}
+
+/// Where the `watch` loop sends what happens to the wallet.
+struct Outlets {
+    dispatcher: WebhookDispatcher<Curl>,
+    #[cfg(feature = "grpc")]
+    grpc: Option<GrpcOutlet>,
+}
+
+/// The gRPC server of a watched wallet, along with what the wallet's commands need to answer it.
+#[cfg(feature = "grpc")]
+struct GrpcOutlet {
+    requests: bdk_cli::grpc::WalletRequests<TxHeight>,
+    network: Network,
+    signers: Vec<Box<dyn bdk_cli::Signer>>,
+}
+
+impl Outlets {
+    fn queue(&mut self, events: &[webhook::WalletEvent]) -> anyhow::Result<()> {
+        #[cfg(feature = "grpc")]
+        if let Some(grpc) = &self.grpc {
+            grpc.requests.publish(events);
+        }
+        self.dispatcher.queue(events)
+    }
+
+    /// Runs the commands of the RPCs that came in since the last poll.
+    #[cfg_attr(not(feature = "grpc"), allow(unused_variables))]
+    fn answer_requests(
+        &self,
+        client: &mut ElectrumClient,
+        tracker: &mut KeychainTracker<Keychain, TxHeight>,
+        db: &mut KeychainStore<Keychain, TxHeight>,
+    ) {
+        #[cfg(feature = "grpc")]
+        if let Some(grpc) = &self.grpc {
+            grpc.requests
+                .handle_pending(client, tracker, db, grpc.network, &grpc.signers);
+        }
+    }
+}
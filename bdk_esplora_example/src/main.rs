@@ -7,7 +7,7 @@ use std::io::{self, Write};
 const DEFAULT_PARALLEL_REQUESTS: u8 = 5;
 use bdk_cli::{
     anyhow::{self, Context},
-    clap::{self, Subcommand},
+    clap::{self, Parser, Subcommand},
 };
 
 #[derive(Subcommand, Debug, Clone)]
@@ -17,6 +17,8 @@ enum EsploraCommands {
         /// When a gap this large has been found for a keychain it will stop.
         #[clap(long, default_value = "5")]
         stop_gap: usize,
+        #[clap(flatten)]
+        server: ServerOption,
     },
     /// Scans particular addresses using esplora API
     Sync {
@@ -29,36 +31,73 @@ enum EsploraCommands {
         /// Scan every address that you have derived
         #[clap(long)]
         all: bool,
+        /// The order to scan the wallet's addresses in: `untouched` (by keychain and index),
+        /// `recent` (the most recently used first) or `value` (the most unspent value first)
+        #[clap(long, default_value = "untouched")]
+        order: bdk_cli::ScriptOrder,
+        #[clap(flatten)]
+        server: ServerOption,
     },
 }
 
-fn main() -> anyhow::Result<()> {
-    let (args, keymap, mut keychain_tracker, mut db) = bdk_cli::init::<EsploraCommands, _>()?;
-    let esplora_url = match args.network {
-        Network::Bitcoin => "https://mempool.space/api",
-        Network::Testnet => "https://mempool.space/testnet/api",
-        Network::Regtest => "http://localhost:3000",
-        Network::Signet => "https://mempool.space/signet/api",
-    };
-
-    let client = Client::new(esplora_url, DEFAULT_PARALLEL_REQUESTS)?;
+#[derive(Parser, Debug, Clone, PartialEq)]
+pub struct ServerOption {
+    /// The base url of the esplora API to use (`http://` or `https://`). Defaults to a public
+    /// server of the network.
+    #[clap(long = "server", env = "ESPLORA_URL")]
+    pub url: Option<String>,
+}
 
-    let esplora_cmd = match args.command {
-        bdk_cli::Commands::ChainSpecific(esplora_cmd) => esplora_cmd,
-        general_command => {
-            return bdk_cli::handle_commands(
-                general_command,
-                client,
-                &mut keychain_tracker,
-                &mut db,
-                args.network,
-                &keymap,
-            )
+impl ServerOption {
+    fn url(&self, network: Network) -> anyhow::Result<&str> {
+        let url = match &self.url {
+            Some(url) => url.as_str(),
+            None => match network {
+                Network::Bitcoin => "https://mempool.space/api",
+                Network::Testnet => "https://mempool.space/testnet/api",
+                Network::Regtest => "http://localhost:3000",
+                Network::Signet => "https://mempool.space/signet/api",
+            },
+        };
+        if !(url.starts_with("https://") || url.starts_with("http://")) {
+            anyhow::bail!("esplora url {} must start with http:// or https://", url);
         }
+        Ok(url)
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let (
+        args,
+        bdk_cli::Wallet {
+            config,
+            signers,
+            tracker: mut keychain_tracker,
+            store: mut db,
+            ..
+        },
+    ) = match bdk_cli::init::<EsploraCommands, _>()? {
+        Some(wallet) => wallet,
+        None => return Ok(()),
+    };
+    let mut server = match &args.command {
+        bdk_cli::Commands::ChainSpecific(EsploraCommands::Scan { server, .. })
+        | bdk_cli::Commands::ChainSpecific(EsploraCommands::Sync { server, .. }) => server.clone(),
+        // the general commands don't take esplora options so only the environment applies
+        _ => ServerOption::parse_from([env!("CARGO_PKG_NAME")]),
     };
+    // only one esplora server is used
+    if let Some(url) = config.servers.first() {
+        server.url = Some(url.clone());
+    }
+
+    let client = Client(bdk_esplora::Client::new(
+        server.url(config.network)?,
+        DEFAULT_PARALLEL_REQUESTS,
+    )?);
 
-    match esplora_cmd {
-        EsploraCommands::Scan { stop_gap } => {
+    match args.command {
+        bdk_cli::Commands::ChainSpecific(EsploraCommands::Scan { stop_gap, .. }) => {
             let spk_iterators = keychain_tracker
                 .txout_index
                 .scripts_of_all_keychains()
@@ -87,14 +126,23 @@ fn main() -> anyhow::Result<()> {
                 .context("scanning the blockchain")?;
             eprintln!();
 
-            let changeset = keychain_tracker.apply_update(wallet_scan)?;
+            let changeset = keychain_tracker.determine_changeset(&wallet_scan)?;
             db.append_changeset(&changeset)?;
+            bdk_cli::record_evictions(&keychain_tracker, &mut db, &changeset)?;
+            keychain_tracker.apply_changeset(changeset);
         }
-        EsploraCommands::Sync {
+        bdk_cli::Commands::ChainSpecific(EsploraCommands::Sync {
             mut unused,
             mut unspent,
             all,
-        } => {
+            order,
+            ..
+        }) => {
+            let external_scripts = bdk_cli::load_extension::<bdk_cli::ExternalScripts, _, _>(
+                &mut db,
+                bdk_cli::EXTERNAL_SCRIPTS_EXTENSION,
+            )?
+            .unwrap_or_default();
             let txout_index = &keychain_tracker.txout_index;
             if !(all || unused || unspent) {
                 unused = true;
@@ -103,33 +151,54 @@ fn main() -> anyhow::Result<()> {
                 unused = false;
                 unspent = false
             }
-            let mut spks: Box<dyn Iterator<Item = bdk_chain::bitcoin::Script>> =
-                Box::new(core::iter::empty());
+            // the wallet's scripts along with what scanning each of them checks
+            let mut wallet_spks = Vec::<(bdk_chain::bitcoin::Script, String)>::new();
 
             if all {
-                spks = Box::new(spks.chain(txout_index.script_pubkeys().iter().map(
-                    |(index, script)| {
-                        eprintln!("scanning {:?}", index);
-                        script.clone()
-                    },
-                )));
+                wallet_spks.extend(
+                    txout_index
+                        .script_pubkeys()
+                        .iter()
+                        .map(|(index, script)| (script.clone(), format!("scanning {:?}", index))),
+                );
             }
 
             if unused {
-                spks = Box::new(spks.chain(txout_index.unused(..).map(|(index, script)| {
-                    eprintln!("Checking if address at {:?} has been used", index);
-                    script.clone()
-                })));
+                wallet_spks.extend(txout_index.unused(..).map(|(index, script)| {
+                    (
+                        script.clone(),
+                        format!("Checking if address at {:?} has been used", index),
+                    )
+                }));
             }
 
             if unspent {
-                spks = Box::new(spks.chain(keychain_tracker.full_utxos().map(
-                    |(_index, ftxout)| {
-                        eprintln!("checking if {} has been spent", ftxout.outpoint);
-                        ftxout.txout.script_pubkey
-                    },
-                )));
+                wallet_spks.extend(keychain_tracker.full_utxos().map(|(_index, ftxout)| {
+                    (
+                        ftxout.txout.script_pubkey,
+                        format!("checking if {} has been spent", ftxout.outpoint),
+                    )
+                }));
             }
+            bdk_cli::rank_scripts(
+                &mut wallet_spks,
+                &bdk_cli::script_activity(&keychain_tracker),
+                &order,
+            );
+
+            let mut spks: Box<dyn Iterator<Item = bdk_chain::bitcoin::Script>> =
+                Box::new(wallet_spks.into_iter().map(|(script, checking)| {
+                    eprintln!("{}", checking);
+                    script
+                }));
+
+            // the outputs of external scripts can only be found by syncing them
+            spks = Box::new(spks.chain(external_scripts.scripts.iter().map(
+                |(script, metadata)| {
+                    eprintln!("checking {} script {}", metadata.kind, script);
+                    script.clone()
+                },
+            )));
 
             let local_chain = keychain_tracker.chain().checkpoints().clone();
             let scan = client
@@ -141,9 +210,87 @@ fn main() -> anyhow::Result<()> {
                 .determine_changeset(&scan)?
                 .into();
             db.append_changeset(&changeset)?;
+            bdk_cli::record_evictions(&keychain_tracker, &mut db, &changeset)?;
             keychain_tracker.apply_changeset(changeset);
         }
+        bdk_cli::Commands::Backup { path } => {
+            let report = bdk_cli::run_backup_cmd(
+                &keychain_tracker,
+                &mut db,
+                &path,
+                args.db_password.as_deref(),
+            )?;
+            print!("{}", report);
+            return Ok(());
+        }
+        bdk_cli::Commands::RestoreDb { path } => {
+            let report = bdk_cli::run_restore_db_cmd(
+                &keychain_tracker,
+                &config.db_path,
+                &path,
+                args.db_password.as_deref(),
+                config.db_wallet.as_deref(),
+            )?;
+            print!("{}", report);
+            return Ok(());
+        }
+        bdk_cli::Commands::RecoverDb => {
+            let report = bdk_cli::run_recover_db_cmd(&mut db, &config.db_path)?;
+            print!("{}", report);
+            return Ok(());
+        }
+        bdk_cli::Commands::Import { descriptors_json } => {
+            let imported =
+                bdk_cli::run_import_cmd(&mut keychain_tracker, &mut db, &descriptors_json)?;
+
+            // only rescan the range that was imported
+            let spk_iterators = imported
+                .iter()
+                .map(|keychain| {
+                    eprintln!("rescanning {}", keychain);
+                    (
+                        *keychain,
+                        keychain_tracker
+                            .txout_index
+                            .stored_scripts_of_keychain(keychain)
+                            .map(|(i, spk)| (i, spk.clone())),
+                    )
+                })
+                .collect();
+
+            let local_chain = keychain_tracker.chain().checkpoints().clone();
+
+            let wallet_scan = client
+                .wallet_scan(spk_iterators, &local_chain, None)
+                .context("scanning the blockchain")?;
+
+            let changeset = keychain_tracker.determine_changeset(&wallet_scan)?;
+            db.append_changeset(&changeset)?;
+            bdk_cli::record_evictions(&keychain_tracker, &mut db, &changeset)?;
+            keychain_tracker.apply_changeset(changeset);
+        }
+        general_command => {
+            let output = bdk_cli::handle_commands(
+                general_command,
+                client,
+                &mut keychain_tracker,
+                &mut db,
+                config.network,
+                &signers,
+            )?;
+            print!("{}", output);
+            return Ok(());
+        }
     }
 
+    for txid in bdk_cli::broadcast_deferred(&client, &keychain_tracker, &mut db)? {
+        eprintln!("broadcast deferred transaction {}", txid);
+    }
+    for (id, status) in bdk_cli::update_invoices(&keychain_tracker, &mut db)? {
+        eprintln!("invoice {} is now {}", id, status);
+    }
+    for txid in bdk_cli::abandon_expired(&mut keychain_tracker, &mut db)? {
+        eprintln!("abandoned tx {} as it didn't confirm in time", txid);
+    }
     Ok(())
 }
@@ -26,8 +26,10 @@ use bitcoin::{
     EcdsaSig, SchnorrSig, Script, TxIn, Witness,
 };
 use miniscript::{
+    descriptor::ShInner,
     descriptor::{InnerXKey, Tr},
-    hash256, DefiniteDescriptorKey, Descriptor, DescriptorPublicKey, ScriptContext, ToPublicKey,
+    hash256, DefiniteDescriptorKey, Descriptor, DescriptorPublicKey, ScriptContext, SigType,
+    ToPublicKey,
 };
 
 pub(crate) fn varint_len(v: usize) -> usize {
@@ -35,11 +37,15 @@ pub(crate) fn varint_len(v: usize) -> usize {
 }
 
 mod plan_impls;
+mod policy;
 mod requirements;
 mod template;
+mod timelock;
+pub use policy::*;
 pub use requirements::*;
 pub use template::PlanKey;
 use template::TemplateItem;
+pub use timelock::*;
 
 #[derive(Clone, Debug)]
 enum TrSpend {
@@ -54,7 +60,12 @@ enum TrSpend {
 enum Target {
     Legacy,
     Segwitv0 {
+        /// The script code the signatures commit to
         script_code: Script,
+        /// The script of a P2WSH output, which goes last in the witness
+        witness_script: Option<Script>,
+        /// Pushes the witness program when it is wrapped in P2SH, empty otherwise
+        script_sig: Script,
     },
     Segwitv1 {
         tr: Tr<DefiniteDescriptorKey>,
@@ -62,7 +73,14 @@ enum Target {
     },
 }
 
-impl Target {}
+impl Target {
+    fn sig_type(&self) -> SigType {
+        match self {
+            Target::Legacy | Target::Segwitv0 { .. } => SigType::Ecdsa,
+            Target::Segwitv1 { .. } => SigType::Schnorr,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 /// A plan represents a particular spending path for a descriptor.
@@ -104,7 +122,7 @@ where
 {
     /// The expected satisfaction weight for the plan if it is completed.
     pub fn expected_weight(&self) -> usize {
-        let script_sig_size = match self.target {
+        let script_sig_size = match &self.target {
             Target::Legacy => unimplemented!(), // self
             // .template
             // .iter()
@@ -113,21 +131,24 @@ where
             //     size + push_opcode_size(size)
             // })
             // .sum()
-            Target::Segwitv0 { .. } | Target::Segwitv1 { .. } => 1,
+            Target::Segwitv0 { script_sig, .. } => varint_len(script_sig.len()) + script_sig.len(),
+            Target::Segwitv1 { .. } => 1,
         };
+        let sig_type = self.target.sig_type();
         let witness_elem_sizes: Option<Vec<usize>> = match &self.target {
             Target::Legacy => None,
-            Target::Segwitv0 { .. } => Some(
+            Target::Segwitv0 { witness_script, .. } => Some(
                 self.template
                     .iter()
-                    .map(|step| step.expected_size())
+                    .map(|step| step.expected_size(sig_type))
+                    .chain(witness_script.as_ref().map(Script::len))
                     .collect(),
             ),
             Target::Segwitv1 { tr, tr_plan } => {
                 let mut witness_elems = self
                     .template
                     .iter()
-                    .map(|step| step.expected_size())
+                    .map(|step| step.expected_size(sig_type))
                     .collect::<Vec<_>>();
 
                 if let TrSpend::LeafSpend {
@@ -161,6 +182,17 @@ where
         script_sig_size * 4 + witness_size
     }
 
+    /// The number of signatures needed to complete the plan.
+    ///
+    /// [`expected_weight`](Self::expected_weight) counts 64 bytes for each of them, which is a
+    /// byte short for signatures with a sighash type other than `SIGHASH_DEFAULT`.
+    pub fn signature_count(&self) -> usize {
+        self.template
+            .iter()
+            .filter(|item| matches!(item, TemplateItem::Sign(_)))
+            .count()
+    }
+
     pub fn requirements(&self) -> Requirements<Ak> {
         match self.try_complete(&SatisfactionMaterial::default()) {
             PlanState::Complete { .. } => Requirements::default(),
@@ -169,13 +201,15 @@ where
     }
 
     pub fn try_complete(&self, auth_data: &SatisfactionMaterial) -> PlanState<Ak> {
+        let sig_type = self.target.sig_type();
         let unsatisfied_items = self
             .template
             .iter()
             .filter(|step| match step {
-                TemplateItem::Sign(key) => {
-                    !auth_data.schnorr_sigs.contains_key(&key.descriptor_key)
-                }
+                TemplateItem::Sign(key) => match sig_type {
+                    SigType::Ecdsa => !auth_data.ecdsa_sigs.contains_key(&key.descriptor_key),
+                    SigType::Schnorr => !auth_data.schnorr_sigs.contains_key(&key.descriptor_key),
+                },
                 TemplateItem::Hash160(image) => !auth_data.hash160_preimages.contains_key(image),
                 TemplateItem::Hash256(image) => !auth_data.hash256_preimages.contains_key(image),
                 TemplateItem::Sha256(image) => !auth_data.sha256_preimages.contains_key(image),
@@ -190,10 +224,20 @@ where
             let mut witness = self
                 .template
                 .iter()
-                .flat_map(|step| step.to_witness_stack(&auth_data))
+                .flat_map(|step| step.to_witness_stack(&auth_data, sig_type))
                 .collect::<Vec<_>>();
             match &self.target {
-                Target::Segwitv0 { .. } => todo!(),
+                Target::Segwitv0 {
+                    witness_script,
+                    script_sig,
+                    ..
+                } => {
+                    witness.extend(witness_script.as_ref().map(|script| script.to_bytes()));
+                    PlanState::Complete {
+                        final_script_sig: Some(script_sig.clone()).filter(|s| !s.is_empty()),
+                        final_script_witness: Some(Witness::from_vec(witness)),
+                    }
+                }
                 Target::Legacy => todo!(),
                 Target::Segwitv1 {
                     tr_plan: TrSpend::KeySpend,
@@ -230,8 +274,11 @@ where
                 Target::Legacy => {
                     todo!()
                 }
-                Target::Segwitv0 { .. } => {
-                    todo!()
+                Target::Segwitv0 { script_code, .. } => {
+                    requirements.signatures = RequiredSignatures::Segwitv0 {
+                        script_code: script_code.clone(),
+                        keys: vec![],
+                    };
                 }
                 Target::Segwitv1 { tr, tr_plan } => {
                     let spend_info = tr.spend_info();
@@ -261,7 +308,7 @@ where
 
             let required_signatures = match requirements.signatures {
                 RequiredSignatures::Legacy { .. } => todo!(),
-                RequiredSignatures::Segwitv0 { .. } => todo!(),
+                RequiredSignatures::Segwitv0 { ref mut keys, .. } => keys,
                 RequiredSignatures::TapKey { .. } => return PlanState::Incomplete(requirements),
                 RequiredSignatures::TapScript {
                     plan_keys: ref mut keys,
@@ -426,11 +473,23 @@ where
     Ak: CanDerive + Clone,
 {
     match desc {
-        Descriptor::Bare(_) => todo!(),
-        Descriptor::Pkh(_) => todo!(),
-        Descriptor::Wpkh(_) => todo!(),
-        Descriptor::Sh(_) => todo!(),
-        Descriptor::Wsh(_) => todo!(),
+        Descriptor::Wpkh(wpkh) => {
+            crate::plan_impls::plan_satisfaction_wpkh(wpkh, Script::new(), assets)
+        }
+        Descriptor::Wsh(wsh) => {
+            crate::plan_impls::plan_satisfaction_wsh(wsh, Script::new(), assets)
+        }
+        Descriptor::Sh(sh) => match sh.as_inner() {
+            ShInner::Wpkh(wpkh) => {
+                crate::plan_impls::plan_satisfaction_wpkh(wpkh, sh.unsigned_script_sig(), assets)
+            }
+            ShInner::Wsh(wsh) => {
+                crate::plan_impls::plan_satisfaction_wsh(wsh, sh.unsigned_script_sig(), assets)
+            }
+            // legacy spends aren't planned for yet
+            ShInner::SortedMulti(_) | ShInner::Ms(_) => None,
+        },
         Descriptor::Tr(tr) => crate::plan_impls::plan_satisfaction_tr(tr, assets),
+        Descriptor::Bare(_) | Descriptor::Pkh(_) => None,
     }
 }
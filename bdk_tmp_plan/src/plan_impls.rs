@@ -1,6 +1,8 @@
 use bdk_chain::{bitcoin, miniscript};
-use bitcoin::locktime::{Height, Time};
-use miniscript::Terminal;
+use miniscript::{
+    descriptor::{Wpkh, Wsh, WshInner},
+    Miniscript, Tap, Terminal,
+};
 
 use super::*;
 
@@ -50,8 +52,11 @@ impl<Ak> TermPlan<Ak> {
         })
     }
 
-    pub(crate) fn expected_size(&self) -> usize {
-        self.template.iter().map(|step| step.expected_size()).sum()
+    pub(crate) fn expected_size(&self, sig_type: SigType) -> usize {
+        self.template
+            .iter()
+            .map(|step| step.expected_size(sig_type))
+            .sum()
     }
 }
 
@@ -78,28 +83,53 @@ impl<Ak> TermPlan<Ak> {
 //     }
 // }
 
-// impl crate::descriptor::Wpkh<DefiniteDescriptorKey> {
-//     pub(crate) fn plan_satisfaction<Ak>(&self, assets: &Assets<Ak>) -> Option<Plan<Ak>>
-//     where
-//         Ak: CanDerive + Clone,
-//     {
-//         let (asset_key, derivation_hint) = assets.keys.iter().find_map(|asset_key| {
-//             let derivation_hint = asset_key.can_derive(self.as_inner())?;
-//             Some((asset_key, derivation_hint))
-//         })?;
+pub(crate) fn plan_satisfaction_wpkh<Ak>(
+    wpkh: &Wpkh<DefiniteDescriptorKey>,
+    script_sig: Script,
+    assets: &Assets<Ak>,
+) -> Option<Plan<Ak>>
+where
+    Ak: CanDerive + Clone,
+{
+    let key = wpkh.as_inner();
+    Some(Plan {
+        template: vec![
+            sign_step(key, assets)?,
+            TemplateItem::Pk { key: key.clone() },
+        ],
+        target: Target::Segwitv0 {
+            script_code: wpkh.ecdsa_sighash_script_code(),
+            witness_script: None,
+            script_sig,
+        },
+        set_locktime: None,
+        set_sequence: None,
+    })
+}
 
-//         Some(Plan {
-//             template: vec![TemplateItem::Sign(PlanKey {
-//                 asset_key: asset_key.clone(),
-//                 descriptor_key: self.as_inner().clone(),
-//                 derivation_hint,
-//             })],
-//             target: Target::Segwitv0,
-//             set_locktime: None,
-//             set_sequence: None,
-//         })
-//     }
-// }
+pub(crate) fn plan_satisfaction_wsh<Ak>(
+    wsh: &Wsh<DefiniteDescriptorKey>,
+    script_sig: Script,
+    assets: &Assets<Ak>,
+) -> Option<Plan<Ak>>
+where
+    Ak: CanDerive + Clone,
+{
+    let plan = match wsh.as_inner() {
+        WshInner::SortedMulti(multi) => plan_steps(&multi.sorted_node(), assets)?,
+        WshInner::Ms(ms) => plan_steps(&ms.node, assets)?,
+    };
+    Some(Plan {
+        template: plan.template,
+        target: Target::Segwitv0 {
+            script_code: wsh.ecdsa_sighash_script_code(),
+            witness_script: Some(wsh.inner_script()),
+            script_sig,
+        },
+        set_locktime: plan.min_locktime,
+        set_sequence: plan.min_sequence,
+    })
+}
 
 pub(crate) fn plan_satisfaction_tr<Ak>(
     tr: &miniscript::descriptor::Tr<DefiniteDescriptorKey>,
@@ -108,47 +138,63 @@ pub(crate) fn plan_satisfaction_tr<Ak>(
 where
     Ak: CanDerive + Clone,
 {
-    let key_path_spend = assets.keys.iter().find_map(|asset_key| {
-        let derivation_hint = asset_key.can_derive(tr.internal_key())?;
-        Some((asset_key, derivation_hint))
-    });
-
-    if let Some((asset_key, derivation_hint)) = key_path_spend {
-        return Some(Plan {
-            template: vec![TemplateItem::Sign(PlanKey {
-                asset_key: asset_key.clone(),
-                descriptor_key: tr.internal_key().clone(),
-                derivation_hint,
-            })],
-            target: Target::Segwitv1 {
-                tr: tr.clone(),
-                tr_plan: TrSpend::KeySpend,
-            },
-            set_locktime: None,
-            set_sequence: None,
-        });
+    if let Some(plan) = plan_tr_key_path(tr, assets) {
+        return Some(plan);
     }
+    tr.iter_scripts()
+        .filter_map(|(_, ms)| plan_tr_leaf(tr, ms, assets))
+        .min_by_key(|plan| plan.expected_weight())
+}
 
-    let mut plans = tr
-        .iter_scripts()
-        .filter_map(|(_, ms)| Some((ms, (plan_steps(&ms.node, assets)?))))
-        .collect::<Vec<_>>();
-
-    plans.sort_by_cached_key(|(_, plan)| plan.expected_size());
-
-    let (script, best_plan) = plans.into_iter().next()?;
+/// Plans spending `tr` with the key path.
+pub(crate) fn plan_tr_key_path<Ak>(
+    tr: &miniscript::descriptor::Tr<DefiniteDescriptorKey>,
+    assets: &Assets<Ak>,
+) -> Option<Plan<Ak>>
+where
+    Ak: CanDerive + Clone,
+{
+    let (asset_key, derivation_hint) = assets.keys.iter().find_map(|asset_key| {
+        let derivation_hint = asset_key.can_derive(tr.internal_key())?;
+        Some((asset_key, derivation_hint))
+    })?;
+    Some(Plan {
+        template: vec![TemplateItem::Sign(PlanKey {
+            asset_key: asset_key.clone(),
+            descriptor_key: tr.internal_key().clone(),
+            derivation_hint,
+        })],
+        target: Target::Segwitv1 {
+            tr: tr.clone(),
+            tr_plan: TrSpend::KeySpend,
+        },
+        set_locktime: None,
+        set_sequence: None,
+    })
+}
 
+/// Plans spending `tr` with its leaf `ms`. The script and the control block (which grows with the
+/// depth of the leaf) are part of the witness too, which [`Plan::expected_weight`] accounts for.
+pub(crate) fn plan_tr_leaf<Ak>(
+    tr: &miniscript::descriptor::Tr<DefiniteDescriptorKey>,
+    ms: &Miniscript<DefiniteDescriptorKey, Tap>,
+    assets: &Assets<Ak>,
+) -> Option<Plan<Ak>>
+where
+    Ak: CanDerive + Clone,
+{
+    let plan = plan_steps(&ms.node, assets)?;
     Some(Plan {
         target: Target::Segwitv1 {
             tr: tr.clone(),
             tr_plan: TrSpend::LeafSpend {
-                script: script.encode(),
+                script: ms.encode(),
                 leaf_version: LeafVersion::TapScript,
             },
         },
-        set_locktime: best_plan.min_locktime.clone(),
-        set_sequence: best_plan.min_sequence.clone(),
-        template: best_plan.template,
+        set_locktime: plan.min_locktime,
+        set_sequence: plan.min_sequence,
+        template: plan.template,
     })
 }
 
@@ -178,6 +224,43 @@ impl<Ak> Default for TermPlan<Ak> {
     }
 }
 
+/// The cheapest of two ways to satisfy a fragment of a script context signing with `sig_type`.
+fn cheapest<Ak>(
+    lhs: Option<TermPlan<Ak>>,
+    rhs: Option<TermPlan<Ak>>,
+    sig_type: SigType,
+) -> Option<TermPlan<Ak>> {
+    match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => {
+            if lhs.expected_size(sig_type) <= rhs.expected_size(sig_type) {
+                Some(lhs)
+            } else {
+                Some(rhs)
+            }
+        }
+        (lhs, rhs) => lhs.or(rhs),
+    }
+}
+
+/// The step signing for `key` with one of the asset keys, if any of them can derive it.
+fn sign_step<Ak: Clone + CanDerive>(
+    key: &DefiniteDescriptorKey,
+    assets: &Assets<Ak>,
+) -> Option<TemplateItem<Ak>> {
+    let (asset_key, derivation_hint) = assets
+        .keys
+        .iter()
+        .find_map(|asset_key| Some((asset_key, asset_key.can_derive(key)?)))?;
+    Some(TemplateItem::Sign(PlanKey {
+        asset_key: asset_key.clone(),
+        derivation_hint,
+        descriptor_key: key.clone(),
+    }))
+}
+
+// The template lists the witness elements from the bottom of the stack up, so the elements of
+// the fragment that runs first go last: the satisfaction of `and_v(X,Y)` is `sat(Y) sat(X)`.
+
 fn plan_steps<Ak: Clone + CanDerive, Ctx: ScriptContext>(
     term: &Terminal<DefiniteDescriptorKey, Ctx>,
     assets: &Assets<Ak>,
@@ -185,31 +268,11 @@ fn plan_steps<Ak: Clone + CanDerive, Ctx: ScriptContext>(
     match term {
         Terminal::True => Some(TermPlan::new(vec![])),
         Terminal::False => return None,
-        Terminal::PkH(key) => {
-            let (asset_key, derivation_hint) = assets
-                .keys
-                .iter()
-                .find_map(|asset_key| Some((asset_key, asset_key.can_derive(key)?)))?;
-            Some(TermPlan::new(vec![
-                TemplateItem::Sign(PlanKey {
-                    asset_key: asset_key.clone(),
-                    derivation_hint,
-                    descriptor_key: key.clone(),
-                }),
-                TemplateItem::Pk { key: key.clone() },
-            ]))
-        }
-        Terminal::PkK(key) => {
-            let (asset_key, derivation_hint) = assets
-                .keys
-                .iter()
-                .find_map(|asset_key| Some((asset_key, asset_key.can_derive(key)?)))?;
-            Some(TermPlan::new(vec![TemplateItem::Sign(PlanKey {
-                asset_key: asset_key.clone(),
-                derivation_hint,
-                descriptor_key: key.clone(),
-            })]))
-        }
+        Terminal::PkH(key) => Some(TermPlan::new(vec![
+            sign_step(key, assets)?,
+            TemplateItem::Pk { key: key.clone() },
+        ])),
+        Terminal::PkK(key) => Some(TermPlan::new(vec![sign_step(key, assets)?])),
         Terminal::RawPkH(_pk_hash) => {
             /* TODO */
             None
@@ -217,11 +280,10 @@ fn plan_steps<Ak: Clone + CanDerive, Ctx: ScriptContext>(
         Terminal::After(locktime) => {
             let max_locktime = assets.max_locktime?;
             let locktime = LockTime::from(locktime);
-            let (height, time) = match max_locktime {
-                LockTime::Blocks(height) => (height, Time::from_consensus(0).unwrap()),
-                LockTime::Seconds(seconds) => (Height::from_consensus(0).unwrap(), seconds),
-            };
-            if max_locktime.is_satisfied_by(height, time) {
+            // a height never satisfies a time based locktime and vice versa
+            if locktime.is_same_unit(max_locktime)
+                && locktime.to_consensus_u32() <= max_locktime.to_consensus_u32()
+            {
                 Some(TermPlan {
                     min_locktime: Some(locktime),
                     ..Default::default()
@@ -290,12 +352,34 @@ fn plan_steps<Ak: Clone + CanDerive, Ctx: ScriptContext>(
         Terminal::AndV(l, r) | Terminal::AndB(l, r) => {
             let lhs = plan_steps(&l.node, assets)?;
             let rhs = plan_steps(&r.node, assets)?;
-            lhs.combine(rhs)
+            rhs.combine(lhs)
+        }
+        Terminal::AndOr(x, y, z) => {
+            // `X NOTIF Z ELSE Y ENDIF`
+            let xy = plan_steps(&x.node, assets)
+                .zip(plan_steps(&y.node, assets))
+                .and_then(|(x, y)| y.combine(x));
+            let z = plan_steps(&z.node, assets)
+                .zip(dissat_steps(&x.node))
+                .and_then(|(z, x)| z.combine(x));
+            cheapest(xy, z, Ctx::sig_type())
+        }
+        Terminal::OrB(x, z) => {
+            let x_only = plan_steps(&x.node, assets)
+                .zip(dissat_steps(&z.node))
+                .and_then(|(x, z)| z.combine(x));
+            let z_only = plan_steps(&z.node, assets)
+                .zip(dissat_steps(&x.node))
+                .and_then(|(z, x)| z.combine(x));
+            cheapest(x_only, z_only, Ctx::sig_type())
+        }
+        Terminal::OrD(x, z) | Terminal::OrC(x, z) => {
+            // Z only runs when X fails
+            let z = plan_steps(&z.node, assets)
+                .zip(dissat_steps(&x.node))
+                .and_then(|(z, x)| z.combine(x));
+            cheapest(plan_steps(&x.node, assets), z, Ctx::sig_type())
         }
-        Terminal::AndOr(_, _, _) => todo!(),
-        Terminal::OrB(_, _) => todo!(),
-        Terminal::OrD(_, _) => todo!(),
-        Terminal::OrC(_, _) => todo!(),
         Terminal::OrI(lhs, rhs) => {
             let lplan = plan_steps(&lhs.node, assets).map(|mut plan| {
                 plan.template.push(TemplateItem::One);
@@ -305,19 +389,134 @@ fn plan_steps<Ak: Clone + CanDerive, Ctx: ScriptContext>(
                 plan.template.push(TemplateItem::Zero);
                 plan
             });
-            match (lplan, rplan) {
-                (Some(lplan), Some(rplan)) => {
-                    if lplan.expected_size() <= rplan.expected_size() {
-                        Some(lplan)
-                    } else {
-                        Some(rplan)
+            cheapest(lplan, rplan, Ctx::sig_type())
+        }
+        Terminal::Thresh(k, subs) => {
+            // satisfy the `k` subs that cost the least more than dissatisfying them
+            let mut options = subs
+                .iter()
+                .map(|sub| {
+                    let dissat = dissat_steps(&sub.node)?;
+                    let sat = plan_steps(&sub.node, assets);
+                    let extra = sat.as_ref().map(|sat| {
+                        sat.expected_size(Ctx::sig_type()) as isize
+                            - dissat.expected_size(Ctx::sig_type()) as isize
+                    });
+                    Some((sat, dissat, extra))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            let mut by_extra = (0..options.len())
+                .filter(|&i| options[i].2.is_some())
+                .collect::<Vec<_>>();
+            if by_extra.len() < *k {
+                return None;
+            }
+            by_extra.sort_by_key(|&i| options[i].2);
+            let satisfied = by_extra[..*k].to_vec();
+
+            let mut plan = TermPlan::default();
+            for (i, (sat, dissat, _)) in options.drain(..).enumerate().rev() {
+                let step = match satisfied.contains(&i) {
+                    true => sat.expect("only satisfiable subs are picked"),
+                    false => dissat,
+                };
+                plan = plan.combine(step)?;
+            }
+            Some(plan)
+        }
+        Terminal::Multi(k, keys) => {
+            // `CHECKMULTISIG` pops an extra element off the stack and wants the signatures in the
+            // order of the keys
+            let mut template = vec![TemplateItem::Zero];
+            template.extend(
+                keys.iter()
+                    .filter_map(|key| sign_step(key, assets))
+                    .take(*k),
+            );
+            if template.len() <= *k {
+                return None;
+            }
+            Some(TermPlan::new(template))
+        }
+        Terminal::MultiA(k, keys) => {
+            // `<key_1> CHECKSIG <key_2> CHECKSIGADD ... <k> NUMEQUAL` checks the signature of the
+            // first key last, an empty signature stands in for the keys that don't sign
+            let mut signed = 0;
+            let mut template = keys
+                .iter()
+                .map(|key| match sign_step(key, assets) {
+                    Some(step) if signed < *k => {
+                        signed += 1;
+                        step
                     }
-                }
-                (lplan, rplan) => lplan.or(rplan),
+                    _ => TemplateItem::Zero,
+                })
+                .collect::<Vec<_>>();
+            if signed < *k {
+                return None;
             }
+            template.reverse();
+            Some(TermPlan::new(template))
+        }
+    }
+}
+
+/// How to make `term` fail without aborting the script, which the `or` fragments and `thresh`
+/// need for the branches that aren't taken.
+///
+/// Hash pre-image checks can't be dissatisfied this way yet as that needs a 32 byte value that
+/// isn't the pre-image.
+fn dissat_steps<Ak, Ctx: ScriptContext>(
+    term: &Terminal<DefiniteDescriptorKey, Ctx>,
+) -> Option<TermPlan<Ak>> {
+    match term {
+        Terminal::False => Some(TermPlan::new(vec![])),
+        // an empty signature
+        Terminal::PkK(_) => Some(TermPlan::new(vec![TemplateItem::Zero])),
+        Terminal::PkH(key) => Some(TermPlan::new(vec![
+            TemplateItem::Zero,
+            TemplateItem::Pk { key: key.clone() },
+        ])),
+        Terminal::Alt(ms)
+        | Terminal::Swap(ms)
+        | Terminal::Check(ms)
+        | Terminal::ZeroNotEqual(ms) => dissat_steps(&ms.node),
+        Terminal::DupIf(_) | Terminal::NonZero(_) => Some(TermPlan::new(vec![TemplateItem::Zero])),
+        Terminal::AndB(l, r) => {
+            let lhs = dissat_steps(&l.node)?;
+            let rhs = dissat_steps(&r.node)?;
+            rhs.combine(lhs)
+        }
+        Terminal::AndOr(x, _, z) | Terminal::OrB(x, z) | Terminal::OrD(x, z) => {
+            let x = dissat_steps(&x.node)?;
+            let z = dissat_steps(&z.node)?;
+            z.combine(x)
+        }
+        Terminal::OrI(lhs, rhs) => {
+            let lplan = dissat_steps(&lhs.node).map(|mut plan: TermPlan<Ak>| {
+                plan.template.push(TemplateItem::One);
+                plan
+            });
+            let rplan = dissat_steps(&rhs.node).map(|mut plan: TermPlan<Ak>| {
+                plan.template.push(TemplateItem::Zero);
+                plan
+            });
+            cheapest(lplan, rplan, Ctx::sig_type())
+        }
+        Terminal::Thresh(_, subs) => {
+            let mut plan = TermPlan::default();
+            for sub in subs.iter().rev() {
+                plan = plan.combine(dissat_steps(&sub.node)?)?;
+            }
+            Some(plan)
         }
-        Terminal::Thresh(_, _) => todo!(),
-        Terminal::Multi(_, _) => todo!(),
-        Terminal::MultiA(_, _) => todo!(),
+        // the extra element and no signatures
+        Terminal::Multi(k, _) => Some(TermPlan::new(
+            (0..=*k).map(|_| TemplateItem::Zero).collect(),
+        )),
+        Terminal::MultiA(_, keys) => Some(TermPlan::new(
+            keys.iter().map(|_| TemplateItem::Zero).collect(),
+        )),
+        _ => None,
     }
 }
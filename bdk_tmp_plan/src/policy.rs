@@ -0,0 +1,326 @@
+//! Describing what it takes to spend from a descriptor.
+//!
+//! [`spending_policy`] breaks a descriptor down into the branches it can be spent with (the key
+//! path and each leaf of a `tr` descriptor, the descriptor as a whole otherwise) and gives the
+//! conditions of each branch as a tree: the keys that have to sign, the thresholds, the timelocks
+//! and the hash locks. Along with it come the weights of satisfying the branch and whether the
+//! given assets can satisfy it.
+use bdk_chain::miniscript::{
+    policy::{Liftable, Semantic},
+    Miniscript, Tap,
+};
+
+use super::*;
+
+/// A way of spending from a descriptor, see [`spending_policy`].
+#[derive(Clone, Debug)]
+pub struct PolicyBranch {
+    pub kind: BranchKind,
+    /// The conditions of the branch
+    pub policy: PolicyNode,
+    /// The weight of the cheapest satisfaction of the branch with every key signing and every
+    /// pre-image at hand. `None` if the branch can never be satisfied or spends of this type of
+    /// descriptor aren't planned for.
+    pub weight: Option<usize>,
+    /// The weight of the cheapest satisfaction of the branch with the assets once its timelocks
+    /// have passed, `None` if the assets can't satisfy it
+    pub assets_weight: Option<usize>,
+}
+
+impl PolicyBranch {
+    /// Whether the assets can satisfy the branch once its timelocks have passed.
+    pub fn is_satisfiable(&self) -> bool {
+        self.policy.satisfiable
+    }
+}
+
+/// Where a [`PolicyBranch`] is in its descriptor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BranchKind {
+    /// The key path of a `tr` descriptor
+    KeyPath,
+    /// A leaf of the tree of a `tr` descriptor
+    Leaf {
+        /// The depth of the leaf in the tree
+        depth: u8,
+        script: Script,
+    },
+    /// The only branch of a descriptor that isn't `tr`. Its alternatives are all in its policy.
+    Script,
+}
+
+impl core::fmt::Display for BranchKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BranchKind::KeyPath => write!(f, "key path"),
+            BranchKind::Leaf { depth, .. } => write!(f, "leaf at depth {}", depth),
+            BranchKind::Script => write!(f, "script"),
+        }
+    }
+}
+
+/// A condition of a spending policy, along with whether the assets it was made with satisfy it.
+/// Timelocks are taken to be satisfied since they pass eventually.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicyNode {
+    pub condition: Condition,
+    pub satisfiable: bool,
+}
+
+/// The conditions of a [`PolicyNode`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Condition {
+    /// A signature for the key
+    Key(DefiniteDescriptorKey),
+    /// The absolute timelock of an `after` fragment
+    After(LockTime),
+    /// The relative timelock of an `older` fragment
+    Older(Sequence),
+    Sha256(sha256::Hash),
+    Hash256(hash256::Hash),
+    Ripemd160(ripemd160::Hash),
+    Hash160(hash160::Hash),
+    /// At least `k` of the nodes
+    Threshold(usize, Vec<PolicyNode>),
+    /// Always satisfied
+    Trivial,
+    /// Never satisfied
+    Unsatisfiable,
+}
+
+impl PolicyNode {
+    fn from_semantic<Ak: CanDerive>(
+        policy: &Semantic<DefiniteDescriptorKey>,
+        assets: &Assets<Ak>,
+    ) -> Self {
+        let (condition, satisfiable) = match policy {
+            Semantic::Key(key) => (
+                Condition::Key(key.clone()),
+                assets
+                    .keys
+                    .iter()
+                    .any(|asset_key| asset_key.can_derive(key).is_some()),
+            ),
+            Semantic::After(locktime) => (Condition::After(LockTime::from(*locktime)), true),
+            Semantic::Older(sequence) => (Condition::Older(*sequence), true),
+            Semantic::Sha256(image) => (Condition::Sha256(*image), assets.sha256.contains(image)),
+            Semantic::Hash256(image) => {
+                (Condition::Hash256(*image), assets.hash256.contains(image))
+            }
+            Semantic::Ripemd160(image) => (
+                Condition::Ripemd160(*image),
+                assets.ripemd160.contains(image),
+            ),
+            Semantic::Hash160(image) => {
+                (Condition::Hash160(*image), assets.hash160.contains(image))
+            }
+            Semantic::Threshold(k, subs) => {
+                let subs = subs
+                    .iter()
+                    .map(|sub| PolicyNode::from_semantic(sub, assets))
+                    .collect::<Vec<_>>();
+                let satisfiable = subs.iter().filter(|sub| sub.satisfiable).count() >= *k;
+                (Condition::Threshold(*k, subs), satisfiable)
+            }
+            Semantic::Trivial => (Condition::Trivial, true),
+            Semantic::Unsatisfiable => (Condition::Unsatisfiable, false),
+        };
+        PolicyNode {
+            condition,
+            satisfiable,
+        }
+    }
+
+    /// The keys, hashes and timelocks of the node and the nodes under it.
+    fn collect_assets(&self, assets: &mut Assets<DescriptorPublicKey>) {
+        match &self.condition {
+            Condition::Key(key) => assets.keys.push(key.clone().into()),
+            Condition::After(locktime) => {
+                assets.max_locktime = Some(latest_locktime(assets.max_locktime, *locktime))
+            }
+            Condition::Older(sequence) => {
+                assets.txo_age = Some(longest_sequence(assets.txo_age, *sequence))
+            }
+            Condition::Sha256(image) => assets.sha256.push(*image),
+            Condition::Hash256(image) => assets.hash256.push(*image),
+            Condition::Ripemd160(image) => assets.ripemd160.push(*image),
+            Condition::Hash160(image) => assets.hash160.push(*image),
+            Condition::Threshold(_, subs) => {
+                for sub in subs {
+                    sub.collect_assets(assets);
+                }
+            }
+            Condition::Trivial | Condition::Unsatisfiable => {}
+        }
+    }
+
+    fn fmt_indented(&self, f: &mut core::fmt::Formatter<'_>, indent: usize) -> core::fmt::Result {
+        let mark = if self.satisfiable { "" } else { " (missing)" };
+        write!(f, "{:indent$}", "", indent = indent)?;
+        match &self.condition {
+            Condition::Key(key) => writeln!(f, "key {}{}", key, mark),
+            Condition::After(LockTime::Blocks(height)) => writeln!(f, "after block {}", height),
+            Condition::After(LockTime::Seconds(time)) => writeln!(f, "after time {}", time),
+            Condition::Older(sequence) => {
+                let value = sequence.to_consensus_u32() & 0xffff;
+                if sequence.is_time_locked() {
+                    writeln!(f, "older {} seconds", value * 512)
+                } else {
+                    writeln!(f, "older {} blocks", value)
+                }
+            }
+            Condition::Sha256(image) => writeln!(f, "sha256 pre-image of {}{}", image, mark),
+            Condition::Hash256(image) => writeln!(f, "hash256 pre-image of {}{}", image, mark),
+            Condition::Ripemd160(image) => {
+                writeln!(f, "ripemd160 pre-image of {}{}", image, mark)
+            }
+            Condition::Hash160(image) => writeln!(f, "hash160 pre-image of {}{}", image, mark),
+            Condition::Threshold(k, subs) => {
+                match *k {
+                    k if k == subs.len() => writeln!(f, "all of{}", mark)?,
+                    1 => writeln!(f, "any of{}", mark)?,
+                    k => writeln!(f, "{} of {}{}", k, subs.len(), mark)?,
+                }
+                for sub in subs {
+                    sub.fmt_indented(f, indent + 2)?;
+                }
+                Ok(())
+            }
+            Condition::Trivial => writeln!(f, "nothing"),
+            Condition::Unsatisfiable => writeln!(f, "never"),
+        }
+    }
+}
+
+/// Shows the tree with a line for each node, marking what the assets miss.
+impl core::fmt::Display for PolicyNode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+/// The later of `current` and `locktime` if they have the same unit, `locktime` otherwise.
+fn latest_locktime(current: Option<LockTime>, locktime: LockTime) -> LockTime {
+    match current {
+        Some(current)
+            if current.is_same_unit(locktime)
+                && current.to_consensus_u32() > locktime.to_consensus_u32() =>
+        {
+            current
+        }
+        _ => locktime,
+    }
+}
+
+/// The longer of `current` and `sequence` if they have the same unit, `sequence` otherwise.
+fn longest_sequence(current: Option<Sequence>, sequence: Sequence) -> Sequence {
+    match current {
+        Some(current)
+            if current.is_height_locked() == sequence.is_height_locked()
+                && current.to_consensus_u32() > sequence.to_consensus_u32() =>
+        {
+            current
+        }
+        _ => sequence,
+    }
+}
+
+/// The part of a descriptor a [`PolicyBranch`] plans the spend of.
+enum BranchSpend<'a> {
+    KeyPath(&'a Tr<DefiniteDescriptorKey>),
+    Leaf(
+        &'a Tr<DefiniteDescriptorKey>,
+        &'a Miniscript<DefiniteDescriptorKey, Tap>,
+    ),
+    Descriptor(&'a Descriptor<DefiniteDescriptorKey>),
+}
+
+impl BranchSpend<'_> {
+    fn plan<Ak: CanDerive + Clone>(&self, assets: &Assets<Ak>) -> Option<Plan<Ak>> {
+        match self {
+            BranchSpend::KeyPath(tr) => plan_impls::plan_tr_key_path(tr, assets),
+            BranchSpend::Leaf(tr, ms) => plan_impls::plan_tr_leaf(tr, ms, assets),
+            BranchSpend::Descriptor(desc) => plan_satisfaction(desc, assets),
+        }
+    }
+}
+
+fn policy_branch<Ak: CanDerive + Clone>(
+    kind: BranchKind,
+    policy: &Semantic<DefiniteDescriptorKey>,
+    spend: BranchSpend<'_>,
+    assets: &Assets<Ak>,
+) -> PolicyBranch {
+    let policy = PolicyNode::from_semantic(policy, assets);
+    let mut full_assets = Assets::default();
+    policy.collect_assets(&mut full_assets);
+    // the assets once the timelocks of the branch have passed
+    let timelocked_assets = Assets {
+        keys: assets.keys.clone(),
+        sha256: assets.sha256.clone(),
+        hash256: assets.hash256.clone(),
+        ripemd160: assets.ripemd160.clone(),
+        hash160: assets.hash160.clone(),
+        max_locktime: full_assets.max_locktime,
+        txo_age: full_assets.txo_age,
+    };
+    PolicyBranch {
+        kind,
+        weight: spend.plan(&full_assets).map(|plan| plan.expected_weight()),
+        assets_weight: spend
+            .plan(&timelocked_assets)
+            .map(|plan| plan.expected_weight()),
+        policy,
+    }
+}
+
+/// The branches `desc` can be spent with: the key path and the leaves of a `tr` descriptor in
+/// the order of its tree, or the whole descriptor otherwise.
+///
+/// The keys and hash pre-images of `assets` decide which branches (and conditions) can be
+/// satisfied. Its timelocks are left out since timelocks pass eventually, the conditions of each
+/// branch show them.
+pub fn spending_policy<Ak>(
+    desc: &Descriptor<DefiniteDescriptorKey>,
+    assets: &Assets<Ak>,
+) -> Vec<PolicyBranch>
+where
+    Ak: CanDerive + Clone,
+{
+    match desc {
+        Descriptor::Tr(tr) => {
+            let key_path = Semantic::Key(tr.internal_key().clone());
+            let mut branches = vec![policy_branch(
+                BranchKind::KeyPath,
+                &key_path,
+                BranchSpend::KeyPath(tr),
+                assets,
+            )];
+            for (depth, ms) in tr.iter_scripts() {
+                let kind = BranchKind::Leaf {
+                    depth,
+                    script: ms.encode(),
+                };
+                let policy = ms.lift().unwrap_or(Semantic::Unsatisfiable);
+                branches.push(policy_branch(
+                    kind,
+                    &policy,
+                    BranchSpend::Leaf(tr, ms),
+                    assets,
+                ));
+            }
+            branches
+        }
+        _ => {
+            // a descriptor that can't be lifted (e.g. because it repeats a key) can't be
+            // described
+            let policy = desc.lift().unwrap_or(Semantic::Unsatisfiable);
+            vec![policy_branch(
+                BranchKind::Script,
+                &policy,
+                BranchSpend::Descriptor(desc),
+                assets,
+            )]
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use bdk_chain::{bitcoin, collections::*, miniscript};
-use core::ops::Deref;
+use core::{borrow::Borrow, ops::Deref};
 
 use bitcoin::{
     hashes::{hash160, ripemd160, sha256},
@@ -11,7 +11,7 @@ use bitcoin::{
 
 use super::*;
 use miniscript::{
-    descriptor::{DescriptorSecretKey, KeyMap},
+    descriptor::{DescriptorSecretKey, DescriptorXKey, KeyMap},
     hash256,
 };
 
@@ -66,7 +66,12 @@ pub enum RequiredSignatures<Ak> {
     /// Legacy ECDSA signatures are required
     Legacy { keys: Vec<PlanKey<Ak>> },
     /// Segwitv0 ECDSA signatures are required
-    Segwitv0 { keys: Vec<PlanKey<Ak>> },
+    Segwitv0 {
+        /// The script code the signatures commit to
+        script_code: Script,
+        /// The keys that require signatures
+        keys: Vec<PlanKey<Ak>>,
+    },
     /// A Taproot key spend signature is required
     TapKey {
         /// the internal key
@@ -120,13 +125,56 @@ impl RequiredSignatures<DescriptorPublicKey> {
         keymap: &KeyMap,
         prevouts: &Prevouts<'_, impl core::borrow::Borrow<TxOut>>,
         schnorr_sighashty: Option<SchnorrSighashType>,
-        _ecdsa_sighashty: Option<EcdsaSighashType>,
+        ecdsa_sighashty: Option<EcdsaSighashType>,
         sighash_cache: &mut SighashCache<T>,
         auth_data: &mut SatisfactionMaterial,
         secp: &Secp256k1<impl Signing + Verification>,
     ) -> Result<bool, SigningError> {
         match self {
-            RequiredSignatures::Legacy { .. } | RequiredSignatures::Segwitv0 { .. } => todo!(),
+            RequiredSignatures::Legacy { .. } => todo!(),
+            RequiredSignatures::Segwitv0 { script_code, keys } => {
+                let sighash_type = ecdsa_sighashty.unwrap_or(EcdsaSighashType::All);
+                let value = match prevouts {
+                    Prevouts::All(prevouts) => prevouts.get(input_index).map(|p| p.borrow().value),
+                    Prevouts::One(index, prevout) if *index == input_index => {
+                        Some(prevout.borrow().value)
+                    }
+                    Prevouts::One(..) => None,
+                }
+                .ok_or(sighash::Error::PrevoutIndex)?;
+                let sighash = sighash_cache.segwit_signature_hash(
+                    input_index,
+                    script_code,
+                    value,
+                    sighash_type,
+                )?;
+                let msg = Message::from_slice(sighash.as_ref()).expect("Sighashes are 32 bytes");
+
+                let mut modified = false;
+                for plan_key in keys {
+                    if let Some(secret_key) = keymap.get(&plan_key.asset_key) {
+                        let secret_key = match secret_key {
+                            DescriptorSecretKey::Single(single) => single.key.inner,
+                            DescriptorSecretKey::XPrv(xprv) => {
+                                xprv.xkey
+                                    .derive_priv(secp, &xprv_derivation(xprv, plan_key))?
+                                    .private_key
+                            }
+                        };
+                        // RFC 6979 nonces so the same transaction is always signed the same way
+                        let sig = secp.sign_ecdsa_low_r(&msg, &secret_key);
+                        auth_data.ecdsa_sigs.insert(
+                            plan_key.descriptor_key.clone(),
+                            EcdsaSig {
+                                sig,
+                                hash_ty: sighash_type,
+                            },
+                        );
+                        modified = true;
+                    }
+                }
+                Ok(modified)
+            }
             RequiredSignatures::TapKey {
                 plan_key,
                 merkle_root,
@@ -145,7 +193,7 @@ impl RequiredSignatures<DescriptorPublicKey> {
                     DescriptorSecretKey::Single(single) => single.key.inner,
                     DescriptorSecretKey::XPrv(xprv) => {
                         xprv.xkey
-                            .derive_priv(&secp, &plan_key.derivation_hint)?
+                            .derive_priv(&secp, &xprv_derivation(xprv, plan_key))?
                             .private_key
                     }
                 };
@@ -192,7 +240,7 @@ impl RequiredSignatures<DescriptorPublicKey> {
                             DescriptorSecretKey::Single(single) => single.key.inner,
                             DescriptorSecretKey::XPrv(xprv) => {
                                 xprv.xkey
-                                    .derive_priv(&secp, &plan_key.derivation_hint)?
+                                    .derive_priv(&secp, &xprv_derivation(xprv, plan_key))?
                                     .private_key
                             }
                         };
@@ -216,3 +264,31 @@ impl RequiredSignatures<DescriptorPublicKey> {
         }
     }
 }
+
+/// The path from the xprv of `xprv` to the key `plan_key` signs with.
+///
+/// The derivation hint is relative to the asset key. When the xprv's path starts with hardened
+/// steps its public key is the xpub at the end of them (with them in its origin), so the hint
+/// starts there rather than at the xprv.
+fn xprv_derivation(
+    xprv: &DescriptorXKey<bip32::ExtendedPrivKey>,
+    plan_key: &PlanKey<DescriptorPublicKey>,
+) -> bip32::DerivationPath {
+    let origin_path = |origin: &Option<bip32::KeySource>| {
+        origin
+            .as_ref()
+            .map(|(_, path)| path.clone())
+            .unwrap_or_else(bip32::DerivationPath::master)
+    };
+    match &plan_key.asset_key {
+        DescriptorPublicKey::XPub(xpub) => {
+            let xpub_origin = origin_path(&xpub.origin);
+            let xprv_origin = origin_path(&xprv.origin);
+            let to_xpub = xpub_origin[..]
+                .strip_prefix(&xprv_origin[..])
+                .unwrap_or_default();
+            bip32::DerivationPath::from(to_xpub).extend(&plan_key.derivation_hint)
+        }
+        DescriptorPublicKey::Single(_) => plan_key.derivation_hint.clone(),
+    }
+}
@@ -33,29 +33,43 @@ pub struct PlanKey<Ak> {
 }
 
 impl<Ak> TemplateItem<Ak> {
-    pub fn expected_size(&self) -> usize {
-        match self {
-            TemplateItem::Sign { .. } => 64, /*size of sig TODO: take into consideration sighash falg*/
-            TemplateItem::Pk { .. } => 32,
-            TemplateItem::One => varint_len(1),
-            TemplateItem::Zero => 0, /* zero means an empty witness element */
+    /// The size of the witness element, which depends on the signature scheme of the script
+    /// context.
+    pub fn expected_size(&self, sig_type: SigType) -> usize {
+        match (self, sig_type) {
+            // a DER encoded ECDSA signature with a low S value and its sighash flag
+            (TemplateItem::Sign { .. }, SigType::Ecdsa) => 72,
+            (TemplateItem::Sign { .. }, SigType::Schnorr) => 64, /*size of sig TODO: take into consideration sighash falg*/
+            (TemplateItem::Pk { .. }, SigType::Ecdsa) => 33,
+            (TemplateItem::Pk { .. }, SigType::Schnorr) => 32,
+            (TemplateItem::One, _) => varint_len(1),
+            (TemplateItem::Zero, _) => 0, /* zero means an empty witness element */
             // I'm not sure if it should be 32 here (it's a 20 byte hash) but that's what other
             // parts of the code were doing.
-            TemplateItem::Hash160(_) | TemplateItem::Ripemd160(_) => 32,
-            TemplateItem::Sha256(_) | TemplateItem::Hash256(_) => 32,
+            (TemplateItem::Hash160(_), _) | (TemplateItem::Ripemd160(_), _) => 32,
+            (TemplateItem::Sha256(_), _) | (TemplateItem::Hash256(_), _) => 32,
         }
     }
 
     // this can only be called if we are sure that auth_data has what we need
-    pub(super) fn to_witness_stack(&self, auth_data: &SatisfactionMaterial) -> Vec<Vec<u8>> {
+    pub(super) fn to_witness_stack(
+        &self,
+        auth_data: &SatisfactionMaterial,
+        sig_type: SigType,
+    ) -> Vec<Vec<u8>> {
         match self {
-            TemplateItem::Sign(plan_key) => {
-                vec![auth_data
+            TemplateItem::Sign(plan_key) => match sig_type {
+                SigType::Ecdsa => vec![auth_data
+                    .ecdsa_sigs
+                    .get(&plan_key.descriptor_key)
+                    .unwrap()
+                    .to_vec()],
+                SigType::Schnorr => vec![auth_data
                     .schnorr_sigs
                     .get(&plan_key.descriptor_key)
                     .unwrap()
-                    .to_vec()]
-            }
+                    .to_vec()],
+            },
             TemplateItem::One => vec![vec![1]],
             TemplateItem::Zero => vec![vec![]],
             TemplateItem::Sha256(image) => {
@@ -70,7 +84,11 @@ impl<Ak> TemplateItem<Ak> {
             TemplateItem::Hash256(image) => {
                 vec![auth_data.hash256_preimages.get(image).unwrap().to_vec()]
             }
-            TemplateItem::Pk { key } => vec![key.to_public_key().to_bytes()],
+            // keys are x-only in taproot scripts
+            TemplateItem::Pk { key } => match sig_type {
+                SigType::Ecdsa => vec![key.to_public_key().to_bytes()],
+                SigType::Schnorr => vec![key.to_x_only_pubkey().serialize().to_vec()],
+            },
         }
     }
 }
@@ -0,0 +1,233 @@
+//! Working out when the timelocked spending paths of a descriptor become usable.
+//!
+//! Descriptors for vaults usually have a branch that can only be used after some absolute
+//! (`after`) or relative (`older`) timelock has passed. [`branch_timelocks`] lists these branches
+//! and [`BranchTimelock::unlock`] tells you at which height/median time past each of them can be
+//! used to spend a particular output.
+use bdk_chain::miniscript::{descriptor::WshInner, Miniscript, Terminal};
+
+use super::*;
+
+/// The timelocks that must pass before a spending branch of a descriptor can be used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BranchTimelock {
+    /// The script of the branch
+    pub script: Script,
+    /// The absolute timelock required by the `after` fragments in the branch (if any)
+    pub after: Option<LockTime>,
+    /// The relative timelock required by the `older` fragments in the branch (if any)
+    pub older: Option<Sequence>,
+}
+
+/// The point in the chain from which a timelocked branch can be used.
+///
+/// The branch can be used to spend the output in a transaction that is mined on top of a tip with
+/// a height of at least `height` and a median time past of at least `time`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Unlock {
+    /// The minimum tip height
+    pub height: Option<u32>,
+    /// The minimum median time past of the tip
+    pub time: Option<u32>,
+}
+
+impl Unlock {
+    /// Whether a transaction mined on top of a tip at `tip_height` with a median time past of
+    /// `tip_mtp` satisfies the timelocks.
+    pub fn is_reached(&self, tip_height: u32, tip_mtp: u32) -> bool {
+        self.height.is_none_or(|height| tip_height >= height)
+            && self.time.is_none_or(|time| tip_mtp >= time)
+    }
+}
+
+impl BranchTimelock {
+    /// Whether the branch has any timelocks at all.
+    pub fn is_timelocked(&self) -> bool {
+        self.after.is_some() || self.older.is_some()
+    }
+
+    /// Works out when the branch can be used to spend an output that was confirmed at
+    /// `confirmation_height` in a block whose median time past was `confirmation_mtp`.
+    ///
+    /// Returns `None` if this can't be known yet. This is the case when the branch has a relative
+    /// timelock and the output is unconfirmed (or the output's confirmation time is unknown for
+    /// time based relative timelocks).
+    pub fn unlock(
+        &self,
+        confirmation_height: Option<u32>,
+        confirmation_mtp: Option<u32>,
+    ) -> Option<Unlock> {
+        let mut unlock = Unlock::default();
+
+        match self.after {
+            // a transaction with `nLockTime` set to `height` is final in block `height + 1`
+            Some(LockTime::Blocks(height)) => unlock.height = Some(height.to_consensus_u32()),
+            // ... and one with a time lock is final once the median time past exceeds it
+            Some(LockTime::Seconds(time)) => {
+                unlock.time = Some(time.to_consensus_u32().saturating_add(1))
+            }
+            None => {}
+        }
+
+        if let Some(older) = self.older {
+            let value = older.to_consensus_u32() & 0xffff;
+            if older.is_height_locked() {
+                // the output has `value` confirmations once the tip is at
+                // `confirmation_height + value - 1`
+                let height = (confirmation_height? + value).saturating_sub(1);
+                unlock.height = Some(unlock.height.map_or(height, |h| h.max(height)));
+            } else if older.is_time_locked() {
+                let time = confirmation_mtp?.saturating_add(value * 512);
+                unlock.time = Some(unlock.time.map_or(time, |t| t.max(time)));
+            }
+        }
+
+        Some(unlock)
+    }
+}
+
+/// Lists the timelock requirements of each spending branch of `desc`.
+///
+/// A branch is one way of satisfying a script: every `or` picks one of its sides and a `thresh`
+/// picks enough of its subs. Taproot descriptors list the branches of each leaf, other descriptors
+/// the branches of their (witness) script. Branches whose timelocks can never be satisfied
+/// together are left out. Descriptors without a miniscript (e.g. `wpkh`) have a single branch
+/// without timelocks.
+pub fn branch_timelocks(desc: &Descriptor<DefiniteDescriptorKey>) -> Vec<BranchTimelock> {
+    fn branches<Ctx: ScriptContext>(
+        ms: &Miniscript<DefiniteDescriptorKey, Ctx>,
+    ) -> impl Iterator<Item = BranchTimelock> {
+        let script = ms.encode();
+        alternatives(ms)
+            .into_iter()
+            .map(move |(after, older)| BranchTimelock {
+                script: script.clone(),
+                after,
+                older,
+            })
+    }
+
+    match desc {
+        Descriptor::Tr(tr) => tr.iter_scripts().flat_map(|(_, ms)| branches(ms)).collect(),
+        Descriptor::Wsh(wsh) => match wsh.as_inner() {
+            WshInner::Ms(ms) => branches(ms).collect(),
+            WshInner::SortedMulti(_) => vec![untimelocked(desc)],
+        },
+        Descriptor::Sh(sh) => match sh.as_inner() {
+            ShInner::Wsh(wsh) => match wsh.as_inner() {
+                WshInner::Ms(ms) => branches(ms).collect(),
+                WshInner::SortedMulti(_) => vec![untimelocked(desc)],
+            },
+            ShInner::Ms(ms) => branches(ms).collect(),
+            ShInner::Wpkh(_) | ShInner::SortedMulti(_) => vec![untimelocked(desc)],
+        },
+        Descriptor::Bare(bare) => branches(bare.as_inner()).collect(),
+        Descriptor::Pkh(_) | Descriptor::Wpkh(_) => vec![untimelocked(desc)],
+    }
+}
+
+fn untimelocked(desc: &Descriptor<DefiniteDescriptorKey>) -> BranchTimelock {
+    BranchTimelock {
+        script: desc.script_pubkey(),
+        after: None,
+        older: None,
+    }
+}
+
+type Timelocks = (Option<LockTime>, Option<Sequence>);
+
+/// The timelocks of each way of satisfying `ms`, without duplicates.
+fn alternatives<Ctx: ScriptContext>(ms: &Miniscript<DefiniteDescriptorKey, Ctx>) -> Vec<Timelocks> {
+    let alts = match &ms.node {
+        Terminal::After(locktime) => vec![(Some(LockTime::from(locktime)), None)],
+        Terminal::Older(older) => vec![(None, Some(*older))],
+        Terminal::Alt(sub)
+        | Terminal::Swap(sub)
+        | Terminal::Check(sub)
+        | Terminal::DupIf(sub)
+        | Terminal::Verify(sub)
+        | Terminal::NonZero(sub)
+        | Terminal::ZeroNotEqual(sub) => alternatives(sub),
+        Terminal::AndV(l, r) | Terminal::AndB(l, r) => both(&alternatives(l), &alternatives(r)),
+        Terminal::OrB(l, r) | Terminal::OrD(l, r) | Terminal::OrC(l, r) | Terminal::OrI(l, r) => {
+            let mut alts = alternatives(l);
+            alts.extend(alternatives(r));
+            alts
+        }
+        Terminal::AndOr(a, b, c) => {
+            let mut alts = both(&alternatives(a), &alternatives(b));
+            alts.extend(alternatives(c));
+            alts
+        }
+        Terminal::Thresh(k, subs) => {
+            let subs = subs.iter().map(|sub| alternatives(sub)).collect::<Vec<_>>();
+            threshold(*k, &subs)
+        }
+        // a branch that can't be satisfied isn't a way of spending
+        Terminal::False => vec![],
+        _ => vec![(None, None)],
+    };
+    let mut unique = Vec::with_capacity(alts.len());
+    for timelocks in alts {
+        if !unique.contains(&timelocks) {
+            unique.push(timelocks);
+        }
+    }
+    unique
+}
+
+/// The timelocks of satisfying any `k` of the `subs`.
+fn threshold(k: usize, subs: &[Vec<Timelocks>]) -> Vec<Timelocks> {
+    if k == 0 {
+        return vec![(None, None)];
+    }
+    match subs.split_first() {
+        Some(_) if subs.len() < k => vec![],
+        Some((first, rest)) => {
+            let mut alts = both(first, &threshold(k - 1, rest));
+            alts.extend(threshold(k, rest));
+            alts
+        }
+        None => vec![],
+    }
+}
+
+/// The timelocks of satisfying an alternative of `a` along with an alternative of `b`.
+fn both(a: &[Timelocks], b: &[Timelocks]) -> Vec<Timelocks> {
+    a.iter()
+        .flat_map(|a| b.iter().filter_map(move |b| merge(*a, *b)))
+        .collect()
+}
+
+/// Returns `None` if the timelocks can never be satisfied together.
+fn merge(a: Timelocks, b: Timelocks) -> Option<Timelocks> {
+    let after = match (a.0, b.0) {
+        (Some(a), Some(b)) if !a.is_same_unit(b) => return None,
+        (Some(a), Some(b)) if a.to_consensus_u32() >= b.to_consensus_u32() => Some(a),
+        (a, b) => b.or(a),
+    };
+    let older = match (a.1, b.1) {
+        (Some(a), Some(b)) if a.is_height_locked() != b.is_height_locked() => return None,
+        (Some(a), Some(b)) if a.to_consensus_u32() >= b.to_consensus_u32() => Some(a),
+        (a, b) => b.or(a),
+    };
+    Some((after, older))
+}
+
+impl<K> Assets<K> {
+    /// Sets the timelock assets for spending an output confirmed at `confirmation_height` with a
+    /// transaction mined on top of `tip_height`.
+    ///
+    /// This lets [`plan_satisfaction`] pick a timelocked branch once (and only once) its height
+    /// based `after` or `older` timelocks have been reached.
+    pub fn with_timelocks(mut self, tip_height: u32, confirmation_height: Option<u32>) -> Self {
+        self.max_locktime = LockTime::from_height(tip_height).ok();
+        self.txo_age = confirmation_height
+            .filter(|&height| height <= tip_height)
+            .map(|height| {
+                let confirmations = tip_height - height + 1;
+                Sequence::from_height(confirmations.min(u16::MAX as u32) as u16)
+            });
+        self
+    }
+}
@@ -0,0 +1,166 @@
+use bdk_chain::{
+    bitcoin::{
+        secp256k1::{Secp256k1, SecretKey},
+        LockTime, PublicKey, Sequence,
+    },
+    miniscript::{DefiniteDescriptorKey, Descriptor, DescriptorPublicKey},
+};
+use bdk_tmp_plan::{branch_timelocks, plan_satisfaction, Assets, BranchTimelock, Unlock};
+use std::str::FromStr;
+
+/// A public key (or its x-only form for taproot) that is different for each `n`.
+fn key(n: u8, x_only: bool) -> String {
+    let secp = Secp256k1::new();
+    let secret = SecretKey::from_slice(&[n; 32]).unwrap();
+    let key = PublicKey::new(secret.public_key(&secp));
+    match x_only {
+        true => key.inner.x_only_public_key().0.to_string(),
+        false => key.to_string(),
+    }
+}
+
+fn descriptor(template: &str) -> Descriptor<DefiniteDescriptorKey> {
+    let x_only = template.starts_with("tr(");
+    let desc = ["@A", "@B", "@C", "@D"]
+        .iter()
+        .zip(1u8..)
+        .fold(template.to_string(), |desc, (name, n)| {
+            desc.replace(name, &key(n, x_only))
+        });
+    Descriptor::from_str(&desc).unwrap()
+}
+
+fn timelocks(desc: &str) -> Vec<(Option<u32>, Option<u32>)> {
+    let mut timelocks = branch_timelocks(&descriptor(desc))
+        .into_iter()
+        .map(|branch| {
+            (
+                branch.after.map(|after| after.to_consensus_u32()),
+                branch.older.map(|older| older.to_consensus_u32()),
+            )
+        })
+        .collect::<Vec<_>>();
+    timelocks.sort();
+    timelocks
+}
+
+#[test]
+fn wsh_vault_branches() {
+    let vault = "or_d(pk(@A),and_v(v:pk(@B),older(144)))";
+    let expected = vec![(None, None), (None, Some(144))];
+    assert_eq!(timelocks(&format!("wsh({})", vault)), expected);
+    assert_eq!(timelocks(&format!("sh(wsh({}))", vault)), expected);
+
+    let desc = descriptor(&format!("wsh({})", vault));
+    let branches = branch_timelocks(&desc);
+    assert_eq!(
+        branches.iter().filter(|b| b.is_timelocked()).count(),
+        1,
+        "only the recovery branch is timelocked"
+    );
+    assert!(branches
+        .iter()
+        .all(|branch| branch.script == desc.explicit_script().unwrap()));
+}
+
+#[test]
+fn tr_branches_of_each_leaf() {
+    assert_eq!(
+        timelocks("tr(@A,{and_v(v:pk(@B),after(500000)),or_i(and_v(v:pk(@C),older(10)),and_v(v:pk(@D),older(20)))})"),
+        vec![(None, Some(10)), (None, Some(20)), (Some(500_000), None)]
+    );
+}
+
+#[test]
+fn thresh_branches() {
+    // any two of the three: the branches using the timelock all need older(10)
+    assert_eq!(
+        timelocks("wsh(thresh(2,pk(@A),s:pk(@B),sln:older(10)))"),
+        vec![(None, None), (None, Some(10))]
+    );
+    // both timelocks are required together
+    assert_eq!(
+        timelocks("wsh(and_v(v:pk(@A),and_v(v:after(100),older(5))))"),
+        vec![(Some(100), Some(5))]
+    );
+}
+
+#[test]
+fn descriptors_without_miniscript_have_one_branch() {
+    let desc = descriptor("wpkh(@A)");
+    assert_eq!(
+        branch_timelocks(&desc),
+        vec![BranchTimelock {
+            script: desc.script_pubkey(),
+            after: None,
+            older: None,
+        }]
+    );
+}
+
+#[test]
+fn unlock_of_a_branch() {
+    let branch = BranchTimelock {
+        script: Default::default(),
+        after: Some(LockTime::from_height(200).unwrap()),
+        older: Some(Sequence::from_height(10)),
+    };
+    // older(10) is reached with the tenth confirmation
+    assert_eq!(
+        branch.unlock(Some(195), None),
+        Some(Unlock {
+            height: Some(204),
+            time: None
+        })
+    );
+    assert_eq!(branch.unlock(None, None), None);
+    let unlock = branch.unlock(Some(100), None).unwrap();
+    assert!(!unlock.is_reached(199, 0));
+    assert!(unlock.is_reached(200, 0));
+
+    let branch = BranchTimelock {
+        script: Default::default(),
+        after: Some(LockTime::from_time(1_700_000_000).unwrap()),
+        older: Some(Sequence::from_512_second_intervals(2)),
+    };
+    assert_eq!(
+        branch.unlock(Some(100), Some(1_700_001_000)),
+        Some(Unlock {
+            height: None,
+            time: Some(1_700_002_024)
+        })
+    );
+    assert_eq!(branch.unlock(Some(100), None), None);
+}
+
+#[test]
+fn with_timelocks_sets_the_assets_of_a_tip() {
+    let assets = Assets::<DescriptorPublicKey>::default().with_timelocks(100, Some(95));
+    assert_eq!(
+        assets.max_locktime,
+        Some(LockTime::from_height(100).unwrap())
+    );
+    assert_eq!(assets.txo_age, Some(Sequence::from_height(6)));
+
+    // unconfirmed outputs and outputs above the tip have no age
+    let assets = Assets::<DescriptorPublicKey>::default().with_timelocks(100, None);
+    assert_eq!(assets.txo_age, None);
+    let assets = Assets::<DescriptorPublicKey>::default().with_timelocks(100, Some(101));
+    assert_eq!(assets.txo_age, None);
+}
+
+#[test]
+fn plans_only_reached_timelocks() {
+    let desc = descriptor("wsh(and_v(v:pk(@A),older(10)))");
+    let assets = Assets {
+        keys: vec![DescriptorPublicKey::from_str(&key(1, false)).unwrap()],
+        ..Default::default()
+    };
+    assert!(plan_satisfaction(&desc, &assets.clone().with_timelocks(100, Some(92))).is_none());
+    let plan = plan_satisfaction(&desc, &assets.clone().with_timelocks(100, Some(91))).unwrap();
+    assert_eq!(plan.required_sequence(), Some(Sequence::from_height(10)));
+
+    // a height never satisfies a time based after and this must not panic
+    let desc = descriptor("wsh(and_v(v:pk(@A),after(1700000000)))");
+    assert!(plan_satisfaction(&desc, &assets.with_timelocks(800_000, None)).is_none());
+}
@@ -0,0 +1,152 @@
+//! Following bitcoind's best chain through its ZMQ notifications.
+use bdk_chain::{
+    bitcoin::{consensus::deserialize, consensus::encode, Block, BlockHash, Transaction},
+    tip::{RecentBlocks, TipEvent, TipStream},
+    BlockId,
+};
+use std::{collections::VecDeque, mem};
+
+/// A notification of bitcoind in the order it arrived.
+pub enum Notification {
+    /// A transaction that entered the mempool or was mined
+    Tx(Transaction),
+    /// A block that was connected to the best chain at this height
+    Block(u32, Block),
+}
+
+/// Why following bitcoind over ZMQ failed.
+#[derive(Debug)]
+pub enum ZmqError {
+    Zmq(zmq::Error),
+    /// bitcoind sent a transaction or block that can't be decoded
+    Decode(encode::Error),
+    /// The coinbase of the block doesn't tell its height
+    NoHeight(BlockHash),
+    /// The notification of the block at this height was missed, so it's unknown where bitcoind's
+    /// chain forked from the blocks we were told about. ZMQ can't be asked for it.
+    MissedBlock(u32),
+}
+
+impl core::fmt::Display for ZmqError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ZmqError::Zmq(e) => write!(f, "{}", e),
+            ZmqError::Decode(e) => {
+                write!(f, "bitcoind sent something that can't be decoded: {}", e)
+            }
+            ZmqError::NoHeight(hash) => write!(f, "block {} has no height", hash),
+            ZmqError::MissedBlock(height) => write!(
+                f,
+                "the notification of the block at height {} was missed, sync the wallet with \
+                 another chain source and listen again",
+                height
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ZmqError {}
+
+impl From<zmq::Error> for ZmqError {
+    fn from(e: zmq::Error) -> Self {
+        ZmqError::Zmq(e)
+    }
+}
+
+impl From<encode::Error> for ZmqError {
+    fn from(e: encode::Error) -> Self {
+        ZmqError::Decode(e)
+    }
+}
+
+/// bitcoind's `rawtx` and `rawblock` notifications as a [`TipStream`].
+///
+/// Each block is reported as the [`TipEvent`]s that lead to it. bitcoind notifies us of every block
+/// it connects in order, so a reorg is reported once the first block of the new chain arrives.
+/// The blocks and transactions themselves are kept until they are taken with
+/// [`take_notifications`].
+///
+/// [`take_notifications`]: Self::take_notifications
+pub struct BitcoindZmq {
+    socket: zmq::Socket,
+    recent_blocks: RecentBlocks,
+    /// Tip events that have been determined but not returned yet
+    tip_events: VecDeque<TipEvent>,
+    notifications: Vec<Notification>,
+}
+
+impl BitcoindZmq {
+    /// Subscribes to the `rawtx` and `rawblock` notifications at their endpoints (which may be
+    /// the same).
+    pub fn connect(rawtx: &str, rawblock: &str) -> Result<Self, zmq::Error> {
+        let context = zmq::Context::new();
+        let socket = context.socket(zmq::SUB)?;
+        socket.connect(rawtx)?;
+        if rawblock != rawtx {
+            socket.connect(rawblock)?;
+        }
+        socket.set_subscribe(b"rawtx")?;
+        socket.set_subscribe(b"rawblock")?;
+        Ok(Self {
+            socket,
+            recent_blocks: RecentBlocks::default(),
+            tip_events: VecDeque::new(),
+            notifications: vec![],
+        })
+    }
+
+    /// The transactions and blocks that arrived since the last call, in the order they arrived.
+    pub fn take_notifications(&mut self) -> Vec<Notification> {
+        mem::take(&mut self.notifications)
+    }
+}
+
+impl TipStream for BitcoindZmq {
+    type Error = ZmqError;
+
+    /// Reads the notifications that have arrived without waiting for more.
+    ///
+    /// Fails with [`ZmqError::MissedBlock`] when a block doesn't build on the blocks we were
+    /// told about.
+    fn next_event(&mut self) -> Result<Option<TipEvent>, ZmqError> {
+        loop {
+            if let Some(event) = self.tip_events.pop_front() {
+                return Ok(Some(event));
+            }
+
+            // each notification is made of a topic, a body and a sequence number
+            let message = match self.socket.recv_multipart(zmq::DONTWAIT) {
+                Ok(message) => message,
+                Err(zmq::Error::EAGAIN) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let (topic, body) = match message.as_slice() {
+                [topic, body, ..] => (topic.as_slice(), body.as_slice()),
+                _ => continue,
+            };
+
+            match topic {
+                b"rawtx" => self
+                    .notifications
+                    .push(Notification::Tx(deserialize(body)?)),
+                b"rawblock" => {
+                    let block: Block = deserialize(body)?;
+                    let hash = block.block_hash();
+                    let height = block
+                        .bip34_block_height()
+                        .map_err(|_| ZmqError::NoHeight(hash))?
+                        as u32;
+                    let events = self.recent_blocks.connect(
+                        BlockId { height, hash },
+                        block.header.prev_blockhash,
+                        // only asked for when the parent of the block above isn't one we know
+                        |height| Err(ZmqError::MissedBlock(height + 1)),
+                    )?;
+                    self.tip_events.extend(events);
+                    self.notifications.push(Notification::Block(height, block));
+                }
+                _ => continue,
+            }
+        }
+    }
+}
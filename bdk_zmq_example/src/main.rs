@@ -0,0 +1,264 @@
+mod bitcoind;
+use bdk_chain::{
+    bitcoin::Transaction,
+    collections::BTreeMap,
+    keychain::{KeychainChangeSet, KeychainScan, KeychainTracker},
+    tip::TipEvent,
+    BlockId, TxHeight,
+};
+use bdk_cli::{
+    anyhow,
+    clap::{self, Subcommand},
+    Broadcast, EstimateFee, Keychain,
+};
+use bitcoind::{BitcoindZmq, Notification};
+use std::time::Duration;
+
+#[derive(Subcommand, Debug, Clone)]
+enum ZmqCommands {
+    /// Listens to bitcoind's ZMQ notifications and applies the transactions and blocks relevant to
+    /// the wallet as they arrive.
+    ///
+    /// bitcoind must be started with `-zmqpubrawtx` and `-zmqpubrawblock`. This only picks up what
+    /// happens while it is running so the wallet should be synced beforehand. It stops when a
+    /// block notification was missed since bitcoind can't be asked for the block over ZMQ.
+    Listen {
+        /// The endpoint of bitcoind's `rawtx` notifications
+        #[clap(long, default_value = "tcp://127.0.0.1:28332")]
+        rawtx: String,
+        /// The endpoint of bitcoind's `rawblock` notifications
+        #[clap(long, default_value = "tcp://127.0.0.1:28332")]
+        rawblock: String,
+        /// How many seconds to wait between checking for notifications
+        #[clap(long, default_value = "1")]
+        poll_secs: u64,
+    },
+}
+
+/// ZMQ notifications can't be used to broadcast.
+#[derive(Debug)]
+struct CantBroadcast;
+
+impl core::fmt::Display for CantBroadcast {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "broadcasting isn't possible over ZMQ")
+    }
+}
+
+impl std::error::Error for CantBroadcast {}
+
+struct NoBroadcast;
+
+impl Broadcast for NoBroadcast {
+    type Error = CantBroadcast;
+
+    fn broadcast(&self, _tx: &Transaction) -> Result<(), Self::Error> {
+        Err(CantBroadcast)
+    }
+}
+
+/// bitcoind doesn't send fee estimates over ZMQ either.
+#[derive(Debug)]
+struct CantEstimateFee;
+
+impl core::fmt::Display for CantEstimateFee {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "fees can't be estimated over ZMQ, give the feerate in sats per vbyte"
+        )
+    }
+}
+
+impl std::error::Error for CantEstimateFee {}
+
+impl EstimateFee for NoBroadcast {
+    type Error = CantEstimateFee;
+
+    fn estimate_fee(&mut self, _target_blocks: usize) -> Result<f32, Self::Error> {
+        Err(CantEstimateFee)
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let (
+        args,
+        bdk_cli::Wallet {
+            config,
+            signers,
+            mut tracker,
+            store: mut db,
+            ..
+        },
+    ) = match bdk_cli::init::<ZmqCommands, _>()? {
+        Some(wallet) => wallet,
+        None => return Ok(()),
+    };
+
+    match args.command {
+        bdk_cli::Commands::ChainSpecific(ZmqCommands::Listen {
+            rawtx,
+            rawblock,
+            poll_secs,
+        }) => {
+            let mut source = BitcoindZmq::connect(&rawtx, &rawblock)?;
+            eprintln!("listening for transactions and blocks...");
+            bdk_cli::follow_tip(
+                &mut source,
+                &mut tracker,
+                &mut db,
+                Duration::from_secs(poll_secs),
+                |source, tracker, db, events| {
+                    for event in events {
+                        match event {
+                            TipEvent::Connected(block) => {
+                                eprintln!("connected block {} at {}", block.hash, block.height)
+                            }
+                            TipEvent::Disconnected(block) => {
+                                eprintln!("disconnected block {} at {}", block.hash, block.height)
+                            }
+                        }
+                    }
+                    for notification in source.take_notifications() {
+                        let changeset = match notification {
+                            Notification::Tx(tx) => {
+                                tracker.determine_changeset(&tx_scan(tracker, tx))?
+                            }
+                            Notification::Block(height, block) => {
+                                // a block a later one reorged out has nothing left to confirm
+                                let block_id = BlockId {
+                                    height,
+                                    hash: block.block_hash(),
+                                };
+                                if tracker.chain().checkpoint_at(height) != Some(block_id) {
+                                    continue;
+                                }
+                                tracker.scan_block_preview(&block, height)?
+                            }
+                        };
+                        if changeset.is_empty() {
+                            continue;
+                        }
+                        db.append_changeset(&changeset)?;
+                        bdk_cli::record_evictions(tracker, db, &changeset)?;
+                        tracker.apply_changeset(changeset.clone());
+                        report(tracker, &changeset);
+                    }
+                    for (id, status) in bdk_cli::update_invoices(tracker, db)? {
+                        eprintln!("invoice {} is now {}", id, status);
+                    }
+                    for txid in bdk_cli::abandon_expired(tracker, db)? {
+                        eprintln!("abandoned tx {} as it didn't confirm in time", txid);
+                    }
+                    Ok(())
+                },
+            )?;
+        }
+        bdk_cli::Commands::Import { descriptors_json } => {
+            bdk_cli::run_import_cmd(&mut tracker, &mut db, &descriptors_json)?;
+            eprintln!("imported descriptors are only watched from now on, rescan them with another chain source to find their history");
+        }
+        bdk_cli::Commands::Backup { path } => {
+            let report =
+                bdk_cli::run_backup_cmd(&tracker, &mut db, &path, args.db_password.as_deref())?;
+            print!("{}", report);
+        }
+        bdk_cli::Commands::RestoreDb { path } => {
+            let report = bdk_cli::run_restore_db_cmd(
+                &tracker,
+                &config.db_path,
+                &path,
+                args.db_password.as_deref(),
+                config.db_wallet.as_deref(),
+            )?;
+            print!("{}", report);
+        }
+        bdk_cli::Commands::RecoverDb => {
+            let report = bdk_cli::run_recover_db_cmd(&mut db, &config.db_path)?;
+            print!("{}", report);
+        }
+        general_command => {
+            let output = bdk_cli::handle_commands(
+                general_command,
+                NoBroadcast,
+                &mut tracker,
+                &mut db,
+                config.network,
+                &signers,
+            )?;
+            print!("{}", output);
+        }
+    }
+
+    Ok(())
+}
+
+/// Turns a mempool transaction into an update if it is relevant to the wallet.
+fn tx_scan(
+    tracker: &KeychainTracker<Keychain, TxHeight>,
+    tx: Transaction,
+) -> KeychainScan<Keychain, TxHeight> {
+    let mut scan = KeychainScan::default();
+
+    // bitcoind also notifies us of the transactions of each block it connects
+    if tracker.chain().tx_position(tx.txid()).is_some()
+        || !tracker.txout_index.inner().is_relevant(&tx)
+    {
+        return scan;
+    }
+
+    scan.last_active_indexes = active_indexes(tracker, [&tx]);
+    let _ = scan
+        .update
+        .insert_tx(tx, TxHeight::Unconfirmed)
+        .expect("the update is empty so this can't conflict");
+    scan
+}
+
+/// The highest index of each keychain that the transactions pay to.
+fn active_indexes<'a>(
+    tracker: &KeychainTracker<Keychain, TxHeight>,
+    txs: impl IntoIterator<Item = &'a Transaction>,
+) -> BTreeMap<Keychain, u32> {
+    let mut indexes = BTreeMap::new();
+    for txout in txs.into_iter().flat_map(|tx| &tx.output) {
+        if let Some((keychain, index)) = tracker
+            .txout_index
+            .inner()
+            .index_of_spk(&txout.script_pubkey)
+        {
+            let last = indexes.entry(keychain).or_insert(index);
+            *last = index.max(*last);
+        }
+    }
+    indexes
+}
+
+/// Prints what an applied changeset did to the wallet.
+fn report(
+    tracker: &KeychainTracker<Keychain, TxHeight>,
+    changeset: &KeychainChangeSet<Keychain, TxHeight>,
+) {
+    for (height, hash) in &changeset.chain_graph.chain.checkpoints {
+        if let Some(hash) = hash {
+            eprintln!("block {} at height {}", hash, height);
+        }
+    }
+    for (txid, position) in &changeset.chain_graph.chain.txids {
+        let net_value = tracker
+            .graph()
+            .get_tx(*txid)
+            .map(|tx| tracker.txout_index.inner().net_value(tx))
+            .unwrap_or_default();
+        eprintln!(
+            "tx {} is now {} (net value {})",
+            txid,
+            match position {
+                Some(TxHeight::Confirmed(height)) => format!("confirmed at height {}", height),
+                Some(TxHeight::Unconfirmed) => "unconfirmed".into(),
+                None => "evicted".into(),
+            },
+            net_value
+        );
+    }
+}